@@ -0,0 +1,93 @@
+// SPDX-License-Identifier: GPL-2.0
+/*
+ * Egress TC classifier for the security module's eBPF backend.
+ * Rewrites the TTL and randomizes the TCP window of selected packets in-kernel.
+ * Configured from userspace through the pinned `mangle_config` map
+ * (layout must match `MangleConfig` in src/ebpf.rs).
+ *
+ * Build: clang -O2 -g -target bpf -c tc_mangle.bpf.c -o tc_mangle.bpf.o
+ */
+#include <linux/bpf.h>
+#include <linux/if_ether.h>
+#include <linux/ip.h>
+#include <linux/tcp.h>
+#include <linux/pkt_cls.h>
+#include <stddef.h>
+#include <bpf/bpf_helpers.h>
+#include <bpf/bpf_endian.h>
+
+#define FLAG_REWRITE_TTL 0x01
+#define FLAG_RANDOM_WINDOW 0x02
+
+struct mangle_config {
+    __u8 flags;
+    __u8 ttl;
+    __u16 dst_port;   /* host order, 0 = any */
+    __u32 mark;       /* 0 = any */
+    __u16 window_min;
+    __u16 window_max;
+    __u32 reserved;
+};
+
+struct {
+    __uint(type, BPF_MAP_TYPE_ARRAY);
+    __uint(max_entries, 1);
+    __type(key, __u32);
+    __type(value, struct mangle_config);
+    __uint(pinning, LIBBPF_PIN_BY_NAME);
+} mangle_config SEC(".maps");
+
+#define IP_TTL_OFF (ETH_HLEN + offsetof(struct iphdr, ttl))
+#define IP_CSUM_OFF (ETH_HLEN + offsetof(struct iphdr, check))
+
+SEC("tc")
+int tc_mangle(struct __sk_buff *skb)
+{
+    __u32 key = 0;
+    struct mangle_config *cfg = bpf_map_lookup_elem(&mangle_config, &key);
+    if (!cfg || !cfg->flags)
+        return TC_ACT_OK;
+    if (cfg->mark && skb->mark != cfg->mark)
+        return TC_ACT_OK;
+
+    void *data = (void *)(long)skb->data;
+    void *data_end = (void *)(long)skb->data_end;
+    struct ethhdr *eth = data;
+    if ((void *)(eth + 1) > data_end || eth->h_proto != bpf_htons(ETH_P_IP))
+        return TC_ACT_OK;
+    struct iphdr *ip = (void *)(eth + 1);
+    if ((void *)(ip + 1) > data_end || ip->protocol != IPPROTO_TCP || ip->ihl != 5)
+        return TC_ACT_OK;
+    struct tcphdr *tcp = (void *)(ip + 1);
+    if ((void *)(tcp + 1) > data_end)
+        return TC_ACT_OK;
+    if (cfg->dst_port && tcp->dest != bpf_htons(cfg->dst_port))
+        return TC_ACT_OK;
+
+    __u32 tcp_off = ETH_HLEN + sizeof(struct iphdr);
+
+    if (cfg->flags & FLAG_RANDOM_WINDOW && cfg->window_max > cfg->window_min) {
+        __be16 old_window = tcp->window;
+        __u16 span = cfg->window_max - cfg->window_min + 1;
+        __be16 new_window = bpf_htons(cfg->window_min + bpf_get_prandom_u32() % span);
+        bpf_l4_csum_replace(skb, tcp_off + offsetof(struct tcphdr, check), old_window,
+                            new_window, sizeof(new_window));
+        bpf_skb_store_bytes(skb, tcp_off + offsetof(struct tcphdr, window), &new_window,
+                            sizeof(new_window), 0);
+    }
+
+    if (cfg->flags & FLAG_REWRITE_TTL) {
+        /* Re-read after the store above invalidated packet pointers */
+        __u8 old_ttl;
+        if (bpf_skb_load_bytes(skb, IP_TTL_OFF, &old_ttl, 1) < 0)
+            return TC_ACT_OK;
+        __u8 new_ttl = cfg->ttl;
+        /* TTL shares a 16-bit checksum word with the protocol byte */
+        bpf_l3_csum_replace(skb, IP_CSUM_OFF, bpf_htons(old_ttl << 8), bpf_htons(new_ttl << 8), 2);
+        bpf_skb_store_bytes(skb, IP_TTL_OFF, &new_ttl, 1, 0);
+    }
+
+    return TC_ACT_OK;
+}
+
+char _license[] SEC("license") = "GPL";
@@ -122,6 +122,44 @@ int apply_dynamic_pattern_rotation(
     int* output_len
 );
 
+/* Session key */
+
+/**
+ * Set the session key shared with the peer, e.g. exported from the TLS
+ * handshake; both sides must set the same key to read each other's traffic
+ * @param key Key material
+ * @param key_len Length of the key material
+ * @return 0 on success, -1 on failure
+ */
+int security_set_session_key(const unsigned char* key, int key_len);
+
+/* Kill switch */
+
+/**
+ * Terminate the session when a processing stage fails; a failing stage
+ * always fails its packet
+ * Later calls fail until security_init starts a new session
+ * @param enabled 1 = fail closed, 0 = fail only the packet (default)
+ * @return 0 on success, -1 on failure
+ */
+int security_set_fail_closed(int enabled);
+
+/**
+ * Check whether the kill switch has terminated the session
+ * @return 1 if terminated, 0 otherwise
+ */
+int security_is_terminated(void);
+
+/* Privacy mode */
+
+/**
+ * Stop persisting anything and redact destinations from logs, for this
+ * session and later ones
+ * @param enabled 1 = on, 0 = off (default)
+ * @return 0 on success, -1 on failure
+ */
+int security_set_privacy_mode(int enabled);
+
 /**
  * Get error message for last error
  * @return Error message string
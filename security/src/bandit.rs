@@ -0,0 +1,477 @@
+//! Multi-armed bandit strategy selection
+//! Treats each combination of evasion techniques as an arm and learns per
+//! destination network which ones get through, instead of choosing at random;
+//! the scoreboard can be saved to disk so learning survives restarts
+
+use crate::desync::DesyncStrategy;
+use crate::error::{Error, Result};
+use crate::privacy;
+use crate::sni_fallback::SniStrategy;
+use crate::tls_fragmentation::FragmentationStrategy;
+use parking_lot::RwLock;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io::ErrorKind;
+use std::path::Path;
+
+/// Format version of saved scoreboards
+const SCOREBOARD_VERSION: u32 = 1;
+
+/// One combination of evasion techniques
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct StrategyArm {
+    pub sni: SniStrategy,
+    /// ClientHello fragmentation, if any
+    pub fragmentation: Option<FragmentationStrategy>,
+    /// Desync trick sent ahead of the first data, if any
+    pub desync: Option<DesyncStrategy>,
+}
+
+impl StrategyArm {
+    /// Every combination of the given techniques
+    pub fn combinations(
+        sni: &[SniStrategy],
+        fragmentation: &[Option<FragmentationStrategy>],
+        desync: &[Option<DesyncStrategy>],
+    ) -> Vec<StrategyArm> {
+        let mut arms = Vec::with_capacity(sni.len() * fragmentation.len() * desync.len());
+        for &sni in sni {
+            for &fragmentation in fragmentation {
+                for &desync in desync {
+                    arms.push(StrategyArm {
+                        sni,
+                        fragmentation,
+                        desync,
+                    });
+                }
+            }
+        }
+        arms
+    }
+}
+
+/// How the next arm is picked once every arm has been tried
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SelectionPolicy {
+    /// Pick a random arm with probability `epsilon`, the best one otherwise
+    EpsilonGreedy { epsilon: f64 },
+    /// Pick the arm with the highest upper confidence bound (UCB1);
+    /// `exploration` weighs the bound, `2.0f64.sqrt()` being the classic value
+    Ucb { exploration: f64 },
+}
+
+impl Default for SelectionPolicy {
+    fn default() -> Self {
+        SelectionPolicy::Ucb {
+            exploration: std::f64::consts::SQRT_2,
+        }
+    }
+}
+
+/// Outcomes of one arm on one network
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArmStats {
+    pub successes: u64,
+    pub failures: u64,
+}
+
+impl ArmStats {
+    pub fn trials(&self) -> u64 {
+        self.successes + self.failures
+    }
+
+    /// Share of trials that succeeded, 0 before the first
+    pub fn success_rate(&self) -> f64 {
+        match self.trials() {
+            0 => 0.0,
+            trials => self.successes as f64 / trials as f64,
+        }
+    }
+}
+
+/// Outcomes of one arm as saved in a scoreboard
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArmRecord {
+    pub arm: StrategyArm,
+    #[serde(flatten)]
+    pub stats: ArmStats,
+}
+
+/// Saved state of a selector: the outcomes of every arm per network
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Scoreboard {
+    pub version: u32,
+    pub networks: BTreeMap<String, Vec<ArmRecord>>,
+}
+
+impl Default for Scoreboard {
+    fn default() -> Self {
+        Scoreboard {
+            version: SCOREBOARD_VERSION,
+            networks: BTreeMap::new(),
+        }
+    }
+}
+
+impl Scoreboard {
+    /// Read a scoreboard saved with `save`; a missing file is an empty one
+    pub fn load(path: &Path) -> Result<Self> {
+        let data = match std::fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e.into()),
+        };
+        let scoreboard: Scoreboard = serde_json::from_slice(&data).map_err(|e| {
+            Error::DataError(format!("Invalid scoreboard {}: {}", path.display(), e))
+        })?;
+        if scoreboard.version != SCOREBOARD_VERSION {
+            return Err(Error::DataError(format!(
+                "Scoreboard {} has unsupported version {}",
+                path.display(),
+                scoreboard.version
+            )));
+        }
+        Ok(scoreboard)
+    }
+
+    /// Write the scoreboard through a temporary file renamed into place, so
+    /// a crash mid-write leaves the previous state intact
+    ///
+    /// Does nothing in privacy mode.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if privacy::is_enabled() {
+            log::debug!("Privacy mode: not saving the strategy scoreboard");
+            return Ok(());
+        }
+        let data = serde_json::to_vec_pretty(self)
+            .map_err(|e| Error::DataError(format!("Failed to encode scoreboard: {}", e)))?;
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+        std::fs::write(&temp, data)?;
+        std::fs::rename(&temp, path)?;
+        privacy::record_store(
+            privacy::DataCategory::StrategyScoreboard,
+            privacy::Location::File {
+                path: path.to_path_buf(),
+            },
+        );
+        Ok(())
+    }
+}
+
+/// Learns per destination network which strategy combination works
+///
+/// Networks are keyed by whatever the caller can tell apart, typically the
+/// ASN of the access network ("AS197207" for MCI, "AS44244" for Irancell):
+/// the same arm that passes one operator's filters is often reset on
+/// another's. Untried arms are always tried first.
+pub struct StrategySelector {
+    arms: Vec<StrategyArm>,
+    policy: SelectionPolicy,
+    networks: RwLock<HashMap<String, HashMap<StrategyArm, ArmStats>>>,
+}
+
+impl StrategySelector {
+    pub fn new(arms: Vec<StrategyArm>, policy: SelectionPolicy) -> Result<Self> {
+        if arms.is_empty() {
+            return Err(Error::ConfigError(
+                "Strategy selector needs at least one arm".to_string(),
+            ));
+        }
+        match policy {
+            SelectionPolicy::EpsilonGreedy { epsilon } if !(0.0..=1.0).contains(&epsilon) => {
+                return Err(Error::ConfigError(format!(
+                    "Epsilon {} is outside [0, 1]",
+                    epsilon
+                )));
+            }
+            SelectionPolicy::Ucb { exploration } if exploration.is_nan() || exploration < 0.0 => {
+                return Err(Error::ConfigError(format!(
+                    "UCB exploration {} must be non-negative",
+                    exploration
+                )));
+            }
+            _ => {}
+        }
+        Ok(StrategySelector {
+            arms,
+            policy,
+            networks: RwLock::new(HashMap::new()),
+        })
+    }
+
+    pub fn arms(&self) -> &[StrategyArm] {
+        &self.arms
+    }
+
+    pub fn policy(&self) -> SelectionPolicy {
+        self.policy
+    }
+
+    /// Pick the arm to use for the next connection on a network
+    pub fn select(&self, network: &str) -> StrategyArm {
+        let networks = self.networks.read();
+        let stats = networks.get(network);
+        let stats_of = |arm: &StrategyArm| {
+            stats
+                .and_then(|stats| stats.get(arm))
+                .copied()
+                .unwrap_or_default()
+        };
+
+        if let Some(arm) = self.arms.iter().find(|arm| stats_of(arm).trials() == 0) {
+            return *arm;
+        }
+
+        match self.policy {
+            SelectionPolicy::EpsilonGreedy { epsilon } => {
+                let mut rng = rand::thread_rng();
+                if rng.gen_bool(epsilon) {
+                    return self.arms[rng.gen_range(0..self.arms.len())];
+                }
+                self.best_by(|arm| stats_of(arm).success_rate())
+            }
+            SelectionPolicy::Ucb { exploration } => {
+                let total: u64 = self.arms.iter().map(|arm| stats_of(arm).trials()).sum();
+                let log_total = (total as f64).ln();
+                self.best_by(|arm| {
+                    let stats = stats_of(arm);
+                    stats.success_rate() + exploration * (log_total / stats.trials() as f64).sqrt()
+                })
+            }
+        }
+    }
+
+    /// Record the outcome of a connection made with an arm
+    pub fn record(&self, network: &str, arm: StrategyArm, success: bool) {
+        let mut networks = self.networks.write();
+        let stats = networks
+            .entry(network.to_string())
+            .or_default()
+            .entry(arm)
+            .or_default();
+        if success {
+            stats.successes += 1;
+        } else {
+            stats.failures += 1;
+        }
+    }
+
+    /// Start arms on a network from prior outcomes, e.g. pseudo-counts from
+    /// published measurements; arms already observed there keep their record
+    pub fn seed(&self, network: &str, priors: impl IntoIterator<Item = (StrategyArm, ArmStats)>) {
+        let mut networks = self.networks.write();
+        let stats = networks.entry(network.to_string()).or_default();
+        for (arm, prior) in priors {
+            stats.entry(arm).or_insert(prior);
+        }
+    }
+
+    /// Outcomes per arm on a network, in arm order
+    pub fn stats(&self, network: &str) -> Vec<(StrategyArm, ArmStats)> {
+        let networks = self.networks.read();
+        let stats = networks.get(network);
+        self.arms
+            .iter()
+            .map(|arm| {
+                let arm_stats = stats.and_then(|stats| stats.get(arm)).copied();
+                (*arm, arm_stats.unwrap_or_default())
+            })
+            .collect()
+    }
+
+    /// The arm with the best success rate on a network, once it has been
+    /// tried there
+    pub fn best(&self, network: &str) -> Option<StrategyArm> {
+        let stats = self.stats(network);
+        stats
+            .iter()
+            .filter(|(_, stats)| stats.trials() > 0)
+            .max_by(|(_, a), (_, b)| a.success_rate().total_cmp(&b.success_rate()))
+            .map(|(arm, _)| *arm)
+    }
+
+    /// Current outcomes of every arm on every network
+    ///
+    /// Arms dropped from the configuration keep their records, so they
+    /// aren't forgotten if they come back.
+    pub fn scoreboard(&self) -> Scoreboard {
+        let networks = self.networks.read();
+        let mut scoreboard = Scoreboard::default();
+        for (network, stats) in networks.iter() {
+            let mut records: Vec<ArmRecord> = stats
+                .iter()
+                .map(|(arm, stats)| ArmRecord {
+                    arm: *arm,
+                    stats: *stats,
+                })
+                .collect();
+            records.sort_by_key(|record| std::cmp::Reverse(record.stats.trials()));
+            scoreboard.networks.insert(network.clone(), records);
+        }
+        scoreboard
+    }
+
+    /// Replace what was learnt with a saved scoreboard
+    pub fn restore(&self, scoreboard: &Scoreboard) {
+        let mut networks = self.networks.write();
+        *networks = scoreboard
+            .networks
+            .iter()
+            .map(|(network, records)| {
+                let stats = records
+                    .iter()
+                    .map(|record| (record.arm, record.stats))
+                    .collect();
+                (network.clone(), stats)
+            })
+            .collect();
+    }
+
+    /// Save what was learnt to a state file
+    pub fn save(&self, path: &Path) -> Result<()> {
+        self.scoreboard().save(path)
+    }
+
+    /// Restore what was learnt from a state file, if there is one
+    pub fn load(&self, path: &Path) -> Result<()> {
+        self.restore(&Scoreboard::load(path)?);
+        Ok(())
+    }
+
+    /// Arm with the highest score; the earliest one wins ties
+    fn best_by(&self, score: impl Fn(&StrategyArm) -> f64) -> StrategyArm {
+        let mut best = self.arms[0];
+        let mut best_score = score(&best);
+        for arm in &self.arms[1..] {
+            let arm_score = score(arm);
+            if arm_score > best_score {
+                best = *arm;
+                best_score = arm_score;
+            }
+        }
+        best
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn arms() -> Vec<StrategyArm> {
+        StrategyArm::combinations(
+            &[SniStrategy::FakeSni, SniStrategy::SniSplit],
+            &[None, Some(FragmentationStrategy::RecordSplit)],
+            &[None, Some(DesyncStrategy::FakeLowTtl)],
+        )
+    }
+
+    /// Run connections where only `working` gets through, returning how
+    /// often it was picked in the last hundred
+    fn converge(selector: &StrategySelector, network: &str, working: StrategyArm) -> usize {
+        let mut hits = 0;
+        for round in 0..500 {
+            let arm = selector.select(network);
+            selector.record(network, arm, arm == working);
+            if round >= 400 && arm == working {
+                hits += 1;
+            }
+        }
+        hits
+    }
+
+    #[test]
+    fn test_ucb_tries_every_arm_then_converges() {
+        let arms = arms();
+        assert_eq!(arms.len(), 8);
+        let selector = StrategySelector::new(arms.clone(), SelectionPolicy::default()).unwrap();
+        let mut first: Vec<StrategyArm> = (0..8)
+            .map(|_| {
+                let arm = selector.select("AS197207");
+                selector.record("AS197207", arm, false);
+                arm
+            })
+            .collect();
+        first.dedup();
+        assert_eq!(first, arms);
+
+        assert!(converge(&selector, "AS197207", arms[5]) > 80);
+        assert_eq!(selector.best("AS197207"), Some(arms[5]));
+    }
+
+    #[test]
+    fn test_epsilon_greedy_converges() {
+        let arms = arms();
+        let policy = SelectionPolicy::EpsilonGreedy { epsilon: 0.1 };
+        let selector = StrategySelector::new(arms.clone(), policy).unwrap();
+        assert!(converge(&selector, "AS44244", arms[2]) > 75);
+    }
+
+    #[test]
+    fn test_networks_learn_independently() {
+        let arms = arms();
+        let selector = StrategySelector::new(arms.clone(), SelectionPolicy::default()).unwrap();
+        converge(&selector, "AS197207", arms[1]);
+        converge(&selector, "AS44244", arms[6]);
+        assert_eq!(selector.best("AS197207"), Some(arms[1]));
+        assert_eq!(selector.best("AS44244"), Some(arms[6]));
+        assert_eq!(selector.best("AS58224"), None);
+        assert!(selector
+            .stats("AS58224")
+            .iter()
+            .all(|(_, stats)| stats.trials() == 0));
+    }
+
+    #[test]
+    fn test_scoreboard_survives_restart() {
+        let _privacy = privacy::TEST_LOCK.lock();
+        let path = std::env::temp_dir().join(format!("ips-scoreboard-{}.json", std::process::id()));
+        let arms = arms();
+        let selector = StrategySelector::new(arms.clone(), SelectionPolicy::default()).unwrap();
+        converge(&selector, "AS197207", arms[3]);
+        selector.save(&path).unwrap();
+
+        let restarted = StrategySelector::new(arms.clone(), SelectionPolicy::default()).unwrap();
+        restarted.load(&path).unwrap();
+        assert_eq!(restarted.stats("AS197207"), selector.stats("AS197207"));
+        assert_eq!(restarted.best("AS197207"), Some(arms[3]));
+        // Every arm has been tried, so selection goes straight to the winner
+        assert!(converge(&restarted, "AS197207", arms[3]) > 80);
+
+        std::fs::write(&path, b"{not json").unwrap();
+        assert!(restarted.load(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+        // No state file yet: start from zero knowledge
+        restarted.load(&path).unwrap();
+        assert_eq!(restarted.best("AS197207"), None);
+    }
+
+    #[test]
+    fn test_no_scoreboard_in_privacy_mode() {
+        let _privacy = privacy::TEST_LOCK.lock();
+        let path = std::env::temp_dir().join(format!("ips-private-{}.json", std::process::id()));
+        let selector = StrategySelector::new(arms(), SelectionPolicy::default()).unwrap();
+        privacy::set_enabled(true);
+        selector.save(&path).unwrap();
+        privacy::set_enabled(false);
+        assert!(!path.exists());
+
+        selector.save(&path).unwrap();
+        let listed = privacy::statement()
+            .stores
+            .iter()
+            .any(|store| store.location == privacy::Location::File { path: path.clone() });
+        std::fs::remove_file(&path).unwrap();
+        assert!(listed);
+    }
+
+    #[test]
+    fn test_invalid_settings() {
+        assert!(StrategySelector::new(Vec::new(), SelectionPolicy::default()).is_err());
+        let policy = SelectionPolicy::EpsilonGreedy { epsilon: 1.5 };
+        assert!(StrategySelector::new(arms(), policy).is_err());
+        let policy = SelectionPolicy::Ucb { exploration: -1.0 };
+        assert!(StrategySelector::new(arms(), policy).is_err());
+    }
+}
@@ -1,35 +1,207 @@
-use iran_proxy_security::{SecurityProcessor, SecurityConfig};
-use log::info;
+//! Security worker daemon
+//!
+//! Usage: security_worker [--config <settings.{json,yaml,toml}[.enc]>]
+//!                        [--key-file <file> | --passphrase-file <file>]
+//!                        [--privacy] [setting=value ...]
+//!        security_worker seal <settings file> (--key-file <file> | --passphrase-file <file>)
+//!
+//! Settings come from the file, `IPS_*` environment variables and the
+//! assignments, in that order; `profile=<name>` or `IPS_PROFILE` picks one
+//! of the file's named profiles over its `active` entry. SIGHUP reloads
+//! them, so editing `active` switches profiles at runtime, and logs which
+//! settings changed; SIGTERM or SIGINT shuts the worker down. `seal` writes an encrypted copy
+//! of a settings file next to it, with `.enc` appended. `--privacy` is
+//! short for `processor.privacy_mode=true`.
+
+use iran_proxy_security::config_encryption::{self, ConfigKey};
+use iran_proxy_security::profiles::ActiveProfile;
+use iran_proxy_security::{overrides, SecurityProcessor};
+use log::{error, info, warn};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use tokio::signal::unix::{signal, SignalKind};
+
+struct Args {
+    seal: Option<PathBuf>,
+    config: Option<PathBuf>,
+    key: Option<ConfigKey>,
+    overrides: Vec<String>,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = Args {
+        seal: None,
+        config: None,
+        key: None,
+        overrides: Vec::new(),
+    };
+    let mut argv = std::env::args().skip(1).peekable();
+    if argv.peek().map(String::as_str) == Some("seal") {
+        argv.next();
+        args.seal = Some(PathBuf::from(
+            argv.next().ok_or("seal needs a settings file")?,
+        ));
+    }
+    while let Some(arg) = argv.next() {
+        if arg == "--key-file" {
+            let path = argv.next().ok_or("--key-file needs a file")?;
+            let key = ConfigKey::device_key_file(path.as_ref()).map_err(|e| e.to_string())?;
+            args.key = Some(key);
+        } else if arg == "--passphrase-file" {
+            let path = argv.next().ok_or("--passphrase-file needs a file")?;
+            let passphrase = std::fs::read_to_string(&path)
+                .map_err(|e| format!("cannot read {}: {}", path, e))?;
+            args.key = Some(ConfigKey::Passphrase(
+                passphrase.trim_end_matches(['\r', '\n']).to_string(),
+            ));
+        } else if arg == "--config" || arg == "-c" {
+            let path = argv.next().ok_or("--config needs a file")?;
+            args.config = Some(PathBuf::from(path));
+        } else if let Some(path) = arg.strip_prefix("--config=") {
+            args.config = Some(PathBuf::from(path));
+        } else if arg == "--privacy" {
+            args.overrides.push("processor.privacy_mode=true".to_string());
+        } else {
+            args.overrides.push(arg);
+        }
+    }
+    Ok(args)
+}
+
+fn load(args: &Args) -> Result<ActiveProfile, String> {
+    let cli = args
+        .overrides
+        .iter()
+        .map(|assignment| overrides::Override::parse(assignment))
+        .collect::<Result<Vec<_>, _>>()?;
+    overrides::resolve_profile(
+        args.config.as_deref(),
+        args.key.as_ref(),
+        std::env::vars(),
+        &cli,
+    )
+}
+
+fn profile_name(profile: &ActiveProfile) -> &str {
+    profile.name.as_deref().unwrap_or("(shared settings)")
+}
+
+/// Write an encrypted copy of a settings file
+fn seal(input: &std::path::Path, key: Option<&ConfigKey>) -> Result<PathBuf, String> {
+    let key = key.ok_or("seal needs --key-file or --passphrase-file")?;
+    let plaintext =
+        std::fs::read(input).map_err(|e| format!("cannot read {}: {}", input.display(), e))?;
+    let sealed = config_encryption::seal(&plaintext, key).map_err(|e| e.to_string())?;
+    let mut output = input.as_os_str().to_owned();
+    output.push(".");
+    output.push(config_encryption::EXTENSION);
+    let output = PathBuf::from(output);
+    std::fs::write(&output, sealed)
+        .map_err(|e| format!("cannot write {}: {}", output.display(), e))?;
+    Ok(output)
+}
 
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
     env_logger::init();
 
-    info!("Iran Proxy Security Module - Starting");
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!(
+                "Usage: security_worker [--config <file>] [--key-file <file> | \
+                 --passphrase-file <file>] [--privacy] [setting=value ...]"
+            );
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Some(input) = &args.seal {
+        return match seal(input, args.key.as_ref()) {
+            Ok(output) => {
+                println!("{}", output.display());
+                ExitCode::SUCCESS
+            }
+            Err(e) => {
+                eprintln!("Failed to seal settings: {}", e);
+                ExitCode::FAILURE
+            }
+        };
+    }
+    let mut profile = match load(&args) {
+        Ok(profile) => profile,
+        Err(e) => {
+            eprintln!("Failed to load settings: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut processor = match SecurityProcessor::with_settings(profile.settings.clone()) {
+        Ok(processor) => processor,
+        Err(e) => {
+            eprintln!("Failed to initialize security processor: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
 
-    // Create default security processor
-    match SecurityProcessor::new() {
-        Ok(processor) => {
-            info!("Security processor initialized successfully");
-            info!("Configuration: {:?}", processor.config());
+    let (mut hangup, mut terminate, mut interrupt) = match (
+        signal(SignalKind::hangup()),
+        signal(SignalKind::terminate()),
+        signal(SignalKind::interrupt()),
+    ) {
+        (Ok(hangup), Ok(terminate), Ok(interrupt)) => (hangup, terminate, interrupt),
+        _ => {
+            eprintln!("Failed to install signal handlers");
+            return ExitCode::FAILURE;
+        }
+    };
 
-            // Example usage
-            let test_data = b"Example proxy traffic data";
+    info!("Iran Proxy Security Module - Running");
+    info!(
+        "Profile: {}, SNI pool {}",
+        profile_name(&profile),
+        profile.sni_pool().name()
+    );
+    info!("Configuration: {:?}", processor.config());
 
-            match processor.process_outgoing(test_data) {
-                Ok(processed) => {
-                    info!("Successfully processed outgoing traffic");
-                    info!("Original size: {}, Processed size: {}", test_data.len(), processed.len());
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => {
+                info!("SIGHUP received, reloading settings");
+                // A bad reload keeps the running settings
+                let reloaded = match load(&args) {
+                    Ok(reloaded) => reloaded,
+                    Err(e) => {
+                        warn!("Reload failed, keeping current settings: {}", e);
+                        continue;
+                    }
+                };
+                let changes = overrides::diff(&profile.settings, &reloaded.settings);
+                if changes.is_empty() && reloaded.name == profile.name {
+                    info!("Settings unchanged");
+                    continue;
+                }
+                if let Err(e) = processor.update_settings(reloaded.settings.clone()) {
+                    error!("Failed to apply reloaded settings: {}", e);
+                    continue;
+                }
+                if reloaded.name != profile.name {
+                    info!(
+                        "Switched profile {} -> {}",
+                        profile_name(&profile),
+                        profile_name(&reloaded)
+                    );
                 }
-                Err(e) => {
-                    eprintln!("Error processing traffic: {}", e);
+                // Paths only: values include hosts and SNI pool entries
+                for change in &changes {
+                    info!("Changed {}", change.path);
                 }
+                profile = reloaded;
             }
-        }
-        Err(e) => {
-            eprintln!("Failed to initialize security processor: {}", e);
+            _ = terminate.recv() => break,
+            _ = interrupt.recv() => break,
         }
     }
 
     info!("Iran Proxy Security Module - Shutdown");
+    ExitCode::SUCCESS
 }
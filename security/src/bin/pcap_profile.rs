@@ -0,0 +1,41 @@
+//! Extract a traffic profile from a PCAP capture
+//!
+//! Usage: pcap_profile <capture.pcap> [client-ip] > profile.json
+
+use iran_proxy_security::traffic_profile::TrafficProfile;
+use std::net::IpAddr;
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let Some(path) = args.first() else {
+        eprintln!("Usage: pcap_profile <capture.pcap> [client-ip] > profile.json");
+        return ExitCode::FAILURE;
+    };
+    let client = match args.get(1).map(|ip| ip.parse::<IpAddr>()).transpose() {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("Invalid client address: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let profile = std::fs::read(path)
+        .map_err(Into::into)
+        .and_then(|data| TrafficProfile::from_pcap(&data, client));
+    match profile {
+        Ok(profile) => {
+            eprintln!(
+                "{} upstream and {} downstream packets",
+                profile.upstream.packets.len(),
+                profile.downstream.packets.len()
+            );
+            println!("{}", serde_json::to_string_pretty(&profile).unwrap());
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("Failed to extract profile from {}: {}", path, e);
+            ExitCode::FAILURE
+        }
+    }
+}
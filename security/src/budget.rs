@@ -0,0 +1,150 @@
+//! Bandwidth overhead budget
+//! Tracks how many bytes go on the wire per byte of payload and turns the
+//! padding, decoy and noise volume down when the overhead exceeds the budget
+
+use crate::error::{Error, Result};
+
+/// Weight older packets keep at each new one; about the last 100 packets
+/// count
+const DECAY: f64 = 0.99;
+/// Change of intensity per packet
+const STEP: f64 = 0.02;
+/// Intensity climbs back only once the overhead is this far under budget
+const RECOVER_BELOW: f64 = 0.8;
+
+/// Whether the overhead is within budget
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BudgetStatus {
+    /// Within budget at full intensity
+    Within,
+    /// Within or approaching budget with padding, decoys and noise reduced
+    Reduced,
+    /// Over budget with everything optional already off; the rest is the
+    /// unavoidable cost of the camouflage itself
+    Exceeded,
+}
+
+/// Overhead controller
+///
+/// Feed it every packet's payload and wire sizes; `intensity` is the share
+/// of the configured padding, decoy and noise volume to use, from 1.0 (all
+/// of it) down to 0.0.
+#[derive(Clone, Debug)]
+pub struct OverheadBudget {
+    max_ratio: f64,
+    original: f64,
+    transmitted: f64,
+    intensity: f64,
+    status: BudgetStatus,
+}
+
+impl OverheadBudget {
+    /// `max_ratio` is the extra wire bytes allowed per payload byte, so
+    /// 0.15 allows 15% overhead
+    pub fn new(max_ratio: f64) -> Result<Self> {
+        if !max_ratio.is_finite() || max_ratio <= 0.0 {
+            return Err(Error::ConfigError(
+                "Overhead budget must be a positive ratio".to_string(),
+            ));
+        }
+        Ok(OverheadBudget {
+            max_ratio,
+            original: 0.0,
+            transmitted: 0.0,
+            intensity: 1.0,
+            status: BudgetStatus::Within,
+        })
+    }
+
+    pub fn max_ratio(&self) -> f64 {
+        self.max_ratio
+    }
+
+    /// Recent overhead: extra wire bytes per payload byte
+    pub fn ratio(&self) -> f64 {
+        if self.original == 0.0 {
+            return 0.0;
+        }
+        self.transmitted / self.original - 1.0
+    }
+
+    /// Share of optional overhead to use, 0.0 to 1.0
+    pub fn intensity(&self) -> f64 {
+        self.intensity
+    }
+
+    pub fn status(&self) -> BudgetStatus {
+        self.status
+    }
+
+    /// Account for one packet and adjust the intensity
+    pub fn record(&mut self, original: usize, transmitted: usize) -> BudgetStatus {
+        self.original = self.original * DECAY + original as f64;
+        self.transmitted = self.transmitted * DECAY + transmitted as f64;
+
+        let ratio = self.ratio();
+        if ratio > self.max_ratio {
+            self.intensity = (self.intensity - STEP).max(0.0);
+        } else if ratio < self.max_ratio * RECOVER_BELOW {
+            self.intensity = (self.intensity + STEP).min(1.0);
+        }
+
+        let status = if ratio > self.max_ratio && self.intensity == 0.0 {
+            BudgetStatus::Exceeded
+        } else if self.intensity < 1.0 {
+            BudgetStatus::Reduced
+        } else {
+            BudgetStatus::Within
+        };
+        if status == BudgetStatus::Exceeded && self.status != BudgetStatus::Exceeded {
+            log::warn!(
+                "Overhead {:.0}% exceeds the {:.0}% budget with padding, decoys and noise off",
+                ratio * 100.0,
+                self.max_ratio * 100.0
+            );
+        }
+        self.status = status;
+        status
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_intensity_settles_within_budget() {
+        let mut budget = OverheadBudget::new(0.15).unwrap();
+        // Fixed 5% framing plus padding of up to 40% at full intensity
+        for _ in 0..2000 {
+            let padding = (400.0 * budget.intensity()) as usize;
+            budget.record(1000, 1050 + padding);
+        }
+        assert!(budget.ratio() < 0.17, "{}", budget.ratio());
+        assert!(budget.intensity() > 0.1 && budget.intensity() < 0.4);
+        assert_eq!(budget.status(), BudgetStatus::Reduced);
+    }
+
+    #[test]
+    fn test_unmeetable_budget_reported() {
+        let mut budget = OverheadBudget::new(0.1).unwrap();
+        let mut status = BudgetStatus::Within;
+        for _ in 0..200 {
+            status = budget.record(100, 150);
+        }
+        assert_eq!(status, BudgetStatus::Exceeded);
+        assert_eq!(budget.intensity(), 0.0);
+
+        // Recovers once traffic is cheap again
+        for _ in 0..2000 {
+            status = budget.record(1000, 1010);
+        }
+        assert_eq!(status, BudgetStatus::Within);
+    }
+
+    #[test]
+    fn test_invalid_budget() {
+        assert!(OverheadBudget::new(0.0).is_err());
+        assert!(OverheadBudget::new(f64::NAN).is_err());
+    }
+}
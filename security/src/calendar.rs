@@ -0,0 +1,382 @@
+//! Strategy calendar
+//! Raises the adaptation level on a schedule, for the evening hours and the
+//! protest and national-event periods when filtering in Iran tightens
+
+use crate::error::{Error, Result};
+use crate::morphing::TrafficShapeProfile;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Iran Standard Time, UTC+03:30; Iran has kept no daylight saving since 2022
+pub const IRAN_UTC_OFFSET_MINUTES: i32 = 3 * 60 + 30;
+
+const MINUTES_PER_DAY: i64 = 24 * 60;
+
+/// Minutes since midnight, written "HH:MM"
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeOfDay(u16);
+
+impl TimeOfDay {
+    pub fn new(hour: u8, minute: u8) -> Result<Self> {
+        if hour > 23 || minute > 59 {
+            return Err(Error::ConfigError(format!(
+                "Invalid time of day {}:{}",
+                hour, minute
+            )));
+        }
+        Ok(TimeOfDay(hour as u16 * 60 + minute as u16))
+    }
+
+    pub fn minutes(self) -> u16 {
+        self.0
+    }
+}
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        let invalid = || Error::ConfigError(format!("Invalid time of day '{}'", value));
+        let (hour, minute) = value.split_once(':').ok_or_else(invalid)?;
+        TimeOfDay::new(
+            hour.parse().map_err(|_| invalid())?,
+            minute.parse().map_err(|_| invalid())?,
+        )
+    }
+}
+
+impl From<TimeOfDay> for String {
+    fn from(time: TimeOfDay) -> String {
+        time.to_string()
+    }
+}
+
+impl fmt::Display for TimeOfDay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02}:{:02}", self.0 / 60, self.0 % 60)
+    }
+}
+
+/// Gregorian date, written "YYYY-MM-DD"
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Date {
+    pub year: i32,
+    pub month: u8,
+    pub day: u8,
+}
+
+impl Date {
+    pub fn new(year: i32, month: u8, day: u8) -> Result<Self> {
+        let date = Date { year, month, day };
+        if month == 0 || month > 12 || day == 0 || Date::from_days(date.days()) != date {
+            return Err(Error::ConfigError(format!(
+                "Invalid date {}-{}-{}",
+                year, month, day
+            )));
+        }
+        Ok(date)
+    }
+
+    /// Days since 1970-01-01
+    fn days(self) -> i64 {
+        let year = self.year as i64 - (self.month <= 2) as i64;
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let month = self.month as i64;
+        let day_of_year =
+            (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        era * 146_097 + day_of_era - 719_468
+    }
+
+    fn from_days(days: i64) -> Self {
+        let days = days + 719_468;
+        let era = days.div_euclid(146_097);
+        let day_of_era = days - era * 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u8;
+        let month = if shifted_month < 10 {
+            shifted_month + 3
+        } else {
+            shifted_month - 9
+        } as u8;
+        let year = (year_of_era + era * 400 + (month <= 2) as i64) as i32;
+        Date { year, month, day }
+    }
+}
+
+impl TryFrom<String> for Date {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        let invalid = || Error::ConfigError(format!("Invalid date '{}'", value));
+        let mut parts = value.splitn(3, '-');
+        let mut next = || parts.next().ok_or_else(invalid);
+        let (year, month, day) = (next()?, next()?, next()?);
+        Date::new(
+            year.parse().map_err(|_| invalid())?,
+            month.parse().map_err(|_| invalid())?,
+            day.parse().map_err(|_| invalid())?,
+        )
+    }
+}
+
+impl From<Date> for String {
+    fn from(date: Date) -> String {
+        date.to_string()
+    }
+}
+
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+/// Day of the week, in Iranian order
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Weekday {
+    Saturday,
+    Sunday,
+    Monday,
+    Tuesday,
+    Wednesday,
+    Thursday,
+    Friday,
+}
+
+impl Weekday {
+    fn from_days(days: i64) -> Self {
+        // 1970-01-01 was a Thursday
+        const WEEK: [Weekday; 7] = [
+            Weekday::Thursday,
+            Weekday::Friday,
+            Weekday::Saturday,
+            Weekday::Sunday,
+            Weekday::Monday,
+            Weekday::Tuesday,
+            Weekday::Wednesday,
+        ];
+        WEEK[days.rem_euclid(7) as usize]
+    }
+}
+
+/// When a calendar rule applies, in the calendar's local time
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Window {
+    /// A daily time span; `start` after `end` runs past midnight. `days`
+    /// limits it to the days it starts on, every day when empty
+    Daily {
+        start: TimeOfDay,
+        end: TimeOfDay,
+        #[serde(default)]
+        days: Vec<Weekday>,
+    },
+    /// Whole days from `from` to `to`, both included
+    Dates { from: Date, to: Date },
+}
+
+impl Window {
+    /// Whether the window covers a local time given as minutes since
+    /// 1970-01-01 00:00
+    fn contains(&self, local_minutes: i64) -> bool {
+        let days = local_minutes.div_euclid(MINUTES_PER_DAY);
+        let minute = local_minutes.rem_euclid(MINUTES_PER_DAY) as u16;
+        match self {
+            Window::Daily {
+                start,
+                end,
+                days: on,
+            } => {
+                let starts_on = |days: i64| on.is_empty() || on.contains(&Weekday::from_days(days));
+                if start <= end {
+                    (start.0..end.0).contains(&minute) && starts_on(days)
+                } else if minute >= start.0 {
+                    starts_on(days)
+                } else {
+                    minute < end.0 && starts_on(days - 1)
+                }
+            }
+            Window::Dates { from, to } => (from.days()..=to.days()).contains(&days),
+        }
+    }
+}
+
+/// What an active rule changes
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Escalation {
+    /// Adaptation level held at least while the rule is active; decoys,
+    /// noise and padding grow with it
+    pub min_adaptation_level: u8,
+    /// Traffic shape for sessions opened while the rule is active
+    #[serde(default)]
+    pub traffic_shape: Option<TrafficShapeProfile>,
+}
+
+/// A named window with its escalation
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CalendarRule {
+    pub name: String,
+    pub window: Window,
+    pub escalation: Escalation,
+}
+
+/// Rules raising evasion on a schedule
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StrategyCalendar {
+    /// Offset of the local time the windows are written in
+    #[serde(default = "default_utc_offset")]
+    pub utc_offset_minutes: i32,
+    pub rules: Vec<CalendarRule>,
+}
+
+fn default_utc_offset() -> i32 {
+    IRAN_UTC_OFFSET_MINUTES
+}
+
+impl Default for StrategyCalendar {
+    /// Heavier evasion through the evening peak, 18:00 to 01:00 Tehran time
+    fn default() -> Self {
+        StrategyCalendar {
+            utc_offset_minutes: IRAN_UTC_OFFSET_MINUTES,
+            rules: vec![CalendarRule {
+                name: "evening".to_string(),
+                window: Window::Daily {
+                    start: TimeOfDay(18 * 60),
+                    end: TimeOfDay(60),
+                    days: Vec::new(),
+                },
+                escalation: Escalation {
+                    min_adaptation_level: 3,
+                    traffic_shape: None,
+                },
+            }],
+        }
+    }
+}
+
+impl StrategyCalendar {
+    pub fn validate(&self) -> Result<()> {
+        for rule in &self.rules {
+            if rule.escalation.min_adaptation_level == 0 {
+                return Err(Error::ConfigError(format!(
+                    "Calendar rule '{}' needs an adaptation level of at least 1",
+                    rule.name
+                )));
+            }
+            if let Window::Dates { from, to } = &rule.window {
+                if from > to {
+                    return Err(Error::ConfigError(format!(
+                        "Calendar rule '{}' ends before it starts",
+                        rule.name
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Rules active at a point in time
+    pub fn active(&self, at: SystemTime) -> Vec<&CalendarRule> {
+        let utc_minutes = match at.duration_since(UNIX_EPOCH) {
+            Ok(since) => (since.as_secs() / 60) as i64,
+            Err(before) => -(before.duration().as_secs().div_ceil(60) as i64),
+        };
+        let local = utc_minutes + self.utc_offset_minutes as i64;
+        self.rules
+            .iter()
+            .filter(|rule| rule.window.contains(local))
+            .collect()
+    }
+
+    /// Combined escalation of the rules active at a point in time: the
+    /// highest level, and the shape of the first rule that sets one
+    pub fn escalation(&self, at: SystemTime) -> Option<Escalation> {
+        let active = self.active(at);
+        let min_adaptation_level = active
+            .iter()
+            .map(|rule| rule.escalation.min_adaptation_level)
+            .max()?;
+        Some(Escalation {
+            min_adaptation_level,
+            traffic_shape: active.iter().find_map(|rule| rule.escalation.traffic_shape),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// A Tehran local time as a system time
+    fn tehran(date: &str, time: &str) -> SystemTime {
+        let date = Date::try_from(date.to_string()).unwrap();
+        let time = TimeOfDay::try_from(time.to_string()).unwrap();
+        let minutes =
+            date.days() * MINUTES_PER_DAY + time.minutes() as i64 - IRAN_UTC_OFFSET_MINUTES as i64;
+        UNIX_EPOCH + Duration::from_secs(minutes as u64 * 60)
+    }
+
+    #[test]
+    fn test_dates_and_weekdays() {
+        for days in [-1, 0, 59, 365, 11_016, 20_742, 100_000] {
+            let date = Date::from_days(days);
+            assert_eq!(date.days(), days, "{}", date);
+        }
+        assert_eq!(Date::from_days(0).to_string(), "1970-01-01");
+        assert_eq!(Date::new(2024, 2, 29).unwrap().days(), 19_782);
+        assert!(Date::new(2023, 2, 29).is_err());
+        assert!(TimeOfDay::try_from("24:00".to_string()).is_err());
+        // 2026-10-16 is a Friday
+        let friday = Date::new(2026, 10, 16).unwrap().days();
+        assert_eq!(Weekday::from_days(friday), Weekday::Friday);
+    }
+
+    #[test]
+    fn test_evening_window_spans_midnight() {
+        let calendar = StrategyCalendar::default();
+        assert!(calendar.escalation(tehran("2026-10-16", "17:59")).is_none());
+        let evening = calendar.escalation(tehran("2026-10-16", "21:30")).unwrap();
+        assert_eq!(evening.min_adaptation_level, 3);
+        assert!(calendar.escalation(tehran("2026-10-17", "00:59")).is_some());
+        assert!(calendar.escalation(tehran("2026-10-17", "01:00")).is_none());
+    }
+
+    #[test]
+    fn test_event_rules_combine() {
+        let json = r#"{
+            "rules": [
+                {"name": "weekend", "window": {"kind": "daily", "start": "22:00",
+                 "end": "02:00", "days": ["thursday"]},
+                 "escalation": {"min_adaptation_level": 2}},
+                {"name": "anniversary", "window": {"kind": "dates", "from": "2026-09-15",
+                 "to": "2026-09-18"},
+                 "escalation": {"min_adaptation_level": 5, "traffic_shape": "streaming"}}
+            ]
+        }"#;
+        let calendar: StrategyCalendar = serde_json::from_str(json).unwrap();
+        assert_eq!(calendar.utc_offset_minutes, IRAN_UTC_OFFSET_MINUTES);
+        calendar.validate().unwrap();
+
+        // 2026-09-17 is a Thursday in the event period
+        let both = calendar.escalation(tehran("2026-09-17", "23:00")).unwrap();
+        assert_eq!(both.min_adaptation_level, 5);
+        assert_eq!(both.traffic_shape, Some(TrafficShapeProfile::Streaming));
+        assert_eq!(calendar.active(tehran("2026-09-19", "01:00")).len(), 0);
+        let weekend = calendar.active(tehran("2026-09-25", "01:00"));
+        assert_eq!(weekend[0].name, "weekend");
+
+        let round_trip: StrategyCalendar =
+            serde_json::from_str(&serde_json::to_string(&calendar).unwrap()).unwrap();
+        assert_eq!(round_trip, calendar);
+    }
+}
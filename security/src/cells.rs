@@ -0,0 +1,265 @@
+//! Fixed-size cell mode
+//! Carries all traffic in constant 512- or 1024-byte cells, Tor-style, with
+//! padding cells filling the gaps, so neither packet sizes nor idle periods
+//! say anything about the payload. Each cell is framed as a TLS
+//! application-data record, so a stream of cells still reads as TLS
+
+use crate::config::ObfuscationConfig;
+use crate::error::{Error, Result};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+/// Record header every cell starts with
+const RECORD_HEADER: [u8; 3] = [0x17, 0x03, 0x03];
+const RECORD_HEADER_LEN: usize = 5;
+
+/// Record header, command byte and payload length ahead of each cell's
+/// payload
+const CELL_HEADER_LEN: usize = RECORD_HEADER_LEN + 3;
+
+const COMMAND_PADDING: u8 = 0;
+const COMMAND_DATA: u8 = 1;
+
+/// Size of the cells traffic is carried in
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CellMode {
+    /// No cells; traffic keeps its own sizes
+    #[default]
+    Off,
+    /// 512-byte cells
+    Small,
+    /// 1024-byte cells
+    Large,
+}
+
+impl CellMode {
+    /// Cell size in bytes, `None` when off
+    pub fn cell_size(self) -> Option<usize> {
+        match self {
+            CellMode::Off => None,
+            CellMode::Small => Some(512),
+            CellMode::Large => Some(1024),
+        }
+    }
+}
+
+/// Measured cost of cell mode
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CellStats {
+    pub data_cells: u64,
+    pub padding_cells: u64,
+    /// Payload bytes carried
+    pub payload_bytes: u64,
+    /// Bytes of all cells sent
+    pub wire_bytes: u64,
+}
+
+impl CellStats {
+    /// Share of the bytes sent that were not payload
+    pub fn overhead(&self) -> f64 {
+        if self.wire_bytes == 0 {
+            return 0.0;
+        }
+        (self.wire_bytes - self.payload_bytes) as f64 / self.wire_bytes as f64
+    }
+}
+
+/// Splits a byte stream into fixed-size cells and reassembles it
+///
+/// For constant-rate operation, `push` outgoing data and send `next_cell`
+/// on every tick: it returns a data cell while data is queued and a padding
+/// cell otherwise.
+#[derive(Clone, Debug)]
+pub struct CellCodec {
+    cell_size: usize,
+    queue: VecDeque<u8>,
+    incoming: Vec<u8>,
+    stats: CellStats,
+}
+
+impl CellCodec {
+    pub fn new(cell_size: usize) -> Result<Self> {
+        if cell_size <= CELL_HEADER_LEN || cell_size - RECORD_HEADER_LEN > u16::MAX as usize {
+            return Err(Error::ConfigError(format!(
+                "Invalid cell size {}",
+                cell_size
+            )));
+        }
+        Ok(CellCodec {
+            cell_size,
+            queue: VecDeque::new(),
+            incoming: Vec::new(),
+            stats: CellStats::default(),
+        })
+    }
+
+    /// Create a codec from the obfuscation settings
+    pub fn from_config(config: &ObfuscationConfig) -> Result<Self> {
+        let cell_size = config
+            .cell_mode
+            .cell_size()
+            .ok_or_else(|| Error::ConfigError("Cell mode is off".to_string()))?;
+        Self::new(cell_size)
+    }
+
+    pub fn cell_size(&self) -> usize {
+        self.cell_size
+    }
+
+    /// Counters of everything sent so far
+    pub fn stats(&self) -> CellStats {
+        self.stats
+    }
+
+    /// Queue outgoing data
+    pub fn push(&mut self, data: &[u8]) {
+        self.queue.extend(data);
+    }
+
+    /// Outgoing bytes waiting for a cell
+    pub fn queued(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// The next cell to send: queued data if any, padding otherwise
+    pub fn next_cell(&mut self) -> Vec<u8> {
+        let take = self.queue.len().min(self.cell_size - CELL_HEADER_LEN);
+        let command = if take == 0 {
+            self.stats.padding_cells += 1;
+            COMMAND_PADDING
+        } else {
+            self.stats.data_cells += 1;
+            COMMAND_DATA
+        };
+
+        let mut cell = Vec::with_capacity(self.cell_size);
+        cell.extend_from_slice(&RECORD_HEADER);
+        cell.extend_from_slice(&((self.cell_size - RECORD_HEADER_LEN) as u16).to_be_bytes());
+        cell.push(command);
+        cell.extend_from_slice(&(take as u16).to_be_bytes());
+        cell.extend(self.queue.drain(..take));
+        let mut padding = vec![0u8; self.cell_size - cell.len()];
+        rand::thread_rng().fill(&mut padding[..]);
+        cell.extend(padding);
+
+        self.stats.payload_bytes += take as u64;
+        self.stats.wire_bytes += self.cell_size as u64;
+        cell
+    }
+
+    /// Queue `data` and return the cells carrying everything queued
+    pub fn encode(&mut self, data: &[u8]) -> Vec<u8> {
+        self.push(data);
+        let mut cells = Vec::new();
+        while !self.queue.is_empty() {
+            cells.extend(self.next_cell());
+        }
+        cells
+    }
+
+    /// Feed received bytes, returning the payload of every complete cell;
+    /// a partial cell waits for the rest
+    pub fn decode(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        self.incoming.extend_from_slice(data);
+        let mut payload = Vec::new();
+        let mut offset = 0;
+        while self.incoming.len() - offset >= self.cell_size {
+            let cell = &self.incoming[offset..offset + self.cell_size];
+            if cell[..3] != RECORD_HEADER {
+                return Err(Error::DataError("Cell is not a TLS record".to_string()));
+            }
+            let len = u16::from_be_bytes([cell[6], cell[7]]) as usize;
+            match cell[5] {
+                COMMAND_PADDING => {}
+                COMMAND_DATA if CELL_HEADER_LEN + len <= self.cell_size => {
+                    payload.extend_from_slice(&cell[CELL_HEADER_LEN..CELL_HEADER_LEN + len]);
+                }
+                COMMAND_DATA => {
+                    return Err(Error::DataError("Cell length exceeds cell".to_string()));
+                }
+                command => {
+                    return Err(Error::DataError(format!(
+                        "Unknown cell command {}",
+                        command
+                    )));
+                }
+            }
+            offset += self.cell_size;
+        }
+        self.incoming.drain(..offset);
+        Ok(payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cells_round_trip() {
+        for mode in [CellMode::Small, CellMode::Large] {
+            let size = mode.cell_size().unwrap();
+            let mut sender = CellCodec::new(size).unwrap();
+            let mut receiver = CellCodec::new(size).unwrap();
+            let data: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+
+            let cells = sender.encode(&data);
+            assert!(cells.len().is_multiple_of(size));
+            // Split across reads mid-cell
+            let mut received = receiver.decode(&cells[..size + 7]).unwrap();
+            received.extend(receiver.decode(&cells[size + 7..]).unwrap());
+            assert_eq!(received, data);
+        }
+    }
+
+    #[test]
+    fn test_padding_cells_fill_gaps() {
+        let mut sender = CellCodec::new(512).unwrap();
+        let mut receiver = CellCodec::new(512).unwrap();
+        sender.push(b"hello");
+
+        let cells: Vec<Vec<u8>> = (0..4).map(|_| sender.next_cell()).collect();
+        assert!(cells.iter().all(|cell| cell.len() == 512));
+        assert!(cells
+            .iter()
+            .all(|cell| cell[..5] == [0x17, 0x03, 0x03, 0x01, 0xFB]));
+        assert_eq!(receiver.decode(&cells.concat()).unwrap(), b"hello");
+
+        let stats = sender.stats();
+        assert_eq!(stats.data_cells, 1);
+        assert_eq!(stats.padding_cells, 3);
+        assert_eq!(stats.payload_bytes, 5);
+        assert_eq!(stats.wire_bytes, 2048);
+        assert!((stats.overhead() - 2043.0 / 2048.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_bulk_overhead_is_header_only() {
+        let mut sender = CellCodec::new(1024).unwrap();
+        sender.encode(&vec![0u8; 1016 * 100]);
+        let stats = sender.stats();
+        assert_eq!(stats.data_cells, 100);
+        assert!((stats.overhead() - 8.0 / 1024.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_decode_rejects_bad_cells() {
+        let mut receiver = CellCodec::new(512).unwrap();
+        let mut cell = vec![7u8; 512];
+        assert!(receiver.decode(&cell).is_err());
+
+        let mut receiver = CellCodec::new(512).unwrap();
+        cell[..5].copy_from_slice(&[0x17, 0x03, 0x03, 0x01, 0xFB]);
+        assert!(receiver.decode(&cell).is_err());
+
+        let mut receiver = CellCodec::new(512).unwrap();
+        cell[5] = COMMAND_DATA;
+        cell[6..8].copy_from_slice(&600u16.to_be_bytes());
+        assert!(receiver.decode(&cell).is_err());
+
+        assert!(CellCodec::new(8).is_err());
+        assert!(CellCodec::from_config(&ObfuscationConfig::default()).is_err());
+    }
+}
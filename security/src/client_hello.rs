@@ -0,0 +1,1253 @@
+//! TLS ClientHello parsing and generation
+//! Locates the fields inside a ClientHello record (cipher suites, extensions, SNI)
+//! so other modules can split, inspect, or rewrite handshakes at exact byte offsets,
+//! and builds complete ClientHellos that match real browser fingerprints
+
+use crate::error::{Error, Result};
+use crate::fingerprint::OsFamily;
+use crate::session_tickets::SessionTicket;
+use crate::sni_obfuscation::BrowserFingerprint;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use std::ops::Range;
+
+pub const TLS_RECORD_HEADER_LEN: usize = 5;
+pub const HANDSHAKE_HEADER_LEN: usize = 4;
+
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_TYPE_CLIENT_HELLO: u8 = 0x01;
+
+pub const EXT_SERVER_NAME: u16 = 0x0000;
+pub const EXT_STATUS_REQUEST: u16 = 0x0005;
+pub const EXT_SUPPORTED_GROUPS: u16 = 0x000a;
+pub const EXT_EC_POINT_FORMATS: u16 = 0x000b;
+pub const EXT_SIGNATURE_ALGORITHMS: u16 = 0x000d;
+pub const EXT_ALPN: u16 = 0x0010;
+pub const EXT_SIGNED_CERT_TIMESTAMP: u16 = 0x0012;
+pub const EXT_PADDING: u16 = 0x0015;
+pub const EXT_EXTENDED_MASTER_SECRET: u16 = 0x0017;
+pub const EXT_COMPRESS_CERTIFICATE: u16 = 0x001b;
+pub const EXT_RECORD_SIZE_LIMIT: u16 = 0x001c;
+pub const EXT_DELEGATED_CREDENTIALS: u16 = 0x0022;
+pub const EXT_SESSION_TICKET: u16 = 0x0023;
+pub const EXT_PRE_SHARED_KEY: u16 = 0x0029;
+pub const EXT_SUPPORTED_VERSIONS: u16 = 0x002b;
+pub const EXT_PSK_KEY_EXCHANGE_MODES: u16 = 0x002d;
+pub const EXT_KEY_SHARE: u16 = 0x0033;
+pub const EXT_APPLICATION_SETTINGS: u16 = 0x4469;
+pub const EXT_ENCRYPTED_CLIENT_HELLO: u16 = 0xfe0d;
+pub const EXT_RENEGOTIATION_INFO: u16 = 0xff01;
+
+pub const GROUP_SECP256R1: u16 = 0x0017;
+pub const GROUP_SECP384R1: u16 = 0x0018;
+pub const GROUP_SECP521R1: u16 = 0x0019;
+pub const GROUP_X25519: u16 = 0x001d;
+pub const GROUP_FFDHE2048: u16 = 0x0100;
+pub const GROUP_FFDHE3072: u16 = 0x0101;
+
+/// Everything a browser advertises in its ClientHello, in wire order
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientHelloSpec {
+    pub cipher_suites: Vec<u16>,
+    /// Extension types in the order they are emitted
+    pub extensions: Vec<u16>,
+    pub supported_groups: Vec<u16>,
+    pub key_share_groups: Vec<u16>,
+    pub signature_algorithms: Vec<u16>,
+    pub supported_versions: Vec<u16>,
+    pub alpn_protocols: Vec<String>,
+    pub cert_compression_algorithms: Vec<u16>,
+    /// Pad the handshake to 512 bytes the way BoringSSL does
+    pub boringssl_padding: bool,
+    /// Inject RFC 8701 GREASE values into ciphers, groups, versions and extensions
+    pub grease: bool,
+    /// Randomly permute the extension order on every handshake (Chrome 110+)
+    pub shuffle_extensions: bool,
+}
+
+impl ClientHelloSpec {
+    /// Spec matching the given browser's current stable release
+    pub fn for_browser(browser: BrowserFingerprint) -> Self {
+        match browser {
+            BrowserFingerprint::Chrome | BrowserFingerprint::Edge | BrowserFingerprint::Opera => {
+                Self::chrome()
+            }
+            BrowserFingerprint::Firefox => Self::firefox(),
+            BrowserFingerprint::Safari => Self::safari(),
+        }
+    }
+
+    /// Spec for a browser running on a particular OS
+    ///
+    /// Browsers on iOS load pages through WebKit and Apple's network stack, so
+    /// Chrome, Firefox and Edge there send Safari's ClientHello. Elsewhere the
+    /// TLS stack ships with the browser and is the same on desktop and Android.
+    pub fn for_browser_on(browser: BrowserFingerprint, os: OsFamily) -> Self {
+        match os {
+            OsFamily::Ios => Self::safari(),
+            _ => Self::for_browser(browser),
+        }
+    }
+
+    fn chrome() -> Self {
+        ClientHelloSpec {
+            cipher_suites: vec![
+                0x1301, 0x1302, 0x1303, 0xc02b, 0xc02f, 0xc02c, 0xc030, 0xcca9, 0xcca8, 0xc013,
+                0xc014, 0x009c, 0x009d, 0x002f, 0x0035,
+            ],
+            extensions: vec![
+                EXT_SERVER_NAME,
+                EXT_EXTENDED_MASTER_SECRET,
+                EXT_RENEGOTIATION_INFO,
+                EXT_SUPPORTED_GROUPS,
+                EXT_EC_POINT_FORMATS,
+                EXT_SESSION_TICKET,
+                EXT_ALPN,
+                EXT_STATUS_REQUEST,
+                EXT_SIGNATURE_ALGORITHMS,
+                EXT_SIGNED_CERT_TIMESTAMP,
+                EXT_KEY_SHARE,
+                EXT_PSK_KEY_EXCHANGE_MODES,
+                EXT_SUPPORTED_VERSIONS,
+                EXT_COMPRESS_CERTIFICATE,
+                EXT_APPLICATION_SETTINGS,
+                EXT_ENCRYPTED_CLIENT_HELLO,
+                EXT_PADDING,
+            ],
+            supported_groups: vec![GROUP_X25519, GROUP_SECP256R1, GROUP_SECP384R1],
+            key_share_groups: vec![GROUP_X25519],
+            signature_algorithms: vec![
+                0x0403, 0x0804, 0x0401, 0x0503, 0x0805, 0x0501, 0x0806, 0x0601,
+            ],
+            supported_versions: vec![0x0304, 0x0303],
+            alpn_protocols: vec!["h2".to_string(), "http/1.1".to_string()],
+            cert_compression_algorithms: vec![0x0002], // brotli
+            boringssl_padding: true,
+            grease: true,
+            shuffle_extensions: true,
+        }
+    }
+
+    fn firefox() -> Self {
+        ClientHelloSpec {
+            cipher_suites: vec![
+                0x1301, 0x1303, 0x1302, 0xc02b, 0xc02f, 0xcca9, 0xcca8, 0xc02c, 0xc030, 0xc00a,
+                0xc009, 0xc013, 0xc014, 0x009c, 0x009d, 0x002f, 0x0035,
+            ],
+            extensions: vec![
+                EXT_SERVER_NAME,
+                EXT_EXTENDED_MASTER_SECRET,
+                EXT_RENEGOTIATION_INFO,
+                EXT_SUPPORTED_GROUPS,
+                EXT_EC_POINT_FORMATS,
+                EXT_SESSION_TICKET,
+                EXT_ALPN,
+                EXT_STATUS_REQUEST,
+                EXT_DELEGATED_CREDENTIALS,
+                EXT_KEY_SHARE,
+                EXT_SUPPORTED_VERSIONS,
+                EXT_SIGNATURE_ALGORITHMS,
+                EXT_PSK_KEY_EXCHANGE_MODES,
+                EXT_RECORD_SIZE_LIMIT,
+                EXT_ENCRYPTED_CLIENT_HELLO,
+            ],
+            supported_groups: vec![
+                GROUP_X25519,
+                GROUP_SECP256R1,
+                GROUP_SECP384R1,
+                GROUP_SECP521R1,
+                GROUP_FFDHE2048,
+                GROUP_FFDHE3072,
+            ],
+            key_share_groups: vec![GROUP_X25519, GROUP_SECP256R1],
+            signature_algorithms: vec![
+                0x0403, 0x0503, 0x0603, 0x0804, 0x0805, 0x0806, 0x0401, 0x0501, 0x0601, 0x0203,
+                0x0201,
+            ],
+            supported_versions: vec![0x0304, 0x0303],
+            alpn_protocols: vec!["h2".to_string(), "http/1.1".to_string()],
+            cert_compression_algorithms: Vec::new(),
+            boringssl_padding: false,
+            grease: false,
+            shuffle_extensions: false,
+        }
+    }
+
+    fn safari() -> Self {
+        ClientHelloSpec {
+            cipher_suites: vec![
+                0x1301, 0x1302, 0x1303, 0xc02c, 0xc02b, 0xcca9, 0xc030, 0xc02f, 0xcca8, 0xc00a,
+                0xc009, 0xc014, 0xc013, 0x009d, 0x009c, 0x0035, 0x002f, 0xc008, 0xc012, 0x000a,
+            ],
+            extensions: vec![
+                EXT_SERVER_NAME,
+                EXT_EXTENDED_MASTER_SECRET,
+                EXT_RENEGOTIATION_INFO,
+                EXT_SUPPORTED_GROUPS,
+                EXT_EC_POINT_FORMATS,
+                EXT_ALPN,
+                EXT_STATUS_REQUEST,
+                EXT_SIGNATURE_ALGORITHMS,
+                EXT_SIGNED_CERT_TIMESTAMP,
+                EXT_KEY_SHARE,
+                EXT_PSK_KEY_EXCHANGE_MODES,
+                EXT_SUPPORTED_VERSIONS,
+                EXT_COMPRESS_CERTIFICATE,
+                EXT_PADDING,
+            ],
+            supported_groups: vec![GROUP_X25519, GROUP_SECP256R1, GROUP_SECP384R1, GROUP_SECP521R1],
+            key_share_groups: vec![GROUP_X25519],
+            signature_algorithms: vec![
+                0x0403, 0x0804, 0x0401, 0x0503, 0x0203, 0x0805, 0x0805, 0x0501, 0x0806, 0x0601,
+                0x0201,
+            ],
+            supported_versions: vec![0x0304, 0x0303, 0x0302, 0x0301],
+            alpn_protocols: vec!["h2".to_string(), "http/1.1".to_string()],
+            cert_compression_algorithms: vec![0x0001], // zlib
+            boringssl_padding: true,
+            grease: true,
+            shuffle_extensions: false,
+        }
+    }
+}
+
+/// ALPN protocols known to appear in browser ClientHellos over TCP
+const KNOWN_TCP_ALPN: &[&str] = &["h2", "http/1.1"];
+
+/// Which ALPN protocols the ClientHello offers
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AlpnPolicy {
+    /// Offer exactly what the claimed browser offers
+    MatchBrowser,
+    /// Offer a custom list, in order
+    Custom(Vec<String>),
+    /// Omit the ALPN extension entirely
+    Disabled,
+}
+
+/// TLS handshake profile: which browser we claim to be and how we present it
+#[derive(Clone, Debug)]
+pub struct TLSProfileConfig {
+    pub browser: BrowserFingerprint,
+    /// Platform the browser runs on; `None` uses the browser's desktop stack
+    pub os: Option<OsFamily>,
+    pub alpn: AlpnPolicy,
+    /// Send a GREASE ECH extension when real ECH isn't available, as Chrome
+    /// and Firefox do. Has no effect for browsers that never send ECH (Safari),
+    /// since adding it there would itself be an anomaly.
+    pub ech_grease: bool,
+}
+
+impl Default for TLSProfileConfig {
+    fn default() -> Self {
+        TLSProfileConfig {
+            browser: BrowserFingerprint::Chrome,
+            os: None,
+            alpn: AlpnPolicy::MatchBrowser,
+            ech_grease: true,
+        }
+    }
+}
+
+impl TLSProfileConfig {
+    /// Create a profile for a browser with its native ALPN list
+    pub fn for_browser(browser: BrowserFingerprint) -> Self {
+        TLSProfileConfig {
+            browser,
+            ..Default::default()
+        }
+    }
+
+    /// Resolve the ALPN list this profile advertises
+    pub fn alpn_protocols(&self) -> Vec<String> {
+        match &self.alpn {
+            AlpnPolicy::MatchBrowser => self.browser.client_hello_spec(self.os).alpn_protocols,
+            AlpnPolicy::Custom(protocols) => protocols.clone(),
+            AlpnPolicy::Disabled => Vec::new(),
+        }
+    }
+
+    /// Reject ALPN lists that no real browser would send over TCP
+    ///
+    /// DPI heuristics flag combinations such as `h3` in a TCP ClientHello,
+    /// `http/1.1` preferred over `h2`, or protocols browsers never offer.
+    pub fn validate(&self) -> Result<()> {
+        let protocols = self.alpn_protocols();
+
+        if let Some(unknown) = protocols
+            .iter()
+            .find(|p| !KNOWN_TCP_ALPN.contains(&p.as_str()))
+        {
+            return Err(Error::ConfigError(format!(
+                "ALPN protocol '{}' is never offered by {:?} over TCP",
+                unknown, self.browser
+            )));
+        }
+
+        let h2 = protocols.iter().position(|p| p == "h2");
+        let http11 = protocols.iter().position(|p| p == "http/1.1");
+        if let (Some(h2), Some(http11)) = (h2, http11) {
+            if http11 < h2 {
+                return Err(Error::ConfigError(
+                    "browsers always prefer h2 over http/1.1 in ALPN".to_string(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Build the ClientHello spec for this profile
+    pub fn to_spec(&self) -> ClientHelloSpec {
+        let mut spec = self.browser.client_hello_spec(self.os);
+        spec.alpn_protocols = self.alpn_protocols();
+        if !self.ech_grease {
+            spec.extensions.retain(|&ext| ext != EXT_ENCRYPTED_CLIENT_HELLO);
+        }
+        spec
+    }
+}
+
+/// Check whether a cipher suite, group, version or extension value is GREASE
+pub fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+/// GREASE values chosen for one handshake, following BoringSSL's placement
+#[derive(Clone, Copy, Debug)]
+struct Grease {
+    cipher: u16,
+    group: u16,
+    version: u16,
+    first_extension: u16,
+    last_extension: u16,
+}
+
+impl Grease {
+    fn random<R: Rng>(rng: &mut R) -> Self {
+        let mut value = || {
+            let nibble = rng.gen_range(0..16u16);
+            (nibble << 12) | (nibble << 4) | 0x0a0a
+        };
+        let first_extension = value();
+        let mut last_extension = value();
+        // The two GREASE extensions must not collide
+        while last_extension == first_extension {
+            last_extension = value();
+        }
+
+        Grease {
+            cipher: value(),
+            group: value(),
+            version: value(),
+            first_extension,
+            last_extension,
+        }
+    }
+}
+
+/// Builds complete, well-formed ClientHello records from a `ClientHelloSpec`
+#[derive(Clone, Debug)]
+pub struct ClientHelloBuilder {
+    spec: ClientHelloSpec,
+    server_name: Option<String>,
+    /// PSK identity and obfuscated ticket age for a resumption handshake
+    psk: Option<(Vec<u8>, u32)>,
+    random: Option<[u8; 32]>,
+    session_id: Option<[u8; 32]>,
+    /// Caller-supplied key shares, used instead of random bytes
+    key_shares: Vec<(u16, Vec<u8>)>,
+}
+
+impl ClientHelloBuilder {
+    /// Create a builder for an explicit spec
+    pub fn new(spec: ClientHelloSpec) -> Self {
+        ClientHelloBuilder {
+            spec,
+            server_name: None,
+            psk: None,
+            random: None,
+            session_id: None,
+            key_shares: Vec::new(),
+        }
+    }
+
+    /// Create a builder matching a browser fingerprint
+    pub fn for_browser(browser: BrowserFingerprint) -> Self {
+        Self::new(ClientHelloSpec::for_browser(browser))
+    }
+
+    /// Create a builder from a TLS profile
+    pub fn from_profile(profile: &TLSProfileConfig) -> Self {
+        Self::new(profile.to_spec())
+    }
+
+    /// Set the SNI hostname
+    pub fn server_name(mut self, name: &str) -> Self {
+        self.server_name = Some(name.to_string());
+        self
+    }
+
+    /// Offer a session ticket for resumption via the pre_shared_key extension
+    pub fn resume_with(mut self, ticket: &SessionTicket) -> Self {
+        self.psk = Some((ticket.ticket.clone(), ticket.obfuscated_age()));
+        self
+    }
+
+    /// Use a fixed ClientHello random instead of a fresh one
+    pub fn random(mut self, random: [u8; 32]) -> Self {
+        self.random = Some(random);
+        self
+    }
+
+    /// Use a fixed legacy_session_id instead of a fresh one
+    pub fn session_id(mut self, session_id: [u8; 32]) -> Self {
+        self.session_id = Some(session_id);
+        self
+    }
+
+    /// Send a real public key for `group` in the key_share extension
+    pub fn key_share(mut self, group: u16, public_key: Vec<u8>) -> Self {
+        self.key_shares.retain(|(g, _)| *g != group);
+        self.key_shares.push((group, public_key));
+        self
+    }
+
+    /// Get the spec this builder emits
+    pub fn spec(&self) -> &ClientHelloSpec {
+        &self.spec
+    }
+
+    /// Build the full TLS record carrying the ClientHello
+    pub fn build(&self) -> Vec<u8> {
+        let mut rng = rand::thread_rng();
+
+        let mut body = Vec::new();
+        put_u16(&mut body, 0x0303); // legacy_version: TLS 1.2
+        body.extend_from_slice(&self.random.unwrap_or_else(|| rng.gen()));
+        body.push(32); // legacy_session_id for middlebox compatibility
+        body.extend_from_slice(&self.session_id.unwrap_or_else(|| rng.gen()));
+
+        let grease = self.spec.grease.then(|| Grease::random(&mut rng));
+
+        let mut cipher_suites = self.spec.cipher_suites.clone();
+        if let Some(grease) = grease {
+            cipher_suites.insert(0, grease.cipher);
+        }
+        put_u16_list(&mut body, &cipher_suites);
+        body.extend_from_slice(&[0x01, 0x00]); // null compression only
+
+        // Padding is always emitted last, after its length is known
+        let mut order: Vec<u16> = self
+            .spec
+            .extensions
+            .iter()
+            .copied()
+            .filter(|&ext_type| ext_type != EXT_PADDING)
+            .collect();
+        if self.spec.shuffle_extensions {
+            order.shuffle(&mut rng);
+        }
+
+        let mut extensions = Vec::new();
+        if let Some(grease) = grease {
+            put_extension(&mut extensions, grease.first_extension, &[]);
+        }
+        for ext_type in order {
+            if let Some(ext_body) = self.extension_body(ext_type, grease, &mut rng) {
+                put_extension(&mut extensions, ext_type, &ext_body);
+            }
+        }
+        if let Some(grease) = grease {
+            put_extension(&mut extensions, grease.last_extension, &[0x00]);
+        }
+
+        // pre_shared_key must be the last extension, after padding
+        let psk = self.psk.as_ref().map(|(identity, age)| psk_body(identity, *age, &mut rng));
+        let psk_len = psk.as_ref().map_or(0, |body| 4 + body.len());
+
+        if self.spec.boringssl_padding && self.spec.extensions.contains(&EXT_PADDING) {
+            // Handshake header + body + extensions length prefix + extensions
+            let unpadded = HANDSHAKE_HEADER_LEN + body.len() + 2 + extensions.len() + psk_len;
+            if let Some(padding_len) = boringssl_padding_len(unpadded) {
+                put_extension(&mut extensions, EXT_PADDING, &vec![0u8; padding_len]);
+            }
+        }
+
+        if let Some(psk) = psk {
+            put_extension(&mut extensions, EXT_PRE_SHARED_KEY, &psk);
+        }
+
+        put_u16(&mut body, extensions.len() as u16);
+        body.extend_from_slice(&extensions);
+
+        let mut handshake = vec![HANDSHAKE_TYPE_CLIENT_HELLO];
+        handshake.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(&body);
+
+        let mut record = vec![CONTENT_TYPE_HANDSHAKE];
+        put_u16(&mut record, 0x0301); // record layer version: TLS 1.0
+        put_u16(&mut record, handshake.len() as u16);
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    /// Encode the body of one extension, or `None` to omit it
+    fn extension_body<R: Rng>(
+        &self,
+        ext_type: u16,
+        grease: Option<Grease>,
+        rng: &mut R,
+    ) -> Option<Vec<u8>> {
+        let mut body = Vec::new();
+        match ext_type {
+            EXT_SERVER_NAME => {
+                let name = self.server_name.as_ref()?.as_bytes();
+                put_u16(&mut body, (name.len() + 3) as u16);
+                body.push(0x00); // host_name
+                put_u16(&mut body, name.len() as u16);
+                body.extend_from_slice(name);
+            }
+            EXT_EXTENDED_MASTER_SECRET
+            | EXT_SESSION_TICKET
+            | EXT_SIGNED_CERT_TIMESTAMP => {}
+            EXT_RENEGOTIATION_INFO => body.push(0x00),
+            EXT_SUPPORTED_GROUPS => {
+                let mut groups = self.spec.supported_groups.clone();
+                if let Some(grease) = grease {
+                    groups.insert(0, grease.group);
+                }
+                put_u16_list(&mut body, &groups);
+            }
+            EXT_EC_POINT_FORMATS => body.extend_from_slice(&[0x01, 0x00]), // uncompressed
+            EXT_ALPN => {
+                if self.spec.alpn_protocols.is_empty() {
+                    return None;
+                }
+                put_alpn_list(&mut body, &self.spec.alpn_protocols);
+            }
+            EXT_STATUS_REQUEST => body.extend_from_slice(&[0x01, 0x00, 0x00, 0x00, 0x00]),
+            EXT_SIGNATURE_ALGORITHMS => put_u16_list(&mut body, &self.spec.signature_algorithms),
+            EXT_DELEGATED_CREDENTIALS => put_u16_list(&mut body, &[0x0403, 0x0503, 0x0603, 0x0203]),
+            EXT_KEY_SHARE => {
+                let mut shares = Vec::new();
+                if let Some(grease) = grease {
+                    // GREASE key share with a single zero byte
+                    put_u16(&mut shares, grease.group);
+                    put_u16(&mut shares, 1);
+                    shares.push(0x00);
+                }
+                for &group in &self.spec.key_share_groups {
+                    if let Some((_, key)) = self.key_shares.iter().find(|(g, _)| *g == group) {
+                        put_u16(&mut shares, group);
+                        put_u16(&mut shares, key.len() as u16);
+                        shares.extend_from_slice(key);
+                        continue;
+                    }
+                    let key_len = key_share_len(group)?;
+                    put_u16(&mut shares, group);
+                    put_u16(&mut shares, key_len as u16);
+                    if group == GROUP_SECP256R1 {
+                        // Uncompressed point marker
+                        shares.push(0x04);
+                        shares.extend((1..key_len).map(|_| rng.gen::<u8>()));
+                    } else {
+                        shares.extend((0..key_len).map(|_| rng.gen::<u8>()));
+                    }
+                }
+                put_u16(&mut body, shares.len() as u16);
+                body.extend_from_slice(&shares);
+            }
+            EXT_PSK_KEY_EXCHANGE_MODES => body.extend_from_slice(&[0x01, 0x01]), // psk_dhe_ke
+            EXT_SUPPORTED_VERSIONS => {
+                let mut versions = self.spec.supported_versions.clone();
+                if let Some(grease) = grease {
+                    versions.insert(0, grease.version);
+                }
+                body.push((versions.len() * 2) as u8);
+                for version in &versions {
+                    put_u16(&mut body, *version);
+                }
+            }
+            EXT_COMPRESS_CERTIFICATE => {
+                if self.spec.cert_compression_algorithms.is_empty() {
+                    return None;
+                }
+                body.push((self.spec.cert_compression_algorithms.len() * 2) as u8);
+                for algorithm in &self.spec.cert_compression_algorithms {
+                    put_u16(&mut body, *algorithm);
+                }
+            }
+            EXT_APPLICATION_SETTINGS => {
+                // ALPS is only advertised for h2, and only when h2 is offered
+                if !self.spec.alpn_protocols.iter().any(|p| p == "h2") {
+                    return None;
+                }
+                put_alpn_list(&mut body, &["h2".to_string()]);
+            }
+            EXT_RECORD_SIZE_LIMIT => put_u16(&mut body, 0x4001),
+            EXT_ENCRYPTED_CLIENT_HELLO => body = ech_grease_body(rng),
+            _ => return None,
+        }
+        Some(body)
+    }
+}
+
+/// Encode a GREASE ECH outer extension the way BoringSSL does when no
+/// ECHConfig is known: random config id, X25519 enc, and a random payload
+/// sized like a real encrypted inner ClientHello
+fn ech_grease_body<R: Rng>(rng: &mut R) -> Vec<u8> {
+    const AEAD_OVERHEAD: usize = 16;
+    let payload_len = 32 * rng.gen_range(4..=7) + AEAD_OVERHEAD;
+
+    let mut body = vec![0x00]; // ECHClientHelloType: outer
+    put_u16(&mut body, 0x0001); // KDF: HKDF-SHA256
+    put_u16(&mut body, 0x0001); // AEAD: AES-128-GCM
+    body.push(rng.gen()); // config_id
+    put_u16(&mut body, 32);
+    body.extend((0..32).map(|_| rng.gen::<u8>())); // enc
+    put_u16(&mut body, payload_len as u16);
+    body.extend((0..payload_len).map(|_| rng.gen::<u8>()));
+    body
+}
+
+/// Encode a pre_shared_key extension offering one ticket
+///
+/// The binder is random: we can't compute a real one without the resumption
+/// secret, and a server that can't decrypt the ticket ignores it anyway.
+fn psk_body<R: Rng>(identity: &[u8], obfuscated_age: u32, rng: &mut R) -> Vec<u8> {
+    const BINDER_LEN: usize = 32; // SHA-256 based suites
+
+    let mut identities = Vec::new();
+    put_u16(&mut identities, identity.len() as u16);
+    identities.extend_from_slice(identity);
+    identities.extend_from_slice(&obfuscated_age.to_be_bytes());
+
+    let mut binders = vec![BINDER_LEN as u8];
+    binders.extend((0..BINDER_LEN).map(|_| rng.gen::<u8>()));
+
+    let mut body = Vec::new();
+    put_u16(&mut body, identities.len() as u16);
+    body.extend_from_slice(&identities);
+    put_u16(&mut body, binders.len() as u16);
+    body.extend_from_slice(&binders);
+    body
+}
+
+/// Size of the key_share entry for a named group
+fn key_share_len(group: u16) -> Option<usize> {
+    match group {
+        GROUP_X25519 => Some(32),
+        GROUP_SECP256R1 => Some(65),
+        GROUP_SECP384R1 => Some(97),
+        _ => None,
+    }
+}
+
+/// Padding extension length BoringSSL adds to dodge the F5 256-511 byte bug
+fn boringssl_padding_len(unpadded_len: usize) -> Option<usize> {
+    if !(0x100..0x200).contains(&unpadded_len) {
+        return None;
+    }
+    let padding = 0x200 - unpadded_len;
+    // The extension header takes 4 bytes; always leave at least one byte of body
+    Some(if padding > 4 { padding - 4 } else { 1 })
+}
+
+fn put_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_u16_list(buf: &mut Vec<u8>, values: &[u16]) {
+    put_u16(buf, (values.len() * 2) as u16);
+    for value in values {
+        put_u16(buf, *value);
+    }
+}
+
+fn put_alpn_list(buf: &mut Vec<u8>, protocols: &[String]) {
+    let list_len: usize = protocols.iter().map(|p| p.len() + 1).sum();
+    put_u16(buf, list_len as u16);
+    for protocol in protocols {
+        buf.push(protocol.len() as u8);
+        buf.extend_from_slice(protocol.as_bytes());
+    }
+}
+
+fn put_extension(buf: &mut Vec<u8>, ext_type: u16, body: &[u8]) {
+    put_u16(buf, ext_type);
+    put_u16(buf, body.len() as u16);
+    buf.extend_from_slice(body);
+}
+
+/// Location of a single extension inside the parsed record
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExtensionInfo {
+    pub ext_type: u16,
+    /// Byte range of the extension body (after type and length) within the record
+    pub data: Range<usize>,
+}
+
+/// Fields of a ClientHello, with offsets relative to the start of the TLS record
+#[derive(Clone, Debug)]
+pub struct ParsedClientHello {
+    pub record_version: u16,
+    pub legacy_version: u16,
+    pub random: [u8; 32],
+    pub session_id: Vec<u8>,
+    pub cipher_suites: Vec<u16>,
+    pub compression_methods: Vec<u8>,
+    pub extensions: Vec<ExtensionInfo>,
+    pub server_name: Option<String>,
+    /// Byte range of the SNI hostname within the record
+    pub server_name_range: Option<Range<usize>>,
+}
+
+impl ParsedClientHello {
+    /// Find an extension by type
+    pub fn extension(&self, ext_type: u16) -> Option<&ExtensionInfo> {
+        self.extensions.iter().find(|ext| ext.ext_type == ext_type)
+    }
+
+    /// Offset that splits the SNI hostname into two halves, if an SNI is present
+    pub fn sni_split_offset(&self) -> Option<usize> {
+        let range = self.server_name_range.as_ref()?;
+        if range.len() < 2 {
+            return None;
+        }
+        Some(range.start + range.len() / 2)
+    }
+
+    /// Public key offered for `group` in the key_share extension
+    ///
+    /// `record` must be the bytes this ClientHello was parsed from.
+    pub fn key_share<'a>(&self, record: &'a [u8], group: u16) -> Option<&'a [u8]> {
+        self.key_shares(record)
+            .into_iter()
+            .find(|(share_group, _)| *share_group == group)
+            .map(|(_, key)| key)
+    }
+
+    /// Groups the key_share extension carries a public key for, in order
+    pub fn key_share_groups(&self, record: &[u8]) -> Vec<u16> {
+        self.key_shares(record)
+            .into_iter()
+            .map(|(group, _)| group)
+            .collect()
+    }
+
+    /// Groups listed in the supported_groups extension, in order
+    pub fn supported_groups(&self, record: &[u8]) -> Vec<u16> {
+        let Some(ext) = self.extension(EXT_SUPPORTED_GROUPS) else {
+            return Vec::new();
+        };
+        let mut reader = Reader::new(&record[..ext.data.end], ext.data.start);
+        let mut groups = Vec::new();
+        if let Ok(list_len) = reader.u16() {
+            let list_end = reader.pos + list_len as usize;
+            while reader.pos < list_end {
+                match reader.u16() {
+                    Ok(group) => groups.push(group),
+                    Err(_) => break,
+                }
+            }
+        }
+        groups
+    }
+
+    /// Versions listed in the supported_versions extension, in order
+    pub fn supported_versions(&self, record: &[u8]) -> Vec<u16> {
+        let Some(ext) = self.extension(EXT_SUPPORTED_VERSIONS) else {
+            return Vec::new();
+        };
+        let mut reader = Reader::new(&record[..ext.data.end], ext.data.start);
+        let mut versions = Vec::new();
+        if let Ok(list_len) = reader.u8() {
+            let list_end = reader.pos + list_len as usize;
+            while reader.pos < list_end {
+                match reader.u16() {
+                    Ok(version) => versions.push(version),
+                    Err(_) => break,
+                }
+            }
+        }
+        versions
+    }
+
+    /// Protocols offered in the ALPN extension, in order
+    pub fn alpn_protocols(&self, record: &[u8]) -> Vec<String> {
+        let Some(ext) = self.extension(EXT_ALPN) else {
+            return Vec::new();
+        };
+        let mut reader = Reader::new(&record[..ext.data.end], ext.data.start);
+        let mut protocols = Vec::new();
+        if let Ok(list_len) = reader.u16() {
+            let list_end = reader.pos + list_len as usize;
+            while reader.pos < list_end {
+                let Some(name) = reader.u8().and_then(|len| reader.take(len as usize)).ok() else {
+                    break;
+                };
+                protocols.push(String::from_utf8_lossy(name).into_owned());
+            }
+        }
+        protocols
+    }
+
+    /// (group, public key) entries of the key_share extension
+    fn key_shares<'a>(&self, record: &'a [u8]) -> Vec<(u16, &'a [u8])> {
+        let Some(ext) = self.extension(EXT_KEY_SHARE) else {
+            return Vec::new();
+        };
+        let mut reader = Reader::new(&record[..ext.data.end], ext.data.start);
+        let mut shares = Vec::new();
+        let Ok(shares_len) = reader.u16() else {
+            return shares;
+        };
+        let shares_end = reader.pos + shares_len as usize;
+        while reader.pos < shares_end {
+            let entry = reader.u16().and_then(|group| {
+                let key_len = reader.u16()? as usize;
+                Ok((group, reader.take(key_len)?))
+            });
+            match entry {
+                Ok(entry) => shares.push(entry),
+                Err(_) => break,
+            }
+        }
+        shares
+    }
+}
+
+/// Bounds-checked big-endian reader over the record bytes
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8], pos: usize) -> Self {
+        Reader { data, pos }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|&end| end <= self.data.len())
+            .ok_or_else(|| Error::DataError("Truncated ClientHello".to_string()))?;
+        let slice = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+}
+
+/// Parse a ClientHello carried in a single TLS handshake record
+pub fn parse_client_hello(data: &[u8]) -> Result<ParsedClientHello> {
+    let mut reader = Reader::new(data, 0);
+
+    if reader.u8()? != CONTENT_TYPE_HANDSHAKE {
+        return Err(Error::DataError("Not a TLS handshake record".to_string()));
+    }
+    let record_version = reader.u16()?;
+    let _record_length = reader.u16()?;
+
+    if reader.u8()? != HANDSHAKE_TYPE_CLIENT_HELLO {
+        return Err(Error::DataError("Not a ClientHello handshake".to_string()));
+    }
+    let _handshake_length = reader.take(3)?;
+
+    let legacy_version = reader.u16()?;
+    let mut random = [0u8; 32];
+    random.copy_from_slice(reader.take(32)?);
+
+    let session_id_len = reader.u8()? as usize;
+    let session_id = reader.take(session_id_len)?.to_vec();
+
+    let cipher_suites_len = reader.u16()? as usize;
+    let cipher_bytes = reader.take(cipher_suites_len)?;
+    let cipher_suites = cipher_bytes
+        .chunks_exact(2)
+        .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+        .collect();
+
+    let compression_len = reader.u8()? as usize;
+    let compression_methods = reader.take(compression_len)?.to_vec();
+
+    let mut parsed = ParsedClientHello {
+        record_version,
+        legacy_version,
+        random,
+        session_id,
+        cipher_suites,
+        compression_methods,
+        extensions: Vec::new(),
+        server_name: None,
+        server_name_range: None,
+    };
+
+    // Extensions are optional in TLS 1.2 ClientHellos
+    if reader.pos == data.len() {
+        return Ok(parsed);
+    }
+
+    let extensions_len = reader.u16()? as usize;
+    let extensions_end = reader.pos + extensions_len;
+    while reader.pos < extensions_end {
+        let ext_type = reader.u16()?;
+        let ext_len = reader.u16()? as usize;
+        let start = reader.pos;
+        reader.take(ext_len)?;
+
+        if ext_type == EXT_SERVER_NAME {
+            if let Some((name, range)) = parse_server_name(data, start..start + ext_len) {
+                parsed.server_name = Some(name);
+                parsed.server_name_range = Some(range);
+            }
+        }
+
+        parsed.extensions.push(ExtensionInfo {
+            ext_type,
+            data: start..start + ext_len,
+        });
+    }
+
+    Ok(parsed)
+}
+
+/// Extract the first host_name entry from a server_name extension body
+fn parse_server_name(data: &[u8], body: Range<usize>) -> Option<(String, Range<usize>)> {
+    let mut reader = Reader::new(&data[..body.end], body.start);
+    let _list_len = reader.u16().ok()?;
+
+    while reader.pos < body.end {
+        let name_type = reader.u8().ok()?;
+        let name_len = reader.u16().ok()? as usize;
+        let start = reader.pos;
+        let name = reader.take(name_len).ok()?;
+
+        // name_type 0 = host_name
+        if name_type == 0 {
+            let host = std::str::from_utf8(name).ok()?.to_string();
+            return Some((host, start..start + name_len));
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_client_hello(sni: &str) -> Vec<u8> {
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0xAB; 32]); // random
+        body.push(0x00); // empty session id
+        body.extend_from_slice(&[0x00, 0x04, 0x13, 0x01, 0x13, 0x02]);
+        body.extend_from_slice(&[0x01, 0x00]); // null compression
+
+        let name = sni.as_bytes();
+        let mut sni_ext = Vec::new();
+        sni_ext.extend_from_slice(&((name.len() + 3) as u16).to_be_bytes());
+        sni_ext.push(0x00);
+        sni_ext.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        sni_ext.extend_from_slice(name);
+
+        let mut extensions = vec![0x00, 0x17, 0x00, 0x00]; // extended_master_secret
+        extensions.extend_from_slice(&EXT_SERVER_NAME.to_be_bytes());
+        extensions.extend_from_slice(&(sni_ext.len() as u16).to_be_bytes());
+        extensions.extend_from_slice(&sni_ext);
+
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+
+        let mut hello = vec![HANDSHAKE_TYPE_CLIENT_HELLO];
+        hello.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        hello.extend_from_slice(&body);
+
+        let mut record = vec![CONTENT_TYPE_HANDSHAKE, 0x03, 0x01];
+        record.extend_from_slice(&(hello.len() as u16).to_be_bytes());
+        record.extend_from_slice(&hello);
+        record
+    }
+
+    #[test]
+    fn test_parse_client_hello() {
+        let hello = sample_client_hello("example.com");
+        let parsed = parse_client_hello(&hello).unwrap();
+
+        assert_eq!(parsed.record_version, 0x0301);
+        assert_eq!(parsed.legacy_version, 0x0303);
+        assert_eq!(parsed.cipher_suites, vec![0x1301, 0x1302]);
+        assert_eq!(parsed.extensions.len(), 2);
+        assert_eq!(parsed.server_name.as_deref(), Some("example.com"));
+    }
+
+    #[test]
+    fn test_server_name_range() {
+        let hello = sample_client_hello("example.com");
+        let parsed = parse_client_hello(&hello).unwrap();
+        let range = parsed.server_name_range.clone().unwrap();
+
+        assert_eq!(&hello[range], b"example.com");
+        assert!(parsed.extension(EXT_SERVER_NAME).is_some());
+    }
+
+    #[test]
+    fn test_sni_split_offset() {
+        let hello = sample_client_hello("example.com");
+        let parsed = parse_client_hello(&hello).unwrap();
+        let split = parsed.sni_split_offset().unwrap();
+
+        assert_eq!(&hello[split - 5..split], b"examp");
+        assert_eq!(&hello[split..split + 6], b"le.com");
+    }
+
+    #[test]
+    fn test_build_chrome_client_hello() {
+        let profile = TLSProfileConfig {
+            ech_grease: false,
+            ..Default::default()
+        };
+        let hello = ClientHelloBuilder::from_profile(&profile)
+            .server_name("www.example.com")
+            .build();
+        let parsed = parse_client_hello(&hello).unwrap();
+        let spec = profile.to_spec();
+
+        // BoringSSL pads to a 512-byte handshake (517-byte record)
+        assert_eq!(hello.len(), 517);
+        assert_eq!(parsed.server_name.as_deref(), Some("www.example.com"));
+        assert_eq!(parsed.cipher_suites[1..], spec.cipher_suites[..]);
+        assert_eq!(parsed.session_id.len(), 32);
+
+        let mut ext_types: Vec<u16> = parsed
+            .extensions
+            .iter()
+            .map(|e| e.ext_type)
+            .filter(|&t| !is_grease(t))
+            .collect();
+        assert_eq!(ext_types.pop(), Some(EXT_PADDING));
+        let mut expected = spec.extensions.clone();
+        expected.retain(|&t| t != EXT_PADDING);
+        ext_types.sort_unstable();
+        expected.sort_unstable();
+        assert_eq!(ext_types, expected);
+    }
+
+    #[test]
+    fn test_is_grease() {
+        assert!(is_grease(0x0a0a));
+        assert!(is_grease(0xfafa));
+        assert!(!is_grease(0x1301));
+        assert!(!is_grease(0x0a1a));
+    }
+
+    #[test]
+    fn test_chrome_grease_placement() {
+        let hello = ClientHelloBuilder::for_browser(BrowserFingerprint::Chrome)
+            .server_name("example.com")
+            .build();
+        let parsed = parse_client_hello(&hello).unwrap();
+
+        assert!(is_grease(parsed.cipher_suites[0]));
+        assert!(is_grease(parsed.extensions[0].ext_type));
+        // The second GREASE extension is last, ahead of any padding
+        let last_grease = parsed
+            .extensions
+            .iter()
+            .map(|e| e.ext_type)
+            .rfind(|&t| t != EXT_PADDING)
+            .unwrap();
+        assert!(is_grease(last_grease));
+        assert_ne!(parsed.extensions[0].ext_type, last_grease);
+    }
+
+    #[test]
+    fn test_chrome_extension_order_is_shuffled() {
+        let builder = ClientHelloBuilder::for_browser(BrowserFingerprint::Chrome);
+        let orders: Vec<Vec<u16>> = (0..5)
+            .map(|_| {
+                let parsed = parse_client_hello(&builder.build()).unwrap();
+                parsed
+                    .extensions
+                    .iter()
+                    .map(|e| e.ext_type)
+                    .filter(|&t| !is_grease(t))
+                    .collect()
+            })
+            .collect();
+        assert!(orders.iter().any(|order| order != &orders[0]));
+    }
+
+    #[test]
+    fn test_firefox_has_no_grease() {
+        let hello = ClientHelloBuilder::for_browser(BrowserFingerprint::Firefox).build();
+        let parsed = parse_client_hello(&hello).unwrap();
+        assert!(!parsed.cipher_suites.iter().any(|&c| is_grease(c)));
+        assert!(!parsed.extensions.iter().any(|e| is_grease(e.ext_type)));
+    }
+
+    #[test]
+    fn test_browsers_produce_distinct_hellos() {
+        let chrome = ClientHelloBuilder::for_browser(BrowserFingerprint::Chrome)
+            .server_name("example.com")
+            .build();
+        let firefox = ClientHelloBuilder::for_browser(BrowserFingerprint::Firefox)
+            .server_name("example.com")
+            .build();
+
+        let chrome = parse_client_hello(&chrome).unwrap();
+        let firefox = parse_client_hello(&firefox).unwrap();
+        assert_ne!(chrome.cipher_suites, firefox.cipher_suites);
+        assert!(firefox.extension(EXT_RECORD_SIZE_LIMIT).is_some());
+        assert!(chrome.extension(EXT_RECORD_SIZE_LIMIT).is_none());
+    }
+
+    #[test]
+    fn test_build_without_server_name_omits_sni() {
+        let hello = ClientHelloBuilder::for_browser(BrowserFingerprint::Safari).build();
+        let parsed = parse_client_hello(&hello).unwrap();
+        assert!(parsed.server_name.is_none());
+        assert!(parsed.extension(EXT_SERVER_NAME).is_none());
+    }
+
+    fn alpn_of(hello: &[u8]) -> Option<Vec<u8>> {
+        let parsed = parse_client_hello(hello).unwrap();
+        let ext = parsed.extension(EXT_ALPN)?;
+        Some(hello[ext.data.clone()].to_vec())
+    }
+
+    #[test]
+    fn test_profile_alpn_matches_browser() {
+        let profile = TLSProfileConfig::for_browser(BrowserFingerprint::Firefox);
+        assert_eq!(profile.alpn_protocols(), vec!["h2", "http/1.1"]);
+        assert!(profile.validate().is_ok());
+
+        let hello = ClientHelloBuilder::from_profile(&profile).build();
+        let alpn = alpn_of(&hello).unwrap();
+        assert_eq!(&alpn[2..], b"\x02h2\x08http/1.1");
+    }
+
+    #[test]
+    fn test_custom_alpn_drops_alps_without_h2() {
+        let profile = TLSProfileConfig {
+            browser: BrowserFingerprint::Chrome,
+            alpn: AlpnPolicy::Custom(vec!["http/1.1".to_string()]),
+            ..Default::default()
+        };
+        assert!(profile.validate().is_ok());
+
+        let hello = ClientHelloBuilder::from_profile(&profile).build();
+        let parsed = parse_client_hello(&hello).unwrap();
+        assert_eq!(&alpn_of(&hello).unwrap()[2..], b"\x08http/1.1");
+        assert!(parsed.extension(EXT_APPLICATION_SETTINGS).is_none());
+    }
+
+    #[test]
+    fn test_alpn_validation_rejects_mismatches() {
+        let h3_over_tcp = TLSProfileConfig {
+            browser: BrowserFingerprint::Chrome,
+            alpn: AlpnPolicy::Custom(vec!["h3".to_string(), "h2".to_string()]),
+            ..Default::default()
+        };
+        assert!(h3_over_tcp.validate().is_err());
+
+        let wrong_order = TLSProfileConfig {
+            browser: BrowserFingerprint::Safari,
+            alpn: AlpnPolicy::Custom(vec!["http/1.1".to_string(), "h2".to_string()]),
+            ..Default::default()
+        };
+        assert!(wrong_order.validate().is_err());
+    }
+
+    #[test]
+    fn test_disabled_alpn_omits_extension() {
+        let profile = TLSProfileConfig {
+            alpn: AlpnPolicy::Disabled,
+            ..Default::default()
+        };
+        let hello = ClientHelloBuilder::from_profile(&profile).build();
+        assert!(alpn_of(&hello).is_none());
+    }
+
+    #[test]
+    fn test_ech_grease_extension() {
+        let hello = ClientHelloBuilder::from_profile(&TLSProfileConfig::default()).build();
+        let parsed = parse_client_hello(&hello).unwrap();
+        let ech = parsed.extension(EXT_ENCRYPTED_CLIENT_HELLO).unwrap();
+        let body = &hello[ech.data.clone()];
+
+        assert_eq!(body[0], 0x00); // outer
+        assert_eq!(&body[1..5], &[0x00, 0x01, 0x00, 0x01]);
+        assert_eq!(&body[6..8], &[0x00, 0x20]);
+        let payload_len = u16::from_be_bytes([body[40], body[41]]) as usize;
+        assert!([144, 176, 208, 240].contains(&payload_len));
+        assert_eq!(body.len(), 42 + payload_len);
+    }
+
+    #[test]
+    fn test_ech_grease_toggle() {
+        let off = TLSProfileConfig {
+            ech_grease: false,
+            ..Default::default()
+        };
+        assert!(!off.to_spec().extensions.contains(&EXT_ENCRYPTED_CLIENT_HELLO));
+
+        // Safari never sends ECH, so the flag must not add it
+        let safari = TLSProfileConfig::for_browser(BrowserFingerprint::Safari);
+        assert!(!safari.to_spec().extensions.contains(&EXT_ENCRYPTED_CLIENT_HELLO));
+    }
+
+    #[test]
+    fn test_resumption_hello_carries_ticket() {
+        let ticket = SessionTicket::synthetic("example.com");
+        let hello = ClientHelloBuilder::for_browser(BrowserFingerprint::Chrome)
+            .server_name("example.com")
+            .resume_with(&ticket)
+            .build();
+        let parsed = parse_client_hello(&hello).unwrap();
+
+        let psk = parsed.extensions.last().unwrap();
+        assert_eq!(psk.ext_type, EXT_PRE_SHARED_KEY);
+        let body = &hello[psk.data.clone()];
+        let identity_len = u16::from_be_bytes([body[2], body[3]]) as usize;
+        assert_eq!(&body[4..4 + identity_len], &ticket.ticket[..]);
+        // identities + binders (2 + 1 + 32)
+        assert_eq!(body.len(), 2 + 2 + identity_len + 4 + 2 + 33);
+    }
+
+    #[test]
+    fn test_fixed_random_session_id_and_key_share() {
+        let key = vec![0x5a; 32];
+        let hello = ClientHelloBuilder::for_browser(BrowserFingerprint::Firefox)
+            .server_name("example.com")
+            .random([0x11; 32])
+            .session_id([0x22; 32])
+            .key_share(GROUP_X25519, key.clone())
+            .build();
+
+        let parsed = parse_client_hello(&hello).unwrap();
+        assert_eq!(parsed.random, [0x11; 32]);
+        assert_eq!(parsed.session_id, vec![0x22; 32]);
+        assert_eq!(parsed.key_share(&hello, GROUP_X25519), Some(key.as_slice()));
+        // Groups without a supplied key still get a well-formed share
+        assert_eq!(parsed.key_share(&hello, GROUP_SECP256R1).map(|k| k.len()), Some(65));
+    }
+
+    #[test]
+    fn test_rejects_truncated_hello() {
+        let hello = sample_client_hello("example.com");
+        assert!(parse_client_hello(&hello[..30]).is_err());
+        assert!(parse_client_hello(&[0x17, 0x03, 0x03, 0x00, 0x00]).is_err());
+    }
+}
@@ -0,0 +1,196 @@
+//! Payload compression
+//! LZ4 or zstd ahead of encryption and camouflage, negotiated between peers,
+//! to win back part of the size the camouflage layers add to text traffic
+
+use crate::entropy;
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+
+/// Level zstd runs at; higher levels cost more CPU than they save here
+const ZSTD_LEVEL: i32 = 3;
+/// Largest payload a frame may decompress to
+pub const MAX_DECOMPRESSED_LEN: usize = 64 * 1024;
+
+const FRAME_STORED: u8 = 0;
+const FRAME_COMPRESSED: u8 = 1;
+
+/// Compression method of a session
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    #[default]
+    None,
+    /// Fast, modest ratio; suits weak phones
+    Lz4,
+    /// Better ratio at a little more CPU
+    Zstd,
+}
+
+impl Compression {
+    /// Wire identifier
+    pub fn id(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+            Compression::Zstd => 2,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Lz4),
+            2 => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+}
+
+/// Bitmask of the methods a peer supports, sent in its handshake
+pub fn offer(methods: &[Compression]) -> u8 {
+    methods
+        .iter()
+        .filter(|&&method| method != Compression::None)
+        .fold(0, |mask, method| mask | 1 << (method.id() - 1))
+}
+
+/// First method of `preference` the peer offered, `None` if no overlap
+pub fn negotiate(offered: u8, preference: &[Compression]) -> Compression {
+    preference
+        .iter()
+        .copied()
+        .find(|&method| method != Compression::None && offered & offer(&[method]) != 0)
+        .unwrap_or(Compression::None)
+}
+
+/// Compress one payload into a frame
+///
+/// A frame is a flag byte and the body. Payloads that don't shrink, such as
+/// tunnelled TLS, are stored, so a frame is never more than one byte longer
+/// than its payload. `Compression::None` returns the payload unframed.
+pub fn compress(method: Compression, data: &[u8]) -> Vec<u8> {
+    let compressed = match method {
+        Compression::None => return data.to_vec(),
+        _ if entropy::classify(data).is_encrypted() => None,
+        Compression::Lz4 => Some(lz4_flex::compress_prepend_size(data)),
+        Compression::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL).ok(),
+    };
+
+    let mut frame = Vec::with_capacity(data.len() + 1);
+    match compressed {
+        Some(body) if body.len() < data.len() => {
+            frame.push(FRAME_COMPRESSED);
+            frame.extend(body);
+        }
+        _ => {
+            frame.push(FRAME_STORED);
+            frame.extend_from_slice(data);
+        }
+    }
+    frame
+}
+
+/// Recover the payload of a frame made by `compress`
+pub fn decompress(method: Compression, frame: &[u8]) -> Result<Vec<u8>> {
+    if method == Compression::None {
+        return Ok(frame.to_vec());
+    }
+    let (&flag, body) = frame
+        .split_first()
+        .ok_or_else(|| Error::DataError("Empty compression frame".to_string()))?;
+    match flag {
+        FRAME_STORED => Ok(body.to_vec()),
+        FRAME_COMPRESSED => match method {
+            Compression::Lz4 => {
+                let size = body
+                    .get(..4)
+                    .map(|prefix| u32::from_le_bytes(prefix.try_into().unwrap()) as usize)
+                    .ok_or_else(|| Error::DataError("Truncated LZ4 frame".to_string()))?;
+                if size > MAX_DECOMPRESSED_LEN {
+                    return Err(Error::DataError("LZ4 frame too large".to_string()));
+                }
+                lz4_flex::decompress(&body[4..], size)
+                    .map_err(|e| Error::DataError(format!("Invalid LZ4 frame: {}", e)))
+            }
+            Compression::Zstd => zstd::bulk::decompress(body, MAX_DECOMPRESSED_LEN)
+                .map_err(|e| Error::DataError(format!("Invalid zstd frame: {}", e))),
+            Compression::None => unreachable!(),
+        },
+        _ => Err(Error::DataError(format!(
+            "Unknown compression frame flag {}",
+            flag
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page() -> Vec<u8> {
+        let mut page = Vec::new();
+        for i in 0..200 {
+            page.extend(
+                format!(
+                    "<li class=\"item\"><a href=\"/post/{}\">Post {}</a></li>\n",
+                    i, i
+                )
+                .bytes(),
+            );
+        }
+        page
+    }
+
+    #[test]
+    fn test_round_trip_shrinks_text() {
+        let page = page();
+        for method in [Compression::Lz4, Compression::Zstd] {
+            let frame = compress(method, &page);
+            assert!(
+                frame.len() < page.len() / 3,
+                "{:?}: {}",
+                method,
+                frame.len()
+            );
+            assert_eq!(decompress(method, &frame).unwrap(), page);
+        }
+        assert_eq!(compress(Compression::None, &page), page);
+    }
+
+    #[test]
+    fn test_ciphertext_is_stored() {
+        let mut data = vec![0u8; 4096];
+        rand::Rng::fill(&mut rand::thread_rng(), &mut data[..]);
+        for method in [Compression::Lz4, Compression::Zstd] {
+            let frame = compress(method, &data);
+            assert_eq!(frame.len(), data.len() + 1);
+            assert_eq!(decompress(method, &frame).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn test_negotiation() {
+        let both = offer(&[Compression::Lz4, Compression::Zstd]);
+        assert_eq!(
+            negotiate(both, &[Compression::Zstd, Compression::Lz4]),
+            Compression::Zstd
+        );
+        let lz4_only = offer(&[Compression::Lz4]);
+        assert_eq!(
+            negotiate(lz4_only, &[Compression::Zstd, Compression::Lz4]),
+            Compression::Lz4
+        );
+        assert_eq!(negotiate(lz4_only, &[Compression::Zstd]), Compression::None);
+        assert_eq!(negotiate(0, &[Compression::Lz4]), Compression::None);
+    }
+
+    #[test]
+    fn test_decompression_bomb_rejected() {
+        let huge = vec![b'a'; MAX_DECOMPRESSED_LEN + 1];
+        for method in [Compression::Lz4, Compression::Zstd] {
+            let frame = compress(method, &huge);
+            assert!(decompress(method, &frame).is_err());
+        }
+        assert!(decompress(Compression::Lz4, &[9, 1, 2]).is_err());
+    }
+}
@@ -3,12 +3,43 @@
 
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct SecuritySettings {
     pub obfuscation: ObfuscationConfig,
     pub pattern_rotation: PatternRotationConfig,
     pub dpi_bypass: DPIBypassConfig,
     pub detection_evasion: DetectionEvadingConfig,
+    /// Largest share of extra wire bytes per payload byte (0.15 for 15%);
+    /// padding, decoys and noise are dialed down to stay under it
+    #[serde(default)]
+    pub max_overhead_ratio: Option<f64>,
+    #[serde(default)]
+    pub processor: ProcessorSettings,
+    /// How ClientHellos are cut into fragments
+    #[serde(default)]
+    pub tls_fragmentation: crate::tls_fragmentation::TLSFragmentationConfig,
+    /// Which server name is presented in place of the real one
+    #[serde(default)]
+    pub sni_obfuscation: crate::sni_obfuscation::SNIObfuscationConfig,
+    /// Per-session TCP/IP parameters (window, TTL, hop limit) and how often
+    /// they rotate
+    #[serde(default)]
+    pub session_patterns: crate::dynamic_patterns::PatternRotationConfig,
+}
+
+/// How `SecurityProcessor` runs, apart from what it does to traffic
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProcessorSettings {
+    /// Score each planned packet with the embedded classifier and re-plan
+    /// with heavier shaping above this threshold, between 0 and 1
+    pub self_test_threshold: Option<f64>,
+    /// Terminate the session when a stage fails instead of carrying on
+    pub fail_closed: bool,
+    /// Also write the outgoing traffic to this PCAP file
+    pub pcap_export: Option<std::path::PathBuf>,
+    /// Switch on the process-wide privacy mode, see `privacy::set_enabled`
+    pub privacy_mode: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -19,6 +50,24 @@ pub struct ObfuscationConfig {
     pub packet_randomization: bool,
     pub min_packet_size: usize,
     pub max_packet_size: usize,
+    /// Pad outgoing TLS records up to fixed bucket sizes
+    #[serde(default)]
+    pub record_padding_enabled: bool,
+    #[serde(default = "default_record_padding_buckets")]
+    pub record_padding_buckets: Vec<usize>,
+    /// Carry all traffic in fixed-size cells, the maximal-protection option
+    #[serde(default)]
+    pub cell_mode: crate::cells::CellMode,
+    /// Lower-entropy representation payload is mapped into
+    #[serde(default)]
+    pub entropy_encoding: crate::entropy::EntropyEncoding,
+    /// HTTP envelope the obfuscated data is carried in
+    #[serde(default)]
+    pub camouflage_profile: crate::obfuscation::CamouflageProfile,
+}
+
+fn default_record_padding_buckets() -> Vec<usize> {
+    crate::record_padding::DEFAULT_BUCKETS.to_vec()
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -34,9 +83,61 @@ pub struct DPIBypassConfig {
     pub enabled: bool,
     pub fragmentation_enabled: bool,
     pub tls_evasion_enabled: bool,
+    /// Select the DNS tunnel transport, configured by `dns_tunnel`
     pub dns_tunneling_enabled: bool,
     pub mirrored_traffic_enabled: bool,
     pub timing_randomization_enabled: bool,
+    #[serde(default)]
+    pub domain_fronting: DomainFrontingConfig,
+    #[serde(default)]
+    pub dns_tunnel: DnsTunnelSettings,
+    #[serde(default)]
+    pub http_evasion: HttpEvasionConfig,
+    /// When packets go on the wire
+    #[serde(default)]
+    pub packet_scheduler: crate::scheduler::SchedulerKind,
+}
+
+/// Header-level rewrites applied to plain-HTTP (port 80) requests
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HttpEvasionConfig {
+    pub enabled: bool,
+    /// Randomize the case of the request method
+    pub mixed_case_method: bool,
+    /// Randomize the case of the `Host` header name
+    pub mixed_case_host_header: bool,
+    /// Pad the request line and the Host header with extra whitespace
+    pub extra_spaces: bool,
+    /// Shuffle the header order so Host isn't the first header
+    pub reorder_headers: bool,
+    /// Where the request is cut into separate TCP segments
+    pub split: crate::http_evasion::HttpSplit,
+}
+
+/// Zone and resolver for the DNS tunnel transport
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DnsTunnelSettings {
+    /// Zone delegated to the tunnel server
+    pub domain: String,
+    #[serde(default)]
+    pub record_type: crate::dns_tunnel::DnsRecordType,
+    /// Resolver as "ip:port"; a public resolver is used when unset
+    #[serde(default)]
+    pub resolver: Option<String>,
+}
+
+/// A front domain carried in the SNI and the real host carried inside the tunnel
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FrontingPair {
+    pub front_domain: String,
+    pub real_host: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DomainFrontingConfig {
+    pub enabled: bool,
+    pub pairs: Vec<FrontingPair>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -48,17 +149,9 @@ pub struct DetectionEvadingConfig {
     pub decoy_traffic_percentage: u8,
     pub max_adaptation_level: u8,
     pub ensemble_approach_enabled: bool,
-}
-
-impl Default for SecuritySettings {
-    fn default() -> Self {
-        SecuritySettings {
-            obfuscation: ObfuscationConfig::default(),
-            pattern_rotation: PatternRotationConfig::default(),
-            dpi_bypass: DPIBypassConfig::default(),
-            detection_evasion: DetectionEvadingConfig::default(),
-        }
-    }
+    /// Traffic shape each session is carried in
+    #[serde(default)]
+    pub traffic_shape: crate::morphing::TrafficShapeProfile,
 }
 
 impl Default for ObfuscationConfig {
@@ -70,6 +163,11 @@ impl Default for ObfuscationConfig {
             packet_randomization: true,
             min_packet_size: 100,
             max_packet_size: 2048,
+            record_padding_enabled: false,
+            record_padding_buckets: default_record_padding_buckets(),
+            cell_mode: crate::cells::CellMode::Off,
+            entropy_encoding: crate::entropy::EntropyEncoding::None,
+            camouflage_profile: crate::obfuscation::CamouflageProfile::Http1,
         }
     }
 }
@@ -91,9 +189,13 @@ impl Default for DPIBypassConfig {
             enabled: true,
             fragmentation_enabled: true,
             tls_evasion_enabled: true,
-            dns_tunneling_enabled: true,
+            dns_tunneling_enabled: false,
             mirrored_traffic_enabled: false,
             timing_randomization_enabled: true,
+            domain_fronting: DomainFrontingConfig::default(),
+            dns_tunnel: DnsTunnelSettings::default(),
+            http_evasion: HttpEvasionConfig::default(),
+            packet_scheduler: crate::scheduler::SchedulerKind::default(),
         }
     }
 }
@@ -108,6 +210,7 @@ impl Default for DetectionEvadingConfig {
             decoy_traffic_percentage: 20,
             max_adaptation_level: 5,
             ensemble_approach_enabled: true,
+            traffic_shape: crate::morphing::TrafficShapeProfile::None,
         }
     }
 }
@@ -128,17 +231,295 @@ impl SecuritySettings {
         serde_yaml::from_str(yaml)
     }
 
-    /// Validate configuration
-    pub fn validate(&self) -> Result<(), String> {
-        if self.obfuscation.min_packet_size >= self.obfuscation.max_packet_size {
-            return Err("min_packet_size must be less than max_packet_size".to_string());
+    /// Load configuration from TOML
+    pub fn from_toml(toml: &str) -> Result<Self, toml::de::Error> {
+        toml::from_str(toml)
+    }
+
+    /// Save configuration to TOML
+    pub fn to_toml(&self) -> Result<String, toml::ser::Error> {
+        toml::to_string_pretty(self)
+    }
+
+    /// Settings from an optional file, overridden by `IPS_*` environment
+    /// variables and then by command-line `path=value` assignments; see
+    /// `overrides` for the naming. `key` opens an encrypted file.
+    pub fn resolve(
+        file: Option<&std::path::Path>,
+        key: Option<&crate::config_encryption::ConfigKey>,
+        cli: &[String],
+    ) -> Result<Self, String> {
+        let cli = cli
+            .iter()
+            .map(|assignment| crate::overrides::Override::parse(assignment))
+            .collect::<Result<Vec<_>, _>>()?;
+        crate::overrides::resolve(file, key, std::env::vars(), &cli)
+    }
+
+    /// Settings tuned for an Iranian operator, e.g. `"irancell"` or `"tci"`
+    pub fn preset(name: &str) -> Result<Self, String> {
+        crate::presets::IspPreset::from_name(name)
+            .map(crate::presets::IspPreset::settings)
+            .ok_or_else(|| format!("unknown ISP preset '{}'", name))
+    }
+
+    /// Validate configuration, reporting every invalid field
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        let mut errors = Vec::new();
+        let mut check = |pointer: &str, result: Result<(), String>| {
+            if let Err(message) = result {
+                errors.push(FieldError {
+                    pointer: pointer.to_string(),
+                    message,
+                });
+            }
+        };
+
+        let obfuscation = &self.obfuscation;
+        check(
+            "/obfuscation/min_packet_size",
+            ensure(obfuscation.min_packet_size > 0, "must be positive"),
+        );
+        check(
+            "/obfuscation/max_packet_size",
+            ensure(
+                obfuscation.max_packet_size <= MAX_PACKET_SIZE,
+                &format!("must not exceed {}", MAX_PACKET_SIZE),
+            ),
+        );
+        check(
+            "/obfuscation/min_packet_size",
+            ensure(
+                obfuscation.min_packet_size < obfuscation.max_packet_size,
+                "must be less than /obfuscation/max_packet_size",
+            ),
+        );
+        if obfuscation.record_padding_enabled {
+            check(
+                "/obfuscation/record_padding_buckets",
+                crate::record_padding::RecordPadder::from_config(obfuscation)
+                    .map(drop)
+                    .map_err(|e| e.to_string()),
+            );
+        }
+        if let Some(cell) = obfuscation.cell_mode.cell_size() {
+            check(
+                "/obfuscation/cell_mode",
+                ensure(
+                    cell <= obfuscation.max_packet_size,
+                    "cells must fit in /obfuscation/max_packet_size",
+                ),
+            );
+        }
+        if obfuscation.camouflage_profile != crate::obfuscation::CamouflageProfile::Http1 {
+            check(
+                "/obfuscation/camouflage_profile",
+                ensure(
+                    obfuscation.enabled && obfuscation.http_headers_enabled,
+                    "needs /obfuscation/enabled and /obfuscation/http_headers_enabled",
+                ),
+            );
+        }
+
+        if self.pattern_rotation.enabled {
+            check(
+                "/pattern_rotation/rotation_interval_hours",
+                ensure(
+                    self.pattern_rotation.rotation_interval_hours > 0,
+                    "must be positive while rotation is enabled",
+                ),
+            );
+        }
+
+        let dpi = &self.dpi_bypass;
+        if dpi.domain_fronting.enabled {
+            check(
+                "/dpi_bypass/domain_fronting/pairs",
+                ensure(!dpi.domain_fronting.pairs.is_empty(), "needs at least one pair"),
+            );
+            for (i, pair) in dpi.domain_fronting.pairs.iter().enumerate() {
+                check(
+                    &format!("/dpi_bypass/domain_fronting/pairs/{}/front_domain", i),
+                    ensure(!pair.front_domain.is_empty(), "must not be empty"),
+                );
+                check(
+                    &format!("/dpi_bypass/domain_fronting/pairs/{}/real_host", i),
+                    ensure(!pair.real_host.is_empty(), "must not be empty"),
+                );
+            }
+        }
+        if dpi.dns_tunneling_enabled {
+            check(
+                "/dpi_bypass/dns_tunnel",
+                crate::dns_tunnel::DnsTunnelConfig::from_settings(&dpi.dns_tunnel)
+                    .map(drop)
+                    .map_err(|e| e.to_string()),
+            );
+        }
+        check(
+            "/dpi_bypass/packet_scheduler",
+            dpi.packet_scheduler.build().map(drop).map_err(|e| e.to_string()),
+        );
+
+        let fragmentation = &self.tls_fragmentation;
+        check(
+            "/tls_fragmentation/min_fragment_size",
+            ensure(fragmentation.min_fragment_size > 0, "must be positive"),
+        );
+        check(
+            "/tls_fragmentation/min_fragment_size",
+            ensure(
+                fragmentation.min_fragment_size <= fragmentation.max_fragment_size,
+                "must not exceed /tls_fragmentation/max_fragment_size",
+            ),
+        );
+        check(
+            "/tls_fragmentation/min_delay_ms",
+            ensure(
+                fragmentation.min_delay_ms <= fragmentation.max_delay_ms,
+                "must not exceed /tls_fragmentation/max_delay_ms",
+            ),
+        );
+        check(
+            "/tls_fragmentation/min_app_record_size",
+            ensure(
+                fragmentation.min_app_record_size > 0
+                    && fragmentation.min_app_record_size <= fragmentation.max_app_record_size,
+                "must be positive and not exceed /tls_fragmentation/max_app_record_size",
+            ),
+        );
+
+        check(
+            "/sni_obfuscation/fake_sni_pool_size",
+            ensure(
+                !self.sni_obfuscation.use_fake_sni || self.sni_obfuscation.fake_sni_pool_size > 0,
+                "must be positive while fake SNIs are used",
+            ),
+        );
+
+        let session = &self.session_patterns;
+        let ranges = [
+            ("tcp_window", session.min_tcp_window <= session.max_tcp_window),
+            ("ttl", session.min_ttl <= session.max_ttl),
+            ("rtt_ms", session.min_rtt_ms <= session.max_rtt_ms),
+            ("hop_limit", session.min_hop_limit <= session.max_hop_limit),
+        ];
+        for (field, ordered) in ranges {
+            check(
+                &format!("/session_patterns/min_{}", field),
+                ensure(
+                    ordered,
+                    &format!("must not exceed /session_patterns/max_{}", field),
+                ),
+            );
+        }
+
+        let evasion = &self.detection_evasion;
+        check(
+            "/detection_evasion/max_adaptation_level",
+            ensure(
+                (1..=MAX_ADAPTATION_LEVEL).contains(&evasion.max_adaptation_level),
+                &format!("must be between 1 and {}", MAX_ADAPTATION_LEVEL),
+            ),
+        );
+        check(
+            "/detection_evasion/decoy_traffic_percentage",
+            crate::decoy::DecoyConfig::from_settings(evasion)
+                .validate()
+                .map_err(|e| e.to_string()),
+        );
+
+        if let Some(threshold) = self.processor.self_test_threshold {
+            check(
+                "/processor/self_test_threshold",
+                ensure(
+                    (0.0..=1.0).contains(&threshold),
+                    "must be between 0 and 1",
+                ),
+            );
+        }
+
+        if let Some(ratio) = self.max_overhead_ratio {
+            check(
+                "/max_overhead_ratio",
+                crate::budget::OverheadBudget::new(ratio)
+                    .map(drop)
+                    .map_err(|e| e.to_string()),
+            );
         }
 
-        if self.detection_evasion.decoy_traffic_percentage > 100 {
-            return Err("decoy_traffic_percentage must be <= 100".to_string());
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ValidationError { errors })
         }
+    }
+}
+
+/// Largest packet size accepted: an IPv4 datagram
+pub const MAX_PACKET_SIZE: usize = 65_535;
+
+/// Highest adaptation level accepted; past it noise and swaps stop growing
+pub const MAX_ADAPTATION_LEVEL: u8 = 10;
 
+fn ensure(condition: bool, message: &str) -> Result<(), String> {
+    if condition {
         Ok(())
+    } else {
+        Err(message.to_string())
+    }
+}
+
+/// One invalid field
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FieldError {
+    /// JSON pointer to the field, e.g. `/obfuscation/min_packet_size`
+    pub pointer: String,
+    pub message: String,
+}
+
+impl std::fmt::Display for FieldError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.pointer, self.message)
+    }
+}
+
+/// Every problem `SecuritySettings::validate` found
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ValidationError {
+    pub errors: Vec<FieldError>,
+}
+
+impl ValidationError {
+    /// Whether the field at `pointer` is among the invalid ones
+    pub fn has(&self, pointer: &str) -> bool {
+        self.errors.iter().any(|error| error.pointer == pointer)
+    }
+}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, error) in self.errors.iter().enumerate() {
+            if i > 0 {
+                f.write_str("; ")?;
+            }
+            write!(f, "{}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+impl From<ValidationError> for String {
+    fn from(error: ValidationError) -> Self {
+        error.to_string()
+    }
+}
+
+impl From<ValidationError> for crate::error::Error {
+    fn from(error: ValidationError) -> Self {
+        crate::error::Error::ConfigError(error.to_string())
     }
 }
 
@@ -159,6 +540,41 @@ mod tests {
         assert!(config.validate().is_ok());
     }
 
+    #[test]
+    fn test_validation_error_paths() {
+        let mut config = SecuritySettings::default();
+        config.obfuscation.min_packet_size = 0;
+        config.detection_evasion.max_adaptation_level = 0;
+        config.dpi_bypass.domain_fronting.enabled = true;
+        config.dpi_bypass.domain_fronting.pairs = vec![FrontingPair {
+            front_domain: "cdn.example.com".to_string(),
+            real_host: String::new(),
+        }];
+
+        let error = config.validate().unwrap_err();
+        let pointers: Vec<&str> = error.errors.iter().map(|e| e.pointer.as_str()).collect();
+        assert_eq!(
+            pointers,
+            vec![
+                "/obfuscation/min_packet_size",
+                "/dpi_bypass/domain_fronting/pairs/0/real_host",
+                "/detection_evasion/max_adaptation_level",
+            ]
+        );
+        assert!(error
+            .to_string()
+            .starts_with("/obfuscation/min_packet_size: must be positive; "));
+
+        config = SecuritySettings::default();
+        config.obfuscation.min_packet_size = 2000;
+        config.obfuscation.cell_mode = crate::cells::CellMode::Large;
+        config.obfuscation.max_packet_size = 600;
+        let error = config.validate().unwrap_err();
+        assert!(error.has("/obfuscation/min_packet_size"));
+        assert!(error.has("/obfuscation/cell_mode"));
+        assert!(!error.has("/obfuscation/max_packet_size"));
+    }
+
     #[test]
     fn test_config_json() {
         let config = SecuritySettings::default();
@@ -166,4 +582,148 @@ mod tests {
         let loaded = SecuritySettings::from_json(&json).unwrap();
         assert_eq!(loaded.obfuscation.enabled, config.obfuscation.enabled);
     }
+
+    #[test]
+    fn test_config_toml() {
+        let mut config = SecuritySettings::preset("irancell").unwrap();
+        config.max_overhead_ratio = Some(0.25);
+        let toml = config.to_toml().unwrap();
+        let loaded = SecuritySettings::from_toml(&toml).unwrap();
+        assert_eq!(loaded.to_json().unwrap(), config.to_json().unwrap());
+
+        let loaded = SecuritySettings::from_toml(&toml.replace("max_overhead_ratio = 0.25\n", ""))
+            .unwrap();
+        assert_eq!(loaded.max_overhead_ratio, None);
+        assert!(SecuritySettings::from_toml("[obfuscation]\nenabled = \"yes\"\n").is_err());
+    }
+
+    #[test]
+    fn test_record_padding_buckets_validated() {
+        let mut config = SecuritySettings::default();
+        config.obfuscation.record_padding_enabled = true;
+        assert!(config.validate().is_ok());
+
+        config.obfuscation.record_padding_buckets = vec![];
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_overhead_budget_validated() {
+        let mut config = SecuritySettings::default();
+        assert!(config.max_overhead_ratio.is_none());
+        config.max_overhead_ratio = Some(0.15);
+        assert!(config.validate().is_ok());
+        config.max_overhead_ratio = Some(-1.0);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_dns_tunnel_needs_domain() {
+        let mut config = SecuritySettings::default();
+        config.dpi_bypass.dns_tunneling_enabled = true;
+        assert!(config.validate().is_err());
+
+        config.dpi_bypass.dns_tunnel.domain = "t.example.com".to_string();
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_packet_scheduler_selection() {
+        use crate::scheduler::SchedulerKind;
+
+        let mut value = serde_json::to_value(SecuritySettings::default()).unwrap();
+        let dpi = value["dpi_bypass"].as_object_mut().unwrap();
+        assert_eq!(dpi.remove("packet_scheduler").unwrap()["kind"], "markov");
+        let loaded: SecuritySettings = serde_json::from_value(value.clone()).unwrap();
+        assert_eq!(loaded.dpi_bypass.packet_scheduler, SchedulerKind::Markov);
+
+        value["dpi_bypass"]["packet_scheduler"] =
+            serde_json::json!({"kind": "uniform_jitter", "min_ms": 50, "max_ms": 10});
+        let loaded: SecuritySettings = serde_json::from_value(value).unwrap();
+        assert!(loaded.validate().is_err());
+    }
+
+    #[test]
+    fn test_traffic_shape_selection() {
+        use crate::morphing::TrafficShapeProfile;
+
+        let mut value = serde_json::to_value(SecuritySettings::default()).unwrap();
+        let evasion = value["detection_evasion"].as_object_mut().unwrap();
+        assert_eq!(evasion.remove("traffic_shape").unwrap(), "none");
+        let loaded: SecuritySettings = serde_json::from_value(value.clone()).unwrap();
+        assert_eq!(loaded.detection_evasion.traffic_shape, TrafficShapeProfile::None);
+
+        value["detection_evasion"]["traffic_shape"] = "streaming".into();
+        let loaded: SecuritySettings = serde_json::from_value(value).unwrap();
+        assert_eq!(
+            loaded.detection_evasion.traffic_shape,
+            TrafficShapeProfile::Streaming
+        );
+    }
+
+    #[test]
+    fn test_camouflage_profile_selection() {
+        use crate::obfuscation::CamouflageProfile;
+
+        let mut value = serde_json::to_value(SecuritySettings::default()).unwrap();
+        let obfuscation = value["obfuscation"].as_object_mut().unwrap();
+        assert_eq!(obfuscation.remove("camouflage_profile").unwrap(), "http1");
+        let loaded: SecuritySettings = serde_json::from_value(value.clone()).unwrap();
+        assert_eq!(loaded.obfuscation.camouflage_profile, CamouflageProfile::Http1);
+
+        value["obfuscation"]["camouflage_profile"] = "chunked".into();
+        let mut loaded: SecuritySettings = serde_json::from_value(value).unwrap();
+        assert_eq!(loaded.obfuscation.camouflage_profile, CamouflageProfile::Chunked);
+        assert!(loaded.validate().is_ok());
+
+        loaded.obfuscation.http_headers_enabled = false;
+        let error = loaded.validate().unwrap_err();
+        assert!(error.has("/obfuscation/camouflage_profile"));
+    }
+
+    #[test]
+    fn test_module_sections() {
+        let mut value = serde_json::to_value(SecuritySettings::default()).unwrap();
+        let sections = value.as_object_mut().unwrap();
+        for section in ["processor", "tls_fragmentation", "sni_obfuscation", "session_patterns"] {
+            assert!(sections.remove(section).is_some(), "{}", section);
+        }
+        // Files written before the sections existed still load
+        let loaded: SecuritySettings = serde_json::from_value(value.clone()).unwrap();
+        assert!(loaded.validate().is_ok());
+
+        value["sni_obfuscation"] = serde_json::json!({"strategy": "sni_padding", "os": "android"});
+        value["tls_fragmentation"] = serde_json::json!({"first_record": {"unusual_version": 769}});
+        value["session_patterns"] = serde_json::json!({"min_ttl": 200, "max_ttl": 100});
+        let loaded: SecuritySettings = serde_json::from_value(value).unwrap();
+        assert_eq!(
+            loaded.sni_obfuscation.os,
+            Some(crate::fingerprint::OsFamily::Android)
+        );
+        assert_eq!(
+            loaded.tls_fragmentation.first_record,
+            crate::tls_fragmentation::FirstRecordMode::UnusualVersion(0x0301)
+        );
+        assert_eq!(
+            loaded.validate().unwrap_err().errors[0].pointer,
+            "/session_patterns/min_ttl"
+        );
+    }
+
+    #[test]
+    fn test_record_padding_defaults_when_missing() {
+        let mut value = serde_json::to_value(SecuritySettings::default()).unwrap();
+        let obfuscation = value["obfuscation"].as_object_mut().unwrap();
+        obfuscation.remove("record_padding_enabled");
+        obfuscation.remove("record_padding_buckets");
+        obfuscation.remove("cell_mode");
+
+        let loaded: SecuritySettings = serde_json::from_value(value).unwrap();
+        assert!(!loaded.obfuscation.record_padding_enabled);
+        assert_eq!(
+            loaded.obfuscation.record_padding_buckets,
+            crate::record_padding::DEFAULT_BUCKETS
+        );
+        assert_eq!(loaded.obfuscation.cell_mode, crate::cells::CellMode::Off);
+    }
 }
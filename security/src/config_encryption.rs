@@ -0,0 +1,186 @@
+//! Encrypted configuration files
+//! Settings name servers and strategies, so a config found on a seized device
+//! gives the whole setup away; this seals it with ChaCha20-Poly1305 under a
+//! passphrase (stretched with Argon2id) or a 32-byte device key
+//!
+//! File layout: `IPSE`, a version byte, a key-kind byte, a 16-byte salt
+//! (zero for device keys), a 12-byte nonce, then the ciphertext. The header
+//! is authenticated along with the ciphertext.
+
+use crate::error::{Error, Result};
+use chacha20poly1305::aead::{Aead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit, Nonce};
+use rand::RngCore;
+
+/// Leading bytes of every encrypted configuration file
+pub const MAGIC: &[u8; 4] = b"IPSE";
+
+/// Extension appended to the plaintext name, e.g. `settings.toml.enc`
+pub const EXTENSION: &str = "enc";
+
+const VERSION: u8 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const HEADER_LEN: usize = MAGIC.len() + 2 + SALT_LEN + NONCE_LEN;
+
+const KIND_DEVICE_KEY: u8 = 0;
+const KIND_PASSPHRASE: u8 = 1;
+
+/// What a configuration file is sealed with
+#[derive(Clone)]
+pub enum ConfigKey {
+    /// A passphrase typed by the user
+    Passphrase(String),
+    /// A key kept by the device, e.g. in the platform keystore
+    Device([u8; 32]),
+}
+
+impl ConfigKey {
+    /// A device key from a file holding exactly 32 bytes
+    pub fn device_key_file(path: &std::path::Path) -> Result<Self> {
+        let bytes = std::fs::read(path)?;
+        let key: [u8; 32] = bytes.as_slice().try_into().map_err(|_| {
+            Error::EncryptionError(format!(
+                "Device key file must hold 32 bytes, found {}",
+                bytes.len()
+            ))
+        })?;
+        Ok(ConfigKey::Device(key))
+    }
+
+    fn kind(&self) -> u8 {
+        match self {
+            ConfigKey::Device(_) => KIND_DEVICE_KEY,
+            ConfigKey::Passphrase(_) => KIND_PASSPHRASE,
+        }
+    }
+
+    fn cipher_key(&self, salt: &[u8; SALT_LEN]) -> Result<[u8; 32]> {
+        match self {
+            ConfigKey::Device(key) => Ok(*key),
+            ConfigKey::Passphrase(passphrase) => {
+                let mut key = [0u8; 32];
+                argon2::Argon2::default()
+                    .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+                    .map_err(|e| Error::EncryptionError(format!("Key derivation failed: {}", e)))?;
+                Ok(key)
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for ConfigKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigKey::Device(_) => f.write_str("Device(..)"),
+            ConfigKey::Passphrase(_) => f.write_str("Passphrase(..)"),
+        }
+    }
+}
+
+/// Whether `data` looks like an encrypted configuration file
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// Encrypt a configuration file's contents
+pub fn seal(plaintext: &[u8], key: &ConfigKey) -> Result<Vec<u8>> {
+    let mut rng = rand::thread_rng();
+    let mut salt = [0u8; SALT_LEN];
+    if let ConfigKey::Passphrase(_) = key {
+        rng.fill_bytes(&mut salt);
+    }
+    let mut nonce = [0u8; NONCE_LEN];
+    rng.fill_bytes(&mut nonce);
+
+    let mut sealed = Vec::with_capacity(HEADER_LEN + plaintext.len() + 16);
+    sealed.extend_from_slice(MAGIC);
+    sealed.push(VERSION);
+    sealed.push(key.kind());
+    sealed.extend_from_slice(&salt);
+    sealed.extend_from_slice(&nonce);
+    let ciphertext = ChaCha20Poly1305::new(&key.cipher_key(&salt)?.into())
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: plaintext,
+                aad: &sealed,
+            },
+        )
+        .map_err(|_| Error::EncryptionError("Encryption failed".to_string()))?;
+    sealed.extend_from_slice(&ciphertext);
+    Ok(sealed)
+}
+
+/// Decrypt a file written by `seal`
+pub fn open(sealed: &[u8], key: &ConfigKey) -> Result<Vec<u8>> {
+    if sealed.len() < HEADER_LEN || !is_encrypted(sealed) {
+        return Err(Error::EncryptionError(
+            "Not an encrypted configuration file".to_string(),
+        ));
+    }
+    let (header, ciphertext) = sealed.split_at(HEADER_LEN);
+    if header[4] != VERSION {
+        return Err(Error::EncryptionError(format!(
+            "Unsupported encrypted configuration version {}",
+            header[4]
+        )));
+    }
+    if header[5] != key.kind() {
+        return Err(Error::EncryptionError(match header[5] {
+            KIND_PASSPHRASE => "Configuration is sealed with a passphrase".to_string(),
+            KIND_DEVICE_KEY => "Configuration is sealed with a device key".to_string(),
+            kind => format!("Unknown key kind {}", kind),
+        }));
+    }
+    let salt: [u8; SALT_LEN] = header[6..6 + SALT_LEN].try_into().unwrap();
+    let nonce = &header[6 + SALT_LEN..];
+    ChaCha20Poly1305::new(&key.cipher_key(&salt)?.into())
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: header,
+            },
+        )
+        .map_err(|_| {
+            Error::EncryptionError("Wrong key or corrupted configuration file".to_string())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_device_key_roundtrip() {
+        let key = ConfigKey::Device([7; 32]);
+        let sealed = seal(b"max_overhead_ratio = 0.2\n", &key).unwrap();
+        assert!(is_encrypted(&sealed));
+        assert!(!sealed
+            .windows(b"max_overhead_ratio".len())
+            .any(|w| w == b"max_overhead_ratio"));
+        assert_eq!(open(&sealed, &key).unwrap(), b"max_overhead_ratio = 0.2\n");
+
+        assert!(open(&sealed, &ConfigKey::Device([8; 32])).is_err());
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(open(&tampered, &key).is_err());
+        // The header is authenticated too
+        let mut tampered = sealed;
+        tampered[HEADER_LEN - 1] ^= 1;
+        assert!(open(&tampered, &key).is_err());
+    }
+
+    #[test]
+    fn test_passphrase_roundtrip() {
+        let key = ConfigKey::Passphrase("correct horse".to_string());
+        let sealed = seal(b"{}", &key).unwrap();
+        assert_eq!(open(&sealed, &key).unwrap(), b"{}");
+        assert!(open(&sealed, &ConfigKey::Passphrase("wrong".to_string())).is_err());
+        assert!(open(&sealed, &ConfigKey::Device([0; 32]))
+            .unwrap_err()
+            .to_string()
+            .contains("passphrase"));
+    }
+}
@@ -0,0 +1,193 @@
+//! Congestion signal
+//! Watches RTT inflation and retransmits on the tunnel's socket and scales
+//! padding and decoy volume down while the path is congested
+
+use crate::error::Result;
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+/// One reading of the path's condition
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PathSample {
+    /// Smoothed RTT
+    pub rtt: Duration,
+    /// Retransmitted segments over the connection's life
+    pub retransmits: u32,
+}
+
+impl PathSample {
+    /// Read RTT and retransmit count from the kernel's TCP_INFO
+    #[cfg(target_os = "linux")]
+    pub fn from_socket(stream: &TcpStream) -> Result<Self> {
+        use std::os::fd::AsRawFd;
+
+        // SAFETY: tcp_info is plain old data; the kernel fills at most `len`
+        // bytes of it for a descriptor the stream owns
+        let mut info: libc::tcp_info = unsafe { std::mem::zeroed() };
+        let mut len = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+        let rc = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                libc::TCP_INFO,
+                &mut info as *mut _ as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if rc < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(PathSample {
+            rtt: Duration::from_micros(info.tcpi_rtt as u64),
+            retransmits: info.tcpi_total_retrans,
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn from_socket(_stream: &TcpStream) -> Result<Self> {
+        Err(crate::error::Error::Unknown(
+            "TCP_INFO is only read on Linux".to_string(),
+        ))
+    }
+}
+
+/// Settings of the congestion monitor
+#[derive(Clone, Debug)]
+pub struct CongestionConfig {
+    /// RTT above the lowest seen, as a multiple, that counts as congestion
+    pub rtt_inflation: f64,
+    /// Extra slack on top of the inflation, for jittery mobile links
+    pub rtt_slack: Duration,
+    /// Factor the scale is multiplied by on each congested sample
+    pub decrease: f64,
+    /// Scale regained per clear sample
+    pub recover_step: f64,
+}
+
+impl Default for CongestionConfig {
+    fn default() -> Self {
+        CongestionConfig {
+            rtt_inflation: 1.5,
+            rtt_slack: Duration::from_millis(10),
+            decrease: 0.5,
+            recover_step: 0.1,
+        }
+    }
+}
+
+/// Turns path samples into a volume scale for padding and decoys
+///
+/// Backs off multiplicatively when RTT inflates past the baseline or new
+/// retransmits appear, and recovers additively once the path is clear, as
+/// TCP does with its own window.
+#[derive(Clone, Debug)]
+pub struct CongestionMonitor {
+    config: CongestionConfig,
+    min_rtt: Option<Duration>,
+    retransmits: Option<u32>,
+    scale: f64,
+    congested: bool,
+}
+
+impl CongestionMonitor {
+    pub fn new(config: CongestionConfig) -> Self {
+        CongestionMonitor {
+            config,
+            min_rtt: None,
+            retransmits: None,
+            scale: 1.0,
+            congested: false,
+        }
+    }
+
+    /// Share of the configured padding and decoy volume to use, 0.0 to 1.0
+    pub fn scale(&self) -> f64 {
+        self.scale
+    }
+
+    /// Whether the last sample showed congestion
+    pub fn is_congested(&self) -> bool {
+        self.congested
+    }
+
+    /// Account for a new sample, returning the updated scale
+    pub fn observe(&mut self, sample: PathSample) -> f64 {
+        let min_rtt = match self.min_rtt {
+            Some(min) if min <= sample.rtt || sample.rtt.is_zero() => min,
+            _ => sample.rtt,
+        };
+        self.min_rtt = Some(min_rtt);
+        let inflated =
+            sample.rtt > min_rtt.mul_f64(self.config.rtt_inflation) + self.config.rtt_slack;
+        // A new connection's counter can restart lower; that isn't loss
+        let retransmitted = self
+            .retransmits
+            .is_some_and(|last| sample.retransmits > last);
+        self.retransmits = Some(sample.retransmits);
+
+        self.congested = inflated || retransmitted;
+        self.scale = if self.congested {
+            self.scale * self.config.decrease
+        } else {
+            (self.scale + self.config.recover_step).min(1.0)
+        };
+        self.scale
+    }
+}
+
+impl Default for CongestionMonitor {
+    fn default() -> Self {
+        Self::new(CongestionConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(rtt_ms: u64, retransmits: u32) -> PathSample {
+        PathSample {
+            rtt: Duration::from_millis(rtt_ms),
+            retransmits,
+        }
+    }
+
+    #[test]
+    fn test_backs_off_and_recovers() {
+        let mut monitor = CongestionMonitor::default();
+        assert_eq!(monitor.observe(sample(80, 0)), 1.0);
+        assert_eq!(monitor.observe(sample(90, 0)), 1.0);
+
+        // Queues build up: RTT triples
+        assert_eq!(monitor.observe(sample(250, 0)), 0.5);
+        assert_eq!(monitor.observe(sample(260, 0)), 0.25);
+        assert!(monitor.is_congested());
+
+        for _ in 0..10 {
+            monitor.observe(sample(85, 0));
+        }
+        assert_eq!(monitor.scale(), 1.0);
+        assert!(!monitor.is_congested());
+    }
+
+    #[test]
+    fn test_retransmits_signal_loss() {
+        let mut monitor = CongestionMonitor::default();
+        monitor.observe(sample(80, 3));
+        assert_eq!(monitor.observe(sample(80, 3)), 1.0);
+        assert_eq!(monitor.observe(sample(80, 5)), 0.5);
+        assert_eq!(monitor.observe(sample(80, 5)), 0.6);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_sample_from_socket() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let sample = PathSample::from_socket(&stream).unwrap();
+        assert!(sample.rtt < Duration::from_secs(1));
+        assert_eq!(sample.retransmits, 0);
+    }
+}
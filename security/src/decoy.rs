@@ -0,0 +1,513 @@
+//! Decoy cover traffic
+//! Fetches from popular allowed sites over independent connections, so the
+//! tunnel is only a configured share of the traffic an observer sees, and
+//! keeps idle sessions from falling completely silent
+
+use crate::config::DetectionEvadingConfig;
+use crate::error::{Error, Result};
+use crate::morphing::padding_packet;
+use crate::obfuscation::HeaderGenerator;
+use crate::privacy::redact;
+use crate::sni_pool::SniPool;
+use crate::timing::{TimingGenerator, TimingModel};
+use async_trait::async_trait;
+use rand::Rng;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+/// Opens one cover connection
+#[async_trait]
+pub trait DecoyFetcher: Send + Sync {
+    /// Fetch from `host` until about `budget` bytes have moved, returning
+    /// the bytes sent and received
+    async fn fetch(&self, host: &str, budget: u64) -> Result<u64>;
+}
+
+/// Fetches pages over HTTPS with browser-like request heads
+pub struct HttpsFetcher {
+    headers: HeaderGenerator,
+    tls: TlsConnector,
+    timeout: Duration,
+}
+
+impl HttpsFetcher {
+    pub fn new(pool: SniPool, timeout: Duration) -> Self {
+        let mut roots = RootCertStore::empty();
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        let mut config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        HttpsFetcher {
+            headers: HeaderGenerator::new(pool),
+            tls: TlsConnector::from(Arc::new(config)),
+            timeout,
+        }
+    }
+
+    async fn fetch_inner(&self, host: &str, budget: u64) -> Result<u64> {
+        let server_name = ServerName::try_from(host.to_string())
+            .map_err(|e| Error::ConfigError(format!("Invalid decoy host {}: {}", host, e)))?;
+        let tcp = TcpStream::connect((host, 443)).await?;
+        let mut tls = self.tls.connect(server_name, tcp).await?;
+
+        let request = self.headers.request_head(host);
+        tls.write_all(&request).await?;
+        let mut moved = request.len() as u64;
+
+        let mut buf = vec![0u8; 16 * 1024];
+        while moved < budget {
+            let n = tls.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            moved += n as u64;
+        }
+        Ok(moved)
+    }
+}
+
+#[async_trait]
+impl DecoyFetcher for HttpsFetcher {
+    async fn fetch(&self, host: &str, budget: u64) -> Result<u64> {
+        tokio::time::timeout(self.timeout, self.fetch_inner(host, budget))
+            .await
+            .map_err(|_| Error::IoError(std::io::ErrorKind::TimedOut.into()))?
+    }
+}
+
+/// Settings of the decoy scheduler
+#[derive(Clone, Debug)]
+pub struct DecoyConfig {
+    /// Share of all traffic, in percent, that should be cover traffic
+    pub percentage: u8,
+    /// Average time between scheduling rounds
+    pub tick: Duration,
+    /// Cover connections open at once
+    pub max_concurrent: usize,
+    /// Budget range of one cover connection; deficits below the minimum
+    /// wait for the next round
+    pub min_fetch: u64,
+    pub max_fetch: u64,
+}
+
+impl Default for DecoyConfig {
+    fn default() -> Self {
+        DecoyConfig {
+            percentage: 20,
+            tick: Duration::from_secs(2),
+            max_concurrent: 2,
+            min_fetch: 16 * 1024,
+            max_fetch: 1024 * 1024,
+        }
+    }
+}
+
+impl DecoyConfig {
+    /// Default scheduling with the share from the detection-evasion
+    /// settings; no cover traffic when decoys or detection evasion are off
+    pub fn from_settings(settings: &DetectionEvadingConfig) -> Self {
+        let enabled = settings.enabled && settings.decoy_traffic_enabled;
+        DecoyConfig {
+            percentage: if enabled {
+                settings.decoy_traffic_percentage
+            } else {
+                0
+            },
+            ..Default::default()
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.percentage >= 100 {
+            return Err(Error::ConfigError(
+                "Decoy percentage must be below 100".to_string(),
+            ));
+        }
+        if self.min_fetch == 0 || self.min_fetch > self.max_fetch {
+            return Err(Error::ConfigError(
+                "Decoy fetch budget range is empty".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Traffic counters shared between sessions and the scheduler
+#[derive(Debug)]
+pub struct DecoyStats {
+    real: AtomicU64,
+    decoy: AtomicU64,
+    /// Budgets of cover connections still running
+    pending: AtomicU64,
+    active: AtomicUsize,
+    /// Share of the configured volume to produce, in thousandths
+    scale: AtomicU64,
+}
+
+impl Default for DecoyStats {
+    fn default() -> Self {
+        DecoyStats {
+            real: AtomicU64::new(0),
+            decoy: AtomicU64::new(0),
+            pending: AtomicU64::new(0),
+            active: AtomicUsize::new(0),
+            scale: AtomicU64::new(1000),
+        }
+    }
+}
+
+impl DecoyStats {
+    /// Count tunnel bytes sent or received
+    pub fn record_real(&self, bytes: u64) {
+        self.real.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn real_bytes(&self) -> u64 {
+        self.real.load(Ordering::Relaxed)
+    }
+
+    pub fn decoy_bytes(&self) -> u64 {
+        self.decoy.load(Ordering::Relaxed)
+    }
+
+    /// Produce only `scale` (0.0 to 1.0) of the configured cover volume,
+    /// for an overhead budget
+    pub fn set_scale(&self, scale: f64) {
+        let permille = (scale.clamp(0.0, 1.0) * 1000.0).round() as u64;
+        self.scale.store(permille, Ordering::Relaxed);
+    }
+
+    pub fn scale(&self) -> f64 {
+        self.scale.load(Ordering::Relaxed) as f64 / 1000.0
+    }
+
+    /// Cover connections currently open
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Share of all counted traffic that was cover traffic
+    pub fn decoy_ratio(&self) -> f64 {
+        let (real, decoy) = (self.real_bytes(), self.decoy_bytes());
+        if real + decoy == 0 {
+            return 0.0;
+        }
+        decoy as f64 / (real + decoy) as f64
+    }
+}
+
+/// Keeps cover traffic at a fixed share of the total volume
+///
+/// Sessions report their bytes through `stats()`; each round the scheduler
+/// works out how much cover traffic is owed and opens connections to sites
+/// from the pool to make it up. Cover traffic never touches the tunnel's
+/// own bytes.
+pub struct DecoyScheduler {
+    config: DecoyConfig,
+    pool: SniPool,
+    fetcher: Arc<dyn DecoyFetcher>,
+    stats: Arc<DecoyStats>,
+}
+
+impl DecoyScheduler {
+    pub fn new(config: DecoyConfig, pool: SniPool, fetcher: Arc<dyn DecoyFetcher>) -> Result<Self> {
+        config.validate()?;
+        Ok(DecoyScheduler {
+            config,
+            pool,
+            fetcher,
+            stats: Arc::new(DecoyStats::default()),
+        })
+    }
+
+    /// Scheduler fetching real pages over HTTPS from the pool's sites
+    pub fn with_https(config: DecoyConfig, pool: SniPool) -> Result<Self> {
+        let fetcher = Arc::new(HttpsFetcher::new(pool.clone(), Duration::from_secs(30)));
+        Self::new(config, pool, fetcher)
+    }
+
+    pub fn config(&self) -> &DecoyConfig {
+        &self.config
+    }
+
+    /// Counters sessions report their traffic to
+    pub fn stats(&self) -> Arc<DecoyStats> {
+        self.stats.clone()
+    }
+
+    /// Cover bytes owed to reach the configured share, beyond what running
+    /// connections will bring
+    pub fn deficit(&self) -> u64 {
+        let percentage = self.config.percentage as u64;
+        let scale = self.stats.scale.load(Ordering::Relaxed);
+        let target = self.stats.real_bytes() * percentage / (100 - percentage) * scale / 1000;
+        target
+            .saturating_sub(self.stats.decoy_bytes())
+            .saturating_sub(self.stats.pending.load(Ordering::Relaxed))
+    }
+
+    /// One scheduling round: open cover connections for the deficit
+    pub fn schedule(&self) -> Vec<JoinHandle<()>> {
+        let mut handles = Vec::new();
+        while self.stats.active() < self.config.max_concurrent {
+            let deficit = self.deficit();
+            if deficit < self.config.min_fetch {
+                break;
+            }
+            let Some(host) = self.pool.choose() else {
+                break;
+            };
+            let budget = rand::thread_rng()
+                .gen_range(self.config.min_fetch..=deficit.min(self.config.max_fetch));
+
+            let stats = self.stats.clone();
+            let fetcher = self.fetcher.clone();
+            stats.pending.fetch_add(budget, Ordering::Relaxed);
+            stats.active.fetch_add(1, Ordering::Relaxed);
+            handles.push(tokio::spawn(async move {
+                match fetcher.fetch(&host, budget).await {
+                    Ok(moved) => {
+                        stats.decoy.fetch_add(moved, Ordering::Relaxed);
+                    }
+                    Err(e) => {
+                        log::debug!("Decoy fetch from {} failed: {}", redact(&host), e)
+                    }
+                }
+                stats.pending.fetch_sub(budget, Ordering::Relaxed);
+                stats.active.fetch_sub(1, Ordering::Relaxed);
+            }));
+        }
+        handles
+    }
+
+    /// Run scheduling rounds in the background at jittered intervals
+    pub fn spawn(self: Arc<Self>) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                let jitter = rand::thread_rng().gen_range(0.5..1.5);
+                tokio::time::sleep(self.config.tick.mul_f64(jitter)).await;
+                self.schedule();
+            }
+        })
+    }
+}
+
+/// Settings of idle-session heartbeats
+#[derive(Clone, Debug)]
+pub struct HeartbeatConfig {
+    /// Quiet time after real traffic before heartbeats start
+    pub idle_after: Duration,
+    /// Spacing of heartbeats once idle
+    pub model: TimingModel,
+    /// Wire length range of one heartbeat record
+    pub len: Range<usize>,
+    /// What the transport adds to each record, as for `MorphingConfig`
+    pub overhead: usize,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        HeartbeatConfig {
+            idle_after: Duration::from_secs(2),
+            model: TimingModel::idle(),
+            len: 40..160,
+            overhead: 0,
+        }
+    }
+}
+
+/// Low-rate cover records for an idle session
+///
+/// Long-lived tunnels otherwise go dead silent between bursts of use, a
+/// pattern classifiers key on. Once a session has been quiet for
+/// `idle_after`, the heartbeat produces tiny padding-only records at
+/// human-plausible intervals; real traffic (`touch`) pushes it back. The
+/// records use the morphing framing, so the peer's `unmorph` reads them as
+/// empty and drops them.
+pub struct Heartbeat {
+    config: HeartbeatConfig,
+    timing: TimingGenerator,
+    due: Instant,
+}
+
+impl Heartbeat {
+    pub fn new(config: HeartbeatConfig) -> Result<Self> {
+        if config.len.is_empty() || config.len.start <= config.overhead {
+            return Err(Error::ConfigError(
+                "Heartbeat length range leaves no room for a record".to_string(),
+            ));
+        }
+        let timing = TimingGenerator::new(config.model.clone());
+        let due = Instant::now() + config.idle_after;
+        Ok(Heartbeat {
+            config,
+            timing,
+            due,
+        })
+    }
+
+    pub fn config(&self) -> &HeartbeatConfig {
+        &self.config
+    }
+
+    /// When the next heartbeat is due
+    pub fn due(&self) -> Instant {
+        self.due
+    }
+
+    /// Note real traffic on the session
+    pub fn touch(&mut self) {
+        self.touch_at(Instant::now());
+    }
+
+    fn touch_at(&mut self, now: Instant) {
+        self.due = now + self.config.idle_after;
+    }
+
+    /// A heartbeat record if one is due
+    pub fn poll(&mut self) -> Option<Vec<u8>> {
+        self.poll_at(Instant::now())
+    }
+
+    fn poll_at(&mut self, now: Instant) -> Option<Vec<u8>> {
+        if now < self.due {
+            return None;
+        }
+        self.due = now + self.timing.next_delay();
+        let len = rand::thread_rng().gen_range(self.config.len.clone());
+        Some(padding_packet(len, self.config.overhead))
+    }
+
+    /// Wait for the next heartbeat; meant to be raced against the session's
+    /// real reads, calling `touch` when those win
+    pub async fn tick(&mut self) -> Vec<u8> {
+        loop {
+            tokio::time::sleep_until(self.due.into()).await;
+            if let Some(record) = self.poll() {
+                return record;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Moves exactly the budget without touching the network
+    struct CountingFetcher;
+
+    #[async_trait]
+    impl DecoyFetcher for CountingFetcher {
+        async fn fetch(&self, _host: &str, budget: u64) -> Result<u64> {
+            Ok(budget)
+        }
+    }
+
+    fn scheduler(percentage: u8) -> DecoyScheduler {
+        let config = DecoyConfig {
+            percentage,
+            max_concurrent: 4,
+            ..Default::default()
+        };
+        DecoyScheduler::new(config, SniPool::builtin(), Arc::new(CountingFetcher)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_cover_traffic_reaches_share() {
+        let scheduler = scheduler(20);
+        scheduler.stats().record_real(8_000_000);
+        assert_eq!(scheduler.deficit(), 2_000_000);
+
+        for _ in 0..20 {
+            for handle in scheduler.schedule() {
+                handle.await.unwrap();
+            }
+        }
+        let stats = scheduler.stats();
+        assert!(scheduler.deficit() < scheduler.config().min_fetch);
+        assert!((stats.decoy_ratio() - 0.2).abs() < 0.01);
+        assert_eq!(stats.active(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_no_cover_traffic_when_disabled() {
+        let scheduler = scheduler(0);
+        scheduler.stats().record_real(10_000_000);
+        assert!(scheduler.schedule().is_empty());
+        assert_eq!(scheduler.stats().decoy_ratio(), 0.0);
+    }
+
+    #[test]
+    fn test_config_validated() {
+        let fetcher: Arc<dyn DecoyFetcher> = Arc::new(CountingFetcher);
+        let full = DecoyConfig {
+            percentage: 100,
+            ..Default::default()
+        };
+        assert!(DecoyScheduler::new(full, SniPool::builtin(), fetcher.clone()).is_err());
+        let empty_range = DecoyConfig {
+            min_fetch: 10,
+            max_fetch: 5,
+            ..Default::default()
+        };
+        assert!(DecoyScheduler::new(empty_range, SniPool::builtin(), fetcher).is_err());
+    }
+
+    #[test]
+    fn test_config_from_settings() {
+        let mut settings = DetectionEvadingConfig {
+            decoy_traffic_percentage: 35,
+            ..Default::default()
+        };
+        assert_eq!(DecoyConfig::from_settings(&settings).percentage, 35);
+        settings.decoy_traffic_enabled = false;
+        assert_eq!(DecoyConfig::from_settings(&settings).percentage, 0);
+        settings.decoy_traffic_enabled = true;
+        settings.enabled = false;
+        assert_eq!(DecoyConfig::from_settings(&settings).percentage, 0);
+    }
+
+    #[test]
+    fn test_heartbeat_only_when_idle() {
+        let mut heartbeat = Heartbeat::new(HeartbeatConfig::default()).unwrap();
+        let start = Instant::now();
+        heartbeat.touch_at(start);
+        assert!(heartbeat.poll_at(start + Duration::from_secs(1)).is_none());
+
+        let record = heartbeat.poll_at(start + Duration::from_secs(3)).unwrap();
+        assert!((40..160).contains(&record.len()));
+        assert!(crate::morphing::unmorph(&record).unwrap().is_empty());
+        // Spaced by the idle model, not back to back
+        assert!(heartbeat.due() > start + Duration::from_secs(3));
+
+        // Real traffic pushes the next one back
+        let later = heartbeat.due() + Duration::from_secs(1);
+        heartbeat.touch_at(later);
+        assert!(heartbeat.poll_at(later).is_none());
+    }
+
+    #[test]
+    fn test_heartbeat_intervals_are_plausible() {
+        let mut heartbeat = Heartbeat::new(HeartbeatConfig::default()).unwrap();
+        let mut now = heartbeat.due();
+        let mut gaps = Vec::new();
+        for _ in 0..200 {
+            assert!(heartbeat.poll_at(now).is_some());
+            gaps.push(heartbeat.due() - now);
+            now = heartbeat.due();
+        }
+        let mean = gaps.iter().sum::<Duration>() / gaps.len() as u32;
+        assert!(mean > Duration::from_secs(3) && mean < Duration::from_secs(40));
+        assert!(gaps.iter().all(|gap| *gap <= Duration::from_secs(120)));
+    }
+}
@@ -0,0 +1,829 @@
+//! Fake-packet desync
+//! Crafts TCP segments that reach the DPI box but not the destination (low TTL),
+//! or that the destination drops (bad checksum), so the DPI's view of the flow is
+//! corrupted before the real data arrives
+
+use crate::client_hello::parse_client_hello;
+use crate::dpi_bypass::{DPIBypass, DecoyDesyncConfig, DecoyPacket, DesyncMethod, RawSender};
+use crate::error::{Error, Result};
+use crate::fingerprint::OsFamily;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+pub(crate) const IPV4_HEADER_LEN: usize = 20;
+const TCP_HEADER_LEN: usize = 20;
+pub(crate) const IPPROTO_TCP: u8 = 6;
+const IPPROTO_RAW: i32 = 255;
+
+pub(crate) const TCP_FLAG_PSH: u8 = 0x08;
+pub(crate) const TCP_FLAG_ACK: u8 = 0x10;
+
+/// Initial TTLs used by common stacks, smallest first
+const INITIAL_TTLS: [u8; 3] = [64, 128, 255];
+
+/// Hop count implied by a received TTL, assuming the sender used the next
+/// common initial TTL (64 for Linux/macOS, 128 for Windows, 255 for routers)
+pub fn hop_count(observed_ttl: u8) -> u8 {
+    let initial = INITIAL_TTLS
+        .iter()
+        .copied()
+        .find(|&initial| initial >= observed_ttl)
+        .unwrap_or(255);
+    initial - observed_ttl
+}
+
+/// How the TTL of fake packets is chosen
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FakeTtl {
+    /// Always use this TTL
+    Fixed(u8),
+    /// Derive the TTL from the measured DPI distance, or failing that from the
+    /// server's distance minus `delta`, clamped to `min..=max`
+    Auto { delta: u8, min: u8, max: u8 },
+}
+
+impl Default for FakeTtl {
+    fn default() -> Self {
+        FakeTtl::Auto {
+            delta: 1,
+            min: 3,
+            max: 20,
+        }
+    }
+}
+
+impl FakeTtl {
+    /// Resolve the TTL for one flow
+    ///
+    /// `server_hops` is the server's distance (see `hop_count`), `dpi_hops`
+    /// the distance to the filtering device if it has been measured.
+    pub fn resolve(&self, server_hops: Option<u8>, dpi_hops: Option<u8>) -> u8 {
+        match *self {
+            FakeTtl::Fixed(ttl) => ttl,
+            FakeTtl::Auto { delta, min, max } => {
+                let ttl = match (dpi_hops, server_hops) {
+                    // Reach the DPI box but stay short of the server
+                    (Some(dpi), Some(server)) => dpi.min(server.saturating_sub(1)),
+                    (Some(dpi), None) => dpi,
+                    (None, Some(server)) => server.saturating_sub(delta),
+                    (None, None) => min,
+                };
+                ttl.clamp(min.max(1), max.max(min))
+            }
+        }
+    }
+}
+
+/// Desync technique applied to the first data of a connection
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DesyncStrategy {
+    /// Send a fake segment with a TTL that expires between the DPI box and
+    /// the server, then the real segment at the same sequence number
+    #[default]
+    FakeLowTtl,
+    /// Send a fake segment with an invalid TCP checksum, which the server's
+    /// stack drops but DPI engines that skip checksum validation consume
+    BadChecksum,
+    /// Send the second part of the payload before the first; the server
+    /// reassembles it, DPI engines that don't reorder see garbage
+    OutOfOrder,
+    /// Send two segments that overlap at the split, with junk in the overlap
+    /// on the copy the endpoint discards; DPI with the opposite reassembly
+    /// policy reconstructs the junk
+    Overlap,
+}
+
+/// Which copy of overlapping TCP data a receiver keeps
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ReassemblyPolicy {
+    /// Bytes received first win; later overlapping bytes are trimmed
+    #[default]
+    First,
+    /// Later bytes overwrite what was received before
+    Last,
+}
+
+impl ReassemblyPolicy {
+    /// Policy of an endpoint OS for overlaps within in-order data
+    ///
+    /// Linux, Windows, macOS/iOS and Android all trim data below `rcv_nxt`,
+    /// so once the first segment is accepted its bytes win. `Last` is for
+    /// targets known to favour new data, such as some embedded stacks and
+    /// re-segmenting middleboxes in front of the server.
+    pub fn for_os(os: OsFamily) -> Self {
+        match os {
+            OsFamily::Linux
+            | OsFamily::Windows
+            | OsFamily::MacOs
+            | OsFamily::Android
+            | OsFamily::Ios => ReassemblyPolicy::First,
+        }
+    }
+}
+
+/// Where the payload is cut for strategies that split it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum SplitPosition {
+    /// In the middle of the SNI hostname, or half-way if there is none
+    #[default]
+    Sni,
+    /// At a fixed byte offset
+    Offset(usize),
+}
+
+impl SplitPosition {
+    /// Resolve the cut for a payload; always leaves both parts non-empty
+    pub fn resolve(&self, payload: &[u8]) -> usize {
+        let split = match self {
+            SplitPosition::Sni => parse_client_hello(payload)
+                .ok()
+                .and_then(|hello| hello.sni_split_offset())
+                .unwrap_or(payload.len() / 2),
+            SplitPosition::Offset(offset) => *offset,
+        };
+        split.clamp(1, payload.len().saturating_sub(1).max(1))
+    }
+}
+
+/// Which desync strategies this platform can carry out
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DesyncCapabilities {
+    /// Raw IPv4 sockets can be opened (root or CAP_NET_RAW)
+    pub raw_sockets: bool,
+    /// The kernel leaves our TCP checksum alone on raw sends. True on Linux,
+    /// the BSDs and macOS; Windows refuses raw TCP sends altogether
+    pub preserves_tcp_checksum: bool,
+}
+
+impl DesyncCapabilities {
+    /// Probe the current process and platform
+    pub fn detect() -> Self {
+        #[cfg(unix)]
+        let raw_sockets = RawSocket::open().is_ok();
+        #[cfg(not(unix))]
+        let raw_sockets = false;
+
+        DesyncCapabilities {
+            raw_sockets,
+            preserves_tcp_checksum: cfg!(any(
+                target_os = "linux",
+                target_os = "android",
+                target_os = "macos",
+                target_os = "freebsd",
+                target_os = "openbsd",
+                target_os = "netbsd"
+            )),
+        }
+    }
+
+    /// Check whether a strategy can run here
+    pub fn supports(&self, strategy: DesyncStrategy) -> bool {
+        match strategy {
+            DesyncStrategy::FakeLowTtl => self.raw_sockets,
+            DesyncStrategy::BadChecksum => self.raw_sockets && self.preserves_tcp_checksum,
+            // Without raw sockets the socket backend reorders via TTL tricks
+            DesyncStrategy::OutOfOrder => true,
+            DesyncStrategy::Overlap => self.raw_sockets,
+        }
+    }
+}
+
+/// Configuration for the desync engine
+#[derive(Clone, Debug)]
+pub struct DesyncConfig {
+    pub strategy: DesyncStrategy,
+    pub fake_ttl: FakeTtl,
+    /// Measured hop distance to the DPI box, if known
+    pub dpi_hops: Option<u8>,
+    /// Payload of fake segments; a decoy ClientHello for an allowed SNI when unset
+    pub fake_payload: Option<Vec<u8>>,
+    /// Fake segments sent before the real one
+    pub repeats: u8,
+    /// Where split strategies cut the payload
+    pub split: SplitPosition,
+    /// Bytes shared by the two segments of `Overlap`
+    pub overlap_len: usize,
+    /// Reassembly policy of the destination's stack
+    pub endpoint_policy: ReassemblyPolicy,
+}
+
+impl Default for DesyncConfig {
+    fn default() -> Self {
+        DesyncConfig {
+            strategy: DesyncStrategy::FakeLowTtl,
+            fake_ttl: FakeTtl::default(),
+            dpi_hops: None,
+            fake_payload: None,
+            repeats: 1,
+            split: SplitPosition::Sni,
+            overlap_len: 8,
+            endpoint_policy: ReassemblyPolicy::First,
+        }
+    }
+}
+
+/// Addresses and sequence state of the TCP connection being desynced
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TcpFlow {
+    pub src: SocketAddrV4,
+    pub dst: SocketAddrV4,
+    /// Sequence number of the next byte we send
+    pub seq: u32,
+    /// Next sequence number expected from the server
+    pub ack: u32,
+    pub window: u16,
+    /// TTL for real segments
+    pub ttl: u8,
+    /// TTL seen on the server's SYN-ACK, used for auto-TTL
+    pub server_ttl: Option<u8>,
+}
+
+/// One crafted IPv4 packet, in send order
+#[derive(Clone, Debug)]
+pub struct Segment {
+    pub packet: Vec<u8>,
+    /// True for packets meant for the DPI only
+    pub fake: bool,
+}
+
+/// Internet checksum over a byte slice, continuing from `sum`
+fn checksum_add(mut sum: u32, data: &[u8]) -> u32 {
+    for pair in data.chunks(2) {
+        let word = match pair {
+            [hi, lo] => u16::from_be_bytes([*hi, *lo]),
+            [hi] => u16::from_be_bytes([*hi, 0]),
+            _ => 0,
+        };
+        sum += word as u32;
+    }
+    sum
+}
+
+fn checksum_finish(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Internet checksum of a self-contained message such as an ICMP packet
+pub(crate) fn internet_checksum(data: &[u8]) -> u16 {
+    checksum_finish(checksum_add(0, data))
+}
+
+/// TCP checksum including the IPv4 pseudo-header
+pub(crate) fn tcp_checksum(src: Ipv4Addr, dst: Ipv4Addr, segment: &[u8]) -> u16 {
+    let mut sum = checksum_add(0, &src.octets());
+    sum = checksum_add(sum, &dst.octets());
+    sum += IPPROTO_TCP as u32;
+    sum += segment.len() as u32;
+    checksum_finish(checksum_add(sum, segment))
+}
+
+/// Build an IPv4/TCP packet carrying `payload` at sequence number `seq`
+pub fn tcp_packet(flow: &TcpFlow, seq: u32, payload: &[u8], ttl: u8) -> Vec<u8> {
+    build_tcp_packet(flow, seq, TCP_FLAG_PSH | TCP_FLAG_ACK, &[], payload, ttl)
+}
+
+/// Build an IPv4/TCP packet with arbitrary flags and TCP options
+///
+/// `options` must already be padded to a multiple of four bytes.
+pub(crate) fn build_tcp_packet(
+    flow: &TcpFlow,
+    seq: u32,
+    flags: u8,
+    options: &[u8],
+    payload: &[u8],
+    ttl: u8,
+) -> Vec<u8> {
+    debug_assert!(options.len().is_multiple_of(4) && options.len() <= 40);
+    let tcp_header_len = TCP_HEADER_LEN + options.len();
+    let total_len = IPV4_HEADER_LEN + tcp_header_len + payload.len();
+    let mut packet = Vec::with_capacity(total_len);
+
+    // IPv4 header
+    packet.push(0x45); // version 4, IHL 5
+    packet.push(0x00); // DSCP/ECN
+    packet.extend_from_slice(&(total_len as u16).to_be_bytes());
+    packet.extend_from_slice(&rand::thread_rng().gen::<u16>().to_be_bytes()); // identification
+    packet.extend_from_slice(&[0x40, 0x00]); // don't fragment
+    packet.push(ttl);
+    packet.push(IPPROTO_TCP);
+    packet.extend_from_slice(&[0x00, 0x00]); // header checksum
+    packet.extend_from_slice(&flow.src.ip().octets());
+    packet.extend_from_slice(&flow.dst.ip().octets());
+    let ip_checksum = checksum_finish(checksum_add(0, &packet[..IPV4_HEADER_LEN]));
+    packet[10..12].copy_from_slice(&ip_checksum.to_be_bytes());
+
+    // TCP header
+    let tcp_start = packet.len();
+    let ack = if flags & TCP_FLAG_ACK != 0 { flow.ack } else { 0 };
+    packet.extend_from_slice(&flow.src.port().to_be_bytes());
+    packet.extend_from_slice(&flow.dst.port().to_be_bytes());
+    packet.extend_from_slice(&seq.to_be_bytes());
+    packet.extend_from_slice(&ack.to_be_bytes());
+    packet.push((tcp_header_len as u8 / 4) << 4);
+    packet.push(flags);
+    packet.extend_from_slice(&flow.window.to_be_bytes());
+    packet.extend_from_slice(&[0x00, 0x00]); // checksum
+    packet.extend_from_slice(&[0x00, 0x00]); // urgent pointer
+    packet.extend_from_slice(options);
+    packet.extend_from_slice(payload);
+
+    let tcp_checksum = tcp_checksum(*flow.src.ip(), *flow.dst.ip(), &packet[tcp_start..]);
+    packet[tcp_start + 16..tcp_start + 18].copy_from_slice(&tcp_checksum.to_be_bytes());
+    packet
+}
+
+/// Build an IPv4/TCP packet whose TCP checksum is deliberately wrong
+pub fn tcp_packet_bad_checksum(flow: &TcpFlow, seq: u32, payload: &[u8], ttl: u8) -> Vec<u8> {
+    let mut packet = tcp_packet(flow, seq, payload, ttl);
+    let offset = IPV4_HEADER_LEN + 16;
+    let checksum = u16::from_be_bytes([packet[offset], packet[offset + 1]]);
+    // Any change works; flipping bits can never produce the valid value
+    packet[offset..offset + 2].copy_from_slice(&(checksum ^ 0xffff).to_be_bytes());
+    packet
+}
+
+/// Header fields of a received IPv4/TCP packet
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TcpSegmentInfo {
+    pub src: SocketAddrV4,
+    pub dst: SocketAddrV4,
+    pub seq: u32,
+    pub ack: u32,
+    pub flags: u8,
+    pub window: u16,
+    pub ttl: u8,
+    pub payload: Vec<u8>,
+    /// Whether the TCP checksum verified
+    pub checksum_ok: bool,
+}
+
+/// Parse an IPv4/TCP packet as read from a raw socket
+pub fn parse_segment(packet: &[u8]) -> Option<TcpSegmentInfo> {
+    if packet.len() < IPV4_HEADER_LEN || packet[0] >> 4 != 4 || packet[9] != IPPROTO_TCP {
+        return None;
+    }
+    let ip_header_len = (packet[0] & 0x0f) as usize * 4;
+    let total_len = (u16::from_be_bytes([packet[2], packet[3]]) as usize).min(packet.len());
+    let tcp = packet.get(ip_header_len..total_len)?;
+    if tcp.len() < 20 {
+        return None;
+    }
+    let tcp_header_len = (tcp[12] >> 4) as usize * 4;
+    if tcp_header_len < 20 || tcp_header_len > tcp.len() {
+        return None;
+    }
+
+    let src_ip = Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]);
+    let dst_ip = Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]);
+    let word = |at: usize| u32::from_be_bytes([tcp[at], tcp[at + 1], tcp[at + 2], tcp[at + 3]]);
+    Some(TcpSegmentInfo {
+        src: SocketAddrV4::new(src_ip, u16::from_be_bytes([tcp[0], tcp[1]])),
+        dst: SocketAddrV4::new(dst_ip, u16::from_be_bytes([tcp[2], tcp[3]])),
+        seq: word(4),
+        ack: word(8),
+        flags: tcp[13],
+        window: u16::from_be_bytes([tcp[14], tcp[15]]),
+        ttl: packet[8],
+        payload: tcp[tcp_header_len..].to_vec(),
+        checksum_ok: tcp_checksum(src_ip, dst_ip, tcp) == 0,
+    })
+}
+
+/// Desync engine: plans the packets to send for the first data of a flow
+pub struct Desync {
+    config: DesyncConfig,
+}
+
+impl Desync {
+    /// Create a desync engine
+    pub fn new(config: DesyncConfig) -> Self {
+        Desync { config }
+    }
+
+    /// Get the configuration
+    pub fn config(&self) -> &DesyncConfig {
+        &self.config
+    }
+
+    /// Record a measured DPI distance for auto-TTL
+    pub fn set_dpi_hops(&mut self, hops: u8) {
+        self.config.dpi_hops = Some(hops);
+    }
+
+    /// TTL for fake packets on this flow
+    pub fn fake_ttl(&self, flow: &TcpFlow) -> u8 {
+        let server_hops = flow.server_ttl.map(hop_count);
+        self.config
+            .fake_ttl
+            .resolve(server_hops, self.config.dpi_hops)
+    }
+
+    /// Payload carried by fake segments
+    fn fake_payload(&self) -> Result<Vec<u8>> {
+        if let Some(payload) = &self.config.fake_payload {
+            return Ok(payload.clone());
+        }
+        let decoy = DPIBypass::decoy_client_hellos(&DecoyDesyncConfig::default())?;
+        Ok(decoy.into_iter().next().map(|d| d.data).unwrap_or_default())
+    }
+
+    /// Packets to send, in order, in place of `payload`
+    pub fn segments(&self, flow: &TcpFlow, payload: &[u8]) -> Result<Vec<Segment>> {
+        let mut segments = Vec::new();
+
+        match self.config.strategy {
+            DesyncStrategy::FakeLowTtl | DesyncStrategy::BadChecksum => {
+                let fake = self.fake_payload()?;
+                let ttl = self.fake_ttl(flow);
+                for _ in 0..self.config.repeats.max(1) {
+                    let packet = if self.config.strategy == DesyncStrategy::BadChecksum {
+                        tcp_packet_bad_checksum(flow, flow.seq, &fake, flow.ttl)
+                    } else {
+                        tcp_packet(flow, flow.seq, &fake, ttl)
+                    };
+                    segments.push(Segment { packet, fake: true });
+                }
+                segments.push(Segment {
+                    packet: tcp_packet(flow, flow.seq, payload, flow.ttl),
+                    fake: false,
+                });
+            }
+            DesyncStrategy::OutOfOrder => {
+                let split = self.config.split.resolve(payload);
+                let second_seq = flow.seq.wrapping_add(split as u32);
+                segments.push(Segment {
+                    packet: tcp_packet(flow, second_seq, &payload[split..], flow.ttl),
+                    fake: false,
+                });
+                segments.push(Segment {
+                    packet: tcp_packet(flow, flow.seq, &payload[..split], flow.ttl),
+                    fake: false,
+                });
+            }
+            DesyncStrategy::Overlap => segments.extend(self.overlap_segments(flow, payload)),
+        }
+
+        Ok(segments)
+    }
+
+    /// Two overlapping segments whose shared bytes differ
+    ///
+    /// The copy the endpoint keeps carries the real bytes; the other carries
+    /// junk, which is what DPI with the opposite policy reassembles.
+    fn overlap_segments(&self, flow: &TcpFlow, payload: &[u8]) -> Vec<Segment> {
+        let split = self.config.split.resolve(payload);
+        let end = (split + self.config.overlap_len.max(1)).min(payload.len());
+        let mut rng = rand::thread_rng();
+        let junk: Vec<u8> = (split..end).map(|_| rng.gen()).collect();
+
+        let mut first = payload[..end].to_vec();
+        let mut second = payload[split..].to_vec();
+        match self.config.endpoint_policy {
+            // The endpoint keeps the first copy, so the second gets the junk
+            ReassemblyPolicy::First => second[..junk.len()].copy_from_slice(&junk),
+            ReassemblyPolicy::Last => first[split..].copy_from_slice(&junk),
+        }
+
+        let second_seq = flow.seq.wrapping_add(split as u32);
+        vec![
+            Segment {
+                packet: tcp_packet(flow, flow.seq, &first, flow.ttl),
+                fake: false,
+            },
+            Segment {
+                packet: tcp_packet(flow, second_seq, &second, flow.ttl),
+                fake: false,
+            },
+        ]
+    }
+
+    /// Send `payload` on a flow through a raw socket, desynced
+    ///
+    /// The kernel's own copy of the payload must be kept off the wire (see
+    /// the firewall helpers), or the flow must be driven entirely from raw
+    /// packets, since the raw socket doesn't advance the kernel's sequence.
+    #[cfg(unix)]
+    pub fn send(&self, socket: &RawSocket, flow: &TcpFlow, payload: &[u8]) -> Result<()> {
+        for segment in self.segments(flow, payload)? {
+            socket.send(&segment.packet)?;
+        }
+        Ok(())
+    }
+}
+
+/// Send `payload` on a regular socket with its two parts arriving out of order
+///
+/// The first part goes out with TTL 1 and dies at the first hop; the second
+/// follows with the normal TTL. The kernel then retransmits the first part
+/// with the restored TTL, so the server gets the second part first. Costs one
+/// retransmission timeout but needs no privileges.
+pub async fn send_out_of_order(stream: &mut TcpStream, payload: &[u8], split: usize) -> Result<()> {
+    let split = split.clamp(1, payload.len().saturating_sub(1).max(1));
+    if payload.len() < 2 {
+        stream.write_all(payload).await?;
+        return Ok(());
+    }
+
+    let ttl = stream.ttl()?;
+    stream.set_nodelay(true)?;
+    stream.set_ttl(1)?;
+    let first = stream.write_all(&payload[..split]).await;
+    // Restore the TTL even if the write failed
+    stream.set_ttl(ttl)?;
+    first?;
+    stream.write_all(&payload[split..]).await?;
+    Ok(())
+}
+
+/// Raw IPv4 socket sending complete, self-built packets
+///
+/// Needs root or CAP_NET_RAW.
+#[cfg(unix)]
+pub struct RawSocket {
+    socket: socket2::Socket,
+}
+
+#[cfg(unix)]
+impl RawSocket {
+    /// Open a raw socket (IPPROTO_RAW implies IP_HDRINCL)
+    pub fn open() -> Result<Self> {
+        let socket = socket2::Socket::new(
+            socket2::Domain::IPV4,
+            socket2::Type::RAW,
+            Some(socket2::Protocol::from(IPPROTO_RAW)),
+        )?;
+        Ok(RawSocket { socket })
+    }
+
+    /// Send one IPv4 packet to the destination in its header
+    pub fn send(&self, packet: &[u8]) -> Result<()> {
+        if packet.len() < IPV4_HEADER_LEN || packet[0] >> 4 != 4 {
+            return Err(Error::DataError("Not an IPv4 packet".to_string()));
+        }
+        let dst = Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]);
+        let addr = socket2::SockAddr::from(SocketAddrV4::new(dst, 0));
+        self.socket.send_to(packet, &addr)?;
+        Ok(())
+    }
+
+    /// Set the fwmark of packets sent through this socket, for routing and
+    /// TTL rules installed by `firewall`
+    #[cfg(target_os = "linux")]
+    pub fn set_mark(&self, mark: u32) -> Result<()> {
+        self.socket.set_mark(mark)?;
+        Ok(())
+    }
+}
+
+/// `dpi_bypass::RawSender` hook injecting decoys into one flow
+#[cfg(unix)]
+pub struct FlowInjector {
+    socket: Arc<RawSocket>,
+    flow: TcpFlow,
+}
+
+#[cfg(unix)]
+impl FlowInjector {
+    /// Create an injector for a flow
+    pub fn new(socket: Arc<RawSocket>, flow: TcpFlow) -> Self {
+        FlowInjector { socket, flow }
+    }
+}
+
+#[cfg(unix)]
+impl RawSender for FlowInjector {
+    fn send_decoy(&self, packet: &DecoyPacket) -> Result<()> {
+        let flow = &self.flow;
+        let raw = match packet.method {
+            DesyncMethod::LowTtl(ttl) => tcp_packet(flow, flow.seq, &packet.data, ttl),
+            DesyncMethod::BadChecksum => {
+                tcp_packet_bad_checksum(flow, flow.seq, &packet.data, flow.ttl)
+            }
+        };
+        self.socket.send(&raw)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flow() -> TcpFlow {
+        TcpFlow {
+            src: "10.0.0.2:40000".parse().unwrap(),
+            dst: "203.0.113.7:443".parse().unwrap(),
+            seq: 1000,
+            ack: 5000,
+            window: 64240,
+            ttl: 64,
+            server_ttl: Some(50),
+        }
+    }
+
+    #[test]
+    fn test_hop_count() {
+        assert_eq!(hop_count(50), 14);
+        assert_eq!(hop_count(116), 12);
+        assert_eq!(hop_count(240), 15);
+    }
+
+    #[test]
+    fn test_auto_ttl() {
+        let auto = FakeTtl::default();
+        // Measured DPI distance wins, but stays short of the server
+        assert_eq!(auto.resolve(Some(14), Some(5)), 5);
+        assert_eq!(auto.resolve(Some(4), Some(9)), 3);
+        // Otherwise just short of the server
+        assert_eq!(auto.resolve(Some(14), None), 13);
+        assert_eq!(auto.resolve(None, None), 3);
+        assert_eq!(FakeTtl::Fixed(7).resolve(Some(14), Some(2)), 7);
+    }
+
+    #[test]
+    fn test_packet_checksums() {
+        let flow = flow();
+        let packet = tcp_packet(&flow, flow.seq, b"hello", 9);
+
+        assert_eq!(packet.len(), 45);
+        assert_eq!(packet[8], 9);
+        // A valid checksum sums to zero over the covered bytes
+        assert_eq!(checksum_finish(checksum_add(0, &packet[..20])), 0);
+        assert_eq!(
+            tcp_checksum(*flow.src.ip(), *flow.dst.ip(), &packet[20..]),
+            0
+        );
+    }
+
+    #[test]
+    fn test_bad_checksum_segments() {
+        let desync = Desync::new(DesyncConfig {
+            strategy: DesyncStrategy::BadChecksum,
+            fake_payload: Some(vec![0xAA; 32]),
+            ..Default::default()
+        });
+        let flow = flow();
+        let segments = desync.segments(&flow, b"real data").unwrap();
+
+        let fake = &segments[0].packet;
+        assert_eq!(fake[8], flow.ttl);
+        assert_eq!(checksum_finish(checksum_add(0, &fake[..20])), 0);
+        assert_ne!(tcp_checksum(*flow.src.ip(), *flow.dst.ip(), &fake[20..]), 0);
+
+        let real = &segments[1].packet;
+        assert_eq!(tcp_checksum(*flow.src.ip(), *flow.dst.ip(), &real[20..]), 0);
+    }
+
+    #[test]
+    fn test_capabilities_gate_strategies() {
+        let none = DesyncCapabilities {
+            raw_sockets: false,
+            preserves_tcp_checksum: true,
+        };
+        assert!(!none.supports(DesyncStrategy::FakeLowTtl));
+
+        let no_checksum = DesyncCapabilities {
+            raw_sockets: true,
+            preserves_tcp_checksum: false,
+        };
+        assert!(no_checksum.supports(DesyncStrategy::FakeLowTtl));
+        assert!(!no_checksum.supports(DesyncStrategy::BadChecksum));
+    }
+
+    #[test]
+    fn test_out_of_order_segments() {
+        let desync = Desync::new(DesyncConfig {
+            strategy: DesyncStrategy::OutOfOrder,
+            split: SplitPosition::Offset(4),
+            ..Default::default()
+        });
+        let flow = flow();
+        let segments = desync.segments(&flow, b"abcdefgh").unwrap();
+
+        assert_eq!(segments.len(), 2);
+        assert_eq!(&segments[0].packet[24..28], &(flow.seq + 4).to_be_bytes());
+        assert_eq!(&segments[0].packet[40..], b"efgh");
+        assert_eq!(&segments[1].packet[24..28], &flow.seq.to_be_bytes());
+        assert_eq!(&segments[1].packet[40..], b"abcd");
+    }
+
+    /// Reassemble TCP payloads by sequence number under a policy
+    fn reassemble(segments: &[Segment], base: u32, policy: ReassemblyPolicy) -> Vec<u8> {
+        let mut stream: Vec<Option<u8>> = Vec::new();
+        for segment in segments {
+            let seq = u32::from_be_bytes(segment.packet[24..28].try_into().unwrap());
+            let offset = (seq - base) as usize;
+            for (i, &byte) in segment.packet[40..].iter().enumerate() {
+                if stream.len() <= offset + i {
+                    stream.resize(offset + i + 1, None);
+                }
+                let slot = &mut stream[offset + i];
+                if slot.is_none() || policy == ReassemblyPolicy::Last {
+                    *slot = Some(byte);
+                }
+            }
+        }
+        stream.into_iter().map(|b| b.unwrap()).collect()
+    }
+
+    #[test]
+    fn test_overlap_segments_per_policy() {
+        let flow = flow();
+        let payload = b"0123456789abcdef";
+
+        for policy in [ReassemblyPolicy::First, ReassemblyPolicy::Last] {
+            let desync = Desync::new(DesyncConfig {
+                strategy: DesyncStrategy::Overlap,
+                split: SplitPosition::Offset(6),
+                overlap_len: 4,
+                endpoint_policy: policy,
+                ..Default::default()
+            });
+            let segments = desync.segments(&flow, payload).unwrap();
+            assert_eq!(segments.len(), 2);
+
+            // The endpoint sees the real stream, DPI with the other policy doesn't
+            assert_eq!(reassemble(&segments, flow.seq, policy), payload);
+            let opposite = match policy {
+                ReassemblyPolicy::First => ReassemblyPolicy::Last,
+                ReassemblyPolicy::Last => ReassemblyPolicy::First,
+            };
+            assert_ne!(reassemble(&segments, flow.seq, opposite), payload);
+        }
+        assert_eq!(
+            ReassemblyPolicy::for_os(OsFamily::Windows),
+            ReassemblyPolicy::First
+        );
+    }
+
+    #[test]
+    fn test_split_position_sni() {
+        use crate::client_hello::{ClientHelloBuilder, TLSProfileConfig};
+
+        let hello = ClientHelloBuilder::from_profile(&TLSProfileConfig::default())
+            .server_name("blocked.example")
+            .build();
+        let split = SplitPosition::Sni.resolve(&hello);
+        let sni = parse_client_hello(&hello)
+            .unwrap()
+            .server_name_range
+            .unwrap();
+        assert!(sni.start < split && split < sni.end);
+        assert_eq!(SplitPosition::Offset(100).resolve(b"abc"), 2);
+    }
+
+    #[tokio::test]
+    async fn test_socket_out_of_order_delivers_stream() {
+        use tokio::io::AsyncReadExt;
+        use tokio::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = Vec::new();
+            stream.read_to_end(&mut buf).await.unwrap();
+            buf
+        });
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let ttl = stream.ttl().unwrap();
+        send_out_of_order(&mut stream, b"hello world", 5)
+            .await
+            .unwrap();
+        assert_eq!(stream.ttl().unwrap(), ttl);
+        drop(stream);
+
+        assert_eq!(server.await.unwrap(), b"hello world");
+    }
+
+    #[test]
+    fn test_fake_low_ttl_segments() {
+        let desync = Desync::new(DesyncConfig {
+            fake_payload: Some(vec![0xAA; 32]),
+            repeats: 2,
+            ..Default::default()
+        });
+        let flow = flow();
+        let segments = desync.segments(&flow, b"real data").unwrap();
+
+        assert_eq!(segments.len(), 3);
+        for fake in &segments[..2] {
+            assert!(fake.fake);
+            assert_eq!(fake.packet[8], 13);
+            assert_eq!(&fake.packet[24..28], &flow.seq.to_be_bytes());
+        }
+        let real = &segments[2];
+        assert!(!real.fake);
+        assert_eq!(real.packet[8], 64);
+        assert_eq!(&real.packet[24..28], &flow.seq.to_be_bytes());
+        assert_eq!(&real.packet[40..], b"real data");
+    }
+}
@@ -1,70 +1,334 @@
 //! Detection evasion module for AI/ML-based DPI systems
 //! Evades machine learning detection through feature scrambling and behavior randomization
+//!
+//! Every scrambled packet is carried in an envelope: a random nonce and the
+//! parameters of the scrambling, masked with a keystream. Byte swaps and
+//! noise positions are drawn from the same keystream, so a peer holding
+//! the session key undoes them exactly.
+//!
+//! In ensemble mode each packet gets a random pair of light-touch
+//! techniques instead of all of them at full strength. Each technique
+//! targets a different classifier family (byte histograms, packet lengths,
+//! header bytes, timing), so the flow's packets disagree with each family
+//! in turn rather than all looking scrambled the same way.
 
 use crate::error::{Error, Result};
+use hkdf::Hkdf;
+use rand::seq::SliceRandom;
 use rand::Rng;
+use sha2::{Digest, Sha256};
+
+const NONCE_LEN: usize = 8;
+/// Nonce, then the masked original length (4 bytes), noise count, pattern,
+/// burst chunk size and swaps per block
+const HEADER_LEN: usize = NONCE_LEN + 8;
+/// Most byte swaps per 16-byte block, reached at high adaptation levels
+const MAX_SWAPS_PER_BLOCK: u32 = 16;
+/// Flag in the swaps-per-block header field: the head of the packet is
+/// not shuffled
+const NO_PREFIX_SHUFFLE: u8 = 0x80;
+/// Techniques applied to each packet in ensemble mode
+const ENSEMBLE_SIZE: usize = 2;
+
+/// Behavior patterns of `add_behavior_randomization`
+const PATTERN_SLOW: u8 = 0;
+const PATTERN_BURST: u8 = 1;
+const PATTERN_NONE: u8 = 2;
+
+const FILLER: u8 = 0x00;
+const BURST_MARKER: [u8; 2] = [0xFF, 0xFE];
+
+/// Keyed pseudo-random draws, SHA-256 over the key, nonce and a counter;
+/// both peers derive the same sequence for a packet
+struct KeyStream {
+    key: [u8; 32],
+    nonce: [u8; NONCE_LEN],
+    counter: u64,
+    block: [u8; 32],
+    used: usize,
+}
+
+impl KeyStream {
+    fn new(key: [u8; 32], nonce: [u8; NONCE_LEN]) -> Self {
+        KeyStream {
+            key,
+            nonce,
+            counter: 0,
+            block: [0; 32],
+            used: 32,
+        }
+    }
+
+    fn byte(&mut self) -> u8 {
+        if self.used == self.block.len() {
+            let mut hasher = Sha256::new();
+            hasher.update(self.key);
+            hasher.update(self.nonce);
+            hasher.update(self.counter.to_be_bytes());
+            self.block = hasher.finalize().into();
+            self.counter += 1;
+            self.used = 0;
+        }
+        self.used += 1;
+        self.block[self.used - 1]
+    }
+
+    /// Draw from `0..n`; `n` must be non-zero
+    fn below(&mut self, n: usize) -> usize {
+        let draw = u32::from_be_bytes([self.byte(), self.byte(), self.byte(), self.byte()]);
+        ((draw as u64 * n as u64) >> 32) as usize
+    }
+}
+
+/// Classifier family a technique is chosen to mislead
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ClassifierFamily {
+    /// Byte-frequency and entropy features; countered by byte swaps
+    ByteDistribution,
+    /// Packet-size distributions; countered by noise bytes
+    PacketLength,
+    /// Models over the first bytes of each packet; countered by shuffling
+    /// the head of the packet
+    HeaderBytes,
+    /// Burst and pacing features; countered by behavior patterns
+    Timing,
+}
+
+impl ClassifierFamily {
+    pub const ALL: [ClassifierFamily; 4] = [
+        ClassifierFamily::ByteDistribution,
+        ClassifierFamily::PacketLength,
+        ClassifierFamily::HeaderBytes,
+        ClassifierFamily::Timing,
+    ];
+}
+
+/// Positions the keyed scrambling of one packet touches
+struct Plan {
+    /// Swaps within 16-byte blocks of the payload
+    swaps: Vec<(usize, usize)>,
+    /// Where each noise byte is inserted, in order
+    inserts: Vec<usize>,
+    /// Swaps shuffling the head of the scrambled packet
+    prefix_swaps: Vec<(usize, usize)>,
+}
+
+impl Plan {
+    fn new(stream: &mut KeyStream, len: usize, noise: usize, rounds: u8, prefix: bool) -> Self {
+        let mut swaps = Vec::new();
+        for start in (0..len).step_by(16) {
+            let block = (start + 16).min(len) - start;
+            for _ in 0..rounds {
+                swaps.push((start + stream.below(block), start + stream.below(block)));
+            }
+        }
+
+        let inserts = (0..noise).map(|k| stream.below(len + k + 1)).collect();
+
+        let mut prefix_swaps = Vec::new();
+        let total = len + noise;
+        if prefix && total > 100 {
+            let pivot = 10 + stream.below(total - 20);
+            for i in 0..pivot.min(10) {
+                prefix_swaps.push((i, i + stream.below(pivot - i)));
+            }
+        }
+
+        Plan {
+            swaps,
+            inserts,
+            prefix_swaps,
+        }
+    }
+}
 
 pub struct DetectionEvader {
     max_adaptation_level: u8,
     current_level: u8,
+    /// Level held at least regardless of feedback, e.g. by the calendar
+    min_level: u8,
+    /// Switch to ensemble mode at high adaptation levels
+    ensemble_enabled: bool,
+    key: [u8; 32],
 }
 
 impl DetectionEvader {
+    /// Create an evader with a random key; set the key shared with the peer
+    /// with `set_session_key` before exchanging traffic
     pub fn new(max_adaptation_level: u8) -> Self {
+        Self::with_key(max_adaptation_level, rand::thread_rng().gen())
+    }
+
+    pub(crate) fn with_key(max_adaptation_level: u8, key: [u8; 32]) -> Self {
         DetectionEvader {
             max_adaptation_level,
             current_level: 1,
+            min_level: 1,
+            ensemble_enabled: true,
+            key,
         }
     }
 
+    pub(crate) fn key(&self) -> [u8; 32] {
+        self.key
+    }
+
+    /// Derive the scrambling key from session key material shared with the
+    /// peer, such as the tunnel's handshake secret
+    pub fn set_session_key(&mut self, secret: &[u8]) {
+        Hkdf::<Sha256>::new(None, secret)
+            .expand(b"iran-proxy detection evasion", &mut self.key)
+            .expect("32 bytes is a valid HKDF output length");
+    }
+
     /// Evade AI/ML detection systems
+    ///
+    /// Cover traffic is not mixed into the payload; it runs on separate
+    /// connections from the `decoy` module.
     pub fn evade_detection(&self, data: &[u8]) -> Result<Vec<u8>> {
-        let data = self.scramble_features(data)?;
-        let data = self.add_behavior_randomization(&data)?;
-        let data = self.inject_decoy_traffic(&data)?;
+        self.evade_detection_scaled(data, 1.0)
+    }
 
-        Ok(data)
+    /// Evade detection with the injected noise scaled by `intensity`, from
+    /// 1.0 (full) down to 0.0 (none), to keep within an overhead budget
+    ///
+    /// The adaptation level scales it further, and sets how many bytes are
+    /// swapped and whether behavior patterns are added; see
+    /// `generate_strategy`.
+    pub fn evade_detection_scaled(&self, data: &[u8], intensity: f64) -> Result<Vec<u8>> {
+        let len = u32::try_from(data.len())
+            .map_err(|_| Error::DetectionEvadingError("Packet too large".to_string()))?;
+        let strategy = self.generate_strategy();
+        let intensity = intensity.clamp(0.0, 1.0) * strategy.scramble_scale();
+        let mut rng = rand::thread_rng();
+        let families = if strategy.ensemble_approach {
+            ClassifierFamily::ALL
+                .choose_multiple(&mut rng, ENSEMBLE_SIZE)
+                .copied()
+                .collect()
+        } else {
+            ClassifierFamily::ALL.to_vec()
+        };
+        let uses = |family| families.contains(&family);
+
+        let nonce: [u8; NONCE_LEN] = rng.gen();
+        let noise = if uses(ClassifierFamily::PacketLength) {
+            (rng.gen_range(5..15) as f64 * intensity).round() as u8
+        } else {
+            0
+        };
+        // The filler-adding patterns are used less as intensity drops; the
+        // ensemble always picks one when timing is its target
+        let pattern = if strategy.ensemble_approach {
+            if uses(ClassifierFamily::Timing) && rng.gen_bool(intensity) {
+                rng.gen_range(PATTERN_SLOW..=PATTERN_BURST)
+            } else {
+                PATTERN_NONE
+            }
+        } else if strategy.behavior_randomization && rng.gen_bool(intensity) {
+            rng.gen_range(PATTERN_SLOW..=PATTERN_NONE)
+        } else {
+            PATTERN_NONE
+        };
+        let chunk_size = rng.gen_range(32..128);
+        // Ensemble techniques are light touch: half the swaps
+        let rounds = match (
+            strategy.ensemble_approach,
+            uses(ClassifierFamily::ByteDistribution),
+        ) {
+            (false, _) => strategy.swaps_per_block,
+            (true, true) => (strategy.swaps_per_block / 2).max(1),
+            (true, false) => 0,
+        };
+        let prefix = uses(ClassifierFamily::HeaderBytes);
+        let rounds_field = if prefix {
+            rounds
+        } else {
+            rounds | NO_PREFIX_SHUFFLE
+        };
+
+        let mut stream = KeyStream::new(self.key, nonce);
+        let mut header = nonce.to_vec();
+        let fields =
+            len.to_be_bytes()
+                .into_iter()
+                .chain([noise, pattern, chunk_size, rounds_field]);
+        header.extend(fields.map(|field| field ^ stream.byte()));
+
+        let plan = Plan::new(&mut stream, data.len(), noise as usize, rounds, prefix);
+        let data = self.scramble_features(data, &plan);
+        let body = self.add_behavior_randomization(&data, &plan, pattern, chunk_size as usize);
+
+        header.extend(body);
+        Ok(header)
     }
 
-    /// Reverse detection evasion
+    /// Reverse detection evasion, recovering the packet exactly
     pub fn reverse_evasion(&self, data: &[u8]) -> Result<Vec<u8>> {
-        // In a real implementation, this would reverse the evasion
-        Ok(data.to_vec())
+        if data.len() < HEADER_LEN {
+            return Err(Error::DetectionEvadingError(
+                "Envelope too short".to_string(),
+            ));
+        }
+        let nonce: [u8; NONCE_LEN] = data[..NONCE_LEN].try_into().unwrap();
+        let mut stream = KeyStream::new(self.key, nonce);
+        let mut fields = [0u8; HEADER_LEN - NONCE_LEN];
+        for (field, &masked) in fields.iter_mut().zip(&data[NONCE_LEN..HEADER_LEN]) {
+            *field = masked ^ stream.byte();
+        }
+        let len = u32::from_be_bytes(fields[..4].try_into().unwrap()) as usize;
+        let [noise, pattern, chunk_size, rounds] = [fields[4], fields[5], fields[6], fields[7]];
+        let noise = noise as usize;
+        let prefix = rounds & NO_PREFIX_SHUFFLE == 0;
+        let rounds = rounds & !NO_PREFIX_SHUFFLE;
+
+        let mut result = remove_pattern(&data[HEADER_LEN..], pattern, chunk_size as usize)?;
+        if result.len() != len + noise {
+            return Err(Error::DetectionEvadingError(
+                "Envelope length mismatch; wrong key or corrupted packet".to_string(),
+            ));
+        }
+
+        let plan = Plan::new(&mut stream, len, noise, rounds, prefix);
+        for &(i, j) in plan.prefix_swaps.iter().rev() {
+            result.swap(i, j);
+        }
+        for &pos in plan.inserts.iter().rev() {
+            result.remove(pos);
+        }
+        for &(i, j) in plan.swaps.iter().rev() {
+            result.swap(i, j);
+        }
+        Ok(result)
     }
 
     /// Scramble features that ML models might classify as VPN/proxy traffic
-    fn scramble_features(&self, data: &[u8]) -> Result<Vec<u8>> {
+    fn scramble_features(&self, data: &[u8], plan: &Plan) -> Vec<u8> {
         let mut rng = rand::thread_rng();
         let mut result = data.to_vec();
 
         // Scramble byte distribution
         // ML models often look at byte frequency distributions
-        for i in (0..result.len()).step_by(16) {
-            let end = std::cmp::min(i + 16, result.len());
-
-            // Swap random pairs of bytes
-            for _ in 0..4 {
-                let idx1 = rng.gen_range(i..end);
-                let idx2 = rng.gen_range(i..end);
-                if idx1 != idx2 {
-                    result.swap(idx1, idx2);
-                }
-            }
+        for &(i, j) in &plan.swaps {
+            result.swap(i, j);
         }
 
         // Inject random bytes to change entropy
-        let num_injections = rng.gen_range(5..15);
-        for _ in 0..num_injections {
-            let pos = rng.gen_range(0..=result.len());
+        for &pos in &plan.inserts {
             result.insert(pos, rng.gen());
         }
 
-        Ok(result)
+        result
     }
 
     /// Add randomization to behavioral patterns
-    fn add_behavior_randomization(&self, data: &[u8]) -> Result<Vec<u8>> {
-        let mut rng = rand::thread_rng();
+    fn add_behavior_randomization(
+        &self,
+        data: &[u8],
+        plan: &Plan,
+        pattern: u8,
+        chunk_size: usize,
+    ) -> Vec<u8> {
         let mut result = data.to_vec();
 
         // ML models look at:
@@ -73,92 +337,47 @@ impl DetectionEvader {
         // 3. Packet order patterns
 
         // Randomize packet order
-        if result.len() > 100 {
-            let pivot = rng.gen_range(10..result.len() - 10);
-            let mut before = result[0..pivot].to_vec();
-            let after = result[pivot..].to_vec();
-
-            // Shuffle before part
-            for i in 0..std::cmp::min(10, before.len()) {
-                let j = rng.gen_range(i..before.len());
-                before.swap(i, j);
-            }
-
-            result.clear();
-            result.extend(before);
-            result.extend(after);
+        for &(i, j) in &plan.prefix_swaps {
+            result.swap(i, j);
         }
 
         // Add behavior signature randomization
         // Different connection patterns each time
-        let randomization = rng.gen_range(0..3);
-        match randomization {
-            0 => {
+        match pattern {
+            PATTERN_SLOW => {
                 // Slow transmission pattern
                 let mut delayed = Vec::new();
                 for (i, &byte) in result.iter().enumerate() {
                     delayed.push(byte);
                     if i % 64 == 0 && i > 0 {
-                        delayed.push(0x00); // Filler byte for timing
+                        delayed.push(FILLER); // Filler byte for timing
                     }
                 }
-                result = delayed;
+                delayed
             }
-            1 => {
+            PATTERN_BURST => {
                 // Burst transmission pattern
-                let chunk_size = rng.gen_range(32..128);
                 let mut bursted = Vec::new();
                 for (i, &byte) in result.iter().enumerate() {
                     bursted.push(byte);
                     if (i + 1) % chunk_size == 0 && i > 0 {
-                        // Burst marker
-                        bursted.push(0xFF);
-                        bursted.push(0xFE);
+                        bursted.extend(BURST_MARKER);
                     }
                 }
-                result = bursted;
+                bursted
             }
             _ => {
                 // Mixed pattern
                 // No change
+                result
             }
         }
-
-        Ok(result)
-    }
-
-    /// Inject decoy traffic to confuse classifiers
-    fn inject_decoy_traffic(&self, data: &[u8]) -> Result<Vec<u8>> {
-        let mut rng = rand::thread_rng();
-        let mut result = data.to_vec();
-
-        // Decoy traffic that looks like normal HTTPS
-        let decoy_patterns: Vec<&[u8]> = vec![
-            b"GET / HTTP/1.1\r\nHost: example.com\r\n",
-            b"POST /api HTTP/1.1\r\nType: json\r\n",
-            b"HTTP/1.1 200 OK\r\nType: html\r\n",
-        ];
-
-        // Insert decoy traffic at random positions
-        let num_decoys = rng.gen_range(1..4);
-        for _ in 0..num_decoys {
-            let decoy = decoy_patterns[rng.gen_range(0..decoy_patterns.len())];
-            let pos = rng.gen_range(0..=result.len());
-
-            // Insert decoy
-            let mut inserted = result[0..pos].to_vec();
-            inserted.extend_from_slice(decoy);
-            inserted.extend_from_slice(&result[pos..]);
-
-            result = inserted;
-        }
-
-        Ok(result)
     }
 
     /// Adapt to detected evasion attempts (feedback loop)
     pub fn adapt_to_detection(&mut self) -> Result<()> {
         // Increase adaptation level for more aggressive evasion
+        self.current_level = self.adaptation_level();
         if self.current_level < self.max_adaptation_level {
             self.current_level += 1;
         }
@@ -173,28 +392,116 @@ impl DetectionEvader {
 
     /// Get current adaptation level
     pub fn adaptation_level(&self) -> u8 {
-        self.current_level
+        self.current_level.max(self.min_level)
+    }
+
+    /// Hold the level at `level` or above whatever the feedback says;
+    /// 1 releases the hold
+    pub fn set_min_level(&mut self, level: u8) {
+        self.min_level = level.clamp(1, self.max_adaptation_level.max(1));
+    }
+
+    pub fn min_level(&self) -> u8 {
+        self.min_level
+    }
+
+    /// Allow or forbid ensemble mode, which otherwise takes over above
+    /// level 3
+    pub fn set_ensemble_enabled(&mut self, enabled: bool) {
+        self.ensemble_enabled = enabled;
+    }
+
+    pub fn ensemble_enabled(&self) -> bool {
+        self.ensemble_enabled
     }
 
     /// Generate adaptive evasion strategy based on level
-    pub fn generate_strategy(&self) -> EvastionStrategy {
-        EvastionStrategy {
-            feature_scrambling_intensity: (self.current_level * 25) as u8,
-            decoy_traffic_percentage: (self.current_level * 10) as u8,
-            behavior_randomization: self.current_level > 2,
-            ensemble_approach: self.current_level > 3,
+    ///
+    /// Noise, cover traffic and padding grow in proportion to the level,
+    /// reaching the full configured volume at the maximum level.
+    pub fn generate_strategy(&self) -> EvasionStrategy {
+        let level = self.adaptation_level();
+        let share = (level as f64 / self.max_adaptation_level.max(1) as f64).min(1.0);
+        let percent = (share * 100.0).round() as u8;
+        EvasionStrategy {
+            level,
+            feature_scrambling_intensity: percent,
+            swaps_per_block: (level as u32 * 2).min(MAX_SWAPS_PER_BLOCK) as u8,
+            decoy_traffic_percentage: percent,
+            padding_ratio: share,
+            behavior_randomization: level > 2,
+            ensemble_approach: self.ensemble_enabled && level > 3,
         }
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct EvastionStrategy {
+/// Strip the fillers or burst markers of a behavior pattern
+fn remove_pattern(data: &[u8], pattern: u8, chunk_size: usize) -> Result<Vec<u8>> {
+    let marker: &[u8] = match pattern {
+        PATTERN_SLOW => &[FILLER],
+        PATTERN_BURST if chunk_size > 0 => &BURST_MARKER,
+        PATTERN_NONE => return Ok(data.to_vec()),
+        _ => {
+            return Err(Error::DetectionEvadingError(format!(
+                "Unknown behavior pattern {}",
+                pattern
+            )))
+        }
+    };
+
+    let mut result = Vec::with_capacity(data.len());
+    let mut rest = data;
+    while let Some((&byte, tail)) = rest.split_first() {
+        let i = result.len();
+        result.push(byte);
+        rest = tail;
+        let marked = match pattern {
+            PATTERN_SLOW => i % 64 == 0 && i > 0,
+            _ => (i + 1) % chunk_size == 0 && i > 0,
+        };
+        if marked {
+            rest = rest.strip_prefix(marker).ok_or_else(|| {
+                Error::DetectionEvadingError("Missing behavior pattern filler".to_string())
+            })?;
+        }
+    }
+    Ok(result)
+}
+
+/// What the evader does at one adaptation level
+#[derive(Debug, Clone, PartialEq)]
+pub struct EvasionStrategy {
+    pub level: u8,
+    /// Share of the full noise injection, in percent
     pub feature_scrambling_intensity: u8,
+    /// Byte swaps per 16-byte block
+    pub swaps_per_block: u8,
+    /// Share of the configured cover-traffic volume to run, in percent
     pub decoy_traffic_percentage: u8,
+    /// Share of the full obfuscation padding to add, 0.0 to 1.0
+    pub padding_ratio: f64,
+    /// Add slow or burst filler patterns
     pub behavior_randomization: bool,
+    /// Apply a random pair of light-touch techniques per packet instead of
+    /// all of them; see `ClassifierFamily`
     pub ensemble_approach: bool,
 }
 
+impl EvasionStrategy {
+    /// `feature_scrambling_intensity` as a factor
+    pub fn scramble_scale(&self) -> f64 {
+        self.feature_scrambling_intensity as f64 / 100.0
+    }
+
+    /// `decoy_traffic_percentage` as a factor
+    pub fn decoy_scale(&self) -> f64 {
+        self.decoy_traffic_percentage as f64 / 100.0
+    }
+}
+
+#[deprecated(note = "renamed to `EvasionStrategy`")]
+pub type EvastionStrategy = EvasionStrategy;
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -213,6 +520,37 @@ mod tests {
         assert!(!result.is_empty());
     }
 
+    #[test]
+    fn test_round_trip_is_lossless() {
+        let evader = DetectionEvader::new(5);
+        let mut rng = rand::thread_rng();
+        for len in [0, 1, 15, 16, 64, 100, 101, 129, 1500, 5000] {
+            let mut data = vec![0u8; len];
+            rng.fill(&mut data[..]);
+            for intensity in [0.0, 0.5, 1.0] {
+                for _ in 0..5 {
+                    let evaded = evader.evade_detection_scaled(&data, intensity).unwrap();
+                    assert_eq!(evader.reverse_evasion(&evaded).unwrap(), data, "{}", len);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_peers_share_session_key() {
+        let mut sender = DetectionEvader::new(5);
+        let mut receiver = DetectionEvader::new(5);
+        sender.set_session_key(b"handshake secret");
+        receiver.set_session_key(b"handshake secret");
+        let data = b"GET /index.html HTTP/1.1\r\nHost: example.com\r\n\r\n".repeat(4);
+        let evaded = sender.evade_detection(&data).unwrap();
+        assert_eq!(receiver.reverse_evasion(&evaded).unwrap(), data);
+
+        let stranger = DetectionEvader::new(5);
+        assert_ne!(stranger.reverse_evasion(&evaded).ok(), Some(data));
+        assert!(receiver.reverse_evasion(&evaded[..10]).is_err());
+    }
+
     #[test]
     fn test_adapt_to_detection() {
         let mut evader = DetectionEvader::new(5);
@@ -227,4 +565,84 @@ mod tests {
         let strategy = evader.generate_strategy();
         assert!(strategy.feature_scrambling_intensity > 0);
     }
+
+    #[test]
+    fn test_strategy_scales_with_level() {
+        let mut evader = DetectionEvader::new(5);
+        let mut previous = evader.generate_strategy();
+        assert_eq!(previous.feature_scrambling_intensity, 20);
+        assert!(!previous.behavior_randomization);
+        for _ in 0..4 {
+            evader.adapt_to_detection().unwrap();
+            let strategy = evader.generate_strategy();
+            assert!(strategy.feature_scrambling_intensity > previous.feature_scrambling_intensity);
+            assert!(strategy.swaps_per_block > previous.swaps_per_block);
+            assert!(strategy.padding_ratio > previous.padding_ratio);
+            previous = strategy;
+        }
+        assert_eq!(previous.decoy_traffic_percentage, 100);
+        assert_eq!(previous.padding_ratio, 1.0);
+
+        // No overflow at the top of the range
+        let mut evader = DetectionEvader::new(u8::MAX);
+        for _ in 0..300 {
+            evader.adapt_to_detection().unwrap();
+        }
+        let strategy = evader.generate_strategy();
+        assert_eq!(strategy.feature_scrambling_intensity, 100);
+        assert_eq!(strategy.swaps_per_block, 16);
+    }
+
+    #[test]
+    fn test_ensemble_mode() {
+        let mut evader = DetectionEvader::new(5);
+        for _ in 0..4 {
+            evader.adapt_to_detection().unwrap();
+        }
+        assert!(evader.generate_strategy().ensemble_approach);
+        let data = vec![b'a'; 1000];
+        let overheads = |evader: &DetectionEvader| -> Vec<usize> {
+            (0..200)
+                .map(|_| {
+                    let evaded = evader.evade_detection(&data).unwrap();
+                    assert_eq!(evader.reverse_evasion(&evaded).unwrap(), data);
+                    evaded.len() - data.len() - HEADER_LEN
+                })
+                .collect()
+        };
+
+        // Packets aimed at the byte and header families keep their length
+        let ensemble = overheads(&evader);
+        assert!(ensemble.contains(&0));
+
+        evader.set_ensemble_enabled(false);
+        assert!(!evader.generate_strategy().ensemble_approach);
+        let single = overheads(&evader);
+        assert!(single.iter().all(|&overhead| overhead >= 5));
+        let mean = |overheads: &[usize]| overheads.iter().sum::<usize>() as f64 / 200.0;
+        assert!(mean(&ensemble) < mean(&single));
+
+        // Below level 4 there is no ensemble either way
+        evader.set_ensemble_enabled(true);
+        evader.reset_adaptation();
+        assert!(!evader.generate_strategy().ensemble_approach);
+    }
+
+    #[test]
+    fn test_noise_grows_with_level() {
+        let data = vec![b'a'; 1000];
+        let mut evader = DetectionEvader::new(5);
+        let noise = |evader: &DetectionEvader| -> usize {
+            (0..20)
+                .map(|_| evader.evade_detection(&data).unwrap().len() - data.len())
+                .sum()
+        };
+        let low = noise(&evader);
+        for _ in 0..4 {
+            evader.adapt_to_detection().unwrap();
+        }
+        assert!(noise(&evader) > low);
+        let evaded = evader.evade_detection(&data).unwrap();
+        assert_eq!(evader.reverse_evasion(&evaded).unwrap(), data);
+    }
 }
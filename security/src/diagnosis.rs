@@ -0,0 +1,274 @@
+//! Blocking diagnosis
+//! Classifies which censorship mechanism broke a connection from its failure
+//! telemetry, since DNS poisoning, blackholing, RST injection and SNI
+//! filtering each need a different counter-strategy
+
+use crate::resolver::PoisoningVerdict;
+use crate::throttle::NetworkEvent;
+use std::time::Duration;
+
+/// TTL difference from the server's SYN-ACK beyond which a reset is taken
+/// as sent by a box on the path; routes rarely change by more mid-flow
+const TTL_SLACK: u8 = 2;
+
+/// A reset that ended the connection
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ResetInfo {
+    /// Time from sending the first data to the reset arriving
+    pub after: Duration,
+    /// IP TTL of the reset packet, if the socket layer exposes it
+    pub ttl: Option<u8>,
+}
+
+/// What was observed about a failed connection
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FailureTelemetry {
+    /// Result of cross-checking the name against an encrypted resolver
+    pub dns: Option<PoisoningVerdict>,
+    /// Round trip from SYN to SYN-ACK; `None` if no SYN-ACK came back
+    pub syn_ack_rtt: Option<Duration>,
+    /// IP TTL of the SYN-ACK
+    pub syn_ack_ttl: Option<u8>,
+    /// Whether the first data, typically the ClientHello, was sent
+    pub data_sent: bool,
+    pub reset: Option<ResetInfo>,
+    /// Bytes received from the server before the failure
+    pub bytes_received: u64,
+    /// Outcome of a control connection to the same address presenting a
+    /// harmless SNI, if one was made
+    pub control_succeeded: Option<bool>,
+}
+
+/// Censorship mechanism behind a failure
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Mechanism {
+    /// The resolver answered with a block-page or bogus address
+    DnsPoisoning,
+    /// Packets to the address are dropped, from the SYN or after it
+    IpBlackhole,
+    /// A box on the path forged a TCP reset
+    RstInjection,
+    /// The connection stalls once the ClientHello names the destination
+    SniFilter,
+    /// Nothing points at the network; the server or the route failed
+    Inconclusive,
+}
+
+/// The response that defeats a mechanism
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Countermeasure {
+    /// Resolve through DoH, DoT or DoQ instead of the system resolver
+    EncryptedDns,
+    /// Reach the destination through another address: a CDN front or relay
+    Relay,
+    /// Desync the DPI's flow state so it never matches the real handshake
+    Desync,
+    /// Keep the name out of the ClientHello: fake SNI, SNI split or ECH
+    SniEvasion,
+    /// Retry as is
+    Retry,
+}
+
+impl Mechanism {
+    pub fn countermeasure(self) -> Countermeasure {
+        match self {
+            Mechanism::DnsPoisoning => Countermeasure::EncryptedDns,
+            Mechanism::IpBlackhole => Countermeasure::Relay,
+            Mechanism::RstInjection => Countermeasure::Desync,
+            Mechanism::SniFilter => Countermeasure::SniEvasion,
+            Mechanism::Inconclusive => Countermeasure::Retry,
+        }
+    }
+
+    /// The event to report to `SecurityProcessor::report_network_event`,
+    /// for mechanisms that detected the connection itself
+    pub fn network_event(self) -> Option<NetworkEvent> {
+        match self {
+            Mechanism::RstInjection => Some(NetworkEvent::ConnReset),
+            Mechanism::SniFilter => Some(NetworkEvent::Timeout),
+            Mechanism::DnsPoisoning | Mechanism::IpBlackhole | Mechanism::Inconclusive => None,
+        }
+    }
+}
+
+/// Verdict on a failed connection
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnosis {
+    pub mechanism: Mechanism,
+    /// The evidence the verdict rests on
+    pub detail: String,
+}
+
+impl Diagnosis {
+    pub fn countermeasure(&self) -> Countermeasure {
+        self.mechanism.countermeasure()
+    }
+}
+
+/// Classify the mechanism behind a failed connection
+pub fn diagnose(telemetry: &FailureTelemetry) -> Diagnosis {
+    let verdict = |mechanism, detail: String| Diagnosis { mechanism, detail };
+
+    if let Some(dns) = telemetry.dns.filter(PoisoningVerdict::is_poisoned) {
+        return verdict(
+            Mechanism::DnsPoisoning,
+            format!("system resolver answer is {:?}", dns),
+        );
+    }
+
+    let Some(rtt) = telemetry.syn_ack_rtt else {
+        return verdict(Mechanism::IpBlackhole, "no SYN-ACK".to_string());
+    };
+    if !telemetry.data_sent {
+        return verdict(
+            Mechanism::Inconclusive,
+            "failed before sending data".to_string(),
+        );
+    }
+
+    if let Some(reset) = telemetry.reset {
+        let ttl_gap = reset
+            .ttl
+            .zip(telemetry.syn_ack_ttl)
+            .map(|(reset, server)| reset.abs_diff(server));
+        if let Some(gap) = ttl_gap.filter(|gap| *gap > TTL_SLACK) {
+            return verdict(
+                Mechanism::RstInjection,
+                format!("reset TTL differs from the server's by {}", gap),
+            );
+        }
+        if reset.after < rtt / 2 {
+            return verdict(
+                Mechanism::RstInjection,
+                format!(
+                    "reset after {:?}, faster than the server can answer ({:?} RTT)",
+                    reset.after, rtt
+                ),
+            );
+        }
+        if telemetry.bytes_received == 0 && telemetry.control_succeeded == Some(true) {
+            return verdict(
+                Mechanism::RstInjection,
+                "reset only when the real SNI is presented".to_string(),
+            );
+        }
+        return verdict(
+            Mechanism::Inconclusive,
+            "reset consistent with the server".to_string(),
+        );
+    }
+
+    if telemetry.bytes_received > 0 {
+        return verdict(
+            Mechanism::Inconclusive,
+            format!("stalled after {} bytes", telemetry.bytes_received),
+        );
+    }
+    match telemetry.control_succeeded {
+        Some(false) => verdict(
+            Mechanism::IpBlackhole,
+            "handshake stalls whatever the SNI".to_string(),
+        ),
+        _ => verdict(
+            Mechanism::SniFilter,
+            "no answer to the ClientHello after a clean TCP handshake".to_string(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connected() -> FailureTelemetry {
+        FailureTelemetry {
+            syn_ack_rtt: Some(Duration::from_millis(80)),
+            syn_ack_ttl: Some(52),
+            data_sent: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_dns_and_blackhole() {
+        let poisoned = FailureTelemetry {
+            dns: Some(PoisoningVerdict::Injected),
+            ..connected()
+        };
+        assert_eq!(diagnose(&poisoned).mechanism, Mechanism::DnsPoisoning);
+        assert_eq!(
+            diagnose(&poisoned).countermeasure(),
+            Countermeasure::EncryptedDns
+        );
+
+        let consistent = FailureTelemetry {
+            dns: Some(PoisoningVerdict::Consistent),
+            syn_ack_rtt: None,
+            ..Default::default()
+        };
+        assert_eq!(diagnose(&consistent).mechanism, Mechanism::IpBlackhole);
+    }
+
+    #[test]
+    fn test_injected_resets() {
+        let ttl = FailureTelemetry {
+            reset: Some(ResetInfo {
+                after: Duration::from_millis(90),
+                ttl: Some(61),
+            }),
+            ..connected()
+        };
+        assert_eq!(diagnose(&ttl).mechanism, Mechanism::RstInjection);
+
+        let early = FailureTelemetry {
+            reset: Some(ResetInfo {
+                after: Duration::from_millis(12),
+                ttl: None,
+            }),
+            ..connected()
+        };
+        let diagnosis = diagnose(&early);
+        assert_eq!(diagnosis.mechanism, Mechanism::RstInjection);
+        assert_eq!(diagnosis.countermeasure(), Countermeasure::Desync);
+        assert_eq!(
+            diagnosis.mechanism.network_event(),
+            Some(NetworkEvent::ConnReset)
+        );
+
+        let server = FailureTelemetry {
+            reset: Some(ResetInfo {
+                after: Duration::from_millis(85),
+                ttl: Some(52),
+            }),
+            ..connected()
+        };
+        assert_eq!(diagnose(&server).mechanism, Mechanism::Inconclusive);
+        let sni_triggered = FailureTelemetry {
+            control_succeeded: Some(true),
+            ..server
+        };
+        assert_eq!(diagnose(&sni_triggered).mechanism, Mechanism::RstInjection);
+    }
+
+    #[test]
+    fn test_stalled_handshake() {
+        let stalled = connected();
+        assert_eq!(diagnose(&stalled).mechanism, Mechanism::SniFilter);
+        assert_eq!(
+            diagnose(&stalled).countermeasure(),
+            Countermeasure::SniEvasion
+        );
+
+        let control_failed = FailureTelemetry {
+            control_succeeded: Some(false),
+            ..connected()
+        };
+        assert_eq!(diagnose(&control_failed).mechanism, Mechanism::IpBlackhole);
+
+        let midstream = FailureTelemetry {
+            bytes_received: 4000,
+            ..connected()
+        };
+        assert_eq!(diagnose(&midstream).mechanism, Mechanism::Inconclusive);
+    }
+}
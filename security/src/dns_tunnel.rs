@@ -0,0 +1,730 @@
+//! DNS tunneling transport
+//! Carries data upstream as base32 in the QNAME labels of ordinary queries
+//! for a delegated zone, and downstream in TXT or NULL answers, so it gets
+//! through wherever the recursive resolver can reach the zone's server
+
+use crate::config::DnsTunnelSettings;
+use crate::error::{Error, Result};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+
+pub(crate) const DNS_HEADER_LEN: usize = 12;
+/// Longest name on the wire, length bytes and root included
+const MAX_NAME_LEN: usize = 255;
+pub(crate) const MAX_LABEL_LEN: usize = 63;
+const MAX_CHARACTER_STRING: usize = 255;
+
+pub(crate) const FLAG_QR: u16 = 0x8000;
+const FLAG_AA: u16 = 0x0400;
+pub(crate) const FLAG_RD: u16 = 0x0100;
+pub(crate) const RCODE_MASK: u16 = 0x000f;
+
+pub(crate) const CLASS_IN: u16 = 1;
+const TYPE_NULL: u16 = 10;
+const TYPE_TXT: u16 = 16;
+pub(crate) const TYPE_OPT: u16 = 41;
+/// EDNS0 buffer size from the DNS flag day 2020 recommendation
+pub(crate) const EDNS_UDP_SIZE: u16 = 1232;
+
+/// Session, sequence and flags ahead of upstream data
+const UPSTREAM_HEADER_LEN: usize = 5;
+/// Sequence and flags ahead of downstream data
+const DOWNSTREAM_HEADER_LEN: usize = 3;
+/// Chunk ends a message
+const CHUNK_LAST: u8 = 0x01;
+/// Server has more data queued; poll again right away
+const CHUNK_MORE: u8 = 0x02;
+
+/// Answers kept per session for re-sending to retried queries
+const RESPONSE_CACHE_LEN: usize = 32;
+
+const BASE32_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// Unpadded lowercase base32 (RFC 4648 alphabet), valid in hostnames
+pub fn base32_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(5) * 8);
+    let mut buffer = 0u16;
+    let mut bits = 0;
+    for &byte in data {
+        buffer = (buffer << 8) | byte as u16;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    out
+}
+
+/// Decode `base32_encode` output, ignoring case since resolvers may change it
+pub fn base32_decode(text: &str) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(text.len() * 5 / 8);
+    let mut buffer = 0u16;
+    let mut bits = 0;
+    for c in text.bytes() {
+        let value = match c.to_ascii_lowercase() {
+            c @ b'a'..=b'z' => c - b'a',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return Err(Error::DataError(format!("Invalid base32 byte {:#x}", c))),
+        };
+        buffer = (buffer << 5) | value as u16;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Ok(out)
+}
+
+/// Answer record type carrying downstream data
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DnsRecordType {
+    /// Passes almost every resolver
+    #[default]
+    Txt,
+    /// Less overhead, but some resolvers refuse it
+    Null,
+}
+
+impl DnsRecordType {
+    fn code(self) -> u16 {
+        match self {
+            DnsRecordType::Txt => TYPE_TXT,
+            DnsRecordType::Null => TYPE_NULL,
+        }
+    }
+}
+
+/// Configuration for the DNS tunnel
+#[derive(Clone, Debug)]
+pub struct DnsTunnelConfig {
+    /// Zone delegated to the tunnel server
+    pub domain: String,
+    pub record_type: DnsRecordType,
+    /// Recursive resolver the client queries
+    pub resolver: SocketAddr,
+    pub query_timeout: Duration,
+    /// Re-sends of an unanswered query; the server answers repeats from cache
+    pub retries: u32,
+    /// Wait between empty polls while the server has nothing queued
+    pub poll_interval: Duration,
+    /// Downstream bytes the server puts in one answer
+    pub max_response_payload: usize,
+}
+
+impl Default for DnsTunnelConfig {
+    fn default() -> Self {
+        DnsTunnelConfig {
+            domain: String::new(),
+            record_type: DnsRecordType::Txt,
+            resolver: SocketAddr::from(([1, 1, 1, 1], 53)),
+            query_timeout: Duration::from_secs(2),
+            retries: 2,
+            poll_interval: Duration::from_millis(200),
+            max_response_payload: 900,
+        }
+    }
+}
+
+impl DnsTunnelConfig {
+    /// Build from the DPI bypass settings
+    pub fn from_settings(settings: &DnsTunnelSettings) -> Result<Self> {
+        let mut config = DnsTunnelConfig {
+            domain: settings.domain.clone(),
+            record_type: settings.record_type,
+            ..Default::default()
+        };
+        if let Some(resolver) = &settings.resolver {
+            config.resolver = resolver.parse().map_err(|_| {
+                Error::ConfigError(format!("Invalid DNS tunnel resolver '{}'", resolver))
+            })?;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        let labels = domain_labels(&self.domain);
+        if labels.is_empty() || labels.iter().any(|l| l.len() > MAX_LABEL_LEN) {
+            return Err(Error::ConfigError(format!(
+                "Invalid DNS tunnel domain '{}'",
+                self.domain
+            )));
+        }
+        if upstream_capacity(&self.domain) <= UPSTREAM_HEADER_LEN {
+            return Err(Error::ConfigError(format!(
+                "DNS tunnel domain '{}' leaves no room for data",
+                self.domain
+            )));
+        }
+        Ok(())
+    }
+}
+
+fn domain_labels(domain: &str) -> Vec<&str> {
+    domain
+        .trim_end_matches('.')
+        .split('.')
+        .filter(|l| !l.is_empty())
+        .collect()
+}
+
+/// Bytes (header included) one query name under `domain` can carry
+fn upstream_capacity(domain: &str) -> usize {
+    let domain_wire: usize = domain_labels(domain)
+        .iter()
+        .map(|l| l.len() + 1)
+        .sum::<usize>()
+        + 1;
+    let available = MAX_NAME_LEN.saturating_sub(domain_wire);
+    // Each full label costs 64 bytes on the wire for 63 characters
+    let mut chars = available - available.div_ceil(MAX_LABEL_LEN + 1);
+    while chars + chars.div_ceil(MAX_LABEL_LEN) > available {
+        chars -= 1;
+    }
+    chars * 5 / 8
+}
+
+pub(crate) fn write_name(packet: &mut Vec<u8>, labels: &[&str]) {
+    for label in labels {
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+}
+
+/// Read a possibly compressed name starting at `offset`; returns its labels
+/// and the offset just past it
+pub(crate) fn read_name(packet: &[u8], mut offset: usize) -> Option<(Vec<String>, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    for _ in 0..128 {
+        let len = *packet.get(offset)? as usize;
+        if len == 0 {
+            return Some((labels, end.unwrap_or(offset + 1)));
+        }
+        if len & 0xc0 == 0xc0 {
+            let pointer = u16::from_be_bytes([len as u8, *packet.get(offset + 1)?]) & 0x3fff;
+            end.get_or_insert(offset + 2);
+            offset = pointer as usize;
+            continue;
+        }
+        let label = packet.get(offset + 1..offset + 1 + len)?;
+        labels.push(String::from_utf8_lossy(label).into_owned());
+        offset += 1 + len;
+    }
+    None
+}
+
+pub(crate) fn read_u16(packet: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([
+        *packet.get(offset)?,
+        *packet.get(offset + 1)?,
+    ]))
+}
+
+/// Query carrying `chunk` (header included) as QNAME labels under `domain`
+fn build_query(id: u16, chunk: &[u8], domain: &str, record_type: DnsRecordType) -> Vec<u8> {
+    let encoded = base32_encode(chunk);
+    let mut labels: Vec<&str> = encoded
+        .as_bytes()
+        .chunks(MAX_LABEL_LEN)
+        .map(|l| std::str::from_utf8(l).unwrap())
+        .collect();
+    labels.extend(domain_labels(domain));
+
+    let mut packet = Vec::with_capacity(DNS_HEADER_LEN + MAX_NAME_LEN + 16);
+    packet.extend_from_slice(&id.to_be_bytes());
+    packet.extend_from_slice(&FLAG_RD.to_be_bytes());
+    packet.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 1]); // one question, one OPT
+    write_name(&mut packet, &labels);
+    packet.extend_from_slice(&record_type.code().to_be_bytes());
+    packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+    // EDNS0 OPT pseudo-record so answers may exceed 512 bytes
+    packet.push(0);
+    packet.extend_from_slice(&TYPE_OPT.to_be_bytes());
+    packet.extend_from_slice(&EDNS_UDP_SIZE.to_be_bytes());
+    packet.extend_from_slice(&[0, 0, 0, 0, 0, 0]); // TTL fields, empty rdata
+    packet
+}
+
+/// Tunnel query as parsed by the server
+struct TunnelQuery {
+    id: u16,
+    flags: u16,
+    qtype: u16,
+    /// Raw question section, echoed in the answer
+    question: Vec<u8>,
+    chunk: Vec<u8>,
+}
+
+fn parse_query(packet: &[u8], domain: &[&str]) -> Option<TunnelQuery> {
+    if packet.len() < DNS_HEADER_LEN {
+        return None;
+    }
+    let flags = read_u16(packet, 2)?;
+    if flags & FLAG_QR != 0 || read_u16(packet, 4)? == 0 {
+        return None;
+    }
+    let (labels, end) = read_name(packet, DNS_HEADER_LEN)?;
+    let qtype = read_u16(packet, end)?;
+    if labels.len() <= domain.len() {
+        return None;
+    }
+    let (data, zone) = labels.split_at(labels.len() - domain.len());
+    if !zone
+        .iter()
+        .zip(domain)
+        .all(|(a, b)| a.eq_ignore_ascii_case(b))
+    {
+        return None;
+    }
+    Some(TunnelQuery {
+        id: read_u16(packet, 0)?,
+        flags,
+        qtype,
+        question: packet.get(DNS_HEADER_LEN..end + 4)?.to_vec(),
+        chunk: base32_decode(&data.concat()).ok()?,
+    })
+}
+
+fn build_response(query: &TunnelQuery, payload: &[u8]) -> Vec<u8> {
+    let rdata = if query.qtype == TYPE_NULL {
+        payload.to_vec()
+    } else {
+        let mut rdata = Vec::with_capacity(payload.len() + payload.len() / 255 + 1);
+        for string in payload.chunks(MAX_CHARACTER_STRING) {
+            rdata.push(string.len() as u8);
+            rdata.extend_from_slice(string);
+        }
+        rdata
+    };
+
+    let mut packet = Vec::with_capacity(DNS_HEADER_LEN + query.question.len() + 12 + rdata.len());
+    packet.extend_from_slice(&query.id.to_be_bytes());
+    packet.extend_from_slice(&(FLAG_QR | FLAG_AA | (query.flags & FLAG_RD)).to_be_bytes());
+    packet.extend_from_slice(&[0, 1, 0, 1, 0, 0, 0, 0]);
+    packet.extend_from_slice(&query.question);
+    packet.extend_from_slice(&[0xc0, DNS_HEADER_LEN as u8]); // pointer to the question name
+    packet.extend_from_slice(&query.qtype.to_be_bytes());
+    packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+    packet.extend_from_slice(&0u32.to_be_bytes()); // TTL 0 keeps answers out of caches
+    packet.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+    packet.extend(rdata);
+    packet
+}
+
+/// Downstream payload from the answer to query `id`
+fn parse_response(packet: &[u8], id: u16) -> Result<Option<Vec<u8>>> {
+    let malformed = || Error::DataError("Malformed DNS tunnel response".to_string());
+    if packet.len() < DNS_HEADER_LEN || read_u16(packet, 0) != Some(id) {
+        return Ok(None);
+    }
+    let flags = read_u16(packet, 2).ok_or_else(malformed)?;
+    if flags & FLAG_QR == 0 {
+        return Ok(None);
+    }
+    if flags & RCODE_MASK != 0 {
+        return Err(Error::DataError(format!(
+            "DNS tunnel query failed with rcode {}",
+            flags & RCODE_MASK
+        )));
+    }
+
+    let questions = read_u16(packet, 4).ok_or_else(malformed)?;
+    let answers = read_u16(packet, 6).ok_or_else(malformed)?;
+    let mut offset = DNS_HEADER_LEN;
+    for _ in 0..questions {
+        offset = read_name(packet, offset).ok_or_else(malformed)?.1 + 4;
+    }
+    for _ in 0..answers {
+        offset = read_name(packet, offset).ok_or_else(malformed)?.1;
+        let rtype = read_u16(packet, offset).ok_or_else(malformed)?;
+        let rdlen = read_u16(packet, offset + 8).ok_or_else(malformed)? as usize;
+        let rdata = packet
+            .get(offset + 10..offset + 10 + rdlen)
+            .ok_or_else(malformed)?;
+        offset += 10 + rdlen;
+        match rtype {
+            TYPE_NULL => return Ok(Some(rdata.to_vec())),
+            TYPE_TXT => {
+                let mut payload = Vec::with_capacity(rdata.len());
+                let mut rest = rdata;
+                while let Some((&len, tail)) = rest.split_first() {
+                    let string = tail.get(..len as usize).ok_or_else(malformed)?;
+                    payload.extend_from_slice(string);
+                    rest = &tail[len as usize..];
+                }
+                return Ok(Some(payload));
+            }
+            _ => {}
+        }
+    }
+    Err(malformed())
+}
+
+/// Whether `seq` comes before `next` in wrapping 16-bit order
+fn is_before(seq: u16, next: u16) -> bool {
+    next.wrapping_sub(seq).wrapping_sub(1) < 0x8000
+}
+
+/// DNS tunnel client talking to the zone's server through a resolver
+pub struct DnsTunnelClient {
+    socket: UdpSocket,
+    config: DnsTunnelConfig,
+    session: u16,
+    seq: u16,
+    down_seq: u16,
+    down_message: Vec<u8>,
+    ready: VecDeque<Vec<u8>>,
+}
+
+impl DnsTunnelClient {
+    /// Open a session through the configured resolver
+    pub async fn connect(config: DnsTunnelConfig) -> Result<Self> {
+        config.validate()?;
+        let local = if config.resolver.is_ipv6() {
+            SocketAddr::from(([0u16; 8], 0))
+        } else {
+            SocketAddr::from(([0u8; 4], 0))
+        };
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(config.resolver).await?;
+        Ok(DnsTunnelClient {
+            socket,
+            config,
+            session: rand::thread_rng().gen(),
+            seq: 0,
+            down_seq: 0,
+            down_message: Vec::new(),
+            ready: VecDeque::new(),
+        })
+    }
+
+    /// Session identifier the server files this client under
+    pub fn session(&self) -> u16 {
+        self.session
+    }
+
+    /// Send one message, as many queries as it takes
+    pub async fn send(&mut self, data: &[u8]) -> Result<()> {
+        if data.is_empty() {
+            return Err(Error::DataError("Empty DNS tunnel message".to_string()));
+        }
+        let per_query = upstream_capacity(&self.config.domain) - UPSTREAM_HEADER_LEN;
+        let chunks: Vec<&[u8]> = data.chunks(per_query).collect();
+        for (i, chunk) in chunks.iter().enumerate() {
+            let flags = if i + 1 == chunks.len() { CHUNK_LAST } else { 0 };
+            self.exchange(flags, chunk).await?;
+        }
+        Ok(())
+    }
+
+    /// Receive the next message from the server, polling until `timeout`
+    pub async fn recv(&mut self, timeout: Duration) -> Result<Vec<u8>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(message) = self.ready.pop_front() {
+                return Ok(message);
+            }
+            if Instant::now() >= deadline {
+                return Err(Error::IoError(std::io::ErrorKind::TimedOut.into()));
+            }
+            if !self.exchange(0, &[]).await? && self.ready.is_empty() {
+                tokio::time::sleep(self.config.poll_interval).await;
+            }
+        }
+    }
+
+    /// Send one query and file the answer; true if the server has more queued
+    async fn exchange(&mut self, flags: u8, data: &[u8]) -> Result<bool> {
+        let mut chunk = Vec::with_capacity(UPSTREAM_HEADER_LEN + data.len());
+        chunk.extend_from_slice(&self.session.to_be_bytes());
+        chunk.extend_from_slice(&self.seq.to_be_bytes());
+        chunk.push(flags);
+        chunk.extend_from_slice(data);
+        self.seq = self.seq.wrapping_add(1);
+
+        let id: u16 = rand::thread_rng().gen();
+        let query = build_query(id, &chunk, &self.config.domain, self.config.record_type);
+        let mut buf = vec![0u8; 4096];
+        for _ in 0..=self.config.retries {
+            self.socket.send(&query).await?;
+            let deadline = tokio::time::Instant::now() + self.config.query_timeout;
+            while let Ok(received) =
+                tokio::time::timeout_at(deadline, self.socket.recv(&mut buf)).await
+            {
+                let len = received?;
+                if let Some(payload) = parse_response(&buf[..len], id)? {
+                    return self.file_downstream(&payload);
+                }
+            }
+        }
+        Err(Error::IoError(std::io::ErrorKind::TimedOut.into()))
+    }
+
+    fn file_downstream(&mut self, payload: &[u8]) -> Result<bool> {
+        if payload.len() < DOWNSTREAM_HEADER_LEN {
+            return Err(Error::DataError("Truncated DNS tunnel answer".to_string()));
+        }
+        let seq = u16::from_be_bytes([payload[0], payload[1]]);
+        let flags = payload[2];
+        let data = &payload[DOWNSTREAM_HEADER_LEN..];
+        let carries_data = !data.is_empty() || flags & CHUNK_LAST != 0;
+        if carries_data && seq == self.down_seq {
+            self.down_seq = self.down_seq.wrapping_add(1);
+            self.down_message.extend_from_slice(data);
+            if flags & CHUNK_LAST != 0 {
+                self.ready.push_back(std::mem::take(&mut self.down_message));
+            }
+        }
+        Ok(flags & CHUNK_MORE != 0)
+    }
+}
+
+/// Per-client state on the server
+struct ServerSession {
+    next_seq: u16,
+    pending: BTreeMap<u16, (u8, Vec<u8>)>,
+    message: Vec<u8>,
+    outgoing: VecDeque<Vec<u8>>,
+    /// Bytes of the front outgoing message already sent
+    sent: usize,
+    down_seq: u16,
+    responses: VecDeque<(u16, Vec<u8>)>,
+    last_seen: Instant,
+}
+
+impl ServerSession {
+    fn new() -> Self {
+        ServerSession {
+            next_seq: 0,
+            pending: BTreeMap::new(),
+            message: Vec::new(),
+            outgoing: VecDeque::new(),
+            sent: 0,
+            down_seq: 0,
+            responses: VecDeque::new(),
+            last_seen: Instant::now(),
+        }
+    }
+
+    /// Next downstream payload, at most `max` data bytes
+    fn next_payload(&mut self, max: usize) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(DOWNSTREAM_HEADER_LEN + max);
+        let Some(message) = self.outgoing.front() else {
+            payload.extend_from_slice(&self.down_seq.to_be_bytes());
+            payload.push(0);
+            return payload;
+        };
+
+        let end = (self.sent + max).min(message.len());
+        let data = message[self.sent..end].to_vec();
+        let mut flags = 0;
+        if end == message.len() {
+            flags |= CHUNK_LAST;
+            self.outgoing.pop_front();
+            self.sent = 0;
+        } else {
+            self.sent = end;
+        }
+        if !self.outgoing.is_empty() {
+            flags |= CHUNK_MORE;
+        }
+
+        payload.extend_from_slice(&self.down_seq.to_be_bytes());
+        payload.push(flags);
+        payload.extend(data);
+        self.down_seq = self.down_seq.wrapping_add(1);
+        payload
+    }
+}
+
+/// Authoritative-side tunnel endpoint, independent of how packets arrive
+///
+/// Feed it every query for the zone with `handle_query` and send back what
+/// it returns; completed client messages come out of `recv`.
+pub struct DnsTunnelServer {
+    config: DnsTunnelConfig,
+    sessions: HashMap<u16, ServerSession>,
+    ready: VecDeque<(u16, Vec<u8>)>,
+}
+
+impl DnsTunnelServer {
+    pub fn new(config: DnsTunnelConfig) -> Result<Self> {
+        config.validate()?;
+        Ok(DnsTunnelServer {
+            config,
+            sessions: HashMap::new(),
+            ready: VecDeque::new(),
+        })
+    }
+
+    /// Answer one query; None for packets that aren't tunnel queries
+    pub fn handle_query(&mut self, packet: &[u8]) -> Option<Vec<u8>> {
+        let domain = domain_labels(&self.config.domain);
+        let query = parse_query(packet, &domain)?;
+        if query.chunk.len() < UPSTREAM_HEADER_LEN {
+            return None;
+        }
+        let session_id = u16::from_be_bytes([query.chunk[0], query.chunk[1]]);
+        let seq = u16::from_be_bytes([query.chunk[2], query.chunk[3]]);
+        let flags = query.chunk[4];
+        let data = query.chunk[UPSTREAM_HEADER_LEN..].to_vec();
+
+        let session = self
+            .sessions
+            .entry(session_id)
+            .or_insert_with(ServerSession::new);
+        session.last_seen = Instant::now();
+
+        // A resolver retrying a query gets the answer it may have lost
+        if is_before(seq, session.next_seq) || session.pending.contains_key(&seq) {
+            let cached = session
+                .responses
+                .iter()
+                .find(|(s, _)| *s == seq)
+                .map(|(_, payload)| payload.clone());
+            return cached.map(|payload| build_response(&query, &payload));
+        }
+
+        session.pending.insert(seq, (flags, data));
+        while let Some((flags, data)) = session.pending.remove(&session.next_seq) {
+            session.next_seq = session.next_seq.wrapping_add(1);
+            session.message.extend(data);
+            if flags & CHUNK_LAST != 0 {
+                self.ready
+                    .push_back((session_id, std::mem::take(&mut session.message)));
+            }
+        }
+
+        let payload = session.next_payload(self.config.max_response_payload);
+        session.responses.push_back((seq, payload.clone()));
+        if session.responses.len() > RESPONSE_CACHE_LEN {
+            session.responses.pop_front();
+        }
+        Some(build_response(&query, &payload))
+    }
+
+    /// Next completed message and the session that sent it
+    pub fn recv(&mut self) -> Option<(u16, Vec<u8>)> {
+        self.ready.pop_front()
+    }
+
+    /// Queue a message for a session; it goes out in answers to its polls
+    pub fn send(&mut self, session: u16, data: &[u8]) -> Result<()> {
+        if data.is_empty() {
+            return Err(Error::DataError("Empty DNS tunnel message".to_string()));
+        }
+        self.sessions
+            .get_mut(&session)
+            .ok_or_else(|| Error::DataError(format!("Unknown DNS tunnel session {}", session)))?
+            .outgoing
+            .push_back(data.to_vec());
+        Ok(())
+    }
+
+    /// Forget sessions silent for longer than `max_idle`
+    pub fn expire_idle(&mut self, max_idle: Duration) {
+        self.sessions
+            .retain(|_, session| session.last_seen.elapsed() < max_idle);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(resolver: SocketAddr) -> DnsTunnelConfig {
+        DnsTunnelConfig {
+            domain: "t.example.com".to_string(),
+            resolver,
+            query_timeout: Duration::from_millis(500),
+            poll_interval: Duration::from_millis(10),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_base32_round_trip() {
+        assert_eq!(base32_encode(b"foobar"), "mzxw6ytboi");
+        for len in 0..20 {
+            let data: Vec<u8> = (0..len).map(|i| (i * 37) as u8).collect();
+            let encoded = base32_encode(&data);
+            assert_eq!(base32_decode(&encoded).unwrap(), data);
+            assert_eq!(base32_decode(&encoded.to_uppercase()).unwrap(), data);
+        }
+        assert!(base32_decode("not-base32").is_err());
+    }
+
+    #[test]
+    fn test_queries_are_valid_names() {
+        let domain = "t.example.com";
+        let chunk = vec![0xabu8; upstream_capacity(domain)];
+        let query = build_query(7, &chunk, domain, DnsRecordType::Txt);
+
+        let (labels, end) = read_name(&query, DNS_HEADER_LEN).unwrap();
+        assert!(end - DNS_HEADER_LEN <= MAX_NAME_LEN);
+        assert!(labels.iter().all(|l| l.len() <= MAX_LABEL_LEN));
+        assert!(labels[..labels.len() - 3].iter().all(|l| l
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit())));
+        assert_eq!(read_u16(&query, end), Some(TYPE_TXT));
+
+        let parsed = parse_query(&query, &domain_labels(domain)).unwrap();
+        assert_eq!(parsed.chunk, chunk);
+        assert!(parse_query(&query, &domain_labels("other.org")).is_none());
+    }
+
+    #[test]
+    fn test_retried_query_gets_same_answer() {
+        let mut server = DnsTunnelServer::new(config("127.0.0.1:53".parse().unwrap())).unwrap();
+        let mut chunk = vec![0, 1, 0, 0, CHUNK_LAST];
+        chunk.extend_from_slice(b"hi");
+        let query = build_query(9, &chunk, "t.example.com", DnsRecordType::Null);
+
+        let first = server.handle_query(&query).unwrap();
+        server.send(1, b"late data").unwrap();
+        assert_eq!(server.handle_query(&query).unwrap(), first);
+        assert_eq!(server.recv(), Some((1, b"hi".to_vec())));
+        assert_eq!(server.recv(), None);
+    }
+
+    #[tokio::test]
+    async fn test_client_server_exchange() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let mut server = DnsTunnelServer::new(config(addr)).unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 4096];
+            loop {
+                let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+                let response = server.handle_query(&buf[..len]).unwrap();
+                if let Some((session, message)) = server.recv() {
+                    let mut reply = message.clone();
+                    reply.reverse();
+                    server.send(session, &reply).unwrap();
+                }
+                socket.send_to(&response, peer).await.unwrap();
+            }
+        });
+
+        let mut client = DnsTunnelClient::connect(config(addr)).await.unwrap();
+        let message: Vec<u8> = (0..2000).map(|i| (i % 251) as u8).collect();
+        client.send(&message).await.unwrap();
+
+        let reply = client.recv(Duration::from_secs(5)).await.unwrap();
+        let mut expected = message;
+        expected.reverse();
+        assert_eq!(reply, expected);
+    }
+}
@@ -0,0 +1,130 @@
+//! Domain fronting
+//! Splits the SNI/Host pair: the TLS SNI names an allowed CDN front domain while
+//! the HTTP Host header inside the encrypted tunnel names the real destination
+
+use crate::client_hello::{ClientHelloBuilder, TLSProfileConfig};
+use crate::config::{DomainFrontingConfig, FrontingPair};
+use crate::error::Result;
+use crate::obfuscation::Obfuscator;
+use rand::seq::SliceRandom;
+
+/// The SNI and Host header to use for one fronted connection
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FrontedRoute {
+    pub sni: String,
+    pub host_header: String,
+}
+
+/// Domain fronting engine
+pub struct DomainFronter {
+    config: DomainFrontingConfig,
+}
+
+impl DomainFronter {
+    /// Create a fronter from configuration
+    pub fn new(config: DomainFrontingConfig) -> Self {
+        DomainFronter { config }
+    }
+
+    /// Check whether fronting is enabled and has any pairs
+    pub fn is_active(&self) -> bool {
+        self.config.enabled && !self.config.pairs.is_empty()
+    }
+
+    /// Fronting pairs configured for a destination
+    fn pairs_for(&self, destination: &str) -> Vec<&FrontingPair> {
+        self.config
+            .pairs
+            .iter()
+            .filter(|pair| pair.real_host.eq_ignore_ascii_case(destination))
+            .collect()
+    }
+
+    /// Pick the front/real pair for a destination
+    ///
+    /// When several fronts serve the same destination one is chosen at random
+    /// so a single front domain doesn't accumulate all of the traffic.
+    pub fn route(&self, destination: &str) -> Option<FrontedRoute> {
+        if !self.config.enabled {
+            return None;
+        }
+
+        let pairs = self.pairs_for(destination);
+        let pair = pairs.choose(&mut rand::thread_rng())?;
+        Some(FrontedRoute {
+            sni: pair.front_domain.to_ascii_lowercase(),
+            host_header: pair.real_host.clone(),
+        })
+    }
+
+    /// Build the ClientHello for a fronted connection (SNI = front domain)
+    pub fn client_hello(&self, route: &FrontedRoute, profile: &TLSProfileConfig) -> Vec<u8> {
+        ClientHelloBuilder::from_profile(profile)
+            .server_name(&route.sni)
+            .build()
+    }
+
+    /// Wrap the inner request so its Host header names the real destination
+    pub fn wrap_request(
+        &self,
+        route: &FrontedRoute,
+        obfuscator: &Obfuscator,
+        data: &[u8],
+    ) -> Result<Vec<u8>> {
+        obfuscator.obfuscate_for_host(data, &route.host_header)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client_hello::parse_client_hello;
+
+    fn fronting_config() -> DomainFrontingConfig {
+        DomainFrontingConfig {
+            enabled: true,
+            pairs: vec![FrontingPair {
+                front_domain: "cdn.front.example".to_string(),
+                real_host: "hidden.example.org".to_string(),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_route_lookup() {
+        let fronter = DomainFronter::new(fronting_config());
+        let route = fronter.route("Hidden.Example.org").unwrap();
+        assert_eq!(route.sni, "cdn.front.example");
+        assert_eq!(route.host_header, "hidden.example.org");
+
+        assert!(fronter.route("other.example.org").is_none());
+    }
+
+    #[test]
+    fn test_disabled_fronting() {
+        let mut config = fronting_config();
+        config.enabled = false;
+        let fronter = DomainFronter::new(config);
+        assert!(!fronter.is_active());
+        assert!(fronter.route("hidden.example.org").is_none());
+    }
+
+    #[test]
+    fn test_sni_and_host_are_split() {
+        let fronter = DomainFronter::new(fronting_config());
+        let route = fronter.route("hidden.example.org").unwrap();
+
+        let hello = fronter.client_hello(&route, &TLSProfileConfig::default());
+        let parsed = parse_client_hello(&hello).unwrap();
+        assert_eq!(parsed.server_name.as_deref(), Some("cdn.front.example"));
+
+        let request = fronter
+            .wrap_request(&route, &Obfuscator::new(), b"payload")
+            .unwrap();
+        let host_line = b"host: hidden.example.org\r\n";
+        assert!(request
+            .to_ascii_lowercase()
+            .windows(host_line.len())
+            .any(|w| w == host_line));
+    }
+}
@@ -1,33 +1,227 @@
 //! DPI bypass module for Deep Packet Inspection evasion
 //! Implements various techniques to bypass DPI detection
 
+use crate::client_hello::{ClientHelloBuilder, TLSProfileConfig};
 use crate::error::{Error, Result};
+use crate::http_evasion::HttpEvasion;
+use crate::mtu::PathMtu;
+use crate::scheduler::PacketScheduler;
+use crate::sni_pool::SniPool;
+use crate::timing::{TimingGenerator, TimingModel};
+use crate::tls_fragmentation::FragmentedPacket;
+use parking_lot::Mutex;
 use rand::Rng;
+use std::sync::Arc;
+
+/// How a decoy packet is kept from reaching the server while still being
+/// seen by on-path DPI
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DesyncMethod {
+    /// IP TTL low enough to expire between the DPI box and the server
+    LowTtl(u8),
+    /// Invalid TCP checksum, dropped by the server's stack but not by most DPI
+    BadChecksum,
+}
+
+/// A crafted packet to inject ahead of the real data
+#[derive(Clone, Debug)]
+pub struct DecoyPacket {
+    pub data: Vec<u8>,
+    pub method: DesyncMethod,
+}
+
+/// Hook for sending packets outside the normal socket path
+///
+/// Implementations inject the payload into the connection's flow with the
+/// same addresses, ports and sequence number as the next real segment,
+/// without advancing the kernel's sequence number, so the real segment that
+/// follows replaces the decoy for the server.
+pub trait RawSender: Send + Sync {
+    fn send_decoy(&self, packet: &DecoyPacket) -> Result<()>;
+}
+
+/// Configuration for the decoy ClientHello desync
+#[derive(Clone, Debug)]
+pub struct DecoyDesyncConfig {
+    /// SNI for the decoy; drawn from the built-in fake-SNI pool when unset
+    pub fake_sni: Option<String>,
+    pub method: DesyncMethod,
+    /// Decoys sent before the real ClientHello
+    pub repeats: u8,
+    /// Profile the decoy ClientHello is built from
+    pub profile: TLSProfileConfig,
+}
+
+impl Default for DecoyDesyncConfig {
+    fn default() -> Self {
+        DecoyDesyncConfig {
+            fake_sni: None,
+            method: DesyncMethod::LowTtl(3),
+            repeats: 1,
+            profile: TLSProfileConfig::default(),
+        }
+    }
+}
+
+/// Which bypass strategy is applied to the connection's ClientHello
+#[derive(Clone, Debug, Default)]
+pub enum BypassStrategy {
+    /// Send the ClientHello as is
+    #[default]
+    Standard,
+    /// Send fake ClientHellos for an allowed SNI that DPI sees but the
+    /// server never gets, poisoning the DPI's flow state before the real one
+    DecoyClientHello(DecoyDesyncConfig),
+}
 
-pub struct DPIBypass;
+/// Separates the chunks `fragmentation_evasion` cuts the data into
+const FRAGMENT_MARKER: u8 = 0xFF;
+
+/// First payload lengths filtered outright: WireGuard handshake initiation
+/// and response
+pub const BLOCKED_FIRST_LENGTHS: [usize; 2] = [148, 92];
+
+/// Whether a connection's first payload of `len` bytes would be dropped
+pub fn is_blocked_first_length(len: usize) -> bool {
+    BLOCKED_FIRST_LENGTHS.contains(&len)
+}
+
+pub struct DPIBypass {
+    strategy: BypassStrategy,
+    raw_sender: Option<Arc<dyn RawSender>>,
+    path_mtu: Option<PathMtu>,
+    http_evasion: Option<HttpEvasion>,
+    timing: Mutex<TimingGenerator>,
+    scheduler: Mutex<Box<dyn PacketScheduler>>,
+}
 
 impl DPIBypass {
     pub fn new() -> Self {
-        DPIBypass
+        DPIBypass {
+            strategy: BypassStrategy::Standard,
+            raw_sender: None,
+            path_mtu: None,
+            http_evasion: None,
+            timing: Mutex::new(TimingGenerator::default()),
+            scheduler: Mutex::new(Box::new(TimingGenerator::default())),
+        }
+    }
+
+    /// Create a bypass engine using a ClientHello strategy
+    pub fn with_strategy(strategy: BypassStrategy) -> Self {
+        DPIBypass {
+            strategy,
+            raw_sender: None,
+            path_mtu: None,
+            http_evasion: None,
+            timing: Mutex::new(TimingGenerator::default()),
+            scheduler: Mutex::new(Box::new(TimingGenerator::default())),
+        }
+    }
+
+    /// Size records to fit the path MTU, configured or from `PathMtu::discover`
+    pub fn set_path_mtu(&mut self, mtu: PathMtu) {
+        self.path_mtu = Some(mtu);
+    }
+
+    /// Install the hook used to inject decoy packets
+    pub fn set_raw_sender(&mut self, sender: Arc<dyn RawSender>) {
+        self.raw_sender = Some(sender);
+    }
+
+    /// Install the header rewrites used for plain-HTTP requests
+    pub fn set_http_evasion(&mut self, evasion: HttpEvasion) {
+        self.http_evasion = Some(evasion);
+    }
+
+    /// Draw packet timing from `model` instead of the browsing default
+    pub fn set_timing_model(&mut self, model: TimingModel) {
+        self.timing = Mutex::new(TimingGenerator::new(model.clone()));
+        self.scheduler = Mutex::new(Box::new(TimingGenerator::new(model)));
+    }
+
+    /// Decide send times with `scheduler` instead of the timing model
+    pub fn set_scheduler(&mut self, scheduler: Box<dyn PacketScheduler>) {
+        self.scheduler = Mutex::new(scheduler);
+    }
+
+    /// Get the active ClientHello strategy
+    pub fn strategy(&self) -> &BypassStrategy {
+        &self.strategy
+    }
+
+    /// Run the ClientHello strategy, returning the bytes to write to the socket
+    ///
+    /// With `DecoyClientHello` the decoys are injected through the raw-send
+    /// hook first; the real ClientHello is returned unchanged.
+    pub fn prepare_client_hello(&self, client_hello: &[u8]) -> Result<Vec<u8>> {
+        if let BypassStrategy::DecoyClientHello(config) = &self.strategy {
+            let sender = self.raw_sender.as_ref().ok_or_else(|| {
+                Error::DPIBypassError("Decoy desync needs a raw-send hook".to_string())
+            })?;
+            for decoy in Self::decoy_client_hellos(config)? {
+                sender.send_decoy(&decoy)?;
+            }
+        }
+
+        Ok(client_hello.to_vec())
+    }
+
+    /// Run the HTTP evasion stage on a plain-HTTP request, returning the
+    /// segments to write; without the stage the request is one segment
+    pub fn prepare_http_request(&self, request: &[u8]) -> Vec<FragmentedPacket> {
+        match &self.http_evasion {
+            Some(evasion) => evasion.prepare(request),
+            None => vec![FragmentedPacket {
+                data: request.to_vec(),
+                delay_ms: 0,
+            }],
+        }
+    }
+
+    /// Build the decoy ClientHellos for a desync config
+    pub fn decoy_client_hellos(config: &DecoyDesyncConfig) -> Result<Vec<DecoyPacket>> {
+        let fake_sni = match &config.fake_sni {
+            Some(sni) => sni.clone(),
+            None => SniPool::builtin().choose().ok_or_else(|| {
+                Error::DPIBypassError("Fake-SNI pool is empty".to_string())
+            })?,
+        };
+
+        let builder = ClientHelloBuilder::from_profile(&config.profile).server_name(&fake_sni);
+        Ok((0..config.repeats.max(1))
+            .map(|_| DecoyPacket {
+                data: builder.build(),
+                method: config.method,
+            })
+            .collect())
     }
 
     /// Apply DPI evasion techniques
+    ///
+    /// The output never has one of the `BLOCKED_FIRST_LENGTHS`, so it can
+    /// open a connection.
     pub fn apply_evasion(&self, data: &[u8]) -> Result<Vec<u8>> {
-        // Apply multiple evasion techniques in sequence
-        let data = self.fragmentation_evasion(data)?;
-        let data = self.tls_evasion(&data)?;
-        let data = self.dns_evasion(&data)?;
-
-        Ok(data)
+        let record_size = self.record_size();
+        let mut data = self.fragmentation_evasion(data)?;
+        // An empty chunk at the end moves the length off a blocked one;
+        // `join_fragments` drops it like any other boundary
+        while is_blocked_first_length(data.len() + 5 * data.len().div_ceil(record_size)) {
+            data.extend_from_slice(&[FRAGMENT_MARKER, 0x00]);
+        }
+        self.tls_evasion(&data, record_size)
     }
 
     /// Reverse DPI evasion
     pub fn reverse_evasion(&self, data: &[u8]) -> Result<Vec<u8>> {
-        // In a real implementation, this would reverse the evasion techniques
-        Ok(data.to_vec())
+        let data = Self::strip_tls_records(data)?;
+        Self::join_fragments(&data)
     }
 
     /// Packet fragmentation to avoid DPI signatures
+    ///
+    /// Chunks are separated by `FRAGMENT_MARKER, 0x00`; a literal
+    /// `FRAGMENT_MARKER` byte in the data is doubled.
     fn fragmentation_evasion(&self, data: &[u8]) -> Result<Vec<u8>> {
         let mut rng = rand::thread_rng();
         let mut result = Vec::new();
@@ -40,29 +234,58 @@ impl DPIBypass {
 
             // Add small random delay indicator between chunks
             if offset > 0 {
-                result.push(0xFF); // Fragment boundary marker
+                result.extend_from_slice(&[FRAGMENT_MARKER, 0x00]);
             }
 
-            result.extend_from_slice(&data[offset..end]);
+            for &byte in &data[offset..end] {
+                result.push(byte);
+                if byte == FRAGMENT_MARKER {
+                    result.push(FRAGMENT_MARKER);
+                }
+            }
             offset = end;
         }
 
         Ok(result)
     }
 
-    /// TLS handshake fragmentation and randomization
-    fn tls_evasion(&self, data: &[u8]) -> Result<Vec<u8>> {
-        let mut rng = rand::thread_rng();
-
-        if data.len() < 100 {
-            return Ok(data.to_vec());
+    /// Remove the boundary markers `fragmentation_evasion` added
+    fn join_fragments(data: &[u8]) -> Result<Vec<u8>> {
+        let mut result = Vec::with_capacity(data.len());
+        let mut bytes = data.iter();
+        while let Some(&byte) = bytes.next() {
+            if byte != FRAGMENT_MARKER {
+                result.push(byte);
+                continue;
+            }
+            match bytes.next() {
+                Some(&FRAGMENT_MARKER) => result.push(FRAGMENT_MARKER),
+                Some(0x00) => {}
+                _ => {
+                    return Err(Error::DPIBypassError(
+                        "Invalid fragment boundary marker".to_string(),
+                    ))
+                }
+            }
         }
+        Ok(result)
+    }
 
-        let mut result = Vec::new();
-
+    /// Payload size of the records `tls_evasion` frames the next packet in
+    fn record_size(&self) -> usize {
         // Simulate TLS record level fragmentation
         // TLS records are typically split across packets
-        let record_size = rng.gen_range(512..2048);
+        let record_size = rand::thread_rng().gen_range(512..2048);
+        match &self.path_mtu {
+            // Header and payload of each record share one segment
+            Some(mtu) => record_size.min(mtu.max_segment_payload() - 5),
+            None => record_size,
+        }
+    }
+
+    /// TLS handshake fragmentation and randomization
+    fn tls_evasion(&self, data: &[u8], record_size: usize) -> Result<Vec<u8>> {
+        let mut result = Vec::new();
 
         for chunk in data.chunks(record_size) {
             // Add TLS record header simulation
@@ -81,26 +304,22 @@ impl DPIBypass {
         Ok(result)
     }
 
-    /// DNS tunneling evasion
-    fn dns_evasion(&self, data: &[u8]) -> Result<Vec<u8>> {
-        // DNS queries use specific port 53 and structure
-        // This can bypass certain DPI rules that look for standard VPN patterns
-
-        let mut result = Vec::new();
-
-        // Add DNS header simulation
-        result.push(0x00); // Transaction ID (high)
-        result.push(0x01);
-        result.push(0x01); // Standard query
-        result.push(0x00);
-        result.push(0x00); // Questions: 0
-        result.push(0x01);
-        result.push(0x00); // Answer RRs: 0
-        result.push(0x00);
-
-        // Add actual data
-        result.extend_from_slice(data);
-
+    /// Unwrap the records `tls_evasion` framed the data in
+    fn strip_tls_records(data: &[u8]) -> Result<Vec<u8>> {
+        let mut result = Vec::with_capacity(data.len());
+        let mut offset = 0;
+        while offset < data.len() {
+            let header = data
+                .get(offset..offset + 5)
+                .filter(|header| header[..3] == [0x17, 0x03, 0x03])
+                .ok_or_else(|| Error::DPIBypassError("Invalid record header".to_string()))?;
+            let len = u16::from_be_bytes([header[3], header[4]]) as usize;
+            let body = data
+                .get(offset + 5..offset + 5 + len)
+                .ok_or_else(|| Error::DPIBypassError("Truncated record".to_string()))?;
+            result.extend_from_slice(body);
+            offset += 5 + len;
+        }
         Ok(result)
     }
 
@@ -117,15 +336,21 @@ impl DPIBypass {
         Ok(result)
     }
 
-    /// Timing attack prevention - randomize packet timing
+    /// Timing attack prevention - next burst from the timing model
     pub fn randomize_timing(&self) -> TimingStrategy {
-        let mut rng = rand::thread_rng();
+        self.timing.lock().strategy()
+    }
 
-        TimingStrategy {
-            inter_packet_delay_ms: rng.gen_range(10..500),
-            burst_size: rng.gen_range(1..10),
-            burst_delay_ms: rng.gen_range(100..2000),
-        }
+    /// Draw the scheduler's delay before the next packet of `len` bytes
+    pub fn next_delay(&self, len: usize) -> std::time::Duration {
+        self.scheduler.lock().next_delay(len)
+    }
+
+    /// Wait the scheduler's delay before the next packet of `len` bytes;
+    /// await before each write on an async send path
+    pub async fn pace(&self, len: usize) {
+        let delay = self.next_delay(len);
+        tokio::time::sleep(delay).await;
     }
 
     /// Implement time-based transformation
@@ -183,10 +408,130 @@ mod tests {
         assert!(result.len() >= test_data.len());
     }
 
+    #[test]
+    fn test_reverse_evasion() {
+        let bypass = DPIBypass::new();
+        for data in [vec![], vec![0xFF; 300], (0..=255).cycle().take(5000).collect()] {
+            let evaded = bypass.apply_evasion(&data).unwrap();
+            assert_eq!(bypass.reverse_evasion(&evaded).unwrap(), data);
+        }
+        assert!(bypass.reverse_evasion(b"\x17\x03\x03\x00\x02\xFF\x01").is_err());
+        assert!(bypass.reverse_evasion(b"not records").is_err());
+    }
+
+    #[test]
+    fn test_output_avoids_blocked_first_lengths() {
+        let bypass = DPIBypass::new();
+        for len in 0..200 {
+            let data = vec![0x42; len];
+            for _ in 0..20 {
+                let evaded = bypass.apply_evasion(&data).unwrap();
+                assert!(!is_blocked_first_length(evaded.len()), "{} bytes", len);
+                assert_eq!(bypass.reverse_evasion(&evaded).unwrap(), data);
+            }
+        }
+    }
+
+    #[test]
+    fn test_http_evasion_stage() {
+        use crate::config::HttpEvasionConfig;
+        use crate::http_evasion::HttpSplit;
+
+        let request = b"GET / HTTP/1.1\r\nHost: blocked.example\r\n\r\n";
+        let mut bypass = DPIBypass::new();
+        assert_eq!(bypass.prepare_http_request(request).len(), 1);
+
+        bypass.set_http_evasion(HttpEvasion::new(HttpEvasionConfig {
+            enabled: true,
+            split: HttpSplit::HostHeader,
+            ..Default::default()
+        }));
+        let packets = bypass.prepare_http_request(request);
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0].data, b"GET / HTTP/1.1\r\n");
+    }
+
+    struct RecordingSender(parking_lot::Mutex<Vec<DecoyPacket>>);
+
+    impl RawSender for RecordingSender {
+        fn send_decoy(&self, packet: &DecoyPacket) -> Result<()> {
+            self.0.lock().push(packet.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_decoy_client_hello_desync() {
+        use crate::client_hello::parse_client_hello;
+
+        let mut bypass = DPIBypass::with_strategy(BypassStrategy::DecoyClientHello(
+            DecoyDesyncConfig {
+                fake_sni: Some("allowed.example".to_string()),
+                repeats: 2,
+                ..Default::default()
+            },
+        ));
+        let real = ClientHelloBuilder::from_profile(&TLSProfileConfig::default())
+            .server_name("blocked.example")
+            .build();
+
+        // Without a hook the strategy can't run
+        assert!(bypass.prepare_client_hello(&real).is_err());
+
+        let sender = Arc::new(RecordingSender(parking_lot::Mutex::new(Vec::new())));
+        bypass.set_raw_sender(sender.clone());
+        assert_eq!(bypass.prepare_client_hello(&real).unwrap(), real);
+
+        let decoys = sender.0.lock();
+        assert_eq!(decoys.len(), 2);
+        for decoy in decoys.iter() {
+            assert_eq!(decoy.method, DesyncMethod::LowTtl(3));
+            let hello = parse_client_hello(&decoy.data).unwrap();
+            assert_eq!(hello.server_name.as_deref(), Some("allowed.example"));
+        }
+    }
+
+    #[test]
+    fn test_tls_records_fit_path_mtu() {
+        let mut bypass = DPIBypass::new();
+        bypass.set_path_mtu(PathMtu::new(576, false));
+        let limit = PathMtu::new(576, false).max_segment_payload();
+
+        let data = bypass.apply_evasion(&[0xAB; 5000]).unwrap();
+        let mut offset = 0;
+        while offset < data.len() {
+            let len = u16::from_be_bytes([data[offset + 3], data[offset + 4]]) as usize;
+            assert!(5 + len <= limit);
+            offset += 5 + len;
+        }
+    }
+
     #[test]
     fn test_randomize_timing() {
         let bypass = DPIBypass::new();
         let strategy = bypass.randomize_timing();
         assert!(strategy.inter_packet_delay_ms > 0);
     }
+
+    #[tokio::test]
+    async fn test_timing_model_paces_sends() {
+        use crate::scheduler::ConstantRate;
+        use crate::timing::TimingState;
+        use std::time::{Duration, Instant};
+
+        let mut bypass = DPIBypass::new();
+        let steady = TimingState::new("steady", Duration::from_millis(20), 0.0, vec![1]);
+        bypass.set_timing_model(TimingModel::new(vec![steady], Duration::from_secs(1)).unwrap());
+        assert_eq!(bypass.randomize_timing().burst_size, 64);
+
+        let start = Instant::now();
+        bypass.pace(1000).await;
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        // 1000 bytes at 20 KB/s
+        bypass.set_scheduler(Box::new(ConstantRate::new(20_000).unwrap()));
+        let start = Instant::now();
+        bypass.pace(1000).await;
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
 }
@@ -0,0 +1,413 @@
+//! DPI simulator
+//! A stateless per-packet inspector applying the filtering rules seen on
+//! Iranian networks, for tests asserting that pipeline output evades them
+
+use crate::client_hello::parse_client_hello;
+use crate::dpi_bypass::BLOCKED_FIRST_LENGTHS;
+use crate::entropy::shannon_entropy;
+use crate::fingerprint;
+
+/// Shortest first packet judged by its entropy
+const MIN_ENTROPY_SAMPLE: usize = 64;
+
+/// A filtering rule of the simulator
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Rule {
+    /// Blocked hostname in a ClientHello's SNI or anywhere in a handshake
+    /// packet
+    Sni,
+    /// JA3 of the ClientHello on the blocklist
+    Fingerprint,
+    /// First packet is high-entropy bytes of no recognized protocol, the
+    /// signature of fully encrypted proxies
+    Entropy,
+    /// First payload length of a known VPN handshake, or a run of equal
+    /// packet sizes as fixed-cell protocols produce
+    PacketSize,
+}
+
+/// A rule that fired
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Detection {
+    pub rule: Rule,
+    /// Index of the packet that triggered it
+    pub packet: usize,
+    pub detail: String,
+}
+
+/// Rules of the simulator
+#[derive(Clone, Debug)]
+pub struct DpiSimConfig {
+    /// Hostnames blocked with their subdomains
+    pub blocked_domains: Vec<String>,
+    /// JA3 hashes of blocked TLS clients
+    pub blocked_ja3: Vec<String>,
+    /// Share of the highest entropy a first packet of its length can reach
+    /// above which it counts as fully encrypted
+    pub entropy_ratio: f64,
+    /// First payload lengths that are blocked outright
+    pub blocked_first_lengths: Vec<usize>,
+    /// Consecutive packets of one size that flag a flow
+    pub uniform_run: usize,
+    /// Smallest packet counted towards a uniform run; short control
+    /// packets often repeat in real traffic
+    pub uniform_min_len: usize,
+}
+
+impl Default for DpiSimConfig {
+    fn default() -> Self {
+        DpiSimConfig {
+            blocked_domains: [
+                "youtube.com",
+                "twitter.com",
+                "x.com",
+                "instagram.com",
+                "facebook.com",
+                "telegram.org",
+                "whatsapp.com",
+            ]
+            .iter()
+            .map(|domain| domain.to_string())
+            .collect(),
+            blocked_ja3: Vec::new(),
+            entropy_ratio: 0.85,
+            blocked_first_lengths: BLOCKED_FIRST_LENGTHS.to_vec(),
+            uniform_run: 8,
+            uniform_min_len: 256,
+        }
+    }
+}
+
+/// Inspects the client-to-server packets of a flow
+///
+/// Like the deployed boxes it keeps no reassembly buffer: each packet is
+/// judged on its own bytes, so a ClientHello split across packets is
+/// neither parsed nor fingerprinted.
+pub struct DpiSim {
+    config: DpiSimConfig,
+}
+
+impl DpiSim {
+    pub fn new(config: DpiSimConfig) -> Self {
+        DpiSim { config }
+    }
+
+    pub fn config(&self) -> &DpiSimConfig {
+        &self.config
+    }
+
+    /// Run every rule over a flow, returning the first detection of each
+    pub fn inspect_flow<P: AsRef<[u8]>>(&self, packets: &[P]) -> Vec<Detection> {
+        let mut detections: Vec<Detection> = Vec::new();
+        let mut report = |detection: Detection| {
+            if !detections.iter().any(|d| d.rule == detection.rule) {
+                detections.push(detection);
+            }
+        };
+
+        let mut run = 0;
+        let mut last_len = None;
+        for (index, packet) in packets.iter().enumerate() {
+            let packet = packet.as_ref();
+            if packet.is_empty() {
+                continue;
+            }
+            if index == 0 {
+                if let Some(detection) = self.first_packet(packet) {
+                    report(detection);
+                }
+            }
+            for detection in self.handshake(index, packet) {
+                report(detection);
+            }
+
+            if packet.len() >= self.config.uniform_min_len && last_len == Some(packet.len()) {
+                run += 1;
+            } else {
+                run = 1;
+            }
+            last_len = Some(packet.len());
+            if self.config.uniform_run > 1 && run == self.config.uniform_run {
+                report(Detection {
+                    rule: Rule::PacketSize,
+                    packet: index,
+                    detail: format!("{} packets of {} bytes in a row", run, packet.len()),
+                });
+            }
+        }
+        detections
+    }
+
+    fn first_packet(&self, packet: &[u8]) -> Option<Detection> {
+        if self.config.blocked_first_lengths.contains(&packet.len()) {
+            return Some(Detection {
+                rule: Rule::PacketSize,
+                packet: 0,
+                detail: format!("first payload of {} bytes", packet.len()),
+            });
+        }
+        if packet.len() < MIN_ENTROPY_SAMPLE || recognized(packet) {
+            return None;
+        }
+        let ceiling = (packet.len().min(256) as f64).log2();
+        let entropy = shannon_entropy(packet);
+        (entropy > ceiling * self.config.entropy_ratio).then(|| Detection {
+            rule: Rule::Entropy,
+            packet: 0,
+            detail: format!("{:.2} bits per byte of unknown protocol", entropy),
+        })
+    }
+
+    /// SNI and fingerprint rules for a packet starting a TLS handshake record
+    fn handshake(&self, index: usize, packet: &[u8]) -> Vec<Detection> {
+        let mut detections = Vec::new();
+        if packet[0] != 0x16 {
+            return detections;
+        }
+        match parse_client_hello(packet) {
+            Ok(hello) => {
+                if let Some(name) = hello.server_name.filter(|name| self.blocked(name)) {
+                    detections.push(Detection {
+                        rule: Rule::Sni,
+                        packet: index,
+                        detail: format!("SNI {}", name),
+                    });
+                }
+                if let Ok(ja3) = fingerprint::ja3(packet) {
+                    if self.config.blocked_ja3.contains(&ja3) {
+                        detections.push(Detection {
+                            rule: Rule::Fingerprint,
+                            packet: index,
+                            detail: format!("JA3 {}", ja3),
+                        });
+                    }
+                }
+            }
+            // A ClientHello cut short: fall back to a keyword match
+            Err(_) => {
+                if let Some(domain) = self
+                    .config
+                    .blocked_domains
+                    .iter()
+                    .find(|domain| contains(packet, domain.as_bytes()))
+                {
+                    detections.push(Detection {
+                        rule: Rule::Sni,
+                        packet: index,
+                        detail: format!("keyword {}", domain),
+                    });
+                }
+            }
+        }
+        detections
+    }
+
+    fn blocked(&self, name: &str) -> bool {
+        let name = name.to_ascii_lowercase();
+        self.config.blocked_domains.iter().any(|domain| {
+            name == *domain
+                || name
+                    .strip_suffix(domain.as_str())
+                    .is_some_and(|prefix| prefix.ends_with('.'))
+        })
+    }
+}
+
+impl Default for DpiSim {
+    fn default() -> Self {
+        Self::new(DpiSimConfig::default())
+    }
+}
+
+/// Starts like a protocol the filter knows: TLS, HTTP or SSH
+fn recognized(packet: &[u8]) -> bool {
+    const PREFIXES: [&[u8]; 9] = [
+        b"GET ",
+        b"POST ",
+        b"PUT ",
+        b"HEAD ",
+        b"DELETE ",
+        b"OPTIONS ",
+        b"CONNECT ",
+        b"HTTP/",
+        b"SSH-",
+    ];
+    matches!(packet, [0x14..=0x17, 0x03, 0x00..=0x04, ..])
+        || PREFIXES.iter().any(|prefix| packet.starts_with(prefix))
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cells::{CellCodec, CellMode};
+    use crate::client_hello::ClientHelloBuilder;
+    use crate::config::SecuritySettings;
+    use crate::entropy::EntropyEncoding;
+    use crate::sni_obfuscation::BrowserFingerprint;
+    use crate::tls_fragmentation::{FragmentationStrategy, TLSFragmentationConfig, TLSFragmenter};
+    use crate::{SecurityConfig, SecurityProcessor};
+    use rand::Rng;
+
+    fn rules(detections: &[Detection]) -> Vec<Rule> {
+        detections.iter().map(|d| d.rule).collect()
+    }
+
+    fn hello(browser: BrowserFingerprint, sni: &str) -> Vec<u8> {
+        ClientHelloBuilder::for_browser(browser)
+            .server_name(sni)
+            .build()
+    }
+
+    #[test]
+    fn test_sni_match_and_split_evasion() {
+        let sim = DpiSim::default();
+        let hello = hello(BrowserFingerprint::Chrome, "www.youtube.com");
+        assert_eq!(rules(&sim.inspect_flow(&[&hello])), vec![Rule::Sni]);
+        let allowed = self::hello(BrowserFingerprint::Chrome, "notyoutube.com");
+        assert!(sim.inspect_flow(&[&allowed]).is_empty());
+
+        for strategy in [
+            FragmentationStrategy::SniSplit,
+            FragmentationStrategy::RecordSplit,
+        ] {
+            let fragmenter = TLSFragmenter::with_config(TLSFragmentationConfig {
+                strategy,
+                ..Default::default()
+            });
+            let packets: Vec<Vec<u8>> = fragmenter
+                .fragment_client_hello(&hello)
+                .unwrap()
+                .into_iter()
+                .map(|packet| packet.data)
+                .collect();
+            assert!(sim.inspect_flow(&packets).is_empty(), "{:?}", strategy);
+        }
+    }
+
+    #[test]
+    fn test_fingerprint_blocklist() {
+        let chrome = hello(BrowserFingerprint::Chrome, "example.com");
+        let firefox = hello(BrowserFingerprint::Firefox, "example.com");
+        let sim = DpiSim::new(DpiSimConfig {
+            blocked_ja3: vec![fingerprint::ja3(&chrome).unwrap()],
+            ..Default::default()
+        });
+        assert_eq!(
+            rules(&sim.inspect_flow(&[&chrome])),
+            vec![Rule::Fingerprint]
+        );
+        assert!(sim.inspect_flow(&[&firefox]).is_empty());
+    }
+
+    #[test]
+    fn test_size_heuristics() {
+        let sim = DpiSim::default();
+        assert_eq!(
+            rules(&sim.inspect_flow(&[vec![1u8; 148]])),
+            vec![Rule::PacketSize]
+        );
+
+        let mut codec = CellCodec::new(512).unwrap();
+        codec.push(&[b'x'; 4000]);
+        let cells: Vec<Vec<u8>> = (0..10).map(|_| codec.next_cell()).collect();
+        assert!(rules(&sim.inspect_flow(&cells)).contains(&Rule::PacketSize));
+    }
+
+    /// Settings for every stage the processor can add to a packet
+    fn pipeline_settings() -> Vec<(String, SecuritySettings)> {
+        let mut configurations = Vec::new();
+        for enforce_obfuscation in [false, true] {
+            for enable_ai_evasion in [false, true] {
+                let config = SecurityConfig {
+                    enforce_obfuscation,
+                    enable_ai_evasion,
+                    ..Default::default()
+                };
+                configurations.push((
+                    format!(
+                        "obfuscation {} evasion {}",
+                        enforce_obfuscation, enable_ai_evasion
+                    ),
+                    config.try_into().unwrap(),
+                ));
+            }
+        }
+
+        let mut settings = SecuritySettings::default();
+        settings.obfuscation.record_padding_enabled = true;
+        configurations.push(("record padding".to_string(), settings));
+        for encoding in [EntropyEncoding::Base64, EntropyEncoding::Html] {
+            let mut settings = SecuritySettings::default();
+            settings.obfuscation.entropy_encoding = encoding;
+            configurations.push((format!("{:?} encoding", encoding), settings));
+        }
+        for mode in [CellMode::Small, CellMode::Large] {
+            let mut settings = SecuritySettings::default();
+            settings.obfuscation.cell_mode = mode;
+            configurations.push((format!("{:?} cells", mode), settings));
+        }
+        configurations
+    }
+
+    #[test]
+    fn test_pipeline_configurations_evade_every_rule() {
+        let sim = DpiSim::default();
+        let mut rng = rand::thread_rng();
+        let mut random = vec![0u8; 1200];
+        rng.fill(&mut random[..]);
+        assert_eq!(rules(&sim.inspect_flow(&[&random])), vec![Rule::Entropy]);
+
+        let text = b"GET /feed?user=42 HTTP/1.1\r\nHost: www.instagram.com\r\n\r\n".repeat(8);
+        for (name, settings) in pipeline_settings() {
+            for strategy in [
+                FragmentationStrategy::Random,
+                FragmentationStrategy::SniSplit,
+                FragmentationStrategy::RecordSplit,
+            ] {
+                let mut settings = settings.clone();
+                settings.tls_fragmentation.strategy = strategy;
+                let processor = SecurityProcessor::with_settings(settings).unwrap();
+
+                // Random cuts make no attempt to hide the name
+                let host = match strategy {
+                    FragmentationStrategy::Random => "www.example.com",
+                    _ => "www.youtube.com",
+                };
+                let packets: Vec<Vec<u8>> = processor
+                    .process_client_hello(&hello(BrowserFingerprint::Chrome, host))
+                    .unwrap()
+                    .into_iter()
+                    .map(|packet| packet.data)
+                    .collect();
+                let detections = sim.inspect_flow(&packets);
+                assert!(
+                    detections.is_empty(),
+                    "{} {:?}: {:?}",
+                    name,
+                    strategy,
+                    detections
+                );
+
+                for payload in [&random[..], &text[..]] {
+                    let flow: Vec<Vec<u8>> = (0..20)
+                        .map(|_| processor.process_outgoing(payload).unwrap())
+                        .collect();
+                    let detections = sim.inspect_flow(&flow);
+                    assert!(
+                        detections.is_empty(),
+                        "{} {:?}: {:?}",
+                        name,
+                        strategy,
+                        detections
+                    );
+                }
+            }
+        }
+    }
+}
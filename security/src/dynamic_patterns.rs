@@ -2,8 +2,10 @@
 // Rotates protocol signatures, TCP parameters, and connection patterns
 // to evade fingerprinting-based DPI systems and AI-based detection
 
+use crate::fingerprint::{BrowserProfile, FingerprintDb};
 use rand::Rng;
 use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::sync::Mutex;
 use std::time::{Duration, Instant};
@@ -16,6 +18,28 @@ pub struct SessionParameters {
     pub ttl: u8,
     pub initial_rtt_ms: u32,
     pub packet_timing_variance: u32,
+    /// Used instead of `ttl`/`tcp_mss` when the connection runs over IPv6
+    pub ipv6: Ipv6Parameters,
+}
+
+/// IPv6 counterparts of the TTL and MSS knobs, plus the flow label
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Ipv6Parameters {
+    pub hop_limit: u8,
+    /// 20-bit flow label; 0 leaves it to the kernel
+    pub flow_label: u32,
+    /// MSS for the 40-byte IPv6 header, e.g. 1440 on a 1500-byte link
+    pub tcp_mss: u16,
+}
+
+impl Default for Ipv6Parameters {
+    fn default() -> Self {
+        Ipv6Parameters {
+            hop_limit: 64,
+            flow_label: 0,
+            tcp_mss: 1440,
+        }
+    }
 }
 
 /// Hourly rotation patterns for signature evasion
@@ -37,10 +61,13 @@ pub struct SessionState {
     pub last_rotation: Instant,
     pub rotation_count: u32,
     pub pattern_profile: String,
+    /// Browser fingerprint the session presents, when fingerprint rotation is on
+    pub fingerprint: Option<String>,
 }
 
 /// Configuration for pattern rotation behavior
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct PatternRotationConfig {
     pub rotation_interval_hours: u32,
     pub enable_hourly_patterns: bool,
@@ -54,6 +81,13 @@ pub struct PatternRotationConfig {
     pub max_ttl: u8,
     pub min_rtt_ms: u32,
     pub max_rtt_ms: u32,
+    /// Rotate among browser fingerprints each rotation interval and take TCP
+    /// window, MSS and TTL from the fingerprint instead of random ranges
+    pub fingerprint_rotation: bool,
+    pub randomize_hop_limit: bool,
+    pub min_hop_limit: u8,
+    pub max_hop_limit: u8,
+    pub randomize_flow_label: bool,
 }
 
 impl Default for PatternRotationConfig {
@@ -71,6 +105,11 @@ impl Default for PatternRotationConfig {
             max_ttl: 128,
             min_rtt_ms: 10,
             max_rtt_ms: 500,
+            fingerprint_rotation: false,
+            randomize_hop_limit: true,
+            min_hop_limit: 32,
+            max_hop_limit: 128,
+            randomize_flow_label: true,
         }
     }
 }
@@ -80,6 +119,7 @@ pub struct PatternRotator {
     config: PatternRotationConfig,
     sessions: Mutex<HashMap<String, SessionState>>,
     last_hourly_pattern: Mutex<HourlyPattern>,
+    fingerprints: FingerprintDb,
 }
 
 impl PatternRotator {
@@ -89,6 +129,7 @@ impl PatternRotator {
             config: PatternRotationConfig::default(),
             sessions: Mutex::new(HashMap::new()),
             last_hourly_pattern: Mutex::new(PatternRotator::generate_hourly_pattern()),
+            fingerprints: FingerprintDb::builtin(),
         }
     }
 
@@ -98,9 +139,70 @@ impl PatternRotator {
             config,
             sessions: Mutex::new(HashMap::new()),
             last_hourly_pattern: Mutex::new(PatternRotator::generate_hourly_pattern()),
+            fingerprints: FingerprintDb::builtin(),
         }
     }
 
+    /// Use a custom fingerprint database for rotation
+    pub fn with_fingerprint_db(mut self, fingerprints: FingerprintDb) -> Self {
+        self.fingerprints = fingerprints;
+        self
+    }
+
+    /// Get the fingerprint database
+    pub fn fingerprint_db(&self) -> &FingerprintDb {
+        &self.fingerprints
+    }
+
+    /// Get the browser fingerprint for the current rotation interval
+    ///
+    /// Every caller in the same interval gets the same fingerprint, so TLS
+    /// and TCP parameters chosen independently still describe one client.
+    pub fn current_fingerprint(&self) -> Option<BrowserProfile> {
+        let hour = self.get_current_hourly_pattern().hour;
+        let slot = hour / self.config.rotation_interval_hours.max(1);
+        self.fingerprints.select_for_slot(slot).cloned()
+    }
+
+    /// Generate parameters for a new or rotated session
+    fn generate_session_parameters(&self) -> (SessionParameters, Option<String>) {
+        let fingerprint = if self.config.fingerprint_rotation {
+            self.current_fingerprint()
+        } else {
+            None
+        };
+
+        let params = match &fingerprint {
+            Some(profile) => SessionParameters {
+                tcp_window_size: profile.tcp.window_size,
+                tcp_mss: profile.tcp.mss,
+                ttl: profile.tcp.initial_ttl,
+                initial_rtt_ms: self.generate_initial_rtt(),
+                packet_timing_variance: self.generate_packet_timing_variance(),
+                // Same stack over IPv6: the header is 20 bytes longer
+                ipv6: Ipv6Parameters {
+                    hop_limit: profile.tcp.initial_ttl,
+                    flow_label: self.generate_flow_label(),
+                    tcp_mss: profile.tcp.mss.saturating_sub(20),
+                },
+            },
+            None => SessionParameters {
+                tcp_window_size: self.generate_tcp_window(),
+                tcp_mss: self.generate_tcp_mss(),
+                ttl: self.generate_ttl(),
+                initial_rtt_ms: self.generate_initial_rtt(),
+                packet_timing_variance: self.generate_packet_timing_variance(),
+                ipv6: Ipv6Parameters {
+                    hop_limit: self.generate_hop_limit(),
+                    flow_label: self.generate_flow_label(),
+                    tcp_mss: self.generate_tcp_mss_v6(),
+                },
+            },
+        };
+
+        (params, fingerprint.map(|profile| profile.name))
+    }
+
     /// Generate random TCP window size
     fn generate_tcp_window(&self) -> u16 {
         let mut rng = rand::thread_rng();
@@ -145,6 +247,34 @@ impl PatternRotator {
         *mss_options.choose(&mut rng).unwrap_or(&1460)
     }
 
+    /// Generate random IPv6 hop limit
+    fn generate_hop_limit(&self) -> u8 {
+        let mut rng = rand::thread_rng();
+        if self.config.randomize_hop_limit {
+            rng.gen_range(self.config.min_hop_limit..=self.config.max_hop_limit)
+        } else {
+            64
+        }
+    }
+
+    /// Generate random non-zero IPv6 flow label, as stacks that set one do
+    fn generate_flow_label(&self) -> u32 {
+        let mut rng = rand::thread_rng();
+        if self.config.randomize_flow_label {
+            rng.gen_range(1..=0xFFFFF)
+        } else {
+            0
+        }
+    }
+
+    /// Generate random IPv6 TCP MSS
+    fn generate_tcp_mss_v6(&self) -> u16 {
+        let mut rng = rand::thread_rng();
+        // 1500-byte links, PPPoE, common tunnels, and the 1280 IPv6 minimum
+        let mss_options = [1220u16, 1360, 1420, 1432, 1440];
+        *mss_options.choose(&mut rng).unwrap_or(&1440)
+    }
+
     /// Generate hourly pattern for signature rotation
     fn generate_hourly_pattern() -> HourlyPattern {
         let mut rng = rand::thread_rng();
@@ -171,14 +301,8 @@ impl PatternRotator {
             return session.parameters.clone();
         }
 
-        // Create new session with random parameters
-        let params = SessionParameters {
-            tcp_window_size: self.generate_tcp_window(),
-            tcp_mss: self.generate_tcp_mss(),
-            ttl: self.generate_ttl(),
-            initial_rtt_ms: self.generate_initial_rtt(),
-            packet_timing_variance: self.generate_packet_timing_variance(),
-        };
+        // Create new session with fresh parameters
+        let (params, fingerprint) = self.generate_session_parameters();
 
         let pattern_profile = self.get_current_hourly_pattern().pattern_id.clone();
 
@@ -189,6 +313,7 @@ impl PatternRotator {
             last_rotation: Instant::now(),
             rotation_count: 0,
             pattern_profile,
+            fingerprint,
         };
 
         sessions.insert(session_id.to_string(), session);
@@ -203,15 +328,10 @@ impl PatternRotator {
         let mut sessions = self.sessions.lock().unwrap();
 
         if let Some(session) = sessions.get_mut(session_id) {
-            let new_params = SessionParameters {
-                tcp_window_size: self.generate_tcp_window(),
-                tcp_mss: self.generate_tcp_mss(),
-                ttl: self.generate_ttl(),
-                initial_rtt_ms: self.generate_initial_rtt(),
-                packet_timing_variance: self.generate_packet_timing_variance(),
-            };
+            let (new_params, fingerprint) = self.generate_session_parameters();
 
             session.parameters = new_params.clone();
+            session.fingerprint = fingerprint;
             session.last_rotation = Instant::now();
             session.rotation_count += 1;
             session.pattern_profile = self.get_current_hourly_pattern().pattern_id.clone();
@@ -430,6 +550,44 @@ mod tests {
         assert_ne!(windows_opts, linux_opts);
     }
 
+    #[test]
+    fn test_fingerprint_rotation_keeps_layers_consistent() {
+        let rotator = PatternRotator::with_config(PatternRotationConfig {
+            fingerprint_rotation: true,
+            ..Default::default()
+        });
+        let fingerprint = rotator.current_fingerprint().unwrap();
+
+        let params = rotator.get_session_parameters("fp-session");
+        assert_eq!(params.tcp_window_size, fingerprint.tcp.window_size);
+        assert_eq!(params.tcp_mss, fingerprint.tcp.mss);
+        assert_eq!(params.ttl, fingerprint.tcp.initial_ttl);
+        assert_eq!(params.ipv6.hop_limit, fingerprint.tcp.initial_ttl);
+        assert_eq!(params.ipv6.tcp_mss, fingerprint.tcp.mss - 20);
+
+        // Within one interval rotation stays on the same fingerprint
+        let rotated = rotator.rotate_session_parameters("fp-session").unwrap();
+        assert_eq!(rotated.ttl, fingerprint.tcp.initial_ttl);
+    }
+
+    #[test]
+    fn test_ipv6_parameters() {
+        let rotator = PatternRotator::new();
+        let params = rotator.get_session_parameters("v6-session");
+        assert!((32..=128).contains(&params.ipv6.hop_limit));
+        assert!((1..=0xFFFFF).contains(&params.ipv6.flow_label));
+        assert!(params.ipv6.tcp_mss <= 1440);
+
+        let fixed = PatternRotator::with_config(PatternRotationConfig {
+            randomize_hop_limit: false,
+            randomize_flow_label: false,
+            ..Default::default()
+        });
+        let params = fixed.get_session_parameters("v6-fixed");
+        assert_eq!(params.ipv6.hop_limit, 64);
+        assert_eq!(params.ipv6.flow_label, 0);
+    }
+
     #[test]
     fn test_signature_mask() {
         let rotator = PatternRotator::new();
@@ -439,6 +597,7 @@ mod tests {
         // Masks should exist
         assert!(mask1.payload_padding_ratio >= 0.0);
         assert!(mask1.payload_padding_ratio <= 0.3);
+        assert!(mask2.payload_padding_ratio <= 0.3);
     }
 
     #[test]
@@ -0,0 +1,325 @@
+//! eBPF packet mangling backend
+//! Attaches the egress TC program in `bpf/tc_mangle.bpf.c` and drives its
+//! config map, so TTL rewriting and window randomization run in-kernel
+//!
+//! The program is loaded with `tc` and its map is written with `bpftool`.
+
+use crate::dynamic_patterns::SessionParameters;
+use crate::error::{Error, Result};
+use crate::firewall::{CommandRunner, RuleCommand};
+use std::path::PathBuf;
+
+/// Where tc pins maps declared with `LIBBPF_PIN_BY_NAME`
+const PIN_DIR: &str = "/sys/fs/bpf/tc/globals";
+const CONFIG_MAP: &str = "mangle_config";
+const PROGRAM_SECTION: &str = "tc";
+/// Priority of the program's filter, so detaching removes only it
+const FILTER_PRIORITY: u16 = 49152;
+
+const FLAG_REWRITE_TTL: u8 = 0x01;
+const FLAG_RANDOM_WINDOW: u8 = 0x02;
+
+/// Settings for the program's config map; `to_bytes` yields `struct mangle_config`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MangleConfig {
+    /// TTL written into selected packets
+    pub ttl: Option<u8>,
+    /// Window randomized into this range
+    pub window: Option<(u16, u16)>,
+    /// Only touch packets to this port
+    pub dst_port: Option<u16>,
+    /// Only touch packets carrying this fwmark
+    pub mark: Option<u32>,
+}
+
+impl MangleConfig {
+    /// Mangle to a session's TTL and a window range around its window size
+    pub fn from_session(params: &SessionParameters, dst_port: Option<u16>) -> Self {
+        let spread = params.tcp_window_size / 8;
+        MangleConfig {
+            ttl: Some(params.ttl),
+            window: Some((
+                params.tcp_window_size - spread,
+                params.tcp_window_size.saturating_add(spread),
+            )),
+            dst_port,
+            mark: None,
+        }
+    }
+
+    /// Encode as the map value, in host byte order like the kernel expects
+    pub fn to_bytes(&self) -> [u8; 16] {
+        let mut flags = 0;
+        if self.ttl.is_some() {
+            flags |= FLAG_REWRITE_TTL;
+        }
+        if self.window.is_some() {
+            flags |= FLAG_RANDOM_WINDOW;
+        }
+        let (window_min, window_max) = self.window.unwrap_or_default();
+
+        let mut bytes = [0u8; 16];
+        bytes[0] = flags;
+        bytes[1] = self.ttl.unwrap_or(0);
+        bytes[2..4].copy_from_slice(&self.dst_port.unwrap_or(0).to_ne_bytes());
+        bytes[4..8].copy_from_slice(&self.mark.unwrap_or(0).to_ne_bytes());
+        bytes[8..10].copy_from_slice(&window_min.to_ne_bytes());
+        bytes[10..12].copy_from_slice(&window_max.to_ne_bytes());
+        bytes
+    }
+}
+
+/// Whether `tc qdisc add` failed because the qdisc already exists
+fn is_exists_error(error: &Error) -> bool {
+    let message = error.to_string();
+    message.contains("File exists") || message.contains("Exclusivity flag on")
+}
+
+fn hex_args(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Egress TC attachment of the mangling program on one interface
+pub struct EbpfBackend {
+    interface: String,
+    object: PathBuf,
+    runner: Box<dyn CommandRunner>,
+    attached: bool,
+    /// The clsact qdisc was added by `attach`, not found in place
+    owns_qdisc: bool,
+}
+
+impl EbpfBackend {
+    /// Prepare to attach the compiled program `object` to `interface`
+    pub fn new(interface: &str, object: PathBuf, runner: Box<dyn CommandRunner>) -> Self {
+        EbpfBackend {
+            interface: interface.to_string(),
+            object,
+            runner,
+            attached: false,
+            owns_qdisc: false,
+        }
+    }
+
+    /// Commands attaching the program
+    pub fn attach_commands(&self) -> Vec<RuleCommand> {
+        vec![self.qdisc_add_command(), self.filter_add_command()]
+    }
+
+    fn qdisc_add_command(&self) -> RuleCommand {
+        RuleCommand::new("tc", &format!("qdisc add dev {} clsact", self.interface))
+    }
+
+    fn filter_add_command(&self) -> RuleCommand {
+        RuleCommand::new(
+            "tc",
+            &format!(
+                "filter add dev {} egress pref {} bpf direct-action object-file {} section {}",
+                self.interface,
+                FILTER_PRIORITY,
+                self.object.display(),
+                PROGRAM_SECTION
+            ),
+        )
+    }
+
+    /// Commands detaching the program and removing its pinned map, and
+    /// the clsact qdisc if `attach` added it
+    pub fn detach_commands(&self) -> Vec<RuleCommand> {
+        let mut commands = vec![RuleCommand::new(
+            "tc",
+            &format!(
+                "filter del dev {} egress pref {}",
+                self.interface, FILTER_PRIORITY
+            ),
+        )];
+        if self.owns_qdisc {
+            commands.push(RuleCommand::new(
+                "tc",
+                &format!("qdisc del dev {} clsact", self.interface),
+            ));
+        }
+        commands.push(RuleCommand::new(
+            "rm",
+            &format!("-f {}/{}", PIN_DIR, CONFIG_MAP),
+        ));
+        commands
+    }
+
+    /// Command writing `config` into the program's map
+    pub fn configure_command(config: &MangleConfig) -> RuleCommand {
+        RuleCommand::new(
+            "bpftool",
+            &format!(
+                "map update pinned {}/{} key hex {} value hex {}",
+                PIN_DIR,
+                CONFIG_MAP,
+                hex_args(&0u32.to_ne_bytes()),
+                hex_args(&config.to_bytes())
+            ),
+        )
+    }
+
+    /// Attach the program, detaching again if any step fails
+    ///
+    /// A clsact qdisc already on the interface is shared: it may carry
+    /// other programs' filters, so detaching leaves it in place.
+    pub fn attach(&mut self) -> Result<()> {
+        match self.runner.run(&self.qdisc_add_command()) {
+            Ok(()) => self.owns_qdisc = true,
+            Err(e) if is_exists_error(&e) => self.owns_qdisc = false,
+            Err(e) => return Err(e),
+        }
+        self.attached = true;
+        if let Err(e) = self.runner.run(&self.filter_add_command()) {
+            let _ = self.detach();
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Update the mangling parameters of the attached program
+    pub fn configure(&self, config: &MangleConfig) -> Result<()> {
+        self.runner.run(&Self::configure_command(config))
+    }
+
+    /// Detach the program, reporting the first failure
+    pub fn detach(&mut self) -> Result<()> {
+        if !self.attached {
+            return Ok(());
+        }
+        self.attached = false;
+
+        let mut first_error = None;
+        for command in self.detach_commands() {
+            if let Err(e) = self.runner.run(&command) {
+                log::warn!("eBPF detach step failed: {}", e);
+                first_error.get_or_insert(e);
+            }
+        }
+        first_error.map_or(Ok(()), Err)
+    }
+}
+
+impl Drop for EbpfBackend {
+    fn drop(&mut self) {
+        let _ = self.detach();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use parking_lot::Mutex;
+    use std::sync::Arc;
+
+    #[derive(Clone, Default)]
+    struct Recorder {
+        log: Arc<Mutex<Vec<String>>>,
+        /// Commands containing the first string fail with the second
+        fail_on: Option<(&'static str, &'static str)>,
+    }
+
+    impl CommandRunner for Recorder {
+        fn run(&self, command: &RuleCommand) -> Result<()> {
+            let line = command.to_string();
+            self.log.lock().push(line.clone());
+            match self.fail_on {
+                Some((needle, message)) if line.contains(needle) => {
+                    Err(Error::ConfigError(message.to_string()))
+                }
+                _ => Ok(()),
+            }
+        }
+    }
+
+    fn backend(recorder: &Recorder) -> EbpfBackend {
+        EbpfBackend::new(
+            "eth0",
+            PathBuf::from("tc_mangle.bpf.o"),
+            Box::new(recorder.clone()),
+        )
+    }
+
+    #[test]
+    fn test_config_encoding() {
+        let config = MangleConfig {
+            ttl: Some(5),
+            window: Some((1000, 2000)),
+            dst_port: Some(443),
+            mark: None,
+        };
+        let bytes = config.to_bytes();
+        assert_eq!(bytes[0], FLAG_REWRITE_TTL | FLAG_RANDOM_WINDOW);
+        assert_eq!(bytes[1], 5);
+        assert_eq!(u16::from_ne_bytes([bytes[2], bytes[3]]), 443);
+        assert_eq!(u16::from_ne_bytes([bytes[10], bytes[11]]), 2000);
+        assert_eq!(MangleConfig::default().to_bytes(), [0u8; 16]);
+    }
+
+    #[test]
+    fn test_configure_command() {
+        let command = EbpfBackend::configure_command(&MangleConfig {
+            ttl: Some(0x40),
+            ..Default::default()
+        });
+        let line = command.to_string();
+        assert!(line.starts_with("bpftool map update pinned /sys/fs/bpf/tc/globals/mangle_config"));
+        assert!(line.ends_with("value hex 01 40 00 00 00 00 00 00 00 00 00 00 00 00 00 00"));
+    }
+
+    #[test]
+    fn test_failed_attach_detaches() {
+        let recorder = Recorder {
+            fail_on: Some(("filter add", "rejected")),
+            ..Default::default()
+        };
+        assert!(backend(&recorder).attach().is_err());
+        let log = recorder.log.lock();
+        assert_eq!(log[0], "tc qdisc add dev eth0 clsact");
+        assert!(log.contains(&"tc qdisc del dev eth0 clsact".to_string()));
+        assert_eq!(
+            log.last().unwrap(),
+            "rm -f /sys/fs/bpf/tc/globals/mangle_config"
+        );
+    }
+
+    #[test]
+    fn test_existing_qdisc_is_shared() {
+        let recorder = Recorder {
+            fail_on: Some((
+                "qdisc add",
+                "`tc qdisc add dev eth0 clsact` failed: RTNETLINK answers: File exists",
+            )),
+            ..Default::default()
+        };
+        let mut backend = backend(&recorder);
+        backend.attach().unwrap();
+        backend.detach().unwrap();
+        assert_eq!(
+            *recorder.log.lock(),
+            [
+                "tc qdisc add dev eth0 clsact",
+                "tc filter add dev eth0 egress pref 49152 bpf direct-action \
+                 object-file tc_mangle.bpf.o section tc",
+                "tc filter del dev eth0 egress pref 49152",
+                "rm -f /sys/fs/bpf/tc/globals/mangle_config",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_qdisc_failure_tears_nothing_down() {
+        let recorder = Recorder {
+            fail_on: Some(("qdisc add", "rejected")),
+            ..Default::default()
+        };
+        assert!(backend(&recorder).attach().is_err());
+        assert_eq!(*recorder.log.lock(), ["tc qdisc add dev eth0 clsact"]);
+    }
+}
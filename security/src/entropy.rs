@@ -0,0 +1,398 @@
+//! Entropy shaping
+//! Maps payload into a lower-entropy representation so ciphertext stops
+//! looking like uniformly random bytes: base64, or text whose byte
+//! frequencies follow HTML. Also tells payloads that are ciphertext already
+//! from plain ones
+
+use crate::error::{Error, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+/// Byte weights of typical HTML, per mille of the bytes of a page
+const HTML_FREQUENCIES: &[(u8, u32)] = &[
+    (b' ', 120),
+    (b'e', 62),
+    (b't', 48),
+    (b'a', 45),
+    (b'o', 40),
+    (b'i', 40),
+    (b'n', 38),
+    (b's', 36),
+    (b'r', 35),
+    (b'l', 25),
+    (b'c', 24),
+    (b'd', 22),
+    (b'h', 20),
+    (b'<', 18),
+    (b'>', 18),
+    (b'"', 17),
+    (b'=', 15),
+    (b'p', 15),
+    (b'u', 14),
+    (b'm', 14),
+    (b'/', 14),
+    (b'-', 12),
+    (b'f', 11),
+    (b'g', 10),
+    (b'\n', 10),
+    (b'v', 9),
+    (b'b', 8),
+    (b'y', 8),
+    (b'w', 8),
+    (b'.', 8),
+    (b'k', 5),
+    (b'x', 4),
+    (b':', 4),
+    (b'_', 3),
+    (b'0', 3),
+    (b'1', 3),
+    (b'2', 3),
+    (b'3', 2),
+    (b'4', 2),
+    (b'5', 2),
+    (b'6', 2),
+    (b'7', 2),
+    (b'8', 2),
+    (b'9', 2),
+    (b'#', 2),
+    (b';', 2),
+    (b',', 2),
+    (b'j', 2),
+    (b'q', 1),
+    (b'z', 1),
+    (b'(', 1),
+    (b')', 1),
+    (b'{', 1),
+    (b'}', 1),
+    (b'\'', 1),
+    (b'&', 1),
+    (b'?', 1),
+    (b'!', 1),
+];
+
+/// Representation payload is mapped into
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EntropyEncoding {
+    /// Payload is sent as is
+    #[default]
+    None,
+    /// Base64 text, 6 bits per byte; suits bodies of HTTP requests
+    Base64,
+    /// Text with HTML byte frequencies, about 4.8 bits per byte
+    Html,
+}
+
+/// Shannon entropy of `data` in bits per byte
+pub fn shannon_entropy(data: &[u8]) -> f64 {
+    if data.is_empty() {
+        return 0.0;
+    }
+    let mut counts = [0usize; 256];
+    for &byte in data {
+        counts[byte as usize] += 1;
+    }
+    let len = data.len() as f64;
+    counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// Shortest payload judged by its entropy; shorter samples say too little
+const MIN_ENTROPY_SAMPLE: usize = 64;
+/// Share of the highest entropy a sample of its length can reach above
+/// which it counts as ciphertext
+const HIGH_ENTROPY_RATIO: f64 = 0.85;
+/// Largest TLS record body, with room for AEAD expansion
+const MAX_TLS_RECORD: usize = 16384 + 256;
+
+/// What a payload already is
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PayloadKind {
+    /// Readable or structured bytes
+    Plain,
+    /// Near-uniform bytes: ciphertext or compressed data
+    HighEntropy,
+    /// Starts with a TLS record header
+    Tls,
+}
+
+impl PayloadKind {
+    /// Whether the payload is ciphertext already, which byte scrambling
+    /// only inflates
+    pub fn is_encrypted(self) -> bool {
+        self != PayloadKind::Plain
+    }
+}
+
+/// Tell already-encrypted payloads from plain ones
+pub fn classify(data: &[u8]) -> PayloadKind {
+    if let [0x14..=0x17, 0x03, 0x00..=0x04, high, low, ..] = data {
+        let len = u16::from_be_bytes([*high, *low]) as usize;
+        if len > 0 && len <= MAX_TLS_RECORD {
+            return PayloadKind::Tls;
+        }
+    }
+    if data.len() >= MIN_ENTROPY_SAMPLE {
+        // A sample can hold at most log2(len) bits per byte below 256 bytes
+        let ceiling = (data.len().min(256) as f64).log2();
+        if shannon_entropy(data) > ceiling * HIGH_ENTROPY_RATIO {
+            return PayloadKind::HighEntropy;
+        }
+    }
+    PayloadKind::Plain
+}
+
+enum Node {
+    Leaf(u8),
+    Branch(usize, usize),
+}
+
+/// Prefix code over a symbol alphabet, built by Huffman's algorithm
+///
+/// Used backwards: reading uniformly random bits down the tree emits each
+/// symbol with probability 2^-depth, which approximates the weights the
+/// tree was built from.
+struct PrefixCode {
+    nodes: Vec<Node>,
+    root: usize,
+    /// Code bits and length per byte value
+    codes: Vec<Option<(u32, u8)>>,
+}
+
+impl PrefixCode {
+    fn new(weights: &[(u8, u32)]) -> Self {
+        let mut nodes = Vec::new();
+        let mut heap = BinaryHeap::new();
+        for &(symbol, weight) in weights {
+            heap.push(Reverse((weight, nodes.len())));
+            nodes.push(Node::Leaf(symbol));
+        }
+        while heap.len() > 1 {
+            let Reverse((left_weight, left)) = heap.pop().unwrap();
+            let Reverse((right_weight, right)) = heap.pop().unwrap();
+            heap.push(Reverse((left_weight + right_weight, nodes.len())));
+            nodes.push(Node::Branch(left, right));
+        }
+        let root = nodes.len() - 1;
+
+        let mut codes = vec![None; 256];
+        let mut stack = vec![(root, 0u32, 0u8)];
+        while let Some((index, bits, len)) = stack.pop() {
+            match nodes[index] {
+                Node::Leaf(symbol) => codes[symbol as usize] = Some((bits, len)),
+                Node::Branch(left, right) => {
+                    stack.push((left, bits << 1, len + 1));
+                    stack.push((right, (bits << 1) | 1, len + 1));
+                }
+            }
+        }
+        PrefixCode { nodes, root, codes }
+    }
+}
+
+/// MSB-first reader that yields zero bits past the end
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl BitReader<'_> {
+    fn next(&mut self) -> bool {
+        let bit = self
+            .data
+            .get(self.pos / 8)
+            .is_some_and(|byte| byte & (0x80 >> (self.pos % 8)) != 0);
+        self.pos += 1;
+        bit
+    }
+
+    fn exhausted(&self) -> bool {
+        self.pos >= self.data.len() * 8
+    }
+}
+
+/// Encoder and decoder for one `EntropyEncoding`
+///
+/// The HTML encoding assumes its input is already uniform, as ciphertext
+/// is; plaintext input comes out with skewed frequencies.
+pub struct EntropyShaper {
+    encoding: EntropyEncoding,
+    code: PrefixCode,
+}
+
+impl EntropyShaper {
+    pub fn new(encoding: EntropyEncoding) -> Self {
+        EntropyShaper {
+            encoding,
+            code: PrefixCode::new(HTML_FREQUENCIES),
+        }
+    }
+
+    pub fn encoding(&self) -> EntropyEncoding {
+        self.encoding
+    }
+
+    /// Map payload into the shaped representation
+    pub fn encode(&self, data: &[u8]) -> Vec<u8> {
+        match self.encoding {
+            EntropyEncoding::None => data.to_vec(),
+            EntropyEncoding::Base64 => BASE64.encode(data).into_bytes(),
+            EntropyEncoding::Html => self.encode_html(data),
+        }
+    }
+
+    /// Recover payload from the shaped representation
+    pub fn decode(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match self.encoding {
+            EntropyEncoding::None => Ok(data.to_vec()),
+            EntropyEncoding::Base64 => BASE64
+                .decode(data)
+                .map_err(|e| Error::DataError(format!("Invalid base64 payload: {}", e))),
+            EntropyEncoding::Html => self.decode_html(data),
+        }
+    }
+
+    fn encode_html(&self, data: &[u8]) -> Vec<u8> {
+        // Length prefix, so the zero bits finishing the last symbol are
+        // not mistaken for payload
+        let mut framed = Vec::with_capacity(data.len() + 5);
+        let mut len = data.len();
+        loop {
+            let byte = (len & 0x7f) as u8;
+            len >>= 7;
+            if len == 0 {
+                framed.push(byte);
+                break;
+            }
+            framed.push(byte | 0x80);
+        }
+        framed.extend_from_slice(data);
+
+        let mut bits = BitReader {
+            data: &framed,
+            pos: 0,
+        };
+        let mut output = Vec::with_capacity(framed.len() * 2);
+        let mut node = self.code.root;
+        loop {
+            if let Node::Leaf(symbol) = self.code.nodes[node] {
+                output.push(symbol);
+                node = self.code.root;
+                if bits.exhausted() {
+                    return output;
+                }
+            }
+            if let Node::Branch(left, right) = self.code.nodes[node] {
+                node = if bits.next() { right } else { left };
+            }
+        }
+    }
+
+    fn decode_html(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut bytes = Vec::with_capacity(data.len() / 2);
+        let mut acc = 0u64;
+        let mut acc_len = 0;
+        for &symbol in data {
+            let (bits, len) = self.code.codes[symbol as usize].ok_or_else(|| {
+                Error::DataError(format!(
+                    "Byte {:#04x} is not in the shaped alphabet",
+                    symbol
+                ))
+            })?;
+            acc = (acc << len) | bits as u64;
+            acc_len += len as u32;
+            while acc_len >= 8 {
+                acc_len -= 8;
+                bytes.push((acc >> acc_len) as u8);
+            }
+            acc &= (1 << acc_len) - 1;
+        }
+
+        let mut len = 0usize;
+        for (i, &byte) in bytes.iter().enumerate().take(5) {
+            len |= ((byte & 0x7f) as usize) << (7 * i);
+            if byte & 0x80 == 0 {
+                let payload = &bytes[i + 1..];
+                return payload
+                    .get(..len)
+                    .map(<[u8]>::to_vec)
+                    .ok_or_else(|| Error::DataError("Shaped payload truncated".to_string()));
+            }
+        }
+        Err(Error::DataError("Shaped payload has no length".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::RngCore;
+
+    fn random_bytes(len: usize) -> Vec<u8> {
+        let mut data = vec![0u8; len];
+        rand::thread_rng().fill_bytes(&mut data);
+        data
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for encoding in [
+            EntropyEncoding::None,
+            EntropyEncoding::Base64,
+            EntropyEncoding::Html,
+        ] {
+            let shaper = EntropyShaper::new(encoding);
+            for len in [0, 1, 2, 127, 128, 5000] {
+                let data = random_bytes(len);
+                assert_eq!(shaper.decode(&shaper.encode(&data)).unwrap(), data);
+            }
+        }
+    }
+
+    #[test]
+    fn test_html_lowers_entropy() {
+        let shaper = EntropyShaper::new(EntropyEncoding::Html);
+        let data = random_bytes(20000);
+        assert!(shannon_entropy(&data) > 7.9);
+
+        let shaped = shaper.encode(&data);
+        let entropy = shannon_entropy(&shaped);
+        assert!(entropy > 4.0 && entropy < 5.0, "{}", entropy);
+        // Spaces and common letters dominate, as in a page
+        let spaces = shaped.iter().filter(|&&b| b == b' ').count();
+        assert!(spaces * 100 / shaped.len() > 8);
+        assert!(shaped.len() < data.len() * 2);
+    }
+
+    #[test]
+    fn test_classify_payloads() {
+        assert_eq!(classify(&random_bytes(64)), PayloadKind::HighEntropy);
+        assert_eq!(classify(&random_bytes(4000)), PayloadKind::HighEntropy);
+        let html = b"<html><head><title>Welcome</title></head><body><p>Hello \
+                     there, this is a perfectly ordinary page of text.</p></body></html>";
+        assert_eq!(classify(html), PayloadKind::Plain);
+        assert_eq!(classify(&random_bytes(20)), PayloadKind::Plain);
+
+        let mut record = vec![0x17, 0x03, 0x03, 0x00, 0x20];
+        record.extend(vec![0u8; 32]);
+        assert_eq!(classify(&record), PayloadKind::Tls);
+        assert!(!PayloadKind::Plain.is_encrypted());
+    }
+
+    #[test]
+    fn test_decode_rejects_foreign_bytes() {
+        let shaper = EntropyShaper::new(EntropyEncoding::Html);
+        assert!(shaper.decode(&[0xff, b'e']).is_err());
+        let base64 = EntropyShaper::new(EntropyEncoding::Base64);
+        assert!(base64.decode(b"not base64!").is_err());
+    }
+}
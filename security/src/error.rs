@@ -26,6 +26,9 @@ pub enum Error {
     #[error("Data error: {0}")]
     DataError(String),
 
+    #[error("Session terminated: {0}")]
+    SessionTerminated(String),
+
     #[error("Unknown error: {0}")]
     Unknown(String),
 }
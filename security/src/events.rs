@@ -0,0 +1,185 @@
+//! Security event hooks
+//! Callbacks fired when the processor changes camouflage or learns about the
+//! network, so front-ends can tell users what is happening
+
+use crate::diagnosis::Diagnosis;
+use crate::probe_guard::ProbeEvent;
+use crate::throttle::PathVerdict;
+use parking_lot::RwLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// What an event is about, for subscribing
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum EventKind {
+    PatternRotated,
+    StrategySwitched,
+    AdaptationLevelChanged,
+    ProbeDetected,
+    BlockingDiagnosed,
+    SessionTerminated,
+}
+
+impl EventKind {
+    pub const ALL: [EventKind; 6] = [
+        EventKind::PatternRotated,
+        EventKind::StrategySwitched,
+        EventKind::AdaptationLevelChanged,
+        EventKind::ProbeDetected,
+        EventKind::BlockingDiagnosed,
+        EventKind::SessionTerminated,
+    ];
+}
+
+/// Something the processor did or detected
+#[derive(Clone, Debug, PartialEq)]
+pub enum SecurityEvent {
+    /// A fresh rotation pattern is in use
+    PatternRotated { pattern_id: u32 },
+    /// The current method was detected and evasion switched away from it
+    StrategySwitched { verdict: PathVerdict },
+    /// The effective adaptation level moved
+    AdaptationLevelChanged { from: u8, to: u8 },
+    /// A client was caught probing the server
+    ProbeDetected(ProbeEvent),
+    /// A failed connection was traced to a censorship mechanism
+    BlockingDiagnosed(Diagnosis),
+    /// The kill switch stopped the session rather than send less-protected
+    /// output
+    SessionTerminated { reason: String },
+}
+
+impl SecurityEvent {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            SecurityEvent::PatternRotated { .. } => EventKind::PatternRotated,
+            SecurityEvent::StrategySwitched { .. } => EventKind::StrategySwitched,
+            SecurityEvent::AdaptationLevelChanged { .. } => EventKind::AdaptationLevelChanged,
+            SecurityEvent::ProbeDetected(_) => EventKind::ProbeDetected,
+            SecurityEvent::BlockingDiagnosed(_) => EventKind::BlockingDiagnosed,
+            SecurityEvent::SessionTerminated { .. } => EventKind::SessionTerminated,
+        }
+    }
+}
+
+/// Handle for removing a subscription
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SubscriptionId(u64);
+
+type Callback = Arc<dyn Fn(&SecurityEvent) + Send + Sync>;
+
+/// Dispatches events to the callbacks subscribed to their kind
+///
+/// Callbacks run synchronously on the thread that emits the event, so they
+/// should hand off anything slow, e.g. to a channel. They may subscribe or
+/// unsubscribe from within the callback.
+#[derive(Default)]
+pub struct EventBus {
+    subscribers: RwLock<Vec<(SubscriptionId, EventKind, Callback)>>,
+    next_id: AtomicU64,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Call `callback` for every event of `kind`
+    pub fn subscribe<F>(&self, kind: EventKind, callback: F) -> SubscriptionId
+    where
+        F: Fn(&SecurityEvent) + Send + Sync + 'static,
+    {
+        let id = SubscriptionId(self.next_id.fetch_add(1, Ordering::Relaxed));
+        self.subscribers
+            .write()
+            .push((id, kind, Arc::new(callback)));
+        id
+    }
+
+    /// Remove a subscription, returning whether it existed
+    pub fn unsubscribe(&self, id: SubscriptionId) -> bool {
+        let mut subscribers = self.subscribers.write();
+        let before = subscribers.len();
+        subscribers.retain(|(subscription, _, _)| *subscription != id);
+        subscribers.len() != before
+    }
+
+    /// Whether anyone listens for `kind`
+    pub fn has_subscribers(&self, kind: EventKind) -> bool {
+        self.subscribers.read().iter().any(|(_, k, _)| *k == kind)
+    }
+
+    /// Deliver an event to its subscribers, in subscription order
+    pub fn emit(&self, event: &SecurityEvent) {
+        let kind = event.kind();
+        let callbacks: Vec<Callback> = self
+            .subscribers
+            .read()
+            .iter()
+            .filter(|(_, k, _)| *k == kind)
+            .map(|(_, _, callback)| callback.clone())
+            .collect();
+        for callback in callbacks {
+            callback(event);
+        }
+    }
+}
+
+impl std::fmt::Debug for EventBus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EventBus")
+            .field("subscribers", &self.subscribers.read().len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parking_lot::Mutex;
+
+    #[test]
+    fn test_events_reach_their_kind_only() {
+        let bus = EventBus::new();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = seen.clone();
+        bus.subscribe(EventKind::PatternRotated, move |event| {
+            sink.lock().push(event.clone())
+        });
+
+        bus.emit(&SecurityEvent::PatternRotated { pattern_id: 7 });
+        bus.emit(&SecurityEvent::AdaptationLevelChanged { from: 1, to: 2 });
+        assert_eq!(
+            *seen.lock(),
+            vec![SecurityEvent::PatternRotated { pattern_id: 7 }]
+        );
+        assert!(bus.has_subscribers(EventKind::PatternRotated));
+        assert!(!bus.has_subscribers(EventKind::ProbeDetected));
+    }
+
+    #[test]
+    fn test_unsubscribe() {
+        let bus = Arc::new(EventBus::new());
+        let count = Arc::new(AtomicU64::new(0));
+        let counter = count.clone();
+        let id = bus.subscribe(EventKind::StrategySwitched, move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        let event = SecurityEvent::StrategySwitched {
+            verdict: PathVerdict::Blocked,
+        };
+        bus.emit(&event);
+        assert!(bus.unsubscribe(id));
+        assert!(!bus.unsubscribe(id));
+        bus.emit(&event);
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+
+        // Callbacks may touch the bus without deadlocking
+        let inner = bus.clone();
+        bus.subscribe(EventKind::StrategySwitched, move |_| {
+            inner.subscribe(EventKind::ProbeDetected, |_| {});
+        });
+        bus.emit(&event);
+        assert!(bus.has_subscribers(EventKind::ProbeDetected));
+    }
+}
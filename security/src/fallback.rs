@@ -0,0 +1,495 @@
+//! Active-probe camouflage for server endpoints
+//! Answers unauthenticated connections exactly like a stock nginx serving
+//! its default site: same status lines, header order, error pages and timeouts
+
+use crate::error::Result;
+use crate::obfuscation::http_date;
+use rand::Rng;
+use std::ops::Range;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Welcome page shipped with nginx 1.18 packages
+pub const NGINX_WELCOME_PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<title>Welcome to nginx!</title>
+<style>
+    body {
+        width: 35em;
+        margin: 0 auto;
+        font-family: Tahoma, Verdana, Arial, sans-serif;
+    }
+</style>
+</head>
+<body>
+<h1>Welcome to nginx!</h1>
+<p>If you see this page, the nginx web server is successfully installed and
+working. Further configuration is required.</p>
+
+<p>For online documentation and support please refer to
+<a href="http://nginx.org/">nginx.org</a>.<br/>
+Commercial support is available at
+<a href="http://nginx.com/">nginx.com</a>.</p>
+
+<p><em>Thank you for using nginx.</em></p>
+</body>
+</html>
+"#;
+
+/// nginx's default `large_client_header_buffers` size
+const MAX_HEADER_LEN: usize = 8192;
+
+/// A static page served by the decoy
+#[derive(Clone, Debug)]
+pub struct DecoyPage {
+    pub path: String,
+    pub content_type: String,
+    pub body: Vec<u8>,
+}
+
+impl DecoyPage {
+    pub fn new(path: &str, content_type: &str, body: impl Into<Vec<u8>>) -> Self {
+        DecoyPage {
+            path: path.to_string(),
+            content_type: content_type.to_string(),
+            body: body.into(),
+        }
+    }
+}
+
+/// Decoy site behavior; defaults match an untouched Ubuntu nginx install
+#[derive(Clone, Debug)]
+pub struct FallbackConfig {
+    /// `Server` header and error-page footer (`nginx` with server_tokens off)
+    pub server: String,
+    /// Pages served; `/` falls back to `/index.html`
+    pub pages: Vec<DecoyPage>,
+    /// `Last-Modified` of every page, also the first half of its ETag
+    pub last_modified: SystemTime,
+    /// How long a client may take to send a request head
+    pub header_timeout: Duration,
+    /// Idle time allowed between keep-alive requests
+    pub keepalive_timeout: Duration,
+    /// Requests served on one connection before it is closed
+    pub keepalive_requests: usize,
+    /// Time taken to produce a response, as seen from a nearby client
+    pub response_delay: Range<Duration>,
+}
+
+impl Default for FallbackConfig {
+    fn default() -> Self {
+        FallbackConfig {
+            server: "nginx/1.18.0 (Ubuntu)".to_string(),
+            pages: vec![DecoyPage::new(
+                "/index.html",
+                "text/html",
+                NGINX_WELCOME_PAGE,
+            )],
+            // Build date of the Ubuntu 20.04 nginx package
+            last_modified: UNIX_EPOCH + Duration::from_secs(1_587_478_141),
+            header_timeout: Duration::from_secs(60),
+            keepalive_timeout: Duration::from_secs(75),
+            keepalive_requests: 1000,
+            response_delay: Duration::from_micros(150)..Duration::from_micros(900),
+        }
+    }
+}
+
+/// A response and whether the connection stays open after it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DecoyResponse {
+    pub data: Vec<u8>,
+    pub keep_alive: bool,
+}
+
+/// A parsed request head
+struct Request<'a> {
+    method: &'a str,
+    path: &'a str,
+    http10: bool,
+    headers: Vec<(&'a str, &'a str)>,
+}
+
+impl<'a> Request<'a> {
+    fn parse(head: &'a str) -> Option<Self> {
+        let mut lines = head.split("\r\n");
+        let mut request_line = lines.next()?.split(' ');
+        let method = request_line.next()?;
+        let target = request_line.next()?;
+        let version = request_line.next()?;
+        if request_line.next().is_some()
+            || !is_method(method.as_bytes())
+            || !target.starts_with('/')
+            || !matches!(version, "HTTP/1.0" | "HTTP/1.1")
+        {
+            return None;
+        }
+
+        let mut headers = Vec::new();
+        for line in lines {
+            let (name, value) = line.split_once(':')?;
+            if name.is_empty() || name.contains(' ') {
+                return None;
+            }
+            headers.push((name, value.trim()));
+        }
+
+        Some(Request {
+            method,
+            path: target.split(['?', '#']).next().unwrap_or(target),
+            http10: version == "HTTP/1.0",
+            headers,
+        })
+    }
+
+    fn header(&self, name: &str) -> Option<&'a str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| *v)
+    }
+
+    fn body_len(&self) -> usize {
+        self.header("content-length")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0)
+    }
+}
+
+/// nginx accepts uppercase letters, `_` and `-` in methods
+fn is_method(bytes: &[u8]) -> bool {
+    !bytes.is_empty()
+        && bytes
+            .iter()
+            .all(|b| b.is_ascii_uppercase() || *b == b'_' || *b == b'-')
+}
+
+/// Whether buffered bytes can no longer become a valid request line, so
+/// nginx would answer 400 without waiting for more
+fn hopeless(buf: &[u8]) -> bool {
+    if buf.is_empty() {
+        return false;
+    }
+    let method_end = buf.iter().position(|&b| b == b' ').unwrap_or(buf.len());
+    let method = &buf[..method_end];
+    !is_method(method) || method.len() > 32
+}
+
+/// nginx-alike decoy web server
+pub struct DecoyServer {
+    config: FallbackConfig,
+}
+
+impl DecoyServer {
+    pub fn new(config: FallbackConfig) -> Self {
+        DecoyServer { config }
+    }
+
+    pub fn config(&self) -> &FallbackConfig {
+        &self.config
+    }
+
+    /// Response to one complete request head (without the final blank line)
+    pub fn respond(&self, head: &[u8]) -> DecoyResponse {
+        let Some(request) = std::str::from_utf8(head).ok().and_then(Request::parse) else {
+            return self.error(400, "Bad Request", None);
+        };
+        if !request.http10 && request.header("host").is_none() {
+            return self.error(400, "Bad Request", None);
+        }
+
+        let keep_alive = match request.header("connection") {
+            Some(v) if v.eq_ignore_ascii_case("close") => false,
+            Some(v) if v.eq_ignore_ascii_case("keep-alive") => true,
+            _ => !request.http10,
+        };
+        let path = if request.path.ends_with('/') {
+            format!("{}index.html", request.path)
+        } else {
+            request.path.to_string()
+        };
+        let page = self.config.pages.iter().find(|p| p.path == path);
+
+        let mut response = match (request.method, page) {
+            ("GET" | "HEAD", Some(page)) => DecoyResponse {
+                data: self.page(page, request.method == "HEAD", keep_alive),
+                keep_alive,
+            },
+            ("GET" | "HEAD" | "POST", None) => self.error(404, "Not Found", Some(keep_alive)),
+            _ => self.error(405, "Not Allowed", Some(keep_alive)),
+        };
+        if request.method == "HEAD" && page.is_none() {
+            let head_end = response.data.windows(4).position(|w| w == b"\r\n\r\n");
+            response.data.truncate(head_end.map_or(0, |end| end + 4));
+        }
+        response
+    }
+
+    fn page(&self, page: &DecoyPage, head_only: bool, keep_alive: bool) -> Vec<u8> {
+        let mtime = self
+            .config
+            .last_modified
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let mut data = format!(
+            "HTTP/1.1 200 OK\r\nServer: {}\r\nDate: {}\r\nContent-Type: {}\r\n\
+             Content-Length: {}\r\nLast-Modified: {}\r\nConnection: {}\r\n\
+             ETag: \"{:x}-{:x}\"\r\nAccept-Ranges: bytes\r\n\r\n",
+            self.config.server,
+            http_date(SystemTime::now()),
+            page.content_type,
+            page.body.len(),
+            http_date(self.config.last_modified),
+            if keep_alive { "keep-alive" } else { "close" },
+            mtime,
+            page.body.len()
+        )
+        .into_bytes();
+        if !head_only {
+            data.extend_from_slice(&page.body);
+        }
+        data
+    }
+
+    /// nginx's built-in error page; `keep_alive` of `None` forces a close
+    /// as nginx does after a 400
+    fn error(&self, status: u16, reason: &str, keep_alive: Option<bool>) -> DecoyResponse {
+        self.error_page(status, reason, reason, keep_alive)
+    }
+
+    fn error_page(
+        &self,
+        status: u16,
+        reason: &str,
+        title: &str,
+        keep_alive: Option<bool>,
+    ) -> DecoyResponse {
+        let mut body = format!(
+            "<html>\r\n<head><title>{} {}</title></head>\r\n<body>\r\n\
+             <center><h1>{} {}</h1></center>\r\n",
+            status, title, status, reason
+        );
+        if title != reason {
+            body.push_str(&format!("<center>{}</center>\r\n", title));
+        }
+        body.push_str(&format!(
+            "<hr><center>{}</center>\r\n</body>\r\n</html>\r\n",
+            self.config.server
+        ));
+
+        let keep_alive = keep_alive.unwrap_or(false);
+        let data = format!(
+            "HTTP/1.1 {} {}\r\nServer: {}\r\nDate: {}\r\nContent-Type: text/html\r\n\
+             Content-Length: {}\r\nConnection: {}\r\n\r\n{}",
+            status,
+            reason,
+            self.config.server,
+            http_date(SystemTime::now()),
+            body.len(),
+            if keep_alive { "keep-alive" } else { "close" },
+            body
+        );
+        DecoyResponse {
+            data: data.into_bytes(),
+            keep_alive,
+        }
+    }
+
+    /// Serve `stream` until the client leaves or a timeout closes it
+    ///
+    /// `initial` holds bytes already read while deciding the client was not
+    /// authenticated; they are treated as the start of the first request.
+    pub async fn serve<S>(&self, mut stream: S, initial: &[u8]) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut buf = initial.to_vec();
+        let mut chunk = [0u8; 4096];
+        for served in 0..self.config.keepalive_requests {
+            let idle = if served == 0 {
+                self.config.header_timeout
+            } else {
+                self.config.keepalive_timeout
+            };
+            let deadline = tokio::time::Instant::now() + idle;
+
+            // Read the request head; nginx closes silently on a timeout
+            let head_end = loop {
+                if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                    break end;
+                }
+                if hopeless(&buf) {
+                    self.send(&mut stream, &self.error(400, "Bad Request", None))
+                        .await?;
+                    return Ok(());
+                }
+                if buf.len() > MAX_HEADER_LEN {
+                    let response = self.error_page(
+                        400,
+                        "Bad Request",
+                        "Request Header Or Cookie Too Large",
+                        None,
+                    );
+                    self.send(&mut stream, &response).await?;
+                    return Ok(());
+                }
+                match tokio::time::timeout_at(deadline, stream.read(&mut chunk)).await {
+                    Ok(Ok(n)) if n > 0 => buf.extend_from_slice(&chunk[..n]),
+                    Ok(Err(e)) => return Err(e.into()),
+                    _ => return Ok(()),
+                }
+            };
+
+            let head: Vec<u8> = buf.drain(..head_end + 4).collect();
+            let head = &head[..head_end];
+            let mut response = self.respond(head);
+            if served + 1 == self.config.keepalive_requests {
+                response.keep_alive = false;
+            }
+
+            // Discard a request body before answering, as nginx does
+            let body_len = std::str::from_utf8(head)
+                .ok()
+                .and_then(Request::parse)
+                .map_or(0, |r| r.body_len());
+            while buf.len() < body_len {
+                match tokio::time::timeout_at(deadline, stream.read(&mut chunk)).await {
+                    Ok(Ok(n)) if n > 0 => buf.extend_from_slice(&chunk[..n]),
+                    Ok(Err(e)) => return Err(e.into()),
+                    _ => return Ok(()),
+                }
+            }
+            buf.drain(..body_len);
+
+            self.send(&mut stream, &response).await?;
+            if !response.keep_alive {
+                break;
+            }
+        }
+        stream.shutdown().await?;
+        Ok(())
+    }
+
+    async fn send<S: AsyncWrite + Unpin>(
+        &self,
+        stream: &mut S,
+        response: &DecoyResponse,
+    ) -> Result<()> {
+        let delay = &self.config.response_delay;
+        if !delay.is_empty() {
+            let delay = rand::thread_rng().gen_range(delay.clone());
+            tokio::time::sleep(delay).await;
+        }
+        stream.write_all(&response.data).await?;
+        stream.flush().await?;
+        Ok(())
+    }
+}
+
+impl Default for DecoyServer {
+    fn default() -> Self {
+        Self::new(FallbackConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(response: &DecoyResponse) -> String {
+        String::from_utf8(response.data.clone()).unwrap()
+    }
+
+    #[test]
+    fn test_default_page_matches_nginx() {
+        assert_eq!(NGINX_WELCOME_PAGE.len(), 612);
+        let server = DecoyServer::default();
+        let response = server.respond(b"GET / HTTP/1.1\r\nHost: 203.0.113.7");
+        assert!(response.keep_alive);
+
+        let response = text(&response);
+        let names: Vec<&str> = response
+            .lines()
+            .skip(1)
+            .take_while(|l| !l.is_empty())
+            .map(|l| l.split_once(':').unwrap().0)
+            .collect();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\nServer: nginx/1.18.0 (Ubuntu)\r\n"));
+        assert_eq!(
+            names,
+            [
+                "Server",
+                "Date",
+                "Content-Type",
+                "Content-Length",
+                "Last-Modified",
+                "Connection",
+                "ETag",
+                "Accept-Ranges"
+            ]
+        );
+        assert!(response.contains("ETag: \"5e9efe7d-264\"\r\n"));
+        assert!(response.ends_with(NGINX_WELCOME_PAGE));
+    }
+
+    #[test]
+    fn test_error_responses() {
+        let server = DecoyServer::default();
+
+        let missing = text(&server.respond(b"GET /admin HTTP/1.1\r\nHost: a"));
+        assert!(missing.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(missing.ends_with(
+            "<html>\r\n<head><title>404 Not Found</title></head>\r\n<body>\r\n\
+             <center><h1>404 Not Found</h1></center>\r\n\
+             <hr><center>nginx/1.18.0 (Ubuntu)</center>\r\n</body>\r\n</html>\r\n"
+        ));
+        assert!(missing.contains("Content-Length: 162\r\n"));
+
+        let head = server.respond(b"HEAD /admin HTTP/1.1\r\nHost: a");
+        assert!(text(&head).ends_with("Connection: keep-alive\r\n\r\n"));
+
+        let put = text(&server.respond(b"PUT / HTTP/1.1\r\nHost: a"));
+        assert!(put.starts_with("HTTP/1.1 405 Not Allowed\r\n"));
+
+        let no_host = server.respond(b"GET / HTTP/1.1\r\nAccept: */*");
+        assert!(!no_host.keep_alive);
+        assert!(text(&no_host).starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_serve_keep_alive_and_probe() {
+        let server = DecoyServer::default();
+        let (mut client, stream) = tokio::io::duplex(1 << 16);
+        let serving = tokio::spawn(async move { server.serve(stream, b"GET /in").await });
+        client
+            .write_all(
+                b"dex.html HTTP/1.1\r\nHost: a\r\n\r\n\
+                  POST /x HTTP/1.0\r\nContent-Length: 3\r\n\r\nabc",
+            )
+            .await
+            .unwrap();
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        serving.await.unwrap().unwrap();
+
+        let received = String::from_utf8(received).unwrap();
+        assert!(received.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert_eq!(received.matches("HTTP/1.1 ").count(), 2);
+        assert!(received.contains("HTTP/1.1 404 Not Found\r\n"));
+        assert!(received.contains("Connection: close\r\n"));
+
+        // A TLS ClientHello is rejected at once with a 400
+        let server = DecoyServer::default();
+        let (mut client, stream) = tokio::io::duplex(1 << 16);
+        let serving = tokio::spawn(async move { server.serve(stream, &[]).await });
+        client
+            .write_all(&[0x16, 0x03, 0x01, 0x02, 0x00])
+            .await
+            .unwrap();
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        serving.await.unwrap().unwrap();
+        assert!(received.starts_with(b"HTTP/1.1 400 Bad Request\r\n"));
+    }
+}
@@ -0,0 +1,259 @@
+//! Classifier feature extraction
+//! Computes the flow features ML-based DPI is known to use (payload entropy,
+//! packet-length histogram, burstiness, direction ratios) over our own
+//! output, to judge how detectable it is and to give tests targets to hit
+
+use crate::entropy::shannon_entropy;
+use std::time::Duration;
+
+/// Upper bounds of the packet-length histogram bins; the last bin holds
+/// everything longer
+pub const LENGTH_BINS: [usize; 6] = [64, 128, 256, 512, 1024, 1500];
+
+/// Packets closer together than this belong to the same burst
+pub const BURST_GAP: Duration = Duration::from_millis(10);
+
+/// Which way a packet travels
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// Client to server
+    Upstream,
+    /// Server to client
+    Downstream,
+}
+
+/// One packet of a flow
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FlowPacket {
+    pub direction: Direction,
+    /// Time since the start of the flow
+    pub at: Duration,
+    pub payload: Vec<u8>,
+}
+
+/// Packets of a flow in the order they were sent
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Flow {
+    pub packets: Vec<FlowPacket>,
+}
+
+impl Flow {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append a packet; packets are expected in time order
+    pub fn push(&mut self, direction: Direction, at: Duration, payload: impl Into<Vec<u8>>) {
+        self.packets.push(FlowPacket {
+            direction,
+            at,
+            payload: payload.into(),
+        });
+    }
+
+    /// A flow of upstream packets sent `gap` apart
+    pub fn upstream<P: AsRef<[u8]>>(payloads: &[P], gap: Duration) -> Self {
+        let mut flow = Self::new();
+        for (index, payload) in payloads.iter().enumerate() {
+            flow.push(Direction::Upstream, gap * index as u32, payload.as_ref());
+        }
+        flow
+    }
+}
+
+/// Features of a flow as a classifier sees them
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FlowFeatures {
+    pub packets: usize,
+    pub bytes: u64,
+    /// Shannon entropy of the payloads, in bits per byte, averaged over
+    /// packets weighted by length
+    pub mean_entropy: f64,
+    /// Entropy of the first payload, which many classifiers weigh most
+    pub first_packet_entropy: f64,
+    pub mean_len: f64,
+    pub len_std_dev: f64,
+    /// Share of packets per bin of `LENGTH_BINS`, plus the overflow bin
+    pub length_histogram: [f64; LENGTH_BINS.len() + 1],
+    /// Coefficient of variation of the gaps between packets: about 1 for
+    /// random arrivals, below for paced traffic, above for bursty traffic
+    pub burstiness: f64,
+    /// Packets per burst, bursts being runs less than `BURST_GAP` apart
+    pub mean_burst_len: f64,
+    /// Share of packets sent upstream
+    pub upstream_packet_ratio: f64,
+    /// Share of bytes sent upstream
+    pub upstream_byte_ratio: f64,
+}
+
+impl FlowFeatures {
+    /// Total variation distance between two length histograms: 0 for the
+    /// same distribution, 1 for disjoint ones
+    pub fn length_distance(&self, other: &FlowFeatures) -> f64 {
+        self.length_histogram
+            .iter()
+            .zip(&other.length_histogram)
+            .map(|(a, b)| (a - b).abs())
+            .sum::<f64>()
+            / 2.0
+    }
+}
+
+/// Histogram bin of a packet length
+pub fn length_bin(len: usize) -> usize {
+    LENGTH_BINS
+        .iter()
+        .position(|&bound| len < bound)
+        .unwrap_or(LENGTH_BINS.len())
+}
+
+/// Compute the features of a flow; an empty flow has all-zero features
+pub fn extract(flow: &Flow) -> FlowFeatures {
+    let packets = &flow.packets;
+    if packets.is_empty() {
+        return FlowFeatures::default();
+    }
+    let count = packets.len() as f64;
+    let lens: Vec<f64> = packets.iter().map(|p| p.payload.len() as f64).collect();
+    let bytes: u64 = packets.iter().map(|p| p.payload.len() as u64).sum();
+
+    let mean_entropy = match bytes {
+        0 => 0.0,
+        bytes => {
+            packets
+                .iter()
+                .map(|p| shannon_entropy(&p.payload) * p.payload.len() as f64)
+                .sum::<f64>()
+                / bytes as f64
+        }
+    };
+
+    let mut bins = [0usize; LENGTH_BINS.len() + 1];
+    for packet in packets {
+        bins[length_bin(packet.payload.len())] += 1;
+    }
+    let length_histogram = bins.map(|packets| packets as f64 / count);
+
+    let gaps: Vec<Duration> = packets
+        .windows(2)
+        .map(|pair| pair[1].at.saturating_sub(pair[0].at))
+        .collect();
+    let gap_secs: Vec<f64> = gaps.iter().map(Duration::as_secs_f64).collect();
+    let (gap_mean, gap_std_dev) = mean_std_dev(&gap_secs);
+    let burstiness = if gap_mean > 0.0 {
+        gap_std_dev / gap_mean
+    } else {
+        0.0
+    };
+    let bursts = 1 + gaps.iter().filter(|gap| **gap >= BURST_GAP).count();
+
+    let upstream: Vec<&FlowPacket> = packets
+        .iter()
+        .filter(|p| p.direction == Direction::Upstream)
+        .collect();
+    let upstream_bytes: u64 = upstream.iter().map(|p| p.payload.len() as u64).sum();
+
+    let (mean_len, len_std_dev) = mean_std_dev(&lens);
+    FlowFeatures {
+        packets: packets.len(),
+        bytes,
+        mean_entropy,
+        first_packet_entropy: shannon_entropy(&packets[0].payload),
+        mean_len,
+        len_std_dev,
+        length_histogram,
+        burstiness,
+        mean_burst_len: count / bursts as f64,
+        upstream_packet_ratio: upstream.len() as f64 / count,
+        upstream_byte_ratio: match bytes {
+            0 => 0.0,
+            bytes => upstream_bytes as f64 / bytes as f64,
+        },
+    }
+}
+
+/// Mean and population standard deviation; zeros for no samples
+fn mean_std_dev(samples: &[f64]) -> (f64, f64) {
+    if samples.is_empty() {
+        return (0.0, 0.0);
+    }
+    let n = samples.len() as f64;
+    let mean = samples.iter().sum::<f64>() / n;
+    let variance = samples.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / n;
+    (mean, variance.sqrt())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SecurityProcessor;
+    use rand::Rng;
+
+    #[test]
+    fn test_basic_features() {
+        let mut flow = Flow::new();
+        flow.push(Direction::Upstream, Duration::ZERO, vec![b'a'; 100]);
+        flow.push(
+            Direction::Downstream,
+            Duration::from_millis(1),
+            vec![0u8; 1400],
+        );
+        flow.push(
+            Direction::Downstream,
+            Duration::from_millis(2),
+            vec![0u8; 1500],
+        );
+        flow.push(
+            Direction::Upstream,
+            Duration::from_millis(500),
+            vec![b'b'; 40],
+        );
+
+        let features = extract(&flow);
+        assert_eq!(features.packets, 4);
+        assert_eq!(features.bytes, 3040);
+        assert_eq!(features.mean_entropy, 0.0);
+        assert_eq!(features.upstream_packet_ratio, 0.5);
+        assert!((features.upstream_byte_ratio - 140.0 / 3040.0).abs() < 1e-9);
+        assert_eq!(
+            features.length_histogram,
+            [0.25, 0.25, 0.0, 0.0, 0.0, 0.25, 0.25]
+        );
+        // Two bursts: three packets within 2ms, then one after a pause
+        assert_eq!(features.mean_burst_len, 2.0);
+        assert!(features.burstiness > 1.0);
+        assert_eq!(extract(&Flow::new()), FlowFeatures::default());
+    }
+
+    #[test]
+    fn test_paced_versus_random_gaps() {
+        let payloads = vec![vec![1u8; 300]; 50];
+        let paced = extract(&Flow::upstream(&payloads, Duration::from_millis(20)));
+        assert!(paced.burstiness < 1e-9);
+        assert_eq!(paced.mean_burst_len, 1.0);
+        assert_eq!(paced.length_distance(&paced), 0.0);
+
+        let mut rng = rand::thread_rng();
+        let random: Vec<Vec<u8>> = (0..50)
+            .map(|_| (0..rng.gen_range(1000..1400)).map(|_| rng.gen()).collect())
+            .collect();
+        let random = extract(&Flow::upstream(&random, Duration::from_millis(20)));
+        assert!(random.mean_entropy > 7.5);
+        assert_eq!(paced.length_distance(&random), 1.0);
+    }
+
+    #[test]
+    fn test_processor_output_features() {
+        let processor = SecurityProcessor::new().unwrap();
+        let request = b"GET /feed HTTP/1.1\r\nHost: example.com\r\n\r\n".repeat(10);
+        let plain = extract(&Flow::upstream(&[&request], Duration::ZERO));
+        let processed: Vec<Vec<u8>> = (0..20)
+            .map(|_| processor.process_outgoing(&request).unwrap())
+            .collect();
+        let processed = extract(&Flow::upstream(&processed, Duration::from_millis(5)));
+        // Obfuscation pads and scrambles: longer packets, spread lengths
+        assert!(processed.mean_len > plain.mean_len);
+        assert!(processed.len_std_dev > 0.0);
+        assert!(processed.mean_entropy > plain.mean_entropy);
+    }
+}
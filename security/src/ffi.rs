@@ -1,10 +1,15 @@
-/// FFI (Foreign Function Interface) module for exposing Rust security functions to C/Go
-/// This module provides C-compatible functions that wrap the Rust security implementations
+//! FFI (Foreign Function Interface) module for exposing Rust security functions to C/Go
+//! This module provides C-compatible functions that wrap the Rust security implementations
+
+// The exported functions are called from C/Go, which cannot observe `unsafe fn`;
+// every pointer argument is null-checked before it is dereferenced.
+#![allow(clippy::not_unsafe_ptr_arg_deref)]
 
-use crate::obfuscation::Obfuscator;
 use crate::pattern_rotation::PatternRotator;
-use crate::dpi_bypass::DPIBypass;
-use crate::detection_evasion::DetectionEvader;
+use crate::config::SecuritySettings;
+use crate::privacy;
+use crate::SecurityProcessor;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Mutex;
 use std::ffi::CStr;
 use std::os::raw::{c_char, c_int, c_void};
@@ -12,14 +17,18 @@ use std::os::raw::{c_char, c_int, c_void};
 /// Thread-safe error message storage
 static ERROR_MESSAGE: Mutex<String> = Mutex::new(String::new());
 
+/// Kill-switch mode: a failing stage terminates the session instead of
+/// only failing the packet
+static FAIL_CLOSED: AtomicBool = AtomicBool::new(false);
+
 /// Global security module state
 static mut SECURITY_STATE: Option<SecurityState> = None;
 
+/// Traffic goes through a `SecurityProcessor`, so C/Go callers and Rust
+/// callers put the same bytes on the wire
 struct SecurityState {
-    obfuscator: Obfuscator,
+    processor: SecurityProcessor,
     pattern_rotator: PatternRotator,
-    dpi_bypasser: DPIBypass,
-    detection_evader: DetectionEvader,
 }
 
 /// C-compatible SecurityBuffer struct
@@ -43,12 +52,20 @@ pub struct SecurityOptions {
 #[no_mangle]
 pub extern "C" fn security_init() -> c_int {
     match std::panic::catch_unwind(|| {
+        let mut settings = SecuritySettings::default();
+        settings.processor.fail_closed = FAIL_CLOSED.load(Ordering::Relaxed);
+        settings.processor.privacy_mode = privacy::is_enabled();
+        let processor = match SecurityProcessor::with_settings(settings) {
+            Ok(processor) => processor,
+            Err(e) => {
+                set_error(&format!("Initialization failed: {}", e));
+                return -1;
+            }
+        };
         unsafe {
             SECURITY_STATE = Some(SecurityState {
-                obfuscator: Obfuscator::new(),
+                processor,
                 pattern_rotator: PatternRotator::new(1),
-                dpi_bypasser: DPIBypass::new(),
-                detection_evader: DetectionEvader::new(5),
             });
         }
         0
@@ -70,13 +87,90 @@ pub extern "C" fn security_shutdown() -> c_int {
     0
 }
 
+/// Set the session key shared with the peer, e.g. exported from the TLS
+/// handshake
+///
+/// The detection-evasion scrambling and the stage header are keyed with
+/// it; until both sides set the same key, neither can read the other's
+/// traffic.
+#[no_mangle]
+pub extern "C" fn security_set_session_key(key: *const u8, key_len: c_int) -> c_int {
+    if key.is_null() || key_len <= 0 {
+        set_error("Empty session key passed to security_set_session_key");
+        return -1;
+    }
+
+    let key = unsafe { std::slice::from_raw_parts(key, key_len as usize) };
+    unsafe {
+        if let Some(ref mut state) = SECURITY_STATE {
+            state.processor.set_session_key(key);
+            return 0;
+        }
+    }
+    set_error("Security module not initialized");
+    -1
+}
+
+/// Switch the kill switch on (non-zero) or off
+///
+/// A stage failing in `process_outgoing_traffic` or
+/// `process_incoming_traffic` always fails that packet; when on, it also
+/// terminates the session, and every later call fails until
+/// `security_init` starts a new session.
+#[no_mangle]
+pub extern "C" fn security_set_fail_closed(enabled: c_int) -> c_int {
+    FAIL_CLOSED.store(enabled != 0, Ordering::Relaxed);
+    unsafe {
+        if let Some(ref mut state) = SECURITY_STATE {
+            let mut settings = state.processor.settings().clone();
+            settings.processor.fail_closed = enabled != 0;
+            if let Err(e) = state.processor.update_settings(settings) {
+                set_error(&format!("Failed to set the kill switch: {}", e));
+                return -1;
+            }
+        }
+    }
+    0
+}
+
+/// 1 if the kill switch has terminated the session, 0 otherwise
+#[no_mangle]
+pub extern "C" fn security_is_terminated() -> c_int {
+    unsafe {
+        match SECURITY_STATE {
+            Some(ref state) => state.processor.is_terminated() as c_int,
+            None => 0,
+        }
+    }
+}
+
+/// Switch privacy mode on (non-zero) or off
+///
+/// Privacy mode is process-wide: while on, nothing is persisted and
+/// destinations are redacted from logs, for this session and later ones.
+#[no_mangle]
+pub extern "C" fn security_set_privacy_mode(enabled: c_int) -> c_int {
+    privacy::set_enabled(enabled != 0);
+    unsafe {
+        if let Some(ref mut state) = SECURITY_STATE {
+            let mut settings = state.processor.settings().clone();
+            settings.processor.privacy_mode = enabled != 0;
+            if let Err(e) = state.processor.update_settings(settings) {
+                set_error(&format!("Failed to set privacy mode: {}", e));
+                return -1;
+            }
+        }
+    }
+    0
+}
+
 /// Get the last error message
 #[no_mangle]
 pub extern "C" fn get_last_error() -> *const c_char {
     match ERROR_MESSAGE.lock() {
         Ok(msg) => msg.as_ptr() as *const c_char,
         Err(_) => {
-            b"Unknown error\0".as_ptr() as *const c_char
+            c"Unknown error".as_ptr()
         }
     }
 }
@@ -107,32 +201,18 @@ pub extern "C" fn process_outgoing_traffic(
 
     let input_len = input_len as usize;
     let input_slice = unsafe { std::slice::from_raw_parts(input, input_len) };
-    let options = unsafe { opts.as_ref() };
+    let _options = unsafe { opts.as_ref() };
 
     match std::panic::catch_unwind(|| {
         unsafe {
             if let Some(ref state) = SECURITY_STATE {
-                let mut processed = input_slice.to_vec();
-
-                // Apply obfuscation if enabled
-                if let Ok(obfuscated) = state.obfuscator.obfuscate(&processed) {
-                    processed = obfuscated;
-                }
-
-                // Apply pattern rotation
-                if let Ok(rotated) = state.pattern_rotator.rotate_pattern(&processed) {
-                    processed = rotated;
-                }
-
-                // Apply DPI bypass techniques
-                if let Ok(evaded) = state.dpi_bypasser.apply_evasion(&processed) {
-                    processed = evaded;
-                }
-
-                // Apply detection evasion
-                if let Ok(final_processed) = state.detection_evader.evade_detection(&processed) {
-                    processed = final_processed;
-                }
+                let processed = match state.processor.process_outgoing(input_slice) {
+                    Ok(processed) => processed,
+                    Err(e) => {
+                        set_error(&e.to_string());
+                        return -1;
+                    }
+                };
 
                 // Copy to output buffer
                 let out_slice = std::slice::from_raw_parts_mut(output, processed.len());
@@ -172,24 +252,13 @@ pub extern "C" fn process_incoming_traffic(
     match std::panic::catch_unwind(|| {
         unsafe {
             if let Some(ref state) = SECURITY_STATE {
-                let mut processed = input_slice.to_vec();
-
-                // Reverse the evasion in opposite order
-                if let Ok(detection_reversed) = state.detection_evader.reverse_evasion(&processed) {
-                    processed = detection_reversed;
-                }
-
-                if let Ok(dpi_reversed) = state.dpi_bypasser.reverse_evasion(&processed) {
-                    processed = dpi_reversed;
-                }
-
-                if let Ok(pattern_reversed) = state.pattern_rotator.reverse_rotation(&processed) {
-                    processed = pattern_reversed;
-                }
-
-                if let Ok(deobfuscated) = state.obfuscator.deobfuscate(&processed) {
-                    processed = deobfuscated;
-                }
+                let processed = match state.processor.process_incoming(input_slice) {
+                    Ok(processed) => processed,
+                    Err(e) => {
+                        set_error(&e.to_string());
+                        return -1;
+                    }
+                };
 
                 // Copy to output buffer
                 let out_slice = std::slice::from_raw_parts_mut(output, processed.len());
@@ -226,11 +295,11 @@ pub extern "C" fn apply_tls_fragmentation(
 
     let handshake_len = handshake_len as usize;
     let handshake_slice = unsafe { std::slice::from_raw_parts(handshake, handshake_len) };
-    let fragment_size = (fragment_size as usize).max(100).min(500);
+    let fragment_size = (fragment_size as usize).clamp(100, 500);
 
     match std::panic::catch_unwind(|| {
         unsafe {
-            if let Some(ref state) = SECURITY_STATE {
+            if let Some(ref _state) = SECURITY_STATE {
                 // Fragment the handshake
                 let mut fragmented = Vec::new();
                 let mut offset = 0;
@@ -248,7 +317,7 @@ pub extern "C" fn apply_tls_fragmentation(
                 }
 
                 // Copy to output
-                if fragmented.len() <= std::i32::MAX as usize {
+                if fragmented.len() <= i32::MAX as usize {
                     let out_slice = std::slice::from_raw_parts_mut(output, fragmented.len());
                     out_slice.copy_from_slice(&fragmented);
                     *output_len = fragmented.len() as c_int;
@@ -284,15 +353,12 @@ pub extern "C" fn apply_sni_obfuscation(
 
     match std::panic::catch_unwind(|| {
         unsafe {
-            let sni_str = match CStr::from_ptr(sni).to_str() {
-                Ok(s) => s,
-                Err(_) => {
-                    set_error("Invalid UTF-8 in SNI");
-                    return -1;
-                }
-            };
+            if CStr::from_ptr(sni).to_str().is_err() {
+                set_error("Invalid UTF-8 in SNI");
+                return -1;
+            }
 
-            if let Some(ref state) = SECURITY_STATE {
+            if let Some(ref _state) = SECURITY_STATE {
                 // Create fake SNI list
                 let fake_snis = vec![
                     "google.com", "youtube.com", "facebook.com", "github.com",
@@ -308,7 +374,7 @@ pub extern "C" fn apply_sni_obfuscation(
 
                 // Randomize case
                 let mut obfuscated_sni = String::new();
-                for (i, c) in fake_sni.chars().enumerate() {
+                for c in fake_sni.chars() {
                     if rng.gen_bool(0.5) && c.is_alphabetic() {
                         obfuscated_sni.push(c.to_uppercase().next().unwrap());
                     } else {
@@ -317,7 +383,7 @@ pub extern "C" fn apply_sni_obfuscation(
                 }
 
                 let obfuscated_bytes = obfuscated_sni.as_bytes();
-                if obfuscated_bytes.len() <= std::i32::MAX as usize {
+                if obfuscated_bytes.len() <= i32::MAX as usize {
                     let out_slice = std::slice::from_raw_parts_mut(output, obfuscated_bytes.len());
                     out_slice.copy_from_slice(obfuscated_bytes);
                     *output_len = obfuscated_bytes.len() as c_int;
@@ -361,7 +427,7 @@ pub extern "C" fn apply_dynamic_pattern_rotation(
             if let Some(ref state) = SECURITY_STATE {
                 // Apply pattern randomization
                 if let Ok(rotated) = state.pattern_rotator.rotate_pattern(packet_slice) {
-                    if rotated.len() <= std::i32::MAX as usize {
+                    if rotated.len() <= i32::MAX as usize {
                         let out_slice = std::slice::from_raw_parts_mut(output, rotated.len());
                         out_slice.copy_from_slice(&rotated);
                         *output_len = rotated.len() as c_int;
@@ -399,14 +465,24 @@ fn set_error(message: &str) {
 mod tests {
     use super::*;
 
+    /// The exported functions share global state; tests that touch it run
+    /// one at a time
+    static GLOBAL_STATE: Mutex<()> = Mutex::new(());
+
+    fn lock_global_state() -> std::sync::MutexGuard<'static, ()> {
+        GLOBAL_STATE.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
     #[test]
     fn test_security_init_shutdown() {
+        let _guard = lock_global_state();
         assert_eq!(security_init(), 0);
         assert_eq!(security_shutdown(), 0);
     }
 
     #[test]
     fn test_null_pointer_checks() {
+        let _guard = lock_global_state();
         let mut output_len = 0;
         let mut output = vec![0u8; 1024];
 
@@ -422,4 +498,94 @@ mod tests {
             -1
         );
     }
+
+    /// Run `input` through `process_incoming_traffic`
+    fn incoming(input: &[u8]) -> std::result::Result<Vec<u8>, c_int> {
+        let mut output = vec![0u8; 4096];
+        let mut output_len = 0;
+        let result = process_incoming_traffic(
+            input.as_ptr(),
+            input.len() as c_int,
+            output.as_mut_ptr(),
+            &mut output_len,
+        );
+        output.truncate(output_len as usize);
+        if result == 0 { Ok(output) } else { Err(result) }
+    }
+
+    /// Run `input` through `process_outgoing_traffic`
+    fn outgoing(input: &[u8]) -> Vec<u8> {
+        let mut output = vec![0u8; 4096];
+        let mut output_len = 0;
+        let result = process_outgoing_traffic(
+            input.as_ptr(),
+            input.len() as c_int,
+            output.as_mut_ptr(),
+            &mut output_len,
+            std::ptr::null(),
+        );
+        assert_eq!(result, 0);
+        output.truncate(output_len as usize);
+        output
+    }
+
+    #[test]
+    fn test_session_key_round_trip() {
+        let _guard = lock_global_state();
+        let key = b"exported handshake secret";
+        let mut payload = vec![0u8; 300];
+        rand::Rng::fill(&mut rand::thread_rng(), &mut payload[..]);
+
+        assert_eq!(security_init(), 0);
+        assert_eq!(security_set_session_key(key.as_ptr(), key.len() as c_int), 0);
+        let sent = outgoing(&payload);
+
+        // The peer is a separate instance holding the same key
+        assert_eq!(security_init(), 0);
+        assert_eq!(security_set_session_key(key.as_ptr(), key.len() as c_int), 0);
+        assert_eq!(incoming(&sent), Ok(payload));
+        assert_eq!(security_shutdown(), 0);
+
+        assert_eq!(security_set_session_key(key.as_ptr(), key.len() as c_int), -1);
+    }
+
+    #[test]
+    fn test_kill_switch_stages() {
+        let _guard = lock_global_state();
+        assert_eq!(security_init(), 0);
+        // Fail-open: the packet fails, but the session goes on
+        assert!(incoming(b"not a packet").is_err());
+        assert_eq!(security_is_terminated(), 0);
+
+        security_set_fail_closed(1);
+        assert!(incoming(b"not a packet").is_err());
+        assert_eq!(security_is_terminated(), 1);
+        security_set_fail_closed(0);
+        assert_eq!(security_init(), 0);
+        assert_eq!(security_is_terminated(), 0);
+        assert_eq!(security_shutdown(), 0);
+    }
+
+    #[test]
+    fn test_privacy_mode() {
+        let _guard = lock_global_state();
+        let _privacy = privacy::TEST_LOCK.lock();
+        assert_eq!(security_set_privacy_mode(1), 0);
+        assert!(privacy::is_enabled());
+
+        // A session started later keeps it on
+        assert_eq!(security_init(), 0);
+        let privacy_mode = || unsafe {
+            match SECURITY_STATE {
+                Some(ref state) => state.processor.settings().processor.privacy_mode,
+                None => false,
+            }
+        };
+        assert!(privacy_mode());
+
+        assert_eq!(security_set_privacy_mode(0), 0);
+        assert!(!privacy::is_enabled());
+        assert!(!privacy_mode());
+        assert_eq!(security_shutdown(), 0);
+    }
 }
@@ -0,0 +1,659 @@
+//! Browser fingerprints
+//! A database of versioned browser/OS fingerprints whose TLS and TCP parameters
+//! are kept mutually consistent, so rotating between them never mixes layers,
+//! plus JA3/JA4 hashing to check generated handshakes against them
+
+use crate::client_hello::{
+    is_grease, parse_client_hello, ClientHelloBuilder, ClientHelloSpec, ParsedClientHello,
+    EXT_ALPN, EXT_EC_POINT_FORMATS, EXT_PADDING, EXT_PRE_SHARED_KEY, EXT_SERVER_NAME,
+    EXT_SIGNATURE_ALGORITHMS, EXT_SUPPORTED_GROUPS, EXT_SUPPORTED_VERSIONS,
+};
+use crate::error::{Error, Result};
+use crate::sni_obfuscation::BrowserFingerprint;
+use md5::Md5;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Operating system a fingerprint was captured on
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OsFamily {
+    Windows,
+    Linux,
+    MacOs,
+    Android,
+    Ios,
+}
+
+impl OsFamily {
+    /// Check whether this is a mobile platform
+    pub fn is_mobile(&self) -> bool {
+        matches!(self, OsFamily::Android | OsFamily::Ios)
+    }
+}
+
+/// TCP/IP parameters of the OS network stack
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TcpProfile {
+    pub initial_ttl: u8,
+    pub window_size: u16,
+    pub mss: u16,
+    pub window_scale: u8,
+    /// SYN options in the order the OS sends them
+    pub syn_options: Vec<u8>,
+}
+
+impl TcpProfile {
+    /// TCP parameters for an OS family
+    pub fn for_os(os: OsFamily) -> Self {
+        match os {
+            OsFamily::Windows => TcpProfile {
+                initial_ttl: 128,
+                window_size: 64240,
+                mss: 1460,
+                window_scale: 8,
+                syn_options: vec![
+                    0x02, 0x04, 0x05, 0xb4, // MSS
+                    0x01, // NOP
+                    0x03, 0x03, 0x08, // Window Scale
+                    0x01, 0x01, // NOP, NOP
+                    0x04, 0x02, // SACK Permitted
+                ],
+            },
+            OsFamily::Linux | OsFamily::Android => TcpProfile {
+                initial_ttl: 64,
+                window_size: if os == OsFamily::Android {
+                    65535
+                } else {
+                    64240
+                },
+                mss: 1460,
+                window_scale: 7,
+                syn_options: vec![
+                    0x02, 0x04, 0x05, 0xb4, // MSS
+                    0x04, 0x02, // SACK Permitted
+                    0x08, 0x0a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // Timestamp
+                    0x01, // NOP
+                    0x03, 0x03, 0x07, // Window Scale
+                ],
+            },
+            OsFamily::MacOs | OsFamily::Ios => TcpProfile {
+                initial_ttl: 64,
+                window_size: 65535,
+                mss: 1460,
+                window_scale: 6,
+                syn_options: vec![
+                    0x02, 0x04, 0x05, 0xb4, // MSS
+                    0x01, // NOP
+                    0x03, 0x03, 0x06, // Window Scale
+                    0x01, 0x01, // NOP, NOP
+                    0x08, 0x0a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // Timestamp
+                    0x04, 0x02, // SACK Permitted
+                    0x00, 0x00, // EOL
+                ],
+            },
+        }
+    }
+}
+
+/// One versioned browser fingerprint
+#[derive(Clone, Debug)]
+pub struct BrowserProfile {
+    /// Stable identifier, e.g. `chrome-120-windows`
+    pub name: String,
+    pub browser: BrowserFingerprint,
+    pub version: u32,
+    pub os: OsFamily,
+    pub user_agent: String,
+    pub client_hello: ClientHelloSpec,
+    pub tcp: TcpProfile,
+    /// Relative share of real traffic, used when rotating
+    pub weight: u32,
+}
+
+impl BrowserProfile {
+    fn new(
+        browser: BrowserFingerprint,
+        version: u32,
+        os: OsFamily,
+        user_agent: &str,
+        weight: u32,
+    ) -> Self {
+        let browser_name = match browser {
+            BrowserFingerprint::Chrome => "chrome",
+            BrowserFingerprint::Firefox => "firefox",
+            BrowserFingerprint::Safari => "safari",
+            BrowserFingerprint::Edge => "edge",
+            BrowserFingerprint::Opera => "opera",
+        };
+        let os_name = match os {
+            OsFamily::Windows => "windows",
+            OsFamily::Linux => "linux",
+            OsFamily::MacOs => "macos",
+            OsFamily::Android => "android",
+            OsFamily::Ios => "ios",
+        };
+
+        BrowserProfile {
+            name: format!("{}-{}-{}", browser_name, version, os_name),
+            browser,
+            version,
+            os,
+            user_agent: user_agent.to_string(),
+            client_hello: ClientHelloSpec::for_browser_on(browser, os),
+            tcp: TcpProfile::for_os(os),
+            weight,
+        }
+    }
+
+    /// ClientHello builder producing this fingerprint
+    pub fn client_hello_builder(&self) -> ClientHelloBuilder {
+        ClientHelloBuilder::new(self.client_hello.clone())
+    }
+
+    /// Check that a ClientHello record really carries this fingerprint
+    pub fn verify(&self, client_hello: &[u8]) -> Result<()> {
+        verify_spec(client_hello, &self.client_hello)
+    }
+}
+
+/// Database of known browser fingerprints
+#[derive(Clone, Debug)]
+pub struct FingerprintDb {
+    profiles: Vec<BrowserProfile>,
+}
+
+impl FingerprintDb {
+    /// The fingerprints shipped with this crate
+    pub fn builtin() -> Self {
+        use BrowserFingerprint::*;
+        use OsFamily::*;
+
+        let profiles = vec![
+            BrowserProfile::new(
+                Chrome,
+                120,
+                Windows,
+                "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 \
+                 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36",
+                30,
+            ),
+            BrowserProfile::new(
+                Chrome,
+                120,
+                MacOs,
+                "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 \
+                 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36",
+                6,
+            ),
+            BrowserProfile::new(
+                Chrome,
+                120,
+                Android,
+                "Mozilla/5.0 (Linux; Android 10; K) AppleWebKit/537.36 \
+                 (KHTML, like Gecko) Chrome/120.0.0.0 Mobile Safari/537.36",
+                35,
+            ),
+            BrowserProfile::new(
+                Edge,
+                120,
+                Windows,
+                "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 \
+                 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36 Edg/120.0.0.0",
+                5,
+            ),
+            BrowserProfile::new(
+                Firefox,
+                121,
+                Windows,
+                "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:121.0) Gecko/20100101 \
+                 Firefox/121.0",
+                6,
+            ),
+            BrowserProfile::new(
+                Firefox,
+                121,
+                Linux,
+                "Mozilla/5.0 (X11; Linux x86_64; rv:121.0) Gecko/20100101 Firefox/121.0",
+                2,
+            ),
+            BrowserProfile::new(
+                Safari,
+                17,
+                MacOs,
+                "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 \
+                 (KHTML, like Gecko) Version/17.2 Safari/605.1.15",
+                4,
+            ),
+            BrowserProfile::new(
+                Safari,
+                17,
+                Ios,
+                "Mozilla/5.0 (iPhone; CPU iPhone OS 17_2 like Mac OS X) AppleWebKit/605.1.15 \
+                 (KHTML, like Gecko) Version/17.2 Mobile/15E148 Safari/604.1",
+                12,
+            ),
+        ];
+
+        FingerprintDb { profiles }
+    }
+
+    /// Create a database from explicit profiles
+    pub fn with_profiles(profiles: Vec<BrowserProfile>) -> Self {
+        FingerprintDb { profiles }
+    }
+
+    /// All profiles
+    pub fn profiles(&self) -> &[BrowserProfile] {
+        &self.profiles
+    }
+
+    /// Look up a profile by name
+    pub fn get(&self, name: &str) -> Option<&BrowserProfile> {
+        self.profiles.iter().find(|p| p.name == name)
+    }
+
+    /// Profiles for one browser family
+    pub fn for_browser(&self, browser: BrowserFingerprint) -> Vec<&BrowserProfile> {
+        self.profiles
+            .iter()
+            .filter(|p| std::mem::discriminant(&p.browser) == std::mem::discriminant(&browser))
+            .collect()
+    }
+
+    /// Pick the profile for a rotation slot (e.g. the hour number)
+    ///
+    /// The choice is deterministic per slot and weighted by traffic share,
+    /// so every component asking during the same hour gets the same answer.
+    pub fn select_for_slot(&self, slot: u32) -> Option<&BrowserProfile> {
+        let total: u64 = self.profiles.iter().map(|p| p.weight as u64).sum();
+        if total == 0 {
+            return self.profiles.first();
+        }
+
+        // splitmix64 scramble so consecutive slots don't pick neighbours
+        let mut x = (slot as u64).wrapping_add(0x9e37_79b9_7f4a_7c15);
+        x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        x ^= x >> 31;
+
+        let mut target = x % total;
+        for profile in &self.profiles {
+            if target < profile.weight as u64 {
+                return Some(profile);
+            }
+            target -= profile.weight as u64;
+        }
+        self.profiles.last()
+    }
+}
+
+impl Default for FingerprintDb {
+    fn default() -> Self {
+        Self::builtin()
+    }
+}
+
+/// Handshake fields that feed the JA3/JA4 hashes, with GREASE removed
+struct HelloFields {
+    legacy_version: u16,
+    ciphers: Vec<u16>,
+    extensions: Vec<u16>,
+    groups: Vec<u16>,
+    point_formats: Vec<u8>,
+    signature_algorithms: Vec<u16>,
+    supported_versions: Vec<u16>,
+    alpn: Option<Vec<u8>>,
+    has_sni: bool,
+}
+
+impl HelloFields {
+    fn extract(record: &[u8]) -> Result<Self> {
+        let parsed = parse_client_hello(record)?;
+        let body = |ext_type: u16| -> Option<&[u8]> {
+            parsed
+                .extension(ext_type)
+                .map(|ext| &record[ext.data.clone()])
+        };
+
+        let groups = body(EXT_SUPPORTED_GROUPS)
+            .map(|b| u16_list(b, 2))
+            .unwrap_or_default();
+        let point_formats = body(EXT_EC_POINT_FORMATS)
+            .and_then(|b| b.get(1..))
+            .map(|b| b.to_vec())
+            .unwrap_or_default();
+        let signature_algorithms = body(EXT_SIGNATURE_ALGORITHMS)
+            .map(|b| u16_list(b, 2))
+            .unwrap_or_default();
+        let supported_versions = body(EXT_SUPPORTED_VERSIONS)
+            .map(|b| u16_list(b, 1))
+            .unwrap_or_default();
+        // First protocol of the ALPN list: u16 list length, u8 name length, name
+        let alpn = body(EXT_ALPN).and_then(|b| {
+            let len = *b.get(2)? as usize;
+            b.get(3..3 + len).map(|name| name.to_vec())
+        });
+
+        Ok(HelloFields {
+            legacy_version: parsed.legacy_version,
+            ciphers: without_grease(&parsed.cipher_suites),
+            extensions: without_grease(&extension_types(&parsed)),
+            groups: without_grease(&groups),
+            point_formats,
+            signature_algorithms,
+            supported_versions: without_grease(&supported_versions),
+            alpn,
+            has_sni: parsed.extension(EXT_SERVER_NAME).is_some(),
+        })
+    }
+}
+
+fn extension_types(parsed: &ParsedClientHello) -> Vec<u16> {
+    parsed.extensions.iter().map(|ext| ext.ext_type).collect()
+}
+
+fn without_grease(values: &[u16]) -> Vec<u16> {
+    values.iter().copied().filter(|v| !is_grease(*v)).collect()
+}
+
+/// Decode a u16 list prefixed by a `prefix_len`-byte length
+fn u16_list(body: &[u8], prefix_len: usize) -> Vec<u16> {
+    body.get(prefix_len..)
+        .unwrap_or_default()
+        .chunks_exact(2)
+        .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+        .collect()
+}
+
+fn join<T: ToString>(values: &[T], separator: &str) -> String {
+    values
+        .iter()
+        .map(|v| v.to_string())
+        .collect::<Vec<_>>()
+        .join(separator)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The JA3 string (before hashing) of a ClientHello record
+///
+/// `SSLVersion,Ciphers,Extensions,EllipticCurves,EllipticCurvePointFormats`
+/// with decimal values, dash-separated lists, and GREASE removed.
+pub fn ja3_string(client_hello: &[u8]) -> Result<String> {
+    let fields = HelloFields::extract(client_hello)?;
+    Ok(format!(
+        "{},{},{},{},{}",
+        fields.legacy_version,
+        join(&fields.ciphers, "-"),
+        join(&fields.extensions, "-"),
+        join(&fields.groups, "-"),
+        join(&fields.point_formats, "-"),
+    ))
+}
+
+/// The JA3 fingerprint (MD5 of the JA3 string) of a ClientHello record
+pub fn ja3(client_hello: &[u8]) -> Result<String> {
+    let ja3 = ja3_string(client_hello)?;
+    Ok(to_hex(&Md5::digest(ja3.as_bytes())))
+}
+
+/// The JA4 fingerprint of a ClientHello record sent over TCP
+///
+/// Unlike JA3 it sorts ciphers and extensions, so it is stable across
+/// Chrome's per-handshake extension shuffling.
+pub fn ja4(client_hello: &[u8]) -> Result<String> {
+    let fields = HelloFields::extract(client_hello)?;
+
+    let version = fields
+        .supported_versions
+        .iter()
+        .copied()
+        .max()
+        .unwrap_or(fields.legacy_version);
+    let version = match version {
+        0x0304 => "13",
+        0x0303 => "12",
+        0x0302 => "11",
+        0x0301 => "10",
+        0x0300 => "s3",
+        _ => "00",
+    };
+
+    let alpn = match fields.alpn.as_deref() {
+        Some([first, .., last]) | Some([first @ last]) => {
+            if first.is_ascii_alphanumeric() && last.is_ascii_alphanumeric() {
+                format!("{}{}", *first as char, *last as char)
+            } else {
+                let hex = to_hex(&[*first, *last]);
+                format!("{}{}", &hex[..1], &hex[3..])
+            }
+        }
+        _ => "00".to_string(),
+    };
+
+    let a = format!(
+        "t{}{}{:02}{:02}{}",
+        version,
+        if fields.has_sni { 'd' } else { 'i' },
+        fields.ciphers.len().min(99),
+        fields.extensions.len().min(99),
+        alpn,
+    );
+
+    let mut ciphers = fields.ciphers.clone();
+    ciphers.sort_unstable();
+    let mut extensions: Vec<u16> = fields
+        .extensions
+        .iter()
+        .copied()
+        .filter(|&ext| ext != EXT_SERVER_NAME && ext != EXT_ALPN)
+        .collect();
+    extensions.sort_unstable();
+
+    let hex_list = |values: &[u16]| {
+        values
+            .iter()
+            .map(|v| format!("{:04x}", v))
+            .collect::<Vec<_>>()
+            .join(",")
+    };
+    let truncated_hash = |input: String| {
+        if input.is_empty() {
+            "000000000000".to_string()
+        } else {
+            to_hex(&Sha256::digest(input.as_bytes()))[..12].to_string()
+        }
+    };
+
+    let b = truncated_hash(hex_list(&ciphers));
+    let mut c_input = hex_list(&extensions);
+    if !fields.signature_algorithms.is_empty() {
+        c_input.push('_');
+        c_input.push_str(&hex_list(&fields.signature_algorithms));
+    }
+    let c = truncated_hash(c_input);
+
+    Ok(format!("{}_{}_{}", a, b, c))
+}
+
+/// Check that a ClientHello record matches a spec
+///
+/// Compares what the spec pins down (ciphers, groups, signature algorithms,
+/// versions, extension set and, unless shuffled, extension order) after
+/// stripping GREASE. Padding and pre_shared_key depend on the handshake and
+/// are ignored.
+pub fn verify_spec(client_hello: &[u8], spec: &ClientHelloSpec) -> Result<()> {
+    let fields = HelloFields::extract(client_hello)?;
+    let mismatch = |what: &str| {
+        Err(Error::DetectionEvadingError(format!(
+            "ClientHello {} do not match the intended fingerprint",
+            what
+        )))
+    };
+
+    if fields.ciphers != without_grease(&spec.cipher_suites) {
+        return mismatch("cipher suites");
+    }
+    if spec.extensions.contains(&EXT_SUPPORTED_GROUPS) && fields.groups != spec.supported_groups {
+        return mismatch("supported groups");
+    }
+    if spec.extensions.contains(&EXT_SIGNATURE_ALGORITHMS)
+        && fields.signature_algorithms != spec.signature_algorithms
+    {
+        return mismatch("signature algorithms");
+    }
+    if spec.extensions.contains(&EXT_SUPPORTED_VERSIONS)
+        && fields.supported_versions != spec.supported_versions
+    {
+        return mismatch("supported versions");
+    }
+
+    let extensions: Vec<u16> = fields
+        .extensions
+        .iter()
+        .copied()
+        .filter(|&ext| ext != EXT_PADDING && ext != EXT_PRE_SHARED_KEY)
+        .collect();
+    if extensions.iter().any(|ext| !spec.extensions.contains(ext)) {
+        return mismatch("extensions");
+    }
+    if !spec.shuffle_extensions {
+        let positions: Vec<usize> = extensions
+            .iter()
+            .filter_map(|ext| spec.extensions.iter().position(|e| e == ext))
+            .collect();
+        if positions.windows(2).any(|pair| pair[0] > pair[1]) {
+            return mismatch("extension order");
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client_hello::parse_client_hello;
+
+    #[test]
+    fn test_builtin_profiles() {
+        let db = FingerprintDb::builtin();
+        for name in [
+            "chrome-120-windows",
+            "firefox-121-windows",
+            "safari-17-macos",
+        ] {
+            assert!(db.get(name).is_some(), "missing {}", name);
+        }
+        assert!(db.profiles().iter().any(|p| p.os.is_mobile()));
+    }
+
+    #[test]
+    fn test_layers_are_consistent() {
+        for profile in FingerprintDb::builtin().profiles() {
+            // Windows stacks start at TTL 128, everything else at 64
+            let expected_ttl = if profile.os == OsFamily::Windows {
+                128
+            } else {
+                64
+            };
+            assert_eq!(profile.tcp.initial_ttl, expected_ttl);
+
+            // Mobile user agents on mobile OSes only
+            assert_eq!(
+                profile.user_agent.contains("Mobile"),
+                profile.os.is_mobile()
+            );
+
+            let hello = profile
+                .client_hello_builder()
+                .server_name("example.com")
+                .build();
+            assert!(parse_client_hello(&hello).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_slot_selection_is_deterministic() {
+        let db = FingerprintDb::builtin();
+        let a = db.select_for_slot(480_000).unwrap().name.clone();
+        let b = db.select_for_slot(480_000).unwrap().name.clone();
+        assert_eq!(a, b);
+
+        // Over many slots more than one profile gets used
+        let distinct: std::collections::HashSet<_> = (0..200)
+            .map(|slot| db.select_for_slot(slot).unwrap().name.clone())
+            .collect();
+        assert!(distinct.len() > 1);
+    }
+
+    #[test]
+    fn test_ja3_of_known_hello() {
+        // Minimal TLS 1.2 hello: two ciphers, SNI and ec_point_formats
+        let mut builder = ClientHelloBuilder::new(ClientHelloSpec {
+            cipher_suites: vec![0xc02f, 0x009c],
+            extensions: vec![EXT_SERVER_NAME, EXT_SUPPORTED_GROUPS, EXT_EC_POINT_FORMATS],
+            supported_groups: vec![0x001d, 0x0017],
+            key_share_groups: Vec::new(),
+            signature_algorithms: Vec::new(),
+            supported_versions: Vec::new(),
+            alpn_protocols: Vec::new(),
+            cert_compression_algorithms: Vec::new(),
+            boringssl_padding: false,
+            grease: false,
+            shuffle_extensions: false,
+        });
+        builder = builder.server_name("example.com");
+        let hello = builder.build();
+
+        assert_eq!(ja3_string(&hello).unwrap(), "771,49199-156,0-10-11,29-23,0");
+        assert_eq!(
+            ja3(&hello).unwrap(),
+            to_hex(&Md5::digest(b"771,49199-156,0-10-11,29-23,0"))
+        );
+        assert!(ja4(&hello).unwrap().starts_with("t12d020300_"));
+    }
+
+    #[test]
+    fn test_ja4_stable_across_shuffling_and_grease() {
+        let mut spec = FingerprintDb::builtin()
+            .get("chrome-120-windows")
+            .unwrap()
+            .client_hello
+            .clone();
+        // The GREASE ECH payload varies in size, which decides whether the
+        // padding extension is needed at all
+        spec.boringssl_padding = false;
+        let builder = ClientHelloBuilder::new(spec).server_name("example.com");
+
+        let first = ja4(&builder.build()).unwrap();
+        for _ in 0..5 {
+            assert_eq!(ja4(&builder.build()).unwrap(), first);
+        }
+        assert!(first.starts_with("t13d1516"));
+        assert!(first[..10].ends_with("h2"));
+    }
+
+    #[test]
+    fn test_verify_against_profile() {
+        let db = FingerprintDb::builtin();
+        let chrome = db.get("chrome-120-windows").unwrap();
+        let firefox = db.get("firefox-121-windows").unwrap();
+
+        let hello = chrome
+            .client_hello_builder()
+            .server_name("example.com")
+            .build();
+        assert!(chrome.verify(&hello).is_ok());
+        assert!(firefox.verify(&hello).is_err());
+
+        let hello = firefox
+            .client_hello_builder()
+            .server_name("example.com")
+            .build();
+        assert!(firefox.verify(&hello).is_ok());
+    }
+}
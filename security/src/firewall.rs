@@ -0,0 +1,380 @@
+//! Firewall rule helper
+//! Generates and installs the nftables/iptables rules desync tricks rely on
+//! (TTL rewriting, fwmark routing, dropping kernel RSTs) and removes them again
+
+use crate::error::{Error, Result};
+use std::fmt;
+use std::process::Command;
+
+/// Name of the nftables table / iptables chain holding our rules
+const TABLE_NAME: &str = "iran_proxy";
+const IPTABLES_CHAIN: &str = "IRAN_PROXY";
+
+/// Firewall tooling to drive
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FirewallBackend {
+    #[default]
+    Nftables,
+    Iptables,
+}
+
+/// One rule the desync setup needs
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FirewallRule {
+    /// Rewrite the TTL of packets carrying `mark`, so fakes can be sent
+    /// through ordinary sockets and still expire before the server
+    RewriteTtl { mark: u32, ttl: u8 },
+    /// Route packets carrying `mark` through routing table `table`
+    RouteMark { mark: u32, table: u32 },
+    /// Drop outgoing RSTs from a source port range; the kernel sends these
+    /// for connections driven from raw sockets
+    DropOutgoingRst { src_ports: (u16, u16) },
+}
+
+/// External command making up part of a rule set
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RuleCommand {
+    pub program: String,
+    pub args: Vec<String>,
+}
+
+impl RuleCommand {
+    pub(crate) fn new(program: &str, args: &str) -> Self {
+        RuleCommand {
+            program: program.to_string(),
+            args: args.split_whitespace().map(str::to_string).collect(),
+        }
+    }
+}
+
+impl fmt::Display for RuleCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.program, self.args.join(" "))
+    }
+}
+
+/// Runs rule commands; swapped out in tests and dry runs
+pub trait CommandRunner: Send + Sync {
+    fn run(&self, command: &RuleCommand) -> Result<()>;
+}
+
+/// Runs commands on the host
+pub struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn run(&self, command: &RuleCommand) -> Result<()> {
+        let output = Command::new(&command.program)
+            .args(&command.args)
+            .output()?;
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(Error::ConfigError(format!(
+                "`{}` failed: {}",
+                command,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )))
+        }
+    }
+}
+
+/// Install and rollback commands for a set of rules
+#[derive(Clone, Debug, Default)]
+pub struct FirewallPlan {
+    pub backend: FirewallBackend,
+    pub rules: Vec<FirewallRule>,
+}
+
+impl FirewallPlan {
+    /// Create an empty plan for a backend
+    pub fn new(backend: FirewallBackend) -> Self {
+        FirewallPlan {
+            backend,
+            rules: Vec::new(),
+        }
+    }
+
+    /// Add a rule
+    pub fn rule(mut self, rule: FirewallRule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Commands installing the rules, in order
+    pub fn install_commands(&self) -> Vec<RuleCommand> {
+        let mut commands = Vec::new();
+        let mut packet_rules = Vec::new();
+
+        for rule in &self.rules {
+            match (rule, self.backend) {
+                (FirewallRule::RouteMark { mark, table }, _) => commands.push(RuleCommand::new(
+                    "ip",
+                    &format!("rule add fwmark {} lookup {}", mark, table),
+                )),
+                (FirewallRule::RewriteTtl { mark, ttl }, FirewallBackend::Nftables) => {
+                    packet_rules.push(format!("meta mark {} ip ttl set {}", mark, ttl))
+                }
+                (FirewallRule::RewriteTtl { mark, ttl }, FirewallBackend::Iptables) => {
+                    packet_rules.push(format!("-m mark --mark {} -j TTL --ttl-set {}", mark, ttl))
+                }
+                (FirewallRule::DropOutgoingRst { src_ports }, FirewallBackend::Nftables) => {
+                    packet_rules.push(format!(
+                        "tcp sport {}-{} tcp flags & rst == rst drop",
+                        src_ports.0, src_ports.1
+                    ))
+                }
+                (FirewallRule::DropOutgoingRst { src_ports }, FirewallBackend::Iptables) => {
+                    packet_rules.push(format!(
+                        "-p tcp --sport {}:{} --tcp-flags RST RST -j DROP",
+                        src_ports.0, src_ports.1
+                    ))
+                }
+            }
+        }
+
+        if packet_rules.is_empty() {
+            return commands;
+        }
+
+        // Packet rules live in their own table/chain so rollback can't touch
+        // anything else on the host
+        match self.backend {
+            FirewallBackend::Nftables => {
+                commands.push(RuleCommand::new(
+                    "nft",
+                    &format!("add table ip {}", TABLE_NAME),
+                ));
+                commands.push(RuleCommand::new(
+                    "nft",
+                    &format!(
+                        "add chain ip {} postrouting {{ type filter hook postrouting priority mangle ; }}",
+                        TABLE_NAME
+                    ),
+                ));
+                for rule in packet_rules {
+                    commands.push(RuleCommand::new(
+                        "nft",
+                        &format!("add rule ip {} postrouting {}", TABLE_NAME, rule),
+                    ));
+                }
+            }
+            FirewallBackend::Iptables => {
+                commands.push(RuleCommand::new(
+                    "iptables",
+                    &format!("-t mangle -N {}", IPTABLES_CHAIN),
+                ));
+                for rule in packet_rules {
+                    commands.push(RuleCommand::new(
+                        "iptables",
+                        &format!("-t mangle -A {} {}", IPTABLES_CHAIN, rule),
+                    ));
+                }
+                commands.push(RuleCommand::new(
+                    "iptables",
+                    &format!("-t mangle -A POSTROUTING -j {}", IPTABLES_CHAIN),
+                ));
+            }
+        }
+        commands
+    }
+
+    /// Commands removing everything `install_commands` adds, in order
+    pub fn rollback_commands(&self) -> Vec<RuleCommand> {
+        let mut commands = Vec::new();
+        let has_packet_rules = self
+            .rules
+            .iter()
+            .any(|rule| !matches!(rule, FirewallRule::RouteMark { .. }));
+
+        if has_packet_rules {
+            match self.backend {
+                FirewallBackend::Nftables => commands.push(RuleCommand::new(
+                    "nft",
+                    &format!("delete table ip {}", TABLE_NAME),
+                )),
+                FirewallBackend::Iptables => {
+                    for args in [
+                        format!("-t mangle -D POSTROUTING -j {}", IPTABLES_CHAIN),
+                        format!("-t mangle -F {}", IPTABLES_CHAIN),
+                        format!("-t mangle -X {}", IPTABLES_CHAIN),
+                    ] {
+                        commands.push(RuleCommand::new("iptables", &args));
+                    }
+                }
+            }
+        }
+
+        for rule in self.rules.iter().rev() {
+            if let FirewallRule::RouteMark { mark, table } = rule {
+                commands.push(RuleCommand::new(
+                    "ip",
+                    &format!("rule del fwmark {} lookup {}", mark, table),
+                ));
+            }
+        }
+        commands
+    }
+
+    /// Install the rules, rolling back if any command fails
+    pub fn install(self, runner: Box<dyn CommandRunner>) -> Result<FirewallGuard> {
+        for command in self.install_commands() {
+            if let Err(e) = runner.run(&command) {
+                // Undo whatever did get installed; failures here are expected
+                // for the parts that never made it in
+                for undo in self.rollback_commands() {
+                    let _ = runner.run(&undo);
+                }
+                return Err(e);
+            }
+        }
+        Ok(FirewallGuard {
+            plan: self,
+            runner,
+            active: true,
+        })
+    }
+}
+
+/// Installed rules; removed on `rollback` or drop
+pub struct FirewallGuard {
+    plan: FirewallPlan,
+    runner: Box<dyn CommandRunner>,
+    active: bool,
+}
+
+impl FirewallGuard {
+    /// Remove the rules, reporting the first failure
+    pub fn rollback(mut self) -> Result<()> {
+        self.remove()
+    }
+
+    fn remove(&mut self) -> Result<()> {
+        if !self.active {
+            return Ok(());
+        }
+        self.active = false;
+
+        let mut first_error = None;
+        for command in self.plan.rollback_commands() {
+            if let Err(e) = self.runner.run(&command) {
+                log::warn!("Firewall rollback step failed: {}", e);
+                first_error.get_or_insert(e);
+            }
+        }
+        first_error.map_or(Ok(()), Err)
+    }
+}
+
+impl Drop for FirewallGuard {
+    fn drop(&mut self) {
+        let _ = self.remove();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parking_lot::Mutex;
+    use std::sync::Arc;
+
+    /// Records commands and fails the ones containing `fail_on`
+    #[derive(Clone, Default)]
+    struct Recorder {
+        log: Arc<Mutex<Vec<String>>>,
+        fail_on: Option<&'static str>,
+    }
+
+    impl CommandRunner for Recorder {
+        fn run(&self, command: &RuleCommand) -> Result<()> {
+            let line = command.to_string();
+            self.log.lock().push(line.clone());
+            match self.fail_on {
+                Some(needle) if line.contains(needle) => {
+                    Err(Error::ConfigError("rejected".to_string()))
+                }
+                _ => Ok(()),
+            }
+        }
+    }
+
+    fn plan(backend: FirewallBackend) -> FirewallPlan {
+        FirewallPlan::new(backend)
+            .rule(FirewallRule::RouteMark {
+                mark: 0x1f,
+                table: 100,
+            })
+            .rule(FirewallRule::RewriteTtl { mark: 0x1f, ttl: 4 })
+            .rule(FirewallRule::DropOutgoingRst {
+                src_ports: (61000, 61999),
+            })
+    }
+
+    #[test]
+    fn test_nftables_commands() {
+        let install: Vec<String> = plan(FirewallBackend::Nftables)
+            .install_commands()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(install[0], "ip rule add fwmark 31 lookup 100");
+        assert_eq!(install[1], "nft add table ip iran_proxy");
+        assert!(install[3].ends_with("meta mark 31 ip ttl set 4"));
+        assert!(install[4].contains("tcp sport 61000-61999"));
+
+        let rollback: Vec<String> = plan(FirewallBackend::Nftables)
+            .rollback_commands()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            rollback,
+            vec![
+                "nft delete table ip iran_proxy",
+                "ip rule del fwmark 31 lookup 100"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_iptables_commands() {
+        let install: Vec<String> = plan(FirewallBackend::Iptables)
+            .install_commands()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert!(install.contains(
+            &"iptables -t mangle -A IRAN_PROXY -m mark --mark 31 -j TTL --ttl-set 4".to_string()
+        ));
+        assert_eq!(
+            install.last().unwrap(),
+            "iptables -t mangle -A POSTROUTING -j IRAN_PROXY"
+        );
+    }
+
+    #[test]
+    fn test_guard_rolls_back_on_drop() {
+        let recorder = Recorder::default();
+        let guard = plan(FirewallBackend::Nftables)
+            .install(Box::new(recorder.clone()))
+            .unwrap();
+        let installed = recorder.log.lock().len();
+        drop(guard);
+
+        let log = recorder.log.lock();
+        assert_eq!(log[installed], "nft delete table ip iran_proxy");
+        assert_eq!(log.len(), installed + 2);
+    }
+
+    #[test]
+    fn test_failed_install_is_undone() {
+        let recorder = Recorder {
+            fail_on: Some("ttl set"),
+            ..Default::default()
+        };
+        assert!(plan(FirewallBackend::Nftables)
+            .install(Box::new(recorder.clone()))
+            .is_err());
+        let log = recorder.log.lock();
+        assert_eq!(log.last().unwrap(), "ip rule del fwmark 31 lookup 100");
+    }
+}
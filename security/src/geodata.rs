@@ -0,0 +1,488 @@
+//! V2Ray geosite/geoip data files
+//! Reads the protobuf `geosite.dat` and `geoip.dat` lists the V2Ray and Xray
+//! communities maintain, so the policy engine can reuse their Iranian domain
+//! and address categorizations instead of hand-written rules
+
+use crate::error::{Error, Result};
+use crate::geoip::{GeoIpDb, NetworkInfo};
+use crate::policy::domain_matches;
+use crate::probe_guard::IpRange;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+
+/// How a geosite entry matches host names, as in V2Ray's `Domain.Type`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DomainRule {
+    /// The value appears anywhere in the host
+    Keyword(String),
+    /// A regular expression; not evaluated, so it never matches
+    Regex(String),
+    /// The domain and its subdomains
+    Domain(String),
+    /// Exactly this host
+    Full(String),
+}
+
+impl DomainRule {
+    pub fn matches(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.');
+        match self {
+            DomainRule::Keyword(keyword) => host
+                .to_ascii_lowercase()
+                .contains(&keyword.to_ascii_lowercase()),
+            DomainRule::Regex(_) => false,
+            DomainRule::Domain(domain) => domain_matches(domain, host),
+            DomainRule::Full(full) => host.eq_ignore_ascii_case(full),
+        }
+    }
+}
+
+/// One entry of a geosite category
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SiteDomain {
+    pub rule: DomainRule,
+    /// Attribute keys, e.g. `ads` for entries selected by `category@ads`
+    pub attributes: Vec<String>,
+}
+
+/// Domain categories from a `geosite.dat`, keyed by lower-case code
+#[derive(Clone, Debug, Default)]
+pub struct GeoSiteList {
+    sites: HashMap<String, Vec<SiteDomain>>,
+}
+
+impl GeoSiteList {
+    /// Decode a `geosite.dat` (a `GeoSiteList` protobuf message)
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let mut list = GeoSiteList::default();
+        let mut regexes = 0;
+        for field in Fields::new(data) {
+            let (1, Value::Bytes(site)) = field? else {
+                continue;
+            };
+            let mut code = String::new();
+            let mut domains = Vec::new();
+            for field in Fields::new(site) {
+                match field? {
+                    (1, Value::Bytes(value)) => code = text(value)?.to_ascii_lowercase(),
+                    (2, Value::Bytes(domain)) => {
+                        let domain = parse_domain(domain)?;
+                        regexes += matches!(domain.rule, DomainRule::Regex(_)) as usize;
+                        domains.push(domain);
+                    }
+                    _ => {}
+                }
+            }
+            if code.is_empty() {
+                return Err(Error::DataError("Geosite entry without a code".to_string()));
+            }
+            list.sites.entry(code).or_default().extend(domains);
+        }
+        if regexes > 0 {
+            log::debug!(
+                "Geosite regex rules are not evaluated; skipping {}",
+                regexes
+            );
+        }
+        Ok(list)
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        Self::parse(&std::fs::read(path)?)
+    }
+
+    /// Category codes, in no particular order
+    pub fn codes(&self) -> impl Iterator<Item = &str> {
+        self.sites.keys().map(String::as_str)
+    }
+
+    /// Entries selected by `code` or `code@attribute`
+    pub fn select(&self, selector: &str) -> Option<Vec<&SiteDomain>> {
+        let (code, attribute) = match selector.split_once('@') {
+            Some((code, attribute)) => (code, Some(attribute)),
+            None => (selector, None),
+        };
+        let domains = self.sites.get(&code.to_ascii_lowercase())?;
+        Some(
+            domains
+                .iter()
+                .filter(|domain| {
+                    attribute.is_none_or(|attribute| {
+                        domain
+                            .attributes
+                            .iter()
+                            .any(|key| key.eq_ignore_ascii_case(attribute))
+                    })
+                })
+                .collect(),
+        )
+    }
+
+    /// Whether `host` is in the category `code` or `code@attribute`
+    pub fn matches(&self, selector: &str, host: &str) -> bool {
+        self.select(selector)
+            .is_some_and(|domains| domains.iter().any(|domain| domain.rule.matches(host)))
+    }
+
+    pub fn len(&self) -> usize {
+        self.sites.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sites.is_empty()
+    }
+}
+
+/// Address lists from a `geoip.dat`, keyed by lower-case code
+#[derive(Clone, Debug, Default)]
+pub struct GeoIpList {
+    lists: HashMap<String, Vec<IpRange>>,
+}
+
+impl GeoIpList {
+    /// Decode a `geoip.dat` (a `GeoIPList` protobuf message)
+    ///
+    /// Entries marked `reverse_match` are rejected: they stand for every
+    /// address outside the list, which a country table cannot express.
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let mut list = GeoIpList::default();
+        for field in Fields::new(data) {
+            let (1, Value::Bytes(entry)) = field? else {
+                continue;
+            };
+            let mut code = String::new();
+            let mut ranges = Vec::new();
+            for field in Fields::new(entry) {
+                match field? {
+                    (1, Value::Bytes(value)) => code = text(value)?.to_ascii_lowercase(),
+                    (2, Value::Bytes(cidr)) => ranges.push(parse_cidr(cidr)?),
+                    (3, Value::Varint(reverse)) if reverse != 0 => {
+                        return Err(Error::DataError(
+                            "Reverse-match geoip entries are not supported".to_string(),
+                        ))
+                    }
+                    _ => {}
+                }
+            }
+            if code.is_empty() {
+                return Err(Error::DataError("Geoip entry without a code".to_string()));
+            }
+            list.lists.entry(code).or_default().extend(ranges);
+        }
+        Ok(list)
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        Self::parse(&std::fs::read(path)?)
+    }
+
+    pub fn codes(&self) -> impl Iterator<Item = &str> {
+        self.lists.keys().map(String::as_str)
+    }
+
+    /// Ranges listed under `code`, e.g. `ir` or `private`
+    pub fn ranges(&self, code: &str) -> Option<&[IpRange]> {
+        self.lists
+            .get(&code.to_ascii_lowercase())
+            .map(Vec::as_slice)
+    }
+
+    pub fn contains(&self, code: &str, ip: IpAddr) -> bool {
+        self.ranges(code)
+            .is_some_and(|ranges| ranges.iter().any(|range| range.contains(ip)))
+    }
+
+    /// A GeoIP table placing the ranges of a country code in that country,
+    /// to extend the bundled table with; AS numbers are left at 0
+    pub fn country_db(&self, code: &str) -> Option<GeoIpDb> {
+        let ranges = self.ranges(code)?;
+        let mut db = GeoIpDb::new();
+        for &range in ranges {
+            db.insert(NetworkInfo {
+                range,
+                asn: 0,
+                country: Some(code.to_ascii_uppercase()),
+            });
+        }
+        Some(db)
+    }
+
+    pub fn len(&self) -> usize {
+        self.lists.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lists.is_empty()
+    }
+}
+
+fn parse_domain(data: &[u8]) -> Result<SiteDomain> {
+    let mut kind = 0;
+    let mut value = String::new();
+    let mut attributes = Vec::new();
+    for field in Fields::new(data) {
+        match field? {
+            (1, Value::Varint(number)) => kind = number,
+            (2, Value::Bytes(bytes)) => value = text(bytes)?.to_string(),
+            (3, Value::Bytes(attribute)) => {
+                for field in Fields::new(attribute) {
+                    if let (1, Value::Bytes(key)) = field? {
+                        attributes.push(text(key)?.to_string());
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    let rule = match kind {
+        0 => DomainRule::Keyword(value),
+        1 => DomainRule::Regex(value),
+        2 => DomainRule::Domain(value),
+        3 => DomainRule::Full(value),
+        other => {
+            return Err(Error::DataError(format!(
+                "Unknown geosite domain type {}",
+                other
+            )))
+        }
+    };
+    Ok(SiteDomain { rule, attributes })
+}
+
+fn parse_cidr(data: &[u8]) -> Result<IpRange> {
+    let mut ip = None;
+    let mut prefix = 0;
+    for field in Fields::new(data) {
+        match field? {
+            (1, Value::Bytes(bytes)) => {
+                ip = Some(match bytes.len() {
+                    4 => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(bytes).unwrap())),
+                    16 => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(bytes).unwrap())),
+                    len => return Err(Error::DataError(format!("Geoip address of {} bytes", len))),
+                })
+            }
+            (2, Value::Varint(number)) => prefix = number,
+            _ => {}
+        }
+    }
+    let ip = ip.ok_or_else(|| Error::DataError("Geoip CIDR without an address".to_string()))?;
+    let prefix = u8::try_from(prefix)
+        .map_err(|_| Error::DataError(format!("Geoip prefix length {}", prefix)))?;
+    IpRange::new(ip, prefix).map_err(|e| Error::DataError(e.to_string()))
+}
+
+fn text(bytes: &[u8]) -> Result<&str> {
+    std::str::from_utf8(bytes)
+        .map_err(|_| Error::DataError("Geodata string is not UTF-8".to_string()))
+}
+
+/// A protobuf field value; fixed-width values are skipped
+enum Value<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    Fixed,
+}
+
+/// Iterator over the fields of a protobuf message
+struct Fields<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Fields<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Fields { data }
+    }
+
+    fn varint(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let (&byte, rest) = self.data.split_first().ok_or_else(truncated)?;
+            self.data = rest;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(Error::DataError("Geodata varint is too long".to_string()))
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.data.len() < len {
+            return Err(truncated());
+        }
+        let (value, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(value)
+    }
+
+    fn field(&mut self) -> Result<(u64, Value<'a>)> {
+        let key = self.varint()?;
+        let value = match key & 7 {
+            0 => Value::Varint(self.varint()?),
+            1 => {
+                self.take(8)?;
+                Value::Fixed
+            }
+            2 => {
+                let len = usize::try_from(self.varint()?).map_err(|_| truncated())?;
+                Value::Bytes(self.take(len)?)
+            }
+            5 => {
+                self.take(4)?;
+                Value::Fixed
+            }
+            wire_type => {
+                return Err(Error::DataError(format!(
+                    "Unsupported geodata wire type {}",
+                    wire_type
+                )))
+            }
+        };
+        Ok((key >> 3, value))
+    }
+}
+
+impl<'a> Iterator for Fields<'a> {
+    type Item = Result<(u64, Value<'a>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.is_empty() {
+            return None;
+        }
+        let field = self.field();
+        if field.is_err() {
+            // Stop after the first error
+            self.data = &[];
+        }
+        Some(field)
+    }
+}
+
+fn truncated() -> Error {
+    Error::DataError("Truncated geodata file".to_string())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    fn varint(out: &mut Vec<u8>, mut value: u64) {
+        while value >= 0x80 {
+            out.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        out.push(value as u8);
+    }
+
+    fn bytes_field(out: &mut Vec<u8>, number: u64, value: &[u8]) {
+        varint(out, number << 3 | 2);
+        varint(out, value.len() as u64);
+        out.extend_from_slice(value);
+    }
+
+    fn varint_field(out: &mut Vec<u8>, number: u64, value: u64) {
+        varint(out, number << 3);
+        varint(out, value);
+    }
+
+    /// A geosite category: its code and `(type, value, attributes)` entries
+    pub(crate) type Site<'a> = (&'a str, &'a [(u64, &'a str, &'a [&'a str])]);
+
+    pub(crate) fn geosite(sites: &[Site]) -> Vec<u8> {
+        let mut list = Vec::new();
+        for (code, domains) in sites {
+            let mut site = Vec::new();
+            bytes_field(&mut site, 1, code.to_uppercase().as_bytes());
+            for (kind, value, attributes) in domains.iter() {
+                let mut domain = Vec::new();
+                varint_field(&mut domain, 1, *kind);
+                bytes_field(&mut domain, 2, value.as_bytes());
+                for key in attributes.iter() {
+                    let mut attribute = Vec::new();
+                    bytes_field(&mut attribute, 1, key.as_bytes());
+                    varint_field(&mut attribute, 2, 1);
+                    bytes_field(&mut domain, 3, &attribute);
+                }
+                bytes_field(&mut site, 2, &domain);
+            }
+            bytes_field(&mut list, 1, &site);
+        }
+        list
+    }
+
+    pub(crate) fn geoip(code: &str, cidrs: &[(IpAddr, u64)], reverse: bool) -> Vec<u8> {
+        let mut entry = Vec::new();
+        bytes_field(&mut entry, 1, code.as_bytes());
+        for (ip, prefix) in cidrs {
+            let mut cidr = Vec::new();
+            match ip {
+                IpAddr::V4(ip) => bytes_field(&mut cidr, 1, &ip.octets()),
+                IpAddr::V6(ip) => bytes_field(&mut cidr, 1, &ip.octets()),
+            }
+            varint_field(&mut cidr, 2, *prefix);
+            bytes_field(&mut entry, 2, &cidr);
+        }
+        if reverse {
+            varint_field(&mut entry, 3, 1);
+        }
+        let mut list = Vec::new();
+        bytes_field(&mut list, 1, &entry);
+        list
+    }
+
+    #[test]
+    fn test_geosite() {
+        let data = geosite(&[
+            (
+                "category-ir",
+                &[
+                    (2, "digikala.com", &[]),
+                    (3, "www.aparat.com", &[]),
+                    (0, "snapp", &[]),
+                    (1, r"^.+\.ir$", &[]),
+                    (2, "tapsell.ir", &["ads"]),
+                ],
+            ),
+            ("google", &[(2, "google.com", &[])]),
+        ]);
+        let list = GeoSiteList::parse(&data).unwrap();
+        assert_eq!(list.len(), 2);
+        assert!(list.matches("category-ir", "api.digikala.com"));
+        assert!(list.matches("CATEGORY-IR", "www.aparat.com"));
+        assert!(!list.matches("category-ir", "cdn.aparat.com"));
+        assert!(list.matches("category-ir", "app.snappfood.example"));
+        // Regex entries are not evaluated
+        assert!(!list.matches("category-ir", "example.ir"));
+        assert!(list.matches("category-ir@ads", "x.tapsell.ir"));
+        assert!(!list.matches("category-ir@ads", "digikala.com"));
+        assert!(!list.matches("missing", "google.com"));
+        assert!(GeoSiteList::parse(&data[..data.len() - 3]).is_err());
+    }
+
+    #[test]
+    fn test_geoip() {
+        let data = geoip(
+            "IR",
+            &[
+                ("5.112.0.0".parse().unwrap(), 12),
+                ("2a01:5ec0::".parse().unwrap(), 29),
+            ],
+            false,
+        );
+        let list = GeoIpList::parse(&data).unwrap();
+        assert!(list.contains("ir", "5.120.1.1".parse().unwrap()));
+        assert!(list.contains("IR", "2a01:5ec0::1".parse().unwrap()));
+        assert!(!list.contains("ir", "8.8.8.8".parse().unwrap()));
+
+        let db = list.country_db("ir").unwrap();
+        assert_eq!(db.len(), 2);
+        assert!(db.is_domestic("5.120.1.1".parse().unwrap()));
+        assert!(list.country_db("cn").is_none());
+
+        let reverse = geoip("IR", &[("5.112.0.0".parse().unwrap(), 12)], true);
+        assert!(GeoIpList::parse(&reverse).is_err());
+        let invalid = geoip("IR", &[("5.112.0.0".parse().unwrap(), 40)], false);
+        assert!(GeoIpList::parse(&invalid).is_err());
+    }
+}
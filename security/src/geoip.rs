@@ -0,0 +1,281 @@
+//! GeoIP/ASN lookup
+//! Maps addresses to their autonomous system and country, so the crate can
+//! tell domestic from international destinations and which Iranian operator
+//! the client is on without every application bringing its own database
+
+use crate::error::{Error, Result};
+use crate::presets::IspPreset;
+use crate::probe_guard::IpRange;
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+
+/// Seed list of address blocks of the major Iranian operators, as
+/// `<cidr> <asn> <country>`; load a full ip2asn dump for complete coverage
+const BUNDLED: &str = "\
+2.176.0.0/12 58224 IR
+5.112.0.0/12 197207 IR
+5.208.0.0/12 44244 IR
+94.182.0.0/15 31549 IR
+";
+
+/// What the database knows about a network
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NetworkInfo {
+    pub range: IpRange,
+    pub asn: u32,
+    /// ISO 3166 country code, upper case
+    pub country: Option<String>,
+}
+
+impl NetworkInfo {
+    /// The Iranian operator announcing the network, if it is a major one
+    pub fn isp(&self) -> Option<IspPreset> {
+        IspPreset::from_asn(self.asn)
+    }
+
+    pub fn is_iranian(&self) -> bool {
+        self.country.as_deref() == Some("IR") || self.isp().is_some()
+    }
+}
+
+/// Longest-prefix-match table of networks
+#[derive(Clone, Debug, Default)]
+pub struct GeoIpDb {
+    /// Networks by prefix length, keyed by their masked address
+    tables: BTreeMap<u8, HashMap<IpAddr, NetworkInfo>>,
+    len: usize,
+}
+
+impl GeoIpDb {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The seed list shipped with the crate
+    pub fn bundled() -> Self {
+        Self::parse(BUNDLED).expect("bundled GeoIP list is valid")
+    }
+
+    /// Parse a database in either of two line formats
+    ///
+    /// - `<cidr> <asn> [<country>]`, whitespace separated
+    /// - the tab-separated ip2asn dump (`<first ip> <last ip> <asn>
+    ///   <country> <description>`), whose ranges are split into CIDR blocks
+    ///
+    /// Blank lines and `#` comments are skipped, as are unrouted ranges
+    /// (AS 0) of ip2asn dumps.
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut db = Self::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim_end_matches('\r');
+            if line.trim().is_empty() || line.trim_start().starts_with('#') {
+                continue;
+            }
+            let invalid =
+                || Error::DataError(format!("Invalid GeoIP line {}: {}", number + 1, line));
+            let country = |code: Option<&str>| {
+                code.filter(|code| !code.is_empty() && *code != "None")
+                    .map(|code| code.to_ascii_uppercase())
+            };
+
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() >= 3 && fields[0].parse::<IpAddr>().is_ok() {
+                let first: IpAddr = fields[0].trim().parse().map_err(|_| invalid())?;
+                let last: IpAddr = fields[1].trim().parse().map_err(|_| invalid())?;
+                let asn: u32 = fields[2].trim().parse().map_err(|_| invalid())?;
+                if asn == 0 {
+                    continue;
+                }
+                let country = country(fields.get(3).map(|code| code.trim()));
+                for range in cidr_blocks(first, last).ok_or_else(invalid)? {
+                    db.insert(NetworkInfo {
+                        range,
+                        asn,
+                        country: country.clone(),
+                    });
+                }
+            } else {
+                let mut fields = line.split_whitespace();
+                let range: IpRange = fields.next().ok_or_else(invalid)?.parse()?;
+                let asn = fields
+                    .next()
+                    .map(|asn| asn.trim_start_matches("AS"))
+                    .and_then(|asn| asn.parse().ok())
+                    .ok_or_else(invalid)?;
+                db.insert(NetworkInfo {
+                    range,
+                    asn,
+                    country: country(fields.next()),
+                });
+            }
+        }
+        Ok(db)
+    }
+
+    /// Read a database file in a format `parse` accepts
+    pub fn load(path: &Path) -> Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Add a network, replacing an entry for the same block
+    pub fn insert(&mut self, mut info: NetworkInfo) {
+        let prefix_len = info.range.prefix_len();
+        let network = masked(info.range.network(), prefix_len);
+        info.range = IpRange::new(network, prefix_len).expect("prefix length already checked");
+        let table = self.tables.entry(prefix_len).or_default();
+        if table.insert(network, info).is_none() {
+            self.len += 1;
+        }
+    }
+
+    /// Add every network of another database, its entries winning
+    pub fn extend(&mut self, other: GeoIpDb) {
+        for info in other.tables.into_values().flat_map(HashMap::into_values) {
+            self.insert(info);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The most specific network containing an address
+    pub fn lookup(&self, ip: IpAddr) -> Option<&NetworkInfo> {
+        let max = if ip.is_ipv4() { 32 } else { 128 };
+        self.tables
+            .range(..=max)
+            .rev()
+            .find_map(|(&prefix_len, table)| table.get(&masked(ip, prefix_len)))
+    }
+
+    /// Whether an address is in Iran, i.e. reachable without crossing the
+    /// international gateways where filtering happens
+    pub fn is_domestic(&self, ip: IpAddr) -> bool {
+        self.lookup(ip).is_some_and(NetworkInfo::is_iranian)
+    }
+
+    /// The operator an address belongs to, e.g. the client's own
+    pub fn isp(&self, ip: IpAddr) -> Option<IspPreset> {
+        self.lookup(ip).and_then(NetworkInfo::isp)
+    }
+}
+
+/// An address with the bits past `prefix_len` cleared
+fn masked(ip: IpAddr, prefix_len: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let mask = u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(ip) & mask))
+        }
+        IpAddr::V6(ip) => {
+            let mask = u128::MAX.checked_shl(128 - prefix_len as u32).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask))
+        }
+    }
+}
+
+/// The fewest CIDR blocks exactly covering `first..=last`; `None` if the
+/// bounds are of different families or out of order
+fn cidr_blocks(first: IpAddr, last: IpAddr) -> Option<Vec<IpRange>> {
+    let (start, end, bits, v4) = match (first, last) {
+        (IpAddr::V4(first), IpAddr::V4(last)) => {
+            (u32::from(first) as u128, u32::from(last) as u128, 32, true)
+        }
+        (IpAddr::V6(first), IpAddr::V6(last)) => (u128::from(first), u128::from(last), 128, false),
+        _ => return None,
+    };
+    if start > end {
+        return None;
+    }
+    // Highest offset within a block of 2^size addresses
+    let span = |size: u32| u128::MAX.checked_shr(128 - size).unwrap_or(0);
+
+    let mut blocks = Vec::new();
+    let mut current = start;
+    loop {
+        let mut size = current.trailing_zeros().min(bits);
+        while current.checked_add(span(size)).is_none_or(|top| top > end) {
+            size -= 1;
+        }
+        let network = if v4 {
+            IpAddr::V4(Ipv4Addr::from(current as u32))
+        } else {
+            IpAddr::V6(Ipv6Addr::from(current))
+        };
+        blocks.push(IpRange::new(network, (bits - size) as u8).ok()?);
+        match (current + span(size)).checked_add(1) {
+            Some(next) if next <= end => current = next,
+            _ => return Some(blocks),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_bundled_operators() {
+        let db = GeoIpDb::bundled();
+        assert_eq!(db.isp(ip("5.120.33.7")), Some(IspPreset::Mci));
+        assert_eq!(db.isp(ip("2.180.1.1")), Some(IspPreset::Tci));
+        assert!(db.is_domestic(ip("94.183.200.1")));
+        assert!(!db.is_domestic(ip("8.8.8.8")));
+        assert!(db.lookup(ip("2001:db8::1")).is_none());
+    }
+
+    #[test]
+    fn test_longest_prefix_wins() {
+        let db = GeoIpDb::parse(
+            "# operator block and a datacenter carved out of it\n\
+             10.0.0.0/8 AS64500 IR\n\
+             10.1.2.0/24 64501 de\n\
+             2001:db8::/32 64502\n",
+        )
+        .unwrap();
+        assert_eq!(db.len(), 3);
+        assert_eq!(db.lookup(ip("10.1.2.3")).unwrap().asn, 64501);
+        assert_eq!(
+            db.lookup(ip("10.1.2.3")).unwrap().country.as_deref(),
+            Some("DE")
+        );
+        assert!(db.is_domestic(ip("10.9.9.9")));
+        assert!(!db.is_domestic(ip("10.1.2.3")));
+        let v6 = db.lookup(ip("2001:db8:1::1")).unwrap();
+        assert_eq!(v6.range.to_string(), "2001:db8::/32");
+        assert!(GeoIpDb::parse("10.0.0.0/8 notanasn").is_err());
+    }
+
+    #[test]
+    fn test_ip2asn_ranges() {
+        let dump = "1.0.0.0\t1.0.0.255\t13335\tUS\tCLOUDFLARENET\n\
+                    1.0.1.0\t1.0.3.255\t0\tNone\tNot routed\n\
+                    5.52.0.0\t5.52.2.255\t197207\tIR\tMCCI-AS\n";
+        let db = GeoIpDb::parse(dump).unwrap();
+        // 5.52.0.0 - 5.52.2.255 is a /23 and a /24
+        assert_eq!(db.len(), 3);
+        assert_eq!(db.isp(ip("5.52.2.200")), Some(IspPreset::Mci));
+        assert!(db.lookup(ip("5.52.3.0")).is_none());
+        assert!(db.lookup(ip("1.0.2.1")).is_none());
+
+        let blocks = cidr_blocks(ip("0.0.0.0"), ip("255.255.255.255")).unwrap();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].prefix_len(), 0);
+        let blocks = cidr_blocks(ip("10.0.0.1"), ip("10.0.0.6")).unwrap();
+        let blocks: Vec<String> = blocks.iter().map(|b| b.to_string()).collect();
+        assert_eq!(
+            blocks,
+            ["10.0.0.1/32", "10.0.0.2/31", "10.0.0.4/31", "10.0.0.6/32"]
+        );
+        assert!(cidr_blocks(ip("10.0.0.2"), ip("10.0.0.1")).is_none());
+    }
+}
@@ -0,0 +1,521 @@
+//! gRPC framing transport
+//! Carries payload as length-prefixed gRPC messages on one bidirectional
+//! HTTP/2 stream, for CDN-fronted gRPC endpoints that survive filtering
+
+use crate::error::{Error, Result};
+use crate::h2_mimicry::{
+    connection_preface, encode_headers, Frame, FLAG_ACK, FLAG_END_HEADERS, FLAG_END_STREAM,
+    FRAME_DATA, FRAME_GOAWAY, FRAME_HEADERS, FRAME_PING, FRAME_RST_STREAM, FRAME_SETTINGS,
+    FRAME_WINDOW_UPDATE, SETTINGS_INITIAL_WINDOW_SIZE, SETTINGS_MAX_FRAME_SIZE,
+};
+use crate::udp_transport::PayloadTransform;
+use std::collections::VecDeque;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+/// gRPC message prefix: compressed flag and big-endian length
+const MESSAGE_PREFIX_LEN: usize = 5;
+/// Protobuf tag of field 1 with the length-delimited wire type
+const PROTO_FIELD_1_BYTES: u8 = 0x0a;
+
+/// HTTP/2 defaults until the server's SETTINGS say otherwise
+const DEFAULT_WINDOW: i64 = 65_535;
+const DEFAULT_PEER_FRAME_SIZE: usize = 16_384;
+
+/// Configuration for the gRPC transport
+#[derive(Clone, Debug)]
+pub struct GrpcConfig {
+    /// `:authority`, the gRPC host behind the CDN
+    pub authority: String,
+    /// Fully qualified service name; with the method it forms the path
+    pub service: String,
+    pub method: String,
+    pub user_agent: String,
+    /// Largest message accepted from the server
+    pub max_message_size: usize,
+}
+
+impl Default for GrpcConfig {
+    fn default() -> Self {
+        GrpcConfig {
+            authority: String::new(),
+            // A real bidirectional-streaming method with long-lived streams
+            service: "google.pubsub.v1.Subscriber".to_string(),
+            method: "StreamingPull".to_string(),
+            user_agent: "grpc-go/1.62.1".to_string(),
+            max_message_size: 4 * 1024 * 1024,
+        }
+    }
+}
+
+impl GrpcConfig {
+    pub fn path(&self) -> String {
+        format!("/{}/{}", self.service, self.method)
+    }
+}
+
+/// Protobuf varint
+fn encode_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn decode_varint(data: &[u8]) -> Option<(u64, usize)> {
+    let mut value = 0u64;
+    for (i, &b) in data.iter().enumerate().take(10) {
+        value |= u64::from(b & 0x7f) << (7 * i);
+        if b & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+/// Frame `payload` as one gRPC message
+///
+/// The payload is the `bytes` field 1 of a protobuf message, the layout of
+/// the `Hunk` message used by V2Ray/Xray gRPC ("gun") servers.
+pub fn encode_message(payload: &[u8]) -> Vec<u8> {
+    let mut proto = Vec::with_capacity(payload.len() + 11);
+    proto.push(PROTO_FIELD_1_BYTES);
+    encode_varint(&mut proto, payload.len() as u64);
+    proto.extend_from_slice(payload);
+
+    let mut out = Vec::with_capacity(MESSAGE_PREFIX_LEN + proto.len());
+    out.push(0);
+    out.extend_from_slice(&(proto.len() as u32).to_be_bytes());
+    out.extend(proto);
+    out
+}
+
+/// Take the first complete message off `buf`, returning its payload
+pub fn decode_message(buf: &mut Vec<u8>, max_size: usize) -> Result<Option<Vec<u8>>> {
+    if buf.len() < MESSAGE_PREFIX_LEN {
+        return Ok(None);
+    }
+    if buf[0] != 0 {
+        return Err(Error::DataError(
+            "Compressed gRPC messages are not supported".to_string(),
+        ));
+    }
+    let len = u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]) as usize;
+    if len > max_size {
+        return Err(Error::DataError(format!(
+            "gRPC message of {} bytes exceeds the limit",
+            len
+        )));
+    }
+    if buf.len() < MESSAGE_PREFIX_LEN + len {
+        return Ok(None);
+    }
+
+    let proto: Vec<u8> = buf
+        .drain(..MESSAGE_PREFIX_LEN + len)
+        .skip(MESSAGE_PREFIX_LEN)
+        .collect();
+    let malformed = || Error::DataError("Malformed gRPC message".to_string());
+    if proto.is_empty() {
+        return Ok(Some(Vec::new()));
+    }
+    if proto[0] != PROTO_FIELD_1_BYTES {
+        return Err(malformed());
+    }
+    let (field_len, varint_len) = decode_varint(&proto[1..]).ok_or_else(malformed)?;
+    let start = 1 + varint_len;
+    proto
+        .get(start..start + field_len as usize)
+        .map(|payload| Some(payload.to_vec()))
+        .ok_or_else(malformed)
+}
+
+/// One gRPC call carrying a stream of messages each way
+///
+/// The client side of a single bidirectional-streaming call on stream 1.
+/// Send flow control follows the server's SETTINGS and WINDOW_UPDATE
+/// frames; received DATA is acknowledged with WINDOW_UPDATE right away.
+pub struct GrpcTransport<S> {
+    io: S,
+    config: GrpcConfig,
+    transform: Option<Arc<dyn PayloadTransform>>,
+    stream_id: u32,
+    conn_window: i64,
+    stream_window: i64,
+    peer_initial_window: i64,
+    peer_frame_size: usize,
+    read_buf: Vec<u8>,
+    message_buf: Vec<u8>,
+    messages: VecDeque<Vec<u8>>,
+    closed: bool,
+}
+
+impl GrpcTransport<TlsStream<TcpStream>> {
+    /// Open a TLS connection negotiating h2 and start the call
+    ///
+    /// `sni` is the name presented in the handshake, e.g. a CDN front;
+    /// the `:authority` from the config names the real endpoint.
+    pub async fn connect_tls(addr: SocketAddr, sni: &str, config: GrpcConfig) -> Result<Self> {
+        let mut roots = RootCertStore::empty();
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        let mut tls_config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        tls_config.alpn_protocols = vec![b"h2".to_vec()];
+
+        let server_name = ServerName::try_from(sni.to_string())
+            .map_err(|e| Error::ConfigError(format!("Invalid server name {}: {}", sni, e)))?;
+        let tcp = TcpStream::connect(addr).await?;
+        let tls = TlsConnector::from(Arc::new(tls_config))
+            .connect(server_name, tcp)
+            .await?;
+        if tls.get_ref().1.alpn_protocol() != Some(b"h2") {
+            return Err(Error::DPIBypassError(format!(
+                "{} did not negotiate HTTP/2",
+                sni
+            )));
+        }
+        Self::start(tls, config).await
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> GrpcTransport<S> {
+    /// Send the connection preface and the call's HEADERS over `io`
+    pub async fn start(mut io: S, config: GrpcConfig) -> Result<Self> {
+        if config.authority.is_empty() {
+            return Err(Error::ConfigError(
+                "gRPC transport needs an authority".to_string(),
+            ));
+        }
+
+        let stream_id = 1;
+        let path = config.path();
+        let block = encode_headers(&[
+            (":method", "POST"),
+            (":scheme", "https"),
+            (":path", &path),
+            (":authority", &config.authority),
+            ("content-type", "application/grpc"),
+            ("user-agent", &config.user_agent),
+            ("te", "trailers"),
+        ]);
+        let mut out = connection_preface();
+        out.extend(Frame::new(FRAME_HEADERS, FLAG_END_HEADERS, stream_id, block).to_bytes());
+        io.write_all(&out).await?;
+        io.flush().await?;
+
+        Ok(GrpcTransport {
+            io,
+            config,
+            transform: None,
+            stream_id,
+            conn_window: DEFAULT_WINDOW,
+            stream_window: DEFAULT_WINDOW,
+            peer_initial_window: DEFAULT_WINDOW,
+            peer_frame_size: DEFAULT_PEER_FRAME_SIZE,
+            read_buf: Vec::new(),
+            message_buf: Vec::new(),
+            messages: VecDeque::new(),
+            closed: false,
+        })
+    }
+
+    /// Apply `transform` to every message sent and received
+    pub fn with_transform(mut self, transform: Arc<dyn PayloadTransform>) -> Self {
+        self.transform = Some(transform);
+        self
+    }
+
+    pub fn config(&self) -> &GrpcConfig {
+        &self.config
+    }
+
+    /// Send `payload` as one gRPC message
+    pub async fn send(&mut self, payload: &[u8]) -> Result<()> {
+        let payload = match &self.transform {
+            Some(transform) => transform.encode(payload)?,
+            None => payload.to_vec(),
+        };
+        let message = encode_message(&payload);
+
+        let mut offset = 0;
+        while offset < message.len() {
+            while self.conn_window <= 0 || self.stream_window <= 0 {
+                if self.closed {
+                    return Err(Error::IoError(ErrorKind::BrokenPipe.into()));
+                }
+                self.read_frame().await?;
+            }
+            let window = self.conn_window.min(self.stream_window) as usize;
+            let len = (message.len() - offset)
+                .min(window)
+                .min(self.peer_frame_size);
+            let data = message[offset..offset + len].to_vec();
+            self.io
+                .write_all(&Frame::new(FRAME_DATA, 0, self.stream_id, data).to_bytes())
+                .await?;
+            self.conn_window -= len as i64;
+            self.stream_window -= len as i64;
+            offset += len;
+        }
+        self.io.flush().await?;
+        Ok(())
+    }
+
+    /// Receive the next gRPC message
+    pub async fn recv(&mut self) -> Result<Vec<u8>> {
+        loop {
+            if let Some(message) = self.messages.pop_front() {
+                return match &self.transform {
+                    Some(transform) => transform.decode(&message),
+                    None => Ok(message),
+                };
+            }
+            if self.closed {
+                return Err(Error::IoError(ErrorKind::UnexpectedEof.into()));
+            }
+            self.read_frame().await?;
+        }
+    }
+
+    /// Half-close the call; the server's remaining messages can still be read
+    pub async fn finish(&mut self) -> Result<()> {
+        let frame = Frame::new(FRAME_DATA, FLAG_END_STREAM, self.stream_id, Vec::new());
+        self.io.write_all(&frame.to_bytes()).await?;
+        self.io.flush().await?;
+        Ok(())
+    }
+
+    /// Read and handle one frame from the server
+    async fn read_frame(&mut self) -> Result<()> {
+        let frame = loop {
+            if let Some((frame, len)) = Frame::parse(&self.read_buf) {
+                self.read_buf.drain(..len);
+                break frame;
+            }
+            let mut chunk = [0u8; 16384];
+            let n = self.io.read(&mut chunk).await?;
+            if n == 0 {
+                self.closed = true;
+                return Err(Error::IoError(ErrorKind::UnexpectedEof.into()));
+            }
+            self.read_buf.extend_from_slice(&chunk[..n]);
+        };
+
+        match frame.kind {
+            FRAME_SETTINGS if frame.flags & FLAG_ACK == 0 => {
+                for setting in frame.payload.chunks_exact(6) {
+                    let id = u16::from_be_bytes([setting[0], setting[1]]);
+                    let value =
+                        u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+                    match id {
+                        SETTINGS_INITIAL_WINDOW_SIZE => {
+                            self.stream_window += value as i64 - self.peer_initial_window;
+                            self.peer_initial_window = value as i64;
+                        }
+                        SETTINGS_MAX_FRAME_SIZE => self.peer_frame_size = value as usize,
+                        _ => {}
+                    }
+                }
+                self.write_frame(Frame::new(FRAME_SETTINGS, FLAG_ACK, 0, Vec::new()))
+                    .await?;
+            }
+            FRAME_PING if frame.flags & FLAG_ACK == 0 => {
+                self.write_frame(Frame::new(FRAME_PING, FLAG_ACK, 0, frame.payload))
+                    .await?;
+            }
+            FRAME_WINDOW_UPDATE if frame.payload.len() == 4 => {
+                let increment = u32::from_be_bytes([
+                    frame.payload[0],
+                    frame.payload[1],
+                    frame.payload[2],
+                    frame.payload[3],
+                ]) & 0x7fff_ffff;
+                if frame.stream_id == 0 {
+                    self.conn_window += increment as i64;
+                } else if frame.stream_id == self.stream_id {
+                    self.stream_window += increment as i64;
+                }
+            }
+            FRAME_DATA if frame.stream_id == self.stream_id => {
+                self.message_buf.extend_from_slice(frame.data()?);
+                while let Some(message) =
+                    decode_message(&mut self.message_buf, self.config.max_message_size)?
+                {
+                    self.messages.push_back(message);
+                }
+                if !frame.payload.is_empty() {
+                    self.acknowledge(frame.payload.len() as u32).await?;
+                }
+                if frame.flags & FLAG_END_STREAM != 0 {
+                    self.closed = true;
+                }
+            }
+            // Response headers carry no END_STREAM; trailers always do
+            FRAME_HEADERS
+                if frame.stream_id == self.stream_id && frame.flags & FLAG_END_STREAM != 0 =>
+            {
+                self.closed = true;
+            }
+            FRAME_RST_STREAM if frame.stream_id == self.stream_id => {
+                self.closed = true;
+                return Err(Error::IoError(ErrorKind::ConnectionReset.into()));
+            }
+            FRAME_GOAWAY => {
+                self.closed = true;
+                return Err(Error::IoError(ErrorKind::ConnectionAborted.into()));
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Return received bytes to both flow-control windows
+    async fn acknowledge(&mut self, len: u32) -> Result<()> {
+        let increment = len.to_be_bytes().to_vec();
+        let mut out = Frame::new(FRAME_WINDOW_UPDATE, 0, 0, increment.clone()).to_bytes();
+        out.extend(Frame::new(FRAME_WINDOW_UPDATE, 0, self.stream_id, increment).to_bytes());
+        self.io.write_all(&out).await?;
+        self.io.flush().await?;
+        Ok(())
+    }
+
+    async fn write_frame(&mut self, frame: Frame) -> Result<()> {
+        self.io.write_all(&frame.to_bytes()).await?;
+        self.io.flush().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::h2_mimicry::{parse_frames, CLIENT_PREFACE};
+    use tokio::io::DuplexStream;
+
+    fn config() -> GrpcConfig {
+        GrpcConfig {
+            authority: "grpc.example.org".to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Read from the server end until `count` frames after the preface
+    async fn read_frames(server: &mut DuplexStream, buf: &mut Vec<u8>, count: usize) -> Vec<Frame> {
+        loop {
+            let data = buf.strip_prefix(CLIENT_PREFACE).unwrap_or(buf);
+            let mut frames = Vec::new();
+            let mut offset = 0;
+            while let Some((frame, len)) = Frame::parse(&data[offset..]) {
+                frames.push(frame);
+                offset += len;
+            }
+            if frames.len() >= count {
+                return frames;
+            }
+            let mut chunk = [0u8; 4096];
+            let n = server.read(&mut chunk).await.unwrap();
+            assert!(n > 0);
+            buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    #[test]
+    fn test_message_framing() {
+        let mut buf = encode_message(b"hello");
+        assert_eq!(&buf[..5], &[0, 0, 0, 0, 7]);
+        buf.extend(encode_message(&vec![7u8; 300]));
+        let partial = buf.split_off(buf.len() - 10);
+
+        assert_eq!(decode_message(&mut buf, 1024).unwrap().unwrap(), b"hello");
+        assert_eq!(decode_message(&mut buf, 1024).unwrap(), None);
+        buf.extend(partial);
+        assert_eq!(
+            decode_message(&mut buf, 1024).unwrap().unwrap(),
+            vec![7u8; 300]
+        );
+        assert!(buf.is_empty());
+
+        let mut big = encode_message(&vec![0u8; 2048]);
+        assert!(decode_message(&mut big, 1024).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_call_over_duplex() {
+        let (client_io, mut server) = tokio::io::duplex(1 << 20);
+        let mut client = GrpcTransport::start(client_io, config()).await.unwrap();
+
+        let mut buf = Vec::new();
+        let opening = read_frames(&mut server, &mut buf, 3).await;
+        assert_eq!(opening[0].kind, FRAME_SETTINGS);
+        assert_eq!(opening[2].kind, FRAME_HEADERS);
+        assert!(opening[2]
+            .payload
+            .windows(b"/google.pubsub.v1.Subscriber/StreamingPull".len())
+            .any(|w| w == b"/google.pubsub.v1.Subscriber/StreamingPull"));
+
+        // Server SETTINGS, then one message split across two DATA frames
+        let message = encode_message(b"from server");
+        let mut reply = Frame::new(FRAME_SETTINGS, 0, 0, Vec::new()).to_bytes();
+        reply.extend(Frame::new(FRAME_DATA, 0, 1, message[..4].to_vec()).to_bytes());
+        reply.extend(Frame::new(FRAME_DATA, 0, 1, message[4..].to_vec()).to_bytes());
+        server.write_all(&reply).await.unwrap();
+        assert_eq!(client.recv().await.unwrap(), b"from server");
+
+        client.send(b"from client").await.unwrap();
+        let frames = read_frames(&mut server, &mut buf, 9).await;
+        assert_eq!(frames[3].kind, FRAME_SETTINGS);
+        assert_eq!(frames[3].flags, FLAG_ACK);
+        assert!(frames[4..8].iter().all(|f| f.kind == FRAME_WINDOW_UPDATE));
+        let mut data = frames[8].payload.clone();
+        assert_eq!(
+            decode_message(&mut data, 1024).unwrap().unwrap(),
+            b"from client"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_waits_for_window() {
+        let (client_io, mut server) = tokio::io::duplex(1 << 20);
+        let mut client = GrpcTransport::start(client_io, config()).await.unwrap();
+        let mut buf = Vec::new();
+        read_frames(&mut server, &mut buf, 3).await;
+
+        // The default 65535-byte windows hold back the rest of the message
+        let payload = vec![9u8; 70_000];
+        let sender = tokio::spawn(async move {
+            client.send(&payload).await.unwrap();
+            client
+        });
+        let frames = read_frames(&mut server, &mut buf, 3 + 4).await;
+        let sent: usize = frames[3..].iter().map(|f| f.payload.len()).sum();
+        assert_eq!(sent, DEFAULT_WINDOW as usize);
+        assert!(!sender.is_finished());
+
+        for stream_id in [0, 1] {
+            let update = Frame::new(FRAME_WINDOW_UPDATE, 0, stream_id, vec![0, 1, 0, 0]);
+            server.write_all(&update.to_bytes()).await.unwrap();
+        }
+        sender.await.unwrap();
+
+        let frames = read_frames(&mut server, &mut buf, 3 + 5).await;
+        let mut message: Vec<u8> = frames[3..]
+            .iter()
+            .filter(|f| f.kind == FRAME_DATA)
+            .flat_map(|f| f.payload.clone())
+            .collect();
+        assert_eq!(
+            decode_message(&mut message, 1 << 20).unwrap().unwrap(),
+            vec![9u8; 70_000]
+        );
+        assert!(parse_frames(&buf[CLIENT_PREFACE.len()..]).is_ok());
+    }
+}
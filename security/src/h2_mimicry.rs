@@ -0,0 +1,442 @@
+//! HTTP/2 frame mimicry
+//! Wraps payload in syntactically valid HTTP/2 frames behind a browser-like
+//! connection preface, since HTTP/1.1-shaped traffic inside TLS is
+//! increasingly anomalous
+
+use crate::error::{Error, Result};
+use crate::obfuscation::USER_AGENTS;
+use crate::sni_pool::SniPool;
+use crate::udp_transport::PayloadTransform;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+pub const CLIENT_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+pub const FRAME_HEADER_LEN: usize = 9;
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16384;
+const MAX_FRAME_SIZE_LIMIT: usize = (1 << 24) - 1;
+
+pub const FRAME_DATA: u8 = 0x0;
+pub const FRAME_HEADERS: u8 = 0x1;
+pub const FRAME_RST_STREAM: u8 = 0x3;
+pub const FRAME_SETTINGS: u8 = 0x4;
+pub const FRAME_PING: u8 = 0x6;
+pub const FRAME_GOAWAY: u8 = 0x7;
+pub const FRAME_WINDOW_UPDATE: u8 = 0x8;
+
+pub const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+pub const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+
+pub const FLAG_END_STREAM: u8 = 0x1;
+pub const FLAG_ACK: u8 = 0x1;
+pub const FLAG_END_HEADERS: u8 = 0x4;
+pub const FLAG_PADDED: u8 = 0x8;
+
+/// SETTINGS Chrome sends: HEADER_TABLE_SIZE, ENABLE_PUSH,
+/// INITIAL_WINDOW_SIZE, MAX_HEADER_LIST_SIZE
+const CHROME_SETTINGS: &[(u16, u32)] = &[(1, 65536), (2, 0), (4, 6_291_456), (6, 262_144)];
+/// Connection-level WINDOW_UPDATE increment Chrome sends after SETTINGS
+const CHROME_WINDOW_INCREMENT: u32 = 15_663_105;
+
+/// HPACK static table entries (RFC 7541 Appendix A) used by the encoder
+const STATIC_TABLE: &[(usize, &str, &str)] = &[
+    (1, ":authority", ""),
+    (2, ":method", "GET"),
+    (3, ":method", "POST"),
+    (4, ":path", "/"),
+    (7, ":scheme", "https"),
+    (8, ":status", "200"),
+    (16, "accept-encoding", "gzip, deflate"),
+    (17, "accept-language", ""),
+    (19, "accept", ""),
+    (28, "content-length", ""),
+    (31, "content-type", ""),
+    (32, "cookie", ""),
+    (51, "referer", ""),
+    (58, "user-agent", ""),
+];
+
+/// One HTTP/2 frame
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub kind: u8,
+    pub flags: u8,
+    pub stream_id: u32,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(kind: u8, flags: u8, stream_id: u32, payload: Vec<u8>) -> Self {
+        Frame {
+            kind,
+            flags,
+            stream_id,
+            payload,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let len = self.payload.len() as u32;
+        let mut out = Vec::with_capacity(FRAME_HEADER_LEN + self.payload.len());
+        out.extend_from_slice(&len.to_be_bytes()[1..]);
+        out.push(self.kind);
+        out.push(self.flags);
+        out.extend_from_slice(&(self.stream_id & 0x7fff_ffff).to_be_bytes());
+        out.extend_from_slice(&self.payload);
+        out
+    }
+
+    /// Parse the frame at the start of `data` and the bytes it spans;
+    /// `None` when the frame is still incomplete
+    pub fn parse(data: &[u8]) -> Option<(Frame, usize)> {
+        let header = data.get(..FRAME_HEADER_LEN)?;
+        let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+        let payload = data.get(FRAME_HEADER_LEN..FRAME_HEADER_LEN + len)?;
+        let stream_id = u32::from_be_bytes([header[5], header[6], header[7], header[8]]);
+        Some((
+            Frame::new(
+                header[3],
+                header[4],
+                stream_id & 0x7fff_ffff,
+                payload.to_vec(),
+            ),
+            FRAME_HEADER_LEN + len,
+        ))
+    }
+
+    /// DATA payload with any padding removed
+    pub fn data(&self) -> Result<&[u8]> {
+        if self.flags & FLAG_PADDED == 0 {
+            return Ok(&self.payload);
+        }
+        let pad = *self.payload.first().unwrap_or(&0) as usize;
+        if self.payload.is_empty() || pad >= self.payload.len() {
+            return Err(Error::DataError("Invalid HTTP/2 padding".to_string()));
+        }
+        Ok(&self.payload[1..self.payload.len() - pad])
+    }
+}
+
+/// Parse every complete frame in `data`, failing on a trailing partial one
+pub fn parse_frames(data: &[u8]) -> Result<Vec<Frame>> {
+    let mut frames = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        let (frame, len) = Frame::parse(&data[offset..])
+            .ok_or_else(|| Error::DataError("Truncated HTTP/2 frame".to_string()))?;
+        frames.push(frame);
+        offset += len;
+    }
+    Ok(frames)
+}
+
+/// HPACK integer with an `prefix_bits`-bit prefix, OR-ed into `first`
+fn encode_int(out: &mut Vec<u8>, mut value: usize, prefix_bits: u8, first: u8) {
+    let max = (1usize << prefix_bits) - 1;
+    if value < max {
+        out.push(first | value as u8);
+        return;
+    }
+    out.push(first | max as u8);
+    value -= max;
+    while value >= 0x80 {
+        out.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn encode_string(out: &mut Vec<u8>, value: &str) {
+    encode_int(out, value.len(), 7, 0);
+    out.extend_from_slice(value.as_bytes());
+}
+
+/// HPACK-encode a header list
+///
+/// Exact static-table matches are indexed; everything else is a literal
+/// without indexing, with a static-table name where one exists, so the
+/// block decodes without any dynamic-table state.
+pub fn encode_headers(headers: &[(&str, &str)]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for &(name, value) in headers {
+        if let Some(&(index, _, _)) = STATIC_TABLE
+            .iter()
+            .find(|&&(_, n, v)| n == name && v == value && !v.is_empty())
+        {
+            encode_int(&mut out, index, 7, 0x80);
+            continue;
+        }
+        match STATIC_TABLE.iter().find(|&&(_, n, _)| n == name) {
+            Some(&(index, _, _)) => encode_int(&mut out, index, 4, 0),
+            None => {
+                out.push(0);
+                encode_string(&mut out, name);
+            }
+        }
+        encode_string(&mut out, value);
+    }
+    out
+}
+
+/// Client preface, SETTINGS and WINDOW_UPDATE as a browser opens with
+pub fn connection_preface() -> Vec<u8> {
+    let mut settings = Vec::with_capacity(CHROME_SETTINGS.len() * 6);
+    for &(id, value) in CHROME_SETTINGS {
+        settings.extend_from_slice(&id.to_be_bytes());
+        settings.extend_from_slice(&value.to_be_bytes());
+    }
+
+    let mut out = CLIENT_PREFACE.to_vec();
+    out.extend(Frame::new(FRAME_SETTINGS, 0, 0, settings).to_bytes());
+    out.extend(
+        Frame::new(
+            FRAME_WINDOW_UPDATE,
+            0,
+            0,
+            CHROME_WINDOW_INCREMENT.to_be_bytes().to_vec(),
+        )
+        .to_bytes(),
+    );
+    out
+}
+
+/// Cut `payload` into DATA frames on `stream_id`, the last one ending the
+/// stream; each frame gets up to `max_padding` bytes of padding
+pub fn data_frames(
+    payload: &[u8],
+    stream_id: u32,
+    max_frame_size: usize,
+    max_padding: u8,
+    end_stream: bool,
+) -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let chunk_size = max_frame_size - 1 - max_padding as usize;
+    let mut chunks: Vec<&[u8]> = payload.chunks(chunk_size).collect();
+    if chunks.is_empty() {
+        chunks.push(&[]);
+    }
+
+    let last = chunks.len() - 1;
+    let mut out = Vec::with_capacity(payload.len() + chunks.len() * (FRAME_HEADER_LEN + 1));
+    for (i, chunk) in chunks.into_iter().enumerate() {
+        let mut flags = 0;
+        if end_stream && i == last {
+            flags |= FLAG_END_STREAM;
+        }
+        let body = if max_padding > 0 {
+            flags |= FLAG_PADDED;
+            let pad = rng.gen_range(0..=max_padding);
+            let mut body = Vec::with_capacity(1 + chunk.len() + pad as usize);
+            body.push(pad);
+            body.extend_from_slice(chunk);
+            body.resize(body.len() + pad as usize, 0);
+            body
+        } else {
+            chunk.to_vec()
+        };
+        out.extend(Frame::new(FRAME_DATA, flags, stream_id, body).to_bytes());
+    }
+    out
+}
+
+/// Configuration for the HTTP/2 camouflage profile
+#[derive(Clone, Debug)]
+pub struct H2MimicryConfig {
+    /// `:authority` of the requests; drawn from the SNI pool when unset
+    pub authority: Option<String>,
+    pub path: String,
+    pub content_type: String,
+    pub max_frame_size: usize,
+    /// Upper bound on the random padding of each DATA frame
+    pub max_padding: u8,
+}
+
+impl Default for H2MimicryConfig {
+    fn default() -> Self {
+        H2MimicryConfig {
+            authority: None,
+            path: "/api/v1/sync".to_string(),
+            content_type: "application/octet-stream".to_string(),
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            max_padding: 64,
+        }
+    }
+}
+
+/// HTTP/2 camouflage: every payload is one POST stream
+///
+/// The first message carries the connection preface; each one after opens
+/// the next client stream with a HEADERS frame and carries the payload in
+/// padded DATA frames. Decoding skips everything but DATA, so the peer can
+/// interleave its own SETTINGS or WINDOW_UPDATE frames freely.
+pub struct H2Mimicry {
+    config: H2MimicryConfig,
+    authority: String,
+    user_agent: &'static str,
+    preface_sent: AtomicBool,
+    next_stream: AtomicU32,
+}
+
+impl H2Mimicry {
+    pub fn new(config: H2MimicryConfig) -> Result<Self> {
+        if !(DEFAULT_MAX_FRAME_SIZE..=MAX_FRAME_SIZE_LIMIT).contains(&config.max_frame_size) {
+            return Err(Error::ConfigError(format!(
+                "HTTP/2 max frame size {} out of range",
+                config.max_frame_size
+            )));
+        }
+        if !config.path.starts_with('/') {
+            return Err(Error::ConfigError(
+                "HTTP/2 path must start with '/'".to_string(),
+            ));
+        }
+
+        let authority = match &config.authority {
+            Some(authority) => authority.clone(),
+            None => SniPool::builtin()
+                .choose()
+                .unwrap_or_else(|| "example.com".to_string()),
+        };
+        Ok(H2Mimicry {
+            authority,
+            user_agent: USER_AGENTS.choose(&mut rand::thread_rng()).unwrap(),
+            preface_sent: AtomicBool::new(false),
+            next_stream: AtomicU32::new(1),
+            config,
+        })
+    }
+
+    pub fn config(&self) -> &H2MimicryConfig {
+        &self.config
+    }
+
+    pub fn authority(&self) -> &str {
+        &self.authority
+    }
+
+    /// Wrap `payload` in a request stream for the configured authority
+    pub fn encode(&self, payload: &[u8]) -> Vec<u8> {
+        self.encode_for(payload, &self.authority)
+    }
+
+    /// Wrap `payload` in a request stream with an explicit `:authority`
+    pub fn encode_for(&self, payload: &[u8], authority: &str) -> Vec<u8> {
+        let mut out = if self.preface_sent.swap(true, Ordering::Relaxed) {
+            Vec::new()
+        } else {
+            connection_preface()
+        };
+        let stream_id = self.next_stream.fetch_add(2, Ordering::Relaxed) & 0x7fff_ffff | 1;
+
+        let content_length = payload.len().to_string();
+        let block = encode_headers(&[
+            (":method", "POST"),
+            (":authority", authority),
+            (":scheme", "https"),
+            (":path", &self.config.path),
+            ("content-length", &content_length),
+            ("content-type", &self.config.content_type),
+            ("user-agent", self.user_agent),
+            ("accept", "*/*"),
+        ]);
+        out.extend(Frame::new(FRAME_HEADERS, FLAG_END_HEADERS, stream_id, block).to_bytes());
+        out.extend(data_frames(
+            payload,
+            stream_id,
+            self.config.max_frame_size,
+            self.config.max_padding,
+            true,
+        ));
+        out
+    }
+
+    /// Recover the payload carried in the DATA frames of `data`
+    pub fn decode(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let data = data.strip_prefix(CLIENT_PREFACE).unwrap_or(data);
+        let mut payload = Vec::with_capacity(data.len());
+        for frame in parse_frames(data)? {
+            if frame.kind == FRAME_DATA {
+                payload.extend_from_slice(frame.data()?);
+            }
+        }
+        Ok(payload)
+    }
+}
+
+impl PayloadTransform for H2Mimicry {
+    fn encode(&self, payload: &[u8]) -> Result<Vec<u8>> {
+        Ok(H2Mimicry::encode(self, payload))
+    }
+
+    fn decode(&self, datagram: &[u8]) -> Result<Vec<u8>> {
+        H2Mimicry::decode(self, datagram)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mimicry() -> H2Mimicry {
+        H2Mimicry::new(H2MimicryConfig {
+            authority: Some("cdn.example.org".to_string()),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_round_trip_across_frames() {
+        let h2 = mimicry();
+        let payload: Vec<u8> = (0..40_000u32).map(|i| i as u8).collect();
+
+        let first = h2.encode(&payload);
+        assert!(first.starts_with(CLIENT_PREFACE));
+        assert_eq!(h2.decode(&first).unwrap(), payload);
+
+        let second = h2.encode(b"short");
+        assert!(!second.starts_with(CLIENT_PREFACE));
+        let frames = parse_frames(&second).unwrap();
+        assert_eq!(frames[0].kind, FRAME_HEADERS);
+        assert_eq!(frames[0].stream_id, 3);
+        assert!(frames.last().unwrap().flags & FLAG_END_STREAM != 0);
+        assert_eq!(h2.decode(&second).unwrap(), b"short");
+    }
+
+    #[test]
+    fn test_preface_frames() {
+        let preface = connection_preface();
+        let frames = parse_frames(&preface[CLIENT_PREFACE.len()..]).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].kind, FRAME_SETTINGS);
+        assert_eq!(frames[0].payload.len(), CHROME_SETTINGS.len() * 6);
+        assert_eq!(frames[1].kind, FRAME_WINDOW_UPDATE);
+        assert_eq!(frames[1].stream_id, 0);
+    }
+
+    #[test]
+    fn test_hpack_encoding() {
+        // RFC 7541 C.3.1 without Huffman: indexed :method GET, :scheme
+        // https, :path /, then a literal :authority with an indexed name
+        let block = encode_headers(&[
+            (":method", "GET"),
+            (":scheme", "https"),
+            (":path", "/"),
+            (":authority", "www.example.com"),
+        ]);
+        let mut expected = vec![0x82, 0x87, 0x84, 0x01, 0x0f];
+        expected.extend_from_slice(b"www.example.com");
+        assert_eq!(block, expected);
+
+        // Name index 58 overflows the 4-bit prefix
+        let block = encode_headers(&[("user-agent", "x")]);
+        assert_eq!(block, vec![0x0f, 58 - 15, 0x01, b'x']);
+    }
+
+    #[test]
+    fn test_truncated_frames_rejected() {
+        let h2 = mimicry();
+        let wire = h2.encode(b"payload");
+        assert!(h2.decode(&wire[..wire.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,304 @@
+//! Blocked-domain hostlists
+//! Anti-zapret style lists of filtered domains: only connections to a listed
+//! host get the heavy evasion, everything else passes untouched, which saves
+//! the overhead and keeps most traffic looking ordinary
+
+use crate::error::{Error, Result};
+use crate::sni_pool::fetch_signed_list;
+use parking_lot::RwLock;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::task::JoinHandle;
+
+/// Upper bound on a downloaded hostlist; full national lists run to
+/// several hundred thousand domains
+const MAX_HOSTLIST_BODY: usize = 32 * 1024 * 1024;
+
+/// Where a hostlist is loaded from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HostlistSource {
+    /// A local file with one domain per line (`#` starts a comment)
+    File(PathBuf),
+    /// A list served over HTTPS with a detached ed25519 signature at
+    /// `<url>.sig`; both the signature and `public_key` are base64
+    Remote { url: String, public_key: String },
+}
+
+/// How a connection is processed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Treatment {
+    /// Full DPI evasion: fragmentation, desync, fake SNI
+    Evade,
+    /// Forwarded as is
+    Passthrough,
+}
+
+/// A set of domains matching themselves and their subdomains
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HostSet {
+    domains: HashSet<String>,
+}
+
+impl HostSet {
+    /// Parse a list: one domain per line, `*.` or `.` prefixes allowed,
+    /// blank lines and `#` comments ignored
+    ///
+    /// Lines that are not domain names are skipped, so lists mixing in
+    /// addresses or URLs still load; a list with no domains at all is an
+    /// error, since it is more likely a truncated download or an error page.
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut set = HostSet::default();
+        let mut skipped = 0;
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            if !set.insert(line) {
+                skipped += 1;
+            }
+        }
+        if skipped > 0 {
+            log::debug!("Skipped {} malformed hostlist lines", skipped);
+        }
+        if set.is_empty() {
+            return Err(Error::DataError("Hostlist has no domains".to_string()));
+        }
+        Ok(set)
+    }
+
+    /// Add a domain, returning whether it was a valid name
+    pub fn insert(&mut self, domain: &str) -> bool {
+        let domain = domain
+            .trim_start_matches("*.")
+            .trim_matches('.')
+            .to_ascii_lowercase();
+        if !is_domain(&domain) {
+            return false;
+        }
+        self.domains.insert(domain);
+        true
+    }
+
+    /// Whether `host` or one of its parent domains is listed
+    pub fn contains(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        let mut suffix = host.as_str();
+        loop {
+            if self.domains.contains(suffix) {
+                return true;
+            }
+            match suffix.split_once('.') {
+                Some((_, parent)) => suffix = parent,
+                None => return false,
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.domains.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.domains.is_empty()
+    }
+}
+
+impl<S: AsRef<str>> FromIterator<S> for HostSet {
+    fn from_iter<I: IntoIterator<Item = S>>(domains: I) -> Self {
+        let mut set = HostSet::default();
+        for domain in domains {
+            set.insert(domain.as_ref());
+        }
+        set
+    }
+}
+
+/// Shared, hot-swappable hostlist
+///
+/// Until a list has been loaded every host is treated as blocked, so a
+/// failed first download degrades to evading everything rather than to
+/// exposing blocked connections. Clones share the same list.
+#[derive(Clone, Debug, Default)]
+pub struct Hostlist {
+    set: Arc<RwLock<Option<Arc<HostSet>>>>,
+    updated_at: Arc<RwLock<Option<SystemTime>>>,
+}
+
+impl Hostlist {
+    /// A hostlist with nothing loaded yet
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A hostlist holding the given set
+    pub fn with_set(set: HostSet) -> Self {
+        let hostlist = Self::new();
+        hostlist.swap(set);
+        hostlist
+    }
+
+    /// Load a hostlist from the given source
+    pub async fn load(source: &HostlistSource) -> Result<Self> {
+        let hostlist = Self::new();
+        hostlist.reload(source).await?;
+        Ok(hostlist)
+    }
+
+    /// Replace the list, returning its size
+    pub fn swap(&self, set: HostSet) -> usize {
+        let len = set.len();
+        *self.set.write() = Some(Arc::new(set));
+        *self.updated_at.write() = Some(SystemTime::now());
+        len
+    }
+
+    /// Fetch the source again and swap it in
+    ///
+    /// On failure the current list is left untouched.
+    pub async fn reload(&self, source: &HostlistSource) -> Result<usize> {
+        let text = match source {
+            HostlistSource::File(path) => tokio::fs::read_to_string(path).await?,
+            HostlistSource::Remote { url, public_key } => {
+                fetch_signed_list(url, public_key, MAX_HOSTLIST_BODY).await?
+            }
+        };
+        Ok(self.swap(HostSet::parse(&text)?))
+    }
+
+    /// Periodically reload the list in the background
+    pub fn spawn_refresh(&self, source: HostlistSource, interval: Duration) -> JoinHandle<()> {
+        let hostlist = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            // The first tick fires immediately; the caller already loaded the list
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                match hostlist.reload(&source).await {
+                    Ok(len) => log::info!("Reloaded hostlist ({} domains)", len),
+                    Err(e) => log::warn!("Hostlist reload failed, keeping current list: {}", e),
+                }
+            }
+        })
+    }
+
+    /// Whether a list has been loaded
+    pub fn is_loaded(&self) -> bool {
+        self.set.read().is_some()
+    }
+
+    /// When the current list was swapped in
+    pub fn updated_at(&self) -> Option<SystemTime> {
+        *self.updated_at.read()
+    }
+
+    /// Number of listed domains
+    pub fn len(&self) -> usize {
+        self.set.read().as_ref().map_or(0, |set| set.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether a host is on the list; `true` for every host until a list
+    /// is loaded
+    pub fn is_blocked(&self, host: &str) -> bool {
+        self.set
+            .read()
+            .as_ref()
+            .is_none_or(|set| set.contains(host))
+    }
+
+    /// How to process a connection to `host`, the SNI or the requested name
+    pub fn treatment(&self, host: &str) -> Treatment {
+        if self.is_blocked(host) {
+            Treatment::Evade
+        } else {
+            Treatment::Passthrough
+        }
+    }
+}
+
+/// Whether a string is a plausible domain name
+fn is_domain(domain: &str) -> bool {
+    domain.len() <= 253
+        && domain.parse::<std::net::IpAddr>().is_err()
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_match() {
+        let list = "# blocked in IR\n\
+                    youtube.com\n\
+                    *.twitter.com\n\
+                    .Instagram.com.\n\
+                    https://not-a-domain/path\n\
+                    \n\
+                    10.0.0.1\n";
+        let set = HostSet::parse(list).unwrap();
+        assert_eq!(set.len(), 3);
+        assert!(set.contains("www.youtube.com"));
+        assert!(set.contains("YOUTUBE.com."));
+        assert!(set.contains("twitter.com"));
+        assert!(set.contains("scontent.cdn.instagram.com"));
+        assert!(!set.contains("notyoutube.com"));
+        assert!(!set.contains("com"));
+        assert!(HostSet::parse("# nothing here\n<html>\n").is_err());
+    }
+
+    #[test]
+    fn test_selective_treatment() {
+        let hostlist = Hostlist::new();
+        // Nothing loaded yet: evade everything
+        assert!(!hostlist.is_loaded());
+        assert_eq!(hostlist.treatment("example.com"), Treatment::Evade);
+
+        let handle = hostlist.clone();
+        assert_eq!(
+            handle.swap(["youtube.com", "telegram.org"].into_iter().collect()),
+            2
+        );
+        assert!(hostlist.updated_at().is_some());
+        assert_eq!(hostlist.treatment("i.ytimg.youtube.com"), Treatment::Evade);
+        assert_eq!(hostlist.treatment("digikala.com"), Treatment::Passthrough);
+    }
+
+    #[tokio::test]
+    async fn test_reload_keeps_list_on_failure() {
+        let path = std::env::temp_dir().join(format!("hostlist-{}.txt", std::process::id()));
+        std::fs::write(&path, "youtube.com\ntelegram.org\n").unwrap();
+        let source = HostlistSource::File(path.clone());
+        let hostlist = Hostlist::load(&source).await.unwrap();
+        assert_eq!(hostlist.len(), 2);
+
+        std::fs::write(&path, "# truncated\n").unwrap();
+        assert!(hostlist.reload(&source).await.is_err());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(hostlist.len(), 2);
+
+        let remote = HostlistSource::Remote {
+            url: "http://lists.example.com/domains.lst".to_string(),
+            public_key: String::new(),
+        };
+        assert!(matches!(
+            hostlist.reload(&remote).await,
+            Err(Error::ConfigError(_))
+        ));
+    }
+}
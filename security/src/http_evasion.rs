@@ -0,0 +1,314 @@
+//! Plain-HTTP DPI evasion
+//! Header-level rewrites for port-80 requests that keyword-matching DPI
+//! trips over but origin servers still accept
+
+use crate::config::HttpEvasionConfig;
+use crate::tls_fragmentation::FragmentedPacket;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+const HEAD_END: &[u8] = b"\r\n\r\n";
+
+/// Where an HTTP request is cut into separate TCP segments
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HttpSplit {
+    /// Send the request in one piece
+    #[default]
+    None,
+    /// Cut right before the Host header line, so the request line and the
+    /// hostname never share a segment
+    HostHeader,
+    /// Cut right before the Host header value, so `Host:` and the hostname
+    /// never share a segment; the HTTP analog of SNI-split
+    HostValue,
+}
+
+/// A parsed HTTP/1.x request head
+struct RequestHead<'a> {
+    method: &'a str,
+    target: &'a str,
+    version: &'a str,
+    headers: Vec<&'a str>,
+    body: &'a [u8],
+}
+
+impl<'a> RequestHead<'a> {
+    fn parse(data: &'a [u8]) -> Option<Self> {
+        let head_len = data.windows(HEAD_END.len()).position(|w| w == HEAD_END)?;
+        let head = std::str::from_utf8(&data[..head_len]).ok()?;
+        let mut lines = head.split("\r\n");
+
+        let mut request_line = lines.next()?.split(' ');
+        let method = request_line.next()?;
+        let target = request_line.next()?;
+        let version = request_line.next()?;
+        if request_line.next().is_some()
+            || method.is_empty()
+            || !method.bytes().all(|b| b.is_ascii_uppercase())
+            || !version.starts_with("HTTP/1.")
+        {
+            return None;
+        }
+
+        Some(RequestHead {
+            method,
+            target,
+            version,
+            headers: lines.collect(),
+            body: &data[head_len + HEAD_END.len()..],
+        })
+    }
+}
+
+fn is_host_header(line: &str) -> bool {
+    line.split_once(':')
+        .is_some_and(|(name, _)| name.trim().eq_ignore_ascii_case("host"))
+}
+
+/// Randomize the case of every letter, changing at least one
+fn mix_case<R: Rng>(rng: &mut R, word: &str) -> String {
+    let mut mixed: Vec<u8> = word
+        .bytes()
+        .map(|b| {
+            if rng.gen() {
+                b.to_ascii_uppercase()
+            } else {
+                b.to_ascii_lowercase()
+            }
+        })
+        .collect();
+    if mixed == word.as_bytes() {
+        if let Some(b) = mixed.iter_mut().find(|b| b.is_ascii_alphabetic()) {
+            *b ^= 0x20;
+        }
+    }
+    String::from_utf8(mixed).unwrap_or_else(|_| word.to_string())
+}
+
+/// A rewritten request and where its Host header and value start
+struct Rewritten {
+    data: Vec<u8>,
+    host_line: Option<usize>,
+    host_value: Option<usize>,
+}
+
+/// Plain-HTTP evasion stage
+///
+/// Server compatibility: header names are case-insensitive and whitespace
+/// around header values is optional whitespace per RFC 9112, so
+/// `mixed_case_host_header`, `extra_spaces` and `reorder_headers` are safe
+/// everywhere tested. Methods are case-sensitive; nginx and most CDNs reject
+/// `gEt`, so `mixed_case_method` only suits lenient servers (Apache, IIS).
+pub struct HttpEvasion {
+    config: HttpEvasionConfig,
+}
+
+impl HttpEvasion {
+    pub fn new(config: HttpEvasionConfig) -> Self {
+        HttpEvasion { config }
+    }
+
+    pub fn config(&self) -> &HttpEvasionConfig {
+        &self.config
+    }
+
+    /// Whether `data` starts with a complete HTTP/1.x request head
+    pub fn is_http_request(data: &[u8]) -> bool {
+        RequestHead::parse(data).is_some()
+    }
+
+    /// Apply the header rewrites; anything but an HTTP request is returned
+    /// unchanged
+    pub fn rewrite(&self, request: &[u8]) -> Vec<u8> {
+        match self.rewrite_head(request) {
+            Some(rewritten) => rewritten.data,
+            None => request.to_vec(),
+        }
+    }
+
+    /// Rewrite a request and cut it into the segments to send
+    ///
+    /// Each packet must go out as its own TCP segment (e.g. with
+    /// `TCP_NODELAY` and a write per packet).
+    pub fn prepare(&self, request: &[u8]) -> Vec<FragmentedPacket> {
+        let Some(rewritten) = self.rewrite_head(request) else {
+            return vec![FragmentedPacket {
+                data: request.to_vec(),
+                delay_ms: 0,
+            }];
+        };
+
+        let cut = match self.config.split {
+            HttpSplit::None => None,
+            HttpSplit::HostHeader => rewritten.host_line,
+            HttpSplit::HostValue => rewritten.host_value,
+        };
+        match cut {
+            Some(cut) => vec![
+                FragmentedPacket {
+                    data: rewritten.data[..cut].to_vec(),
+                    delay_ms: 0,
+                },
+                FragmentedPacket {
+                    data: rewritten.data[cut..].to_vec(),
+                    delay_ms: 0,
+                },
+            ],
+            None => vec![FragmentedPacket {
+                data: rewritten.data,
+                delay_ms: 0,
+            }],
+        }
+    }
+
+    fn rewrite_head(&self, request: &[u8]) -> Option<Rewritten> {
+        let head = RequestHead::parse(request)?;
+        if !self.config.enabled {
+            return Some(Rewritten {
+                data: request.to_vec(),
+                host_line: None,
+                host_value: None,
+            });
+        }
+        let mut rng = rand::thread_rng();
+
+        let method = if self.config.mixed_case_method {
+            mix_case(&mut rng, head.method)
+        } else {
+            head.method.to_string()
+        };
+        let gap = if self.config.extra_spaces { "  " } else { " " };
+        let mut out = format!("{}{}{} {}\r\n", method, gap, head.target, head.version);
+
+        let mut headers = head.headers.clone();
+        if self.config.reorder_headers && headers.len() > 1 {
+            headers.shuffle(&mut rng);
+            if is_host_header(headers[0]) {
+                let other = rng.gen_range(1..headers.len());
+                headers.swap(0, other);
+            }
+        }
+
+        let mut host_line = None;
+        let mut host_value = None;
+        for header in headers {
+            match header.split_once(':') {
+                Some((_, value)) if is_host_header(header) => {
+                    host_line = Some(out.len());
+                    let name = if self.config.mixed_case_host_header {
+                        mix_case(&mut rng, "Host")
+                    } else {
+                        "Host".to_string()
+                    };
+                    let (colon, end) = if self.config.extra_spaces {
+                        (":  ", " \r\n")
+                    } else {
+                        (": ", "\r\n")
+                    };
+                    out.push_str(&name);
+                    out.push_str(colon);
+                    host_value = Some(out.len());
+                    out.push_str(value.trim());
+                    out.push_str(end);
+                }
+                _ => {
+                    out.push_str(header);
+                    out.push_str("\r\n");
+                }
+            }
+        }
+        out.push_str("\r\n");
+
+        let mut data = out.into_bytes();
+        data.extend_from_slice(head.body);
+        Some(Rewritten {
+            data,
+            host_line,
+            host_value,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REQUEST: &[u8] = b"GET /index.html HTTP/1.1\r\nHost: blocked.example\r\n\
+        User-Agent: test\r\nAccept: */*\r\n\r\nbody";
+
+    fn all_tricks(split: HttpSplit) -> HttpEvasion {
+        HttpEvasion::new(HttpEvasionConfig {
+            enabled: true,
+            mixed_case_method: true,
+            mixed_case_host_header: true,
+            extra_spaces: true,
+            reorder_headers: true,
+            split,
+        })
+    }
+
+    #[test]
+    fn test_rewrite_keeps_request_meaning() {
+        let rewritten = all_tricks(HttpSplit::None).rewrite(REQUEST);
+        let text = String::from_utf8(rewritten).unwrap();
+        let (head, body) = text.split_once("\r\n\r\n").unwrap();
+        assert_eq!(body, "body");
+
+        let mut lines = head.split("\r\n");
+        let request_line = lines.next().unwrap();
+        assert!(request_line.starts_with(|c: char| c.eq_ignore_ascii_case(&'g')));
+        assert!(!request_line.starts_with("GET "));
+        assert!(request_line.ends_with(" /index.html HTTP/1.1"));
+
+        let headers: Vec<&str> = lines.collect();
+        assert_eq!(headers.len(), 3);
+        assert!(!is_host_header(headers[0]));
+        let host = headers.iter().find(|h| is_host_header(h)).unwrap();
+        assert!(!host.starts_with("Host:"));
+        assert_eq!(host.split_once(':').unwrap().1.trim(), "blocked.example");
+    }
+
+    #[test]
+    fn test_split_before_host_header() {
+        let packets = all_tricks(HttpSplit::HostHeader).prepare(REQUEST);
+        assert_eq!(packets.len(), 2);
+        assert!(!packets[0]
+            .data
+            .to_ascii_lowercase()
+            .windows(b"blocked.example".len())
+            .any(|w| w == b"blocked.example"));
+        assert!(packets[1].data[..4].eq_ignore_ascii_case(b"host"));
+    }
+
+    #[test]
+    fn test_split_before_host_value() {
+        let evasion = HttpEvasion::new(HttpEvasionConfig {
+            enabled: true,
+            split: HttpSplit::HostValue,
+            ..Default::default()
+        });
+        let packets = evasion.prepare(REQUEST);
+        assert_eq!(packets.len(), 2);
+        assert!(packets[0].data.ends_with(b"\r\nHost: "));
+        assert!(packets[1].data.starts_with(b"blocked.example\r\n"));
+        assert_eq!(
+            [&packets[0].data[..], &packets[1].data[..]].concat(),
+            REQUEST
+        );
+    }
+
+    #[test]
+    fn test_non_http_passes_through() {
+        let evasion = all_tricks(HttpSplit::HostHeader);
+        let tls = [0x16, 0x03, 0x01, 0x00, 0x05, 1, 2, 3, 4, 5];
+        assert!(!HttpEvasion::is_http_request(&tls));
+        let packets = evasion.prepare(&tls);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].data, tls);
+
+        let disabled = HttpEvasion::new(HttpEvasionConfig::default());
+        assert_eq!(disabled.rewrite(REQUEST), REQUEST);
+    }
+}
@@ -0,0 +1,529 @@
+//! ICMP echo tunnel
+//! Last-resort transport carrying data in echo request/reply payloads, for
+//! shutdowns where TCP and UDP to foreign addresses barely get through
+//!
+//! The client sends data in echo requests and keeps polling with empty ones;
+//! the server answers each request exactly once, so its data only ever rides
+//! in replies matching an outstanding request (which is what NATs and
+//! stateful firewalls let back in). The server host must not answer pings
+//! itself: `sysctl net.ipv4.icmp_echo_ignore_all=1`.
+
+use crate::desync::internet_checksum;
+use crate::error::{Error, Result};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+pub const ICMP_ECHO_REPLY: u8 = 0;
+pub const ICMP_ECHO_REQUEST: u8 = 8;
+pub const ICMP_HEADER_LEN: usize = 8;
+
+/// Tag, message number, fragment index and count; the same size as the
+/// timestamp real pings start with
+pub const TUNNEL_HEADER_LEN: usize = 8;
+
+/// Side of the tunnel
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IcmpRole {
+    #[default]
+    Client,
+    Server,
+}
+
+/// Configuration for the ICMP tunnel; `tag` must match on both ends
+#[derive(Clone, Debug)]
+pub struct IcmpTunnelConfig {
+    pub role: IcmpRole,
+    /// Shared value marking tunnel packets among ordinary pings
+    pub tag: u32,
+    /// Echo identifier the client uses
+    pub identifier: u16,
+    /// Largest message bytes carried per packet
+    pub max_fragment: usize,
+    /// How often an idle client sends empty requests for the server to answer
+    pub poll_interval: Duration,
+    /// Incomplete messages are dropped after this long
+    pub reassembly_timeout: Duration,
+}
+
+impl Default for IcmpTunnelConfig {
+    fn default() -> Self {
+        IcmpTunnelConfig {
+            role: IcmpRole::Client,
+            tag: 0,
+            identifier: std::process::id() as u16,
+            max_fragment: 1024,
+            poll_interval: Duration::from_millis(100),
+            reassembly_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Tag written by `role`; the directions differ so a peer never accepts its
+/// own packets echoed back by a kernel
+fn role_tag(tag: u32, role: IcmpRole) -> u32 {
+    match role {
+        IcmpRole::Client => tag,
+        IcmpRole::Server => !tag,
+    }
+}
+
+/// One ICMP echo message
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EchoPacket {
+    /// `ICMP_ECHO_REQUEST` or `ICMP_ECHO_REPLY`
+    pub kind: u8,
+    pub identifier: u16,
+    pub sequence: u16,
+    pub payload: Vec<u8>,
+}
+
+impl EchoPacket {
+    /// Serialize with a valid checksum
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(ICMP_HEADER_LEN + self.payload.len());
+        packet.push(self.kind);
+        packet.push(0); // code
+        packet.extend_from_slice(&[0, 0]); // checksum
+        packet.extend_from_slice(&self.identifier.to_be_bytes());
+        packet.extend_from_slice(&self.sequence.to_be_bytes());
+        packet.extend_from_slice(&self.payload);
+        let checksum = internet_checksum(&packet);
+        packet[2..4].copy_from_slice(&checksum.to_be_bytes());
+        packet
+    }
+
+    /// Parse an echo request or reply, rejecting bad checksums
+    pub fn parse(packet: &[u8]) -> Option<Self> {
+        if packet.len() < ICMP_HEADER_LEN
+            || !matches!(packet[0], ICMP_ECHO_REQUEST | ICMP_ECHO_REPLY)
+            || packet[1] != 0
+            || internet_checksum(packet) != 0
+        {
+            return None;
+        }
+        Some(EchoPacket {
+            kind: packet[0],
+            identifier: u16::from_be_bytes([packet[4], packet[5]]),
+            sequence: u16::from_be_bytes([packet[6], packet[7]]),
+            payload: packet[ICMP_HEADER_LEN..].to_vec(),
+        })
+    }
+}
+
+/// Splits messages into tunnel payloads
+#[derive(Clone, Debug)]
+pub struct IcmpCodec {
+    tag: u32,
+    max_fragment: usize,
+    next_message: u16,
+}
+
+impl IcmpCodec {
+    pub fn new(config: &IcmpTunnelConfig) -> Self {
+        IcmpCodec {
+            tag: role_tag(config.tag, config.role),
+            max_fragment: config.max_fragment.max(1),
+            next_message: 0,
+        }
+    }
+
+    fn header(&self, message: u16, index: u8, count: u8) -> Vec<u8> {
+        let mut header = Vec::with_capacity(TUNNEL_HEADER_LEN);
+        header.extend_from_slice(&self.tag.to_be_bytes());
+        header.extend_from_slice(&message.to_be_bytes());
+        header.push(index);
+        header.push(count);
+        header
+    }
+
+    /// Payloads carrying `data`, in order
+    pub fn fragment(&mut self, data: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let chunks: Vec<&[u8]> = data.chunks(self.max_fragment).collect();
+        if chunks.is_empty() || chunks.len() > u8::MAX as usize {
+            return Err(Error::DataError(format!(
+                "Message of {} bytes can't be carried over ICMP",
+                data.len()
+            )));
+        }
+        let message = self.next_message;
+        self.next_message = self.next_message.wrapping_add(1);
+
+        Ok(chunks
+            .iter()
+            .enumerate()
+            .map(|(index, chunk)| {
+                let mut payload = self.header(message, index as u8, chunks.len() as u8);
+                payload.extend_from_slice(chunk);
+                payload
+            })
+            .collect())
+    }
+
+    /// Empty payload that only gives the server a reply slot
+    pub fn poll(&self) -> Vec<u8> {
+        self.header(0, 0, 0)
+    }
+}
+
+struct PartialMessage {
+    fragments: Vec<Option<Vec<u8>>>,
+    started: Instant,
+}
+
+/// Rebuilds messages from the peer's fragments, by message number and index
+pub struct Reassembler {
+    peer_tag: u32,
+    timeout: Duration,
+    partial: HashMap<u16, PartialMessage>,
+}
+
+impl Reassembler {
+    pub fn new(config: &IcmpTunnelConfig) -> Self {
+        let peer_role = match config.role {
+            IcmpRole::Client => IcmpRole::Server,
+            IcmpRole::Server => IcmpRole::Client,
+        };
+        Reassembler {
+            peer_tag: role_tag(config.tag, peer_role),
+            timeout: config.reassembly_timeout,
+            partial: HashMap::new(),
+        }
+    }
+
+    /// Whether `payload` came from the tunnel peer
+    pub fn is_tunnel(&self, payload: &[u8]) -> bool {
+        payload.len() >= TUNNEL_HEADER_LEN && payload[..4] == self.peer_tag.to_be_bytes()
+    }
+
+    /// Add one payload; returns the message it completes
+    pub fn push(&mut self, payload: &[u8]) -> Option<Vec<u8>> {
+        if !self.is_tunnel(payload) {
+            return None;
+        }
+        let message = u16::from_be_bytes([payload[4], payload[5]]);
+        let (index, count) = (payload[6] as usize, payload[7] as usize);
+        if count == 0 || index >= count {
+            return None;
+        }
+
+        let partial = self
+            .partial
+            .entry(message)
+            .or_insert_with(|| PartialMessage {
+                fragments: vec![None; count],
+                started: Instant::now(),
+            });
+        if partial.fragments.len() != count {
+            return None;
+        }
+        partial.fragments[index] = Some(payload[TUNNEL_HEADER_LEN..].to_vec());
+        if partial.fragments.iter().any(Option::is_none) {
+            return None;
+        }
+
+        let partial = self.partial.remove(&message)?;
+        Some(partial.fragments.into_iter().flatten().flatten().collect())
+    }
+
+    /// Drop messages still incomplete after the reassembly timeout
+    pub fn expire(&mut self, now: Instant) {
+        let timeout = self.timeout;
+        self.partial
+            .retain(|_, partial| now.duration_since(partial.started) < timeout);
+    }
+
+    /// Messages waiting for fragments
+    pub fn pending(&self) -> usize {
+        self.partial.len()
+    }
+}
+
+#[cfg(unix)]
+pub use socket::IcmpTunnel;
+
+#[cfg(unix)]
+mod socket {
+    use super::*;
+    use crate::desync::IPV4_HEADER_LEN;
+    use std::collections::VecDeque;
+    use std::io::ErrorKind;
+    use std::mem::MaybeUninit;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    /// Server replies held back for data; beyond this the oldest are answered empty
+    const MAX_PENDING_REPLIES: usize = 16;
+
+    /// ICMP tunnel endpoint over a raw socket (root or CAP_NET_RAW)
+    ///
+    /// Blocking; run it on a blocking thread.
+    pub struct IcmpTunnel {
+        socket: socket2::Socket,
+        config: IcmpTunnelConfig,
+        peer: Option<Ipv4Addr>,
+        codec: IcmpCodec,
+        reassembler: Reassembler,
+        sequence: u16,
+        /// Client requests (identifier, sequence) the server hasn't answered
+        reply_slots: VecDeque<(u16, u16)>,
+        ready: VecDeque<Vec<u8>>,
+        last_sent: Instant,
+    }
+
+    impl IcmpTunnel {
+        /// Open a tunnel endpoint; servers may leave `peer` unset to learn it
+        /// from the first tunnel request
+        pub fn open(config: IcmpTunnelConfig, peer: Option<Ipv4Addr>) -> Result<Self> {
+            if config.role == IcmpRole::Client && peer.is_none() {
+                return Err(Error::ConfigError(
+                    "ICMP tunnel client needs a peer address".to_string(),
+                ));
+            }
+            let socket = socket2::Socket::new(
+                socket2::Domain::IPV4,
+                socket2::Type::RAW,
+                Some(socket2::Protocol::ICMPV4),
+            )?;
+            Ok(IcmpTunnel {
+                socket,
+                codec: IcmpCodec::new(&config),
+                reassembler: Reassembler::new(&config),
+                config,
+                peer,
+                sequence: 0,
+                reply_slots: VecDeque::new(),
+                ready: VecDeque::new(),
+                last_sent: Instant::now(),
+            })
+        }
+
+        /// Peer address, once known
+        pub fn peer(&self) -> Option<Ipv4Addr> {
+            self.peer
+        }
+
+        fn send_echo(&mut self, packet: &EchoPacket) -> Result<()> {
+            let peer = self
+                .peer
+                .ok_or_else(|| Error::DataError("ICMP tunnel peer not known yet".to_string()))?;
+            let addr = socket2::SockAddr::from(SocketAddrV4::new(peer, 0));
+            self.socket.send_to(&packet.to_bytes(), &addr)?;
+            self.last_sent = Instant::now();
+            Ok(())
+        }
+
+        fn send_request(&mut self, payload: Vec<u8>) -> Result<()> {
+            self.sequence = self.sequence.wrapping_add(1);
+            let packet = EchoPacket {
+                kind: ICMP_ECHO_REQUEST,
+                identifier: self.config.identifier,
+                sequence: self.sequence,
+                payload,
+            };
+            self.send_echo(&packet)
+        }
+
+        fn send_reply(
+            &mut self,
+            (identifier, sequence): (u16, u16),
+            payload: Vec<u8>,
+        ) -> Result<()> {
+            let packet = EchoPacket {
+                kind: ICMP_ECHO_REPLY,
+                identifier,
+                sequence,
+                payload,
+            };
+            self.send_echo(&packet)
+        }
+
+        /// Send one message
+        ///
+        /// A server waits for client requests to reply to, failing once the
+        /// reassembly timeout passes without enough of them.
+        pub fn send(&mut self, data: &[u8]) -> Result<()> {
+            let deadline = Instant::now() + self.config.reassembly_timeout;
+            for payload in self.codec.fragment(data)? {
+                match self.config.role {
+                    IcmpRole::Client => self.send_request(payload)?,
+                    IcmpRole::Server => {
+                        let slot = loop {
+                            if let Some(slot) = self.reply_slots.pop_front() {
+                                break slot;
+                            }
+                            self.receive_one(deadline)?;
+                        };
+                        self.send_reply(slot, payload)?;
+                    }
+                }
+            }
+            Ok(())
+        }
+
+        /// Receive the next message, polling the server while waiting
+        pub fn recv(&mut self, timeout: Duration) -> Result<Vec<u8>> {
+            let deadline = Instant::now() + timeout;
+            loop {
+                if let Some(message) = self.ready.pop_front() {
+                    return Ok(message);
+                }
+                if self.config.role == IcmpRole::Client
+                    && self.last_sent.elapsed() >= self.config.poll_interval
+                {
+                    let poll = self.codec.poll();
+                    self.send_request(poll)?;
+                }
+                let wait = deadline.min(Instant::now() + self.config.poll_interval);
+                match self.receive_one(wait) {
+                    Err(Error::IoError(e))
+                        if e.kind() == ErrorKind::TimedOut && Instant::now() < deadline => {}
+                    other => other?,
+                }
+            }
+        }
+
+        /// Read one packet before `deadline` and file whatever it carries
+        fn receive_one(&mut self, deadline: Instant) -> Result<()> {
+            let mut buf = [MaybeUninit::<u8>::uninit(); 65535];
+            loop {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    return Err(Error::IoError(ErrorKind::TimedOut.into()));
+                }
+                self.socket.set_read_timeout(Some(remaining))?;
+                let len = match self.socket.recv(&mut buf) {
+                    Ok(len) => len,
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+                    Err(e) => return Err(e.into()),
+                };
+                // SAFETY: recv initialised the first `len` bytes
+                let packet = unsafe { std::slice::from_raw_parts(buf.as_ptr() as *const u8, len) };
+                if packet.len() < IPV4_HEADER_LEN {
+                    continue;
+                }
+                let source = Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]);
+                let ip_header_len = (packet[0] & 0x0f) as usize * 4;
+                let Some(echo) = packet.get(ip_header_len..).and_then(EchoPacket::parse) else {
+                    continue;
+                };
+                if self.handle(source, echo)? {
+                    return Ok(());
+                }
+            }
+        }
+
+        /// File one echo packet; false if it wasn't for this tunnel
+        fn handle(&mut self, source: Ipv4Addr, echo: EchoPacket) -> Result<bool> {
+            if !self.reassembler.is_tunnel(&echo.payload) {
+                return Ok(false);
+            }
+            match self.config.role {
+                IcmpRole::Client => {
+                    if echo.kind != ICMP_ECHO_REPLY
+                        || echo.identifier != self.config.identifier
+                        || Some(source) != self.peer
+                    {
+                        return Ok(false);
+                    }
+                }
+                IcmpRole::Server => {
+                    if echo.kind != ICMP_ECHO_REQUEST {
+                        return Ok(false);
+                    }
+                    match self.peer {
+                        Some(peer) if peer != source => return Ok(false),
+                        Some(_) => {}
+                        None => self.peer = Some(source),
+                    }
+                    self.reply_slots.push_back((echo.identifier, echo.sequence));
+                    while self.reply_slots.len() > MAX_PENDING_REPLIES {
+                        let slot = self.reply_slots.pop_front().unwrap();
+                        let poll = self.codec.poll();
+                        self.send_reply(slot, poll)?;
+                    }
+                }
+            }
+
+            self.reassembler.expire(Instant::now());
+            if let Some(message) = self.reassembler.push(&echo.payload) {
+                self.ready.push_back(message);
+            }
+            Ok(true)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(role: IcmpRole) -> IcmpTunnelConfig {
+        IcmpTunnelConfig {
+            role,
+            tag: 0x5a17_c3e1,
+            max_fragment: 100,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_echo_packet_round_trip() {
+        let packet = EchoPacket {
+            kind: ICMP_ECHO_REQUEST,
+            identifier: 0x1234,
+            sequence: 7,
+            payload: b"payload".to_vec(),
+        };
+        let bytes = packet.to_bytes();
+        assert_eq!(internet_checksum(&bytes), 0);
+        assert_eq!(EchoPacket::parse(&bytes).unwrap(), packet);
+
+        let mut corrupted = bytes.clone();
+        corrupted[9] ^= 0xff;
+        assert!(EchoPacket::parse(&corrupted).is_none());
+    }
+
+    #[test]
+    fn test_out_of_order_reassembly() {
+        let mut codec = IcmpCodec::new(&config(IcmpRole::Server));
+        let mut reassembler = Reassembler::new(&config(IcmpRole::Client));
+
+        let message: Vec<u8> = (0..350).map(|i| i as u8).collect();
+        let mut fragments = codec.fragment(&message).unwrap();
+        assert_eq!(fragments.len(), 4);
+        fragments.reverse();
+
+        let last = fragments.pop().unwrap();
+        for fragment in &fragments {
+            assert!(reassembler.push(fragment).is_none());
+        }
+        assert_eq!(reassembler.push(&last).unwrap(), message);
+        assert_eq!(reassembler.pending(), 0);
+    }
+
+    #[test]
+    fn test_own_packets_and_polls_ignored() {
+        let mut client = IcmpCodec::new(&config(IcmpRole::Client));
+        let mut reassembler = Reassembler::new(&config(IcmpRole::Client));
+
+        // A kernel echoing our request back must not look like server data
+        let echoed = client.fragment(b"hello").unwrap();
+        assert!(!reassembler.is_tunnel(&echoed[0]));
+        assert!(reassembler.push(&echoed[0]).is_none());
+
+        let server = IcmpCodec::new(&config(IcmpRole::Server));
+        assert!(reassembler.is_tunnel(&server.poll()));
+        assert!(reassembler.push(&server.poll()).is_none());
+    }
+
+    #[test]
+    fn test_incomplete_messages_expire() {
+        let mut codec = IcmpCodec::new(&config(IcmpRole::Server));
+        let mut reassembler = Reassembler::new(&config(IcmpRole::Client));
+        let fragments = codec.fragment(&[0u8; 150]).unwrap();
+        reassembler.push(&fragments[0]);
+        assert_eq!(reassembler.pending(), 1);
+
+        reassembler.expire(Instant::now() + Duration::from_secs(11));
+        assert_eq!(reassembler.pending(), 0);
+    }
+}
@@ -11,18 +11,196 @@ pub mod config;
 pub mod error;
 pub mod ffi;  // FFI module for C/Go interoperability
 pub mod tls_fragmentation;  // TLS ClientHello fragmentation
+pub mod mtu;  // Path MTU discovery and segment sizing
 pub mod sni_obfuscation;  // SNI obfuscation
+pub mod sni_pool;  // Loadable, hot-swappable fake-SNI pools
+pub mod sni_policy;  // Routing-safe fake-SNI substitution policy
+pub mod sni_fallback;  // Per-host SNI evasion fallback chain
 pub mod dynamic_patterns;  // Dynamic pattern rotation
+pub mod client_hello;  // TLS ClientHello parsing and generation
+pub mod fingerprint;  // Versioned browser/OS fingerprint database
+pub mod record_padding;  // TLS record padding to fixed buckets
+pub mod cells;  // Tor-like fixed-size cells with padding cells
+pub mod entropy;  // Lower-entropy payload encodings imitating text
+pub mod compression;  // Negotiated LZ4/zstd payload compression
+pub mod budget;  // Bandwidth overhead budget dialing optional overhead down
+pub mod congestion;  // RTT-inflation and retransmit congestion signal
+pub mod throttle;  // Throttled-versus-blocked goodput classification
+pub mod diagnosis;  // Censorship mechanism classification from failure telemetry
+pub mod presets;  // Per-operator settings presets for Iranian ISPs
+pub mod overrides;  // Layered file, IPS_* environment and command-line settings
+pub mod config_encryption;  // Passphrase or device-key sealed configuration files
+pub mod profiles;  // Named per-network settings profiles in one file
+pub mod geoip;  // Iranian network and ASN lookup for per-destination decisions
+pub mod geodata;  // V2Ray geosite.dat/geoip.dat rule lists
+pub mod policy;  // Domestic/international split-tunneling decisions
+pub mod hostlist;  // Blocked-domain lists for selective evasion
+pub mod calendar;  // Time-of-day and event calendar raising the adaptation level
+pub mod bandit;  // Per-network bandit selection of evasion strategy combinations
+pub mod priors;  // Selector priors from OONI/Censored Planet measurement summaries
+pub mod scheduler;  // Pluggable packet send-time schedulers
+pub mod pcap;  // PCAP capture reading
+pub mod traffic_profile;  // Size/timing profiles extracted from captures
+pub mod dpi_sim;  // Simulated filtering rules for self-tests
+pub mod features;  // Flow features ML classifiers use, for self-assessment
+pub mod self_test;  // Embedded proxy-traffic classifier scoring planned output
+pub mod session_tickets;  // TLS session ticket cache for resumption mimicry
+pub mod domain_fronting;  // SNI/Host split domain fronting
+pub mod reality;  // REALITY-style authenticated camouflage transport
+pub mod tls_in_tls;  // Inner-handshake shaping for tunnelled TLS
+pub mod desync;  // Fake-packet desync with low-TTL segments
+pub mod probe;  // Path probes locating the filtering device
+pub mod socket_config;  // Session TCP parameters via socket options
+pub mod firewall;  // nftables/iptables rules for desync, with rollback
+#[cfg(target_os = "linux")]
+pub mod ebpf;  // In-kernel TC mangling program control
+#[cfg(unix)]
+pub mod tun;  // TUN device packet forwarding with per-flow pipelines
+#[cfg(target_os = "linux")]
+pub mod transparent;  // Transparent REDIRECT/TPROXY proxy mode
+pub mod port_hopping;  // Seed-scheduled destination port rotation
+pub mod udp_transport;  // UDP and FakeTCP datagram transports
+pub mod dtls_mimicry;  // DTLS 1.2 shaping for the UDP path
+pub mod icmp_tunnel;  // ICMP echo tunnel transport
+pub mod dns_tunnel;  // DNS tunnel transport over QNAME labels and TXT/NULL answers
+pub mod resolver;  // Encrypted DNS (DoH/DoT/DoQ) resolver for the crate's own connections
+pub mod http_evasion;  // Header-level evasions for plain-HTTP requests
+pub mod h2_mimicry;  // HTTP/2 frame camouflage profile
+pub mod grpc_transport;  // gRPC message framing over a single HTTP/2 stream
+pub mod websocket;  // WebSocket transport with a genuine Upgrade handshake
+pub mod fallback;  // nginx-alike decoy responses for unauthenticated clients
+pub mod probe_guard;  // Active-probe detection with temporary IP quarantine
+pub mod morphing;  // Packet-length morphing and application traffic shapes
+pub mod timing;  // Markov-chain inter-packet timing model
+pub mod decoy;  // Cover connections to allowed sites at a configured volume share
+pub mod events;  // Callback hooks for rotations, strategy switches and detections
+pub mod privacy;  // Privacy mode disabling persistence, and a retained-data statement
+#[cfg(all(feature = "raw-net", target_os = "linux"))]
+pub mod raw_net;  // Raw-socket TCP backend applying SessionParameters
 
 pub use error::{Error, Result};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
-#[derive(Debug, Clone)]
+/// Endpoints of the flow exported to PCAP, from the documentation ranges
+const EXPORT_CLIENT: &str = "192.0.2.10:51000";
+const EXPORT_SERVER: &str = "203.0.113.10:443";
+
+type PcapExport = pcap::SyntheticFlow<std::io::BufWriter<std::fs::File>>;
+
+/// Extra plans tried for a packet the self-test flags
+const MAX_REPLANS: u32 = 2;
+
+/// Packets of one size sent in a row before the fixed-size stages grow the
+/// next one; DPI flags longer runs as a fixed-cell protocol
+const MAX_UNIFORM_RUN: usize = 4;
+
+/// Stage flag: the byte-scrambling stages ran on the packet
+///
+/// Every outgoing packet starts with a stage header telling the peer which
+/// per-packet stages to reverse: a random nonce, then the flags and the
+/// rotation pattern byte masked with a keystream from the session key and
+/// the nonce, so observers can't tell which packets were scrambled. With
+/// the pattern in every packet, either side can rotate on its own.
+const STAGE_SCRAMBLED: u8 = 0x01;
+const STAGE_NONCE_LEN: usize = 4;
+const STAGE_HEADER_LEN: usize = STAGE_NONCE_LEN + 2;
+
+/// The self-test at `threshold`, if set
+fn open_self_test(threshold: Option<f64>) -> Option<self_test::SelfTest> {
+    threshold.map(|threshold| {
+        self_test::SelfTest::new(self_test::ProxyClassifier::default(), threshold)
+    })
+}
+
+/// The entropy shaper, if an encoding is set
+fn open_entropy_shaper(settings: &config::ObfuscationConfig) -> Option<entropy::EntropyShaper> {
+    match settings.entropy_encoding {
+        entropy::EntropyEncoding::None => None,
+        encoding => Some(entropy::EntropyShaper::new(encoding)),
+    }
+}
+
+/// The record padder, if record padding is on
+fn open_record_padder(
+    settings: &config::ObfuscationConfig,
+) -> Result<Option<record_padding::RecordPadder>> {
+    if !settings.record_padding_enabled {
+        return Ok(None);
+    }
+    record_padding::RecordPadder::from_config(settings).map(Some)
+}
+
+/// The cell codec, if cell mode is on
+fn open_cells(
+    settings: &config::ObfuscationConfig,
+) -> Result<Option<parking_lot::Mutex<cells::CellCodec>>> {
+    if settings.cell_mode.cell_size().is_none() {
+        return Ok(None);
+    }
+    cells::CellCodec::from_config(settings).map(|codec| Some(parking_lot::Mutex::new(codec)))
+}
+
+/// Open the export flow for `path`, if set
+fn open_pcap_export(path: Option<&std::path::Path>) -> Result<Option<PcapExport>> {
+    let Some(path) = path else {
+        return Ok(None);
+    };
+    if privacy::is_enabled() {
+        log::warn!("Privacy mode: not exporting traffic to PCAP");
+        return Ok(None);
+    }
+    let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    privacy::record_store(
+        privacy::DataCategory::PacketCapture,
+        privacy::Location::File {
+            path: path.to_path_buf(),
+        },
+    );
+    let start = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    let flow = pcap::SyntheticFlow::new(
+        file,
+        EXPORT_CLIENT.parse().unwrap(),
+        EXPORT_SERVER.parse().unwrap(),
+        start,
+    )?;
+    Ok(Some(flow))
+}
+
+/// Flat view of the settings `SecurityProcessor` uses
+///
+/// Converts to and from `config::SecuritySettings`, which the processor
+/// keeps internally; fields without a counterpart here keep their defaults.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
 pub struct SecurityConfig {
     pub enforce_obfuscation: bool,
     pub pattern_rotation_interval_hours: u32,
     pub max_adaptation_level: u8,
     pub decoy_traffic_percentage: u8,
     pub enable_ai_evasion: bool,
+    /// Let detection evasion switch to ensemble mode at high adaptation
+    /// levels, as in `DetectionEvadingConfig::ensemble_approach_enabled`
+    pub ensemble_approach_enabled: bool,
+    /// Score each planned packet with the embedded classifier and re-plan
+    /// with heavier shaping when it scores above this threshold; `None`
+    /// skips the self-test
+    pub self_test_threshold: Option<f64>,
+    /// Kill switch: when a stage fails, terminate the session instead of
+    /// reporting the error and carrying on; every later packet is refused
+    /// until `reset_kill_switch`
+    pub fail_closed: bool,
+    /// Overhead budget, as in `SecuritySettings::max_overhead_ratio`
+    pub max_overhead_ratio: Option<f64>,
+    /// Also write the outgoing traffic to this PCAP file, as one synthetic
+    /// TCP flow timed by the packet scheduler, for offline IDS checks
+    pub pcap_export: Option<std::path::PathBuf>,
+    /// Privacy mode, as in `ProcessorSettings::privacy_mode`
+    pub privacy_mode: bool,
+    /// HTTP envelope, as in `ObfuscationConfig::camouflage_profile`
+    pub camouflage_profile: obfuscation::CamouflageProfile,
 }
 
 impl Default for SecurityConfig {
@@ -33,17 +211,202 @@ impl Default for SecurityConfig {
             max_adaptation_level: 5,
             decoy_traffic_percentage: 20,
             enable_ai_evasion: true,
+            ensemble_approach_enabled: true,
+            self_test_threshold: None,
+            fail_closed: false,
+            max_overhead_ratio: None,
+            pcap_export: None,
+            privacy_mode: false,
+            camouflage_profile: obfuscation::CamouflageProfile::Http1,
+        }
+    }
+}
+
+impl From<&config::SecuritySettings> for SecurityConfig {
+    fn from(settings: &config::SecuritySettings) -> Self {
+        let evasion = &settings.detection_evasion;
+        SecurityConfig {
+            enforce_obfuscation: settings.obfuscation.enabled,
+            pattern_rotation_interval_hours: settings.pattern_rotation.rotation_interval_hours,
+            max_adaptation_level: evasion.max_adaptation_level,
+            decoy_traffic_percentage: if evasion.decoy_traffic_enabled {
+                evasion.decoy_traffic_percentage
+            } else {
+                0
+            },
+            enable_ai_evasion: evasion.enabled,
+            ensemble_approach_enabled: evasion.ensemble_approach_enabled,
+            self_test_threshold: settings.processor.self_test_threshold,
+            fail_closed: settings.processor.fail_closed,
+            max_overhead_ratio: settings.max_overhead_ratio,
+            pcap_export: settings.processor.pcap_export.clone(),
+            privacy_mode: settings.processor.privacy_mode,
+            camouflage_profile: settings.obfuscation.camouflage_profile,
         }
     }
 }
 
+impl From<config::SecuritySettings> for SecurityConfig {
+    fn from(settings: config::SecuritySettings) -> Self {
+        Self::from(&settings)
+    }
+}
+
+impl SecurityConfig {
+    /// Write the config's fields into `settings`; settings without a
+    /// counterpart here are left as they are
+    pub fn apply_to(self, settings: &mut config::SecuritySettings) {
+        settings.obfuscation.enabled = self.enforce_obfuscation;
+        settings.obfuscation.camouflage_profile = self.camouflage_profile;
+        settings.pattern_rotation.rotation_interval_hours = self.pattern_rotation_interval_hours;
+        let evasion = &mut settings.detection_evasion;
+        evasion.enabled = self.enable_ai_evasion;
+        evasion.max_adaptation_level = self.max_adaptation_level;
+        evasion.decoy_traffic_enabled = self.decoy_traffic_percentage > 0;
+        evasion.decoy_traffic_percentage = self.decoy_traffic_percentage;
+        evasion.ensemble_approach_enabled = self.ensemble_approach_enabled;
+        settings.max_overhead_ratio = self.max_overhead_ratio;
+        settings.processor = config::ProcessorSettings {
+            self_test_threshold: self.self_test_threshold,
+            fail_closed: self.fail_closed,
+            pcap_export: self.pcap_export,
+            privacy_mode: self.privacy_mode,
+        };
+    }
+}
+
+impl TryFrom<SecurityConfig> for config::SecuritySettings {
+    type Error = config::ValidationError;
+
+    /// Default settings with the config's fields applied, validated
+    fn try_from(config: SecurityConfig) -> std::result::Result<Self, Self::Error> {
+        let mut settings = config::SecuritySettings::default();
+        config.apply_to(&mut settings);
+        settings.validate()?;
+        Ok(settings)
+    }
+}
+
+/// What the processor did to one outgoing packet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PacketStats {
+    /// What the payload was found to be
+    pub kind: entropy::PayloadKind,
+    /// Whether the byte-scrambling stages ran; false for ciphertext, which
+    /// only got fragmentation and timing shaping
+    pub scrambled: bool,
+    pub input_len: usize,
+    pub output_len: usize,
+    /// Extra plans made because the self-test flagged the packet
+    pub replans: u32,
+}
+
+/// Bytes into and out of one pipeline stage
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StageBytes {
+    pub input: u64,
+    pub output: u64,
+}
+
+impl StageBytes {
+    fn record(&mut self, input: usize, output: usize) {
+        self.input += input as u64;
+        self.output += output as u64;
+    }
+
+    /// Output bytes per input byte; 1.0 for a stage that never ran
+    pub fn expansion(&self) -> f64 {
+        if self.input == 0 {
+            return 1.0;
+        }
+        self.output as f64 / self.input as f64
+    }
+}
+
+/// Cumulative cost of the processor's features since it was created
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OverheadStats {
+    pub packets: u64,
+    /// Payload bytes handed to `process_outgoing`
+    pub original_bytes: u64,
+    /// Bytes it produced for the wire
+    pub transmitted_bytes: u64,
+    pub obfuscation: StageBytes,
+    pub pattern_rotation: StageBytes,
+    pub dpi_bypass: StageBytes,
+    pub entropy: StageBytes,
+    pub detection_evasion: StageBytes,
+    pub record_padding: StageBytes,
+    pub cells: StageBytes,
+    /// Cover traffic on separate connections, when decoys are attached
+    pub decoy_bytes: u64,
+    /// Random padding and noise among the transmitted bytes
+    pub padding_bytes: u64,
+}
+
+impl OverheadStats {
+    /// Extra bytes sent, cover traffic included, per payload byte
+    pub fn overhead_ratio(&self) -> f64 {
+        if self.original_bytes == 0 {
+            return 0.0;
+        }
+        (self.transmitted_bytes + self.decoy_bytes) as f64 / self.original_bytes as f64 - 1.0
+    }
+
+    /// Fold in one packet's stage counts
+    fn add(&mut self, packet: &OverheadStats, original: usize, transmitted: usize) {
+        self.packets += 1;
+        self.original_bytes += original as u64;
+        self.transmitted_bytes += transmitted as u64;
+        for (total, stage) in [
+            (&mut self.obfuscation, packet.obfuscation),
+            (&mut self.pattern_rotation, packet.pattern_rotation),
+            (&mut self.dpi_bypass, packet.dpi_bypass),
+            (&mut self.detection_evasion, packet.detection_evasion),
+            (&mut self.entropy, packet.entropy),
+            (&mut self.record_padding, packet.record_padding),
+            (&mut self.cells, packet.cells),
+        ] {
+            total.input += stage.input;
+            total.output += stage.output;
+        }
+        self.padding_bytes += packet.padding_bytes;
+    }
+}
+
 /// Main security processor for proxy traffic
 pub struct SecurityProcessor {
+    settings: config::SecuritySettings,
+    /// Flat view of `settings`, kept in step with it
     config: SecurityConfig,
     obfuscator: obfuscation::Obfuscator,
     pattern_rotator: pattern_rotation::PatternRotator,
     dpi_bypasser: dpi_bypass::DPIBypass,
     detection_evader: detection_evasion::DetectionEvader,
+    /// Set when `obfuscation.entropy_encoding` is not `None`
+    entropy_shaper: Option<entropy::EntropyShaper>,
+    /// Set when `obfuscation.record_padding_enabled` is
+    record_padder: Option<record_padding::RecordPadder>,
+    /// Set when `obfuscation.cell_mode` is on; holds partial cells between reads
+    cells: Option<parking_lot::Mutex<cells::CellCodec>>,
+    tls_fragmenter: tls_fragmentation::TLSFragmenter,
+    sni_obfuscator: sni_obfuscation::SNIObfuscator,
+    session_patterns: dynamic_patterns::PatternRotator,
+    budget: Option<parking_lot::Mutex<budget::OverheadBudget>>,
+    decoys: Option<Arc<decoy::DecoyStats>>,
+    /// Decoy bytes already charged to the budget
+    decoy_charged: AtomicU64,
+    overhead: parking_lot::Mutex<OverheadStats>,
+    congestion: parking_lot::Mutex<congestion::CongestionMonitor>,
+    pcap_export: parking_lot::Mutex<Option<PcapExport>>,
+    self_test: Option<self_test::SelfTest>,
+    events: events::EventBus,
+    /// Why the kill switch tripped, once it has
+    terminated: parking_lot::Mutex<Option<String>>,
+    /// Masks the stage header; derived from the session key
+    stage_key: [u8; 32],
+    /// Length of the last packet sent and how many in a row had it
+    size_run: parking_lot::Mutex<(usize, usize)>,
 }
 
 impl SecurityProcessor {
@@ -54,85 +417,775 @@ impl SecurityProcessor {
 
     /// Create a new security processor with custom configuration
     pub fn with_config(config: SecurityConfig) -> Result<Self> {
-        let pattern_rotation_interval = config.pattern_rotation_interval_hours;
-        let max_adaptation_level = config.max_adaptation_level;
+        Self::with_settings(config.try_into()?)
+    }
+
+    /// Create a new security processor from full settings
+    pub fn with_settings(settings: config::SecuritySettings) -> Result<Self> {
+        settings.validate()?;
+        // Before anything is opened or logged
+        if settings.processor.privacy_mode {
+            privacy::set_enabled(true);
+        }
+        let pattern_rotation_interval = settings.pattern_rotation.rotation_interval_hours;
+        let evasion = &settings.detection_evasion;
+        let budget = match settings.max_overhead_ratio {
+            Some(ratio) => Some(parking_lot::Mutex::new(budget::OverheadBudget::new(ratio)?)),
+            None => None,
+        };
+        let pcap_export = open_pcap_export(settings.processor.pcap_export.as_deref())?;
+        let mut detection_evader =
+            detection_evasion::DetectionEvader::new(evasion.max_adaptation_level);
+        detection_evader.set_ensemble_enabled(evasion.ensemble_approach_enabled);
+        let self_test = open_self_test(settings.processor.self_test_threshold);
+        let obfuscator =
+            obfuscation::Obfuscator::new().with_profile(settings.obfuscation.camouflage_profile);
+        let entropy_shaper = open_entropy_shaper(&settings.obfuscation);
+        let record_padder = open_record_padder(&settings.obfuscation)?;
+        let cells = open_cells(&settings.obfuscation)?;
+        let tls_fragmenter =
+            tls_fragmentation::TLSFragmenter::with_config(settings.tls_fragmentation.clone());
+        let sni_obfuscator =
+            sni_obfuscation::SNIObfuscator::with_config(settings.sni_obfuscation.clone());
+        let session_patterns =
+            dynamic_patterns::PatternRotator::with_config(settings.session_patterns.clone());
+        let mut dpi_bypasser = dpi_bypass::DPIBypass::new();
+        dpi_bypasser.set_scheduler(settings.dpi_bypass.packet_scheduler.build()?);
+        dpi_bypasser.set_http_evasion(http_evasion::HttpEvasion::new(
+            settings.dpi_bypass.http_evasion.clone(),
+        ));
 
         Ok(SecurityProcessor {
-            config,
-            obfuscator: obfuscation::Obfuscator::new(),
+            config: SecurityConfig::from(&settings),
+            settings,
+            obfuscator,
             pattern_rotator: pattern_rotation::PatternRotator::new(
                 pattern_rotation_interval,
             ),
-            dpi_bypasser: dpi_bypass::DPIBypass::new(),
-            detection_evader: detection_evasion::DetectionEvader::new(
-                max_adaptation_level,
-            ),
+            dpi_bypasser,
+            detection_evader,
+            entropy_shaper,
+            record_padder,
+            cells,
+            tls_fragmenter,
+            sni_obfuscator,
+            session_patterns,
+            budget,
+            decoys: None,
+            decoy_charged: AtomicU64::new(0),
+            overhead: parking_lot::Mutex::new(OverheadStats::default()),
+            congestion: parking_lot::Mutex::new(congestion::CongestionMonitor::default()),
+            pcap_export: parking_lot::Mutex::new(pcap_export),
+            self_test,
+            events: events::EventBus::new(),
+            terminated: parking_lot::Mutex::new(None),
+            stage_key: rand::random(),
+            size_run: parking_lot::Mutex::new((0, 0)),
         })
     }
 
+    /// Call `callback` for every security event of `kind`, e.g. to tell
+    /// users the camouflage is switching
+    pub fn subscribe<F>(&self, kind: events::EventKind, callback: F) -> events::SubscriptionId
+    where
+        F: Fn(&events::SecurityEvent) + Send + Sync + 'static,
+    {
+        self.events.subscribe(kind, callback)
+    }
+
+    pub fn unsubscribe(&self, id: events::SubscriptionId) -> bool {
+        self.events.unsubscribe(id)
+    }
+
+    /// Emit an adaptation level change if the level moved from `from`
+    fn emit_level_change(&self, from: u8) {
+        let to = self.detection_evader.adaptation_level();
+        if to != from {
+            self.events
+                .emit(&events::SecurityEvent::AdaptationLevelChanged { from, to });
+        }
+    }
+
+    /// Count tunnel traffic towards the decoy share and, under an overhead
+    /// budget, charge cover traffic to it and scale the decoy volume
+    pub fn attach_decoys(&mut self, stats: Arc<decoy::DecoyStats>) {
+        self.decoy_charged = AtomicU64::new(stats.decoy_bytes());
+        self.decoys = Some(stats);
+    }
+
+    /// Cover-traffic scheduling at the configured `decoy_traffic_percentage`
+    pub fn decoy_config(&self) -> decoy::DecoyConfig {
+        decoy::DecoyConfig {
+            percentage: self.config().decoy_traffic_percentage,
+            ..Default::default()
+        }
+    }
+
+    /// A decoy scheduler keeping cover traffic at the configured share of
+    /// this processor's output, scaled with the adaptation level
+    ///
+    /// The share is read once; a scheduler created before `update_config`
+    /// keeps the old one.
+    pub fn decoy_scheduler(
+        &mut self,
+        pool: sni_pool::SniPool,
+        fetcher: Arc<dyn decoy::DecoyFetcher>,
+    ) -> Result<Arc<decoy::DecoyScheduler>> {
+        let scheduler = decoy::DecoyScheduler::new(self.decoy_config(), pool, fetcher)?;
+        self.attach_decoys(scheduler.stats());
+        scheduler.stats().set_scale(self.decoy_scale());
+        Ok(Arc::new(scheduler))
+    }
+
+    /// What each feature has cost in bytes so far
+    pub fn overhead_stats(&self) -> OverheadStats {
+        let mut stats = *self.overhead.lock();
+        if let Some(decoys) = &self.decoys {
+            stats.decoy_bytes = decoys.decoy_bytes();
+        }
+        stats
+    }
+
+    /// Feed a reading of the tunnel socket's RTT and retransmits; padding
+    /// and decoy volume back off while the path is congested
+    pub fn report_path_sample(&self, sample: congestion::PathSample) {
+        self.congestion.lock().observe(sample);
+        if let Some(decoys) = &self.decoys {
+            decoys.set_scale(self.decoy_scale());
+        }
+    }
+
+    /// Share of the optional padding, decoy and noise volume in use, after
+    /// the overhead budget and path congestion
+    pub fn intensity(&self) -> f64 {
+        let budget = self
+            .budget
+            .as_ref()
+            .map_or(1.0, |budget| budget.lock().intensity());
+        budget * self.congestion.lock().scale()
+    }
+
+    /// Share of the configured cover traffic to run: the intensity, scaled
+    /// by the adaptation level
+    fn decoy_scale(&self) -> f64 {
+        self.intensity() * self.detection_evader.generate_strategy().decoy_scale()
+    }
+
+    /// State of the overhead budget, if one is set
+    pub fn budget_status(&self) -> Option<budget::BudgetStatus> {
+        self.budget.as_ref().map(|budget| budget.lock().status())
+    }
+
     /// Process outgoing traffic with security enhancements
     pub fn process_outgoing(&self, data: &[u8]) -> Result<Vec<u8>> {
+        self.process_outgoing_with_stats(data).map(|(processed, _)| processed)
+    }
+
+    /// Process outgoing traffic, reporting what was done to the packet
+    ///
+    /// Payloads that are ciphertext already (tunnelled TLS, high-entropy
+    /// bytes) skip the byte-scrambling stages, which only add overhead to
+    /// them, and get fragmentation and timing shaping alone.
+    pub fn process_outgoing_with_stats(&self, data: &[u8]) -> Result<(Vec<u8>, PacketStats)> {
+        self.check_kill_switch()?;
+        let kind = entropy::classify(data);
+        let scrambled = !kind.is_encrypted();
+        let intensity = self.intensity();
+        let padding_ratio = self.detection_evader.generate_strategy().padding_ratio;
+        // Held until the packet is chosen, so re-plans start from the codec
+        // as this packet found it and only the packet sent changes it
+        let mut cells = self.cells.as_ref().map(|cells| cells.lock());
+        let snapshot = self.self_test.as_ref().and_then(|_| cells.as_deref().cloned());
+        let (mut processed, mut overhead) = self
+            .run_pipeline(data, scrambled, intensity, padding_ratio, cells.as_deref_mut())
+            .map_err(|e| self.fail(e))?;
+
+        let mut replans = 0;
+        if let Some(self_test) = &self.self_test {
+            let first_score = self_test.score(&processed);
+            let mut score = first_score;
+            while self_test.is_flagged(score) && replans < MAX_REPLANS {
+                replans += 1;
+                // Heavier shaping: full padding and noise, whatever the
+                // budget and congestion allow
+                let mut codec = snapshot.clone();
+                let (candidate, candidate_overhead) = self
+                    .run_pipeline(data, scrambled, 1.0, 1.0, codec.as_mut())
+                    .map_err(|e| self.fail(e))?;
+                let candidate_score = self_test.score(&candidate);
+                if candidate_score < score {
+                    processed = candidate;
+                    overhead = candidate_overhead;
+                    score = candidate_score;
+                    if let (Some(live), Some(codec)) = (cells.as_deref_mut(), codec) {
+                        *live = codec;
+                    }
+                }
+            }
+            self_test.record(processed.len(), first_score, score, replans);
+        }
+        drop(cells);
+        self.break_size_run(&mut processed, &mut overhead);
+
+        self.account(data.len(), processed.len());
+        self.overhead.lock().add(&overhead, data.len(), processed.len());
+        self.export(&processed);
+
+        let stats = PacketStats {
+            kind,
+            scrambled,
+            input_len: data.len(),
+            output_len: processed.len(),
+            replans,
+        };
+        Ok((processed, stats))
+    }
+
+    /// Run the outgoing stages once, with the optional volume scaled by
+    /// `intensity` and the obfuscation padding by `padding_ratio` too
+    ///
+    /// The cell codec is the only stage with state; it is passed in so
+    /// candidate plans can run on a copy.
+    fn run_pipeline(
+        &self,
+        data: &[u8],
+        scrambled: bool,
+        intensity: f64,
+        padding_ratio: f64,
+        cells: Option<&mut cells::CellCodec>,
+    ) -> Result<(Vec<u8>, OverheadStats)> {
         let mut processed = data.to_vec();
+        let mut overhead = OverheadStats::default();
+        let pattern = self.pattern_rotator.current_pattern_byte();
 
-        // Apply obfuscation
-        if self.config.enforce_obfuscation {
-            processed = self.obfuscator.obfuscate(&processed)?;
+        if scrambled {
+            // Apply obfuscation
+            if self.settings.obfuscation.enabled {
+                let input = processed.len();
+                let (output, padding) = self
+                    .obfuscator
+                    .obfuscate_counted(&processed, intensity * padding_ratio)?;
+                processed = output;
+                overhead.obfuscation.record(input, processed.len());
+                overhead.padding_bytes += padding as u64;
+            }
+
+            // Apply pattern rotation
+            let input = processed.len();
+            processed = pattern_rotation::PatternRotator::apply_pattern(pattern, &processed);
+            overhead.pattern_rotation.record(input, processed.len());
+
+            // Apply detection evasion if enabled
+            if self.settings.detection_evasion.enabled {
+                let input = processed.len();
+                processed = self
+                    .detection_evader
+                    .evade_detection_scaled(&processed, intensity)?;
+                overhead.detection_evasion.record(input, processed.len());
+                // Everything this stage adds is noise
+                overhead.padding_bytes += processed.len().saturating_sub(input) as u64;
+            }
         }
 
-        // Apply pattern rotation
-        processed = self.pattern_rotator.rotate_pattern(&processed)?;
+        // Tell the peer which stages ran, under the DPI bypass framing so
+        // the packet still starts like a TLS record
+        let input = processed.len();
+        let flags = if scrambled { STAGE_SCRAMBLED } else { 0 };
+        processed.splice(0..0, self.seal_stage_header(flags, pattern));
 
         // Apply DPI bypass techniques
         processed = self.dpi_bypasser.apply_evasion(&processed)?;
+        overhead.dpi_bypass.record(input, processed.len());
 
-        // Apply detection evasion if enabled
-        if self.config.enable_ai_evasion {
-            processed = self.detection_evader.evade_detection(&processed)?;
+        // Shape the byte distribution
+        if let Some(shaper) = &self.entropy_shaper {
+            let input = processed.len();
+            processed = shaper.encode(&processed);
+            overhead.entropy.record(input, processed.len());
         }
 
-        Ok(processed)
+        // Pad into fixed-size records
+        if let Some(padder) = &self.record_padder {
+            let input = processed.len();
+            processed = padder.pad(&processed);
+            overhead.record_padding.record(input, processed.len());
+        }
+
+        // Carry everything in fixed-size cells
+        if let Some(cells) = cells {
+            let input = processed.len();
+            processed = cells.encode(&processed);
+            overhead.cells.record(input, processed.len());
+            overhead.padding_bytes += (processed.len() - input) as u64;
+        }
+
+        Ok((processed, overhead))
+    }
+
+    /// Grow a packet that would make the run of equal sizes longer than
+    /// `MAX_UNIFORM_RUN`, by a padding cell or an empty padded record;
+    /// without those stages packet sizes vary on their own
+    fn break_size_run(&self, processed: &mut Vec<u8>, overhead: &mut OverheadStats) {
+        let mut run = self.size_run.lock();
+        if run.0 == processed.len() && run.1 >= MAX_UNIFORM_RUN {
+            let input = processed.len();
+            if let Some(cells) = &self.cells {
+                processed.extend(cells.lock().next_cell());
+                overhead.cells.output += (processed.len() - input) as u64;
+            } else if let Some(padder) = &self.record_padder {
+                processed.extend(padder.padding_record());
+                overhead.record_padding.output += (processed.len() - input) as u64;
+            }
+            overhead.padding_bytes += (processed.len() - input) as u64;
+        }
+
+        if run.0 == processed.len() {
+            run.1 += 1;
+        } else {
+            *run = (processed.len(), 1);
+        }
+    }
+
+    /// Cells sent so far, if cell mode is on
+    pub fn cell_stats(&self) -> Option<cells::CellStats> {
+        self.cells.as_ref().map(|cells| cells.lock().stats())
+    }
+
+    /// Self-test scores and re-plans so far, if the self-test is on
+    pub fn self_test_stats(&self) -> Option<self_test::SelfTestStats> {
+        self.self_test.as_ref().map(self_test::SelfTest::stats)
+    }
+
+    /// Feed one packet to the decoy counters and the overhead budget
+    fn account(&self, original: usize, transmitted: usize) {
+        let mut decoy_bytes = 0;
+        if let Some(decoys) = &self.decoys {
+            decoys.record_real(transmitted as u64);
+            let total = decoys.decoy_bytes();
+            let charged = self.decoy_charged.swap(total, Ordering::Relaxed);
+            decoy_bytes = total.saturating_sub(charged) as usize;
+        }
+        if let Some(budget) = &self.budget {
+            budget.lock().record(original, transmitted + decoy_bytes);
+        }
+        if let Some(decoys) = &self.decoys {
+            decoys.set_scale(self.decoy_scale());
+        }
+    }
+
+    /// Append an outgoing packet to the PCAP export, if one is running
+    fn export(&self, packet: &[u8]) {
+        let mut export = self.pcap_export.lock();
+        if let Some(flow) = export.as_mut() {
+            let delay = self.dpi_bypasser.next_delay(packet.len());
+            if let Err(e) = flow.send(delay, packet) {
+                log::warn!("PCAP export stopped: {}", e);
+                *export = None;
+            }
+        }
+    }
+
+    /// Close the exported flow and flush the PCAP file
+    pub fn finish_pcap_export(&self) -> Result<()> {
+        match self.pcap_export.lock().take() {
+            Some(flow) => flow.finish().map(drop),
+            None => Ok(()),
+        }
     }
 
     /// Process incoming traffic
     pub fn process_incoming(&self, data: &[u8]) -> Result<Vec<u8>> {
-        let mut processed = data.to_vec();
+        self.check_kill_switch()?;
+        self.reverse_pipeline(data).map_err(|e| self.fail(e))
+    }
 
-        // Reverse detection evasion
-        if self.config.enable_ai_evasion {
-            processed = self.detection_evader.reverse_evasion(&processed)?;
+    fn reverse_pipeline(&self, data: &[u8]) -> Result<Vec<u8>> {
+        // Unpack cells; nothing to pass on until a data cell completes
+        let mut processed = match &self.cells {
+            Some(cells) => cells.lock().decode(data)?,
+            None => data.to_vec(),
+        };
+        if self.cells.is_some() && processed.is_empty() {
+            return Ok(processed);
+        }
+
+        // Strip record padding
+        if let Some(padder) = &self.record_padder {
+            processed = padder.unpad(&processed)?;
+        }
+
+        // Undo entropy shaping
+        if let Some(shaper) = &self.entropy_shaper {
+            processed = shaper.decode(&processed)?;
         }
 
         // Reverse DPI bypass
         processed = self.dpi_bypasser.reverse_evasion(&processed)?;
 
-        // Reverse pattern rotation
-        processed = self.pattern_rotator.reverse_rotation(&processed)?;
+        // The stage header says which stages the sender ran
+        if processed.len() < STAGE_HEADER_LEN {
+            return Err(Error::DataError("Missing stage header".to_string()));
+        }
+        let (flags, pattern) = self.open_stage_header(&processed[..STAGE_HEADER_LEN]);
+        processed.drain(..STAGE_HEADER_LEN);
+        let scrambled = flags & STAGE_SCRAMBLED != 0;
+
+        if scrambled {
+            // Reverse detection evasion
+            if self.settings.detection_evasion.enabled {
+                processed = self.detection_evader.reverse_evasion(&processed)?;
+            }
+
+            // Reverse pattern rotation with the sender's pattern
+            processed = pattern_rotation::PatternRotator::reverse_pattern(pattern, &processed);
 
-        // Reverse obfuscation
-        if self.config.enforce_obfuscation {
-            processed = self.obfuscator.deobfuscate(&processed)?;
+            // Reverse obfuscation
+            if self.settings.obfuscation.enabled {
+                processed = self.obfuscator.deobfuscate(&processed)?;
+            }
         }
 
         Ok(processed)
     }
 
+    /// A stage header carrying `flags` and `pattern` under a fresh nonce
+    fn seal_stage_header(&self, flags: u8, pattern: u8) -> [u8; STAGE_HEADER_LEN] {
+        let nonce: [u8; STAGE_NONCE_LEN] = rand::random();
+        let mask = self.stage_mask(&nonce);
+        let mut header = [0; STAGE_HEADER_LEN];
+        header[..STAGE_NONCE_LEN].copy_from_slice(&nonce);
+        header[STAGE_NONCE_LEN] = flags ^ mask[0];
+        header[STAGE_NONCE_LEN + 1] = pattern ^ mask[1];
+        header
+    }
+
+    /// The flags and pattern byte in a stage header
+    fn open_stage_header(&self, header: &[u8]) -> (u8, u8) {
+        let (nonce, masked) = header.split_at(STAGE_NONCE_LEN);
+        let mask = self.stage_mask(nonce);
+        (masked[0] ^ mask[0], masked[1] ^ mask[1])
+    }
+
+    fn stage_mask(&self, nonce: &[u8]) -> [u8; 32] {
+        use sha2::Digest;
+        let mut hasher = sha2::Sha256::new();
+        hasher.update(self.stage_key);
+        hasher.update(nonce);
+        hasher.finalize().into()
+    }
+
+    /// Prepare a ClientHello for the wire: decoys go out first under a
+    /// desync strategy, then the ClientHello is cut into fragments to send
+    /// with the given delays, as set in `tls_fragmentation`
+    pub fn process_client_hello(
+        &self,
+        client_hello: &[u8],
+    ) -> Result<Vec<tls_fragmentation::FragmentedPacket>> {
+        self.check_kill_switch()?;
+        self.client_hello_pipeline(client_hello)
+            .map_err(|e| self.fail(e))
+    }
+
+    fn client_hello_pipeline(
+        &self,
+        client_hello: &[u8],
+    ) -> Result<Vec<tls_fragmentation::FragmentedPacket>> {
+        let prepared = self.dpi_bypasser.prepare_client_hello(client_hello)?;
+        let bypass = &self.settings.dpi_bypass;
+        if !(bypass.enabled && bypass.fragmentation_enabled) {
+            return Ok(vec![tls_fragmentation::FragmentedPacket {
+                data: prepared,
+                delay_ms: 0,
+            }]);
+        }
+        self.tls_fragmenter
+            .fragment_with_ipd(&prepared)
+            .map_err(Error::DPIBypassError)
+    }
+
+    /// Prepare a plain-HTTP request for the wire: header rewrites and the
+    /// cut into segments set in `dpi_bypass.http_evasion`; each packet goes
+    /// out as its own TCP segment
+    pub fn process_http_request(
+        &self,
+        request: &[u8],
+    ) -> Result<Vec<tls_fragmentation::FragmentedPacket>> {
+        self.check_kill_switch()?;
+        if !self.settings.dpi_bypass.enabled {
+            return Ok(vec![tls_fragmentation::FragmentedPacket {
+                data: request.to_vec(),
+                delay_ms: 0,
+            }]);
+        }
+        Ok(self.dpi_bypasser.prepare_http_request(request))
+    }
+
+    /// Server name to present for `host`, as set in `sni_obfuscation`;
+    /// `host` itself when TLS evasion is off
+    pub fn server_name(&self, host: &str) -> String {
+        let bypass = &self.settings.dpi_bypass;
+        if bypass.enabled && bypass.tls_evasion_enabled {
+            self.sni_obfuscator.obfuscate_sni(host)
+        } else {
+            host.to_string()
+        }
+    }
+
+    /// Delay before sending the next packet of `len` bytes, from the
+    /// scheduler set in `dpi_bypass.packet_scheduler`
+    pub fn next_delay(&self, len: usize) -> std::time::Duration {
+        self.dpi_bypasser.next_delay(len)
+    }
+
+    /// Fake-SNI pool the server names are drawn from
+    pub fn sni_pool(&self) -> &sni_pool::SniPool {
+        self.sni_obfuscator.pool()
+    }
+
+    /// TCP/IP parameters for a session, fresh once per rotation interval
+    /// of `session_patterns`
+    pub fn session_parameters(&self, session_id: &str) -> dynamic_patterns::SessionParameters {
+        if self.session_patterns.should_rotate_session(session_id) {
+            if let Some(parameters) = self.session_patterns.rotate_session_parameters(session_id) {
+                return parameters;
+            }
+        }
+        self.session_patterns.get_session_parameters(session_id)
+    }
+
+    /// Terminate the session: every later packet is refused until
+    /// `reset_kill_switch`; for layers outside the processor, such as the
+    /// handshake, to fail closed too
+    pub fn trip_kill_switch(&self, reason: &str) {
+        let mut terminated = self.terminated.lock();
+        if terminated.is_some() {
+            return;
+        }
+        log::warn!("Kill switch tripped: {}", reason);
+        *terminated = Some(reason.to_string());
+        drop(terminated);
+        self.events.emit(&events::SecurityEvent::SessionTerminated {
+            reason: reason.to_string(),
+        });
+    }
+
+    pub fn is_terminated(&self) -> bool {
+        self.terminated.lock().is_some()
+    }
+
+    /// Allow processing again, for a new session
+    pub fn reset_kill_switch(&self) {
+        *self.terminated.lock() = None;
+    }
+
+    fn check_kill_switch(&self) -> Result<()> {
+        match &*self.terminated.lock() {
+            Some(reason) => Err(Error::SessionTerminated(reason.clone())),
+            None => Ok(()),
+        }
+    }
+
+    /// In fail-closed mode, trip the kill switch over a stage error
+    fn fail(&self, error: Error) -> Error {
+        if !self.settings.processor.fail_closed {
+            return error;
+        }
+        let reason = error.to_string();
+        self.trip_kill_switch(&reason);
+        Error::SessionTerminated(reason)
+    }
+
+    /// Key the detection-evasion scrambling and the stage header with
+    /// secret material shared with the peer, so it can reverse them
+    pub fn set_session_key(&mut self, secret: &[u8]) {
+        self.detection_evader.set_session_key(secret);
+        hkdf::Hkdf::<sha2::Sha256>::new(None, secret)
+            .expand(b"iran-proxy stage header", &mut self.stage_key)
+            .expect("32 bytes is a valid HKDF output length");
+    }
+
+    /// Feed a DNS cross-check into the detection feedback loop; poisoning
+    /// means the path is actively filtered, so evasion escalates
+    pub fn report_dns_poisoning(&mut self, check: &resolver::PoisoningCheck) -> Result<()> {
+        if check.is_poisoned() {
+            let level = self.detection_evader.adaptation_level();
+            self.detection_evader.adapt_to_detection()?;
+            self.emit_level_change(level);
+        }
+        Ok(())
+    }
+
+    /// Feed a throttling-detector verdict into the feedback loop
+    ///
+    /// Blocking means the current method is detected, so evasion escalates
+    /// and a fresh rotation pattern is picked, which the peer reads from the
+    /// stage header; throttling is answered with a fresh handshake, which
+    /// the caller performs when told to.
+    pub fn report_path_verdict(
+        &mut self,
+        verdict: throttle::PathVerdict,
+    ) -> Result<throttle::Adaptation> {
+        let response = verdict.response();
+        if response == throttle::Adaptation::SwitchStrategy {
+            let level = self.detection_evader.adaptation_level();
+            self.detection_evader.adapt_to_detection()?;
+            self.pattern_rotator.rotate_now();
+            log::info!(
+                "Path blocked; evasion raised to level {}",
+                self.detection_evader.adaptation_level()
+            );
+            self.events
+                .emit(&events::SecurityEvent::StrategySwitched { verdict });
+            self.events.emit(&events::SecurityEvent::PatternRotated {
+                pattern_id: self.pattern_rotator.current_pattern_id(),
+            });
+            self.emit_level_change(level);
+        }
+        Ok(response)
+    }
+
+    /// Hold the adaptation level at the minimum of the calendar rules active
+    /// at `at`, or release the hold when none is; call it periodically,
+    /// e.g. once a minute, to follow the schedule
+    pub fn apply_calendar(
+        &mut self,
+        calendar: &calendar::StrategyCalendar,
+        at: std::time::SystemTime,
+    ) -> Option<calendar::Escalation> {
+        let escalation = calendar.escalation(at);
+        let level = escalation.map_or(1, |escalation| escalation.min_adaptation_level);
+        if level != self.detection_evader.min_level() {
+            log::info!("Calendar holds the adaptation level at {} or above", level);
+            let previous = self.detection_evader.adaptation_level();
+            self.detection_evader.set_min_level(level);
+            self.emit_level_change(previous);
+            if let Some(decoys) = &self.decoys {
+                decoys.set_scale(self.decoy_scale());
+            }
+        }
+        escalation
+    }
+
+    /// Feed a connection outcome into the feedback loop, adapting as for
+    /// the matching path verdict
+    pub fn report_network_event(
+        &mut self,
+        event: throttle::NetworkEvent,
+    ) -> Result<throttle::Adaptation> {
+        self.report_path_verdict(event.verdict())
+    }
+
+    /// Surface a probe the server's `ProbeGuard` caught
+    pub fn report_probe(&self, event: &probe_guard::ProbeEvent) {
+        self.events
+            .emit(&events::SecurityEvent::ProbeDetected(event.clone()));
+    }
+
+    /// Surface a blocking diagnosis and, for mechanisms that detected the
+    /// connection itself, adapt as for the matching network event
+    pub fn report_diagnosis(
+        &mut self,
+        diagnosis: &diagnosis::Diagnosis,
+    ) -> Result<Option<throttle::Adaptation>> {
+        self.events
+            .emit(&events::SecurityEvent::BlockingDiagnosed(diagnosis.clone()));
+        diagnosis
+            .mechanism
+            .network_event()
+            .map(|event| self.report_network_event(event))
+            .transpose()
+    }
+
     /// Get configuration
     pub fn config(&self) -> &SecurityConfig {
         &self.config
     }
 
+    /// Get the full settings
+    pub fn settings(&self) -> &config::SecuritySettings {
+        &self.settings
+    }
+
     /// Update configuration dynamically
+    ///
+    /// Only the settings `SecurityConfig` covers change; the rest, e.g. those
+    /// given to `with_settings`, are kept.
     pub fn update_config(&mut self, config: SecurityConfig) -> Result<()> {
-        let pattern_rotation_interval = config.pattern_rotation_interval_hours;
-        let max_adaptation_level = config.max_adaptation_level;
+        let mut settings = self.settings.clone();
+        config.apply_to(&mut settings);
+        self.update_settings(settings)
+    }
+
+    /// Update the full settings dynamically
+    pub fn update_settings(&mut self, settings: config::SecuritySettings) -> Result<()> {
+        settings.validate()?;
+        let pattern_rotation_interval = settings.pattern_rotation.rotation_interval_hours;
+        let max_adaptation_level = settings.detection_evasion.max_adaptation_level;
 
-        self.config = config;
+        self.budget = match settings.max_overhead_ratio {
+            Some(ratio) => Some(parking_lot::Mutex::new(budget::OverheadBudget::new(ratio)?)),
+            None => None,
+        };
+        let (old, new) = (&self.settings.processor, &settings.processor);
+        if new.privacy_mode != old.privacy_mode {
+            privacy::set_enabled(new.privacy_mode);
+        }
+        if new.pcap_export != old.pcap_export {
+            self.finish_pcap_export()?;
+            *self.pcap_export.lock() = open_pcap_export(new.pcap_export.as_deref())?;
+        }
+        if new.self_test_threshold != old.self_test_threshold {
+            self.self_test = open_self_test(new.self_test_threshold);
+        }
+        self.entropy_shaper = open_entropy_shaper(&settings.obfuscation);
+        self.record_padder = open_record_padder(&settings.obfuscation)?;
+        // A new HTTP/2 envelope would send a second connection preface
+        let profile = settings.obfuscation.camouflage_profile;
+        if profile != self.settings.obfuscation.camouflage_profile {
+            self.obfuscator = obfuscation::Obfuscator::new().with_profile(profile);
+        }
+        // A new codec would drop any partial cell
+        if settings.obfuscation.cell_mode != self.settings.obfuscation.cell_mode {
+            self.cells = open_cells(&settings.obfuscation)?;
+        }
+        let scheduler = &settings.dpi_bypass.packet_scheduler;
+        if *scheduler != self.settings.dpi_bypass.packet_scheduler {
+            self.dpi_bypasser.set_scheduler(scheduler.build()?);
+        }
+        self.dpi_bypasser.set_http_evasion(http_evasion::HttpEvasion::new(
+            settings.dpi_bypass.http_evasion.clone(),
+        ));
+        self.tls_fragmenter =
+            tls_fragmentation::TLSFragmenter::with_config(settings.tls_fragmentation.clone());
+        // Keep the pool handle, which may have been reloaded or validated,
+        // unless the pool itself changed
+        self.sni_obfuscator = if settings.sni_obfuscation.pool_profile
+            == self.settings.sni_obfuscation.pool_profile
+        {
+            sni_obfuscation::SNIObfuscator::with_pool(
+                settings.sni_obfuscation.clone(),
+                self.sni_obfuscator.pool().clone(),
+            )
+        } else {
+            sni_obfuscation::SNIObfuscator::with_config(settings.sni_obfuscation.clone())
+        };
+        self.session_patterns =
+            dynamic_patterns::PatternRotator::with_config(settings.session_patterns.clone());
+        self.config = SecurityConfig::from(&settings);
+        self.settings = settings;
         self.pattern_rotator = pattern_rotation::PatternRotator::new(
             pattern_rotation_interval,
         );
-        self.detection_evader = detection_evasion::DetectionEvader::new(
+        let min_level = self.detection_evader.min_level();
+        let level = self.detection_evader.adaptation_level();
+        self.detection_evader = detection_evasion::DetectionEvader::with_key(
             max_adaptation_level,
+            self.detection_evader.key(),
         );
+        self.detection_evader.set_min_level(min_level);
+        self.detection_evader
+            .set_ensemble_enabled(self.settings.detection_evasion.ensemble_approach_enabled);
+        self.emit_level_change(level);
         Ok(())
     }
 }
@@ -150,7 +1203,177 @@ mod tests {
     #[test]
     fn test_security_processor_creation() {
         let processor = SecurityProcessor::new().unwrap();
-        assert!(processor.config.enforce_obfuscation);
+        assert!(processor.config().enforce_obfuscation);
+    }
+
+    #[test]
+    fn test_config_settings_conversion() {
+        let config = SecurityConfig {
+            decoy_traffic_percentage: 0,
+            fail_closed: true,
+            max_overhead_ratio: Some(0.2),
+            ..Default::default()
+        };
+        let settings = config::SecuritySettings::try_from(config.clone()).unwrap();
+        assert!(!settings.detection_evasion.decoy_traffic_enabled);
+        assert!(settings.processor.fail_closed);
+        assert_eq!(SecurityConfig::from(&settings), config);
+        assert_eq!(
+            SecurityConfig::from(config::SecuritySettings::default()),
+            SecurityConfig::default()
+        );
+
+        let json = serde_json::to_string(&config).unwrap();
+        assert_eq!(serde_json::from_str::<SecurityConfig>(&json).unwrap(), config);
+        let partial: SecurityConfig = serde_json::from_str(r#"{"fail_closed": true}"#).unwrap();
+        assert_eq!(partial.max_adaptation_level, 5);
+
+        let invalid = SecurityConfig {
+            max_adaptation_level: 0,
+            ..Default::default()
+        };
+        let error = config::SecuritySettings::try_from(invalid.clone()).unwrap_err();
+        assert!(error.has("/detection_evasion/max_adaptation_level"));
+        assert!(SecurityProcessor::with_config(invalid).is_err());
+
+        let processor = SecurityProcessor::with_settings(settings).unwrap();
+        assert_eq!(processor.config(), &config);
+    }
+
+    #[test]
+    fn test_camouflage_profile_setting() {
+        let config = SecurityConfig {
+            camouflage_profile: obfuscation::CamouflageProfile::Chunked,
+            ..Default::default()
+        };
+        let settings = config::SecuritySettings::try_from(config.clone()).unwrap();
+        assert_eq!(
+            settings.obfuscation.camouflage_profile,
+            obfuscation::CamouflageProfile::Chunked
+        );
+        assert_eq!(SecurityConfig::from(&settings), config);
+
+        // Chunk lengths frame the data, so it round-trips with obfuscation on
+        let mut sender = SecurityProcessor::with_config(config.clone()).unwrap();
+        let mut receiver = SecurityProcessor::with_config(config.clone()).unwrap();
+        sender.set_session_key(b"handshake secret");
+        receiver.set_session_key(b"handshake secret");
+        assert_eq!(sender.obfuscator.profile(), obfuscation::CamouflageProfile::Chunked);
+        let payload = b"GET /feed HTTP/1.1\r\nHost: example.com\r\n\r\n".repeat(4);
+        let processed = sender.process_outgoing(&payload).unwrap();
+        assert_eq!(receiver.process_incoming(&processed).unwrap(), payload);
+
+        let mut processor = sender;
+        processor
+            .update_config(SecurityConfig {
+                camouflage_profile: obfuscation::CamouflageProfile::Http2,
+                ..config.clone()
+            })
+            .unwrap();
+        assert_eq!(processor.obfuscator.profile(), obfuscation::CamouflageProfile::Http2);
+        let invalid = SecurityConfig {
+            enforce_obfuscation: false,
+            ..config
+        };
+        assert!(processor.update_config(invalid).is_err());
+    }
+
+    #[test]
+    fn test_update_config_keeps_other_settings() {
+        let mut settings = config::SecuritySettings::default();
+        settings.tls_fragmentation.strategy = tls_fragmentation::FragmentationStrategy::SniSplit;
+        settings.session_patterns.min_ttl = 64;
+        settings.session_patterns.max_ttl = 64;
+        settings.dpi_bypass.fragmentation_enabled = false;
+        let mut processor = SecurityProcessor::with_settings(settings.clone()).unwrap();
+
+        let config = SecurityConfig {
+            max_adaptation_level: 7,
+            ..processor.config().clone()
+        };
+        processor.update_config(config.clone()).unwrap();
+        assert_eq!(processor.config(), &config);
+        let updated = processor.settings();
+        assert_eq!(updated.detection_evasion.max_adaptation_level, 7);
+        assert_eq!(updated.tls_fragmentation.strategy, settings.tls_fragmentation.strategy);
+        assert_eq!(updated.session_patterns.min_ttl, 64);
+        assert!(!updated.dpi_bypass.fragmentation_enabled);
+        assert_eq!(processor.session_parameters("session-1").ttl, 64);
+    }
+
+    #[test]
+    fn test_client_hello_and_session_stages() {
+        let mut settings = config::SecuritySettings::default();
+        settings.tls_fragmentation.strategy = tls_fragmentation::FragmentationStrategy::SniSplit;
+        settings.sni_obfuscation.strategy =
+            sni_obfuscation::ObfuscationStrategy::CapitalizationRandomization;
+        settings.session_patterns.min_ttl = 64;
+        settings.session_patterns.max_ttl = 64;
+        let mut processor = SecurityProcessor::with_settings(settings.clone()).unwrap();
+
+        let client_hello = sni_obfuscation::SNIObfuscator::new().build_client_hello("example.com");
+        let packets = processor.process_client_hello(&client_hello).unwrap();
+        assert!(packets.len() > 1);
+        let data: Vec<Vec<u8>> = packets.into_iter().map(|packet| packet.data).collect();
+        assert_eq!(tls_fragmentation::reassemble_fragments(&data), client_hello);
+        assert!(processor.process_client_hello(b"not a handshake").is_err());
+
+        let name = processor.server_name("www.example.com");
+        assert!(name.eq_ignore_ascii_case("www.example.com"));
+        assert_eq!(processor.session_parameters("session-1").ttl, 64);
+
+        // Without fragmentation the ClientHello goes out whole
+        settings.dpi_bypass.fragmentation_enabled = false;
+        settings.dpi_bypass.tls_evasion_enabled = false;
+        processor.update_settings(settings).unwrap();
+        let packets = processor.process_client_hello(&client_hello).unwrap();
+        assert_eq!(packets.len(), 1);
+        assert_eq!(processor.server_name("www.example.com"), "www.example.com");
+    }
+
+    #[test]
+    fn test_http_evasion_setting() {
+        let request = b"GET / HTTP/1.1\r\nHost: blocked.example\r\n\r\n";
+        let mut settings = config::SecuritySettings::default();
+        let mut processor = SecurityProcessor::with_settings(settings.clone()).unwrap();
+        let packets = processor.process_http_request(request).unwrap();
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].data, request);
+
+        settings.dpi_bypass.http_evasion = config::HttpEvasionConfig {
+            enabled: true,
+            split: http_evasion::HttpSplit::HostHeader,
+            ..Default::default()
+        };
+        processor.update_settings(settings.clone()).unwrap();
+        let packets = processor.process_http_request(request).unwrap();
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0].data, b"GET / HTTP/1.1\r\n");
+
+        // The DPI bypass switch turns it off with the other evasions
+        settings.dpi_bypass.enabled = false;
+        processor.update_settings(settings).unwrap();
+        assert_eq!(processor.process_http_request(request).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_packet_scheduler_setting() {
+        use std::time::Duration;
+
+        let mut settings = config::SecuritySettings::default();
+        settings.dpi_bypass.packet_scheduler = scheduler::SchedulerKind::UniformJitter {
+            min_ms: 7,
+            max_ms: 7,
+        };
+        let mut processor = SecurityProcessor::with_settings(settings.clone()).unwrap();
+        for len in [100, 1400] {
+            assert_eq!(processor.next_delay(len), Duration::from_millis(7));
+        }
+
+        settings.dpi_bypass.packet_scheduler =
+            scheduler::SchedulerKind::ConstantRate { bytes_per_sec: 1000 };
+        processor.update_settings(settings).unwrap();
+        assert_eq!(processor.next_delay(500), Duration::from_millis(500));
     }
 
     #[test]
@@ -160,4 +1383,600 @@ mod tests {
         let result = processor.process_outgoing(test_data);
         assert!(result.is_ok());
     }
+
+    #[test]
+    fn test_encrypted_payload_skips_scrambling() {
+        let processor = SecurityProcessor::new().unwrap();
+        let mut record = vec![0x17, 0x03, 0x03, 0x01, 0x00];
+        record.extend((0..256).map(|i| (i * 97 % 256) as u8));
+
+        let (processed, stats) = processor.process_outgoing_with_stats(&record).unwrap();
+        assert_eq!(stats.kind, entropy::PayloadKind::Tls);
+        assert!(!stats.scrambled);
+        assert_eq!(stats.output_len, processed.len());
+        // Only fragmentation and record framing, no camouflage headers
+        assert!(processed.len() < record.len() + 64);
+
+        let (_, stats) = processor.process_outgoing_with_stats(b"plain text").unwrap();
+        assert_eq!(stats.kind, entropy::PayloadKind::Plain);
+        assert!(stats.scrambled);
+    }
+
+    #[test]
+    fn test_ciphertext_round_trip() {
+        // Detection evasion is on but skipped for ciphertext; the receiver
+        // must not look for its envelope, or the kill switch trips
+        let processor = SecurityProcessor::with_config(SecurityConfig {
+            fail_closed: true,
+            ..Default::default()
+        })
+        .unwrap();
+        let mut record = vec![0x17, 0x03, 0x03, 0x01, 0x00];
+        record.extend((0..256).map(|i| (i * 97 % 256) as u8));
+        let mut random = vec![0u8; 300];
+        rand::Rng::fill(&mut rand::thread_rng(), &mut random[..]);
+
+        for payload in [record, random] {
+            let (processed, stats) = processor.process_outgoing_with_stats(&payload).unwrap();
+            assert!(!stats.scrambled);
+            assert_eq!(processor.process_incoming(&processed).unwrap(), payload);
+        }
+        assert!(!processor.is_terminated());
+    }
+
+    #[test]
+    fn test_stage_header_is_keyed() {
+        let mut sender = SecurityProcessor::new().unwrap();
+        let mut receiver = SecurityProcessor::new().unwrap();
+        sender.set_session_key(b"handshake secret");
+        receiver.set_session_key(b"handshake secret");
+        let mut payload = vec![0u8; 300];
+        rand::Rng::fill(&mut rand::thread_rng(), &mut payload[..]);
+
+        let mut masked_flags = std::collections::HashSet::new();
+        for _ in 0..64 {
+            let processed = sender.process_outgoing(&payload).unwrap();
+            assert_eq!(receiver.process_incoming(&processed).unwrap(), payload);
+            let header = sender.dpi_bypasser.reverse_evasion(&processed).unwrap();
+            masked_flags.insert(header[STAGE_NONCE_LEN] & STAGE_SCRAMBLED);
+        }
+        // The same flags go out under a different mask every packet
+        assert_eq!(masked_flags.len(), 2);
+    }
+
+    #[test]
+    fn test_rotation_on_one_side_keeps_decoding() {
+        // Obfuscation off: its HTTP padding is not length-prefixed, so
+        // scrambled packets only round-trip without it
+        let mut settings = config::SecuritySettings::default();
+        settings.obfuscation.enabled = false;
+        let mut sender = SecurityProcessor::with_settings(settings.clone()).unwrap();
+        let mut receiver = SecurityProcessor::with_settings(settings).unwrap();
+        sender.set_session_key(b"handshake secret");
+        receiver.set_session_key(b"handshake secret");
+
+        let payload = b"GET /feed HTTP/1.1\r\nHost: example.com\r\n\r\n".repeat(4);
+        for round in 0..4 {
+            for _ in 0..8 {
+                let (processed, stats) = sender.process_outgoing_with_stats(&payload).unwrap();
+                assert!(stats.scrambled);
+                assert_eq!(receiver.process_incoming(&processed).unwrap(), payload);
+            }
+            // Only the sender learns of the block and rotates
+            let pattern = sender.pattern_rotator.current_pattern_id();
+            sender
+                .report_path_verdict(throttle::PathVerdict::Blocked)
+                .unwrap();
+            assert_ne!(sender.pattern_rotator.current_pattern_id(), pattern, "{}", round);
+        }
+    }
+
+    #[test]
+    fn test_record_padding_stage() {
+        let mut settings = config::SecuritySettings::default();
+        settings.obfuscation.record_padding_enabled = true;
+        settings.obfuscation.record_padding_buckets = vec![512, 2048];
+        let processor = SecurityProcessor::with_settings(settings).unwrap();
+
+        let mut record = vec![0x17, 0x03, 0x03, 0x01, 0x00];
+        record.extend((0..256).map(|i| (i * 97 % 256) as u8));
+        let processed = processor.process_outgoing(&record).unwrap();
+        // One record with a 512-byte body
+        assert_eq!(processed.len(), 5 + 512);
+        assert_eq!(&processed[..5], [0x17, 0x03, 0x03, 0x02, 0x00]);
+        assert_eq!(processor.process_incoming(&processed).unwrap(), record);
+
+        let stats = processor.overhead_stats();
+        assert_eq!(stats.record_padding.input, stats.dpi_bypass.output);
+        assert_eq!(stats.record_padding.output, stats.transmitted_bytes);
+    }
+
+    #[test]
+    fn test_entropy_encoding_stage() {
+        let mut record = vec![0x17, 0x03, 0x03, 0x04, 0x00];
+        record.extend((0..1024).map(|_| rand::random::<u8>()));
+
+        for encoding in [entropy::EntropyEncoding::Base64, entropy::EntropyEncoding::Html] {
+            let mut settings = config::SecuritySettings::default();
+            settings.obfuscation.entropy_encoding = encoding;
+            let processor = SecurityProcessor::with_settings(settings).unwrap();
+
+            let processed = processor.process_outgoing(&record).unwrap();
+            assert!(entropy::shannon_entropy(&processed) < 6.5);
+            assert_eq!(processor.process_incoming(&processed).unwrap(), record);
+
+            let stats = processor.overhead_stats();
+            assert_eq!(stats.entropy.input, stats.dpi_bypass.output);
+            assert_eq!(stats.entropy.output, stats.transmitted_bytes);
+        }
+    }
+
+    #[test]
+    fn test_cell_mode_stage() {
+        let mut settings = config::SecuritySettings::default();
+        settings.obfuscation.cell_mode = cells::CellMode::Small;
+        let processor = SecurityProcessor::with_settings(settings).unwrap();
+
+        let mut record = vec![0x17, 0x03, 0x03, 0x03, 0x00];
+        record.extend((0..768).map(|i| (i * 97 % 256) as u8));
+        let processed = processor.process_outgoing(&record).unwrap();
+        assert!(processed.len().is_multiple_of(512));
+        assert!(processed.len() >= 2 * 512);
+
+        // A partial cell yields nothing until the rest arrives
+        assert!(processor.process_incoming(&processed[..100]).unwrap().is_empty());
+        assert_eq!(processor.process_incoming(&processed[100..]).unwrap(), record);
+
+        let stats = processor.overhead_stats();
+        assert_eq!(stats.cells.input, stats.dpi_bypass.output);
+        assert_eq!(stats.cells.output, stats.transmitted_bytes);
+    }
+
+    #[test]
+    fn test_fixed_size_stages_break_size_runs() {
+        let mut record = vec![0x17, 0x03, 0x03, 0x03, 0x00];
+        record.extend((0..768).map(|i| (i * 97 % 256) as u8));
+
+        for cell_mode in [cells::CellMode::Off, cells::CellMode::Small] {
+            let mut settings = config::SecuritySettings::default();
+            settings.obfuscation.record_padding_enabled = true;
+            settings.obfuscation.cell_mode = cell_mode;
+            let processor = SecurityProcessor::with_settings(settings).unwrap();
+
+            let mut run = 0;
+            let mut last = 0;
+            for _ in 0..12 {
+                let processed = processor.process_outgoing(&record).unwrap();
+                run = if processed.len() == last { run + 1 } else { 1 };
+                last = processed.len();
+                assert!(run <= MAX_UNIFORM_RUN, "{:?}", cell_mode);
+                assert_eq!(processor.process_incoming(&processed).unwrap(), record);
+            }
+
+            let stats = processor.overhead_stats();
+            assert_eq!(stats.record_padding.input, stats.dpi_bypass.output);
+            let last_stage = match cell_mode {
+                cells::CellMode::Off => stats.record_padding,
+                _ => stats.cells,
+            };
+            assert_eq!(last_stage.output, stats.transmitted_bytes);
+        }
+    }
+
+    #[test]
+    fn test_pcap_export() {
+        let _privacy = privacy::TEST_LOCK.lock();
+        let path = std::env::temp_dir().join(format!("ips-export-{}.pcap", std::process::id()));
+        let config = SecurityConfig {
+            pcap_export: Some(path.clone()),
+            ..Default::default()
+        };
+        let processor = SecurityProcessor::with_config(config).unwrap();
+        let mut sent = 0;
+        for _ in 0..5 {
+            sent += processor.process_outgoing(b"GET / HTTP/1.1\r\n\r\n").unwrap().len();
+        }
+        processor.finish_pcap_export().unwrap();
+
+        let file = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mut reader = pcap::PcapReader::new(&file).unwrap();
+        let mut exported = 0;
+        let mut last = std::time::Duration::ZERO;
+        while let Some(record) = reader.next_record().unwrap() {
+            let info = pcap::decode(reader.link_type(), &record.data).unwrap();
+            if info.src == EXPORT_CLIENT.parse().unwrap() {
+                exported += info.payload_len;
+            }
+            assert!(record.timestamp >= last);
+            last = record.timestamp;
+        }
+        assert_eq!(exported, sent);
+    }
+
+    #[test]
+    fn test_privacy_mode_setting() {
+        let _privacy = privacy::TEST_LOCK.lock();
+        let path = std::env::temp_dir().join(format!("ips-private-{}.pcap", std::process::id()));
+        let config = SecurityConfig {
+            pcap_export: Some(path.clone()),
+            privacy_mode: true,
+            ..Default::default()
+        };
+        let settings = config::SecuritySettings::try_from(config.clone()).unwrap();
+        assert!(settings.processor.privacy_mode);
+        assert_eq!(SecurityConfig::from(&settings), config);
+
+        let mut processor = SecurityProcessor::with_config(config.clone()).unwrap();
+        assert!(privacy::is_enabled());
+        processor.process_outgoing(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        processor.finish_pcap_export().unwrap();
+        assert!(!path.exists());
+
+        processor
+            .update_config(SecurityConfig {
+                privacy_mode: false,
+                pcap_export: None,
+                ..config
+            })
+            .unwrap();
+        assert!(!privacy::is_enabled());
+    }
+
+    #[test]
+    fn test_overhead_budget_dials_down_noise() {
+        let config = SecurityConfig {
+            max_overhead_ratio: Some(0.5),
+            ..Default::default()
+        };
+        let mut processor = SecurityProcessor::with_config(config).unwrap();
+        let decoys = Arc::new(decoy::DecoyStats::default());
+        processor.attach_decoys(decoys.clone());
+
+        let payload = vec![b'a'; 200];
+        for _ in 0..500 {
+            processor.process_outgoing(&payload).unwrap();
+        }
+        // Headers alone are more than half of a 200-byte payload
+        assert_eq!(
+            processor.budget_status(),
+            Some(budget::BudgetStatus::Exceeded)
+        );
+        assert_eq!(decoys.scale(), 0.0);
+        assert!(decoys.real_bytes() > 500 * 200);
+    }
+
+    #[test]
+    fn test_congestion_scales_decoys() {
+        // At the top adaptation level, so only congestion scales decoys
+        let config = SecurityConfig {
+            max_adaptation_level: 1,
+            ..Default::default()
+        };
+        let mut processor = SecurityProcessor::with_config(config).unwrap();
+        let decoys = Arc::new(decoy::DecoyStats::default());
+        processor.attach_decoys(decoys.clone());
+        let sample = |rtt_ms, retransmits| congestion::PathSample {
+            rtt: std::time::Duration::from_millis(rtt_ms),
+            retransmits,
+        };
+
+        processor.report_path_sample(sample(60, 0));
+        processor.report_path_sample(sample(60, 4));
+        assert_eq!(processor.intensity(), 0.5);
+        assert_eq!(decoys.scale(), 0.5);
+
+        for _ in 0..6 {
+            processor.report_path_sample(sample(60, 4));
+        }
+        assert_eq!(processor.intensity(), 1.0);
+        assert_eq!(decoys.scale(), 1.0);
+    }
+
+    #[test]
+    fn test_adaptation_level_scales_decoys() {
+        let mut processor = SecurityProcessor::new().unwrap();
+        let decoys = Arc::new(decoy::DecoyStats::default());
+        processor.attach_decoys(decoys.clone());
+        processor.process_outgoing(b"payload").unwrap();
+        assert_eq!(decoys.scale(), 0.2);
+
+        for _ in 0..4 {
+            processor
+                .report_path_verdict(throttle::PathVerdict::Blocked)
+                .unwrap();
+        }
+        processor.process_outgoing(b"payload").unwrap();
+        assert_eq!(decoys.scale(), 1.0);
+    }
+
+    /// Moves exactly the budget without touching the network
+    struct CountingFetcher;
+
+    #[async_trait::async_trait]
+    impl decoy::DecoyFetcher for CountingFetcher {
+        async fn fetch(&self, _host: &str, budget: u64) -> Result<u64> {
+            Ok(budget)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_decoy_percentage_end_to_end() {
+        for percentage in [0, 10, 30] {
+            // At the top adaptation level, so the full share is produced
+            let config = SecurityConfig {
+                decoy_traffic_percentage: percentage,
+                max_adaptation_level: 1,
+                ..Default::default()
+            };
+            let mut processor = SecurityProcessor::with_config(config).unwrap();
+            let scheduler = processor
+                .decoy_scheduler(sni_pool::SniPool::builtin(), Arc::new(CountingFetcher))
+                .unwrap();
+            assert_eq!(scheduler.config().percentage, percentage);
+
+            let payload = vec![b'a'; 4000];
+            for _ in 0..50 {
+                for _ in 0..10 {
+                    processor.process_outgoing(&payload).unwrap();
+                }
+                for handle in scheduler.schedule() {
+                    handle.await.unwrap();
+                }
+            }
+            let realized = scheduler.stats().decoy_ratio();
+            let target = percentage as f64 / 100.0;
+            assert!((realized - target).abs() < 0.02, "{} vs {}", realized, target);
+        }
+
+        // An all-cover share is rejected with the rest of the settings
+        let config = SecurityConfig {
+            decoy_traffic_percentage: 100,
+            ..Default::default()
+        };
+        assert!(SecurityProcessor::with_config(config).is_err());
+    }
+
+    #[test]
+    fn test_overhead_stats() {
+        let mut processor = SecurityProcessor::new().unwrap();
+        let decoys = Arc::new(decoy::DecoyStats::default());
+        processor.attach_decoys(decoys);
+
+        let payload = vec![b'a'; 1000];
+        for _ in 0..10 {
+            processor.process_outgoing(&payload).unwrap();
+        }
+        let stats = processor.overhead_stats();
+        assert_eq!(stats.packets, 10);
+        assert_eq!(stats.original_bytes, 10_000);
+        assert_eq!(stats.obfuscation.input, 10_000);
+        // Each stage feeds the next
+        assert_eq!(stats.pattern_rotation.input, stats.obfuscation.output);
+        assert_eq!(stats.detection_evasion.input, stats.pattern_rotation.output);
+        assert_eq!(stats.dpi_bypass.input, stats.detection_evasion.output);
+        assert_eq!(stats.dpi_bypass.output, stats.transmitted_bytes);
+        assert!(stats.obfuscation.expansion() > 1.0);
+        assert!(stats.padding_bytes < stats.transmitted_bytes - stats.original_bytes);
+        assert!(stats.overhead_ratio() > 0.0);
+        assert_eq!(stats.decoy_bytes, 0);
+    }
+
+    #[test]
+    fn test_self_test_replans_flagged_packets() {
+        assert!(SecurityProcessor::new().unwrap().self_test_stats().is_none());
+
+        // A threshold nothing passes: every packet is flagged and re-planned
+        let config = SecurityConfig {
+            self_test_threshold: Some(0.0),
+            ..Default::default()
+        };
+        let processor = SecurityProcessor::with_config(config).unwrap();
+        let payload = vec![b'a'; 1000];
+        for _ in 0..5 {
+            let (_, packet) = processor.process_outgoing_with_stats(&payload).unwrap();
+            assert_eq!(packet.replans, MAX_REPLANS);
+        }
+        let stats = processor.self_test_stats().unwrap();
+        assert_eq!(stats.packets, 5);
+        assert_eq!(stats.flagged, 5);
+        assert_eq!(stats.replans, 5 * MAX_REPLANS as u64);
+        assert_eq!(stats.sent_flagged, 5);
+        assert!(stats.mean_score > 0.0 && stats.mean_score < 1.0);
+    }
+
+    #[test]
+    fn test_replans_leave_no_trace_in_cells() {
+        let mut settings = config::SecuritySettings::default();
+        settings.obfuscation.cell_mode = cells::CellMode::Small;
+        settings.processor.self_test_threshold = Some(0.0);
+        let processor = SecurityProcessor::with_settings(settings).unwrap();
+        assert!(SecurityProcessor::new().unwrap().cell_stats().is_none());
+
+        let mut record = vec![0x17, 0x03, 0x03, 0x03, 0x00];
+        record.extend((0..768).map(|_| rand::random::<u8>()));
+        for _ in 0..3 {
+            let (processed, packet) = processor.process_outgoing_with_stats(&record).unwrap();
+            assert_eq!(packet.replans, MAX_REPLANS);
+            assert_eq!(processor.process_incoming(&processed).unwrap(), record);
+        }
+
+        // Only the cells of the packets sent are counted
+        let cells = processor.cell_stats().unwrap();
+        let stats = processor.overhead_stats();
+        assert_eq!(cells.payload_bytes, stats.cells.input);
+        assert_eq!(cells.wire_bytes, stats.transmitted_bytes);
+        assert_eq!(cells.padding_cells, 0);
+    }
+
+    #[test]
+    fn test_path_verdicts_adapt_differently() {
+        let mut processor = SecurityProcessor::new().unwrap();
+        let before = processor.detection_evader.adaptation_level();
+
+        let response = processor
+            .report_path_verdict(throttle::PathVerdict::Throttled)
+            .unwrap();
+        assert_eq!(response, throttle::Adaptation::Rehandshake);
+        assert_eq!(processor.detection_evader.adaptation_level(), before);
+
+        let response = processor
+            .report_path_verdict(throttle::PathVerdict::Blocked)
+            .unwrap();
+        assert_eq!(response, throttle::Adaptation::SwitchStrategy);
+        assert_eq!(processor.detection_evader.adaptation_level(), before + 1);
+    }
+
+    #[test]
+    fn test_network_events_drive_adaptation() {
+        use throttle::{Adaptation, NetworkEvent};
+
+        let mut processor = SecurityProcessor::new().unwrap();
+        let pattern = processor.pattern_rotator.current_pattern_id();
+        assert_eq!(
+            processor.report_network_event(NetworkEvent::Throttle).unwrap(),
+            Adaptation::Rehandshake
+        );
+        assert_eq!(processor.detection_evader.adaptation_level(), 1);
+        assert_eq!(processor.pattern_rotator.current_pattern_id(), pattern);
+
+        for event in [NetworkEvent::ConnReset, NetworkEvent::Timeout] {
+            assert_eq!(
+                processor.report_network_event(event).unwrap(),
+                Adaptation::SwitchStrategy
+            );
+        }
+        assert_eq!(processor.detection_evader.adaptation_level(), 3);
+        assert_ne!(processor.pattern_rotator.current_pattern_id(), pattern);
+    }
+
+    #[test]
+    fn test_calendar_holds_adaptation_level() {
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let mut processor = SecurityProcessor::new().unwrap();
+        let calendar = calendar::StrategyCalendar::default();
+        // 2026-10-16 19:00 and 12:00 in Tehran
+        let evening = UNIX_EPOCH + Duration::from_secs(1_792_108_800 - 12_600 + 19 * 3600);
+        let noon = evening - Duration::from_secs(7 * 3600);
+
+        assert!(processor.apply_calendar(&calendar, noon).is_none());
+        assert_eq!(processor.detection_evader.adaptation_level(), 1);
+        assert!(processor.apply_calendar(&calendar, evening).is_some());
+        assert_eq!(processor.detection_evader.adaptation_level(), 3);
+        processor.update_config(SecurityConfig::default()).unwrap();
+        assert_eq!(processor.detection_evader.adaptation_level(), 3);
+        processor.apply_calendar(&calendar, noon);
+        assert_eq!(processor.detection_evader.adaptation_level(), 1);
+    }
+
+    #[test]
+    fn test_events_follow_adaptation() {
+        use events::{EventKind, SecurityEvent};
+        let mut processor = SecurityProcessor::new().unwrap();
+        let seen = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        for kind in events::EventKind::ALL {
+            let sink = seen.clone();
+            processor.subscribe(kind, move |event| sink.lock().push(event.clone()));
+        }
+
+        let reset = diagnosis::Diagnosis {
+            mechanism: diagnosis::Mechanism::RstInjection,
+            detail: "forged RST".to_string(),
+        };
+        assert_eq!(
+            processor.report_diagnosis(&reset).unwrap(),
+            Some(throttle::Adaptation::SwitchStrategy)
+        );
+        let kinds: Vec<EventKind> = seen.lock().iter().map(SecurityEvent::kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                EventKind::BlockingDiagnosed,
+                EventKind::StrategySwitched,
+                EventKind::PatternRotated,
+                EventKind::AdaptationLevelChanged,
+            ]
+        );
+        assert_eq!(
+            seen.lock()[3],
+            SecurityEvent::AdaptationLevelChanged { from: 1, to: 2 }
+        );
+
+        // Throttling needs no switch, and DNS poisoning no adaptation here
+        seen.lock().clear();
+        processor
+            .report_path_verdict(throttle::PathVerdict::Throttled)
+            .unwrap();
+        let dns = diagnosis::Diagnosis {
+            mechanism: diagnosis::Mechanism::DnsPoisoning,
+            detail: "bogus answer".to_string(),
+        };
+        assert_eq!(processor.report_diagnosis(&dns).unwrap(), None);
+        let probe = probe_guard::ProbeEvent {
+            ip: "198.51.100.7".parse().unwrap(),
+            signal: probe_guard::ProbeSignal::ReplayedHandshake,
+            at: std::time::SystemTime::now(),
+            quarantined: true,
+        };
+        processor.report_probe(&probe);
+        assert_eq!(
+            *seen.lock(),
+            [
+                SecurityEvent::BlockingDiagnosed(dns),
+                SecurityEvent::ProbeDetected(probe),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_kill_switch() {
+        let garbage = [0xffu8; 3];
+        // Fail-open: the error is reported and the session carries on
+        let processor = SecurityProcessor::new().unwrap();
+        assert!(processor.process_incoming(&garbage).is_err());
+        assert!(!processor.is_terminated());
+        assert!(processor.process_outgoing(b"payload").is_ok());
+
+        let config = SecurityConfig {
+            fail_closed: true,
+            ..Default::default()
+        };
+        let processor = SecurityProcessor::with_config(config).unwrap();
+        let reasons = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        let sink = reasons.clone();
+        processor.subscribe(events::EventKind::SessionTerminated, move |event| {
+            sink.lock().push(event.clone())
+        });
+        assert!(matches!(
+            processor.process_incoming(&garbage),
+            Err(Error::SessionTerminated(_))
+        ));
+        assert!(processor.is_terminated());
+        assert!(matches!(
+            processor.process_outgoing(b"payload"),
+            Err(Error::SessionTerminated(_))
+        ));
+        processor.trip_kill_switch("again");
+        assert_eq!(reasons.lock().len(), 1);
+
+        processor.reset_kill_switch();
+        assert!(processor.process_outgoing(b"payload").is_ok());
+        processor.trip_kill_switch("handshake failed");
+        assert!(processor.process_outgoing(b"payload").is_err());
+    }
+
+    #[test]
+    fn test_dns_poisoning_escalates_evasion() {
+        let mut processor = SecurityProcessor::new().unwrap();
+        let check = resolver::PoisoningCheck {
+            host: "blocked.example".to_string(),
+            system: vec!["10.10.34.36".parse().unwrap()],
+            encrypted: vec!["104.16.132.229".parse().unwrap()],
+            verdict: resolver::PoisoningVerdict::Injected,
+        };
+        let before = processor.detection_evader.adaptation_level();
+        processor.report_dns_poisoning(&check).unwrap();
+        assert_eq!(processor.detection_evader.adaptation_level(), before + 1);
+    }
 }
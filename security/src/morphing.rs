@@ -0,0 +1,728 @@
+//! Traffic morphing
+//! Reshapes outgoing packet sizes to follow an empirical length distribution
+//! or an application's traffic shape (sizes and pacing), buffering and
+//! splitting payload so the packets on the wire match the target
+
+use crate::error::{Error, Result};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::ops::Range;
+use std::time::Duration;
+
+/// Per-packet header carrying the payload length
+const MORPH_HEADER_LEN: usize = 2;
+
+/// Packet-length distribution as weighted ranges of lengths
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LengthDistribution {
+    bins: Vec<(Range<usize>, u32)>,
+    total_weight: u64,
+}
+
+impl LengthDistribution {
+    /// Build from `(lengths, weight)` bins; lengths within a bin are uniform
+    pub fn new(bins: Vec<(Range<usize>, u32)>) -> Result<Self> {
+        let bins: Vec<_> = bins
+            .into_iter()
+            .filter(|(range, weight)| *weight > 0 && !range.is_empty())
+            .collect();
+        if bins.is_empty() {
+            return Err(Error::ConfigError(
+                "Length distribution has no non-empty bins".to_string(),
+            ));
+        }
+        let total_weight = bins.iter().map(|(_, weight)| *weight as u64).sum();
+        Ok(LengthDistribution { bins, total_weight })
+    }
+
+    /// Build from observed packet lengths, one bin per distinct length
+    pub fn from_samples(samples: &[usize]) -> Result<Self> {
+        let mut sorted = samples.to_vec();
+        sorted.sort_unstable();
+        let mut bins: Vec<(Range<usize>, u32)> = Vec::new();
+        for len in sorted {
+            match bins.last_mut() {
+                Some((range, weight)) if range.start == len => *weight += 1,
+                _ => bins.push((len..len + 1, 1)),
+            }
+        }
+        Self::new(bins)
+    }
+
+    /// TCP payload lengths of HTTPS browsing: small request and control
+    /// records, a spread of partial records, and a large share of full
+    /// segments (1448 with TCP timestamps, 1460 without)
+    pub fn https_browsing() -> Self {
+        Self::new(vec![
+            (60..120, 14),
+            (120..250, 12),
+            (250..600, 14),
+            (600..1000, 8),
+            (1000..1300, 6),
+            (1300..1448, 10),
+            (1448..1449, 28),
+            (1460..1461, 8),
+        ])
+        .expect("built-in distribution is valid")
+    }
+
+    pub fn bins(&self) -> &[(Range<usize>, u32)] {
+        &self.bins
+    }
+
+    /// Largest length the distribution produces
+    pub fn max_len(&self) -> usize {
+        self.bins
+            .iter()
+            .map(|(range, _)| range.end - 1)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Draw a packet length
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> usize {
+        let mut pick = rng.gen_range(0..self.total_weight);
+        for (range, weight) in &self.bins {
+            if pick < *weight as u64 {
+                return rng.gen_range(range.clone());
+            }
+            pick -= *weight as u64;
+        }
+        unreachable!("pick is below the total weight")
+    }
+}
+
+impl Default for LengthDistribution {
+    fn default() -> Self {
+        Self::https_browsing()
+    }
+}
+
+/// Configuration for traffic morphing
+#[derive(Clone, Debug, Default)]
+pub struct MorphingConfig {
+    /// Lengths packets should have on the wire
+    pub distribution: LengthDistribution,
+    /// Bytes the transport adds to every morphed packet (e.g. 22 for a
+    /// TLS 1.3 record), subtracted from each drawn length
+    pub overhead: usize,
+}
+
+/// Splits and pads a byte stream into packets with drawn lengths
+///
+/// Each packet is a 2-byte payload length, the payload and random padding.
+/// Drawn lengths too small to carry payload become padding-only packets,
+/// so the emitted sizes follow the distribution instead of being redrawn
+/// until they fit, which would skew it towards large packets.
+pub struct Morpher {
+    config: MorphingConfig,
+    buffer: Vec<u8>,
+    next_len: usize,
+}
+
+impl Morpher {
+    pub fn new(config: MorphingConfig) -> Result<Self> {
+        if config.distribution.max_len() <= config.overhead + MORPH_HEADER_LEN {
+            return Err(Error::ConfigError(
+                "Length distribution leaves no room for payload".to_string(),
+            ));
+        }
+        let next_len = config.distribution.sample(&mut rand::thread_rng());
+        Ok(Morpher {
+            config,
+            buffer: Vec::new(),
+            next_len,
+        })
+    }
+
+    pub fn config(&self) -> &MorphingConfig {
+        &self.config
+    }
+
+    /// Payload bytes waiting for a packet
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Queue `data`, returning the packets it completes; the rest stays
+    /// buffered until more data or a `flush`
+    pub fn push(&mut self, data: &[u8]) -> Vec<Vec<u8>> {
+        self.buffer.extend_from_slice(data);
+        let mut packets = Vec::new();
+        while !self.buffer.is_empty() && self.buffer.len() >= self.capacity() {
+            packets.push(self.emit());
+        }
+        packets
+    }
+
+    /// Emit everything buffered, padding the last packet
+    pub fn flush(&mut self) -> Vec<Vec<u8>> {
+        let mut packets = Vec::new();
+        while !self.buffer.is_empty() {
+            packets.push(self.emit());
+        }
+        packets
+    }
+
+    /// Morph `data` on its own into packets ready to send
+    pub fn morph(&mut self, data: &[u8]) -> Vec<Vec<u8>> {
+        let mut packets = self.push(data);
+        packets.extend(self.flush());
+        packets
+    }
+
+    /// Payload that fits in the next packet
+    fn capacity(&self) -> usize {
+        self.next_len
+            .saturating_sub(self.config.overhead + MORPH_HEADER_LEN)
+    }
+
+    fn emit(&mut self) -> Vec<u8> {
+        let packet = fill_packet(&mut self.buffer, self.next_len, self.config.overhead);
+        self.next_len = self.config.distribution.sample(&mut rand::thread_rng());
+        packet
+    }
+}
+
+/// Frame as much of `buffer` as fits a packet of `wire_len` bytes (including
+/// `overhead`), padding the rest
+fn fill_packet(buffer: &mut Vec<u8>, wire_len: usize, overhead: usize) -> Vec<u8> {
+    let packet_len = wire_len.saturating_sub(overhead).max(MORPH_HEADER_LEN);
+    let take = (packet_len - MORPH_HEADER_LEN)
+        .min(buffer.len())
+        .min(u16::MAX as usize);
+
+    let mut packet = Vec::with_capacity(packet_len);
+    packet.extend_from_slice(&(take as u16).to_be_bytes());
+    packet.extend(buffer.drain(..take));
+    let mut padding = vec![0u8; packet_len - packet.len()];
+    rand::thread_rng().fill(&mut padding[..]);
+    packet.extend(padding);
+    packet
+}
+
+/// One packet of a traffic shape: its length on the wire and the wait
+/// before sending it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Slot {
+    pub len: usize,
+    pub delay: Duration,
+}
+
+/// A source of packet slots imitating some application's traffic
+pub trait TrafficShape: Send {
+    fn next_slot(&mut self) -> Slot;
+    /// Largest slot length the shape produces
+    fn max_len(&self) -> usize;
+}
+
+/// Settings of the video-call shape; defaults resemble a 720p WebRTC call
+#[derive(Clone, Debug)]
+pub struct VideoCallConfig {
+    pub fps: u32,
+    /// Average video bitrate between keyframes
+    pub video_bitrate: u32,
+    /// Time between keyframes
+    pub keyframe_interval: Duration,
+    /// Size of a keyframe relative to a delta frame
+    pub keyframe_ratio: f64,
+    /// Largest SRTP packet; WebRTC keeps packets under 1200 bytes
+    pub max_packet: usize,
+    /// Audio packet interval (Opus at 20 ms frames)
+    pub audio_interval: Duration,
+    pub audio_len: Range<usize>,
+    /// Gap between consecutive packets of one video frame
+    pub pacing: Duration,
+}
+
+impl Default for VideoCallConfig {
+    fn default() -> Self {
+        VideoCallConfig {
+            fps: 30,
+            video_bitrate: 1_200_000,
+            keyframe_interval: Duration::from_secs(3),
+            keyframe_ratio: 8.0,
+            max_packet: 1200,
+            audio_interval: Duration::from_millis(20),
+            audio_len: 90..160,
+            pacing: Duration::from_micros(300),
+        }
+    }
+}
+
+/// SRTP video call: a steady 50 pps of small audio packets interleaved
+/// with video frames at a fixed rate, each frame split into near-equal
+/// packets, and periodic keyframes arriving as a burst several times larger
+pub struct VideoCallShape {
+    config: VideoCallConfig,
+    /// Virtual time of the last slot
+    now: Duration,
+    next_audio: Duration,
+    next_frame: Duration,
+    frame_index: u64,
+    /// Packet lengths of the frame being sent and when the next is due
+    frame_packets: VecDeque<usize>,
+    next_video: Duration,
+}
+
+impl VideoCallShape {
+    pub fn new(config: VideoCallConfig) -> Result<Self> {
+        if config.fps == 0
+            || config.max_packet <= MORPH_HEADER_LEN
+            || config.audio_len.is_empty()
+            || config.audio_interval.is_zero()
+        {
+            return Err(Error::ConfigError(
+                "Video call shape needs a frame rate, packet size and audio rate".to_string(),
+            ));
+        }
+        Ok(VideoCallShape {
+            config,
+            now: Duration::ZERO,
+            next_audio: Duration::ZERO,
+            next_frame: Duration::ZERO,
+            frame_index: 0,
+            frame_packets: VecDeque::new(),
+            next_video: Duration::ZERO,
+        })
+    }
+
+    pub fn config(&self) -> &VideoCallConfig {
+        &self.config
+    }
+
+    /// Queue the packets of the next video frame
+    fn start_frame(&mut self) {
+        let mut rng = rand::thread_rng();
+        let config = &self.config;
+        let frames_per_key =
+            (config.keyframe_interval.as_secs_f64() * config.fps as f64).max(1.0) as u64;
+        let delta = config.video_bitrate as f64 / 8.0 / config.fps as f64;
+        let size = if self.frame_index.is_multiple_of(frames_per_key) {
+            delta * config.keyframe_ratio
+        } else {
+            delta * rng.gen_range(0.6..1.4)
+        } as usize;
+
+        let count = size.div_ceil(config.max_packet).max(1);
+        let each = (size / count).clamp(MORPH_HEADER_LEN + 1, config.max_packet);
+        self.frame_packets = std::iter::repeat_n(each, count).collect();
+        self.next_video = self.next_frame;
+        self.frame_index += 1;
+        self.next_frame += Duration::from_secs_f64(1.0 / config.fps as f64);
+    }
+}
+
+impl TrafficShape for VideoCallShape {
+    fn next_slot(&mut self) -> Slot {
+        if self.frame_packets.is_empty() && self.next_frame <= self.next_audio {
+            self.start_frame();
+        }
+
+        let (at, len) = if !self.frame_packets.is_empty() && self.next_video <= self.next_audio {
+            let len = self
+                .frame_packets
+                .pop_front()
+                .unwrap_or(self.config.max_packet);
+            let at = self.next_video;
+            self.next_video += self.config.pacing;
+            (at, len)
+        } else {
+            let at = self.next_audio;
+            self.next_audio += self.config.audio_interval;
+            (
+                at,
+                rand::thread_rng().gen_range(self.config.audio_len.clone()),
+            )
+        };
+
+        let delay = at.saturating_sub(self.now);
+        self.now = self.now.max(at);
+        Slot { len, delay }
+    }
+
+    fn max_len(&self) -> usize {
+        self.config
+            .max_packet
+            .max(self.config.audio_len.end.saturating_sub(1))
+    }
+}
+
+/// Settings of the streaming shape; defaults resemble HLS/DASH video
+#[derive(Clone, Debug)]
+pub struct StreamingConfig {
+    /// Media time carried by one segment
+    pub segment_duration: Duration,
+    /// Bitrate ladder, lowest first; the player starts at the bottom
+    pub bitrates: Vec<u32>,
+    /// Link rate a segment downloads at
+    pub burst_rate: u32,
+    /// Full-size packet length
+    pub packet_len: usize,
+    /// Segments fetched back-to-back to fill the buffer before playback
+    /// settles into one segment per `segment_duration`
+    pub startup_segments: u32,
+    /// Gap between segments while filling the buffer (request round trip)
+    pub request_gap: Duration,
+}
+
+impl Default for StreamingConfig {
+    fn default() -> Self {
+        StreamingConfig {
+            segment_duration: Duration::from_secs(4),
+            bitrates: vec![800_000, 1_500_000, 3_000_000, 5_000_000],
+            burst_rate: 20_000_000,
+            packet_len: 1448,
+            startup_segments: 4,
+            request_gap: Duration::from_millis(40),
+        }
+    }
+}
+
+/// Adaptive-bitrate streaming: each segment arrives as a burst of full-size
+/// packets at link rate, then the flow idles until the next one is due;
+/// the bitrate steps through the ladder as the player adapts
+pub struct StreamingShape {
+    config: StreamingConfig,
+    level: usize,
+    segments: u32,
+    /// Packets left in the current segment
+    remaining: VecDeque<usize>,
+    /// Start of the current segment and the wait before its first packet
+    segment_start: Duration,
+    first_delay: Option<Duration>,
+    elapsed: Duration,
+}
+
+impl StreamingShape {
+    pub fn new(config: StreamingConfig) -> Result<Self> {
+        if config.bitrates.is_empty()
+            || config.bitrates.contains(&0)
+            || config.burst_rate == 0
+            || config.packet_len <= MORPH_HEADER_LEN
+        {
+            return Err(Error::ConfigError(
+                "Streaming shape needs bitrates, a link rate and a packet size".to_string(),
+            ));
+        }
+        Ok(StreamingShape {
+            config,
+            level: 0,
+            segments: 0,
+            remaining: VecDeque::new(),
+            segment_start: Duration::ZERO,
+            first_delay: None,
+            elapsed: Duration::ZERO,
+        })
+    }
+
+    pub fn config(&self) -> &StreamingConfig {
+        &self.config
+    }
+
+    /// Current bitrate of the ladder
+    pub fn bitrate(&self) -> u32 {
+        self.config.bitrates[self.level]
+    }
+
+    fn packet_gap(&self) -> Duration {
+        Duration::from_secs_f64(self.config.packet_len as f64 * 8.0 / self.config.burst_rate as f64)
+    }
+
+    /// Queue the packets of the next segment and compute the idle gap
+    /// before it
+    fn start_segment(&mut self) {
+        let mut rng = rand::thread_rng();
+        if self.segments > 0 {
+            // Once the buffer is full the player probes upwards, and backs
+            // off now and then as throughput dips
+            let step: f64 = rng.gen();
+            if step < 0.3 && self.level + 1 < self.config.bitrates.len() {
+                self.level += 1;
+            } else if step > 0.9 && self.level > 0 {
+                self.level -= 1;
+            }
+        }
+
+        let size = self.bitrate() as f64 / 8.0
+            * self.config.segment_duration.as_secs_f64()
+            * rng.gen_range(0.8..1.2);
+        let size = size as usize;
+        let full = size / self.config.packet_len;
+        self.remaining = std::iter::repeat_n(self.config.packet_len, full).collect();
+        if size % self.config.packet_len > MORPH_HEADER_LEN {
+            self.remaining.push_back(size % self.config.packet_len);
+        }
+
+        let next_start = if self.segments < self.config.startup_segments {
+            self.elapsed + self.config.request_gap
+        } else {
+            (self.segment_start + self.config.segment_duration)
+                .max(self.elapsed + self.config.request_gap)
+        };
+        self.first_delay = Some(next_start.saturating_sub(self.elapsed));
+        self.segment_start = next_start;
+        self.segments += 1;
+    }
+}
+
+impl TrafficShape for StreamingShape {
+    fn next_slot(&mut self) -> Slot {
+        if self.remaining.is_empty() {
+            self.start_segment();
+        }
+        let len = self.remaining.pop_front().unwrap_or(self.config.packet_len);
+        let delay = self.first_delay.take().unwrap_or_else(|| self.packet_gap());
+        self.elapsed += delay;
+        Slot { len, delay }
+    }
+
+    fn max_len(&self) -> usize {
+        self.config.packet_len
+    }
+}
+
+/// Traffic shape selectable per session
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TrafficShapeProfile {
+    /// No shaping
+    #[default]
+    None,
+    /// SRTP video call, for interactive sessions
+    VideoCall,
+    /// Adaptive-bitrate video streaming, for long bulk transfers
+    Streaming,
+}
+
+impl TrafficShapeProfile {
+    /// A fresh shape for one session, with default settings
+    pub fn shape(self) -> Option<Box<dyn TrafficShape>> {
+        match self {
+            TrafficShapeProfile::None => None,
+            TrafficShapeProfile::VideoCall => Some(Box::new(
+                VideoCallShape::new(VideoCallConfig::default())
+                    .expect("default video call config is valid"),
+            )),
+            TrafficShapeProfile::Streaming => Some(Box::new(
+                StreamingShape::new(StreamingConfig::default())
+                    .expect("default streaming config is valid"),
+            )),
+        }
+    }
+}
+
+/// A morphed packet and the wait before sending it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ShapedPacket {
+    pub data: Vec<u8>,
+    pub delay: Duration,
+}
+
+/// Carries a byte stream in the slots of a `TrafficShape`
+///
+/// Unlike `Morpher`, the shaper emits a packet for every slot, padding-only
+/// when nothing is queued, so sizes and pacing follow the shape whether or
+/// not there is data. Packets use the same framing, read with `unmorph`.
+pub struct Shaper {
+    shape: Box<dyn TrafficShape>,
+    overhead: usize,
+    buffer: Vec<u8>,
+}
+
+impl Shaper {
+    /// `overhead` is what the transport adds to each packet, as for
+    /// `MorphingConfig`
+    pub fn new(shape: Box<dyn TrafficShape>, overhead: usize) -> Result<Self> {
+        if shape.max_len() <= overhead + MORPH_HEADER_LEN {
+            return Err(Error::ConfigError(
+                "Traffic shape leaves no room for payload".to_string(),
+            ));
+        }
+        Ok(Shaper {
+            shape,
+            overhead,
+            buffer: Vec::new(),
+        })
+    }
+
+    /// Queue data for the coming slots
+    pub fn push(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// The next packet; wait its `delay`, then send it
+    pub fn next_packet(&mut self) -> ShapedPacket {
+        let slot = self.shape.next_slot();
+        ShapedPacket {
+            data: fill_packet(&mut self.buffer, slot.len, self.overhead),
+            delay: slot.delay,
+        }
+    }
+}
+
+/// A packet carrying no payload, `wire_len` bytes on the wire; `unmorph`
+/// reads it as empty
+pub fn padding_packet(wire_len: usize, overhead: usize) -> Vec<u8> {
+    fill_packet(&mut Vec::new(), wire_len, overhead)
+}
+
+/// Extract the payload of a morphed packet
+pub fn unmorph(packet: &[u8]) -> Result<Vec<u8>> {
+    let header = packet
+        .get(..MORPH_HEADER_LEN)
+        .ok_or_else(|| Error::DataError("Morphed packet too short".to_string()))?;
+    let len = u16::from_be_bytes([header[0], header[1]]) as usize;
+    packet
+        .get(MORPH_HEADER_LEN..MORPH_HEADER_LEN + len)
+        .map(<[u8]>::to_vec)
+        .ok_or_else(|| Error::DataError("Morphed packet truncated".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_distribution_sampling() {
+        let dist = LengthDistribution::from_samples(&[100, 100, 100, 1448]).unwrap();
+        assert_eq!(dist.bins(), &[(100..101, 3), (1448..1449, 1)]);
+        let mut rng = rand::thread_rng();
+        let draws: Vec<usize> = (0..2000).map(|_| dist.sample(&mut rng)).collect();
+        assert!(draws.iter().all(|&len| len == 100 || len == 1448));
+        let small = draws.iter().filter(|&&len| len == 100).count();
+        assert!((1200..1800).contains(&small));
+
+        assert!(LengthDistribution::new(vec![(5..5, 1), (10..20, 0)]).is_err());
+        assert!(LengthDistribution::from_samples(&[]).is_err());
+    }
+
+    #[test]
+    fn test_morph_round_trip_and_sizes() {
+        let mut morpher = Morpher::new(MorphingConfig {
+            overhead: 22,
+            ..Default::default()
+        })
+        .unwrap();
+        let dist = LengthDistribution::https_browsing();
+        let data: Vec<u8> = (0..20_000u32).map(|i| (i % 253) as u8).collect();
+
+        let packets = morpher.morph(&data);
+        let mut restored = Vec::new();
+        for packet in &packets {
+            let wire_len = packet.len() + 22;
+            assert!(dist
+                .bins()
+                .iter()
+                .any(|(range, _)| range.contains(&wire_len)));
+            restored.extend(unmorph(packet).unwrap());
+        }
+        assert_eq!(restored, data);
+        assert_eq!(morpher.buffered(), 0);
+    }
+
+    #[test]
+    fn test_push_buffers_until_a_packet_fills() {
+        let dist = LengthDistribution::new(vec![(500..501, 1)]).unwrap();
+        let mut morpher = Morpher::new(MorphingConfig {
+            distribution: dist,
+            overhead: 0,
+        })
+        .unwrap();
+
+        assert!(morpher.push(&[1u8; 300]).is_empty());
+        assert_eq!(morpher.buffered(), 300);
+        let packets = morpher.push(&[2u8; 300]);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].len(), 500);
+        assert_eq!(morpher.buffered(), 102);
+
+        let rest = morpher.flush();
+        assert_eq!(rest.len(), 1);
+        assert_eq!(rest[0].len(), 500);
+        assert_eq!(unmorph(&rest[0]).unwrap(), vec![2u8; 102]);
+        assert!(unmorph(&[0, 9, 1]).is_err());
+    }
+
+    #[test]
+    fn test_video_call_shape() {
+        let config = VideoCallConfig::default();
+        let mut shape = VideoCallShape::new(config.clone()).unwrap();
+        let mut elapsed = Duration::ZERO;
+        let mut slots = Vec::new();
+        while elapsed < Duration::from_secs(9) {
+            let slot = shape.next_slot();
+            elapsed += slot.delay;
+            slots.push((elapsed, slot.len));
+        }
+
+        assert!(slots.iter().all(|&(_, len)| len <= config.max_packet));
+        let audio = slots.iter().filter(|&&(_, len)| len < 160).count();
+        assert!((445..=455).contains(&audio), "{} audio packets", audio);
+        // Keyframes at 0, 3 and 6 s show up as bursts of full-size packets
+        let per_second: Vec<usize> = (0..9)
+            .map(|s| slots.iter().filter(|&&(at, _)| at.as_secs() == s).count())
+            .collect();
+        assert!(per_second[0] > per_second[1]);
+        assert!(per_second[3] > per_second[4]);
+    }
+
+    #[test]
+    fn test_streaming_shape() {
+        let config = StreamingConfig::default();
+        let mut shape = StreamingShape::new(config.clone()).unwrap();
+        let mut elapsed = Duration::ZERO;
+        let mut gaps = Vec::new();
+        while elapsed < Duration::from_secs(60) {
+            let slot = shape.next_slot();
+            assert!(slot.len <= config.packet_len);
+            elapsed += slot.delay;
+            if slot.delay > Duration::from_millis(30) {
+                gaps.push(slot.delay);
+            }
+        }
+
+        // Startup segments come back-to-back, then idle gaps of seconds
+        assert!(gaps[..4].iter().all(|gap| *gap == config.request_gap));
+        assert!(gaps[4..].iter().all(|gap| *gap > Duration::from_secs(1)));
+        assert!(shape.bitrate() >= config.bitrates[0]);
+        assert_eq!(
+            TrafficShapeProfile::Streaming.shape().unwrap().max_len(),
+            1448
+        );
+        assert!(TrafficShapeProfile::None.shape().is_none());
+    }
+
+    #[test]
+    fn test_shaper_round_trip() {
+        let shape = VideoCallShape::new(VideoCallConfig::default()).unwrap();
+        let mut shaper = Shaper::new(Box::new(shape), 0).unwrap();
+        let data: Vec<u8> = (0..30_000u32).map(|i| (i % 241) as u8).collect();
+        shaper.push(&data);
+
+        let mut restored = Vec::new();
+        while shaper.buffered() > 0 {
+            restored.extend(unmorph(&shaper.next_packet().data).unwrap());
+        }
+        assert_eq!(restored, data);
+        // Idle slots still go out, carrying only padding
+        let idle = shaper.next_packet();
+        assert!(idle.data.len() > MORPH_HEADER_LEN);
+        assert!(unmorph(&idle.data).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_rejects_distribution_without_room() {
+        let dist = LengthDistribution::new(vec![(10..20, 1)]).unwrap();
+        let config = MorphingConfig {
+            distribution: dist,
+            overhead: 22,
+        };
+        assert!(Morpher::new(config).is_err());
+    }
+}
@@ -0,0 +1,145 @@
+//! Path MTU handling
+//! Works out how much TCP payload fits in one packet on the path, so
+//! fragmentation never produces segments the IP layer has to fragment again
+
+use crate::error::Result;
+use tokio::net::TcpStream;
+
+/// Ethernet MTU, assumed when nothing better is known
+pub const DEFAULT_MTU: u32 = 1500;
+/// Smallest MTU every IPv4 host must accept
+pub const MIN_IPV4_MTU: u32 = 576;
+/// Smallest MTU IPv6 allows
+pub const MIN_IPV6_MTU: u32 = 1280;
+
+const IPV4_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
+const TCP_HEADER_LEN: usize = 20;
+/// Room for the timestamp option most stacks put on every segment
+const TCP_OPTIONS_ALLOWANCE: usize = 12;
+
+/// MTU of the path to one destination
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PathMtu {
+    pub mtu: u32,
+    pub ipv6: bool,
+}
+
+impl PathMtu {
+    /// Use a configured MTU, raised to the protocol minimum if lower
+    pub fn new(mtu: u32, ipv6: bool) -> Self {
+        let min = if ipv6 { MIN_IPV6_MTU } else { MIN_IPV4_MTU };
+        PathMtu {
+            mtu: mtu.max(min),
+            ipv6,
+        }
+    }
+
+    /// Largest TCP payload that fits in one packet
+    pub fn max_segment_payload(&self) -> usize {
+        let ip_header = if self.ipv6 {
+            IPV6_HEADER_LEN
+        } else {
+            IPV4_HEADER_LEN
+        };
+        self.mtu as usize - ip_header - TCP_HEADER_LEN - TCP_OPTIONS_ALLOWANCE
+    }
+
+    /// Read the kernel's path MTU for a connected stream
+    ///
+    /// Turns on PMTU discovery (DF set, no local fragmentation) first, then
+    /// takes the smaller of the route's cached MTU and the negotiated MSS.
+    #[cfg(target_os = "linux")]
+    pub fn discover(stream: &TcpStream) -> Result<Self> {
+        use std::os::fd::AsRawFd;
+
+        let ipv6 = stream.peer_addr()?.is_ipv6();
+        let fd = stream.as_raw_fd();
+        let (level, discover_opt, discover_do, mtu_opt) = if ipv6 {
+            (
+                libc::IPPROTO_IPV6,
+                libc::IPV6_MTU_DISCOVER,
+                libc::IPV6_PMTUDISC_DO,
+                libc::IPV6_MTU,
+            )
+        } else {
+            (
+                libc::IPPROTO_IP,
+                libc::IP_MTU_DISCOVER,
+                libc::IP_PMTUDISC_DO,
+                libc::IP_MTU,
+            )
+        };
+
+        let mut mtu: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        // SAFETY: int-valued socket options on a descriptor the stream owns
+        let rc = unsafe {
+            let rc = libc::setsockopt(
+                fd,
+                level,
+                discover_opt,
+                &discover_do as *const _ as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            );
+            if rc == 0 {
+                libc::getsockopt(
+                    fd,
+                    level,
+                    mtu_opt,
+                    &mut mtu as *mut _ as *mut libc::c_void,
+                    &mut len,
+                )
+            } else {
+                rc
+            }
+        };
+        if rc < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        let path = PathMtu::new(mtu as u32, ipv6);
+        // The peer's MSS can be lower than the route allows
+        let mss = socket2::SockRef::from(stream).tcp_mss()? as usize;
+        let overhead = path.mtu as usize - path.max_segment_payload();
+        let from_mss = (mss + overhead) as u32;
+        Ok(PathMtu::new(path.mtu.min(from_mss), ipv6))
+    }
+}
+
+impl Default for PathMtu {
+    fn default() -> Self {
+        PathMtu::new(DEFAULT_MTU, false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_segment_payload() {
+        assert_eq!(PathMtu::default().max_segment_payload(), 1448);
+        assert_eq!(PathMtu::new(1280, true).max_segment_payload(), 1208);
+        // PPPoE links common on Iranian ADSL
+        assert_eq!(PathMtu::new(1492, false).max_segment_payload(), 1440);
+    }
+
+    #[test]
+    fn test_minimum_mtu() {
+        assert_eq!(PathMtu::new(100, false).mtu, MIN_IPV4_MTU);
+        assert_eq!(PathMtu::new(1000, true).mtu, MIN_IPV6_MTU);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_discover_on_loopback() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let path = PathMtu::discover(&stream).unwrap();
+        assert!(!path.ipv6);
+        assert!(path.max_segment_payload() > 0);
+    }
+}
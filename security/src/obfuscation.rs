@@ -2,63 +2,435 @@
 //! Implements various obfuscation techniques to make proxy traffic look like legitimate HTTPS
 
 use crate::error::{Error, Result};
+use crate::h2_mimicry::{H2Mimicry, H2MimicryConfig};
+use crate::sni_pool::SniPool;
+use rand::seq::SliceRandom;
 use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub(crate) const USER_AGENTS: &[&str] = &[
+    "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) \
+     Chrome/124.0.0.0 Safari/537.36",
+    "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) \
+     Chrome/124.0.0.0 Safari/537.36 Edg/124.0.2478.80",
+    "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:125.0) Gecko/20100101 Firefox/125.0",
+    "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 (KHTML, like Gecko) \
+     Version/17.4.1 Safari/605.1.15",
+    "Mozilla/5.0 (Linux; Android 14; SM-S918B) AppleWebKit/537.36 (KHTML, like Gecko) \
+     Chrome/124.0.6367.82 Mobile Safari/537.36",
+    "Mozilla/5.0 (iPhone; CPU iPhone OS 17_4 like Mac OS X) AppleWebKit/605.1.15 \
+     (KHTML, like Gecko) Version/17.4 Mobile/15E148 Safari/604.1",
+];
+
+const ACCEPTS: &[&str] = &[
+    "text/html,application/xhtml+xml,application/xml;q=0.9,image/avif,image/webp,*/*;q=0.8",
+    "application/json, text/plain, */*",
+    "*/*",
+];
+
+const ACCEPT_LANGUAGES: &[&str] = &[
+    "en-US,en;q=0.9",
+    "fa-IR,fa;q=0.9,en-US;q=0.8,en;q=0.7",
+    "en-GB,en;q=0.8",
+];
+
+const PATHS: &[&str] = &[
+    "/",
+    "/index.html",
+    "/favicon.ico",
+    "/static/js/main.js",
+    "/assets/css/app.css",
+    "/api/v1/status",
+    "/images/logo.png",
+];
+
+const UPLOAD_PATHS: &[&str] = &["/api/v1/events", "/upload", "/api/v2/sync", "/collect"];
+
+const CONTENT_TYPES: &[&str] = &[
+    "application/octet-stream",
+    "application/json",
+    "application/x-protobuf",
+];
+
+const SERVERS: &[&str] = &["nginx", "cloudflare", "Apache", "openresty"];
+
+/// Format a time as an HTTP date (RFC 9110 IMF-fixdate)
+pub(crate) fn http_date(time: SystemTime) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, rem) = (secs / 86400, secs % 86400);
+
+    // Days since the epoch to a civil date, counting from 0000-03-01
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days % 7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+/// Builds camouflage request heads that vary like real browser traffic
+///
+/// Every head gets its own User-Agent, path, header order and casing, and
+/// (unless given) a Host drawn from the SNI pool, so no two requests share
+/// a static signature.
+#[derive(Clone, Debug)]
+pub struct HeaderGenerator {
+    pool: SniPool,
+}
+
+impl HeaderGenerator {
+    pub fn new(pool: SniPool) -> Self {
+        HeaderGenerator { pool }
+    }
+
+    pub fn pool(&self) -> &SniPool {
+        &self.pool
+    }
+
+    /// A Host to use when the caller has none
+    pub fn random_host(&self) -> String {
+        self.pool
+            .choose()
+            .unwrap_or_else(|| "example.com".to_string())
+    }
+
+    /// Request line, headers and the blank line ending the head
+    pub fn request_head(&self, host: &str) -> Vec<u8> {
+        let mut rng = rand::thread_rng();
+        let line = format!("GET {} HTTP/1.1", PATHS.choose(&mut rng).unwrap());
+        self.head(&line, host, Vec::new())
+    }
+
+    /// Head of a POST whose body follows in chunked transfer coding
+    pub fn chunked_request_head(&self, host: &str) -> Vec<u8> {
+        let mut rng = rand::thread_rng();
+        let line = format!("POST {} HTTP/1.1", UPLOAD_PATHS.choose(&mut rng).unwrap());
+        let extra = vec![
+            (
+                "Content-Type",
+                CONTENT_TYPES.choose(&mut rng).unwrap().to_string(),
+            ),
+            ("Transfer-Encoding", "chunked".to_string()),
+            ("Origin", format!("https://{}", host)),
+        ];
+        self.head(&line, host, extra)
+    }
+
+    /// Head of a 200 response whose body follows in chunked transfer coding
+    pub fn chunked_response_head(&self) -> Vec<u8> {
+        let mut rng = rand::thread_rng();
+        let mut headers = vec![
+            ("Server", SERVERS.choose(&mut rng).unwrap().to_string()),
+            ("Date", http_date(SystemTime::now())),
+            (
+                "Content-Type",
+                CONTENT_TYPES.choose(&mut rng).unwrap().to_string(),
+            ),
+            ("Transfer-Encoding", "chunked".to_string()),
+            ("Connection", "keep-alive".to_string()),
+        ];
+        if rng.gen() {
+            headers.push(("Cache-Control", "no-cache, no-store".to_string()));
+        }
+        if rng.gen() {
+            headers.push(("Vary", "Accept-Encoding".to_string()));
+        }
+        // Servers emit their headers in a fixed order, so only the set varies
+        let mut head = "HTTP/1.1 200 OK\r\n".to_string();
+        for (name, value) in headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+        head.into_bytes()
+    }
+
+    /// `line` followed by browser-like headers, plus `extra`, in random
+    /// order and casing
+    fn head(&self, line: &str, host: &str, extra: Vec<(&str, String)>) -> Vec<u8> {
+        let mut rng = rand::thread_rng();
+
+        let mut headers = vec![
+            ("Host", host.to_string()),
+            (
+                "User-Agent",
+                USER_AGENTS.choose(&mut rng).unwrap().to_string(),
+            ),
+            ("Accept", ACCEPTS.choose(&mut rng).unwrap().to_string()),
+            (
+                "Accept-Language",
+                ACCEPT_LANGUAGES.choose(&mut rng).unwrap().to_string(),
+            ),
+            ("Accept-Encoding", "gzip, deflate, br".to_string()),
+            ("Date", http_date(SystemTime::now())),
+        ];
+        headers.extend(extra);
+        if rng.gen_bool(0.6) {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs());
+            headers.push((
+                "Cookie",
+                format!(
+                    "_ga=GA1.2.{}.{}; sid={:016x}",
+                    rng.gen_range(100_000_000u32..2_000_000_000),
+                    now - rng.gen_range(0..30 * 86400),
+                    rng.gen::<u64>()
+                ),
+            ));
+        }
+        if rng.gen_bool(0.5) {
+            let referrer = if rng.gen() {
+                host.to_string()
+            } else {
+                self.random_host()
+            };
+            headers.push(("Referer", format!("https://{}/", referrer)));
+        }
+        headers.shuffle(&mut rng);
+
+        // HTTP/1.1 clients send canonical names; libraries built on HTTP/2
+        // stacks often send them lowercased
+        let lowercase = rng.gen_bool(0.3);
+        let mut head = format!("{}\r\n", line);
+        for (name, value) in headers {
+            if lowercase {
+                head.push_str(&name.to_ascii_lowercase());
+            } else {
+                head.push_str(name);
+            }
+            head.push_str(": ");
+            head.push_str(&value);
+            head.push_str("\r\n");
+        }
+        head.push_str("\r\n");
+        head.into_bytes()
+    }
+}
+
+/// Chunk sizes for a body of `len` bytes
+///
+/// Mirrors common servers: full buffers of one fixed size with a shorter
+/// tail (nginx, Node), or small irregular flushes from streaming handlers.
+fn chunk_sizes<R: Rng>(rng: &mut R, len: usize) -> Vec<usize> {
+    const BUFFER_SIZES: [usize; 5] = [1024, 4096, 8000, 8192, 16384];
+
+    let buffered = rng.gen_bool(0.7);
+    let buffer = *BUFFER_SIZES.choose(rng).unwrap();
+    let mut sizes = Vec::new();
+    let mut left = len;
+    while left > 0 {
+        let size = if buffered {
+            buffer
+        } else {
+            rng.gen_range(32..=2048)
+        };
+        let size = size.min(left);
+        sizes.push(size);
+        left -= size;
+    }
+    sizes
+}
+
+/// Encode `data` in chunked transfer coding, ending with the last-chunk
+fn encode_chunked(data: &[u8]) -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let mut out = Vec::with_capacity(data.len() + data.len() / 512 + 16);
+    let mut offset = 0;
+    for size in chunk_sizes(&mut rng, data.len()) {
+        out.extend_from_slice(format!("{:x}\r\n", size).as_bytes());
+        out.extend_from_slice(&data[offset..offset + size]);
+        out.extend_from_slice(b"\r\n");
+        offset += size;
+    }
+    out.extend_from_slice(b"0\r\n\r\n");
+    out
+}
+
+/// Decode a chunked body, ignoring chunk extensions and trailers
+fn decode_chunked(body: &[u8]) -> Result<Vec<u8>> {
+    let malformed = || Error::ObfuscationError("Malformed chunked body".to_string());
+
+    let mut out = Vec::new();
+    let mut pos = 0;
+    loop {
+        let line_len = body[pos..]
+            .windows(2)
+            .position(|w| w == b"\r\n")
+            .ok_or_else(malformed)?;
+        let line = std::str::from_utf8(&body[pos..pos + line_len]).map_err(|_| malformed())?;
+        let size_field = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size_field, 16).map_err(|_| malformed())?;
+        pos += line_len + 2;
+        if size == 0 {
+            return Ok(out);
+        }
+
+        let end = pos.checked_add(size).ok_or_else(malformed)?;
+        out.extend_from_slice(body.get(pos..end).ok_or_else(malformed)?);
+        if body.get(end..end + 2) != Some(b"\r\n") {
+            return Err(malformed());
+        }
+        pos = end + 2;
+    }
+}
+
+/// Shape of the HTTP envelope around obfuscated data
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CamouflageProfile {
+    /// HTTP/1.1 request heads
+    #[default]
+    Http1,
+    /// HTTP/2 frames after a browser-like connection preface
+    Http2,
+    /// HTTP/1.1 messages with a chunked body; the chunk lengths frame the
+    /// data, so it round-trips exactly
+    Chunked,
+}
 
 pub struct Obfuscator {
-    // Add HTTP headers that make traffic look legitimate
-    common_headers: Vec<&'static str>,
+    // Generates HTTP headers that make traffic look legitimate
+    headers: HeaderGenerator,
+    profile: CamouflageProfile,
+    h2: Option<H2Mimicry>,
 }
 
 impl Obfuscator {
     pub fn new() -> Self {
+        Self::with_sni_pool(SniPool::builtin())
+    }
+
+    /// Draw camouflage Hosts and Referers from `pool`
+    pub fn with_sni_pool(pool: SniPool) -> Self {
         Obfuscator {
-            common_headers: vec![
-                "User-Agent: Mozilla/5.0 (Windows NT 10.0; Win64; x64)",
-                "Accept: text/html,application/xhtml+xml,application/xml;q=0.9",
-                "Accept-Language: en-US,en;q=0.9",
-                "Accept-Encoding: gzip, deflate, br",
-                "Cache-Control: max-age=0",
-                "Upgrade-Insecure-Requests: 1",
-            ],
+            headers: HeaderGenerator::new(pool),
+            profile: CamouflageProfile::Http1,
+            h2: None,
         }
     }
 
+    /// Switch the envelope to `profile`
+    pub fn with_profile(mut self, profile: CamouflageProfile) -> Self {
+        self.profile = profile;
+        self.h2 = match profile {
+            CamouflageProfile::Http1 | CamouflageProfile::Chunked => None,
+            CamouflageProfile::Http2 => Some(
+                H2Mimicry::new(H2MimicryConfig {
+                    authority: Some(self.headers.random_host()),
+                    ..Default::default()
+                })
+                .expect("default HTTP/2 mimicry config is valid"),
+            ),
+        };
+        self
+    }
+
+    pub fn profile(&self) -> CamouflageProfile {
+        self.profile
+    }
+
     /// Obfuscate data to look like HTTP/HTTPS traffic
     pub fn obfuscate(&self, data: &[u8]) -> Result<Vec<u8>> {
-        let mut result = Vec::new();
+        self.obfuscate_scaled(data, 1.0)
+    }
 
-        // Add fake HTTP headers
-        result.extend_from_slice(b"GET / HTTP/1.1\r\n");
-        result.extend_from_slice(b"Host: example.com\r\n");
+    /// Obfuscate data with the random padding scaled by `intensity`, from
+    /// 1.0 (full) down to 0.0 (none), to keep within an overhead budget
+    pub fn obfuscate_scaled(&self, data: &[u8], intensity: f64) -> Result<Vec<u8>> {
+        self.obfuscate_counted(data, intensity)
+            .map(|(result, _)| result)
+    }
 
-        // Add random headers
-        let mut rng = rand::thread_rng();
-        let num_headers = rng.gen_range(2..4);
+    /// `obfuscate_scaled`, also returning how many padding bytes were added
+    pub(crate) fn obfuscate_counted(
+        &self,
+        data: &[u8],
+        intensity: f64,
+    ) -> Result<(Vec<u8>, usize)> {
+        if let Some(h2) = &self.h2 {
+            return Ok((h2.encode(data), 0));
+        }
+        self.envelope(data, &self.headers.random_host(), intensity)
+    }
 
-        for i in 0..num_headers {
-            if i < self.common_headers.len() {
-                result.extend_from_slice(self.common_headers[i].as_bytes());
-                result.extend_from_slice(b"\r\n");
-            }
+    /// Obfuscate data with an explicit HTTP Host header
+    ///
+    /// Used by domain fronting, where the Host carries the real destination
+    /// while the TLS SNI carries the front domain.
+    pub fn obfuscate_for_host(&self, data: &[u8], host: &str) -> Result<Vec<u8>> {
+        self.envelope(data, host, 1.0).map(|(result, _)| result)
+    }
+
+    /// The HTTP envelope and the number of padding bytes in it
+    fn envelope(&self, data: &[u8], host: &str, intensity: f64) -> Result<(Vec<u8>, usize)> {
+        if let Some(h2) = &self.h2 {
+            return Ok((h2.encode_for(data, host), 0));
+        }
+        if self.profile == CamouflageProfile::Chunked {
+            let mut result = self.headers.chunked_request_head(host);
+            result.extend(encode_chunked(data));
+            return Ok((result, 0));
         }
 
-        result.extend_from_slice(b"\r\n");
+        // Add fake HTTP headers
+        let mut result = self.headers.request_head(host);
 
         // Add actual data
         result.extend_from_slice(data);
 
         // Add random padding
-        let padding_size = rng.gen_range(0..256);
+        let mut rng = rand::thread_rng();
+        let max_padding = (256.0 * intensity.clamp(0.0, 1.0)) as usize;
+        let padding_size = rng.gen_range(0..max_padding.max(1));
         let padding: Vec<u8> = (0..padding_size).map(|_| rng.gen()).collect();
         result.extend(padding);
 
-        Ok(result)
+        Ok((result, padding_size))
+    }
+
+    /// Obfuscate data as a chunked HTTP response, for the server side of
+    /// the `Chunked` profile
+    pub fn obfuscate_response(&self, data: &[u8]) -> Vec<u8> {
+        let mut result = self.headers.chunked_response_head();
+        result.extend(encode_chunked(data));
+        result
     }
 
     /// Reverse obfuscation to extract original data
     pub fn deobfuscate(&self, data: &[u8]) -> Result<Vec<u8>> {
+        if let Some(h2) = &self.h2 {
+            return h2.decode(data);
+        }
+
         // Try to find the separator between headers and body
         let separator = b"\r\n\r\n";
+        if self.profile == CamouflageProfile::Chunked {
+            let body_start = data
+                .windows(separator.len())
+                .position(|w| w == separator)
+                .ok_or_else(|| Error::ObfuscationError("Missing HTTP head".to_string()))?;
+            return decode_chunked(&data[body_start + separator.len()..]);
+        }
 
         let mut idx = 0;
         while idx + separator.len() <= data.len() {
@@ -100,7 +472,7 @@ impl Obfuscator {
         if data.len() > 512 {
             // For large data, fragment it
             let chunk_size = rng.gen_range(100..512);
-            return Ok(data[0..std::cmp::min(chunk_size, data.len())].to_vec());
+            Ok(data[0..std::cmp::min(chunk_size, data.len())].to_vec())
         } else {
             // For small data, add padding
             let desired_size = rng.gen_range(data.len()..1024);
@@ -133,6 +505,92 @@ mod tests {
         assert!(result.windows(4).any(|w| w == b"GET "));
     }
 
+    #[test]
+    fn test_obfuscate_for_host() {
+        let obfuscator = Obfuscator::new();
+        let result = obfuscator
+            .obfuscate_for_host(b"test", "real.example.net")
+            .unwrap();
+        assert!(result
+            .to_ascii_lowercase()
+            .windows(b"host: real.example.net\r\n".len())
+            .any(|w| w == b"host: real.example.net\r\n"));
+    }
+
+    #[test]
+    fn test_headers_rotate() {
+        let pool = SniPool::from_domains(vec!["cdn.example.org".to_string()]).unwrap();
+        let generator = HeaderGenerator::new(pool);
+        let heads: Vec<String> = (0..20)
+            .map(|_| String::from_utf8(generator.request_head(&generator.random_host())).unwrap())
+            .collect();
+
+        assert!(heads
+            .iter()
+            .all(|h| h.to_ascii_lowercase().contains("host: cdn.example.org\r\n")));
+        assert!(heads.iter().all(|h| h.ends_with("\r\n\r\n")));
+        let orders: std::collections::HashSet<Vec<String>> = heads
+            .iter()
+            .map(|h| {
+                h.lines()
+                    .skip(1)
+                    .filter_map(|l| l.split_once(':'))
+                    .map(|(name, _)| name.to_ascii_lowercase())
+                    .collect()
+            })
+            .collect();
+        assert!(orders.len() > 1);
+    }
+
+    #[test]
+    fn test_http2_profile_round_trip() {
+        let obfuscator = Obfuscator::new().with_profile(CamouflageProfile::Http2);
+        assert_eq!(obfuscator.profile(), CamouflageProfile::Http2);
+        let wire = obfuscator.obfuscate(b"secret payload").unwrap();
+        assert!(wire.starts_with(crate::h2_mimicry::CLIENT_PREFACE));
+        assert_eq!(obfuscator.deobfuscate(&wire).unwrap(), b"secret payload");
+    }
+
+    #[test]
+    fn test_chunked_profile_round_trip() {
+        let obfuscator = Obfuscator::new().with_profile(CamouflageProfile::Chunked);
+        for len in [0, 1, 700, 50_000] {
+            let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+
+            let request = obfuscator.obfuscate(&data).unwrap();
+            assert!(request.starts_with(b"POST "));
+            assert!(request.ends_with(b"\r\n0\r\n\r\n"));
+            assert_eq!(obfuscator.deobfuscate(&request).unwrap(), data);
+
+            let response = obfuscator.obfuscate_response(&data);
+            assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+            assert_eq!(obfuscator.deobfuscate(&response).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn test_decode_chunked() {
+        let body = b"4;ext=1\r\nWiki\r\n5\r\npedia\r\nE\r\n in\r\n\r\nchunks.\r\n\
+            0\r\nX-Trailer: 1\r\n\r\n";
+        assert_eq!(
+            decode_chunked(body).unwrap(),
+            b"Wikipedia in\r\n\r\nchunks."
+        );
+        assert!(decode_chunked(b"5\r\nabc").is_err());
+        assert!(decode_chunked(b"zz\r\n").is_err());
+        assert!(decode_chunked(b"3\r\nabcX\r\n0\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn test_http_date() {
+        let time = UNIX_EPOCH + std::time::Duration::from_secs(784_111_777);
+        assert_eq!(http_date(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(
+            http_date(UNIX_EPOCH + std::time::Duration::from_secs(951_782_400)),
+            "Tue, 29 Feb 2000 00:00:00 GMT"
+        );
+    }
+
     #[test]
     fn test_add_noise() {
         let obfuscator = Obfuscator::new();
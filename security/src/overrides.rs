@@ -0,0 +1,423 @@
+//! Layered settings resolution
+//! Builds `SecuritySettings` from a config file, then `IPS_*` environment
+//! variables, then command-line assignments, each layer overriding the last,
+//! so deployments can tweak any field without shipping a file
+//!
+//! Every field is addressed by its path of field names. In the environment
+//! the path is upper case, prefixed with `IPS_` and separated by double
+//! underscores; on the command line it is dotted:
+//!
+//! - `max_overhead_ratio`: `IPS_MAX_OVERHEAD_RATIO=0.2`, `max_overhead_ratio=0.2`
+//! - `obfuscation.min_packet_size`: `IPS_OBFUSCATION__MIN_PACKET_SIZE=200`,
+//!   `obfuscation.min_packet_size=200`
+//! - `dpi_bypass.dns_tunnel.domain`: `IPS_DPI_BYPASS__DNS_TUNNEL__DOMAIN=t.example.com`,
+//!   `dpi_bypass.dns_tunnel.domain=t.example.com`
+//!
+//! Values are read as JSON where the field is not a string, so numbers,
+//! booleans and `null` work as written; lists take JSON arrays or
+//! comma-separated items (`IPS_OBFUSCATION__RECORD_PADDING_BUCKETS=512,1024`).
+//!
+//! The file may hold named `profiles`; the `profile` pseudo-setting
+//! (`IPS_PROFILE=office`, `profile=office`) picks one over the file's
+//! `active` entry before the other overrides apply.
+//!
+//! A file sealed by `config_encryption` is named after its plaintext with
+//! `.enc` appended (`settings.toml.enc`) and needs the key to load.
+
+use crate::config::SecuritySettings;
+use crate::config_encryption::{self, ConfigKey};
+use crate::profiles::{ActiveProfile, ProfileSet};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::path::Path;
+
+/// Prefix of the environment variables read as overrides
+pub const ENV_PREFIX: &str = "IPS_";
+
+/// Separator between path segments in variable names
+pub const ENV_SEPARATOR: &str = "__";
+
+/// Pseudo-setting naming the profile to use
+pub const PROFILE_SETTING: &str = "profile";
+
+/// One field to set: its dotted path and the raw value
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Override {
+    pub path: String,
+    pub value: String,
+}
+
+impl Override {
+    /// Parse a `path=value` assignment
+    pub fn parse(assignment: &str) -> Result<Self, String> {
+        let (path, value) = assignment
+            .split_once('=')
+            .ok_or_else(|| format!("expected <setting>=<value>, got '{}'", assignment))?;
+        let path = path.trim().trim_start_matches("--");
+        if path.is_empty() {
+            return Err(format!("missing setting name in '{}'", assignment));
+        }
+        Ok(Override {
+            path: path.to_ascii_lowercase(),
+            value: value.to_string(),
+        })
+    }
+}
+
+/// Overrides from environment variables carrying the `IPS_` prefix
+pub fn env_overrides<I>(vars: I) -> Vec<Override>
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    let mut overrides: Vec<Override> = vars
+        .into_iter()
+        .filter_map(|(name, value)| {
+            let path = name.strip_prefix(ENV_PREFIX)?;
+            Some(Override {
+                path: path.to_ascii_lowercase().replace(ENV_SEPARATOR, "."),
+                value,
+            })
+        })
+        .collect();
+    // Environment order is arbitrary; make the result deterministic
+    overrides.sort_by(|a, b| a.path.cmp(&b.path));
+    overrides
+}
+
+/// Load settings from a file, picking the format from the extension:
+/// `.json`, `.yaml`/`.yml` or `.toml`, decrypting it first with `key` when
+/// it is sealed
+pub fn load_file(path: &Path, key: Option<&ConfigKey>) -> Result<SecuritySettings, String> {
+    load_document(path, key)
+}
+
+/// Load any settings document the way `load_file` does
+pub(crate) fn load_document<T: DeserializeOwned>(
+    path: &Path,
+    key: Option<&ConfigKey>,
+) -> Result<T, String> {
+    let data = std::fs::read(path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+    let (data, format_path) = if config_encryption::is_encrypted(&data) {
+        let key = key.ok_or_else(|| {
+            format!(
+                "{} is encrypted; a passphrase or device key is needed",
+                path.display()
+            )
+        })?;
+        let data = config_encryption::open(&data, key)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        let inner = match path.extension() {
+            Some(extension) if extension == config_encryption::EXTENSION => path.with_extension(""),
+            _ => path.to_path_buf(),
+        };
+        (data, inner)
+    } else {
+        (data, path.to_path_buf())
+    };
+    let text = String::from_utf8(data).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let extension = format_path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or("")
+        .to_ascii_lowercase();
+    let parsed = match extension.as_str() {
+        "json" => serde_json::from_str(&text).map_err(|e| e.to_string()),
+        "yaml" | "yml" => serde_yaml::from_str(&text).map_err(|e| e.to_string()),
+        "toml" => toml::from_str(&text).map_err(|e| e.to_string()),
+        _ => Err("unknown format; use .json, .yaml or .toml".to_string()),
+    };
+    parsed.map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// Apply overrides in order to a copy of `settings`
+pub fn apply(
+    settings: &SecuritySettings,
+    overrides: &[Override],
+) -> Result<SecuritySettings, String> {
+    let mut tree = serde_json::to_value(settings).map_err(|e| e.to_string())?;
+    for item in overrides {
+        let slot = lookup(&mut tree, &item.path)?;
+        *slot = parse_value(slot, &item.value);
+    }
+    serde_json::from_value(tree).map_err(|e| format!("invalid override: {}", e))
+}
+
+/// Resolve settings from a file (defaults without one), the given
+/// environment and command-line assignments, then validate them
+pub fn resolve<I>(
+    file: Option<&Path>,
+    key: Option<&ConfigKey>,
+    env: I,
+    cli: &[Override],
+) -> Result<SecuritySettings, String>
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    resolve_profile(file, key, env, cli).map(|profile| profile.settings)
+}
+
+/// Like `resolve`, keeping which profile was picked and its SNI pool
+pub fn resolve_profile<I>(
+    file: Option<&Path>,
+    key: Option<&ConfigKey>,
+    env: I,
+    cli: &[Override],
+) -> Result<ActiveProfile, String>
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    let profiles = match file {
+        Some(path) => ProfileSet::load(path, key)?,
+        None => ProfileSet::default(),
+    };
+    let env = env_overrides(env);
+    let (chosen, overrides): (Vec<Override>, Vec<Override>) = env
+        .into_iter()
+        .chain(cli.iter().cloned())
+        .partition(|item| item.path == PROFILE_SETTING);
+    let name = match chosen.last() {
+        Some(choice) => Some(choice.value.as_str()),
+        None => profiles.active.as_deref(),
+    };
+    let mut profile = profiles.merged(name)?;
+    profile.settings = apply(&profile.settings, &overrides)?;
+    profile.settings.validate()?;
+    Ok(profile)
+}
+
+/// One field that differs between two settings
+#[derive(Clone, Debug, PartialEq)]
+pub struct SettingChange {
+    pub path: String,
+    pub old: Value,
+    pub new: Value,
+}
+
+impl std::fmt::Display for SettingChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {} -> {}", self.path, self.old, self.new)
+    }
+}
+
+/// The fields of `new` that differ from `old`, by dotted path
+pub fn diff(old: &SecuritySettings, new: &SecuritySettings) -> Vec<SettingChange> {
+    let old = serde_json::to_value(old).unwrap_or(Value::Null);
+    let new = serde_json::to_value(new).unwrap_or(Value::Null);
+    let mut changes = Vec::new();
+    diff_values(String::new(), &old, &new, &mut changes);
+    changes
+}
+
+fn diff_values(path: String, old: &Value, new: &Value, changes: &mut Vec<SettingChange>) {
+    match (old, new) {
+        (Value::Object(old_fields), Value::Object(new_fields)) => {
+            let mut keys: Vec<&String> = old_fields.keys().chain(new_fields.keys()).collect();
+            keys.sort();
+            keys.dedup();
+            for key in keys {
+                let child = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                let missing = Value::Null;
+                diff_values(
+                    child,
+                    old_fields.get(key).unwrap_or(&missing),
+                    new_fields.get(key).unwrap_or(&missing),
+                    changes,
+                );
+            }
+        }
+        _ if old != new => changes.push(SettingChange {
+            path,
+            old: old.clone(),
+            new: new.clone(),
+        }),
+        _ => {}
+    }
+}
+
+/// The value at a dotted path; only existing fields can be set
+fn lookup<'a>(tree: &'a mut Value, path: &str) -> Result<&'a mut Value, String> {
+    let mut node = tree;
+    for segment in path.split('.') {
+        node = match node {
+            Value::Object(fields) => fields
+                .get_mut(segment)
+                .ok_or_else(|| format!("unknown setting '{}'", path))?,
+            _ => return Err(format!("unknown setting '{}'", path)),
+        };
+    }
+    Ok(node)
+}
+
+/// Read a raw value for a field shaped like `current`
+fn parse_value(current: &Value, raw: &str) -> Value {
+    match current {
+        Value::String(_) => Value::String(raw.to_string()),
+        Value::Array(_) if !raw.trim_start().starts_with('[') => Value::Array(
+            raw.split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(json_or_string)
+                .collect(),
+        ),
+        _ => json_or_string(raw),
+    }
+}
+
+fn json_or_string(raw: &str) -> Value {
+    serde_json::from_str(raw).unwrap_or_else(|_| Value::String(raw.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_env_mapping() {
+        let overrides = env_overrides(env(&[
+            ("PATH", "/usr/bin"),
+            ("IPS_OBFUSCATION__MIN_PACKET_SIZE", "200"),
+            ("IPS_MAX_OVERHEAD_RATIO", "0.2"),
+        ]));
+        assert_eq!(
+            overrides,
+            vec![
+                Override::parse("max_overhead_ratio=0.2").unwrap(),
+                Override::parse("obfuscation.min_packet_size=200").unwrap(),
+            ]
+        );
+        assert!(Override::parse("no-assignment").is_err());
+        assert_eq!(
+            Override::parse("--dpi_bypass.enabled=false").unwrap().path,
+            "dpi_bypass.enabled"
+        );
+    }
+
+    #[test]
+    fn test_layers_override_in_order() {
+        let path = std::env::temp_dir().join(format!("ips-layers-{}.toml", std::process::id()));
+        let mut file = SecuritySettings::default();
+        file.obfuscation.min_packet_size = 150;
+        file.obfuscation.max_packet_size = 1800;
+        std::fs::write(&path, file.to_toml().unwrap()).unwrap();
+
+        let settings = resolve(
+            Some(&path),
+            None,
+            env(&[
+                ("IPS_OBFUSCATION__MIN_PACKET_SIZE", "200"),
+                ("IPS_OBFUSCATION__RECORD_PADDING_BUCKETS", "512, 1024"),
+                ("IPS_DETECTION_EVASION__DECOY_TRAFFIC_ENABLED", "false"),
+                ("IPS_MAX_OVERHEAD_RATIO", "0.2"),
+            ]),
+            &[Override::parse("obfuscation.min_packet_size=300").unwrap()],
+        );
+        std::fs::remove_file(&path).unwrap();
+        let settings = settings.unwrap();
+        // File, then environment, then command line
+        assert_eq!(settings.obfuscation.max_packet_size, 1800);
+        assert_eq!(settings.obfuscation.min_packet_size, 300);
+        assert_eq!(settings.obfuscation.record_padding_buckets, vec![512, 1024]);
+        assert!(!settings.detection_evasion.decoy_traffic_enabled);
+        assert_eq!(settings.max_overhead_ratio, Some(0.2));
+    }
+
+    #[test]
+    fn test_invalid_overrides() {
+        let defaults = SecuritySettings::default();
+        let unknown = [Override::parse("obfuscation.no_such_field=1").unwrap()];
+        assert!(apply(&defaults, &unknown)
+            .unwrap_err()
+            .contains("unknown setting"));
+        let mistyped = [Override::parse("obfuscation.min_packet_size=lots").unwrap()];
+        assert!(apply(&defaults, &mistyped).is_err());
+        // Overrides are validated like files
+        let inverted = [Override::parse("obfuscation.min_packet_size=5000").unwrap()];
+        assert!(resolve(None, None, Vec::new(), &inverted).is_err());
+    }
+
+    #[test]
+    fn test_encrypted_file() {
+        let path = std::env::temp_dir().join(format!("ips-sealed-{}.yaml.enc", std::process::id()));
+        let mut settings = SecuritySettings::default();
+        settings.obfuscation.min_packet_size = 180;
+        let yaml = serde_yaml::to_string(&settings).unwrap();
+        let key = ConfigKey::Device([3; 32]);
+        let sealed = config_encryption::seal(yaml.as_bytes(), &key).unwrap();
+        std::fs::write(&path, sealed).unwrap();
+
+        let without_key = load_file(&path, None);
+        let loaded = load_file(&path, Some(&key));
+        std::fs::remove_file(&path).unwrap();
+        assert!(without_key.unwrap_err().contains("encrypted"));
+        assert_eq!(loaded.unwrap().obfuscation.min_packet_size, 180);
+    }
+
+    #[test]
+    fn test_profile_selection() {
+        let path = std::env::temp_dir().join(format!("ips-profiles-{}.json", std::process::id()));
+        let mut file = serde_json::to_value(SecuritySettings::default()).unwrap();
+        file["active"] = "home".into();
+        file["profiles"] = serde_json::json!({
+            "home": {},
+            "mobile-data": {"detection_evasion": {"max_adaptation_level": 8}}
+        });
+        std::fs::write(&path, file.to_string()).unwrap();
+
+        let home = resolve_profile(Some(&path), None, Vec::new(), &[]);
+        let mobile = resolve_profile(
+            Some(&path),
+            None,
+            env(&[("IPS_PROFILE", "mobile-data")]),
+            &[Override::parse("detection_evasion.max_adaptation_level=9").unwrap()],
+        );
+        let unknown = resolve(
+            Some(&path),
+            None,
+            Vec::new(),
+            &[Override::parse("profile=hotel").unwrap()],
+        );
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(home.unwrap().name.as_deref(), Some("home"));
+        let mobile = mobile.unwrap();
+        assert_eq!(mobile.name.as_deref(), Some("mobile-data"));
+        assert_eq!(mobile.settings.detection_evasion.max_adaptation_level, 9);
+        assert!(unknown.unwrap_err().contains("unknown profile"));
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = SecuritySettings::default();
+        assert!(diff(&old, &old).is_empty());
+        let new = apply(
+            &old,
+            &[
+                Override::parse("obfuscation.min_packet_size=200").unwrap(),
+                Override::parse("max_overhead_ratio=0.2").unwrap(),
+            ],
+        )
+        .unwrap();
+        let changes = diff(&old, &new);
+        let shown: Vec<String> = changes.iter().map(|change| change.to_string()).collect();
+        assert_eq!(
+            shown,
+            vec![
+                "max_overhead_ratio: null -> 0.2".to_string(),
+                format!(
+                    "obfuscation.min_packet_size: {} -> 200",
+                    old.obfuscation.min_packet_size
+                ),
+            ]
+        );
+    }
+}
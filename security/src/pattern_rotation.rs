@@ -1,7 +1,7 @@
 //! Pattern rotation module for evasion of fingerprinting
 //! Rotates protocol signatures and connection patterns to avoid being classified
 
-use crate::error::{Error, Result};
+use crate::error::Result;
 use rand::Rng;
 use std::time::{SystemTime, UNIX_EPOCH};
 
@@ -74,13 +74,27 @@ impl PatternRotator {
     }
 
     fn apply_current_pattern(&self, data: &[u8]) -> Vec<u8> {
-        let mut result = data.to_vec();
+        // Only the low byte of the pattern selects the transformation
+        Self::apply_pattern(self.current_pattern as u8, data)
+    }
+
+    /// Pattern byte in force now, for `apply_pattern`; it moves on once per
+    /// rotation interval since the last rotation
+    pub fn current_pattern_byte(&self) -> u8 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let rotation_seconds = (self.rotation_interval_hours as u64 * 3600).max(1);
+        let intervals = now.saturating_sub(self.last_rotation) / rotation_seconds;
+        (self.current_pattern as u64).wrapping_add(intervals) as u8
+    }
 
-        // Apply pattern transformations based on current_pattern
-        // This is deterministic for the current interval
-        let pattern_mod = self.current_pattern % 4;
+    /// Transform `data` with the pattern `pattern` selects
+    pub fn apply_pattern(pattern: u8, data: &[u8]) -> Vec<u8> {
+        let mut result = data.to_vec();
 
-        match pattern_mod {
+        match pattern % 4 {
             0 => {
                 // Pattern 1: No transformation
                 result
@@ -88,7 +102,7 @@ impl PatternRotator {
             1 => {
                 // Pattern 2: Xor with pattern byte
                 for byte in &mut result {
-                    *byte ^= (self.current_pattern % 256) as u8;
+                    *byte ^= pattern;
                 }
                 result
             }
@@ -109,36 +123,34 @@ impl PatternRotator {
         }
     }
 
+    /// Undo `apply_pattern` with the same pattern byte
+    pub fn reverse_pattern(pattern: u8, data: &[u8]) -> Vec<u8> {
+        match pattern % 4 {
+            3 => data.iter().map(|byte| byte.rotate_right(3)).collect(),
+            // The other transformations are their own inverse
+            _ => Self::apply_pattern(pattern, data),
+        }
+    }
+
     fn generate_pattern() -> u32 {
         let mut rng = rand::thread_rng();
         rng.gen()
     }
 
+    /// Switch to a fresh pattern now, as when the current one is detected
+    pub fn rotate_now(&mut self) {
+        self.current_pattern = Self::generate_pattern();
+        self.last_rotation = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+    }
+
     /// Get current pattern ID
     pub fn current_pattern_id(&self) -> u32 {
         self.current_pattern
     }
 
-    /// Vary TLS handshake characteristics
-    pub fn vary_tls_handshake(&self, handshake_data: &[u8]) -> Result<Vec<u8>> {
-        let mut result = handshake_data.to_vec();
-        let mut rng = rand::thread_rng();
-
-        // Randomize cipher suite order
-        if result.len() > 64 {
-            let cipher_section_start = rng.gen_range(20..40);
-            let cipher_section_end = rng.gen_range(cipher_section_start + 10..100);
-
-            if cipher_section_end <= result.len() {
-                let mut cipher_section = result[cipher_section_start..cipher_section_end].to_vec();
-                cipher_section.reverse();
-                result.splice(cipher_section_start..cipher_section_end, cipher_section);
-            }
-        }
-
-        Ok(result)
-    }
-
     /// Randomize connection parameters
     pub fn randomize_connection_params(&self) -> ConnectionParams {
         let mut rng = rand::thread_rng();
@@ -170,6 +182,35 @@ mod tests {
         assert!(rotator.current_pattern_id() > 0);
     }
 
+    #[test]
+    fn test_patterns_reverse() {
+        let data: Vec<u8> = (0..37).map(|i| (i * 31) as u8).collect();
+        for pattern in 0..=u8::MAX {
+            let applied = PatternRotator::apply_pattern(pattern, &data);
+            assert_eq!(PatternRotator::reverse_pattern(pattern, &applied), data);
+        }
+        assert_ne!(PatternRotator::apply_pattern(3, &data), data);
+    }
+
+    #[test]
+    fn test_pattern_byte_follows_rotation() {
+        let mut rotator = PatternRotator::new(1);
+        assert_eq!(
+            rotator.current_pattern_byte(),
+            rotator.current_pattern_id() as u8
+        );
+        rotator.last_rotation -= 2 * 3600;
+        assert_eq!(
+            rotator.current_pattern_byte(),
+            (rotator.current_pattern_id() as u8).wrapping_add(2)
+        );
+        rotator.rotate_now();
+        assert_eq!(
+            rotator.current_pattern_byte(),
+            rotator.current_pattern_id() as u8
+        );
+    }
+
     #[test]
     fn test_rotate_pattern() {
         let rotator = PatternRotator::new(24);
@@ -177,12 +218,4 @@ mod tests {
         let result = rotator.rotate_pattern(test_data).unwrap();
         assert!(!result.is_empty());
     }
-
-    #[test]
-    fn test_vary_tls_handshake() {
-        let rotator = PatternRotator::new(1);
-        let handshake = vec![0; 100];
-        let result = rotator.vary_tls_handshake(&handshake).unwrap();
-        assert_eq!(result.len(), handshake.len());
-    }
 }
@@ -0,0 +1,582 @@
+//! PCAP capture files
+//! Reads classic libpcap captures and decodes the IP and TCP/UDP headers of
+//! each packet, enough to learn sizes, timing and direction of real traffic,
+//! and writes synthesized TCP flows for offline IDS analysis
+
+use crate::error::{Error, Result};
+use std::io::Write;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+const GLOBAL_HEADER_LEN: usize = 24;
+const RECORD_HEADER_LEN: usize = 16;
+
+pub const LINKTYPE_ETHERNET: u32 = 1;
+pub const LINKTYPE_RAW: u32 = 101;
+pub const LINKTYPE_LINUX_SLL: u32 = 113;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+
+const PROTO_TCP: u8 = 6;
+const PROTO_UDP: u8 = 17;
+
+const TCP_SYN: u8 = 0x02;
+const TCP_ACK: u8 = 0x10;
+
+/// One captured frame
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PcapRecord {
+    /// Capture time since the epoch
+    pub timestamp: Duration,
+    /// Bytes captured, possibly cut short by the snap length
+    pub data: Vec<u8>,
+    /// Length of the frame on the wire
+    pub orig_len: usize,
+}
+
+/// Reader of a classic (not pcapng) capture held in memory
+pub struct PcapReader<'a> {
+    data: &'a [u8],
+    offset: usize,
+    big_endian: bool,
+    nanos: bool,
+    link_type: u32,
+}
+
+impl<'a> PcapReader<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Self> {
+        let header = data
+            .get(..GLOBAL_HEADER_LEN)
+            .ok_or_else(|| Error::DataError("PCAP file too short".to_string()))?;
+        let (big_endian, nanos) = match header[..4] {
+            [0xd4, 0xc3, 0xb2, 0xa1] => (false, false),
+            [0xa1, 0xb2, 0xc3, 0xd4] => (true, false),
+            [0x4d, 0x3c, 0xb2, 0xa1] => (false, true),
+            [0xa1, 0xb2, 0x3c, 0x4d] => (true, true),
+            [0x0a, 0x0d, 0x0d, 0x0a] => {
+                return Err(Error::DataError(
+                    "pcapng is not supported; convert with `editcap -F pcap`".to_string(),
+                ))
+            }
+            _ => return Err(Error::DataError("Not a PCAP file".to_string())),
+        };
+        let mut reader = PcapReader {
+            data,
+            offset: GLOBAL_HEADER_LEN,
+            big_endian,
+            nanos,
+            link_type: 0,
+        };
+        reader.link_type = reader.u32_at(20);
+        Ok(reader)
+    }
+
+    pub fn link_type(&self) -> u32 {
+        self.link_type
+    }
+
+    fn u32_at(&self, offset: usize) -> u32 {
+        let bytes: [u8; 4] = self.data[offset..offset + 4].try_into().unwrap();
+        if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    }
+
+    /// The next record, `None` at the end of the file
+    pub fn next_record(&mut self) -> Result<Option<PcapRecord>> {
+        if self.offset == self.data.len() {
+            return Ok(None);
+        }
+        if self.data.len() - self.offset < RECORD_HEADER_LEN {
+            return Err(Error::DataError("Truncated PCAP record header".to_string()));
+        }
+        let secs = self.u32_at(self.offset) as u64;
+        let frac = self.u32_at(self.offset + 4);
+        let incl_len = self.u32_at(self.offset + 8) as usize;
+        let orig_len = self.u32_at(self.offset + 12) as usize;
+        let start = self.offset + RECORD_HEADER_LEN;
+        let data = self
+            .data
+            .get(start..start + incl_len)
+            .ok_or_else(|| Error::DataError("Truncated PCAP record".to_string()))?;
+        self.offset = start + incl_len;
+
+        let frac = if self.nanos {
+            Duration::from_nanos(frac as u64)
+        } else {
+            Duration::from_micros(frac as u64)
+        };
+        Ok(Some(PcapRecord {
+            timestamp: Duration::from_secs(secs) + frac,
+            data: data.to_vec(),
+            orig_len,
+        }))
+    }
+}
+
+/// Transport protocol of a decoded packet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transport {
+    Tcp,
+    Udp,
+}
+
+/// Addressing and sizes of a TCP or UDP packet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PacketInfo {
+    pub transport: Transport,
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+    /// Transport payload length, from the IP header so it is right even
+    /// when the capture kept only the headers
+    pub payload_len: usize,
+    /// A TCP SYN without ACK, the first packet of a connection
+    pub syn: bool,
+}
+
+/// Decode the IP and transport headers of a frame of `link_type`
+///
+/// Returns `None` for frames that are not TCP or UDP over IPv4 or IPv6.
+pub fn decode(link_type: u32, frame: &[u8]) -> Option<PacketInfo> {
+    let (ethertype, ip) = match link_type {
+        LINKTYPE_ETHERNET => {
+            let mut ethertype = u16::from_be_bytes([*frame.get(12)?, *frame.get(13)?]);
+            let mut offset = 14;
+            if ethertype == ETHERTYPE_VLAN {
+                ethertype = u16::from_be_bytes([*frame.get(16)?, *frame.get(17)?]);
+                offset = 18;
+            }
+            (Some(ethertype), frame.get(offset..)?)
+        }
+        LINKTYPE_LINUX_SLL => (
+            Some(u16::from_be_bytes([*frame.get(14)?, *frame.get(15)?])),
+            frame.get(16..)?,
+        ),
+        LINKTYPE_RAW => (None, frame),
+        _ => return None,
+    };
+    let version = ip.first()? >> 4;
+    match (ethertype, version) {
+        (Some(ETHERTYPE_IPV4) | None, 4) => decode_ipv4(ip),
+        (Some(ETHERTYPE_IPV6) | None, 6) => decode_ipv6(ip),
+        _ => None,
+    }
+}
+
+fn decode_ipv4(ip: &[u8]) -> Option<PacketInfo> {
+    let header_len = ((ip.first()? & 0x0f) as usize) * 4;
+    let total_len = u16::from_be_bytes([*ip.get(2)?, *ip.get(3)?]) as usize;
+    let protocol = *ip.get(9)?;
+    let src: [u8; 4] = ip.get(12..16)?.try_into().ok()?;
+    let dst: [u8; 4] = ip.get(16..20)?.try_into().ok()?;
+    decode_transport(
+        protocol,
+        IpAddr::V4(Ipv4Addr::from(src)),
+        IpAddr::V4(Ipv4Addr::from(dst)),
+        ip.get(header_len..)?,
+        total_len.checked_sub(header_len)?,
+    )
+}
+
+fn decode_ipv6(ip: &[u8]) -> Option<PacketInfo> {
+    let payload_len = u16::from_be_bytes([*ip.get(4)?, *ip.get(5)?]) as usize;
+    let next_header = *ip.get(6)?;
+    let src: [u8; 16] = ip.get(8..24)?.try_into().ok()?;
+    let dst: [u8; 16] = ip.get(24..40)?.try_into().ok()?;
+    decode_transport(
+        next_header,
+        IpAddr::V6(Ipv6Addr::from(src)),
+        IpAddr::V6(Ipv6Addr::from(dst)),
+        ip.get(40..)?,
+        payload_len,
+    )
+}
+
+/// `segment` is what was captured of the transport segment, `len` its
+/// length according to the IP header
+fn decode_transport(
+    protocol: u8,
+    src: IpAddr,
+    dst: IpAddr,
+    segment: &[u8],
+    len: usize,
+) -> Option<PacketInfo> {
+    let sport = u16::from_be_bytes([*segment.first()?, *segment.get(1)?]);
+    let dport = u16::from_be_bytes([*segment.get(2)?, *segment.get(3)?]);
+    let (transport, header_len, syn) = match protocol {
+        PROTO_TCP => {
+            let header_len = ((segment.get(12)? >> 4) as usize) * 4;
+            let flags = *segment.get(13)?;
+            (
+                Transport::Tcp,
+                header_len,
+                flags & TCP_SYN != 0 && flags & TCP_ACK == 0,
+            )
+        }
+        PROTO_UDP => (Transport::Udp, 8, false),
+        _ => return None,
+    };
+    Some(PacketInfo {
+        transport,
+        src: SocketAddr::new(src, sport),
+        dst: SocketAddr::new(dst, dport),
+        payload_len: len.checked_sub(header_len)?,
+        syn,
+    })
+}
+
+/// Flags of a TCP segment
+pub mod tcp_flags {
+    pub const FIN: u8 = 0x01;
+    pub const SYN: u8 = super::TCP_SYN;
+    pub const PSH: u8 = 0x08;
+    pub const ACK: u8 = super::TCP_ACK;
+}
+
+/// Build an Ethernet frame carrying one TCP segment, with valid IP and TCP
+/// checksums so IDS engines don't discard it
+pub fn ethernet_tcp(
+    src: SocketAddr,
+    dst: SocketAddr,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    payload: &[u8],
+) -> Result<Vec<u8>> {
+    let mut segment = Vec::with_capacity(20 + payload.len());
+    segment.extend(src.port().to_be_bytes());
+    segment.extend(dst.port().to_be_bytes());
+    segment.extend(seq.to_be_bytes());
+    segment.extend(ack.to_be_bytes());
+    segment.extend([0x50, flags, 0xff, 0xff, 0, 0, 0, 0]);
+    segment.extend_from_slice(payload);
+    let segment_len = u16::try_from(segment.len())
+        .map_err(|_| Error::DataError("TCP segment too large".to_string()))?;
+
+    let mut frame = vec![0x02, 0, 0, 0, 0, 0x02, 0x02, 0, 0, 0, 0, 0x01];
+    let mut pseudo = Vec::with_capacity(40);
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            frame.extend(ETHERTYPE_IPV4.to_be_bytes());
+            let mut header = vec![0x45, 0];
+            header.extend((20 + segment_len).to_be_bytes());
+            header.extend([0, 0, 0x40, 0, 64, PROTO_TCP, 0, 0]);
+            header.extend(src_ip.octets());
+            header.extend(dst_ip.octets());
+            let checksum = internet_checksum(&[&header]);
+            header[10..12].copy_from_slice(&checksum.to_be_bytes());
+            frame.extend(header);
+
+            pseudo.extend(src_ip.octets());
+            pseudo.extend(dst_ip.octets());
+            pseudo.extend([0, PROTO_TCP]);
+            pseudo.extend(segment_len.to_be_bytes());
+        }
+        (IpAddr::V6(src_ip), IpAddr::V6(dst_ip)) => {
+            frame.extend(ETHERTYPE_IPV6.to_be_bytes());
+            frame.extend([0x60, 0, 0, 0]);
+            frame.extend(segment_len.to_be_bytes());
+            frame.extend([PROTO_TCP, 64]);
+            frame.extend(src_ip.octets());
+            frame.extend(dst_ip.octets());
+
+            pseudo.extend(src_ip.octets());
+            pseudo.extend(dst_ip.octets());
+            pseudo.extend((segment_len as u32).to_be_bytes());
+            pseudo.extend([0, 0, 0, PROTO_TCP]);
+        }
+        _ => {
+            return Err(Error::DataError(
+                "TCP endpoints must share an address family".to_string(),
+            ))
+        }
+    }
+    let checksum = internet_checksum(&[&pseudo, &segment]);
+    segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+    frame.extend(segment);
+    Ok(frame)
+}
+
+/// RFC 1071 checksum over the concatenation of `parts`
+fn internet_checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum = 0u32;
+    for part in parts {
+        let mut words = part.chunks_exact(2);
+        for word in &mut words {
+            sum += u16::from_be_bytes([word[0], word[1]]) as u32;
+        }
+        if let [last] = words.remainder() {
+            sum += (*last as u32) << 8;
+        }
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Writer of a classic little-endian, microsecond-resolution capture of
+/// Ethernet frames
+pub struct PcapWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> PcapWriter<W> {
+    pub fn new(mut writer: W) -> Result<Self> {
+        writer.write_all(&0xa1b2c3d4u32.to_le_bytes())?;
+        writer.write_all(&2u16.to_le_bytes())?;
+        writer.write_all(&4u16.to_le_bytes())?;
+        writer.write_all(&[0; 8])?;
+        writer.write_all(&65535u32.to_le_bytes())?;
+        writer.write_all(&LINKTYPE_ETHERNET.to_le_bytes())?;
+        Ok(PcapWriter { writer })
+    }
+
+    /// Append a frame captured at `timestamp`
+    pub fn write_frame(&mut self, timestamp: Duration, frame: &[u8]) -> Result<()> {
+        self.writer
+            .write_all(&(timestamp.as_secs() as u32).to_le_bytes())?;
+        self.writer
+            .write_all(&timestamp.subsec_micros().to_le_bytes())?;
+        self.writer.write_all(&(frame.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&(frame.len() as u32).to_le_bytes())?;
+        self.writer.write_all(frame)?;
+        Ok(())
+    }
+
+    /// Flush and return the underlying writer
+    pub fn into_inner(mut self) -> Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// A TCP connection synthesized into a capture
+///
+/// Writes the three-way handshake, then each payload as MSS-sized data
+/// segments with correct sequence numbers, each acknowledged by the peer,
+/// so Suricata, Zeek or nDPI reassemble the stream as if it had been
+/// captured off a real link. Timestamps start at `start` and advance by the
+/// delay given with each payload.
+pub struct SyntheticFlow<W: Write> {
+    writer: PcapWriter<W>,
+    client: SocketAddr,
+    server: SocketAddr,
+    now: Duration,
+    client_seq: u32,
+    server_seq: u32,
+    mss: usize,
+}
+
+impl<W: Write> SyntheticFlow<W> {
+    pub fn new(writer: W, client: SocketAddr, server: SocketAddr, start: Duration) -> Result<Self> {
+        let mut flow = SyntheticFlow {
+            writer: PcapWriter::new(writer)?,
+            client,
+            server,
+            now: start,
+            client_seq: rand::random(),
+            server_seq: rand::random(),
+            mss: 1460,
+        };
+        flow.segment(true, tcp_flags::SYN, &[])?;
+        flow.client_seq = flow.client_seq.wrapping_add(1);
+        flow.segment(false, tcp_flags::SYN | tcp_flags::ACK, &[])?;
+        flow.server_seq = flow.server_seq.wrapping_add(1);
+        flow.segment(true, tcp_flags::ACK, &[])?;
+        Ok(flow)
+    }
+
+    /// Time of the last packet written
+    pub fn now(&self) -> Duration {
+        self.now
+    }
+
+    /// Write `data` sent by the client `delay` after the previous packet
+    pub fn send(&mut self, delay: Duration, data: &[u8]) -> Result<()> {
+        self.data(true, delay, data)
+    }
+
+    /// Write `data` sent by the server `delay` after the previous packet
+    pub fn receive(&mut self, delay: Duration, data: &[u8]) -> Result<()> {
+        self.data(false, delay, data)
+    }
+
+    /// Close the connection with a FIN exchange and flush
+    pub fn finish(mut self) -> Result<W> {
+        self.segment(true, tcp_flags::FIN | tcp_flags::ACK, &[])?;
+        self.client_seq = self.client_seq.wrapping_add(1);
+        self.segment(false, tcp_flags::FIN | tcp_flags::ACK, &[])?;
+        self.server_seq = self.server_seq.wrapping_add(1);
+        self.segment(true, tcp_flags::ACK, &[])?;
+        self.writer.into_inner()
+    }
+
+    fn data(&mut self, from_client: bool, delay: Duration, data: &[u8]) -> Result<()> {
+        self.now += delay;
+        for chunk in data.chunks(self.mss) {
+            self.segment(from_client, tcp_flags::PSH | tcp_flags::ACK, chunk)?;
+            let seq = if from_client {
+                &mut self.client_seq
+            } else {
+                &mut self.server_seq
+            };
+            *seq = seq.wrapping_add(chunk.len() as u32);
+        }
+        // The peer acknowledges everything at once
+        self.segment(!from_client, tcp_flags::ACK, &[])
+    }
+
+    fn segment(&mut self, from_client: bool, flags: u8, payload: &[u8]) -> Result<()> {
+        let frame = if from_client {
+            ethernet_tcp(
+                self.client,
+                self.server,
+                self.client_seq,
+                self.server_seq,
+                flags,
+                payload,
+            )?
+        } else {
+            ethernet_tcp(
+                self.server,
+                self.client,
+                self.server_seq,
+                self.client_seq,
+                flags,
+                payload,
+            )?
+        };
+        self.writer.write_frame(self.now, &frame)
+    }
+}
+
+/// Ethernet frame of an IPv4 TCP segment with `payload_len` zero bytes
+#[cfg(test)]
+pub(crate) fn tcp_frame(
+    src: SocketAddr,
+    dst: SocketAddr,
+    flags: u8,
+    payload_len: usize,
+) -> Vec<u8> {
+    ethernet_tcp(src, dst, 1, 1, flags, &vec![0; payload_len]).unwrap()
+}
+
+/// Capture of the given frames
+#[cfg(test)]
+pub(crate) fn capture(frames: &[(Duration, Vec<u8>)]) -> Vec<u8> {
+    let mut writer = PcapWriter::new(Vec::new()).unwrap();
+    for (at, frame) in frames {
+        writer.write_frame(*at, frame).unwrap();
+    }
+    writer.into_inner().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_reads_records() {
+        let syn = tcp_frame(addr("10.0.0.2:50000"), addr("1.2.3.4:443"), TCP_SYN, 0);
+        let data = tcp_frame(addr("1.2.3.4:443"), addr("10.0.0.2:50000"), TCP_ACK, 1400);
+        let file = capture(&[
+            (Duration::from_millis(1500), syn),
+            (Duration::from_millis(1520), data),
+        ]);
+
+        let mut reader = PcapReader::new(&file).unwrap();
+        assert_eq!(reader.link_type(), LINKTYPE_ETHERNET);
+        let first = reader.next_record().unwrap().unwrap();
+        assert_eq!(first.timestamp, Duration::from_millis(1500));
+        let info = decode(reader.link_type(), &first.data).unwrap();
+        assert!(info.syn);
+        assert_eq!(info.payload_len, 0);
+        assert_eq!(info.dst, addr("1.2.3.4:443"));
+
+        let second = reader.next_record().unwrap().unwrap();
+        let info = decode(reader.link_type(), &second.data).unwrap();
+        assert_eq!(info.transport, Transport::Tcp);
+        assert_eq!(info.payload_len, 1400);
+        assert!(!info.syn);
+        assert!(reader.next_record().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_payload_length_survives_snap_length() {
+        let mut frame = tcp_frame(addr("10.0.0.2:1"), addr("1.2.3.4:443"), TCP_ACK, 1000);
+        frame.truncate(54);
+        assert_eq!(decode(LINKTYPE_ETHERNET, &frame).unwrap().payload_len, 1000);
+        // Raw IP captures have no link header
+        assert_eq!(
+            decode(LINKTYPE_RAW, &frame[14..]).unwrap().payload_len,
+            1000
+        );
+    }
+
+    #[test]
+    fn test_rejects_other_formats() {
+        assert!(PcapReader::new(&[0x0a, 0x0d, 0x0d, 0x0a, 0, 0, 0, 0]).is_err());
+        let mut pcapng = vec![0x0a, 0x0d, 0x0d, 0x0a];
+        pcapng.resize(GLOBAL_HEADER_LEN, 0);
+        assert!(PcapReader::new(&pcapng).is_err());
+
+        let mut file = capture(&[]);
+        file.extend([1, 2, 3]);
+        let mut reader = PcapReader::new(&file).unwrap();
+        assert!(reader.next_record().is_err());
+    }
+
+    #[test]
+    fn test_checksums_verify() {
+        for (src, dst) in [
+            (addr("10.0.0.2:50000"), addr("1.2.3.4:443")),
+            (addr("[2001:db8::2]:50000"), addr("[2001:db8::1]:443")),
+        ] {
+            let frame = ethernet_tcp(src, dst, 7, 9, tcp_flags::ACK, b"hello").unwrap();
+            let info = decode(LINKTYPE_ETHERNET, &frame).unwrap();
+            assert_eq!(info.payload_len, 5);
+            assert_eq!((info.src, info.dst), (src, dst));
+            if src.is_ipv4() {
+                assert_eq!(internet_checksum(&[&frame[14..34]]), 0);
+            }
+        }
+        assert!(ethernet_tcp(addr("10.0.0.2:1"), addr("[2001:db8::1]:443"), 0, 0, 0, &[]).is_err());
+    }
+
+    #[test]
+    fn test_synthetic_flow() {
+        let client = addr("10.0.0.2:50000");
+        let server = addr("203.0.113.10:443");
+        let start = Duration::from_secs(1_700_000_000);
+        let mut flow = SyntheticFlow::new(Vec::new(), client, server, start).unwrap();
+        flow.send(Duration::from_millis(15), &[1; 3000]).unwrap();
+        flow.receive(Duration::from_millis(40), &[2; 100]).unwrap();
+        let file = flow.finish().unwrap();
+
+        let mut reader = PcapReader::new(&file).unwrap();
+        let mut packets = Vec::new();
+        while let Some(record) = reader.next_record().unwrap() {
+            let seq = u32::from_be_bytes(record.data[38..42].try_into().unwrap());
+            let info = decode(reader.link_type(), &record.data).unwrap();
+            packets.push((record.timestamp - start, info, seq));
+        }
+        // Handshake, 3 data segments and an ACK, 1 and an ACK, FIN exchange
+        assert_eq!(packets.len(), 3 + 4 + 2 + 3);
+        assert!(packets[0].1.syn);
+        let sent: Vec<usize> = packets[3..6].iter().map(|p| p.1.payload_len).collect();
+        assert_eq!(sent, vec![1460, 1460, 80]);
+        assert_eq!(packets[4].2, packets[3].2.wrapping_add(1460));
+        assert_eq!(packets[3].0, Duration::from_millis(15));
+        assert_eq!(packets[7].0, Duration::from_millis(55));
+        assert_eq!(packets[7].1.src, server);
+    }
+}
@@ -0,0 +1,342 @@
+//! Split-tunneling policy
+//! Decides which destinations go direct: Iranian domestic services must not
+//! be proxied or obfuscated, since many refuse foreign addresses and tunnelled
+//! domestic traffic stands out
+
+use crate::error::{Error, Result};
+use crate::geodata::GeoSiteList;
+use crate::geoip::GeoIpDb;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, OnceLock};
+
+/// Domains sent direct by default: the national TLD and domestic services
+/// hosted under generic TLDs
+const DOMESTIC_DOMAINS: &[&str] = &[
+    "ir",
+    "aparat.com",
+    "digikala.com",
+    "divar.ir",
+    "snapp.taxi",
+    "cafebazaar.ir",
+    "shaparak.ir",
+    "eitaa.com",
+    "rubika.ir",
+    "filimo.com",
+    "namava.ir",
+];
+
+/// Prefix of domain-list entries naming a geosite category, e.g.
+/// `geosite:category-ir` or `geosite:category-ads-all@ads`
+const GEOSITE_PREFIX: &str = "geosite:";
+
+/// Which domains go direct and which are always proxied
+///
+/// Entries of either list may name a geosite category instead of a domain;
+/// those match once a `geosite.dat` is given to the policy.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SplitTunnelConfig {
+    /// Domains sent direct with their subdomains; `ir` covers the TLD
+    pub direct_domains: Vec<String>,
+    /// Domains always proxied, overriding the domain list and GeoIP, e.g.
+    /// foreign services behind domestic CDN addresses
+    pub proxy_domains: Vec<String>,
+    /// Send private, loopback and link-local addresses direct
+    pub bypass_local: bool,
+}
+
+impl Default for SplitTunnelConfig {
+    fn default() -> Self {
+        SplitTunnelConfig {
+            direct_domains: DOMESTIC_DOMAINS.iter().map(|d| d.to_string()).collect(),
+            proxy_domains: Vec::new(),
+            bypass_local: true,
+        }
+    }
+}
+
+/// A connection target: its name, its address, or both once resolved
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Destination<'a> {
+    pub host: Option<&'a str>,
+    pub ip: Option<IpAddr>,
+}
+
+impl<'a> Destination<'a> {
+    pub fn resolved(host: &'a str, ip: IpAddr) -> Self {
+        Destination {
+            host: Some(host),
+            ip: Some(ip),
+        }
+    }
+}
+
+impl<'a> From<&'a str> for Destination<'a> {
+    /// A host name, or an address literal
+    fn from(host: &'a str) -> Self {
+        match host.trim_matches(|c| c == '[' || c == ']').parse() {
+            Ok(ip) => Destination {
+                host: None,
+                ip: Some(ip),
+            },
+            Err(_) => Destination {
+                host: Some(host),
+                ip: None,
+            },
+        }
+    }
+}
+
+impl From<IpAddr> for Destination<'_> {
+    fn from(ip: IpAddr) -> Self {
+        Destination {
+            host: None,
+            ip: Some(ip),
+        }
+    }
+}
+
+impl From<SocketAddr> for Destination<'_> {
+    fn from(addr: SocketAddr) -> Self {
+        addr.ip().into()
+    }
+}
+
+/// Why a destination goes direct
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DirectReason {
+    /// The name is on the direct domain list
+    DomesticDomain,
+    /// GeoIP places the address in Iran
+    DomesticAddress,
+    /// A private, loopback or link-local address
+    LocalAddress,
+}
+
+/// How to carry a connection
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Route {
+    /// Straight to the destination, with no proxying or obfuscation
+    Direct(DirectReason),
+    /// Through the tunnel
+    Proxy,
+}
+
+/// Classifies destinations as domestic or international
+pub struct SplitTunnelPolicy {
+    config: SplitTunnelConfig,
+    geoip: Arc<GeoIpDb>,
+    geosite: Option<Arc<GeoSiteList>>,
+}
+
+impl SplitTunnelPolicy {
+    pub fn new(config: SplitTunnelConfig, geoip: Arc<GeoIpDb>) -> Self {
+        SplitTunnelPolicy {
+            config,
+            geoip,
+            geosite: None,
+        }
+    }
+
+    /// Resolve `geosite:` entries against a loaded `geosite.dat`; fails if
+    /// an entry names a category the file lacks
+    pub fn with_geosite(mut self, geosite: Arc<GeoSiteList>) -> Result<Self> {
+        let domains = self.config.direct_domains.iter();
+        for entry in domains.chain(&self.config.proxy_domains) {
+            if let Some(selector) = entry.strip_prefix(GEOSITE_PREFIX) {
+                if geosite.select(selector).is_none() {
+                    return Err(Error::ConfigError(format!(
+                        "Unknown geosite category '{}'",
+                        selector
+                    )));
+                }
+            }
+        }
+        self.geosite = Some(geosite);
+        Ok(self)
+    }
+
+    pub fn config(&self) -> &SplitTunnelConfig {
+        &self.config
+    }
+
+    /// Decide how to carry a connection
+    ///
+    /// The name is checked before the address: a forced-proxy domain wins
+    /// over everything, a direct domain over GeoIP.
+    pub fn route<'a>(&self, dest: impl Into<Destination<'a>>) -> Route {
+        let dest = dest.into();
+        if let Some(host) = dest.host {
+            if self.matches_any(&self.config.proxy_domains, host) {
+                return Route::Proxy;
+            }
+            if self.matches_any(&self.config.direct_domains, host) {
+                return Route::Direct(DirectReason::DomesticDomain);
+            }
+        }
+        if let Some(ip) = dest.ip {
+            if self.config.bypass_local && is_local(ip) {
+                return Route::Direct(DirectReason::LocalAddress);
+            }
+            if self.geoip.is_domestic(ip) {
+                return Route::Direct(DirectReason::DomesticAddress);
+            }
+        }
+        Route::Proxy
+    }
+
+    /// Whether a destination should skip the tunnel
+    pub fn should_bypass<'a>(&self, dest: impl Into<Destination<'a>>) -> bool {
+        matches!(self.route(dest), Route::Direct(_))
+    }
+
+    fn matches_any(&self, domains: &[String], host: &str) -> bool {
+        domains
+            .iter()
+            .any(|entry| match entry.strip_prefix(GEOSITE_PREFIX) {
+                Some(selector) => self
+                    .geosite
+                    .as_ref()
+                    .is_some_and(|geosite| geosite.matches(selector, host)),
+                None => domain_matches(entry, host),
+            })
+    }
+}
+
+impl Default for SplitTunnelPolicy {
+    fn default() -> Self {
+        Self::new(SplitTunnelConfig::default(), Arc::new(GeoIpDb::bundled()))
+    }
+}
+
+/// Whether a destination should skip the tunnel under the default policy:
+/// the bundled GeoIP list and the default domestic domains
+pub fn should_bypass<'a>(dest: impl Into<Destination<'a>>) -> bool {
+    static DEFAULT: OnceLock<SplitTunnelPolicy> = OnceLock::new();
+    DEFAULT
+        .get_or_init(SplitTunnelPolicy::default)
+        .should_bypass(dest)
+}
+
+/// Whether `host` is `domain` or one of its subdomains
+pub(crate) fn domain_matches(domain: &str, host: &str) -> bool {
+    let host = host.trim_end_matches('.');
+    let domain = domain.trim_start_matches("*.").trim_matches('.');
+    host.len() >= domain.len()
+        && host[host.len() - domain.len()..].eq_ignore_ascii_case(domain)
+        && (host.len() == domain.len() || host.as_bytes()[host.len() - domain.len() - 1] == b'.')
+}
+
+fn is_local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_loopback() || ip.is_link_local(),
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            // Unique local fc00::/7 and link-local fe80::/10
+            ip.is_loopback() || first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_domain_matching() {
+        assert!(domain_matches("ir", "www.digikala.ir"));
+        assert!(domain_matches("digikala.com", "DigiKala.com."));
+        assert!(domain_matches("*.digikala.com", "api.digikala.com"));
+        assert!(!domain_matches("digikala.com", "notdigikala.com"));
+        assert!(!domain_matches("ir", "example.com"));
+    }
+
+    #[test]
+    fn test_default_policy() {
+        assert!(should_bypass("www.aparat.com"));
+        assert!(should_bypass("bank.sb24.ir"));
+        assert!(!should_bypass("www.youtube.com"));
+        assert!(should_bypass("5.120.1.1"));
+        assert!(should_bypass("[fe80::1]"));
+        assert!(!should_bypass("8.8.8.8".parse::<IpAddr>().unwrap()));
+        let addr: SocketAddr = "192.168.1.1:443".parse().unwrap();
+        assert!(should_bypass(addr));
+    }
+
+    #[test]
+    fn test_domains_override_geoip() {
+        let policy = SplitTunnelPolicy::new(
+            SplitTunnelConfig {
+                proxy_domains: vec!["cdn.foreign.example".to_string()],
+                direct_domains: vec!["shop.example".to_string()],
+                bypass_local: false,
+            },
+            Arc::new(GeoIpDb::bundled()),
+        );
+        let domestic: IpAddr = "2.180.0.1".parse().unwrap();
+        let foreign: IpAddr = "104.16.0.1".parse().unwrap();
+        assert_eq!(
+            policy.route(Destination::resolved("cdn.foreign.example", domestic)),
+            Route::Proxy
+        );
+        assert_eq!(
+            policy.route(Destination::resolved("shop.example", foreign)),
+            Route::Direct(DirectReason::DomesticDomain)
+        );
+        assert_eq!(
+            policy.route(domestic),
+            Route::Direct(DirectReason::DomesticAddress)
+        );
+        assert_eq!(policy.route("10.0.0.1"), Route::Proxy);
+    }
+
+    #[test]
+    fn test_geosite_and_geoip_data() {
+        let geosite = crate::geodata::tests::geosite(&[
+            ("category-ir", &[(2, "example-bank.com", &[])]),
+            ("netflix", &[(2, "nflxvideo.net", &[])]),
+        ]);
+        let geosite = Arc::new(GeoSiteList::parse(&geosite).unwrap());
+        let config = SplitTunnelConfig {
+            direct_domains: vec!["geosite:category-ir".to_string()],
+            proxy_domains: vec!["geosite:netflix".to_string()],
+            bypass_local: false,
+        };
+
+        // Categories only match once the data file is loaded
+        let unloaded = SplitTunnelPolicy::new(config.clone(), Arc::new(GeoIpDb::new()));
+        assert!(!unloaded.should_bypass("www.example-bank.com"));
+
+        let iran = "185.143.232.0".parse().unwrap();
+        let geoip_dat = crate::geodata::tests::geoip("IR", &[(iran, 22)], false);
+        let mut geoip = GeoIpDb::bundled();
+        geoip.extend(
+            crate::geodata::GeoIpList::parse(&geoip_dat)
+                .unwrap()
+                .country_db("ir")
+                .unwrap(),
+        );
+        let policy = SplitTunnelPolicy::new(config.clone(), Arc::new(geoip))
+            .with_geosite(geosite.clone())
+            .unwrap();
+        assert!(policy.should_bypass("www.example-bank.com"));
+        let domestic: IpAddr = "185.143.233.10".parse().unwrap();
+        assert_eq!(
+            policy.route(domestic),
+            Route::Direct(DirectReason::DomesticAddress)
+        );
+        assert_eq!(
+            policy.route(Destination::resolved("ipv4.nflxvideo.net", domestic)),
+            Route::Proxy
+        );
+
+        let unknown = SplitTunnelConfig {
+            direct_domains: vec!["geosite:category-cn".to_string()],
+            ..config
+        };
+        assert!(SplitTunnelPolicy::new(unknown, Arc::new(GeoIpDb::new()))
+            .with_geosite(geosite)
+            .is_err());
+    }
+}
@@ -0,0 +1,300 @@
+//! Destination port hopping
+//! Moves connections to a new port every interval, on a schedule both ends
+//! derive from a shared seed, so no single port carries a long-lived flow
+
+use crate::error::{Error, Result};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// Configuration for port hopping; both peers need identical values
+#[derive(Clone, Debug)]
+pub struct PortHopConfig {
+    /// Shared pattern seed the schedule is derived from
+    pub seed: Vec<u8>,
+    /// Inclusive range ports are drawn from
+    pub port_range: (u16, u16),
+    pub hop_interval: Duration,
+    /// Neighbouring slots the listening side keeps open to absorb clock skew
+    /// and connections still finishing on the previous port
+    pub grace_slots: u32,
+}
+
+impl Default for PortHopConfig {
+    fn default() -> Self {
+        PortHopConfig {
+            seed: Vec::new(),
+            port_range: (20000, 40000),
+            hop_interval: Duration::from_secs(60),
+            grace_slots: 1,
+        }
+    }
+}
+
+/// Computes the port schedule
+#[derive(Clone, Debug)]
+pub struct PortHopper {
+    config: PortHopConfig,
+}
+
+impl PortHopper {
+    pub fn new(config: PortHopConfig) -> Result<Self> {
+        if config.seed.is_empty() {
+            return Err(Error::ConfigError("Port hopping seed is empty".to_string()));
+        }
+        if config.port_range.0 == 0 || config.port_range.0 > config.port_range.1 {
+            return Err(Error::ConfigError(format!(
+                "Invalid port hopping range {}-{}",
+                config.port_range.0, config.port_range.1
+            )));
+        }
+        if config.hop_interval.as_secs() == 0 {
+            return Err(Error::ConfigError(
+                "Port hopping interval must be at least one second".to_string(),
+            ));
+        }
+        Ok(PortHopper { config })
+    }
+
+    pub fn config(&self) -> &PortHopConfig {
+        &self.config
+    }
+
+    /// Schedule slot containing `time`
+    pub fn slot_at(&self, time: SystemTime) -> u64 {
+        let secs = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        secs / self.config.hop_interval.as_secs()
+    }
+
+    pub fn current_slot(&self) -> u64 {
+        self.slot_at(SystemTime::now())
+    }
+
+    /// Port used during `slot`
+    pub fn port_for_slot(&self, slot: u64) -> u16 {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.config.seed)
+            .expect("HMAC accepts keys of any length");
+        mac.update(b"port-hop");
+        mac.update(&slot.to_be_bytes());
+        let digest = mac.finalize().into_bytes();
+
+        let (low, high) = self.config.port_range;
+        let span = (high - low) as u64 + 1;
+        let value = u64::from_be_bytes(digest[..8].try_into().unwrap());
+        low + (value % span) as u16
+    }
+
+    /// Port to connect to right now
+    pub fn current_port(&self) -> u16 {
+        self.port_for_slot(self.current_slot())
+    }
+
+    /// Address to connect to right now
+    pub fn current_addr(&self, ip: IpAddr) -> SocketAddr {
+        SocketAddr::new(ip, self.current_port())
+    }
+
+    /// Time left until the schedule moves to the next port
+    pub fn next_hop_in(&self) -> Duration {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let interval = self.config.hop_interval.as_secs();
+        let next = (now.as_secs() / interval + 1) * interval;
+        Duration::from_secs(next) - now
+    }
+
+    /// Ports the listening side keeps open during `slot`
+    pub fn listen_ports(&self, slot: u64) -> Vec<u16> {
+        let grace = self.config.grace_slots as u64;
+        let mut ports: Vec<u16> = (slot.saturating_sub(grace)..=slot.saturating_add(grace))
+            .map(|s| self.port_for_slot(s))
+            .collect();
+        ports.sort_unstable();
+        ports.dedup();
+        ports
+    }
+}
+
+/// Listener following the hop schedule on the serving side
+///
+/// Keeps one accept task per port in the current listen set and moves them
+/// as slots change. Ports that can't be bound are logged and skipped.
+pub struct HoppingListener {
+    hopper: PortHopper,
+    ip: IpAddr,
+    tasks: HashMap<u16, JoinHandle<()>>,
+    tx: mpsc::Sender<(TcpStream, SocketAddr)>,
+    rx: mpsc::Receiver<(TcpStream, SocketAddr)>,
+}
+
+impl HoppingListener {
+    /// Start listening on the current port set
+    pub async fn bind(hopper: PortHopper, ip: IpAddr) -> Result<Self> {
+        let (tx, rx) = mpsc::channel(64);
+        let mut listener = HoppingListener {
+            hopper,
+            ip,
+            tasks: HashMap::new(),
+            tx,
+            rx,
+        };
+        listener.refresh().await;
+        if listener.tasks.is_empty() {
+            return Err(Error::IoError(std::io::Error::new(
+                std::io::ErrorKind::AddrInUse,
+                "No port in the hop set could be bound",
+            )));
+        }
+        Ok(listener)
+    }
+
+    /// Ports currently listened on
+    pub fn ports(&self) -> Vec<u16> {
+        let mut ports: Vec<u16> = self.tasks.keys().copied().collect();
+        ports.sort_unstable();
+        ports
+    }
+
+    /// Bind newly scheduled ports and release stale ones
+    pub async fn refresh(&mut self) {
+        let wanted = self.hopper.listen_ports(self.hopper.current_slot());
+
+        self.tasks.retain(|port, task| {
+            let keep = wanted.contains(port);
+            if !keep {
+                task.abort();
+            }
+            keep
+        });
+
+        for port in wanted {
+            if self.tasks.contains_key(&port) {
+                continue;
+            }
+            match TcpListener::bind(SocketAddr::new(self.ip, port)).await {
+                Ok(listener) => {
+                    let tx = self.tx.clone();
+                    let task = tokio::spawn(async move {
+                        while let Ok(conn) = listener.accept().await {
+                            if tx.send(conn).await.is_err() {
+                                break;
+                            }
+                        }
+                    });
+                    self.tasks.insert(port, task);
+                }
+                Err(e) => log::warn!("Port hop to {} failed: {}", port, e),
+            }
+        }
+    }
+
+    /// Accept the next connection on any scheduled port
+    pub async fn accept(&mut self) -> Result<(TcpStream, SocketAddr)> {
+        loop {
+            let hop = self.hopper.next_hop_in();
+            tokio::select! {
+                conn = self.rx.recv() => {
+                    // The sender half lives in self, so the channel never closes
+                    return conn
+                        .ok_or_else(|| Error::Unknown("Listener channel closed".to_string()));
+                }
+                _ = tokio::time::sleep(hop) => self.refresh().await,
+            }
+        }
+    }
+}
+
+impl Drop for HoppingListener {
+    fn drop(&mut self) {
+        for task in self.tasks.values() {
+            task.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hopper(seed: &[u8]) -> PortHopper {
+        PortHopper::new(PortHopConfig {
+            seed: seed.to_vec(),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_schedule_is_shared_by_seed() {
+        let a = hopper(b"shared seed");
+        let b = hopper(b"shared seed");
+        let other = hopper(b"another seed");
+
+        let schedule: Vec<u16> = (0..32).map(|s| a.port_for_slot(s)).collect();
+        assert_eq!(
+            schedule,
+            (0..32).map(|s| b.port_for_slot(s)).collect::<Vec<_>>()
+        );
+        assert_ne!(
+            schedule,
+            (0..32).map(|s| other.port_for_slot(s)).collect::<Vec<_>>()
+        );
+        assert!(schedule.iter().all(|p| (20000..=40000).contains(p)));
+        // The port actually moves
+        assert!(schedule.windows(2).any(|w| w[0] != w[1]));
+    }
+
+    #[test]
+    fn test_listen_ports_cover_neighbouring_slots() {
+        let hopper = hopper(b"seed");
+        let ports = hopper.listen_ports(100);
+        for slot in 99..=101 {
+            assert!(ports.contains(&hopper.port_for_slot(slot)));
+        }
+        assert!(ports.len() <= 3);
+    }
+
+    #[test]
+    fn test_invalid_config_rejected() {
+        assert!(PortHopper::new(PortHopConfig::default()).is_err());
+        assert!(PortHopper::new(PortHopConfig {
+            seed: b"seed".to_vec(),
+            port_range: (5000, 4000),
+            ..Default::default()
+        })
+        .is_err());
+        assert!(PortHopper::new(PortHopConfig {
+            seed: b"seed".to_vec(),
+            hop_interval: Duration::from_millis(10),
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_listener_accepts_on_current_port() {
+        let hopper = PortHopper::new(PortHopConfig {
+            seed: b"listener test".to_vec(),
+            port_range: (45000, 55000),
+            hop_interval: Duration::from_secs(3600),
+            grace_slots: 0,
+        })
+        .unwrap();
+        let ip: IpAddr = [127, 0, 0, 1].into();
+        let mut listener = HoppingListener::bind(hopper.clone(), ip).await.unwrap();
+        assert_eq!(listener.ports(), vec![hopper.current_port()]);
+
+        let _client = TcpStream::connect(hopper.current_addr(ip)).await.unwrap();
+        let (_stream, peer) = listener.accept().await.unwrap();
+        assert!(peer.ip().is_loopback());
+    }
+}
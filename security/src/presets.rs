@@ -0,0 +1,191 @@
+//! ISP presets
+//! Named settings for the major Iranian operators, tuned to how each one's
+//! filtering is observed to behave
+
+use crate::config::SecuritySettings;
+use serde::{Deserialize, Serialize};
+
+/// A major Iranian access network
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IspPreset {
+    /// Mobile Telecommunication Company of Iran (Hamrah-e Aval)
+    Mci,
+    /// MTN Irancell
+    Irancell,
+    /// Telecommunication Company of Iran, the fixed-line incumbent
+    Tci,
+    Shatel,
+    /// Mobinnet TD-LTE wireless broadband
+    Mobinnet,
+}
+
+/// Filtering behavior of an operator that shapes its preset
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IspQuirks {
+    /// Mobile network, where data volume costs the user
+    pub mobile: bool,
+    /// UDP flows are throttled after a short burst; UDP transports and the
+    /// DNS tunnel are left off
+    pub udp_throttled: bool,
+    /// International TLS only passes with an allowed SNI at times, so
+    /// hiding the name by splitting fails where a fake one gets through
+    pub sni_whitelist: bool,
+    /// Filtered SNIs draw injected resets rather than silent drops, which
+    /// makes desync worth trying first
+    pub rst_injection: bool,
+}
+
+impl IspPreset {
+    pub const ALL: [IspPreset; 5] = [
+        IspPreset::Mci,
+        IspPreset::Irancell,
+        IspPreset::Tci,
+        IspPreset::Shatel,
+        IspPreset::Mobinnet,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            IspPreset::Mci => "mci",
+            IspPreset::Irancell => "irancell",
+            IspPreset::Tci => "tci",
+            IspPreset::Shatel => "shatel",
+            IspPreset::Mobinnet => "mobinnet",
+        }
+    }
+
+    /// Look a preset up by name, ignoring case; common alternative names
+    /// of the operators are accepted too
+    pub fn from_name(name: &str) -> Option<Self> {
+        let name = name
+            .trim()
+            .to_ascii_lowercase()
+            .replace(['-', '_', ' '], "");
+        let preset = match name.as_str() {
+            "mci" | "hamrahaval" | "hamraheaval" => IspPreset::Mci,
+            "irancell" | "mtn" | "mtnirancell" => IspPreset::Irancell,
+            "tci" | "mokhaberat" => IspPreset::Tci,
+            "shatel" => IspPreset::Shatel,
+            "mobinnet" | "mobin" => IspPreset::Mobinnet,
+            _ => return None,
+        };
+        Some(preset)
+    }
+
+    /// Autonomous systems the operator announces its subscribers from
+    pub fn asns(self) -> &'static [u32] {
+        match self {
+            IspPreset::Mci => &[197207],
+            IspPreset::Irancell => &[44244],
+            IspPreset::Tci => &[58224],
+            IspPreset::Shatel => &[31549],
+            IspPreset::Mobinnet => &[50810],
+        }
+    }
+
+    /// The operator whose subscribers an ASN belongs to
+    pub fn from_asn(asn: u32) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|preset| preset.asns().contains(&asn))
+    }
+
+    pub fn quirks(self) -> IspQuirks {
+        match self {
+            IspPreset::Mci => IspQuirks {
+                mobile: true,
+                udp_throttled: true,
+                sni_whitelist: false,
+                rst_injection: true,
+            },
+            IspPreset::Irancell => IspQuirks {
+                mobile: true,
+                udp_throttled: true,
+                sni_whitelist: false,
+                rst_injection: false,
+            },
+            IspPreset::Tci => IspQuirks {
+                mobile: false,
+                udp_throttled: false,
+                sni_whitelist: true,
+                rst_injection: true,
+            },
+            IspPreset::Shatel => IspQuirks {
+                mobile: false,
+                udp_throttled: false,
+                sni_whitelist: false,
+                rst_injection: true,
+            },
+            IspPreset::Mobinnet => IspQuirks {
+                mobile: true,
+                udp_throttled: true,
+                sni_whitelist: false,
+                rst_injection: false,
+            },
+        }
+    }
+
+    /// Adjust settings for the operator's quirks
+    pub fn apply(self, settings: &mut SecuritySettings) {
+        let quirks = self.quirks();
+        let bypass = &mut settings.dpi_bypass;
+        bypass.enabled = true;
+        bypass.tls_evasion_enabled = true;
+        bypass.fragmentation_enabled = !quirks.sni_whitelist;
+        if quirks.udp_throttled {
+            bypass.dns_tunneling_enabled = false;
+        }
+
+        if quirks.mobile {
+            settings.detection_evasion.decoy_traffic_percentage = 10;
+            settings.max_overhead_ratio = Some(0.2);
+        }
+    }
+
+    /// Default settings adjusted for the operator
+    pub fn settings(self) -> SecuritySettings {
+        let mut settings = SecuritySettings::default();
+        self.apply(&mut settings);
+        settings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_names_and_asns() {
+        for preset in IspPreset::ALL {
+            assert_eq!(IspPreset::from_name(preset.name()), Some(preset));
+            assert_eq!(IspPreset::from_asn(preset.asns()[0]), Some(preset));
+        }
+        assert_eq!(
+            IspPreset::from_name("MTN-Irancell"),
+            Some(IspPreset::Irancell)
+        );
+        assert_eq!(IspPreset::from_name("Hamrah-e Aval"), Some(IspPreset::Mci));
+        assert_eq!(IspPreset::from_name("comcast"), None);
+        assert_eq!(IspPreset::from_asn(13335), None);
+    }
+
+    #[test]
+    fn test_presets_follow_quirks() {
+        for preset in IspPreset::ALL {
+            let settings = SecuritySettings::preset(preset.name()).unwrap();
+            assert!(settings.validate().is_ok(), "{:?}", preset);
+            let quirks = preset.quirks();
+            assert_eq!(
+                settings.dpi_bypass.fragmentation_enabled,
+                !quirks.sni_whitelist
+            );
+            assert_eq!(settings.max_overhead_ratio.is_some(), quirks.mobile);
+        }
+
+        let tci = IspPreset::Tci.settings();
+        assert!(!tci.dpi_bypass.fragmentation_enabled);
+        assert!(tci.dpi_bypass.tls_evasion_enabled);
+        assert!(SecuritySettings::preset("unknown").is_err());
+    }
+}
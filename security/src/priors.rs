@@ -0,0 +1,449 @@
+//! Strategy priors from censorship measurements
+//! Imports published measurement summaries (OONI, Censored Planet) and turns
+//! the blocking mechanisms seen on each ASN into starting scores for the
+//! strategy selector, so a first run on a known network skips blind probing
+
+use crate::bandit::{ArmStats, StrategyArm, StrategySelector};
+use crate::diagnosis::Mechanism;
+use crate::error::{Error, Result};
+use crate::sni_fallback::SniStrategy;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+/// Pseudo-trials given to each arm by default: enough to order the first
+/// picks, few enough that a handful of real outcomes override them
+pub const DEFAULT_PRIOR_WEIGHT: u64 = 8;
+
+/// Chance a connection that the measurements saw unblocked gets through
+const UNBLOCKED_SUCCESS: f64 = 0.95;
+
+/// Chance an arm gets through where no arm makes a difference, e.g. DNS
+/// poisoning, which the resolver rather than the arm has to answer
+const NEUTRAL_SUCCESS: f64 = 0.5;
+
+const ASN_COLUMNS: &[&str] = &["probe_asn", "asn"];
+const OUTCOME_COLUMNS: &[&str] = &["blocking", "blocking_type", "outcome", "failure", "result"];
+const COUNT_COLUMNS: &[&str] = &["count", "measurement_count", "measurements"];
+
+/// Measurements of one network, by what happened to them
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MechanismCounts {
+    pub unblocked: u64,
+    pub blocked: HashMap<Mechanism, u64>,
+}
+
+impl MechanismCounts {
+    pub fn total(&self) -> u64 {
+        self.unblocked + self.blocked.values().sum::<u64>()
+    }
+
+    /// Share of measurements blocked by a mechanism
+    pub fn share(&self, mechanism: Mechanism) -> f64 {
+        match self.total() {
+            0 => 0.0,
+            total => self.blocked.get(&mechanism).copied().unwrap_or(0) as f64 / total as f64,
+        }
+    }
+
+    /// The most frequent blocking mechanism
+    pub fn dominant(&self) -> Option<Mechanism> {
+        self.blocked
+            .iter()
+            .filter(|(_, count)| **count > 0)
+            .max_by_key(|(_, count)| **count)
+            .map(|(mechanism, _)| *mechanism)
+    }
+
+    /// Estimated chance an arm gets through, weighing how well it counters
+    /// each mechanism by that mechanism's share
+    pub fn success_estimate(&self, arm: &StrategyArm) -> f64 {
+        let total = self.total();
+        if total == 0 {
+            return NEUTRAL_SUCCESS;
+        }
+        let blocked: f64 = self
+            .blocked
+            .iter()
+            .map(|(mechanism, count)| *count as f64 * effectiveness(arm, *mechanism))
+            .sum();
+        (self.unblocked as f64 * UNBLOCKED_SUCCESS + blocked) / total as f64
+    }
+}
+
+/// Blocking mechanisms per network, keyed like the strategy selector
+/// ("AS197207")
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CensorshipPriors {
+    networks: BTreeMap<String, MechanismCounts>,
+}
+
+impl CensorshipPriors {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parse a measurement summary, as JSON or CSV
+    ///
+    /// Each record needs an ASN (`probe_asn` or `asn`, as `AS197207` or a
+    /// number) and an outcome (`blocking`, `blocking_type`, `outcome`,
+    /// `failure` or `result`), with an optional `count` or
+    /// `measurement_count`. JSON is an array of records or an object with
+    /// a `result`/`results` array, as the OONI API returns; CSV has a
+    /// header row. Outcomes are OONI blocking types and failure strings or
+    /// Censored Planet outcomes; see [`classify_outcome`].
+    pub fn parse(text: &str) -> Result<Self> {
+        let trimmed = text.trim_start();
+        if trimmed.starts_with('[') || trimmed.starts_with('{') {
+            Self::parse_json(trimmed)
+        } else {
+            Self::parse_csv(text)
+        }
+    }
+
+    /// Read a summary file in a format `parse` accepts
+    pub fn load(path: &Path) -> Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    fn parse_json(text: &str) -> Result<Self> {
+        let value: Value = serde_json::from_str(text)
+            .map_err(|e| Error::DataError(format!("Invalid measurement summary: {}", e)))?;
+        let records = match &value {
+            Value::Array(records) => records,
+            Value::Object(object) => object
+                .get("result")
+                .or_else(|| object.get("results"))
+                .and_then(Value::as_array)
+                .ok_or_else(|| {
+                    Error::DataError("Measurement summary has no result array".to_string())
+                })?,
+            _ => return Err(Error::DataError("Invalid measurement summary".to_string())),
+        };
+
+        let mut priors = Self::new();
+        for (index, record) in records.iter().enumerate() {
+            let field = |names: &[&str]| names.iter().find_map(|name| record.get(*name));
+            let invalid = || Error::DataError(format!("Invalid measurement record {}", index));
+            let asn = field(ASN_COLUMNS)
+                .and_then(|asn| match asn {
+                    Value::Number(asn) => asn.as_u64().map(|asn| asn.to_string()),
+                    Value::String(asn) => Some(asn.clone()),
+                    _ => None,
+                })
+                .ok_or_else(invalid)?;
+            let outcome = match field(OUTCOME_COLUMNS) {
+                None | Some(Value::Null) | Some(Value::Bool(false)) => String::new(),
+                Some(Value::String(outcome)) => outcome.clone(),
+                Some(other) => other.to_string(),
+            };
+            let count = match field(COUNT_COLUMNS) {
+                Some(count) => count.as_u64().ok_or_else(invalid)?,
+                None => 1,
+            };
+            priors.record(&asn, classify_outcome(&outcome), count)?;
+        }
+        Ok(priors)
+    }
+
+    fn parse_csv(text: &str) -> Result<Self> {
+        let mut lines = text
+            .lines()
+            .map(|line| line.trim_end_matches('\r'))
+            .filter(|line| !line.trim().is_empty());
+        let header = split_csv(lines.next().unwrap_or(""));
+        let column = |names: &[&str]| {
+            header
+                .iter()
+                .position(|column| names.contains(&column.to_ascii_lowercase().as_str()))
+        };
+        let missing =
+            |what| Error::DataError(format!("Measurement summary has no {} column", what));
+        let asn_column = column(ASN_COLUMNS).ok_or_else(|| missing("ASN"))?;
+        let outcome_column = column(OUTCOME_COLUMNS).ok_or_else(|| missing("outcome"))?;
+        let count_column = column(COUNT_COLUMNS);
+
+        let mut priors = Self::new();
+        for (number, line) in lines.enumerate() {
+            let fields = split_csv(line);
+            let invalid = || Error::DataError(format!("Invalid measurement row {}", number + 2));
+            let asn = fields.get(asn_column).ok_or_else(invalid)?;
+            let outcome = fields.get(outcome_column).map_or("", String::as_str);
+            let count = match count_column.and_then(|column| fields.get(column)) {
+                Some(count) => count.parse().map_err(|_| invalid())?,
+                None => 1,
+            };
+            priors.record(asn, classify_outcome(outcome), count)?;
+        }
+        Ok(priors)
+    }
+
+    /// Count measurements on a network; `None` is an unblocked outcome
+    pub fn record(&mut self, asn: &str, mechanism: Option<Mechanism>, count: u64) -> Result<()> {
+        let network = network_key(asn)
+            .ok_or_else(|| Error::DataError(format!("Invalid ASN in measurements: {:?}", asn)))?;
+        let counts = self.networks.entry(network).or_default();
+        match mechanism {
+            Some(mechanism) => *counts.blocked.entry(mechanism).or_default() += count,
+            None => counts.unblocked += count,
+        }
+        Ok(())
+    }
+
+    /// Merge another summary into this one
+    pub fn extend(&mut self, other: CensorshipPriors) {
+        for (network, counts) in other.networks {
+            let merged = self.networks.entry(network).or_default();
+            merged.unblocked += counts.unblocked;
+            for (mechanism, count) in counts.blocked {
+                *merged.blocked.entry(mechanism).or_default() += count;
+            }
+        }
+    }
+
+    pub fn networks(&self) -> impl Iterator<Item = (&str, &MechanismCounts)> {
+        self.networks
+            .iter()
+            .map(|(network, counts)| (network.as_str(), counts))
+    }
+
+    pub fn network(&self, network: &str) -> Option<&MechanismCounts> {
+        self.networks.get(&network_key(network)?)
+    }
+
+    /// Pseudo-counts for arms on a network, `weight` trials each split by
+    /// the arm's estimated success; `None` for networks without data
+    pub fn arm_priors(
+        &self,
+        network: &str,
+        arms: &[StrategyArm],
+        weight: u64,
+    ) -> Option<Vec<(StrategyArm, ArmStats)>> {
+        let counts = self.network(network)?;
+        let priors = arms
+            .iter()
+            .map(|arm| {
+                let successes = (counts.success_estimate(arm) * weight as f64).round() as u64;
+                let stats = ArmStats {
+                    successes: successes.min(weight),
+                    failures: weight - successes.min(weight),
+                };
+                (*arm, stats)
+            })
+            .collect();
+        Some(priors)
+    }
+
+    /// Seed a selector on every network with data, returning how many
+    pub fn seed(&self, selector: &StrategySelector, weight: u64) -> usize {
+        if weight == 0 {
+            return 0;
+        }
+        let mut seeded = 0;
+        for network in self.networks.keys() {
+            if let Some(priors) = self.arm_priors(network, selector.arms(), weight) {
+                selector.seed(network, priors);
+                seeded += 1;
+            }
+        }
+        seeded
+    }
+}
+
+/// Map a measurement outcome to the mechanism behind it; `None` for
+/// unblocked outcomes
+///
+/// Understands OONI blocking types (`dns`, `tcp_ip`, `http-failure`,
+/// `http-diff`), OONI failure strings (`connection_reset`,
+/// `generic_timeout_error`, ...) and Censored Planet outcomes
+/// (`read/tcp.reset`, `dial/timeout`, `expected/match`, ...). Anything
+/// else blocked counts as inconclusive.
+pub fn classify_outcome(outcome: &str) -> Option<Mechanism> {
+    let outcome = outcome.trim().to_ascii_lowercase();
+    let unblocked = ["", "false", "ok", "none", "null", "accessible"];
+    if unblocked.contains(&outcome.as_str()) || outcome.starts_with("expected") {
+        return None;
+    }
+    let mechanism = if outcome.contains("dns") || outcome.contains("nxdomain") {
+        Mechanism::DnsPoisoning
+    } else if outcome.contains("reset") || outcome.contains("refused") {
+        Mechanism::RstInjection
+    } else if outcome.contains("dial") || outcome.contains("tcp_ip") || outcome.contains("connect")
+    {
+        Mechanism::IpBlackhole
+    } else if ["timeout", "tls", "ssl", "sni", "eof", "http-failure"]
+        .iter()
+        .any(|marker| outcome.contains(marker))
+    {
+        Mechanism::SniFilter
+    } else {
+        Mechanism::Inconclusive
+    };
+    Some(mechanism)
+}
+
+/// How likely an arm gets through a mechanism
+///
+/// A coarse model of what each technique is known to defeat, only meant to
+/// order the first attempts: hiding the name beats SNI filtering, desync
+/// beats reset injection, and nothing in an arm helps against DNS
+/// poisoning or a blackholed address.
+fn effectiveness(arm: &StrategyArm, mechanism: Mechanism) -> f64 {
+    let hides_name = match arm.sni {
+        SniStrategy::Ech | SniStrategy::DomainFronting => 0.8,
+        SniStrategy::FakeSni => 0.7,
+        SniStrategy::SniSplit => 0.4,
+    };
+    let fragments = arm.fragmentation.is_some();
+    match mechanism {
+        Mechanism::SniFilter => {
+            let bonus = if fragments { 0.1 } else { 0.0 };
+            f64::min(hides_name + bonus, 0.9)
+        }
+        Mechanism::RstInjection => {
+            let desync: f64 = if arm.desync.is_some() { 0.75 } else { 0.1 };
+            let fragmentation = if fragments { 0.4 } else { 0.1 };
+            desync.max(fragmentation).max(hides_name * 0.75)
+        }
+        Mechanism::DnsPoisoning | Mechanism::IpBlackhole | Mechanism::Inconclusive => {
+            NEUTRAL_SUCCESS
+        }
+    }
+}
+
+/// Normalize "AS197207", "as197207" or "197207" to "AS197207"
+fn network_key(asn: &str) -> Option<String> {
+    let asn = asn.trim();
+    let digits = asn
+        .strip_prefix("AS")
+        .or_else(|| asn.strip_prefix("as"))
+        .unwrap_or(asn);
+    let asn: u32 = digits.parse().ok()?;
+    Some(format!("AS{}", asn))
+}
+
+/// Split a CSV line, honoring double-quoted fields
+fn split_csv(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field).trim().to_string()),
+            c => field.push(c),
+        }
+    }
+    fields.push(field.trim().to_string());
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bandit::SelectionPolicy;
+    use crate::desync::DesyncStrategy;
+    use crate::tls_fragmentation::FragmentationStrategy;
+
+    fn arms() -> Vec<StrategyArm> {
+        StrategyArm::combinations(
+            &[SniStrategy::SniSplit, SniStrategy::FakeSni],
+            &[None, Some(FragmentationStrategy::RecordSplit)],
+            &[None, Some(DesyncStrategy::FakeLowTtl)],
+        )
+    }
+
+    #[test]
+    fn test_classify_outcomes() {
+        assert_eq!(classify_outcome("expected/match"), None);
+        assert_eq!(classify_outcome(""), None);
+        assert_eq!(classify_outcome("dns"), Some(Mechanism::DnsPoisoning));
+        assert_eq!(
+            classify_outcome("read/tcp.reset"),
+            Some(Mechanism::RstInjection)
+        );
+        assert_eq!(
+            classify_outcome("connection_reset"),
+            Some(Mechanism::RstInjection)
+        );
+        assert_eq!(
+            classify_outcome("dial/timeout"),
+            Some(Mechanism::IpBlackhole)
+        );
+        assert_eq!(classify_outcome("tcp_ip"), Some(Mechanism::IpBlackhole));
+        assert_eq!(
+            classify_outcome("generic_timeout_error"),
+            Some(Mechanism::SniFilter)
+        );
+        assert_eq!(
+            classify_outcome("content/blockpage"),
+            Some(Mechanism::Inconclusive)
+        );
+    }
+
+    #[test]
+    fn test_parse_ooni_json_and_csv() {
+        let json = r#"{"result": [
+            {"probe_asn": "AS197207", "blocking": "tcp_ip", "measurement_count": 3},
+            {"probe_asn": 197207, "blocking": false, "measurement_count": 1},
+            {"probe_asn": "AS44244", "failure": "connection_reset"}
+        ]}"#;
+        let priors = CensorshipPriors::parse(json).unwrap();
+        let mci = priors.network("197207").unwrap();
+        assert_eq!(mci.total(), 4);
+        assert_eq!(mci.dominant(), Some(Mechanism::IpBlackhole));
+        assert_eq!(
+            priors
+                .network("AS44244")
+                .unwrap()
+                .share(Mechanism::RstInjection),
+            1.0
+        );
+
+        let csv = "date,asn,outcome,count\n\
+                   2026-10-01,AS58224,\"read/tcp.reset\",5\n\
+                   2026-10-01,AS58224,expected/match,5\n";
+        let mut merged = CensorshipPriors::parse(csv).unwrap();
+        merged.extend(priors);
+        assert_eq!(merged.networks().count(), 3);
+        assert_eq!(merged.network("AS58224").unwrap().unblocked, 5);
+
+        assert!(CensorshipPriors::parse("asn,count\nAS1,2\n").is_err());
+        assert!(CensorshipPriors::parse("asn,outcome\nnot-an-asn,dns\n").is_err());
+    }
+
+    #[test]
+    fn test_seeded_selector_starts_from_known_good() {
+        let arms = arms();
+        let mut priors = CensorshipPriors::new();
+        priors
+            .record("AS197207", Some(Mechanism::RstInjection), 90)
+            .unwrap();
+        priors.record("AS197207", None, 10).unwrap();
+        priors
+            .record("AS58224", Some(Mechanism::SniFilter), 50)
+            .unwrap();
+
+        let selector = StrategySelector::new(arms.clone(), SelectionPolicy::default()).unwrap();
+        selector.record("AS58224", arms[0], true);
+        assert_eq!(priors.seed(&selector, DEFAULT_PRIOR_WEIGHT), 2);
+
+        // Reset injection: the first pick desyncs
+        assert!(selector.select("AS197207").desync.is_some());
+        assert!(selector.best("AS197207").unwrap().desync.is_some());
+        // SNI filtering: a fake name beats a split one
+        let best = selector.best("AS58224").unwrap();
+        assert_eq!(best, arms[0]);
+        let stats = selector.stats("AS58224");
+        assert_eq!(stats[0].1.trials(), 1);
+        assert!(stats[4].1.success_rate() > stats[1].1.success_rate());
+
+        // Networks without data still explore from scratch
+        assert_eq!(selector.best("AS44244"), None);
+    }
+}
@@ -0,0 +1,192 @@
+//! Privacy mode and data audit
+//! A process-wide switch that stops the crate persisting anything or logging
+//! destinations, and a machine-readable statement of what it has stored
+//! where, for users whose devices may be inspected
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Held by tests that switch privacy mode or need it off, so they run one
+/// at a time
+#[cfg(test)]
+pub(crate) static TEST_LOCK: parking_lot::Mutex<()> = parking_lot::const_mutex(());
+
+static STORES: parking_lot::Mutex<Vec<DataStore>> = parking_lot::const_mutex(Vec::new());
+
+/// Kind of data the crate can retain
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DataCategory {
+    /// Which evasion strategies worked on which access networks
+    StrategyScoreboard,
+    /// Captured outgoing traffic
+    PacketCapture,
+    /// Log lines naming destinations, SNIs or peer addresses
+    DestinationLogs,
+}
+
+/// Where a category is kept
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Location {
+    File {
+        path: PathBuf,
+    },
+    /// Whatever the application's log backend does with log records
+    Log,
+}
+
+/// One place holding one category of data
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DataStore {
+    pub category: DataCategory,
+    pub location: Location,
+}
+
+/// What the crate currently retains
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DataStatement {
+    pub privacy_mode: bool,
+    /// Stores written so far, including ones written before privacy mode
+    /// was switched on, and the logs while destinations are logged
+    pub stores: Vec<DataStore>,
+}
+
+impl DataStatement {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("statement serializes")
+    }
+}
+
+/// Switch privacy mode on or off
+///
+/// While on, scoreboards are not saved, PCAP exports are not opened and
+/// destinations are redacted from log lines. Data already written stays
+/// where it is and remains listed in the statement.
+///
+/// Set through `ProcessorSettings::privacy_mode`, the worker's `--privacy`
+/// flag or `security_set_privacy_mode`.
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Note that `category` has been written to `location`
+pub fn record_store(category: DataCategory, location: Location) {
+    let store = DataStore { category, location };
+    let mut stores = STORES.lock();
+    if !stores.contains(&store) {
+        stores.push(store);
+    }
+}
+
+/// Note that a store was deleted
+pub fn forget_store(category: DataCategory, location: &Location) {
+    STORES
+        .lock()
+        .retain(|store| store.category != category || store.location != *location);
+}
+
+/// Statement of the data categories currently stored and where
+pub fn statement() -> DataStatement {
+    let privacy_mode = is_enabled();
+    let mut stores = STORES.lock().clone();
+    if !privacy_mode {
+        stores.push(DataStore {
+            category: DataCategory::DestinationLogs,
+            location: Location::Log,
+        });
+    }
+    DataStatement {
+        privacy_mode,
+        stores,
+    }
+}
+
+/// A value for a log line, shown as `[redacted]` in privacy mode
+pub struct Redacted<T>(pub T);
+
+/// Wrap a destination, SNI or address for logging
+pub fn redact<T>(value: T) -> Redacted<T> {
+    Redacted(value)
+}
+
+impl<T: fmt::Display> fmt::Display for Redacted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if is_enabled() {
+            f.write_str("[redacted]")
+        } else {
+            self.0.fmt(f)
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Redacted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if is_enabled() {
+            f.write_str("[redacted]")
+        } else {
+            self.0.fmt(f)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redaction() {
+        let _privacy = TEST_LOCK.lock();
+        assert_eq!(format!("{}", redact("youtube.com")), "youtube.com");
+        set_enabled(true);
+        assert_eq!(format!("{}", redact("youtube.com")), "[redacted]");
+        let addrs: Vec<std::net::IpAddr> = vec!["10.10.34.36".parse().unwrap()];
+        assert_eq!(format!("{:?}", redact(&addrs)), "[redacted]");
+        set_enabled(false);
+    }
+
+    #[test]
+    fn test_statement() {
+        let _privacy = TEST_LOCK.lock();
+        let path = PathBuf::from("/var/lib/example/scores.json");
+        let location = Location::File { path: path.clone() };
+        record_store(DataCategory::StrategyScoreboard, location.clone());
+        record_store(DataCategory::StrategyScoreboard, location.clone());
+
+        let statement = statement();
+        assert!(!statement.privacy_mode);
+        let scoreboards = statement
+            .stores
+            .iter()
+            .filter(|store| store.location == location)
+            .count();
+        assert_eq!(scoreboards, 1);
+        assert!(statement
+            .stores
+            .iter()
+            .any(|store| store.category == DataCategory::DestinationLogs));
+        let json: serde_json::Value = serde_json::from_str(&statement.to_json()).unwrap();
+        assert_eq!(json["privacy_mode"], false);
+
+        set_enabled(true);
+        assert!(!super::statement()
+            .stores
+            .iter()
+            .any(|store| store.category == DataCategory::DestinationLogs));
+        set_enabled(false);
+
+        forget_store(DataCategory::StrategyScoreboard, &location);
+        assert!(!super::statement()
+            .stores
+            .iter()
+            .any(|store| store.location == location));
+    }
+}
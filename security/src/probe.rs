@@ -0,0 +1,587 @@
+//! Network probes
+//! Measures where filtering happens on the path, e.g. how many hops away the
+//! DPI box sits, and which evasion techniques survive on the current network,
+//! so desync and the SNI strategies can be configured automatically
+
+use crate::client_hello::{ClientHelloBuilder, TLSProfileConfig};
+use crate::config::SecuritySettings;
+use crate::desync::Desync;
+use crate::error::{Error, Result};
+use crate::resolver::{self, DohEndpoint, Resolver, ResolverConfig};
+use crate::sni_fallback::SniStrategy;
+use crate::tls_fragmentation::{
+    FragmentationStrategy, FragmentedPacket, TLSFragmentationConfig, TLSFragmenter,
+};
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::task::JoinSet;
+
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+
+/// Configuration for the DPI traceroute
+#[derive(Clone, Debug)]
+pub struct TracerouteConfig {
+    /// Highest TTL probed
+    pub max_hops: u8,
+    /// How long to wait for a reset or reply at each TTL
+    pub timeout: Duration,
+    /// Profile of the probe ClientHello
+    pub profile: TLSProfileConfig,
+}
+
+impl Default for TracerouteConfig {
+    fn default() -> Self {
+        TracerouteConfig {
+            max_hops: 30,
+            timeout: Duration::from_secs(2),
+            profile: TLSProfileConfig::default(),
+        }
+    }
+}
+
+/// What happened to a ClientHello sent with a limited TTL
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HopOutcome {
+    /// Nothing came back: the packet expired before anything acted on it
+    Silent,
+    /// The connection was reset or closed: something on the path saw the SNI
+    Interfered,
+    /// A TLS handshake reply arrived: the packet reached the server
+    Reached,
+}
+
+/// Result of a DPI traceroute
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DpiTrace {
+    /// Smallest TTL at which the connection got reset before reaching the server
+    pub dpi_hops: Option<u8>,
+    /// Smallest TTL at which the server answered
+    pub server_hops: Option<u8>,
+    /// Outcome per TTL, starting at 1
+    pub hops: Vec<HopOutcome>,
+}
+
+/// Send one ClientHello for `sni` with the IP TTL limited to `ttl`
+///
+/// The TCP handshake uses the normal TTL; only the ClientHello is limited.
+/// A reset or close means a device within `ttl` hops reacted to the SNI, since
+/// the server itself would answer with a handshake record.
+pub async fn probe_hop(
+    target: SocketAddr,
+    sni: &str,
+    ttl: u8,
+    config: &TracerouteConfig,
+) -> Result<HopOutcome> {
+    let mut stream = TcpStream::connect(target).await?;
+    stream.set_nodelay(true)?;
+    stream.set_ttl(ttl as u32)?;
+
+    let hello = ClientHelloBuilder::from_profile(&config.profile)
+        .server_name(sni)
+        .build();
+    stream.write_all(&hello).await?;
+    read_reply(&mut stream, config.timeout).await
+}
+
+/// Judge the first byte the far end sends back after a ClientHello
+async fn read_reply(stream: &mut TcpStream, timeout: Duration) -> Result<HopOutcome> {
+    let mut byte = [0u8; 1];
+    let outcome = match tokio::time::timeout(timeout, stream.read(&mut byte)).await {
+        Err(_) => HopOutcome::Silent,
+        Ok(Ok(0)) => HopOutcome::Interfered,
+        Ok(Ok(_)) if byte[0] == CONTENT_TYPE_HANDSHAKE => HopOutcome::Reached,
+        // Block pages and alerts injected in place of the server's reply
+        Ok(Ok(_)) => HopOutcome::Interfered,
+        Ok(Err(e)) if e.kind() == ErrorKind::ConnectionReset => HopOutcome::Interfered,
+        Ok(Err(e)) => return Err(e.into()),
+    };
+    Ok(outcome)
+}
+
+/// Measure the hop distance to the device filtering `sni` towards `target`
+///
+/// Probes every TTL up to `max_hops` in parallel with a ClientHello naming a
+/// blocked host. The lowest TTL that draws a reset without the server having
+/// been reached is where the DPI sits.
+pub async fn traceroute_dpi(
+    target: SocketAddr,
+    sni: &str,
+    config: &TracerouteConfig,
+) -> Result<DpiTrace> {
+    if config.max_hops == 0 {
+        return Err(Error::ConfigError(
+            "max_hops must be at least 1".to_string(),
+        ));
+    }
+
+    let mut probes = JoinSet::new();
+    for ttl in 1..=config.max_hops {
+        let sni = sni.to_string();
+        let config = config.clone();
+        probes.spawn(async move { (ttl, probe_hop(target, &sni, ttl, &config).await) });
+    }
+
+    let mut hops = vec![HopOutcome::Silent; config.max_hops as usize];
+    let mut failures = 0;
+    while let Some(joined) = probes.join_next().await {
+        let (ttl, outcome) = joined.map_err(|e| Error::Unknown(e.to_string()))?;
+        match outcome {
+            Ok(outcome) => hops[ttl as usize - 1] = outcome,
+            Err(_) => failures += 1,
+        }
+    }
+    if failures == config.max_hops as usize {
+        return Err(Error::DetectionEvadingError(format!(
+            "Could not connect to {} for the DPI traceroute",
+            target
+        )));
+    }
+
+    let first = |wanted: HopOutcome| {
+        hops.iter()
+            .position(|&outcome| outcome == wanted)
+            .map(|i| i as u8 + 1)
+    };
+    let server_hops = first(HopOutcome::Reached);
+    let dpi_hops =
+        first(HopOutcome::Interfered).filter(|&dpi| server_hops.is_none_or(|server| dpi < server));
+
+    Ok(DpiTrace {
+        dpi_hops,
+        server_hops,
+        hops,
+    })
+}
+
+/// Run the DPI traceroute and feed the measured distance into a desync engine
+pub async fn calibrate_desync(
+    desync: &mut Desync,
+    target: SocketAddr,
+    sni: &str,
+    config: &TracerouteConfig,
+) -> Result<DpiTrace> {
+    let trace = traceroute_dpi(target, sni, config).await?;
+    if let Some(hops) = trace.dpi_hops {
+        desync.set_dpi_hops(hops);
+    }
+    Ok(trace)
+}
+
+/// Technique exercised by a canary connection of the probe suite
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Technique {
+    /// ClientHello naming the blocked host in one segment
+    PlainSni,
+    /// The same ClientHello cut inside the hostname
+    SplitSni,
+    /// ClientHello naming a harmless host
+    FakeSni,
+    /// ClientHello carrying an ECH extension behind the ECH public name
+    Ech,
+    /// DNS over HTTPS query
+    Doh,
+    /// QUIC handshake
+    Quic,
+}
+
+/// What happened to a canary connection
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CanaryOutcome {
+    /// The reference server answered
+    Passed,
+    /// Reset, closed or answered by something other than the server
+    Interfered,
+    /// Nothing came back in time
+    TimedOut,
+    /// The connection failed before the technique could be tried
+    Unreachable(String),
+    /// No reference server configured for the technique
+    Skipped,
+}
+
+/// Reference servers and names for the probe suite
+#[derive(Clone, Debug)]
+pub struct SuiteConfig {
+    /// TLS server answering ClientHellos for every name below
+    pub tls_target: SocketAddr,
+    /// A name the network filters
+    pub blocked_sni: String,
+    /// A name the network lets through
+    pub fake_sni: String,
+    /// Outer name presented with ECH
+    pub ech_public_name: String,
+    /// DoH endpoint queried for `doh_name`; `None` skips the DoH canary
+    pub doh: Option<DohEndpoint>,
+    pub doh_name: String,
+    /// QUIC server and the name to present to it; `None` skips the QUIC
+    /// canary
+    pub quic: Option<(SocketAddr, String)>,
+    /// How long each canary waits for an answer
+    pub timeout: Duration,
+    pub profile: TLSProfileConfig,
+}
+
+impl SuiteConfig {
+    pub fn new(tls_target: SocketAddr) -> Self {
+        SuiteConfig {
+            tls_target,
+            blocked_sni: "www.youtube.com".to_string(),
+            fake_sni: "www.aparat.com".to_string(),
+            ech_public_name: "cloudflare-ech.com".to_string(),
+            doh: Some(
+                DohEndpoint::new("https://cloudflare-dns.com/dns-query")
+                    .with_address(SocketAddr::from(([1, 1, 1, 1], 443))),
+            ),
+            doh_name: "example.com".to_string(),
+            quic: None,
+            timeout: Duration::from_secs(5),
+            profile: TLSProfileConfig::default(),
+        }
+    }
+}
+
+/// Which techniques survive on the current network
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CapabilityReport {
+    pub results: Vec<(Technique, CanaryOutcome)>,
+}
+
+impl CapabilityReport {
+    pub fn outcome(&self, technique: Technique) -> Option<&CanaryOutcome> {
+        self.results
+            .iter()
+            .find(|(t, _)| *t == technique)
+            .map(|(_, outcome)| outcome)
+    }
+
+    pub fn passed(&self, technique: Technique) -> bool {
+        self.outcome(technique) == Some(&CanaryOutcome::Passed)
+    }
+
+    /// SNI strategies for `SniFallbackConfig::order`: those whose canary
+    /// passed first, untested domain fronting last
+    pub fn sni_order(&self) -> Vec<SniStrategy> {
+        let mut order: Vec<SniStrategy> = [
+            (Technique::Ech, SniStrategy::Ech),
+            (Technique::FakeSni, SniStrategy::FakeSni),
+            (Technique::SplitSni, SniStrategy::SniSplit),
+        ]
+        .into_iter()
+        .filter(|(technique, _)| self.passed(*technique))
+        .map(|(_, strategy)| strategy)
+        .collect();
+        order.push(SniStrategy::DomainFronting);
+        order
+    }
+
+    /// Turn on the evasions the network calls for
+    ///
+    /// Nothing is turned off: a passing plain SNI only says the probed name
+    /// isn't filtered, not that no other one is.
+    pub fn apply(&self, settings: &mut SecuritySettings) {
+        if self.passed(Technique::PlainSni) {
+            return;
+        }
+        let bypass = &mut settings.dpi_bypass;
+        if self.passed(Technique::SplitSni) {
+            bypass.enabled = true;
+            bypass.fragmentation_enabled = true;
+        }
+        if self.passed(Technique::FakeSni) {
+            bypass.enabled = true;
+            bypass.tls_evasion_enabled = true;
+        }
+    }
+}
+
+/// Send canary connections exercising each technique and report which
+/// got through
+///
+/// Canaries run concurrently; the TLS ones all go to `tls_target`, so a
+/// failing `FakeSni` means the server itself is unreachable and the other
+/// TLS results say nothing either.
+pub async fn run_suite(config: &SuiteConfig) -> Result<CapabilityReport> {
+    let hello = |sni: &str, ech: bool| {
+        let profile = TLSProfileConfig {
+            ech_grease: ech,
+            ..config.profile.clone()
+        };
+        ClientHelloBuilder::from_profile(&profile)
+            .server_name(sni)
+            .build()
+    };
+    let single = |data: Vec<u8>| vec![FragmentedPacket { data, delay_ms: 0 }];
+
+    let plain = hello(&config.blocked_sni, false);
+    let split = TLSFragmenter::with_config(TLSFragmentationConfig {
+        strategy: FragmentationStrategy::SniSplit,
+        ..Default::default()
+    })
+    .fragment_client_hello(&plain)
+    .map_err(Error::DPIBypassError)?;
+
+    let (plain, split, fake, ech, doh, quic) = tokio::join!(
+        tls_canary(config.tls_target, single(plain.clone()), config.timeout),
+        tls_canary(config.tls_target, split, config.timeout),
+        tls_canary(
+            config.tls_target,
+            single(hello(&config.fake_sni, false)),
+            config.timeout
+        ),
+        tls_canary(
+            config.tls_target,
+            single(hello(&config.ech_public_name, true)),
+            config.timeout
+        ),
+        doh_canary(config),
+        quic_canary(config),
+    );
+    Ok(CapabilityReport {
+        results: vec![
+            (Technique::PlainSni, plain),
+            (Technique::SplitSni, split),
+            (Technique::FakeSni, fake),
+            (Technique::Ech, ech),
+            (Technique::Doh, doh),
+            (Technique::Quic, quic),
+        ],
+    })
+}
+
+async fn tls_canary(
+    target: SocketAddr,
+    packets: Vec<FragmentedPacket>,
+    timeout: Duration,
+) -> CanaryOutcome {
+    let attempt = async {
+        let mut stream = tokio::time::timeout(timeout, TcpStream::connect(target))
+            .await
+            .map_err(|_| Error::IoError(ErrorKind::TimedOut.into()))??;
+        stream.set_nodelay(true)?;
+        for packet in packets {
+            match stream.write_all(&packet.data).await {
+                Err(e)
+                    if matches!(e.kind(), ErrorKind::ConnectionReset | ErrorKind::BrokenPipe) =>
+                {
+                    return Ok(HopOutcome::Interfered)
+                }
+                result => result?,
+            }
+            if packet.delay_ms > 0 {
+                tokio::time::sleep(Duration::from_millis(packet.delay_ms as u64)).await;
+            }
+        }
+        read_reply(&mut stream, timeout).await
+    };
+    match attempt.await {
+        Ok(HopOutcome::Reached) => CanaryOutcome::Passed,
+        Ok(HopOutcome::Interfered) => CanaryOutcome::Interfered,
+        Ok(HopOutcome::Silent) => CanaryOutcome::TimedOut,
+        Err(e) => CanaryOutcome::Unreachable(e.to_string()),
+    }
+}
+
+async fn doh_canary(config: &SuiteConfig) -> CanaryOutcome {
+    let Some(endpoint) = &config.doh else {
+        return CanaryOutcome::Skipped;
+    };
+    let resolver = match Resolver::new(ResolverConfig {
+        endpoints: vec![endpoint.clone().into()],
+        timeout: config.timeout,
+        ..Default::default()
+    }) {
+        Ok(resolver) => resolver,
+        Err(e) => return CanaryOutcome::Unreachable(e.to_string()),
+    };
+    match resolver.query(&config.doh_name, resolver::TYPE_A).await {
+        Ok(_) => CanaryOutcome::Passed,
+        Err(Error::IoError(e)) if e.kind() == ErrorKind::TimedOut => CanaryOutcome::TimedOut,
+        Err(Error::IoError(e)) if e.kind() == ErrorKind::ConnectionReset => {
+            CanaryOutcome::Interfered
+        }
+        Err(e) => CanaryOutcome::Unreachable(e.to_string()),
+    }
+}
+
+async fn quic_canary(config: &SuiteConfig) -> CanaryOutcome {
+    use quinn::ConnectionError;
+
+    let Some((address, server_name)) = &config.quic else {
+        return CanaryOutcome::Skipped;
+    };
+    let attempt = async {
+        let local = if address.is_ipv6() {
+            SocketAddr::from(([0u16; 8], 0))
+        } else {
+            SocketAddr::from(([0u8; 4], 0))
+        };
+        let mut endpoint = quinn::Endpoint::client(local)?;
+        endpoint.set_default_client_config(resolver::quic_client_config()?);
+        let connecting = endpoint
+            .connect(*address, server_name)
+            .map_err(|e| Error::DataError(e.to_string()))?;
+        let outcome = match tokio::time::timeout(config.timeout, connecting).await {
+            Err(_) | Ok(Err(ConnectionError::TimedOut)) => CanaryOutcome::TimedOut,
+            Ok(Ok(connection)) => {
+                connection.close(0u32.into(), b"");
+                CanaryOutcome::Passed
+            }
+            // Any close, even one refusing our certificate checks or ALPN,
+            // means the server's packets got through
+            Ok(Err(
+                ConnectionError::TransportError(_)
+                | ConnectionError::ConnectionClosed(_)
+                | ConnectionError::ApplicationClosed(_)
+                | ConnectionError::VersionMismatch,
+            )) => CanaryOutcome::Passed,
+            Ok(Err(ConnectionError::Reset)) => CanaryOutcome::Interfered,
+            Ok(Err(e)) => CanaryOutcome::Unreachable(e.to_string()),
+        };
+        endpoint.close(0u32.into(), b"");
+        Ok::<_, Error>(outcome)
+    };
+    attempt
+        .await
+        .unwrap_or_else(|e| CanaryOutcome::Unreachable(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::desync::DesyncConfig;
+    use tokio::net::TcpListener;
+
+    /// Server that resets connections naming a blocked host and answers the rest
+    ///
+    /// Like stateless DPI it judges each segment on its own, and it answers
+    /// once the whole ClientHello record has arrived.
+    async fn filtering_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut buf = vec![0u8; 4096];
+                    let mut received = Vec::new();
+                    loop {
+                        let n = stream.read(&mut buf).await.unwrap_or(0);
+                        if n == 0 {
+                            return;
+                        }
+                        if buf[..n].windows(7).any(|w| w == b"blocked") {
+                            let _ =
+                                socket2::SockRef::from(&stream).set_linger(Some(Duration::ZERO));
+                            return;
+                        }
+                        received.extend_from_slice(&buf[..n]);
+                        if received.len() >= 5
+                            && received.len()
+                                >= 5 + u16::from_be_bytes([received[3], received[4]]) as usize
+                        {
+                            let _ = stream.write_all(&[0x16, 0x03, 0x03, 0x00, 0x00]).await;
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    fn config() -> TracerouteConfig {
+        TracerouteConfig {
+            max_hops: 3,
+            timeout: Duration::from_millis(500),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reset_marks_dpi_hop() {
+        let addr = filtering_server().await;
+        let trace = traceroute_dpi(addr, "blocked.example", &config())
+            .await
+            .unwrap();
+        assert_eq!(trace.dpi_hops, Some(1));
+        assert_eq!(trace.server_hops, None);
+        assert_eq!(trace.hops, vec![HopOutcome::Interfered; 3]);
+    }
+
+    #[tokio::test]
+    async fn test_reachable_server_has_no_dpi() {
+        let addr = filtering_server().await;
+        let trace = traceroute_dpi(addr, "allowed.example", &config())
+            .await
+            .unwrap();
+        assert_eq!(trace.dpi_hops, None);
+        assert_eq!(trace.server_hops, Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_suite_reports_surviving_techniques() {
+        let addr = filtering_server().await;
+        let closed = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let config = SuiteConfig {
+            // Split in the middle, "www.block" and "ed.example"
+            blocked_sni: "www.blocked.example".to_string(),
+            fake_sni: "allowed.example".to_string(),
+            ech_public_name: "public.example".to_string(),
+            doh: Some(DohEndpoint::new("https://dns.example/dns-query").with_address(closed)),
+            quic: Some((silent.local_addr().unwrap(), "quic.example".to_string())),
+            timeout: Duration::from_millis(300),
+            ..SuiteConfig::new(addr)
+        };
+
+        let report = run_suite(&config).await.unwrap();
+        assert_eq!(
+            report.outcome(Technique::PlainSni),
+            Some(&CanaryOutcome::Interfered)
+        );
+        assert!(report.passed(Technique::SplitSni));
+        assert!(report.passed(Technique::FakeSni));
+        assert!(report.passed(Technique::Ech));
+        assert!(matches!(
+            report.outcome(Technique::Doh),
+            Some(CanaryOutcome::Unreachable(_))
+        ));
+        assert_eq!(
+            report.outcome(Technique::Quic),
+            Some(&CanaryOutcome::TimedOut)
+        );
+
+        assert_eq!(
+            report.sni_order(),
+            vec![
+                SniStrategy::Ech,
+                SniStrategy::FakeSni,
+                SniStrategy::SniSplit,
+                SniStrategy::DomainFronting
+            ]
+        );
+        let mut settings = SecuritySettings::default();
+        settings.dpi_bypass.fragmentation_enabled = false;
+        settings.dpi_bypass.tls_evasion_enabled = false;
+        report.apply(&mut settings);
+        assert!(settings.dpi_bypass.fragmentation_enabled);
+        assert!(settings.dpi_bypass.tls_evasion_enabled);
+    }
+
+    #[tokio::test]
+    async fn test_calibrate_sets_desync_hops() {
+        let addr = filtering_server().await;
+        let mut desync = Desync::new(DesyncConfig::default());
+        calibrate_desync(&mut desync, addr, "blocked.example", &config())
+            .await
+            .unwrap();
+        assert_eq!(desync.config().dpi_hops, Some(1));
+    }
+}
@@ -0,0 +1,424 @@
+//! Active-probe detection and quarantine
+//! Spots replayed handshakes, repeated malformed authentication and scans
+//! from known prober ranges, logging each and quarantining the source
+
+use crate::error::{Error, Result};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime};
+
+/// An IPv4 or IPv6 network in CIDR notation
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct IpRange {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpRange {
+    pub fn new(network: IpAddr, prefix_len: u8) -> Result<Self> {
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        if prefix_len > max {
+            return Err(Error::ConfigError(format!(
+                "Prefix length {} is too long for {}",
+                prefix_len, network
+            )));
+        }
+        Ok(IpRange {
+            network,
+            prefix_len,
+        })
+    }
+
+    pub fn network(&self) -> IpAddr {
+        self.network
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = Error;
+
+    /// Parse `network/prefix`, or a bare address as a single-host range
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::ConfigError(format!("Invalid IP range: {}", s));
+        let (address, prefix) = match s.trim().split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (s.trim(), None),
+        };
+        let network: IpAddr = address.parse().map_err(|_| invalid())?;
+        let prefix_len = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| invalid())?,
+            None if network.is_ipv4() => 32,
+            None => 128,
+        };
+        Self::new(network, prefix_len)
+    }
+}
+
+impl fmt::Display for IpRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+/// What gave a client away as a prober
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ProbeSignal {
+    /// A handshake already seen, replayed to see how the server reacts
+    ReplayedHandshake,
+    /// Repeated handshakes that fail authentication
+    MalformedAuth,
+    /// Connections from many addresses of one suspicious range
+    Scan,
+}
+
+/// One entry of the probe log
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProbeEvent {
+    pub ip: IpAddr,
+    pub signal: ProbeSignal,
+    pub at: SystemTime,
+    /// Whether this event put the source into quarantine
+    pub quarantined: bool,
+}
+
+/// Thresholds for probe detection
+#[derive(Clone, Debug)]
+pub struct ProbeGuardConfig {
+    /// How long a detected prober stays quarantined
+    pub quarantine_duration: Duration,
+    /// Period over which signals are counted and handshakes remembered
+    pub window: Duration,
+    /// Failed authentications from one address before quarantine
+    pub malformed_threshold: usize,
+    /// Distinct addresses from one suspicious range before it is treated
+    /// as a scan and quarantined as a whole
+    pub scan_threshold: usize,
+    /// Ranges known to host probers, e.g. from ASN data
+    pub suspicious_ranges: Vec<IpRange>,
+    /// Handshake fingerprints remembered for replay detection
+    pub max_tracked_handshakes: usize,
+    /// Events kept in the log
+    pub max_events: usize,
+}
+
+impl Default for ProbeGuardConfig {
+    fn default() -> Self {
+        ProbeGuardConfig {
+            quarantine_duration: Duration::from_secs(3600),
+            window: Duration::from_secs(600),
+            malformed_threshold: 3,
+            scan_threshold: 4,
+            suspicious_ranges: Vec::new(),
+            max_tracked_handshakes: 100_000,
+            max_events: 1024,
+        }
+    }
+}
+
+/// Server-side probe detector
+///
+/// Quarantined clients should get the decoy site from the `fallback`
+/// module rather than a reset: refusing them outright is itself the
+/// confirmation a prober looks for.
+pub struct ProbeGuard {
+    config: ProbeGuardConfig,
+    handshakes: HashMap<[u8; 32], Instant>,
+    handshake_order: VecDeque<([u8; 32], Instant)>,
+    failures: HashMap<IpAddr, VecDeque<Instant>>,
+    range_hits: HashMap<IpRange, VecDeque<(IpAddr, Instant)>>,
+    quarantined: HashMap<IpAddr, Instant>,
+    quarantined_ranges: HashMap<IpRange, Instant>,
+    events: VecDeque<ProbeEvent>,
+}
+
+impl ProbeGuard {
+    pub fn new(config: ProbeGuardConfig) -> Self {
+        ProbeGuard {
+            config,
+            handshakes: HashMap::new(),
+            handshake_order: VecDeque::new(),
+            failures: HashMap::new(),
+            range_hits: HashMap::new(),
+            quarantined: HashMap::new(),
+            quarantined_ranges: HashMap::new(),
+            events: VecDeque::new(),
+        }
+    }
+
+    pub fn config(&self) -> &ProbeGuardConfig {
+        &self.config
+    }
+
+    /// Probe log, oldest first
+    pub fn events(&self) -> impl Iterator<Item = &ProbeEvent> {
+        self.events.iter()
+    }
+
+    /// Whether `ip` or its range is quarantined
+    pub fn is_quarantined(&self, ip: IpAddr) -> bool {
+        self.is_quarantined_at(ip, Instant::now())
+    }
+
+    /// Note a new connection; returns whether it should be treated as a
+    /// prober (already quarantined, or completing a scan)
+    pub fn on_connection(&mut self, ip: IpAddr) -> bool {
+        self.on_connection_at(ip, Instant::now())
+    }
+
+    /// Check a handshake that passed authentication for replay; returns
+    /// whether it is a replay, which quarantines the sender
+    ///
+    /// `handshake` should be the bytes bound to the authentication, such as
+    /// the ClientHello random and session id.
+    pub fn check_handshake(&mut self, ip: IpAddr, handshake: &[u8]) -> bool {
+        self.check_handshake_at(ip, handshake, Instant::now())
+    }
+
+    /// Note a handshake that failed authentication
+    pub fn report_malformed_auth(&mut self, ip: IpAddr) {
+        self.report_malformed_auth_at(ip, Instant::now())
+    }
+
+    /// Drop expired state
+    pub fn prune(&mut self) {
+        self.prune_at(Instant::now())
+    }
+
+    fn is_quarantined_at(&self, ip: IpAddr, now: Instant) -> bool {
+        self.quarantined.get(&ip).is_some_and(|&until| until > now)
+            || self
+                .quarantined_ranges
+                .iter()
+                .any(|(range, &until)| until > now && range.contains(ip))
+    }
+
+    fn on_connection_at(&mut self, ip: IpAddr, now: Instant) -> bool {
+        if self.is_quarantined_at(ip, now) {
+            return true;
+        }
+        let Some(range) = self
+            .config
+            .suspicious_ranges
+            .iter()
+            .find(|range| range.contains(ip))
+            .copied()
+        else {
+            return false;
+        };
+
+        let window = self.config.window;
+        let hits = self.range_hits.entry(range).or_default();
+        hits.retain(|&(_, at)| now.duration_since(at) < window);
+        hits.push_back((ip, now));
+        let mut sources: Vec<IpAddr> = hits.iter().map(|&(ip, _)| ip).collect();
+        sources.sort();
+        sources.dedup();
+        if sources.len() < self.config.scan_threshold {
+            return false;
+        }
+
+        self.range_hits.remove(&range);
+        self.quarantined_ranges
+            .insert(range, now + self.config.quarantine_duration);
+        self.log(ip, ProbeSignal::Scan, true);
+        true
+    }
+
+    fn check_handshake_at(&mut self, ip: IpAddr, handshake: &[u8], now: Instant) -> bool {
+        let fingerprint: [u8; 32] = Sha256::digest(handshake).into();
+        let window = self.config.window;
+        match self.handshakes.get(&fingerprint) {
+            Some(&seen) if now.duration_since(seen) < window => {
+                self.quarantine(ip, now);
+                self.log(ip, ProbeSignal::ReplayedHandshake, true);
+                true
+            }
+            _ => {
+                self.handshakes.insert(fingerprint, now);
+                self.handshake_order.push_back((fingerprint, now));
+                while self.handshake_order.len() > self.config.max_tracked_handshakes {
+                    self.forget_oldest_handshake();
+                }
+                false
+            }
+        }
+    }
+
+    fn report_malformed_auth_at(&mut self, ip: IpAddr, now: Instant) {
+        let window = self.config.window;
+        let failures = self.failures.entry(ip).or_default();
+        failures.retain(|&at| now.duration_since(at) < window);
+        failures.push_back(now);
+        let quarantine = failures.len() >= self.config.malformed_threshold;
+        if quarantine {
+            self.failures.remove(&ip);
+            self.quarantine(ip, now);
+        }
+        self.log(ip, ProbeSignal::MalformedAuth, quarantine);
+    }
+
+    fn prune_at(&mut self, now: Instant) {
+        let window = self.config.window;
+        while self
+            .handshake_order
+            .front()
+            .is_some_and(|&(_, at)| now.duration_since(at) >= window)
+        {
+            self.forget_oldest_handshake();
+        }
+        self.failures.retain(|_, failures| {
+            failures.retain(|&at| now.duration_since(at) < window);
+            !failures.is_empty()
+        });
+        self.range_hits.retain(|_, hits| {
+            hits.retain(|&(_, at)| now.duration_since(at) < window);
+            !hits.is_empty()
+        });
+        self.quarantined.retain(|_, &mut until| until > now);
+        self.quarantined_ranges.retain(|_, &mut until| until > now);
+    }
+
+    fn forget_oldest_handshake(&mut self) {
+        if let Some((fingerprint, at)) = self.handshake_order.pop_front() {
+            // A later sighting of the same handshake refreshed the map entry
+            if self.handshakes.get(&fingerprint) == Some(&at) {
+                self.handshakes.remove(&fingerprint);
+            }
+        }
+    }
+
+    fn quarantine(&mut self, ip: IpAddr, now: Instant) {
+        self.quarantined
+            .insert(ip, now + self.config.quarantine_duration);
+    }
+
+    fn log(&mut self, ip: IpAddr, signal: ProbeSignal, quarantined: bool) {
+        if quarantined {
+            log::warn!(
+                "Quarantined probe source {} ({:?})",
+                crate::privacy::redact(ip),
+                signal
+            );
+        }
+        self.events.push_back(ProbeEvent {
+            ip,
+            signal,
+            at: SystemTime::now(),
+            quarantined,
+        });
+        while self.events.len() > self.config.max_events {
+            self.events.pop_front();
+        }
+    }
+}
+
+impl Default for ProbeGuard {
+    fn default() -> Self {
+        Self::new(ProbeGuardConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_ip_range() {
+        let range: IpRange = "203.0.113.0/24".parse().unwrap();
+        assert!(range.contains(ip("203.0.113.200")));
+        assert!(!range.contains(ip("203.0.114.1")));
+        assert!(!range.contains(ip("2001:db8::1")));
+        assert_eq!(range.to_string(), "203.0.113.0/24");
+
+        let v6: IpRange = "2001:db8::/32".parse().unwrap();
+        assert!(v6.contains(ip("2001:db8:ffff::1")));
+        assert!("0.0.0.0/0"
+            .parse::<IpRange>()
+            .unwrap()
+            .contains(ip("8.8.8.8")));
+        assert_eq!("10.0.0.1".parse::<IpRange>().unwrap().prefix_len(), 32);
+        assert!("10.0.0.0/33".parse::<IpRange>().is_err());
+        assert!("nonsense/8".parse::<IpRange>().is_err());
+    }
+
+    #[test]
+    fn test_replayed_handshake_quarantines() {
+        let mut guard = ProbeGuard::default();
+        let now = Instant::now();
+        assert!(!guard.check_handshake_at(ip("198.51.100.1"), b"hello", now));
+        assert!(guard.check_handshake_at(ip("198.51.100.9"), b"hello", now));
+        assert!(guard.is_quarantined_at(ip("198.51.100.9"), now));
+        assert!(!guard.is_quarantined_at(ip("198.51.100.1"), now));
+
+        // Quarantine and handshake memory both expire
+        let later = now + Duration::from_secs(3601);
+        assert!(!guard.is_quarantined_at(ip("198.51.100.9"), later));
+        guard.prune_at(later);
+        assert!(!guard.check_handshake_at(ip("198.51.100.9"), b"hello", later));
+
+        let events: Vec<_> = guard.events().collect();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].signal, ProbeSignal::ReplayedHandshake);
+    }
+
+    #[test]
+    fn test_malformed_auth_threshold() {
+        let mut guard = ProbeGuard::default();
+        let now = Instant::now();
+        let prober = ip("192.0.2.5");
+        guard.report_malformed_auth_at(prober, now);
+        guard.report_malformed_auth_at(prober, now + Duration::from_secs(700));
+        assert!(!guard.is_quarantined_at(prober, now + Duration::from_secs(700)));
+        guard.report_malformed_auth_at(prober, now + Duration::from_secs(701));
+        guard.report_malformed_auth_at(prober, now + Duration::from_secs(702));
+        assert!(guard.is_quarantined_at(prober, now + Duration::from_secs(702)));
+        assert_eq!(guard.events().filter(|e| e.quarantined).count(), 1);
+    }
+
+    #[test]
+    fn test_scan_from_suspicious_range() {
+        let mut guard = ProbeGuard::new(ProbeGuardConfig {
+            suspicious_ranges: vec!["192.0.2.0/24".parse().unwrap()],
+            ..Default::default()
+        });
+        let now = Instant::now();
+        assert!(!guard.on_connection_at(ip("198.51.100.1"), now));
+        for host in 1..4 {
+            assert!(!guard.on_connection_at(ip(&format!("192.0.2.{}", host)), now));
+        }
+        // Repeats from one address do not count as a scan
+        assert!(!guard.on_connection_at(ip("192.0.2.3"), now));
+        assert!(guard.on_connection_at(ip("192.0.2.4"), now));
+        assert!(guard.is_quarantined_at(ip("192.0.2.77"), now));
+        assert!(!guard.is_quarantined_at(ip("198.51.100.1"), now));
+    }
+}
@@ -0,0 +1,207 @@
+//! Named configuration profiles
+//! One settings file can carry a profile per network the device moves
+//! between ("home", "mobile-data", "office"), each changing only what
+//! differs from the shared settings, plus the fake-SNI pool used there
+//!
+//! ```toml
+//! active = "home"
+//!
+//! [obfuscation]        # shared settings, as in a plain settings file
+//! enabled = true
+//! # ...
+//!
+//! [profiles.mobile-data]
+//! sni_pool = "global-cdn"
+//! detection_evasion = { max_adaptation_level = 8 }
+//! ```
+//!
+//! A plain settings file is a profile set without profiles.
+
+use crate::config::SecuritySettings;
+use crate::config_encryption::ConfigKey;
+use crate::sni_pool::SniPoolProfile;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// One network's changes to the shared settings
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct NetworkProfile {
+    /// Fake-SNI pool for this network, short for
+    /// `sni_obfuscation.pool_profile`; the shared pool when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sni_pool: Option<SniPoolProfile>,
+    /// Settings that differ from the shared ones, as a partial settings tree
+    #[serde(flatten)]
+    pub settings: Map<String, Value>,
+}
+
+/// Shared settings and the named profiles layered on them
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ProfileSet {
+    /// Profile in use; the shared settings alone when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub active: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, NetworkProfile>,
+    #[serde(flatten)]
+    pub shared: SecuritySettings,
+}
+
+/// Settings in effect for one profile
+#[derive(Clone, Debug)]
+pub struct ActiveProfile {
+    /// `None` for the shared settings alone
+    pub name: Option<String>,
+    pub settings: SecuritySettings,
+}
+
+impl ActiveProfile {
+    /// Fake-SNI pool the profile uses
+    pub fn sni_pool(&self) -> &SniPoolProfile {
+        &self.settings.sni_obfuscation.pool_profile
+    }
+}
+
+impl ProfileSet {
+    /// Load a profile set from a JSON, YAML or TOML file, sealed or not
+    pub fn load(path: &Path, key: Option<&ConfigKey>) -> Result<Self, String> {
+        crate::overrides::load_document(path, key)
+    }
+
+    /// Names of the profiles, in order
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.profiles.keys().map(String::as_str)
+    }
+
+    /// Validated settings of a profile, or of the shared settings alone
+    pub fn profile(&self, name: Option<&str>) -> Result<ActiveProfile, String> {
+        let profile = self.merged(name)?;
+        profile.settings.validate()?;
+        Ok(profile)
+    }
+
+    /// Settings of the active profile
+    pub fn active_profile(&self) -> Result<ActiveProfile, String> {
+        self.profile(self.active.as_deref())
+    }
+
+    /// Make `name` the active profile; the active profile is unchanged if
+    /// it is unknown or its settings are invalid
+    pub fn switch(&mut self, name: &str) -> Result<ActiveProfile, String> {
+        let profile = self.profile(Some(name))?;
+        self.active = Some(name.to_string());
+        Ok(profile)
+    }
+
+    /// A profile's settings before validation
+    pub(crate) fn merged(&self, name: Option<&str>) -> Result<ActiveProfile, String> {
+        let Some(name) = name else {
+            return Ok(ActiveProfile {
+                name: None,
+                settings: self.shared.clone(),
+            });
+        };
+        let profile = self
+            .profiles
+            .get(name)
+            .ok_or_else(|| format!("unknown profile '{}'", name))?;
+        let mut tree = serde_json::to_value(&self.shared).map_err(|e| e.to_string())?;
+        merge(&mut tree, &profile.settings, &format!("profiles.{}", name))?;
+        let mut settings: SecuritySettings =
+            serde_json::from_value(tree).map_err(|e| format!("profile '{}': {}", name, e))?;
+        if let Some(pool) = &profile.sni_pool {
+            settings.sni_obfuscation.pool_profile = pool.clone();
+        }
+        Ok(ActiveProfile {
+            name: Some(name.to_string()),
+            settings,
+        })
+    }
+}
+
+/// Overlay `changes` onto `tree`; only fields the settings have can change
+fn merge(tree: &mut Value, changes: &Map<String, Value>, path: &str) -> Result<(), String> {
+    for (key, change) in changes {
+        let path = format!("{}.{}", path, key);
+        let slot = tree
+            .as_object_mut()
+            .and_then(|fields| fields.get_mut(key))
+            .ok_or_else(|| format!("unknown setting '{}'", path))?;
+        match change {
+            Value::Object(inner) if slot.is_object() => merge(slot, inner, &path)?,
+            _ => *slot = change.clone(),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile_set() -> ProfileSet {
+        let mut shared = serde_json::to_value(SecuritySettings::default()).unwrap();
+        shared["active"] = "home".into();
+        shared["profiles"] = serde_json::json!({
+            "home": {},
+            "mobile-data": {
+                "sni_pool": "global-cdn",
+                "detection_evasion": {"max_adaptation_level": 8},
+                "max_overhead_ratio": 0.1
+            },
+            "office": {
+                "sni_pool": {"custom": {"file": "/etc/ips/office-sni.txt"}},
+                "obfuscation": {"min_packet_size": 5000}
+            }
+        });
+        serde_json::from_value(shared).unwrap()
+    }
+
+    #[test]
+    fn test_profiles_layer_on_shared_settings() {
+        let set = profile_set();
+        assert_eq!(
+            set.names().collect::<Vec<_>>(),
+            ["home", "mobile-data", "office"]
+        );
+
+        let home = set.active_profile().unwrap();
+        assert_eq!(home.name.as_deref(), Some("home"));
+        assert_eq!(home.sni_pool(), &SniPoolProfile::IranWhitelisted);
+
+        let mobile = set.profile(Some("mobile-data")).unwrap();
+        assert_eq!(mobile.sni_pool(), &SniPoolProfile::GlobalCdn);
+        assert_eq!(mobile.settings.detection_evasion.max_adaptation_level, 8);
+        assert_eq!(mobile.settings.max_overhead_ratio, Some(0.1));
+        // Untouched fields keep the shared values
+        assert_eq!(
+            mobile.settings.detection_evasion.decoy_traffic_percentage,
+            set.shared.detection_evasion.decoy_traffic_percentage
+        );
+    }
+
+    #[test]
+    fn test_switch() {
+        let mut set = profile_set();
+        let mobile = set.switch("mobile-data").unwrap();
+        assert_eq!(set.active.as_deref(), Some("mobile-data"));
+        assert_eq!(mobile.settings.detection_evasion.max_adaptation_level, 8);
+
+        // Invalid or unknown profiles leave the active one in place
+        assert!(set.switch("office").is_err());
+        assert!(set.switch("hotel").unwrap_err().contains("unknown profile"));
+        assert_eq!(set.active.as_deref(), Some("mobile-data"));
+
+        set.profiles
+            .get_mut("home")
+            .unwrap()
+            .settings
+            .insert("no_such_section".to_string(), Value::Bool(true));
+        assert!(set
+            .switch("home")
+            .unwrap_err()
+            .contains("profiles.home.no_such_section"));
+    }
+}
@@ -0,0 +1,366 @@
+//! Raw-socket TCP backend (Linux, `raw-net` feature)
+//! Drives a TCP connection from self-built packets so the TTL, window and MSS
+//! generated in `dynamic_patterns::SessionParameters` are what goes on the wire
+
+use crate::desync::{
+    build_tcp_packet, parse_segment, RawSocket, TcpFlow, TcpSegmentInfo, TCP_FLAG_ACK,
+    TCP_FLAG_PSH,
+};
+use crate::dynamic_patterns::SessionParameters;
+use crate::error::{Error, Result};
+use rand::Rng;
+use std::io::ErrorKind;
+use std::mem::MaybeUninit;
+use std::net::{IpAddr, Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::time::{Duration, Instant};
+
+const TCP_FLAG_SYN: u8 = 0x02;
+pub(crate) const TCP_FLAG_RST: u8 = 0x04;
+
+const TCP_OPTION_NOP: u8 = 1;
+const TCP_OPTION_MSS: u8 = 2;
+const TCP_OPTION_SACK_PERMITTED: u8 = 4;
+
+/// SYN options: MSS, then SACK-permitted padded with NOPs
+fn syn_options(mss: u16) -> [u8; 8] {
+    let mss = mss.to_be_bytes();
+    [
+        TCP_OPTION_MSS,
+        4,
+        mss[0],
+        mss[1],
+        TCP_OPTION_NOP,
+        TCP_OPTION_NOP,
+        TCP_OPTION_SACK_PERMITTED,
+        2,
+    ]
+}
+
+/// Build the SYN opening `flow`, advertising the session's window and MSS
+pub fn syn_packet(flow: &TcpFlow, params: &SessionParameters) -> Vec<u8> {
+    let flow = TcpFlow {
+        window: params.tcp_window_size,
+        ..*flow
+    };
+    build_tcp_packet(
+        &flow,
+        flow.seq,
+        TCP_FLAG_SYN,
+        &syn_options(params.tcp_mss),
+        &[],
+        params.ttl,
+    )
+}
+
+/// Local address the kernel would route `dst` from
+fn local_addr_for(dst: SocketAddrV4) -> Result<Ipv4Addr> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.connect(dst)?;
+    match socket.local_addr()?.ip() {
+        IpAddr::V4(ip) => Ok(ip),
+        IpAddr::V6(_) => Err(Error::ConfigError(
+            "No IPv4 route to destination".to_string(),
+        )),
+    }
+}
+
+/// TCP connection driven entirely from raw packets
+///
+/// The kernel has no socket for the connection and answers the server's
+/// SYN-ACK with a RST, so outgoing RSTs for the source port must be dropped
+/// (see the firewall helpers) for the connection to survive. Needs root or
+/// CAP_NET_RAW.
+pub struct RawTcpConnection {
+    sender: RawSocket,
+    receiver: socket2::Socket,
+    flow: TcpFlow,
+    params: SessionParameters,
+}
+
+impl RawTcpConnection {
+    /// Open a connection to `dst` with the session's TTL, window and MSS
+    pub fn connect(
+        dst: SocketAddrV4,
+        params: &SessionParameters,
+        timeout: Duration,
+    ) -> Result<Self> {
+        let mut rng = rand::thread_rng();
+        let src = SocketAddrV4::new(local_addr_for(dst)?, rng.gen_range(49152..=65535));
+        let isn: u32 = rng.gen();
+
+        let sender = RawSocket::open()?;
+        let receiver = raw_receiver()?;
+
+        let mut flow = TcpFlow {
+            src,
+            dst,
+            seq: isn,
+            ack: 0,
+            window: params.tcp_window_size,
+            ttl: params.ttl,
+            server_ttl: None,
+        };
+        sender.send(&syn_packet(&flow, params))?;
+
+        let deadline = Instant::now() + timeout;
+        let syn_ack = loop {
+            let segment = recv_segment(&receiver, deadline)?;
+            if segment.src != dst || segment.dst != src || !segment.checksum_ok {
+                continue;
+            }
+            if segment.flags & TCP_FLAG_RST != 0 {
+                return Err(Error::IoError(ErrorKind::ConnectionRefused.into()));
+            }
+            if segment.flags & (TCP_FLAG_SYN | TCP_FLAG_ACK) == TCP_FLAG_SYN | TCP_FLAG_ACK
+                && segment.ack == isn.wrapping_add(1)
+            {
+                break segment;
+            }
+        };
+
+        flow.seq = isn.wrapping_add(1);
+        flow.ack = syn_ack.seq.wrapping_add(1);
+        flow.server_ttl = Some(syn_ack.ttl);
+        sender.send(&build_tcp_packet(
+            &flow,
+            flow.seq,
+            TCP_FLAG_ACK,
+            &[],
+            &[],
+            flow.ttl,
+        ))?;
+
+        Ok(RawTcpConnection {
+            sender,
+            receiver,
+            flow,
+            params: params.clone(),
+        })
+    }
+
+    /// Accept one connection on `local`, answering its SYN from raw packets
+    ///
+    /// Counterpart of `connect` for the serving side; an unspecified IP accepts
+    /// SYNs for any local address. With no listening socket the kernel resets
+    /// incoming SYNs as well, so RSTs from `local`'s port must be dropped too.
+    pub fn accept(
+        local: SocketAddrV4,
+        params: &SessionParameters,
+        timeout: Duration,
+    ) -> Result<Self> {
+        let sender = RawSocket::open()?;
+        let receiver = raw_receiver()?;
+
+        let deadline = Instant::now() + timeout;
+        let syn = loop {
+            let segment = recv_segment(&receiver, deadline)?;
+            let ip_matches = local.ip().is_unspecified() || segment.dst.ip() == local.ip();
+            if segment.dst.port() != local.port() || !ip_matches || !segment.checksum_ok {
+                continue;
+            }
+            if segment.flags & (TCP_FLAG_SYN | TCP_FLAG_ACK) == TCP_FLAG_SYN {
+                break segment;
+            }
+        };
+
+        let isn: u32 = rand::thread_rng().gen();
+        let mut flow = TcpFlow {
+            src: syn.dst,
+            dst: syn.src,
+            seq: isn,
+            ack: syn.seq.wrapping_add(1),
+            window: params.tcp_window_size,
+            ttl: params.ttl,
+            server_ttl: Some(syn.ttl),
+        };
+        sender.send(&build_tcp_packet(
+            &flow,
+            isn,
+            TCP_FLAG_SYN | TCP_FLAG_ACK,
+            &syn_options(params.tcp_mss),
+            &[],
+            params.ttl,
+        ))?;
+
+        loop {
+            let segment = recv_segment(&receiver, deadline)?;
+            if segment.src != flow.dst || segment.dst != flow.src || !segment.checksum_ok {
+                continue;
+            }
+            if segment.flags & TCP_FLAG_RST != 0 {
+                return Err(Error::IoError(ErrorKind::ConnectionReset.into()));
+            }
+            if segment.flags & TCP_FLAG_ACK != 0 && segment.ack == isn.wrapping_add(1) {
+                break;
+            }
+        }
+        flow.seq = isn.wrapping_add(1);
+
+        Ok(RawTcpConnection {
+            sender,
+            receiver,
+            flow,
+            params: params.clone(),
+        })
+    }
+
+    /// Session parameters the connection was opened with
+    pub fn params(&self) -> &SessionParameters {
+        &self.params
+    }
+
+    /// Current flow state, e.g. for `desync::Desync::segments`
+    pub fn flow(&self) -> &TcpFlow {
+        &self.flow
+    }
+
+    /// Send data in MSS-sized segments
+    pub fn send(&mut self, data: &[u8]) -> Result<()> {
+        let mss = self.params.tcp_mss.max(1) as usize;
+        for chunk in data.chunks(mss) {
+            let packet = build_tcp_packet(
+                &self.flow,
+                self.flow.seq,
+                TCP_FLAG_PSH | TCP_FLAG_ACK,
+                &[],
+                chunk,
+                self.flow.ttl,
+            );
+            self.sender.send(&packet)?;
+            self.flow.seq = self.flow.seq.wrapping_add(chunk.len() as u32);
+        }
+        Ok(())
+    }
+
+    /// Send a packet built elsewhere (e.g. a desync segment) on this connection
+    pub fn send_raw(&self, packet: &[u8]) -> Result<()> {
+        self.sender.send(packet)
+    }
+
+    /// Wait for the next segment from the server, updating the ack state
+    pub fn recv(&mut self, timeout: Duration) -> Result<TcpSegmentInfo> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let segment = recv_segment(&self.receiver, deadline)?;
+            if segment.src != self.flow.dst || segment.dst != self.flow.src || !segment.checksum_ok
+            {
+                continue;
+            }
+            if !segment.payload.is_empty() && segment.seq == self.flow.ack {
+                self.flow.ack = self.flow.ack.wrapping_add(segment.payload.len() as u32);
+            }
+            return Ok(segment);
+        }
+    }
+
+    /// Tear the connection down with a RST
+    pub fn reset(self) -> Result<()> {
+        let packet = build_tcp_packet(
+            &self.flow,
+            self.flow.seq,
+            TCP_FLAG_RST,
+            &[],
+            &[],
+            self.flow.ttl,
+        );
+        self.sender.send(&packet)
+    }
+}
+
+/// Raw socket receiving a copy of every incoming TCP segment
+fn raw_receiver() -> Result<socket2::Socket> {
+    Ok(socket2::Socket::new(
+        socket2::Domain::IPV4,
+        socket2::Type::RAW,
+        Some(socket2::Protocol::TCP),
+    )?)
+}
+
+/// Read one TCP segment from a raw receive socket before `deadline`
+fn recv_segment(socket: &socket2::Socket, deadline: Instant) -> Result<TcpSegmentInfo> {
+    let mut buf = [MaybeUninit::<u8>::uninit(); 65535];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(Error::IoError(ErrorKind::TimedOut.into()));
+        }
+        socket.set_read_timeout(Some(remaining))?;
+        let len = match socket.recv(&mut buf) {
+            Ok(len) => len,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e.into()),
+        };
+        // SAFETY: recv initialised the first `len` bytes
+        let packet = unsafe { std::slice::from_raw_parts(buf.as_ptr() as *const u8, len) };
+        if let Some(segment) = parse_segment(packet) {
+            return Ok(segment);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::desync::IPV4_HEADER_LEN;
+    use crate::dynamic_patterns::Ipv6Parameters;
+
+    fn params() -> SessionParameters {
+        SessionParameters {
+            tcp_window_size: 29200,
+            tcp_mss: 1360,
+            ttl: 57,
+            initial_rtt_ms: 40,
+            packet_timing_variance: 5,
+            ipv6: Ipv6Parameters::default(),
+        }
+    }
+
+    fn flow() -> TcpFlow {
+        TcpFlow {
+            src: "10.0.0.2:40000".parse().unwrap(),
+            dst: "203.0.113.7:443".parse().unwrap(),
+            seq: 7000,
+            ack: 0,
+            window: 0,
+            ttl: 64,
+            server_ttl: None,
+        }
+    }
+
+    #[test]
+    fn test_syn_carries_session_parameters() {
+        let packet = syn_packet(&flow(), &params());
+        let segment = parse_segment(&packet).unwrap();
+        assert!(segment.checksum_ok);
+        assert_eq!(segment.flags, TCP_FLAG_SYN);
+        assert_eq!(segment.ttl, 57);
+        assert_eq!(segment.window, 29200);
+        assert_eq!(segment.seq, 7000);
+        assert_eq!(segment.ack, 0);
+
+        let options = &packet[IPV4_HEADER_LEN + 20..];
+        assert_eq!(&options[..4], &[TCP_OPTION_MSS, 4, 0x05, 0x50]);
+    }
+
+    #[test]
+    fn test_parse_rejects_non_tcp() {
+        let mut packet = syn_packet(&flow(), &params());
+        packet[9] = 17;
+        assert!(parse_segment(&packet).is_none());
+        assert!(parse_segment(&packet[..10]).is_none());
+    }
+
+    #[test]
+    fn test_data_segment_round_trip() {
+        let flow = TcpFlow {
+            ack: 9000,
+            window: 29200,
+            ..flow()
+        };
+        let packet = build_tcp_packet(&flow, 7001, TCP_FLAG_PSH | TCP_FLAG_ACK, &[], b"hello", 57);
+        let segment = parse_segment(&packet).unwrap();
+        assert!(segment.checksum_ok);
+        assert_eq!(segment.ack, 9000);
+        assert_eq!(segment.payload, b"hello");
+    }
+}
@@ -0,0 +1,765 @@
+//! REALITY-style camouflage transport
+//! Clients authenticate inside an ordinary-looking ClientHello (a short id sealed
+//! into legacy_session_id); everyone else, active probes included, is relayed
+//! untouched to a real target site and sees its genuine certificate
+
+use crate::client_hello::{
+    parse_client_hello, ClientHelloBuilder, TLSProfileConfig, GROUP_X25519, HANDSHAKE_HEADER_LEN,
+    TLS_RECORD_HEADER_LEN,
+};
+use crate::compression::{self, Compression};
+use crate::error::{Error, Result};
+use aes_gcm::aead::{Aead, Payload};
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
+use hkdf::Hkdf;
+use rand::Rng;
+use sha2::Sha256;
+use std::ops::Range;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// Protocol version sealed into the session id
+const REALITY_VERSION: [u8; 3] = [1, 0, 0];
+
+/// legacy_session_id sits after the record header, handshake header,
+/// legacy_version and random
+const SESSION_ID_OFFSET: usize = TLS_RECORD_HEADER_LEN + HANDSHAKE_HEADER_LEN + 2 + 32 + 1;
+
+const CONTENT_TYPE_CHANGE_CIPHER_SPEC: u8 = 0x14;
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const CONTENT_TYPE_APPLICATION_DATA: u8 = 0x17;
+const HANDSHAKE_TYPE_SERVER_HELLO: u8 = 0x02;
+const TLS_AES_128_GCM_SHA256: u16 = 0x1301;
+const EXT_SUPPORTED_VERSIONS: u16 = 0x002b;
+const EXT_KEY_SHARE: u16 = 0x0033;
+
+const MAX_RECORD_LEN: usize = 16384 + 256;
+const AEAD_TAG_LEN: usize = 16;
+
+/// A TLS 1.3 client Finished record carries 36 bytes plus the inner content type
+const CLIENT_FINISHED_LEN: usize = 37;
+
+/// Parse a short id written as up to 16 hex characters (zero-padded on the right)
+pub fn parse_short_id(hex: &str) -> Result<[u8; 8]> {
+    if hex.len() > 16 || !hex.len().is_multiple_of(2) {
+        return Err(Error::ConfigError(format!(
+            "Invalid REALITY short id: {}",
+            hex
+        )));
+    }
+    let mut short_id = [0u8; 8];
+    for (i, byte) in short_id.iter_mut().take(hex.len() / 2).enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .map_err(|_| Error::ConfigError(format!("Invalid REALITY short id: {}", hex)))?;
+    }
+    Ok(short_id)
+}
+
+/// Generate a server key pair, returning (private, public)
+pub fn generate_keypair() -> ([u8; 32], [u8; 32]) {
+    let secret = StaticSecret::from(rand::thread_rng().gen::<[u8; 32]>());
+    let public = PublicKey::from(&secret);
+    (secret.to_bytes(), public.to_bytes())
+}
+
+/// Client-side REALITY settings
+#[derive(Clone, Debug)]
+pub struct RealityClientConfig {
+    /// SNI to present; must be one of the server's `server_names`
+    pub server_name: String,
+    /// The server's X25519 public key
+    pub public_key: [u8; 32],
+    pub short_id: [u8; 8],
+    pub profile: TLSProfileConfig,
+    /// Compression methods offered to the server
+    pub compression: Vec<Compression>,
+}
+
+/// Server-side REALITY settings
+#[derive(Clone, Debug)]
+pub struct RealityServerConfig {
+    pub private_key: [u8; 32],
+    pub short_ids: Vec<[u8; 8]>,
+    /// SNIs accepted from clients, normally the names served by `dest`
+    pub server_names: Vec<String>,
+    /// Real site that unauthenticated connections are relayed to (`host:port`)
+    pub dest: String,
+    /// Largest accepted clock difference between client and server
+    pub max_time_diff: Duration,
+    /// Size range of the first encrypted server flight, matching a typical
+    /// certificate chain so the record lengths look like a real handshake
+    pub server_flight_len: Range<usize>,
+    /// Compression methods accepted from clients, most preferred first
+    pub compression: Vec<Compression>,
+}
+
+impl Default for RealityServerConfig {
+    fn default() -> Self {
+        RealityServerConfig {
+            private_key: [0u8; 32],
+            short_ids: Vec::new(),
+            server_names: Vec::new(),
+            dest: String::new(),
+            max_time_diff: Duration::from_secs(120),
+            server_flight_len: 2500..4500,
+            compression: Vec::new(),
+        }
+    }
+}
+
+/// Derive the per-connection authentication key from the ECDH secret
+fn auth_key(shared_secret: &[u8; 32], client_random: &[u8; 32]) -> [u8; 32] {
+    let hkdf = Hkdf::<Sha256>::new(Some(&client_random[..20]), shared_secret);
+    let mut key = [0u8; 32];
+    hkdf.expand(b"REALITY", &mut key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    key
+}
+
+/// Directional traffic keys for the authenticated channel
+fn traffic_keys(auth_key: &[u8; 32], ecdhe: &[u8; 32], label: &[u8]) -> ([u8; 32], [u8; 12]) {
+    let hkdf = Hkdf::<Sha256>::new(Some(auth_key), ecdhe);
+    let mut okm = [0u8; 44];
+    hkdf.expand(label, &mut okm)
+        .expect("44 bytes is a valid HKDF-SHA256 output length");
+    let mut key = [0u8; 32];
+    let mut iv = [0u8; 12];
+    key.copy_from_slice(&okm[..32]);
+    iv.copy_from_slice(&okm[32..]);
+    (key, iv)
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// State the client keeps between sending its hello and reading the reply
+pub struct RealityClientState {
+    secret: StaticSecret,
+    auth_key: [u8; 32],
+}
+
+/// REALITY client
+pub struct RealityClient {
+    config: RealityClientConfig,
+}
+
+impl RealityClient {
+    /// Create a client from configuration
+    pub fn new(config: RealityClientConfig) -> Self {
+        RealityClient { config }
+    }
+
+    /// Build an authenticating ClientHello record
+    pub fn client_hello(&self) -> (Vec<u8>, RealityClientState) {
+        let mut rng = rand::thread_rng();
+        let secret = StaticSecret::from(rng.gen::<[u8; 32]>());
+        let random: [u8; 32] = rng.gen();
+
+        let mut hello = ClientHelloBuilder::from_profile(&self.config.profile)
+            .server_name(&self.config.server_name)
+            .random(random)
+            .session_id([0u8; 32])
+            .key_share(GROUP_X25519, PublicKey::from(&secret).to_bytes().to_vec())
+            .build();
+
+        let shared = secret.diffie_hellman(&PublicKey::from(self.config.public_key));
+        let auth_key = auth_key(shared.as_bytes(), &random);
+
+        let mut plaintext = [0u8; 16];
+        plaintext[..3].copy_from_slice(&REALITY_VERSION);
+        plaintext[3] = compression::offer(&self.config.compression);
+        plaintext[4..8].copy_from_slice(&(unix_time() as u32).to_be_bytes());
+        plaintext[8..].copy_from_slice(&self.config.short_id);
+
+        // The whole handshake message, with the session id still zeroed, is
+        // authenticated so the hello can't be replayed with edits
+        let sealed = Aes256Gcm::new(&auth_key.into())
+            .encrypt(
+                Nonce::from_slice(&random[20..]),
+                Payload {
+                    msg: &plaintext,
+                    aad: &hello[TLS_RECORD_HEADER_LEN..],
+                },
+            )
+            .expect("AES-GCM encryption of 16 bytes cannot fail");
+        hello[SESSION_ID_OFFSET..SESSION_ID_OFFSET + 32].copy_from_slice(&sealed);
+
+        (hello, RealityClientState { secret, auth_key })
+    }
+
+    /// Perform the handshake over an established stream
+    pub async fn connect<S>(&self, mut stream: S) -> Result<RealityStream<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (hello, state) = self.client_hello();
+        stream.write_all(&hello).await?;
+
+        let (content_type, server_hello) = read_record(&mut stream).await?;
+        if content_type != CONTENT_TYPE_HANDSHAKE {
+            return Err(Error::DPIBypassError(
+                "REALITY server did not answer with a ServerHello".to_string(),
+            ));
+        }
+        let server_key = server_key_share(&server_hello).ok_or_else(|| {
+            Error::DPIBypassError("REALITY ServerHello has no X25519 key share".to_string())
+        })?;
+        let ecdhe = state.secret.diffie_hellman(&PublicKey::from(server_key));
+
+        let (content_type, _) = read_record(&mut stream).await?;
+        if content_type != CONTENT_TYPE_CHANGE_CIPHER_SPEC {
+            return Err(Error::DPIBypassError(
+                "REALITY server sent an unexpected record".to_string(),
+            ));
+        }
+
+        let mut channel = RealityStream::new(
+            stream,
+            traffic_keys(&state.auth_key, ecdhe.as_bytes(), b"reality c2s"),
+            traffic_keys(&state.auth_key, ecdhe.as_bytes(), b"reality s2c"),
+        );
+
+        // Only a server holding the private key can produce a flight that
+        // decrypts, so this doubles as server authentication
+        let flight = channel.recv().await.map_err(|_| {
+            Error::DPIBypassError("REALITY server failed authentication".to_string())
+        })?;
+        // The flight's first byte carries the compression the server chose
+        let chosen = flight.first().copied().and_then(Compression::from_id);
+        let compression = match chosen {
+            Some(method)
+                if method == Compression::None || self.config.compression.contains(&method) =>
+            {
+                method
+            }
+            _ => {
+                return Err(Error::DPIBypassError(
+                    "REALITY server chose a compression that was not offered".to_string(),
+                ))
+            }
+        };
+
+        channel
+            .stream
+            .write_all(&[
+                CONTENT_TYPE_CHANGE_CIPHER_SPEC,
+                0x03,
+                0x03,
+                0x00,
+                0x01,
+                0x01,
+            ])
+            .await?;
+        channel.send(&[0u8; CLIENT_FINISHED_LEN]).await?;
+        channel.compression = compression;
+        Ok(channel)
+    }
+}
+
+/// Details of a client that passed authentication
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuthenticatedHello {
+    pub short_id: [u8; 8],
+    pub server_name: String,
+    /// Compression negotiated for the session
+    pub compression: Compression,
+    auth_key: [u8; 32],
+    client_key: [u8; 32],
+    session_id: Vec<u8>,
+}
+
+/// Outcome of accepting a connection
+pub enum RealityAccept<S> {
+    /// A REALITY client; the stream carries its traffic
+    Authenticated(Box<RealityStream<S>>, AuthenticatedHello),
+    /// Anything else, relayed to `dest` until either side closed
+    Fallback,
+}
+
+/// REALITY server
+pub struct RealityServer {
+    config: RealityServerConfig,
+}
+
+impl RealityServer {
+    /// Create a server from configuration
+    pub fn new(config: RealityServerConfig) -> Self {
+        RealityServer { config }
+    }
+
+    /// Check whether a ClientHello record comes from a REALITY client
+    pub fn authenticate(&self, hello: &[u8]) -> Option<AuthenticatedHello> {
+        let parsed = parse_client_hello(hello).ok()?;
+        let server_name = parsed.server_name.clone()?;
+        if !self
+            .config
+            .server_names
+            .iter()
+            .any(|name| name.eq_ignore_ascii_case(&server_name))
+        {
+            return None;
+        }
+        if parsed.session_id.len() != 32 {
+            return None;
+        }
+        let client_key: [u8; 32] = parsed.key_share(hello, GROUP_X25519)?.try_into().ok()?;
+
+        let secret = StaticSecret::from(self.config.private_key);
+        let shared = secret.diffie_hellman(&PublicKey::from(client_key));
+        let auth_key = auth_key(shared.as_bytes(), &parsed.random);
+
+        let mut aad = hello[TLS_RECORD_HEADER_LEN..].to_vec();
+        let session_id_at = SESSION_ID_OFFSET - TLS_RECORD_HEADER_LEN;
+        aad[session_id_at..session_id_at + 32].fill(0);
+
+        let plaintext = Aes256Gcm::new(&auth_key.into())
+            .decrypt(
+                Nonce::from_slice(&parsed.random[20..]),
+                Payload {
+                    msg: &parsed.session_id,
+                    aad: &aad,
+                },
+            )
+            .ok()?;
+        if plaintext.len() != 16 || plaintext[..3] != REALITY_VERSION {
+            return None;
+        }
+
+        let client_time =
+            u32::from_be_bytes([plaintext[4], plaintext[5], plaintext[6], plaintext[7]]);
+        let drift = (unix_time() as u32).abs_diff(client_time) as u64;
+        if drift > self.config.max_time_diff.as_secs() {
+            return None;
+        }
+
+        let mut short_id = [0u8; 8];
+        short_id.copy_from_slice(&plaintext[8..]);
+        if !self.config.short_ids.contains(&short_id) {
+            return None;
+        }
+
+        Some(AuthenticatedHello {
+            short_id,
+            server_name,
+            compression: compression::negotiate(plaintext[3], &self.config.compression),
+            auth_key,
+            client_key,
+            session_id: parsed.session_id,
+        })
+    }
+
+    /// Accept a connection, relaying it to `dest` unless it authenticates
+    pub async fn accept<S>(&self, mut stream: S) -> Result<RealityAccept<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (content_type, body) = read_record(&mut stream).await?;
+        let mut hello = vec![content_type, 0x03, 0x01];
+        hello.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        hello.extend_from_slice(&body);
+
+        let Some(client) = self.authenticate(&hello) else {
+            self.relay_to_dest(stream, &hello).await?;
+            return Ok(RealityAccept::Fallback);
+        };
+
+        let secret = StaticSecret::from(rand::thread_rng().gen::<[u8; 32]>());
+        let server_hello = server_hello(&client.session_id, &PublicKey::from(&secret).to_bytes());
+        stream.write_all(&server_hello).await?;
+        stream
+            .write_all(&[
+                CONTENT_TYPE_CHANGE_CIPHER_SPEC,
+                0x03,
+                0x03,
+                0x00,
+                0x01,
+                0x01,
+            ])
+            .await?;
+
+        let ecdhe = secret.diffie_hellman(&PublicKey::from(client.client_key));
+        let mut channel = RealityStream::new(
+            stream,
+            traffic_keys(&client.auth_key, ecdhe.as_bytes(), b"reality s2c"),
+            traffic_keys(&client.auth_key, ecdhe.as_bytes(), b"reality c2s"),
+        );
+
+        let flight_len = rand::thread_rng().gen_range(self.config.server_flight_len.clone());
+        let mut flight = vec![0u8; flight_len.max(1)];
+        flight[0] = client.compression.id();
+        channel.send(&flight).await?;
+
+        let (content_type, _) = read_record(&mut channel.stream).await?;
+        if content_type != CONTENT_TYPE_CHANGE_CIPHER_SPEC {
+            return Err(Error::DPIBypassError(
+                "REALITY client sent an unexpected record".to_string(),
+            ));
+        }
+        channel.recv().await?;
+        channel.compression = client.compression;
+
+        Ok(RealityAccept::Authenticated(Box::new(channel), client))
+    }
+
+    /// Splice the connection to the real target, replaying the ClientHello
+    async fn relay_to_dest<S>(&self, mut stream: S, hello: &[u8]) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut dest = TcpStream::connect(&self.config.dest).await?;
+        dest.write_all(hello).await?;
+        tokio::io::copy_bidirectional(&mut stream, &mut dest).await?;
+        Ok(())
+    }
+}
+
+/// Build a TLS 1.3 ServerHello record echoing the client's session id
+fn server_hello(session_id: &[u8], public_key: &[u8; 32]) -> Vec<u8> {
+    let mut extensions = Vec::new();
+    extensions.extend_from_slice(&EXT_SUPPORTED_VERSIONS.to_be_bytes());
+    extensions.extend_from_slice(&[0x00, 0x02, 0x03, 0x04]);
+    extensions.extend_from_slice(&EXT_KEY_SHARE.to_be_bytes());
+    extensions.extend_from_slice(&36u16.to_be_bytes());
+    extensions.extend_from_slice(&GROUP_X25519.to_be_bytes());
+    extensions.extend_from_slice(&32u16.to_be_bytes());
+    extensions.extend_from_slice(public_key);
+
+    let mut body = vec![0x03, 0x03];
+    body.extend_from_slice(&rand::thread_rng().gen::<[u8; 32]>());
+    body.push(session_id.len() as u8);
+    body.extend_from_slice(session_id);
+    body.extend_from_slice(&TLS_AES_128_GCM_SHA256.to_be_bytes());
+    body.push(0x00); // null compression
+    body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+    body.extend_from_slice(&extensions);
+
+    let mut handshake = vec![HANDSHAKE_TYPE_SERVER_HELLO];
+    handshake.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+    handshake.extend_from_slice(&body);
+
+    let mut record = vec![CONTENT_TYPE_HANDSHAKE, 0x03, 0x03];
+    record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+    record.extend_from_slice(&handshake);
+    record
+}
+
+/// Extract the X25519 key share from a ServerHello handshake message
+fn server_key_share(handshake: &[u8]) -> Option<[u8; 32]> {
+    if handshake.first() != Some(&HANDSHAKE_TYPE_SERVER_HELLO) {
+        return None;
+    }
+    // type(1) + length(3) + version(2) + random(32)
+    let mut pos = HANDSHAKE_HEADER_LEN + 2 + 32;
+    let session_id_len = *handshake.get(pos)? as usize;
+    pos += 1 + session_id_len + 2 + 1; // session id, cipher suite, compression
+    pos += 2; // extensions length
+
+    while pos + 4 <= handshake.len() {
+        let ext_type = u16::from_be_bytes([handshake[pos], handshake[pos + 1]]);
+        let ext_len = u16::from_be_bytes([handshake[pos + 2], handshake[pos + 3]]) as usize;
+        let data = handshake.get(pos + 4..pos + 4 + ext_len)?;
+        if ext_type == EXT_KEY_SHARE && data.len() == 36 && data[..2] == GROUP_X25519.to_be_bytes()
+        {
+            return data[4..].try_into().ok();
+        }
+        pos += 4 + ext_len;
+    }
+    None
+}
+
+/// Read one TLS record, returning its content type and body
+async fn read_record<S: AsyncRead + Unpin>(stream: &mut S) -> Result<(u8, Vec<u8>)> {
+    let mut header = [0u8; TLS_RECORD_HEADER_LEN];
+    stream.read_exact(&mut header).await?;
+    let len = u16::from_be_bytes([header[3], header[4]]) as usize;
+    if len > MAX_RECORD_LEN {
+        return Err(Error::DataError("TLS record too large".to_string()));
+    }
+    let mut body = vec![0u8; len];
+    stream.read_exact(&mut body).await?;
+    Ok((header[0], body))
+}
+
+/// Authenticated channel framed as TLS 1.3 application-data records
+pub struct RealityStream<S> {
+    stream: S,
+    send_key: Aes256Gcm,
+    send_iv: [u8; 12],
+    send_seq: u64,
+    recv_key: Aes256Gcm,
+    recv_iv: [u8; 12],
+    recv_seq: u64,
+    compression: Compression,
+}
+
+impl<S> RealityStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn new(stream: S, send: ([u8; 32], [u8; 12]), recv: ([u8; 32], [u8; 12])) -> Self {
+        RealityStream {
+            stream,
+            send_key: Aes256Gcm::new(&send.0.into()),
+            send_iv: send.1,
+            send_seq: 0,
+            recv_key: Aes256Gcm::new(&recv.0.into()),
+            recv_iv: recv.1,
+            recv_seq: 0,
+            compression: Compression::None,
+        }
+    }
+
+    /// Per-record nonce: the IV XORed with the sequence number, as in TLS 1.3
+    fn nonce(iv: &[u8; 12], seq: u64) -> [u8; 12] {
+        let mut nonce = *iv;
+        for (n, s) in nonce[4..].iter_mut().zip(seq.to_be_bytes()) {
+            *n ^= s;
+        }
+        nonce
+    }
+
+    /// Compression negotiated for the session
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    /// Encrypt and send data, split into full-size records as needed
+    pub async fn send(&mut self, data: &[u8]) -> Result<()> {
+        // Compressed records are framed separately, at one byte of overhead
+        let max_chunk = match self.compression {
+            Compression::None => 16384 - AEAD_TAG_LEN,
+            _ => 16384 - AEAD_TAG_LEN - 1,
+        };
+        for chunk in data.chunks(max_chunk) {
+            let record = compression::compress(self.compression, chunk);
+            let len = (record.len() + AEAD_TAG_LEN) as u16;
+            let header = [
+                CONTENT_TYPE_APPLICATION_DATA,
+                0x03,
+                0x03,
+                (len >> 8) as u8,
+                len as u8,
+            ];
+            let nonce = Self::nonce(&self.send_iv, self.send_seq);
+            let ciphertext = self
+                .send_key
+                .encrypt(
+                    Nonce::from_slice(&nonce),
+                    Payload {
+                        msg: &record,
+                        aad: &header,
+                    },
+                )
+                .map_err(|_| {
+                    Error::EncryptionError("REALITY record encryption failed".to_string())
+                })?;
+            self.send_seq += 1;
+
+            self.stream.write_all(&header).await?;
+            self.stream.write_all(&ciphertext).await?;
+        }
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    /// Receive and decrypt the next record
+    pub async fn recv(&mut self) -> Result<Vec<u8>> {
+        let (content_type, body) = read_record(&mut self.stream).await?;
+        if content_type != CONTENT_TYPE_APPLICATION_DATA {
+            return Err(Error::DataError(
+                "Unexpected REALITY record type".to_string(),
+            ));
+        }
+        let len = body.len() as u16;
+        let header = [content_type, 0x03, 0x03, (len >> 8) as u8, len as u8];
+        let nonce = Self::nonce(&self.recv_iv, self.recv_seq);
+        let plaintext = self
+            .recv_key
+            .decrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &body,
+                    aad: &header,
+                },
+            )
+            .map_err(|_| Error::EncryptionError("REALITY record failed to decrypt".to_string()))?;
+        self.recv_seq += 1;
+        compression::decompress(self.compression, &plaintext)
+    }
+
+    /// Get the underlying stream back
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn configs() -> (RealityClientConfig, RealityServerConfig) {
+        let (private_key, public_key) = generate_keypair();
+        let short_id = parse_short_id("0123abcd").unwrap();
+        let client = RealityClientConfig {
+            server_name: "www.example.com".to_string(),
+            public_key,
+            short_id,
+            profile: TLSProfileConfig::default(),
+            compression: Vec::new(),
+        };
+        let server = RealityServerConfig {
+            private_key,
+            short_ids: vec![short_id],
+            server_names: vec!["www.example.com".to_string()],
+            dest: "127.0.0.1:9".to_string(),
+            ..Default::default()
+        };
+        (client, server)
+    }
+
+    #[test]
+    fn test_parse_short_id() {
+        assert_eq!(parse_short_id("").unwrap(), [0u8; 8]);
+        assert_eq!(parse_short_id("ab").unwrap(), [0xab, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(parse_short_id("abc").is_err());
+        assert!(parse_short_id("zz").is_err());
+        assert!(parse_short_id("00112233445566778899").is_err());
+    }
+
+    #[test]
+    fn test_server_authenticates_client_hello() {
+        let (client_config, server_config) = configs();
+        let (hello, _) = RealityClient::new(client_config.clone()).client_hello();
+
+        let server = RealityServer::new(server_config.clone());
+        let client = server.authenticate(&hello).unwrap();
+        assert_eq!(client.short_id, client_config.short_id);
+
+        // Any edit to the hello breaks authentication
+        let mut tampered = hello.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 0x01;
+        assert!(server.authenticate(&tampered).is_none());
+
+        // Unknown short ids and other server keys are rejected
+        let other = RealityServer::new(RealityServerConfig {
+            short_ids: vec![[0xff; 8]],
+            ..server_config
+        });
+        assert!(other.authenticate(&hello).is_none());
+    }
+
+    #[test]
+    fn test_browser_hello_is_not_authenticated() {
+        let (_, server_config) = configs();
+        let hello = ClientHelloBuilder::from_profile(&TLSProfileConfig::default())
+            .server_name("www.example.com")
+            .build();
+        assert!(RealityServer::new(server_config)
+            .authenticate(&hello)
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_handshake_and_data() {
+        let (client_config, server_config) = configs();
+        let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+
+        let server = tokio::spawn(async move {
+            match RealityServer::new(server_config)
+                .accept(server_io)
+                .await
+                .unwrap()
+            {
+                RealityAccept::Authenticated(mut channel, _) => {
+                    let request = channel.recv().await.unwrap();
+                    channel.send(&request).await.unwrap();
+                }
+                RealityAccept::Fallback => panic!("client should authenticate"),
+            }
+        });
+
+        let mut channel = RealityClient::new(client_config)
+            .connect(client_io)
+            .await
+            .unwrap();
+        channel.send(b"hello through reality").await.unwrap();
+        assert_eq!(channel.recv().await.unwrap(), b"hello through reality");
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_probe_is_relayed_to_dest() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (_, mut server_config) = configs();
+        server_config.dest = target.local_addr().unwrap().to_string();
+
+        let probe = ClientHelloBuilder::from_profile(&TLSProfileConfig::default())
+            .server_name("www.example.com")
+            .build();
+        let expected = probe.clone();
+        let target_task = tokio::spawn(async move {
+            let (mut stream, _) = target.accept().await.unwrap();
+            let mut received = vec![0u8; expected.len()];
+            stream.read_exact(&mut received).await.unwrap();
+            assert_eq!(received, expected);
+            stream.write_all(b"real target").await.unwrap();
+        });
+
+        let (mut prober, server_io) = tokio::io::duplex(64 * 1024);
+        let server = tokio::spawn(async move {
+            RealityServer::new(server_config)
+                .accept(server_io)
+                .await
+                .unwrap()
+        });
+
+        prober.write_all(&probe).await.unwrap();
+        let mut reply = [0u8; 11];
+        prober.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"real target");
+        drop(prober);
+
+        target_task.await.unwrap();
+        assert!(matches!(server.await.unwrap(), RealityAccept::Fallback));
+    }
+
+    #[tokio::test]
+    async fn test_compression_negotiated() {
+        let (mut client_config, mut server_config) = configs();
+        client_config.compression = vec![Compression::Lz4, Compression::Zstd];
+        server_config.compression = vec![Compression::Zstd];
+        let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+
+        let page = b"<p>compressible text</p>\n".repeat(600);
+        let expected = page.clone();
+        let server = tokio::spawn(async move {
+            match RealityServer::new(server_config)
+                .accept(server_io)
+                .await
+                .unwrap()
+            {
+                RealityAccept::Authenticated(mut channel, client) => {
+                    assert_eq!(client.compression, Compression::Zstd);
+                    assert_eq!(channel.recv().await.unwrap(), expected);
+                }
+                RealityAccept::Fallback => panic!("client should authenticate"),
+            }
+        });
+
+        let mut channel = RealityClient::new(client_config)
+            .connect(client_io)
+            .await
+            .unwrap();
+        assert_eq!(channel.compression(), Compression::Zstd);
+        channel.send(&page).await.unwrap();
+        server.await.unwrap();
+    }
+}
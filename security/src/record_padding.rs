@@ -0,0 +1,215 @@
+//! TLS record padding
+//! Pads outgoing records up to a small set of fixed bucket sizes so that record
+//! lengths no longer reveal payload sizes to length-based classifiers
+
+use crate::config::ObfuscationConfig;
+use crate::error::{Error, Result};
+
+/// Default bucket sizes for padded record bodies
+pub const DEFAULT_BUCKETS: &[usize] = &[512, 1024, 4096, 16384];
+
+/// Largest record body TLS permits (2^14 plaintext bytes)
+pub const MAX_RECORD_BODY: usize = 16384;
+
+const RECORD_HEADER_LEN: usize = 5;
+const CONTENT_TYPE_APPLICATION_DATA: u8 = 0x17;
+
+/// Each padded body starts with the real payload length
+const LENGTH_PREFIX_LEN: usize = 2;
+
+/// Record padding engine
+#[derive(Clone, Debug)]
+pub struct RecordPadder {
+    buckets: Vec<usize>,
+}
+
+impl RecordPadder {
+    /// Create a padder for the given bucket sizes
+    pub fn new(mut buckets: Vec<usize>) -> Result<Self> {
+        buckets.sort_unstable();
+        buckets.dedup();
+
+        if buckets.is_empty() {
+            return Err(Error::ConfigError(
+                "record padding needs at least one bucket".to_string(),
+            ));
+        }
+        if buckets[0] <= LENGTH_PREFIX_LEN {
+            return Err(Error::ConfigError(format!(
+                "record padding buckets must be larger than {} bytes",
+                LENGTH_PREFIX_LEN
+            )));
+        }
+        if buckets[buckets.len() - 1] > MAX_RECORD_BODY {
+            return Err(Error::ConfigError(format!(
+                "record padding buckets must not exceed {} bytes",
+                MAX_RECORD_BODY
+            )));
+        }
+
+        Ok(RecordPadder { buckets })
+    }
+
+    /// Create a padder from the obfuscation settings
+    pub fn from_config(config: &ObfuscationConfig) -> Result<Self> {
+        Self::new(config.record_padding_buckets.clone())
+    }
+
+    /// Get the configured buckets, smallest first
+    pub fn buckets(&self) -> &[usize] {
+        &self.buckets
+    }
+
+    /// Smallest bucket that fits `payload_len` bytes plus the length prefix
+    pub fn bucket_for(&self, payload_len: usize) -> usize {
+        let needed = payload_len + LENGTH_PREFIX_LEN;
+        self.buckets
+            .iter()
+            .copied()
+            .find(|&bucket| bucket >= needed)
+            .unwrap_or(self.buckets[self.buckets.len() - 1])
+    }
+
+    /// Largest payload that fits in a single padded record
+    fn max_payload(&self) -> usize {
+        self.buckets[self.buckets.len() - 1] - LENGTH_PREFIX_LEN
+    }
+
+    /// Split data into application-data records whose bodies are padded to bucket sizes
+    pub fn pad(&self, data: &[u8]) -> Vec<u8> {
+        let mut result = Vec::new();
+
+        for chunk in data.chunks(self.max_payload()) {
+            let bucket = self.bucket_for(chunk.len());
+
+            result.push(CONTENT_TYPE_APPLICATION_DATA);
+            result.extend_from_slice(&[0x03, 0x03]);
+            result.extend_from_slice(&(bucket as u16).to_be_bytes());
+            result.extend_from_slice(&(chunk.len() as u16).to_be_bytes());
+            result.extend_from_slice(chunk);
+            result.resize(result.len() + bucket - LENGTH_PREFIX_LEN - chunk.len(), 0x00);
+        }
+
+        result
+    }
+
+    /// A record in the smallest bucket carrying no payload, which `unpad`
+    /// drops
+    pub fn padding_record(&self) -> Vec<u8> {
+        let bucket = self.buckets[0];
+        let mut record = vec![CONTENT_TYPE_APPLICATION_DATA, 0x03, 0x03];
+        record.extend_from_slice(&(bucket as u16).to_be_bytes());
+        record.resize(RECORD_HEADER_LEN + bucket, 0x00);
+        record
+    }
+
+    /// Strip record framing and padding added by `pad`
+    pub fn unpad(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut result = Vec::new();
+        let mut offset = 0;
+
+        while offset < data.len() {
+            if offset + RECORD_HEADER_LEN > data.len() {
+                return Err(Error::DataError("Truncated padded record header".to_string()));
+            }
+            let body_len = u16::from_be_bytes([data[offset + 3], data[offset + 4]]) as usize;
+            let body_start = offset + RECORD_HEADER_LEN;
+            let body_end = body_start + body_len;
+            if body_end > data.len() || body_len < LENGTH_PREFIX_LEN {
+                return Err(Error::DataError("Truncated padded record body".to_string()));
+            }
+
+            let payload_len = u16::from_be_bytes([data[body_start], data[body_start + 1]]) as usize;
+            let payload_start = body_start + LENGTH_PREFIX_LEN;
+            if payload_start + payload_len > body_end {
+                return Err(Error::DataError(
+                    "Padded record payload exceeds record body".to_string(),
+                ));
+            }
+
+            result.extend_from_slice(&data[payload_start..payload_start + payload_len]);
+            offset = body_end;
+        }
+
+        Ok(result)
+    }
+}
+
+impl Default for RecordPadder {
+    fn default() -> Self {
+        RecordPadder {
+            buckets: DEFAULT_BUCKETS.to_vec(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record_body_lengths(data: &[u8]) -> Vec<usize> {
+        let mut lengths = Vec::new();
+        let mut offset = 0;
+        while offset < data.len() {
+            let len = u16::from_be_bytes([data[offset + 3], data[offset + 4]]) as usize;
+            lengths.push(len);
+            offset += RECORD_HEADER_LEN + len;
+        }
+        lengths
+    }
+
+    #[test]
+    fn test_bucket_for() {
+        let padder = RecordPadder::default();
+        assert_eq!(padder.bucket_for(10), 512);
+        assert_eq!(padder.bucket_for(510), 512);
+        assert_eq!(padder.bucket_for(511), 1024);
+        assert_eq!(padder.bucket_for(5000), 16384);
+    }
+
+    #[test]
+    fn test_records_use_bucket_sizes() {
+        let padder = RecordPadder::default();
+        let data = vec![0x42; 20000];
+        let padded = padder.pad(&data);
+
+        for len in record_body_lengths(&padded) {
+            assert!(DEFAULT_BUCKETS.contains(&len));
+        }
+    }
+
+    #[test]
+    fn test_pad_round_trip() {
+        let padder = RecordPadder::new(vec![64, 256]).unwrap();
+        for size in [0, 1, 62, 63, 300, 1000] {
+            let data: Vec<u8> = (0..size).map(|i| i as u8).collect();
+            let padded = padder.pad(&data);
+            assert_eq!(padder.unpad(&padded).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn test_padding_record_carries_nothing() {
+        let padder = RecordPadder::new(vec![64, 256]).unwrap();
+        let record = padder.padding_record();
+        assert_eq!(record_body_lengths(&record), vec![64]);
+
+        let mut padded = padder.pad(b"hello");
+        padded.extend(padder.padding_record());
+        assert_eq!(padder.unpad(&padded).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn test_invalid_buckets() {
+        assert!(RecordPadder::new(Vec::new()).is_err());
+        assert!(RecordPadder::new(vec![2]).is_err());
+        assert!(RecordPadder::new(vec![512, 20000]).is_err());
+    }
+
+    #[test]
+    fn test_unpad_rejects_truncated_input() {
+        let padder = RecordPadder::default();
+        let padded = padder.pad(b"hello");
+        assert!(padder.unpad(&padded[..padded.len() - 1]).is_err());
+    }
+}
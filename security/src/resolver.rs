@@ -0,0 +1,861 @@
+//! Encrypted DNS resolver
+//! Resolves destination hostnames over DNS-over-HTTPS, -TLS or -QUIC before
+//! connecting, so the crate's own connections are never betrayed by (or fed
+//! poisoned answers from) plaintext DNS
+
+use crate::dns_tunnel::{
+    read_name, read_u16, write_name, CLASS_IN, DNS_HEADER_LEN, EDNS_UDP_SIZE, FLAG_QR, FLAG_RD,
+    MAX_LABEL_LEN, RCODE_MASK, TYPE_OPT,
+};
+use crate::error::{Error, Result};
+use crate::privacy::redact;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::Bytes;
+use hyper_util::rt::TokioIo;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_AAAA: u16 = 28;
+const RCODE_NXDOMAIN: u16 = 3;
+
+const DNS_MESSAGE: &str = "application/dns-message";
+const MAX_DOH_RESPONSE: usize = 64 * 1024;
+const DOQ_ALPN: &[u8] = b"doq";
+
+/// Bounds applied to answer TTLs before caching
+const MIN_CACHE_TTL: u32 = 30;
+const MAX_CACHE_TTL: u32 = 24 * 3600;
+
+/// Wire-format query for `name`; ID 0 as RFC 8484 asks, for HTTP caching
+pub fn build_query(name: &str, qtype: u16) -> Result<Vec<u8>> {
+    let name = name.trim_end_matches('.');
+    let labels: Vec<&str> = name.split('.').collect();
+    if name.is_empty()
+        || name.len() > 253
+        || labels
+            .iter()
+            .any(|l| l.is_empty() || l.len() > MAX_LABEL_LEN)
+    {
+        return Err(Error::DataError(format!("Invalid hostname '{}'", name)));
+    }
+
+    let mut packet = Vec::with_capacity(DNS_HEADER_LEN + name.len() + 16);
+    packet.extend_from_slice(&0u16.to_be_bytes());
+    packet.extend_from_slice(&FLAG_RD.to_be_bytes());
+    packet.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 1]); // one question, one OPT
+    write_name(&mut packet, &labels);
+    packet.extend_from_slice(&qtype.to_be_bytes());
+    packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+    packet.push(0);
+    packet.extend_from_slice(&TYPE_OPT.to_be_bytes());
+    packet.extend_from_slice(&EDNS_UDP_SIZE.to_be_bytes());
+    packet.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+    Ok(packet)
+}
+
+/// Addresses from an answer and the smallest TTL among them
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DnsAnswer {
+    pub addresses: Vec<IpAddr>,
+    pub ttl: u32,
+}
+
+/// Extract the `qtype` addresses from a response; NXDOMAIN gives none
+pub fn parse_response(packet: &[u8], qtype: u16) -> Result<DnsAnswer> {
+    let malformed = || Error::DataError("Malformed DNS response".to_string());
+    let flags = read_u16(packet, 2).ok_or_else(malformed)?;
+    if flags & FLAG_QR == 0 {
+        return Err(malformed());
+    }
+    match flags & RCODE_MASK {
+        0 => {}
+        RCODE_NXDOMAIN => return Ok(DnsAnswer::default()),
+        rcode => {
+            return Err(Error::DataError(format!(
+                "DNS query failed with rcode {}",
+                rcode
+            )))
+        }
+    }
+
+    let questions = read_u16(packet, 4).ok_or_else(malformed)?;
+    let answers = read_u16(packet, 6).ok_or_else(malformed)?;
+    let mut offset = DNS_HEADER_LEN;
+    for _ in 0..questions {
+        offset = read_name(packet, offset).ok_or_else(malformed)?.1 + 4;
+    }
+
+    let mut answer = DnsAnswer {
+        addresses: Vec::new(),
+        ttl: u32::MAX,
+    };
+    for _ in 0..answers {
+        offset = read_name(packet, offset).ok_or_else(malformed)?.1;
+        let rtype = read_u16(packet, offset).ok_or_else(malformed)?;
+        let ttl = packet
+            .get(offset + 4..offset + 8)
+            .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+            .ok_or_else(malformed)?;
+        let rdlen = read_u16(packet, offset + 8).ok_or_else(malformed)? as usize;
+        let rdata = packet
+            .get(offset + 10..offset + 10 + rdlen)
+            .ok_or_else(malformed)?;
+        offset += 10 + rdlen;
+
+        // CNAMEs in the chain are skipped; their targets' records follow
+        let address = match (rtype, rdata.len()) {
+            (TYPE_A, 4) if rtype == qtype => {
+                IpAddr::V4(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]))
+            }
+            (TYPE_AAAA, 16) if rtype == qtype => {
+                let octets: [u8; 16] = rdata.try_into().unwrap();
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            _ => continue,
+        };
+        answer.addresses.push(address);
+        answer.ttl = answer.ttl.min(ttl);
+    }
+    if answer.addresses.is_empty() {
+        answer.ttl = 0;
+    }
+    Ok(answer)
+}
+
+/// A DNS-over-HTTPS server
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DohEndpoint {
+    /// Query URL, e.g. `https://cloudflare-dns.com/dns-query`
+    pub url: String,
+    /// Address to connect to; without it the URL's host is looked up with
+    /// the system resolver, leaking that one name
+    pub address: Option<SocketAddr>,
+    /// TLS SNI to present instead of the URL's host, for endpoints reachable
+    /// through a CDN front; the Host header still names the real endpoint
+    pub front_sni: Option<String>,
+}
+
+impl DohEndpoint {
+    pub fn new(url: &str) -> Self {
+        DohEndpoint {
+            url: url.to_string(),
+            address: None,
+            front_sni: None,
+        }
+    }
+
+    /// Connect to a fixed address instead of looking the host up
+    pub fn with_address(mut self, address: SocketAddr) -> Self {
+        self.address = Some(address);
+        self
+    }
+
+    /// Present `sni` in the TLS handshake
+    pub fn with_front(mut self, sni: &str) -> Self {
+        self.front_sni = Some(sni.to_string());
+        self
+    }
+}
+
+/// An encrypted-DNS server and the protocol to reach it with
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ResolverEndpoint {
+    /// DNS-over-HTTPS (RFC 8484)
+    Doh(DohEndpoint),
+    /// DNS-over-TLS (RFC 7858), usually on port 853
+    Dot {
+        address: SocketAddr,
+        server_name: String,
+    },
+    /// DNS-over-QUIC (RFC 9250), usually on UDP port 853
+    Doq {
+        address: SocketAddr,
+        server_name: String,
+    },
+}
+
+impl ResolverEndpoint {
+    pub fn dot(address: SocketAddr, server_name: &str) -> Self {
+        ResolverEndpoint::Dot {
+            address,
+            server_name: server_name.to_string(),
+        }
+    }
+
+    pub fn doq(address: SocketAddr, server_name: &str) -> Self {
+        ResolverEndpoint::Doq {
+            address,
+            server_name: server_name.to_string(),
+        }
+    }
+
+    /// Short description for logs
+    pub fn describe(&self) -> String {
+        match self {
+            ResolverEndpoint::Doh(endpoint) => endpoint.url.clone(),
+            ResolverEndpoint::Dot {
+                address,
+                server_name,
+            } => format!("tls://{}@{}", server_name, address),
+            ResolverEndpoint::Doq {
+                address,
+                server_name,
+            } => format!("quic://{}@{}", server_name, address),
+        }
+    }
+}
+
+impl From<DohEndpoint> for ResolverEndpoint {
+    fn from(endpoint: DohEndpoint) -> Self {
+        ResolverEndpoint::Doh(endpoint)
+    }
+}
+
+/// Configuration for the resolver
+#[derive(Clone, Debug)]
+pub struct ResolverConfig {
+    /// Tried in order until one answers; endpoints that fail are moved to
+    /// the back so a blocked protocol stops costing a timeout per query
+    pub endpoints: Vec<ResolverEndpoint>,
+    /// Per-endpoint query timeout
+    pub timeout: Duration,
+    /// Also ask for AAAA records
+    pub query_ipv6: bool,
+    pub max_cache_entries: usize,
+}
+
+impl Default for ResolverConfig {
+    fn default() -> Self {
+        ResolverConfig {
+            endpoints: vec![
+                DohEndpoint::new("https://cloudflare-dns.com/dns-query")
+                    .with_address(SocketAddr::from(([1, 1, 1, 1], 443)))
+                    .into(),
+                ResolverEndpoint::dot(SocketAddr::from(([1, 1, 1, 1], 853)), "cloudflare-dns.com"),
+                ResolverEndpoint::doq(
+                    SocketAddr::from(([94, 140, 14, 14], 853)),
+                    "dns.adguard-dns.com",
+                ),
+                DohEndpoint::new("https://dns.google/dns-query")
+                    .with_address(SocketAddr::from(([8, 8, 8, 8], 443)))
+                    .into(),
+                ResolverEndpoint::dot(SocketAddr::from(([8, 8, 8, 8], 853)), "dns.google"),
+                DohEndpoint::new("https://dns.quad9.net/dns-query")
+                    .with_address(SocketAddr::from(([9, 9, 9, 9], 443)))
+                    .into(),
+            ],
+            timeout: Duration::from_secs(5),
+            query_ipv6: true,
+            max_cache_entries: 1024,
+        }
+    }
+}
+
+struct CacheEntry {
+    addresses: Vec<IpAddr>,
+    expires: Instant,
+}
+
+/// Caching encrypted-DNS resolver
+pub struct Resolver {
+    config: ResolverConfig,
+    tls: TlsConnector,
+    quic: quinn::ClientConfig,
+    /// Indices into `config.endpoints`, most recently reliable first
+    order: Mutex<Vec<usize>>,
+    cache: Mutex<HashMap<(String, u16), CacheEntry>>,
+}
+
+impl Resolver {
+    pub fn new(config: ResolverConfig) -> Result<Self> {
+        if config.endpoints.is_empty() {
+            return Err(Error::ConfigError(
+                "Resolver needs at least one endpoint".to_string(),
+            ));
+        }
+        for endpoint in &config.endpoints {
+            match endpoint {
+                ResolverEndpoint::Doh(endpoint) => {
+                    let uri: hyper::Uri = endpoint.url.parse().map_err(|e| {
+                        Error::ConfigError(format!("Invalid DoH URL {}: {}", endpoint.url, e))
+                    })?;
+                    if uri.scheme_str() != Some("https") || uri.host().is_none() {
+                        return Err(Error::ConfigError(format!(
+                            "DoH URL must be https with a host: {}",
+                            endpoint.url
+                        )));
+                    }
+                }
+                ResolverEndpoint::Dot { server_name, .. }
+                | ResolverEndpoint::Doq { server_name, .. } => {
+                    ServerName::try_from(server_name.as_str()).map_err(|e| {
+                        Error::ConfigError(format!("Invalid server name {}: {}", server_name, e))
+                    })?;
+                }
+            }
+        }
+
+        let mut roots = RootCertStore::empty();
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        let tls_config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        Ok(Resolver {
+            order: Mutex::new((0..config.endpoints.len()).collect()),
+            config,
+            tls: TlsConnector::from(Arc::new(tls_config)),
+            quic: quic_client_config()?,
+            cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn config(&self) -> &ResolverConfig {
+        &self.config
+    }
+
+    /// Addresses for `host`, IPv4 first; IP literals are returned as-is
+    pub async fn resolve(&self, host: &str) -> Result<Vec<IpAddr>> {
+        if let Ok(ip) = host
+            .trim_matches(|c| c == '[' || c == ']')
+            .parse::<IpAddr>()
+        {
+            return Ok(vec![ip]);
+        }
+
+        let (v4, v6) = if self.config.query_ipv6 {
+            tokio::join!(self.query(host, TYPE_A), self.query(host, TYPE_AAAA))
+        } else {
+            (self.query(host, TYPE_A).await, Ok(Vec::new()))
+        };
+
+        let addresses = match (v4, v6) {
+            (Err(e), Err(_)) => return Err(e),
+            (v4, v6) => {
+                let mut addresses = v4.unwrap_or_default();
+                addresses.extend(v6.unwrap_or_default());
+                addresses
+            }
+        };
+        if addresses.is_empty() {
+            return Err(Error::DataError(format!("No addresses for {}", host)));
+        }
+        Ok(addresses)
+    }
+
+    /// Socket addresses for `host:port`
+    pub async fn lookup(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        Ok(self
+            .resolve(host)
+            .await?
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect())
+    }
+
+    /// Resolve `host` and connect to the first address that accepts
+    pub async fn connect(&self, host: &str, port: u16) -> Result<TcpStream> {
+        let mut last_error = None;
+        for addr in self.lookup(host, port).await? {
+            match tokio::time::timeout(self.config.timeout, TcpStream::connect(addr)).await {
+                Ok(Ok(stream)) => return Ok(stream),
+                Ok(Err(e)) => last_error = Some(e.into()),
+                Err(_) => last_error = Some(Error::IoError(std::io::ErrorKind::TimedOut.into())),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| Error::DataError(format!("No addresses for {}", host))))
+    }
+
+    /// Records of one type, from cache or the first endpoint that answers
+    pub async fn query(&self, name: &str, qtype: u16) -> Result<Vec<IpAddr>> {
+        let key = (name.trim_end_matches('.').to_ascii_lowercase(), qtype);
+        if let Some(addresses) = self.cached(&key) {
+            return Ok(addresses);
+        }
+
+        let query = build_query(&key.0, qtype)?;
+        let order = self.order.lock().clone();
+        let mut last_error = None;
+        for index in order {
+            let endpoint = &self.config.endpoints[index];
+            let exchange = self.exchange(endpoint, &query);
+            let result = match tokio::time::timeout(self.config.timeout, exchange).await {
+                Ok(result) => result.and_then(|response| parse_response(&response, qtype)),
+                Err(_) => Err(Error::IoError(std::io::ErrorKind::TimedOut.into())),
+            };
+            match result {
+                Ok(answer) => {
+                    self.store(key, &answer);
+                    return Ok(answer.addresses);
+                }
+                Err(e) => {
+                    log::debug!("DNS query to {} failed: {}", endpoint.describe(), e);
+                    self.demote(index);
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap())
+    }
+
+    /// Resolve `host` through both the system resolver and this one and
+    /// compare the answers
+    pub async fn check_poisoning(&self, host: &str) -> Result<PoisoningCheck> {
+        let (system, encrypted) = tokio::join!(
+            tokio::time::timeout(self.config.timeout, tokio::net::lookup_host((host, 0))),
+            self.resolve(host)
+        );
+        let system: Vec<IpAddr> = match system {
+            Ok(Ok(addrs)) => addrs.map(|addr| addr.ip()).collect(),
+            _ => Vec::new(),
+        };
+        let encrypted = encrypted.unwrap_or_default();
+        let verdict = classify_answers(&system, &encrypted);
+        if verdict.is_poisoned() {
+            log::warn!(
+                "DNS for {} looks poisoned ({:?}): system {:?}, encrypted {:?}",
+                redact(host),
+                verdict,
+                redact(&system),
+                redact(&encrypted)
+            );
+        }
+        Ok(PoisoningCheck {
+            host: host.to_string(),
+            system,
+            encrypted,
+            verdict,
+        })
+    }
+
+    /// Endpoints in the order the next query will try them
+    pub fn endpoint_order(&self) -> Vec<&ResolverEndpoint> {
+        let order = self.order.lock();
+        order.iter().map(|&i| &self.config.endpoints[i]).collect()
+    }
+
+    fn demote(&self, index: usize) {
+        let mut order = self.order.lock();
+        order.retain(|&i| i != index);
+        order.push(index);
+    }
+
+    fn cached(&self, key: &(String, u16)) -> Option<Vec<IpAddr>> {
+        let cache = self.cache.lock();
+        cache
+            .get(key)
+            .filter(|entry| entry.expires > Instant::now())
+            .map(|entry| entry.addresses.clone())
+    }
+
+    fn store(&self, key: (String, u16), answer: &DnsAnswer) {
+        let ttl = answer.ttl.clamp(MIN_CACHE_TTL, MAX_CACHE_TTL);
+        let mut cache = self.cache.lock();
+        if cache.len() >= self.config.max_cache_entries {
+            let now = Instant::now();
+            cache.retain(|_, entry| entry.expires > now);
+        }
+        if cache.len() >= self.config.max_cache_entries {
+            let oldest = cache
+                .iter()
+                .min_by_key(|(_, entry)| entry.expires)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                cache.remove(&oldest);
+            }
+        }
+        cache.insert(
+            key,
+            CacheEntry {
+                addresses: answer.addresses.clone(),
+                expires: Instant::now() + Duration::from_secs(ttl as u64),
+            },
+        );
+    }
+
+    async fn exchange(&self, endpoint: &ResolverEndpoint, query: &[u8]) -> Result<Vec<u8>> {
+        match endpoint {
+            ResolverEndpoint::Doh(endpoint) => self.doh_exchange(endpoint, query).await,
+            ResolverEndpoint::Dot {
+                address,
+                server_name,
+            } => self.dot_exchange(*address, server_name, query).await,
+            ResolverEndpoint::Doq {
+                address,
+                server_name,
+            } => self.doq_exchange(*address, server_name, query).await,
+        }
+    }
+
+    /// One length-prefixed query over a fresh TLS connection
+    async fn dot_exchange(
+        &self,
+        address: SocketAddr,
+        server_name: &str,
+        query: &[u8],
+    ) -> Result<Vec<u8>> {
+        let server_name = ServerName::try_from(server_name.to_string())
+            .map_err(|e| Error::ConfigError(format!("Invalid DoT server name: {}", e)))?;
+        let tcp = TcpStream::connect(address).await?;
+        let mut tls = self.tls.connect(server_name, tcp).await?;
+
+        tls.write_all(&length_prefixed(query)).await?;
+        tls.flush().await?;
+        let len = tls.read_u16().await? as usize;
+        let mut response = vec![0u8; len];
+        tls.read_exact(&mut response).await?;
+        Ok(response)
+    }
+
+    /// One query on its own bidirectional stream of a fresh QUIC connection
+    async fn doq_exchange(
+        &self,
+        address: SocketAddr,
+        server_name: &str,
+        query: &[u8],
+    ) -> Result<Vec<u8>> {
+        let failed =
+            |e: &dyn std::fmt::Display| Error::DataError(format!("DoQ query failed: {}", e));
+        let local = if address.is_ipv6() {
+            SocketAddr::from(([0u16; 8], 0))
+        } else {
+            SocketAddr::from(([0u8; 4], 0))
+        };
+        let mut endpoint = quinn::Endpoint::client(local)?;
+        endpoint.set_default_client_config(self.quic.clone());
+
+        let conn = endpoint
+            .connect(address, server_name)
+            .map_err(|e| failed(&e))?
+            .await
+            .map_err(|e| failed(&e))?;
+        let (mut send, mut recv) = conn.open_bi().await.map_err(|e| failed(&e))?;
+        send.write_all(&length_prefixed(query))
+            .await
+            .map_err(|e| failed(&e))?;
+        send.finish().map_err(|e| failed(&e))?;
+        let framed = recv
+            .read_to_end(2 + u16::MAX as usize)
+            .await
+            .map_err(|e| failed(&e))?;
+        conn.close(0u32.into(), b"");
+        endpoint.close(0u32.into(), b"");
+
+        match read_u16(&framed, 0) {
+            Some(len) if framed.len() == 2 + len as usize => Ok(framed[2..].to_vec()),
+            _ => Err(failed(&"bad message length")),
+        }
+    }
+
+    /// POST one query to a DoH endpoint and return the wire-format answer
+    async fn doh_exchange(&self, endpoint: &DohEndpoint, query: &[u8]) -> Result<Vec<u8>> {
+        let failed = |e: &dyn std::fmt::Display| {
+            Error::DataError(format!("DoH query to {} failed: {}", endpoint.url, e))
+        };
+        let uri: hyper::Uri = endpoint.url.parse().map_err(|e| failed(&e))?;
+        let host = uri.host().unwrap_or_default().to_string();
+        let port = uri.port_u16().unwrap_or(443);
+
+        let tcp = match endpoint.address {
+            Some(address) => TcpStream::connect(address).await?,
+            None => TcpStream::connect((host.as_str(), port)).await?,
+        };
+        let sni = endpoint.front_sni.clone().unwrap_or_else(|| host.clone());
+        let server_name = ServerName::try_from(sni)
+            .map_err(|e| Error::ConfigError(format!("Invalid DoH server name: {}", e)))?;
+        let tls = self.tls.connect(server_name, tcp).await?;
+
+        let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(tls))
+            .await
+            .map_err(|e| failed(&e))?;
+        tokio::spawn(async move {
+            if let Err(e) = conn.await {
+                log::debug!("DoH connection closed: {}", e);
+            }
+        });
+
+        let path = uri.path_and_query().map_or("/", |p| p.as_str());
+        let request = http::Request::post(path)
+            .header(http::header::HOST, host.as_str())
+            .header(http::header::CONTENT_TYPE, DNS_MESSAGE)
+            .header(http::header::ACCEPT, DNS_MESSAGE)
+            .body(Full::new(Bytes::copy_from_slice(query)))
+            .map_err(|e| failed(&e))?;
+        let response = sender.send_request(request).await.map_err(|e| failed(&e))?;
+        if !response.status().is_success() {
+            return Err(failed(&response.status()));
+        }
+
+        let body = Limited::new(response.into_body(), MAX_DOH_RESPONSE)
+            .collect()
+            .await
+            .map_err(|e| failed(&e))?;
+        Ok(body.to_bytes().to_vec())
+    }
+}
+
+/// Addresses Iranian resolvers hand out for filtered names
+const INJECTED_ADDRESSES: &[Ipv4Addr] = &[
+    Ipv4Addr::new(10, 10, 34, 34),
+    Ipv4Addr::new(10, 10, 34, 35),
+    Ipv4Addr::new(10, 10, 34, 36),
+];
+
+/// How the system resolver's answer compares to the encrypted one
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PoisoningVerdict {
+    /// The answers overlap
+    Consistent,
+    /// The system answer is a known block-page or private address
+    Injected,
+    /// The answers share no address or /24 (/48 for IPv6)
+    Divergent,
+    /// Only the encrypted resolver had an answer
+    SystemFailed,
+    /// The encrypted resolver had no answer, so nothing can be said
+    Inconclusive,
+}
+
+impl PoisoningVerdict {
+    pub fn is_poisoned(&self) -> bool {
+        matches!(
+            self,
+            PoisoningVerdict::Injected
+                | PoisoningVerdict::Divergent
+                | PoisoningVerdict::SystemFailed
+        )
+    }
+}
+
+/// Outcome of a multi-resolver cross-check for one name
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PoisoningCheck {
+    pub host: String,
+    pub system: Vec<IpAddr>,
+    pub encrypted: Vec<IpAddr>,
+    pub verdict: PoisoningVerdict,
+}
+
+impl PoisoningCheck {
+    pub fn is_poisoned(&self) -> bool {
+        self.verdict.is_poisoned()
+    }
+}
+
+fn is_injected(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            INJECTED_ADDRESSES.contains(ip)
+                || ip.is_private()
+                || ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_link_local()
+        }
+        IpAddr::V6(ip) => ip.is_loopback() || ip.is_unspecified(),
+    }
+}
+
+/// Network prefix used to match CDN answers that rotate within a range
+fn answer_prefix(ip: &IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            IpAddr::V4(Ipv4Addr::new(a, b, c, 0))
+        }
+        IpAddr::V6(ip) => {
+            let s = ip.segments();
+            IpAddr::V6(Ipv6Addr::new(s[0], s[1], s[2], 0, 0, 0, 0, 0))
+        }
+    }
+}
+
+/// Compare a system resolver answer against an encrypted one
+pub fn classify_answers(system: &[IpAddr], encrypted: &[IpAddr]) -> PoisoningVerdict {
+    if system.iter().any(is_injected) && !encrypted.iter().any(is_injected) {
+        return PoisoningVerdict::Injected;
+    }
+    if encrypted.is_empty() {
+        return PoisoningVerdict::Inconclusive;
+    }
+    if system.is_empty() {
+        return PoisoningVerdict::SystemFailed;
+    }
+    let overlaps = system.iter().any(|ip| {
+        encrypted
+            .iter()
+            .any(|other| answer_prefix(ip) == answer_prefix(other))
+    });
+    if overlaps {
+        PoisoningVerdict::Consistent
+    } else {
+        PoisoningVerdict::Divergent
+    }
+}
+
+/// DoT and DoQ carry each message behind a two-byte length
+fn length_prefixed(message: &[u8]) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(2 + message.len());
+    framed.extend_from_slice(&(message.len() as u16).to_be_bytes());
+    framed.extend_from_slice(message);
+    framed
+}
+
+pub(crate) fn quic_client_config() -> Result<quinn::ClientConfig> {
+    let mut roots = quinn::rustls::RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    let mut tls = quinn::rustls::ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    tls.alpn_protocols = vec![DOQ_ALPN.to_vec()];
+    let crypto = quinn::crypto::rustls::QuicClientConfig::try_from(tls)
+        .map_err(|e| Error::ConfigError(format!("Invalid QUIC TLS config: {}", e)))?;
+    Ok(quinn::ClientConfig::new(Arc::new(crypto)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Turn a query into a response carrying `records` (type, rdata)
+    fn answer(query: &[u8], rcode: u16, records: &[(u16, Vec<u8>)]) -> Vec<u8> {
+        let (_, question_end) = read_name(query, DNS_HEADER_LEN).unwrap();
+        let mut packet = query[..question_end + 4].to_vec();
+        packet[2..4].copy_from_slice(&(FLAG_QR | FLAG_RD | rcode).to_be_bytes());
+        packet[6..8].copy_from_slice(&(records.len() as u16).to_be_bytes());
+        packet[10..12].copy_from_slice(&[0, 0]);
+        for (rtype, rdata) in records {
+            packet.extend_from_slice(&[0xc0, DNS_HEADER_LEN as u8]);
+            packet.extend_from_slice(&rtype.to_be_bytes());
+            packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+            packet.extend_from_slice(&300u32.to_be_bytes());
+            packet.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            packet.extend_from_slice(rdata);
+        }
+        packet
+    }
+
+    fn unreachable_resolver() -> Resolver {
+        Resolver::new(ResolverConfig {
+            endpoints: vec![
+                DohEndpoint::new("https://doh.invalid/dns-query")
+                    .with_address(SocketAddr::from(([127, 0, 0, 1], 1)))
+                    .into(),
+                ResolverEndpoint::dot(SocketAddr::from(([127, 0, 0, 1], 1)), "dot.invalid"),
+                ResolverEndpoint::doq(SocketAddr::from(([127, 0, 0, 1], 1)), "doq.invalid"),
+            ],
+            timeout: Duration::from_millis(500),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_parse_answers() {
+        let query = build_query("example.com", TYPE_A).unwrap();
+        let cname = (5u16, vec![3, b'c', b'd', b'n', 0xc0, 12]);
+        let response = answer(&query, 0, &[cname, (TYPE_A, vec![93, 184, 216, 34])]);
+        let parsed = parse_response(&response, TYPE_A).unwrap();
+        assert_eq!(parsed.addresses, vec![IpAddr::from([93, 184, 216, 34])]);
+        assert_eq!(parsed.ttl, 300);
+
+        let nxdomain = answer(&query, RCODE_NXDOMAIN, &[]);
+        assert!(parse_response(&nxdomain, TYPE_A)
+            .unwrap()
+            .addresses
+            .is_empty());
+        assert!(parse_response(&answer(&query, 2, &[]), TYPE_A).is_err());
+    }
+
+    #[test]
+    fn test_invalid_names_and_endpoints() {
+        assert!(build_query("", TYPE_A).is_err());
+        assert!(build_query(&format!("{}.com", "a".repeat(64)), TYPE_A).is_err());
+        assert!(Resolver::new(ResolverConfig {
+            endpoints: vec![DohEndpoint::new("http://dns.example/dns-query").into()],
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_literals_and_cache_skip_network() {
+        let resolver = unreachable_resolver();
+        assert_eq!(
+            resolver.resolve("192.0.2.1").await.unwrap(),
+            vec![IpAddr::from([192, 0, 2, 1])]
+        );
+
+        let cached = DnsAnswer {
+            addresses: vec![IpAddr::from([198, 51, 100, 7])],
+            ttl: 60,
+        };
+        resolver.store(("blocked.example".to_string(), TYPE_A), &cached);
+        resolver.store(
+            ("blocked.example".to_string(), TYPE_AAAA),
+            &DnsAnswer::default(),
+        );
+        assert_eq!(
+            resolver.lookup("Blocked.Example.", 443).await.unwrap(),
+            vec![SocketAddr::from(([198, 51, 100, 7], 443))]
+        );
+        assert!(resolver.resolve("uncached.example").await.is_err());
+    }
+
+    #[test]
+    fn test_failed_endpoint_moves_to_back() {
+        let resolver = unreachable_resolver();
+        resolver.demote(0);
+        let order: Vec<String> = resolver
+            .endpoint_order()
+            .iter()
+            .map(|e| e.describe())
+            .collect();
+        assert_eq!(
+            order,
+            vec![
+                "tls://dot.invalid@127.0.0.1:1",
+                "quic://doq.invalid@127.0.0.1:1",
+                "https://doh.invalid/dns-query",
+            ]
+        );
+        assert_eq!(length_prefixed(b"abc"), vec![0, 3, b'a', b'b', b'c']);
+    }
+
+    #[test]
+    fn test_classify_answers() {
+        let public = |last: u8| IpAddr::from([104, 16, 132, last]);
+        let injected = IpAddr::from([10, 10, 34, 36]);
+
+        assert_eq!(
+            classify_answers(&[injected], &[public(229)]),
+            PoisoningVerdict::Injected
+        );
+        // CDN answers rotating inside one /24 are not poisoning
+        assert_eq!(
+            classify_answers(&[public(1)], &[public(229)]),
+            PoisoningVerdict::Consistent
+        );
+        assert_eq!(
+            classify_answers(&[IpAddr::from([185, 1, 2, 3])], &[public(229)]),
+            PoisoningVerdict::Divergent
+        );
+        assert_eq!(
+            classify_answers(&[], &[public(229)]),
+            PoisoningVerdict::SystemFailed
+        );
+        assert_eq!(
+            classify_answers(&[public(1)], &[]),
+            PoisoningVerdict::Inconclusive
+        );
+        assert!(!PoisoningVerdict::Inconclusive.is_poisoned());
+    }
+}
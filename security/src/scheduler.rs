@@ -0,0 +1,174 @@
+//! Packet scheduling
+//! Decides when each packet goes on the wire, behind one trait with
+//! interchangeable implementations selectable in config
+
+use crate::error::{Error, Result};
+use crate::morphing::{TrafficShape, TrafficShapeProfile};
+use crate::timing::TimingGenerator;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Picks the delay before each packet
+pub trait PacketScheduler: Send {
+    /// Delay before sending a packet of `len` bytes
+    fn next_delay(&mut self, len: usize) -> Duration;
+}
+
+/// Delays drawn uniformly from a range
+pub struct UniformJitter {
+    min: Duration,
+    max: Duration,
+}
+
+impl UniformJitter {
+    pub fn new(min: Duration, max: Duration) -> Result<Self> {
+        if min > max {
+            return Err(Error::ConfigError(
+                "Jitter minimum exceeds its maximum".to_string(),
+            ));
+        }
+        Ok(UniformJitter { min, max })
+    }
+}
+
+impl PacketScheduler for UniformJitter {
+    fn next_delay(&mut self, _len: usize) -> Duration {
+        rand::thread_rng().gen_range(self.min..=self.max)
+    }
+}
+
+/// Markov-chain delays
+impl PacketScheduler for TimingGenerator {
+    fn next_delay(&mut self, _len: usize) -> Duration {
+        TimingGenerator::next_delay(self)
+    }
+}
+
+/// A fixed byte rate: each packet waits as long as it takes to send at
+/// that rate, so throughput is flat whatever the payload does
+pub struct ConstantRate {
+    bytes_per_sec: u64,
+}
+
+impl ConstantRate {
+    pub fn new(bytes_per_sec: u64) -> Result<Self> {
+        if bytes_per_sec == 0 {
+            return Err(Error::ConfigError(
+                "Constant rate must be above zero".to_string(),
+            ));
+        }
+        Ok(ConstantRate { bytes_per_sec })
+    }
+}
+
+impl PacketScheduler for ConstantRate {
+    fn next_delay(&mut self, len: usize) -> Duration {
+        Duration::from_secs_f64(len as f64 / self.bytes_per_sec as f64)
+    }
+}
+
+/// The pacing of an application's traffic shape
+pub struct ShapeScheduler {
+    shape: Box<dyn TrafficShape>,
+}
+
+impl ShapeScheduler {
+    pub fn new(shape: Box<dyn TrafficShape>) -> Self {
+        ShapeScheduler { shape }
+    }
+}
+
+impl PacketScheduler for ShapeScheduler {
+    fn next_delay(&mut self, _len: usize) -> Duration {
+        self.shape.next_slot().delay
+    }
+}
+
+/// Scheduler selection in config
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SchedulerKind {
+    /// Browsing-like bursts and pauses from the Markov timing model
+    #[default]
+    Markov,
+    UniformJitter {
+        min_ms: u64,
+        max_ms: u64,
+    },
+    ConstantRate {
+        bytes_per_sec: u64,
+    },
+    /// Pacing of an application's traffic shape
+    Application {
+        shape: TrafficShapeProfile,
+    },
+}
+
+impl SchedulerKind {
+    pub fn build(&self) -> Result<Box<dyn PacketScheduler>> {
+        Ok(match self {
+            SchedulerKind::Markov => Box::new(TimingGenerator::default()),
+            SchedulerKind::UniformJitter { min_ms, max_ms } => Box::new(UniformJitter::new(
+                Duration::from_millis(*min_ms),
+                Duration::from_millis(*max_ms),
+            )?),
+            SchedulerKind::ConstantRate { bytes_per_sec } => {
+                Box::new(ConstantRate::new(*bytes_per_sec)?)
+            }
+            SchedulerKind::Application { shape } => {
+                let shape = shape.shape().ok_or_else(|| {
+                    Error::ConfigError("Application scheduler needs a traffic shape".to_string())
+                })?;
+                Box::new(ShapeScheduler::new(shape))
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_constant_rate() {
+        let mut scheduler = ConstantRate::new(100_000).unwrap();
+        assert_eq!(scheduler.next_delay(1000), Duration::from_millis(10));
+        assert_eq!(scheduler.next_delay(0), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_uniform_jitter_in_range() {
+        let mut scheduler =
+            UniformJitter::new(Duration::from_millis(5), Duration::from_millis(15)).unwrap();
+        for _ in 0..1000 {
+            let delay = scheduler.next_delay(100);
+            assert!(delay >= Duration::from_millis(5) && delay <= Duration::from_millis(15));
+        }
+        assert!(UniformJitter::new(Duration::from_secs(2), Duration::from_secs(1)).is_err());
+    }
+
+    #[test]
+    fn test_kinds_from_config() {
+        let kind: SchedulerKind =
+            serde_json::from_str(r#"{"kind": "constant_rate", "bytes_per_sec": 1000}"#).unwrap();
+        assert_eq!(
+            kind.build().unwrap().next_delay(500),
+            Duration::from_millis(500)
+        );
+
+        let kind: SchedulerKind =
+            serde_json::from_str(r#"{"kind": "application", "shape": "video_call"}"#).unwrap();
+        // Video-call packets are a few milliseconds apart at most
+        let mut scheduler = kind.build().unwrap();
+        assert!(scheduler.next_delay(1000) < Duration::from_millis(100));
+
+        let none = SchedulerKind::Application {
+            shape: TrafficShapeProfile::None,
+        };
+        assert!(none.build().is_err());
+        assert!(SchedulerKind::ConstantRate { bytes_per_sec: 0 }
+            .build()
+            .is_err());
+    }
+}
@@ -0,0 +1,286 @@
+//! Local self-test classifier
+//! A small embedded model approximating known proxy-detection classifiers,
+//! used to score planned output before it is sent so proxy-like packets can
+//! be re-planned with heavier shaping
+
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+
+/// Packets of recent output the length-regularity feature looks back over
+pub const WINDOW: usize = 32;
+
+/// Scores above which output is taken as proxy-like by default
+pub const DEFAULT_THRESHOLD: f64 = 0.5;
+
+/// Bytes inspected by the fully-encrypted traffic rule, as in the deployed
+/// censor's first-packet check
+const FET_PREFIX: usize = 6;
+/// Longest run of printable bytes that still counts as random
+const PRINTABLE_RUN: usize = 20;
+
+/// Logistic-regression weights over the packet features
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Weights {
+    pub bias: f64,
+    /// No fully-encrypted-traffic exemption applies
+    pub fully_encrypted: f64,
+    /// Entropy relative to the highest a packet of its length can reach
+    pub entropy: f64,
+    /// How uniform recent packet lengths are: 1 for all equal, 0 once
+    /// their coefficient of variation reaches 1
+    pub length_regularity: f64,
+}
+
+impl Default for Weights {
+    /// Hand-fitted so that uniform-length random bytes score about 0.9
+    /// and plain HTTP or TLS about 0.05
+    fn default() -> Self {
+        Weights {
+            bias: -4.0,
+            fully_encrypted: 3.0,
+            entropy: 2.0,
+            length_regularity: 1.5,
+        }
+    }
+}
+
+/// Why a packet escapes the fully-encrypted traffic rule, in the order the
+/// rule checks them
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Exemption {
+    /// Average set bits per byte at or below 3.4, or at or above 4.6
+    Popcount,
+    /// The first six bytes are printable ASCII
+    PrintablePrefix,
+    /// More than half the bytes are printable ASCII
+    MostlyPrintable,
+    /// More than twenty printable bytes in a row
+    PrintableRun,
+    /// Starts like TLS or HTTP
+    KnownProtocol,
+}
+
+/// The exemption letting a packet through the fully-encrypted traffic
+/// rule, `None` if it looks like random bytes
+///
+/// Mirrors the published heuristic a national censor uses to block fully
+/// encrypted proxies: traffic that matches no known protocol and none of
+/// these exemptions is dropped.
+pub fn exemption(packet: &[u8]) -> Option<Exemption> {
+    if packet.is_empty() {
+        return Some(Exemption::Popcount);
+    }
+    let printable = |byte: &u8| (0x20..=0x7e).contains(byte);
+    let bits: u32 = packet.iter().map(|byte| byte.count_ones()).sum();
+    let popcount = bits as f64 / packet.len() as f64;
+    if popcount <= 3.4 || popcount >= 4.6 {
+        return Some(Exemption::Popcount);
+    }
+    if packet.len() >= FET_PREFIX && packet[..FET_PREFIX].iter().all(printable) {
+        return Some(Exemption::PrintablePrefix);
+    }
+    if packet.iter().filter(|byte| printable(byte)).count() * 2 > packet.len() {
+        return Some(Exemption::MostlyPrintable);
+    }
+    let longest_run = packet
+        .split(|byte| !printable(byte))
+        .map(<[u8]>::len)
+        .max()
+        .unwrap_or(0);
+    if longest_run > PRINTABLE_RUN {
+        return Some(Exemption::PrintableRun);
+    }
+    let known = matches!(packet, [0x16 | 0x17, 0x03, 0x00..=0x04, ..])
+        || [&b"GET "[..], b"POST ", b"HTTP/", b"PUT ", b"CONNECT "]
+            .iter()
+            .any(|prefix| packet.starts_with(prefix));
+    known.then_some(Exemption::KnownProtocol)
+}
+
+/// Scores packets for how proxy-like they look
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ProxyClassifier {
+    pub weights: Weights,
+}
+
+impl ProxyClassifier {
+    pub fn new(weights: Weights) -> Self {
+        ProxyClassifier { weights }
+    }
+
+    /// Probability-like score in 0..1 that `packet`, sent after packets of
+    /// `recent` lengths, is proxy traffic
+    pub fn score(&self, packet: &[u8], recent: &[usize]) -> f64 {
+        let w = &self.weights;
+        let fully_encrypted = if exemption(packet).is_none() {
+            1.0
+        } else {
+            0.0
+        };
+        let z = w.bias
+            + w.fully_encrypted * fully_encrypted
+            + w.entropy * normalized_entropy(packet)
+            + w.length_regularity * length_regularity(packet.len(), recent);
+        1.0 / (1.0 + (-z).exp())
+    }
+}
+
+impl Default for ProxyClassifier {
+    fn default() -> Self {
+        Self::new(Weights::default())
+    }
+}
+
+/// Scoring totals, for tuning the threshold and shaping
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SelfTestStats {
+    pub packets: u64,
+    /// Packets whose first plan scored above the threshold
+    pub flagged: u64,
+    /// Extra plans made for flagged packets
+    pub replans: u64,
+    /// Packets sent although every plan scored above the threshold
+    pub sent_flagged: u64,
+    /// Score of the last packet sent
+    pub last_score: f64,
+    /// Mean score of the packets sent
+    pub mean_score: f64,
+}
+
+/// A classifier with the output history it scores against
+pub struct SelfTest {
+    classifier: ProxyClassifier,
+    threshold: f64,
+    recent: Mutex<VecDeque<usize>>,
+    stats: Mutex<SelfTestStats>,
+}
+
+impl SelfTest {
+    pub fn new(classifier: ProxyClassifier, threshold: f64) -> Self {
+        SelfTest {
+            classifier,
+            threshold,
+            recent: Mutex::new(VecDeque::with_capacity(WINDOW)),
+            stats: Mutex::new(SelfTestStats::default()),
+        }
+    }
+
+    pub fn threshold(&self) -> f64 {
+        self.threshold
+    }
+
+    /// Score a planned packet against the recent output
+    pub fn score(&self, packet: &[u8]) -> f64 {
+        let mut recent = self.recent.lock();
+        self.classifier.score(packet, recent.make_contiguous())
+    }
+
+    pub fn is_flagged(&self, score: f64) -> bool {
+        score > self.threshold
+    }
+
+    /// Record the packet that was sent, its score and the re-plans made
+    pub fn record(&self, len: usize, first_score: f64, score: f64, replans: u32) {
+        let mut recent = self.recent.lock();
+        if recent.len() == WINDOW {
+            recent.pop_front();
+        }
+        recent.push_back(len);
+
+        let mut stats = self.stats.lock();
+        stats.packets += 1;
+        stats.flagged += self.is_flagged(first_score) as u64;
+        stats.replans += replans as u64;
+        stats.sent_flagged += self.is_flagged(score) as u64;
+        stats.last_score = score;
+        stats.mean_score += (score - stats.mean_score) / stats.packets as f64;
+    }
+
+    pub fn stats(&self) -> SelfTestStats {
+        *self.stats.lock()
+    }
+}
+
+/// Entropy over the highest a sample of this length can hold
+fn normalized_entropy(packet: &[u8]) -> f64 {
+    let max = (packet.len().min(256) as f64).log2();
+    if max <= 0.0 {
+        return 0.0;
+    }
+    (crate::entropy::shannon_entropy(packet) / max).min(1.0)
+}
+
+/// 1 minus the coefficient of variation of the lengths, floored at 0; a
+/// lone packet has nothing to be regular with
+fn length_regularity(len: usize, recent: &[usize]) -> f64 {
+    if recent.is_empty() {
+        return 0.0;
+    }
+    let lens: Vec<f64> = recent.iter().chain([&len]).map(|&l| l as f64).collect();
+    let n = lens.len() as f64;
+    let mean = lens.iter().sum::<f64>() / n;
+    if mean == 0.0 {
+        return 0.0;
+    }
+    let variance = lens.iter().map(|l| (l - mean).powi(2)).sum::<f64>() / n;
+    (1.0 - variance.sqrt() / mean).max(0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+
+    fn random(len: usize) -> Vec<u8> {
+        let mut data = vec![0u8; len];
+        rand::thread_rng().fill(&mut data[..]);
+        data
+    }
+
+    #[test]
+    fn test_fully_encrypted_rule() {
+        let mut data = random(500);
+        // Keep the prefix and runs out of the printable range
+        data[0] = 0x80;
+        for byte in data.iter_mut().skip(20).step_by(10) {
+            *byte = 0x9a;
+        }
+        assert_eq!(exemption(&data), None);
+        assert!(exemption(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n").is_some());
+        assert_eq!(exemption(&[0x00; 64]), Some(Exemption::Popcount));
+        data[..6].copy_from_slice(b"Hello!");
+        assert_eq!(exemption(&data), Some(Exemption::PrintablePrefix));
+        data[..6].copy_from_slice(&[0x16, 0x03, 0x01, 0x01, 0xf0, 0x80]);
+        assert_eq!(exemption(&data), Some(Exemption::KnownProtocol));
+    }
+
+    #[test]
+    fn test_scores_order() {
+        let classifier = ProxyClassifier::default();
+        let recent = vec![1200; WINDOW];
+        let mut packet = random(1200);
+        packet[0] = 0x80;
+        let proxy = classifier.score(&packet, &recent);
+        assert!(proxy > 0.8, "{}", proxy);
+
+        let http = b"GET /feed HTTP/1.1\r\nHost: example.com\r\nAccept: */*\r\n\r\n";
+        let varied = [40, 900, 120, 1460, 300];
+        let benign = classifier.score(http, &varied);
+        assert!(benign < 0.1, "{}", benign);
+        assert!(classifier.score(&random(1200), &varied) < proxy);
+    }
+
+    #[test]
+    fn test_stats_track_scores() {
+        let self_test = SelfTest::new(ProxyClassifier::default(), DEFAULT_THRESHOLD);
+        self_test.record(100, 0.9, 0.3, 2);
+        self_test.record(100, 0.2, 0.2, 0);
+        let stats = self_test.stats();
+        assert_eq!(stats.packets, 2);
+        assert_eq!(stats.flagged, 1);
+        assert_eq!(stats.replans, 2);
+        assert_eq!(stats.sent_flagged, 0);
+        assert_eq!(stats.last_score, 0.2);
+        assert!((stats.mean_score - 0.25).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,199 @@
+//! TLS session ticket cache
+//! Keeps per-host session tickets so repeat connections present resumption-shaped
+//! handshakes (pre_shared_key) the way real browsers do, instead of an endless
+//! stream of full handshakes that stands out statistically
+
+use crate::client_hello::{ClientHelloBuilder, TLSProfileConfig};
+use parking_lot::Mutex;
+use rand::Rng;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, SystemTime};
+
+/// Ticket lifetime most servers advertise (RFC 8446 allows up to 7 days)
+const DEFAULT_TICKET_LIFETIME: Duration = Duration::from_secs(7200);
+const DEFAULT_MAX_TICKETS_PER_HOST: usize = 4;
+
+/// A TLS 1.3 session ticket as issued by NewSessionTicket
+#[derive(Clone, Debug)]
+pub struct SessionTicket {
+    pub server_name: String,
+    pub ticket: Vec<u8>,
+    pub age_add: u32,
+    pub lifetime: Duration,
+    pub received_at: SystemTime,
+}
+
+impl SessionTicket {
+    /// Create a ticket from NewSessionTicket fields
+    pub fn new(server_name: &str, ticket: Vec<u8>, age_add: u32, lifetime: Duration) -> Self {
+        SessionTicket {
+            server_name: server_name.to_string(),
+            ticket,
+            age_add,
+            lifetime,
+            received_at: SystemTime::now(),
+        }
+    }
+
+    /// Create a plausible ticket for hosts whose real tickets we never see
+    ///
+    /// Servers that can't decrypt it simply fall back to a full handshake, but
+    /// the ClientHello on the wire still has the shape of a resumption.
+    pub fn synthetic(server_name: &str) -> Self {
+        let mut rng = rand::thread_rng();
+        // Common server ticket sizes (nginx/OpenSSL, Google front ends)
+        let len = rng.gen_range(176..=256);
+        let ticket = (0..len).map(|_| rng.gen::<u8>()).collect();
+        Self::new(server_name, ticket, rng.gen(), DEFAULT_TICKET_LIFETIME)
+    }
+
+    /// Check whether the ticket lifetime has elapsed
+    pub fn is_expired(&self) -> bool {
+        self.age() >= self.lifetime
+    }
+
+    /// Time since the ticket was received
+    pub fn age(&self) -> Duration {
+        self.received_at.elapsed().unwrap_or_default()
+    }
+
+    /// The obfuscated_ticket_age sent in the pre_shared_key extension
+    pub fn obfuscated_age(&self) -> u32 {
+        (self.age().as_millis() as u32).wrapping_add(self.age_add)
+    }
+}
+
+/// Per-host cache of session tickets
+pub struct SessionTicketCache {
+    tickets: Mutex<HashMap<String, VecDeque<SessionTicket>>>,
+    max_per_host: usize,
+}
+
+impl SessionTicketCache {
+    /// Create an empty cache
+    pub fn new() -> Self {
+        Self::with_capacity(DEFAULT_MAX_TICKETS_PER_HOST)
+    }
+
+    /// Create an empty cache keeping at most `max_per_host` tickets per host
+    pub fn with_capacity(max_per_host: usize) -> Self {
+        SessionTicketCache {
+            tickets: Mutex::new(HashMap::new()),
+            max_per_host: max_per_host.max(1),
+        }
+    }
+
+    /// Store a ticket, evicting the oldest one for the host when full
+    pub fn store(&self, ticket: SessionTicket) {
+        let mut tickets = self.tickets.lock();
+        let host_tickets = tickets
+            .entry(ticket.server_name.to_ascii_lowercase())
+            .or_default();
+        if host_tickets.len() >= self.max_per_host {
+            host_tickets.pop_front();
+        }
+        host_tickets.push_back(ticket);
+    }
+
+    /// Take the newest valid ticket for a host
+    ///
+    /// TLS 1.3 tickets are single-use, so the ticket is removed from the cache.
+    pub fn take(&self, server_name: &str) -> Option<SessionTicket> {
+        let mut tickets = self.tickets.lock();
+        let host_tickets = tickets.get_mut(&server_name.to_ascii_lowercase())?;
+        host_tickets.retain(|t| !t.is_expired());
+        host_tickets.pop_back()
+    }
+
+    /// Build a ClientHello for the host, resuming if a ticket is cached
+    ///
+    /// Every handshake leaves a synthetic ticket behind (servers issue fresh
+    /// tickets after resumptions too), so repeat connections to the same host
+    /// look like resumptions.
+    pub fn client_hello(&self, profile: &TLSProfileConfig, server_name: &str) -> Vec<u8> {
+        let mut builder = ClientHelloBuilder::from_profile(profile).server_name(server_name);
+        if let Some(ticket) = self.take(server_name) {
+            builder = builder.resume_with(&ticket);
+        }
+
+        self.store(SessionTicket::synthetic(server_name));
+        builder.build()
+    }
+
+    /// Drop all expired tickets
+    pub fn purge_expired(&self) {
+        let mut tickets = self.tickets.lock();
+        for host_tickets in tickets.values_mut() {
+            host_tickets.retain(|t| !t.is_expired());
+        }
+        tickets.retain(|_, host_tickets| !host_tickets.is_empty());
+    }
+
+    /// Total number of cached tickets
+    pub fn len(&self) -> usize {
+        self.tickets.lock().values().map(|t| t.len()).sum()
+    }
+
+    /// Check whether the cache holds no tickets
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for SessionTicketCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client_hello::{parse_client_hello, EXT_PRE_SHARED_KEY};
+
+    #[test]
+    fn test_store_and_take() {
+        let cache = SessionTicketCache::new();
+        cache.store(SessionTicket::synthetic("Example.com"));
+        assert_eq!(cache.len(), 1);
+
+        assert!(cache.take("example.com").is_some());
+        // Tickets are single-use
+        assert!(cache.take("example.com").is_none());
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_expired_tickets_are_skipped() {
+        let cache = SessionTicketCache::new();
+        let mut ticket = SessionTicket::synthetic("example.com");
+        ticket.lifetime = Duration::ZERO;
+        cache.store(ticket);
+
+        assert!(cache.take("example.com").is_none());
+        cache.purge_expired();
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_capacity_per_host() {
+        let cache = SessionTicketCache::with_capacity(2);
+        for _ in 0..5 {
+            cache.store(SessionTicket::synthetic("example.com"));
+        }
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn test_second_connection_resumes() {
+        let cache = SessionTicketCache::new();
+        let profile = TLSProfileConfig::default();
+
+        let first = parse_client_hello(&cache.client_hello(&profile, "example.com")).unwrap();
+        assert!(first.extension(EXT_PRE_SHARED_KEY).is_none());
+
+        let second = parse_client_hello(&cache.client_hello(&profile, "example.com")).unwrap();
+        let last = second.extensions.last().unwrap();
+        assert_eq!(last.ext_type, EXT_PRE_SHARED_KEY);
+    }
+}
@@ -0,0 +1,518 @@
+//! SNI evasion fallback chain
+//! Tries ECH, fake SNI, SNI-split fragmentation and domain fronting in order for
+//! each destination, or races a few of them at once, and remembers which
+//! strategy last got through per host
+
+use crate::client_hello::{ClientHelloBuilder, TLSProfileConfig};
+use crate::domain_fronting::{DomainFronter, FrontedRoute};
+use crate::error::{Error, Result};
+use crate::sni_obfuscation::SNIObfuscator;
+use crate::tls_fragmentation::{
+    FragmentationStrategy, FragmentedPacket, TLSFragmentationConfig, TLSFragmenter,
+};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::future::{poll_fn, Future};
+use std::pin::Pin;
+use std::task::Poll;
+use std::time::{Duration, Instant};
+
+/// One way of keeping the destination name away from DPI
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SniStrategy {
+    /// Encrypted ClientHello; the inner name is encrypted to the server's ECH key
+    Ech,
+    /// Present a harmless name from the fake-SNI pool
+    FakeSni,
+    /// Keep the real name but cut the ClientHello inside it
+    SniSplit,
+    /// Present a CDN front domain and name the destination in the Host header
+    DomainFronting,
+}
+
+/// Configuration for the fallback chain
+#[derive(Clone, Debug)]
+pub struct SniFallbackConfig {
+    /// Strategies to try, in order
+    pub order: Vec<SniStrategy>,
+    /// Hosts that publish ECH configs; `*.example.com` matches subdomains.
+    /// ECH is skipped for everything else
+    pub ech_hosts: Vec<String>,
+    /// Give up on a strategy after this long
+    pub attempt_timeout: Duration,
+    /// Forget a working strategy after this long so better ones get retried
+    pub memory_ttl: Duration,
+    /// Attempts `race` keeps in flight at once
+    pub race_width: usize,
+    /// Head start each raced attempt gets before the next one is started
+    pub race_stagger: Duration,
+}
+
+impl Default for SniFallbackConfig {
+    fn default() -> Self {
+        SniFallbackConfig {
+            order: vec![
+                SniStrategy::Ech,
+                SniStrategy::FakeSni,
+                SniStrategy::SniSplit,
+                SniStrategy::DomainFronting,
+            ],
+            ech_hosts: Vec::new(),
+            attempt_timeout: Duration::from_secs(10),
+            memory_ttl: Duration::from_secs(6 * 3600),
+            race_width: 3,
+            race_stagger: Duration::from_millis(300),
+        }
+    }
+}
+
+/// ClientHello packets prepared for one strategy
+#[derive(Clone, Debug)]
+pub struct PreparedHello {
+    pub strategy: SniStrategy,
+    pub packets: Vec<FragmentedPacket>,
+    /// Set for domain fronting: the Host header for the inner request
+    pub route: Option<FrontedRoute>,
+}
+
+/// Orchestrates SNI evasion strategies per destination
+pub struct SniFallbackChain {
+    config: SniFallbackConfig,
+    obfuscator: SNIObfuscator,
+    fronter: DomainFronter,
+    /// Last strategy that worked per host, with when it worked
+    working: RwLock<HashMap<String, (SniStrategy, Instant)>>,
+}
+
+impl SniFallbackChain {
+    /// Create a chain drawing fake names from `obfuscator` and fronts from `fronter`
+    pub fn new(
+        config: SniFallbackConfig,
+        obfuscator: SNIObfuscator,
+        fronter: DomainFronter,
+    ) -> Self {
+        SniFallbackChain {
+            config,
+            obfuscator,
+            fronter,
+            working: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Check whether a strategy can be tried for a host at all
+    pub fn is_applicable(&self, host: &str, strategy: SniStrategy) -> bool {
+        match strategy {
+            SniStrategy::Ech => self
+                .config
+                .ech_hosts
+                .iter()
+                .any(|pattern| matches_host(pattern, host)),
+            SniStrategy::FakeSni => !self.obfuscator.pool().is_empty(),
+            SniStrategy::SniSplit => true,
+            SniStrategy::DomainFronting => self.fronter.route(host).is_some(),
+        }
+    }
+
+    /// Strategies to try for a host: the remembered one first, then the
+    /// configured order, skipping any that can't apply
+    pub fn plan(&self, host: &str) -> Vec<SniStrategy> {
+        let mut plan = Vec::with_capacity(self.config.order.len());
+        if let Some(strategy) = self.remembered(host) {
+            plan.push(strategy);
+        }
+        for &strategy in &self.config.order {
+            if !plan.contains(&strategy) && self.is_applicable(host, strategy) {
+                plan.push(strategy);
+            }
+        }
+        plan
+    }
+
+    /// The strategy that last worked for a host, if still fresh
+    pub fn remembered(&self, host: &str) -> Option<SniStrategy> {
+        let working = self.working.read();
+        let (strategy, at) = working.get(&host.to_ascii_lowercase())?;
+        (at.elapsed() < self.config.memory_ttl).then_some(*strategy)
+    }
+
+    /// Remember that a strategy worked for a host
+    pub fn record_success(&self, host: &str, strategy: SniStrategy) {
+        self.working
+            .write()
+            .insert(host.to_ascii_lowercase(), (strategy, Instant::now()));
+    }
+
+    /// Forget a strategy for a host after it stopped working
+    pub fn record_failure(&self, host: &str, strategy: SniStrategy) {
+        let mut working = self.working.write();
+        let host = host.to_ascii_lowercase();
+        if working.get(&host).is_some_and(|(s, _)| *s == strategy) {
+            working.remove(&host);
+        }
+    }
+
+    /// Try each planned strategy until one succeeds
+    ///
+    /// `attempt` performs the connection for one strategy. For everything but
+    /// ECH it can use `client_hello` to get the packets to send; ECH needs an
+    /// ECH-capable TLS stack, so the attempt does that handshake itself.
+    pub async fn run<T, F, Fut>(&self, host: &str, mut attempt: F) -> Result<(SniStrategy, T)>
+    where
+        F: FnMut(SniStrategy) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut failures = Vec::new();
+
+        for strategy in self.plan(host) {
+            let outcome =
+                tokio::time::timeout(self.config.attempt_timeout, attempt(strategy)).await;
+            match outcome {
+                Ok(Ok(value)) => {
+                    self.record_success(host, strategy);
+                    return Ok((strategy, value));
+                }
+                Ok(Err(e)) => failures.push(format!("{:?}: {}", strategy, e)),
+                Err(_) => failures.push(format!("{:?}: timed out", strategy)),
+            }
+            self.record_failure(host, strategy);
+        }
+
+        Err(Error::DPIBypassError(format!(
+            "All SNI strategies failed for {}: [{}]",
+            host,
+            failures.join("; ")
+        )))
+    }
+
+    /// Race the planned strategies, keeping the first that connects
+    ///
+    /// Happy eyeballs across evasion strategies: attempts start
+    /// `race_stagger` apart, or as soon as one fails, with at most
+    /// `race_width` in flight. The first success is returned and the other
+    /// attempts are dropped, which cancels them.
+    pub async fn race<T, F, Fut>(&self, host: &str, mut attempt: F) -> Result<(SniStrategy, T)>
+    where
+        F: FnMut(SniStrategy) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        enum Step<T> {
+            Start,
+            Finished(
+                usize,
+                std::result::Result<Result<T>, tokio::time::error::Elapsed>,
+            ),
+        }
+
+        let width = self.config.race_width.max(1);
+        let mut pending = self.plan(host).into_iter().peekable();
+        let mut running: Vec<(SniStrategy, Pin<Box<tokio::time::Timeout<Fut>>>)> = Vec::new();
+        let mut failures = Vec::new();
+        let stagger = tokio::time::sleep(Duration::ZERO);
+        tokio::pin!(stagger);
+
+        loop {
+            let can_start = pending.peek().is_some() && running.len() < width;
+            if !can_start && running.is_empty() {
+                break;
+            }
+            let step = poll_fn(|cx| {
+                if can_start && (running.is_empty() || stagger.as_mut().poll(cx).is_ready()) {
+                    return Poll::Ready(Step::Start);
+                }
+                for (index, (_, attempt)) in running.iter_mut().enumerate() {
+                    if let Poll::Ready(outcome) = attempt.as_mut().poll(cx) {
+                        return Poll::Ready(Step::Finished(index, outcome));
+                    }
+                }
+                Poll::Pending
+            })
+            .await;
+
+            match step {
+                Step::Start => {
+                    let strategy = pending.next().expect("checked by can_start");
+                    let timed =
+                        tokio::time::timeout(self.config.attempt_timeout, attempt(strategy));
+                    running.push((strategy, Box::pin(timed)));
+                    let next = tokio::time::Instant::now() + self.config.race_stagger;
+                    stagger.as_mut().reset(next);
+                }
+                Step::Finished(index, outcome) => {
+                    let (strategy, _) = running.swap_remove(index);
+                    match outcome {
+                        Ok(Ok(value)) => {
+                            self.record_success(host, strategy);
+                            return Ok((strategy, value));
+                        }
+                        Ok(Err(e)) => failures.push(format!("{:?}: {}", strategy, e)),
+                        Err(_) => failures.push(format!("{:?}: timed out", strategy)),
+                    }
+                    self.record_failure(host, strategy);
+                    // A failure frees a slot right away
+                    stagger.as_mut().reset(tokio::time::Instant::now());
+                }
+            }
+        }
+
+        Err(Error::DPIBypassError(format!(
+            "All raced SNI strategies failed for {}: [{}]",
+            host,
+            failures.join("; ")
+        )))
+    }
+
+    /// Build the ClientHello packets for a strategy
+    pub fn client_hello(
+        &self,
+        host: &str,
+        strategy: SniStrategy,
+        profile: &TLSProfileConfig,
+    ) -> Result<PreparedHello> {
+        let single = |hello: Vec<u8>| {
+            vec![FragmentedPacket {
+                data: hello,
+                delay_ms: 0,
+            }]
+        };
+
+        let (packets, route) = match strategy {
+            SniStrategy::Ech => {
+                return Err(Error::DPIBypassError(
+                    "ECH handshakes are produced by the TLS stack".to_string(),
+                ))
+            }
+            SniStrategy::FakeSni => {
+                let fake =
+                    self.obfuscator.pool().choose().ok_or_else(|| {
+                        Error::DPIBypassError("Fake-SNI pool is empty".to_string())
+                    })?;
+                let hello = ClientHelloBuilder::from_profile(profile)
+                    .server_name(&fake)
+                    .build();
+                (single(hello), None)
+            }
+            SniStrategy::SniSplit => {
+                let hello = ClientHelloBuilder::from_profile(profile)
+                    .server_name(host)
+                    .build();
+                let fragmenter = TLSFragmenter::with_config(TLSFragmentationConfig {
+                    strategy: FragmentationStrategy::SniSplit,
+                    ..Default::default()
+                });
+                let packets = fragmenter
+                    .fragment_client_hello(&hello)
+                    .map_err(Error::DPIBypassError)?;
+                (packets, None)
+            }
+            SniStrategy::DomainFronting => {
+                let route = self.fronter.route(host).ok_or_else(|| {
+                    Error::DPIBypassError(format!("No fronting pair for {}", host))
+                })?;
+                let hello = self.fronter.client_hello(&route, profile);
+                (single(hello), Some(route))
+            }
+        };
+
+        Ok(PreparedHello {
+            strategy,
+            packets,
+            route,
+        })
+    }
+}
+
+/// Match a host against an `ech_hosts` entry
+fn matches_host(pattern: &str, host: &str) -> bool {
+    let host = host.to_ascii_lowercase();
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => host
+            .strip_suffix(&suffix.to_ascii_lowercase())
+            .is_some_and(|rest| rest.ends_with('.')),
+        None => pattern.eq_ignore_ascii_case(&host),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client_hello::parse_client_hello;
+    use crate::config::{DomainFrontingConfig, FrontingPair};
+    use crate::tls_fragmentation::reassemble_fragments;
+
+    fn chain(ech_hosts: &[&str], fronted: &[&str]) -> SniFallbackChain {
+        let config = SniFallbackConfig {
+            ech_hosts: ech_hosts.iter().map(|s| s.to_string()).collect(),
+            ..Default::default()
+        };
+        let fronter = DomainFronter::new(DomainFrontingConfig {
+            enabled: true,
+            pairs: fronted
+                .iter()
+                .map(|host| FrontingPair {
+                    front_domain: "front.cdn.example".to_string(),
+                    real_host: host.to_string(),
+                })
+                .collect(),
+        });
+        SniFallbackChain::new(config, SNIObfuscator::new(), fronter)
+    }
+
+    #[test]
+    fn test_plan_skips_inapplicable_strategies() {
+        let chain = chain(&["*.ech.example"], &["fronted.example"]);
+        assert_eq!(
+            chain.plan("a.ech.example"),
+            vec![
+                SniStrategy::Ech,
+                SniStrategy::FakeSni,
+                SniStrategy::SniSplit
+            ]
+        );
+        assert_eq!(
+            chain.plan("fronted.example"),
+            vec![
+                SniStrategy::FakeSni,
+                SniStrategy::SniSplit,
+                SniStrategy::DomainFronting
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_falls_back_and_remembers() {
+        let chain = chain(&[], &["blocked.example"]);
+        let mut tried = Vec::new();
+
+        let (strategy, _) = chain
+            .run("blocked.example", |strategy| {
+                tried.push(strategy);
+                async move {
+                    match strategy {
+                        SniStrategy::DomainFronting => Ok(()),
+                        _ => Err(Error::DPIBypassError("reset".to_string())),
+                    }
+                }
+            })
+            .await
+            .unwrap();
+        assert_eq!(strategy, SniStrategy::DomainFronting);
+        assert_eq!(tried.len(), 3);
+
+        // The next connection starts with what worked
+        assert_eq!(
+            chain.plan("blocked.example")[0],
+            SniStrategy::DomainFronting
+        );
+    }
+
+    #[tokio::test]
+    async fn test_forgets_strategy_that_stops_working() {
+        let chain = chain(&[], &[]);
+        chain.record_success("host.example", SniStrategy::SniSplit);
+
+        let result = chain
+            .run("host.example", |_| async {
+                Err::<(), _>(Error::DPIBypassError("reset".to_string()))
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(chain.remembered("host.example"), None);
+    }
+
+    #[tokio::test]
+    async fn test_race_takes_first_to_connect() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+
+        struct Cancelled(Arc<AtomicBool>);
+        impl Drop for Cancelled {
+            fn drop(&mut self) {
+                self.0.store(true, Ordering::SeqCst);
+            }
+        }
+
+        let mut chain = chain(&[], &["blocked.example"]);
+        chain.config.race_stagger = Duration::from_millis(20);
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let started = Instant::now();
+        let mut tried = Vec::new();
+
+        let (strategy, _) = chain
+            .race("blocked.example", |strategy| {
+                tried.push(strategy);
+                let guard = Cancelled(cancelled.clone());
+                async move {
+                    let _guard = guard;
+                    match strategy {
+                        // Fake SNI is blackholed: it would hang until the timeout
+                        SniStrategy::FakeSni => {
+                            tokio::time::sleep(Duration::from_secs(10)).await;
+                            Ok(())
+                        }
+                        SniStrategy::SniSplit => Err(Error::DPIBypassError("reset".to_string())),
+                        _ => {
+                            tokio::time::sleep(Duration::from_millis(10)).await;
+                            Ok(())
+                        }
+                    }
+                }
+            })
+            .await
+            .unwrap();
+        assert_eq!(strategy, SniStrategy::DomainFronting);
+        assert_eq!(
+            tried,
+            vec![
+                SniStrategy::FakeSni,
+                SniStrategy::SniSplit,
+                SniStrategy::DomainFronting
+            ]
+        );
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(cancelled.load(Ordering::SeqCst));
+        assert_eq!(
+            chain.remembered("blocked.example"),
+            Some(SniStrategy::DomainFronting)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_race_reports_every_failure() {
+        let mut chain = chain(&[], &[]);
+        chain.config.race_width = 2;
+        let error = chain
+            .race("host.example", |strategy| async move {
+                Err::<(), _>(Error::DPIBypassError(format!("{:?} reset", strategy)))
+            })
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("FakeSni reset") && error.contains("SniSplit reset"));
+    }
+
+    #[test]
+    fn test_client_hello_per_strategy() {
+        let chain = chain(&[], &["fronted.example"]);
+        let profile = TLSProfileConfig::default();
+
+        let split = chain
+            .client_hello("fronted.example", SniStrategy::SniSplit, &profile)
+            .unwrap();
+        let data: Vec<Vec<u8>> = split.packets.into_iter().map(|p| p.data).collect();
+        let hello = parse_client_hello(&reassemble_fragments(&data)).unwrap();
+        assert_eq!(hello.server_name.as_deref(), Some("fronted.example"));
+
+        let fronted = chain
+            .client_hello("fronted.example", SniStrategy::DomainFronting, &profile)
+            .unwrap();
+        let hello = parse_client_hello(&fronted.packets[0].data).unwrap();
+        assert_eq!(hello.server_name.as_deref(), Some("front.cdn.example"));
+        assert_eq!(fronted.route.unwrap().host_header, "fronted.example");
+
+        assert!(chain
+            .client_hello("fronted.example", SniStrategy::Ech, &profile)
+            .is_err());
+    }
+}
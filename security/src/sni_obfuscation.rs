@@ -2,11 +2,15 @@
 // Randomizes SNI values in TLS ClientHello to evade DPI-based SNI filtering
 // Includes domain rotation, capitalization randomization, and fingerprint matching
 
-use rand::seq::SliceRandom;
+use crate::client_hello::{ClientHelloBuilder, ClientHelloSpec};
+use crate::error::Result;
+use crate::fingerprint::OsFamily;
+use crate::sni_pool::{PoolReport, SniPool, SniPoolProfile, SniPoolValidator};
 use rand::Rng;
+use serde::{Deserialize, Serialize};
 
 /// Comprehensive pool of legitimate global domains for SNI rotation
-const FAKE_SNI_POOL: &[&str] = &[
+pub(crate) const FAKE_SNI_POOL: &[&str] = &[
     // Global platforms
     "google.com",
     "youtube.com",
@@ -76,7 +80,8 @@ const FAKE_SNI_POOL: &[&str] = &[
 ];
 
 /// Browser User-Agent styles for fingerprint matching
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum BrowserFingerprint {
     Chrome,
     Safari,
@@ -85,21 +90,36 @@ pub enum BrowserFingerprint {
     Opera,
 }
 
+impl BrowserFingerprint {
+    /// Complete ClientHello spec for this browser: cipher suites, extension
+    /// set and order, signature algorithms and key shares. `os` selects the
+    /// platform variant; `None` means desktop.
+    pub fn client_hello_spec(&self, os: Option<OsFamily>) -> ClientHelloSpec {
+        match os {
+            Some(os) => ClientHelloSpec::for_browser_on(*self, os),
+            None => ClientHelloSpec::for_browser(*self),
+        }
+    }
+}
+
 /// SNI obfuscation strategies
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum ObfuscationStrategy {
     /// Simple domain rotation from fake pool
     RandomDomain,
     /// Randomize capitalization (Example.Com, EXAMPLE.COM)
     CapitalizationRandomization,
     /// Insert padding in SNI extension
+    #[serde(rename = "sni_padding")]
     SNIPadding,
     /// Combine multiple strategies
     Combined,
 }
 
 /// Configuration for SNI obfuscation
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct SNIObfuscationConfig {
     pub strategy: ObfuscationStrategy,
     pub use_fake_sni: bool,
@@ -108,6 +128,10 @@ pub struct SNIObfuscationConfig {
     pub add_padding: bool,
     pub max_padding_bytes: usize,
     pub browser_fingerprint: Option<BrowserFingerprint>,
+    /// Platform of the claimed browser, selecting its OS-specific handshake
+    pub os: Option<OsFamily>,
+    /// Which fake-SNI pool to draw from
+    pub pool_profile: SniPoolProfile,
 }
 
 impl Default for SNIObfuscationConfig {
@@ -120,6 +144,8 @@ impl Default for SNIObfuscationConfig {
             add_padding: true,
             max_padding_bytes: 50,
             browser_fingerprint: Some(BrowserFingerprint::Chrome),
+            os: None,
+            pool_profile: SniPoolProfile::default(),
         }
     }
 }
@@ -127,28 +153,54 @@ impl Default for SNIObfuscationConfig {
 /// SNI obfuscation engine
 pub struct SNIObfuscator {
     config: SNIObfuscationConfig,
+    pool: SniPool,
 }
 
 impl SNIObfuscator {
     /// Create a new SNI obfuscator with default configuration
     pub fn new() -> Self {
-        SNIObfuscator {
-            config: SNIObfuscationConfig::default(),
-        }
+        Self::with_config(SNIObfuscationConfig::default())
     }
 
     /// Create a new SNI obfuscator with custom configuration
+    ///
+    /// Custom pool profiles start with the built-in pool; call `reload_pool`
+    /// to load their source.
     pub fn with_config(config: SNIObfuscationConfig) -> Self {
-        SNIObfuscator { config }
+        let pool = SniPool::for_profile(&config.pool_profile);
+        SNIObfuscator { config, pool }
+    }
+
+    /// Create a new SNI obfuscator sharing an existing pool
+    pub fn with_pool(config: SNIObfuscationConfig, pool: SniPool) -> Self {
+        SNIObfuscator { config, pool }
+    }
+
+    /// Get the fake SNI pool handle
+    pub fn pool(&self) -> &SniPool {
+        &self.pool
+    }
+
+    /// Load the configured pool profile and swap it in
+    pub async fn reload_pool(&self) -> Result<usize> {
+        self.pool.reload_profile(&self.config.pool_profile).await
+    }
+
+    /// Probe the pool from the current vantage point and prune bad domains
+    pub async fn validate_pool(&self, validator: &SniPoolValidator) -> PoolReport {
+        self.pool.validate(validator).await
+    }
+
+    /// Health report from the most recent pool validation
+    pub fn pool_report(&self) -> Option<PoolReport> {
+        self.pool.report()
     }
 
     /// Generate a random domain from the fake SNI pool
     fn get_random_fake_sni(&self) -> String {
-        let mut rng = rand::thread_rng();
-        FAKE_SNI_POOL
-            .choose(&mut rng)
-            .unwrap_or(&"google.com")
-            .to_string()
+        self.pool
+            .choose()
+            .unwrap_or_else(|| "google.com".to_string())
     }
 
     /// Randomize capitalization of a domain name
@@ -235,13 +287,10 @@ impl SNIObfuscator {
                 // 40% chance to use fake SNI, 60% to modify original
                 if rng.gen_bool(0.4) && self.config.use_fake_sni {
                     self.get_random_fake_sni()
+                } else if rng.gen_bool(0.5) && self.config.randomize_capitalization {
+                    self.randomize_capitalization(original_sni)
                 } else {
-                    let sni = if rng.gen_bool(0.5) && self.config.randomize_capitalization {
-                        self.randomize_capitalization(original_sni)
-                    } else {
-                        self.apply_browser_capitalization(original_sni)
-                    };
-                    sni
+                    self.apply_browser_capitalization(original_sni)
                 }
             }
         }
@@ -249,13 +298,10 @@ impl SNIObfuscator {
 
     /// Get a diverse set of SNI values for rotation
     pub fn get_rotation_set(&self, count: usize) -> Vec<String> {
-        let mut rng = rand::thread_rng();
         let mut result = Vec::new();
 
         for _ in 0..count {
-            result.push(self.obfuscate_sni(
-                FAKE_SNI_POOL.choose(&mut rng).unwrap_or(&"google.com"),
-            ));
+            result.push(self.obfuscate_sni(&self.get_random_fake_sni()));
         }
 
         // Remove duplicates while preserving some variety
@@ -266,6 +312,23 @@ impl SNIObfuscator {
         result
     }
 
+    /// ClientHello spec of the configured browser fingerprint
+    pub fn client_hello_spec(&self) -> ClientHelloSpec {
+        self.config
+            .browser_fingerprint
+            .unwrap_or(BrowserFingerprint::Chrome)
+            .client_hello_spec(self.config.os)
+    }
+
+    /// Build a complete ClientHello carrying the obfuscated SNI, with the
+    /// extension set of the configured browser fingerprint
+    pub fn build_client_hello(&self, original_sni: &str) -> Vec<u8> {
+        let obfuscated_sni = self.obfuscate_sni(original_sni);
+        ClientHelloBuilder::new(self.client_hello_spec())
+            .server_name(&obfuscated_sni)
+            .build()
+    }
+
     /// Create SNI extension data with obfuscation
     /// This generates the raw TLS extension bytes
     pub fn create_sni_extension(&self, original_sni: &str) -> Vec<u8> {
@@ -289,10 +352,10 @@ impl SNIObfuscator {
         extension.extend_from_slice(&[0x00, 0x00]);
 
         // Build name data
-        let name_data = vec![0x00]; // Name type: host_name
         let sni_bytes = sni.as_bytes();
-        extension.extend_from_slice(&(sni_bytes.len() as u16).to_be_bytes());
-        extension.extend_from_slice(sni_bytes);
+        let mut name_data = vec![0x00]; // Name type: host_name
+        name_data.extend_from_slice(&(sni_bytes.len() as u16).to_be_bytes());
+        name_data.extend_from_slice(sni_bytes);
 
         // Server Name List
         let mut list = Vec::new();
@@ -373,7 +436,7 @@ mod tests {
         let obfuscator = SNIObfuscator::new();
         let sni = obfuscator.get_random_fake_sni();
         assert!(!sni.is_empty());
-        assert!(FAKE_SNI_POOL.contains(&sni.as_str()));
+        assert!(obfuscator.pool().domains().contains(&sni));
     }
 
     #[test]
@@ -413,6 +476,37 @@ mod tests {
         let extension = obfuscator.create_sni_extension("example.com");
         // Should have valid format
         assert!(extension.len() > 6); // At least header + data
+
+        // type, extension length, list length, name type, name length, name
+        let name_len = u16::from_be_bytes([extension[7], extension[8]]) as usize;
+        assert_eq!(u16::from_be_bytes([extension[2], extension[3]]) as usize, name_len + 5);
+        assert_eq!(u16::from_be_bytes([extension[4], extension[5]]) as usize, name_len + 3);
+        assert_eq!(extension[6], 0x00);
+        assert_eq!(extension.len(), 9 + name_len);
+    }
+
+    #[test]
+    fn test_client_hello_follows_browser_and_os() {
+        use crate::fingerprint::verify_spec;
+
+        let firefox = SNIObfuscator::with_config(SNIObfuscationConfig {
+            browser_fingerprint: Some(BrowserFingerprint::Firefox),
+            ..Default::default()
+        });
+        let hello = firefox.build_client_hello("example.com");
+        let spec = ClientHelloSpec::for_browser(BrowserFingerprint::Firefox);
+        assert!(verify_spec(&hello, &spec).is_ok());
+
+        // Every browser on iOS presents Safari's handshake
+        let ios = SNIObfuscator::with_config(SNIObfuscationConfig {
+            browser_fingerprint: Some(BrowserFingerprint::Chrome),
+            os: Some(OsFamily::Ios),
+            ..Default::default()
+        });
+        assert_eq!(
+            ios.client_hello_spec(),
+            BrowserFingerprint::Safari.client_hello_spec(None)
+        );
     }
 
     #[test]
@@ -432,6 +526,20 @@ mod tests {
         assert!(stats.is_fake_domain);
     }
 
+    #[test]
+    fn test_swapped_pool_is_used() {
+        let config = SNIObfuscationConfig {
+            strategy: ObfuscationStrategy::RandomDomain,
+            ..Default::default()
+        };
+        let obfuscator = SNIObfuscator::with_config(config);
+        obfuscator
+            .pool()
+            .swap(vec!["fresh.example.com".to_string()])
+            .unwrap();
+        assert_eq!(obfuscator.obfuscate_sni("blocked.example"), "fresh.example.com");
+    }
+
     #[test]
     fn test_title_case() {
         assert_eq!(SNIObfuscator::title_case("example.com"), "Example.Com");
@@ -0,0 +1,272 @@
+//! Routing-safe fake-SNI policy
+//! Only substitutes the SNI for destinations known to tolerate it; servers that
+//! route on SNI keep their real name, hidden by ClientHello fragmentation instead
+
+use crate::client_hello::{ClientHelloBuilder, TLSProfileConfig};
+use crate::error::{Error, Result};
+use crate::sni_obfuscation::SNIObfuscator;
+use crate::tls_fragmentation::{
+    FragmentationStrategy, FragmentedPacket, TLSFragmentationConfig, TLSFragmenter,
+};
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Configuration for the fake-SNI policy
+#[derive(Clone, Debug)]
+pub struct FakeSniPolicyConfig {
+    /// Destinations that accept any SNI; `*.example.com` matches subdomains
+    pub allowlist: Vec<String>,
+    /// Also substitute for destinations that passed a fake-SNI probe
+    pub trust_probe_results: bool,
+    /// Ask the caller to pad records when the real SNI is kept
+    pub pad_records_on_fallback: bool,
+}
+
+impl Default for FakeSniPolicyConfig {
+    fn default() -> Self {
+        FakeSniPolicyConfig {
+            allowlist: Vec::new(),
+            trust_probe_results: true,
+            pad_records_on_fallback: true,
+        }
+    }
+}
+
+/// How the SNI is handled for one connection
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SniDecision {
+    /// The destination tolerates a fake SNI; present this one
+    Substitute(String),
+    /// Keep the real SNI and hide it from DPI with fragmentation/padding
+    Preserve {
+        fragmentation: FragmentationStrategy,
+        pad_records: bool,
+    },
+}
+
+/// Decides per destination whether SNI substitution is safe
+pub struct FakeSniPolicy {
+    config: FakeSniPolicyConfig,
+    obfuscator: SNIObfuscator,
+    /// Probe results: true if the destination answered a fake-SNI hello
+    probed: RwLock<HashMap<String, bool>>,
+}
+
+impl FakeSniPolicy {
+    /// Create a policy drawing fake names from the given obfuscator's pool
+    pub fn new(config: FakeSniPolicyConfig, obfuscator: SNIObfuscator) -> Self {
+        FakeSniPolicy {
+            config,
+            obfuscator,
+            probed: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Check whether a destination is known to tolerate a fake SNI
+    pub fn tolerates_fake_sni(&self, destination: &str) -> bool {
+        let destination = destination.to_ascii_lowercase();
+        if self
+            .config
+            .allowlist
+            .iter()
+            .any(|pattern| matches_pattern(pattern, &destination))
+        {
+            return true;
+        }
+
+        self.config.trust_probe_results
+            && self
+                .probed
+                .read()
+                .get(&destination)
+                .copied()
+                .unwrap_or(false)
+    }
+
+    /// Record the outcome of a fake-SNI probe done elsewhere
+    pub fn record_probe(&self, destination: &str, tolerated: bool) {
+        self.probed
+            .write()
+            .insert(destination.to_ascii_lowercase(), tolerated);
+    }
+
+    /// Decide how to handle the SNI for a destination
+    pub fn decide(&self, destination: &str) -> SniDecision {
+        if self.tolerates_fake_sni(destination) {
+            if let Some(fake) = self.obfuscator.pool().choose() {
+                return SniDecision::Substitute(fake);
+            }
+        }
+
+        SniDecision::Preserve {
+            fragmentation: FragmentationStrategy::SniSplit,
+            pad_records: self.config.pad_records_on_fallback,
+        }
+    }
+
+    /// Build the ClientHello packets to send for a destination
+    pub fn client_hello(
+        &self,
+        destination: &str,
+        profile: &TLSProfileConfig,
+    ) -> Result<(SniDecision, Vec<FragmentedPacket>)> {
+        let decision = self.decide(destination);
+
+        let packets = match &decision {
+            SniDecision::Substitute(fake) => {
+                let hello = ClientHelloBuilder::from_profile(profile)
+                    .server_name(fake)
+                    .build();
+                vec![FragmentedPacket {
+                    data: hello,
+                    delay_ms: 0,
+                }]
+            }
+            SniDecision::Preserve { fragmentation, .. } => {
+                let hello = ClientHelloBuilder::from_profile(profile)
+                    .server_name(destination)
+                    .build();
+                let fragmenter = TLSFragmenter::with_config(TLSFragmentationConfig {
+                    strategy: *fragmentation,
+                    ..Default::default()
+                });
+                fragmenter
+                    .fragment_client_hello(&hello)
+                    .map_err(Error::DPIBypassError)?
+            }
+        };
+
+        Ok((decision, packets))
+    }
+
+    /// Check whether a server completes a handshake under a fake SNI
+    ///
+    /// Servers that route on SNI answer an unknown name with an alert or by
+    /// closing; tolerant ones reply with a ServerHello. The result is
+    /// remembered for `tolerates_fake_sni`.
+    pub async fn probe(&self, destination: &str, port: u16, profile: &TLSProfileConfig) -> bool {
+        let fake = match self.obfuscator.pool().choose() {
+            Some(fake) => fake,
+            None => return false,
+        };
+        let hello = ClientHelloBuilder::from_profile(profile)
+            .server_name(&fake)
+            .build();
+
+        let attempt = async {
+            let mut stream = TcpStream::connect((destination, port)).await?;
+            stream.write_all(&hello).await?;
+            let mut header = [0u8; 5];
+            stream.read_exact(&mut header).await?;
+            Ok::<_, std::io::Error>(header[0] == CONTENT_TYPE_HANDSHAKE)
+        };
+
+        let tolerated = matches!(
+            tokio::time::timeout(PROBE_TIMEOUT, attempt).await,
+            Ok(Ok(true))
+        );
+        self.record_probe(destination, tolerated);
+        tolerated
+    }
+}
+
+/// Match a destination against an allowlist entry
+fn matches_pattern(pattern: &str, destination: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => destination
+            .strip_suffix(&suffix.to_ascii_lowercase())
+            .is_some_and(|rest| rest.ends_with('.')),
+        None => pattern.eq_ignore_ascii_case(destination),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client_hello::parse_client_hello;
+    use crate::tls_fragmentation::reassemble_fragments;
+    use tokio::net::TcpListener;
+
+    fn policy(allowlist: &[&str]) -> FakeSniPolicy {
+        let config = FakeSniPolicyConfig {
+            allowlist: allowlist.iter().map(|s| s.to_string()).collect(),
+            ..Default::default()
+        };
+        FakeSniPolicy::new(config, SNIObfuscator::new())
+    }
+
+    #[test]
+    fn test_allowlist_patterns() {
+        let policy = policy(&["cdn.example.com", "*.tolerant.net"]);
+        assert!(policy.tolerates_fake_sni("CDN.example.com"));
+        assert!(policy.tolerates_fake_sni("a.b.tolerant.net"));
+        assert!(!policy.tolerates_fake_sni("tolerant.net"));
+        assert!(!policy.tolerates_fake_sni("nottolerant.net"));
+    }
+
+    #[test]
+    fn test_unknown_destination_keeps_real_sni() {
+        let policy = policy(&[]);
+        let (decision, packets) = policy
+            .client_hello("routed.example.com", &TLSProfileConfig::default())
+            .unwrap();
+
+        assert!(matches!(decision, SniDecision::Preserve { .. }));
+        assert!(packets.len() > 1);
+        let data: Vec<Vec<u8>> = packets.into_iter().map(|p| p.data).collect();
+        let hello = parse_client_hello(&reassemble_fragments(&data)).unwrap();
+        assert_eq!(hello.server_name.as_deref(), Some("routed.example.com"));
+    }
+
+    #[test]
+    fn test_allowed_destination_gets_fake_sni() {
+        let policy = policy(&["cdn.example.com"]);
+        let (decision, packets) = policy
+            .client_hello("cdn.example.com", &TLSProfileConfig::default())
+            .unwrap();
+
+        let SniDecision::Substitute(fake) = decision else {
+            panic!("expected substitution");
+        };
+        let hello = parse_client_hello(&packets[0].data).unwrap();
+        assert_eq!(hello.server_name.as_deref(), Some(fake.as_str()));
+    }
+
+    #[tokio::test]
+    async fn test_probe_records_result() {
+        let tolerant = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tolerant_port = tolerant.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = tolerant.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = stream.read(&mut buf).await;
+            let _ = stream.write_all(&[0x16, 0x03, 0x03, 0x00, 0x00]).await;
+        });
+
+        let routing = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let routing_port = routing.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = routing.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = stream.read(&mut buf).await;
+            // unrecognized_name alert
+            let _ = stream
+                .write_all(&[0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 0x70])
+                .await;
+        });
+
+        let policy = policy(&[]);
+        let profile = TLSProfileConfig::default();
+        assert!(policy.probe("127.0.0.1", tolerant_port, &profile).await);
+        assert!(policy.tolerates_fake_sni("127.0.0.1"));
+
+        assert!(!policy.probe("127.0.0.1", routing_port, &profile).await);
+        assert!(!policy.tolerates_fake_sni("127.0.0.1"));
+    }
+}
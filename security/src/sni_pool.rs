@@ -0,0 +1,783 @@
+//! Loadable fake-SNI pools
+//! Pools can come from the built-in list, a local file or a signed remote URL,
+//! and can be swapped at runtime without restarting the worker
+
+use crate::error::{Error, Result};
+use crate::sni_obfuscation::FAKE_SNI_POOL;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use http_body_util::{BodyExt, Empty, Limited};
+use hyper::body::Bytes;
+use hyper_util::rt::TokioIo;
+use parking_lot::RwLock;
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::net::TcpStream;
+use tokio::sync::Semaphore;
+use tokio::task::{JoinHandle, JoinSet};
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+/// Upper bound on a downloaded pool list or signature
+const MAX_REMOTE_BODY: usize = 1024 * 1024;
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+const DEFAULT_PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_PROBE_CONCURRENCY: usize = 8;
+
+/// Domains that stay reachable from inside Iran: domestic services on the
+/// national network plus foreign infrastructure the filter leaves alone
+const IRAN_WHITELISTED_POOL: &[&str] = &[
+    // Domestic platforms
+    "digikala.com",
+    "aparat.com",
+    "divar.ir",
+    "snapp.ir",
+    "tapsi.ir",
+    "cafebazaar.ir",
+    "myket.ir",
+    "filimo.com",
+    "namava.ir",
+    "torob.com",
+    "varzesh3.com",
+    // Banking and payments
+    "shaparak.ir",
+    "zarinpal.com",
+    "bmi.ir",
+    // Operators
+    "mci.ir",
+    "irancell.ir",
+    // Foreign infrastructure left unfiltered
+    "microsoft.com",
+    "update.microsoft.com",
+    "apple.com",
+    "icloud.com",
+    "cdn.jsdelivr.net",
+];
+
+/// Large shared CDN hostnames, whose IP ranges also carry a lot of
+/// unrelated traffic
+const GLOBAL_CDN_POOL: &[&str] = &[
+    "cloudflare.com",
+    "cdnjs.cloudflare.com",
+    "ajax.cloudflare.com",
+    "cdn.jsdelivr.net",
+    "unpkg.com",
+    "fastly.com",
+    "global.fastly.net",
+    "akamai.com",
+    "akamaihd.net",
+    "cloudfront.net",
+    "azureedge.net",
+    "ajax.aspnetcdn.com",
+    "fonts.gstatic.com",
+];
+
+/// Named fake-SNI pool selection
+///
+/// Several domains in the general-purpose built-in list (facebook.com,
+/// twitter.com, youtube.com, ...) are blocked in Iran, and presenting a
+/// blocked name as the fake SNI draws exactly the attention it should avoid.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SniPoolProfile {
+    /// Domains known to be reachable from Iranian networks
+    #[default]
+    IranWhitelisted,
+    /// Large CDN hostnames
+    GlobalCdn,
+    /// An operator-supplied pool
+    Custom(SniPoolSource),
+}
+
+impl SniPoolProfile {
+    /// Profile name as used in configuration and reports
+    pub fn name(&self) -> &'static str {
+        match self {
+            SniPoolProfile::IranWhitelisted => "iran-whitelisted",
+            SniPoolProfile::GlobalCdn => "global-cdn",
+            SniPoolProfile::Custom(_) => "custom",
+        }
+    }
+
+    /// Domain list compiled in for this profile (`None` for custom pools)
+    pub fn builtin_domains(&self) -> Option<&'static [&'static str]> {
+        match self {
+            SniPoolProfile::IranWhitelisted => Some(IRAN_WHITELISTED_POOL),
+            SniPoolProfile::GlobalCdn => Some(GLOBAL_CDN_POOL),
+            SniPoolProfile::Custom(_) => None,
+        }
+    }
+}
+
+/// Where a fake-SNI pool is loaded from
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SniPoolSource {
+    /// The general-purpose list compiled into the binary
+    Builtin,
+    /// A local file with one domain per line (`#` starts a comment)
+    File(PathBuf),
+    /// A domain list served over HTTPS with a detached ed25519 signature at
+    /// `<url>.sig`; both the signature and `public_key` are base64
+    Remote { url: String, public_key: String },
+}
+
+/// Shared, hot-swappable fake-SNI pool
+///
+/// Clones share the same underlying list, so a swap through any handle is
+/// picked up by every obfuscator using the pool.
+#[derive(Clone, Debug)]
+pub struct SniPool {
+    domains: Arc<RwLock<Arc<Vec<String>>>>,
+    report: Arc<RwLock<Option<PoolReport>>>,
+}
+
+impl SniPool {
+    fn with_list(domains: Vec<String>) -> Self {
+        SniPool {
+            domains: Arc::new(RwLock::new(Arc::new(domains))),
+            report: Arc::new(RwLock::new(None)),
+        }
+    }
+
+    /// Create a pool with the built-in domain list
+    pub fn builtin() -> Self {
+        Self::with_list(FAKE_SNI_POOL.iter().map(|d| d.to_string()).collect())
+    }
+
+    /// Create a pool from an explicit domain list
+    pub fn from_domains(domains: Vec<String>) -> Result<Self> {
+        Ok(Self::with_list(normalize_domains(domains)?))
+    }
+
+    /// Create a pool for a profile
+    ///
+    /// Custom profiles start from the built-in list until `reload_profile`
+    /// has fetched their source.
+    pub fn for_profile(profile: &SniPoolProfile) -> Self {
+        match profile.builtin_domains() {
+            Some(domains) => Self::with_list(domains.iter().map(|d| d.to_string()).collect()),
+            None => Self::builtin(),
+        }
+    }
+
+    /// Load a pool from the given source
+    pub async fn load(source: &SniPoolSource) -> Result<Self> {
+        let pool = Self::builtin();
+        pool.reload(source).await?;
+        Ok(pool)
+    }
+
+    /// Replace the pool contents, returning the new pool size
+    pub fn swap(&self, domains: Vec<String>) -> Result<usize> {
+        let domains = normalize_domains(domains)?;
+        let len = domains.len();
+        *self.domains.write() = Arc::new(domains);
+        Ok(len)
+    }
+
+    /// Fetch the source again and swap it in
+    ///
+    /// On failure the current pool is left untouched.
+    pub async fn reload(&self, source: &SniPoolSource) -> Result<usize> {
+        let domains = match source {
+            SniPoolSource::Builtin => FAKE_SNI_POOL.iter().map(|d| d.to_string()).collect(),
+            SniPoolSource::File(path) => parse_pool_list(&tokio::fs::read_to_string(path).await?),
+            SniPoolSource::Remote { url, public_key } => {
+                parse_pool_list(&fetch_signed_list(url, public_key, MAX_REMOTE_BODY).await?)
+            }
+        };
+        self.swap(domains)
+    }
+
+    /// Reload the pool for a profile
+    pub async fn reload_profile(&self, profile: &SniPoolProfile) -> Result<usize> {
+        match profile {
+            SniPoolProfile::Custom(source) => self.reload(source).await,
+            _ => self.swap(
+                profile
+                    .builtin_domains()
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|d| d.to_string())
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Periodically reload the pool in the background
+    pub fn spawn_refresh(&self, source: SniPoolSource, interval: Duration) -> JoinHandle<()> {
+        let pool = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            // The first tick fires immediately; the caller already loaded the pool
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                match pool.reload(&source).await {
+                    Ok(len) => log::info!("Reloaded SNI pool ({} domains)", len),
+                    Err(e) => log::warn!("SNI pool reload failed, keeping current pool: {}", e),
+                }
+            }
+        })
+    }
+
+    /// Get a snapshot of the current domains
+    pub fn domains(&self) -> Arc<Vec<String>> {
+        self.domains.read().clone()
+    }
+
+    /// Pick a random domain from the pool
+    pub fn choose(&self) -> Option<String> {
+        self.domains.read().choose(&mut rand::thread_rng()).cloned()
+    }
+
+    /// Number of domains in the pool
+    pub fn len(&self) -> usize {
+        self.domains.read().len()
+    }
+
+    /// Check whether the pool is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Probe every domain and drop the ones that aren't healthy
+    ///
+    /// If no domain passes, the pool is kept as-is (an empty pool would leave
+    /// nothing to rotate through) and the report says so.
+    pub async fn validate(&self, validator: &SniPoolValidator) -> PoolReport {
+        let report = validator.check(&self.domains()).await;
+        self.prune(&report);
+        *self.report.write() = Some(report.clone());
+        report
+    }
+
+    /// Remove domains the report marks as unhealthy
+    pub fn prune(&self, report: &PoolReport) -> usize {
+        let healthy: Vec<String> = self
+            .domains()
+            .iter()
+            .filter(|domain| {
+                report
+                    .status_of(domain)
+                    .is_none_or(|status| status == DomainStatus::Healthy)
+            })
+            .cloned()
+            .collect();
+
+        if healthy.is_empty() {
+            log::warn!("No healthy SNI pool domains, keeping current pool");
+            return self.len();
+        }
+        let len = healthy.len();
+        *self.domains.write() = Arc::new(healthy);
+        len
+    }
+
+    /// Result of the most recent validation run
+    pub fn report(&self) -> Option<PoolReport> {
+        self.report.read().clone()
+    }
+}
+
+impl Default for SniPool {
+    fn default() -> Self {
+        Self::builtin()
+    }
+}
+
+/// Outcome of probing one fake-SNI candidate
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DomainStatus {
+    /// TCP and TLS handshakes completed
+    Healthy,
+    /// TCP connect failed (DNS failure, refused, or poisoned address)
+    Unreachable,
+    /// TCP connected but the handshake was reset or blackholed after the
+    /// ClientHello, which is what SNI filtering looks like
+    Blocked,
+    /// The handshake failed for another reason (e.g. certificate mismatch
+    /// from a block page)
+    TlsError(String),
+}
+
+/// Health of a single domain
+#[derive(Clone, Debug)]
+pub struct DomainHealth {
+    pub domain: String,
+    pub status: DomainStatus,
+    pub handshake_time: Option<Duration>,
+}
+
+/// Results of one pool validation run
+#[derive(Clone, Debug)]
+pub struct PoolReport {
+    pub checked_at: SystemTime,
+    pub results: Vec<DomainHealth>,
+}
+
+impl PoolReport {
+    /// Status recorded for a domain, if it was probed
+    pub fn status_of(&self, domain: &str) -> Option<DomainStatus> {
+        self.results
+            .iter()
+            .find(|health| health.domain.eq_ignore_ascii_case(domain))
+            .map(|health| health.status.clone())
+    }
+
+    /// Domains that passed the probe
+    pub fn healthy(&self) -> Vec<&str> {
+        self.results
+            .iter()
+            .filter(|health| health.status == DomainStatus::Healthy)
+            .map(|health| health.domain.as_str())
+            .collect()
+    }
+
+    /// Domains that look filtered from this vantage point
+    pub fn blocked(&self) -> Vec<&str> {
+        self.results
+            .iter()
+            .filter(|health| health.status == DomainStatus::Blocked)
+            .map(|health| health.domain.as_str())
+            .collect()
+    }
+}
+
+/// Probes fake-SNI candidates with a real TCP+TLS handshake
+#[derive(Clone, Debug)]
+pub struct SniPoolValidator {
+    pub port: u16,
+    pub timeout: Duration,
+    pub concurrency: usize,
+}
+
+impl SniPoolValidator {
+    /// Create a validator with default settings
+    pub fn new() -> Self {
+        SniPoolValidator {
+            port: 443,
+            timeout: DEFAULT_PROBE_TIMEOUT,
+            concurrency: DEFAULT_PROBE_CONCURRENCY,
+        }
+    }
+
+    /// Probe a single domain
+    pub async fn probe(&self, domain: &str) -> DomainHealth {
+        let start = Instant::now();
+        let status = self.probe_status(domain).await;
+        let handshake_time = (status == DomainStatus::Healthy).then(|| start.elapsed());
+
+        DomainHealth {
+            domain: domain.to_string(),
+            status,
+            handshake_time,
+        }
+    }
+
+    async fn probe_status(&self, domain: &str) -> DomainStatus {
+        let server_name = match ServerName::try_from(domain.to_string()) {
+            Ok(name) => name,
+            Err(e) => return DomainStatus::TlsError(e.to_string()),
+        };
+
+        let tcp = match tokio::time::timeout(self.timeout, TcpStream::connect((domain, self.port)))
+            .await
+        {
+            Ok(Ok(tcp)) => tcp,
+            Ok(Err(_)) | Err(_) => return DomainStatus::Unreachable,
+        };
+
+        match tokio::time::timeout(self.timeout, tls_connector().connect(server_name, tcp)).await {
+            Ok(Ok(_)) => DomainStatus::Healthy,
+            Ok(Err(e))
+                if matches!(
+                    e.kind(),
+                    ErrorKind::ConnectionReset
+                        | ErrorKind::ConnectionAborted
+                        | ErrorKind::UnexpectedEof
+                ) =>
+            {
+                DomainStatus::Blocked
+            }
+            Ok(Err(e)) => DomainStatus::TlsError(e.to_string()),
+            Err(_) => DomainStatus::Blocked,
+        }
+    }
+
+    /// Probe a list of domains concurrently
+    pub async fn check(&self, domains: &[String]) -> PoolReport {
+        let permits = Arc::new(Semaphore::new(self.concurrency.max(1)));
+        let mut tasks = JoinSet::new();
+
+        for (index, domain) in domains.iter().enumerate() {
+            let validator = self.clone();
+            let permits = permits.clone();
+            let domain = domain.clone();
+            tasks.spawn(async move {
+                let _permit = permits.acquire_owned().await;
+                (index, validator.probe(&domain).await)
+            });
+        }
+
+        let mut results = Vec::with_capacity(domains.len());
+        while let Some(joined) = tasks.join_next().await {
+            if let Ok(result) = joined {
+                results.push(result);
+            }
+        }
+        results.sort_by_key(|(index, _)| *index);
+
+        PoolReport {
+            checked_at: SystemTime::now(),
+            results: results.into_iter().map(|(_, health)| health).collect(),
+        }
+    }
+}
+
+impl Default for SniPoolValidator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// TLS connector trusting the bundled web PKI roots
+fn tls_connector() -> TlsConnector {
+    let mut roots = RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    let tls_config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(tls_config))
+}
+
+/// Parse a pool list: one domain per line, blank lines and `#` comments ignored
+pub fn parse_pool_list(text: &str) -> Vec<String> {
+    text.lines()
+        .map(|line| line.split('#').next().unwrap_or("").trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| line.to_string())
+        .collect()
+}
+
+/// Check a pool list against its detached signature
+pub fn verify_pool_signature(body: &[u8], signature_b64: &str, public_key_b64: &str) -> Result<()> {
+    let key_bytes: [u8; 32] = BASE64
+        .decode(public_key_b64.trim())
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| Error::ConfigError("Invalid SNI pool public key".to_string()))?;
+    let key = VerifyingKey::from_bytes(&key_bytes)
+        .map_err(|e| Error::ConfigError(format!("Invalid SNI pool public key: {}", e)))?;
+
+    let sig_bytes: [u8; 64] = BASE64
+        .decode(signature_b64.trim())
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| Error::DataError("Malformed SNI pool signature".to_string()))?;
+
+    key.verify(body, &Signature::from_bytes(&sig_bytes))
+        .map_err(|_| Error::DataError("SNI pool signature does not verify".to_string()))
+}
+
+/// Download a list of at most `max_len` bytes and its detached signature at
+/// `<url>.sig`, verifying before returning the text
+pub(crate) async fn fetch_signed_list(
+    url: &str,
+    public_key: &str,
+    max_len: usize,
+) -> Result<String> {
+    let body = https_get(url, max_len).await?;
+    let signature = https_get(&format!("{}.sig", url), MAX_REMOTE_BODY).await?;
+    let signature = String::from_utf8(signature)
+        .map_err(|_| Error::DataError("Malformed list signature".to_string()))?;
+
+    verify_pool_signature(&body, &signature, public_key)?;
+
+    String::from_utf8(body)
+        .map_err(|_| Error::DataError(format!("List at {} is not valid UTF-8", url)))
+}
+
+/// Minimal HTTPS GET returning the response body
+///
+/// Plain HTTP is refused: the signature protects integrity, but the list
+/// itself must not be visible to an on-path censor.
+async fn https_get(url: &str, max_len: usize) -> Result<Vec<u8>> {
+    let uri: hyper::Uri = url
+        .parse()
+        .map_err(|e| Error::ConfigError(format!("Invalid list URL {}: {}", url, e)))?;
+    if uri.scheme_str() != Some("https") {
+        return Err(Error::ConfigError(format!(
+            "List URL must use https: {}",
+            url
+        )));
+    }
+    let host = uri
+        .host()
+        .ok_or_else(|| Error::ConfigError(format!("List URL has no host: {}", url)))?
+        .to_string();
+    let port = uri.port_u16().unwrap_or(443);
+
+    let fetch = async {
+        let server_name = ServerName::try_from(host.clone())
+            .map_err(|e| Error::ConfigError(format!("Invalid list host: {}", e)))?;
+
+        let tcp = TcpStream::connect((host.as_str(), port)).await?;
+        let tls = tls_connector().connect(server_name, tcp).await?;
+
+        let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(tls))
+            .await
+            .map_err(|e| Error::DataError(format!("List fetch failed: {}", e)))?;
+        tokio::spawn(async move {
+            if let Err(e) = conn.await {
+                log::debug!("List fetch connection closed: {}", e);
+            }
+        });
+
+        let path = uri.path_and_query().map_or("/", |p| p.as_str());
+        let request = http::Request::get(path)
+            .header(http::header::HOST, host.as_str())
+            .body(Empty::<Bytes>::new())
+            .map_err(|e| Error::DataError(format!("List fetch failed: {}", e)))?;
+        let response = sender
+            .send_request(request)
+            .await
+            .map_err(|e| Error::DataError(format!("List fetch failed: {}", e)))?;
+        if !response.status().is_success() {
+            return Err(Error::DataError(format!(
+                "List fetch returned {} for {}",
+                response.status(),
+                url
+            )));
+        }
+
+        let body = Limited::new(response.into_body(), max_len)
+            .collect()
+            .await
+            .map_err(|e| Error::DataError(format!("List fetch failed: {}", e)))?;
+        Ok(body.to_bytes().to_vec())
+    };
+
+    tokio::time::timeout(FETCH_TIMEOUT, fetch)
+        .await
+        .map_err(|_| Error::DataError(format!("List fetch timed out: {}", url)))?
+}
+
+/// Check that a string is a plausible hostname for an SNI value
+fn is_valid_domain(domain: &str) -> bool {
+    domain.len() <= 253
+        && domain.contains('.')
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+/// Lowercase, validate and de-duplicate a domain list
+fn normalize_domains(domains: Vec<String>) -> Result<Vec<String>> {
+    let mut result: Vec<String> = Vec::with_capacity(domains.len());
+    for domain in domains {
+        let domain = domain.trim().to_ascii_lowercase();
+        if !is_valid_domain(&domain) {
+            return Err(Error::DataError(format!(
+                "Invalid domain in SNI pool: {:?}",
+                domain
+            )));
+        }
+        if !result.contains(&domain) {
+            result.push(domain);
+        }
+    }
+
+    if result.is_empty() {
+        return Err(Error::DataError("SNI pool is empty".to_string()));
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey};
+
+    #[test]
+    fn test_parse_pool_list() {
+        let list = "# fresh fronts\nexample.com\n\n  cdn.example.net  # via CDN\n";
+        assert_eq!(
+            parse_pool_list(list),
+            vec!["example.com", "cdn.example.net"]
+        );
+    }
+
+    #[test]
+    fn test_swap_is_shared() {
+        let pool = SniPool::builtin();
+        let handle = pool.clone();
+
+        let len = handle
+            .swap(vec![
+                "One.Example.com".to_string(),
+                "one.example.com".to_string(),
+            ])
+            .unwrap();
+        assert_eq!(len, 1);
+        assert_eq!(pool.choose().as_deref(), Some("one.example.com"));
+    }
+
+    #[test]
+    fn test_invalid_pools_are_rejected() {
+        let pool = SniPool::builtin();
+        let before = pool.len();
+
+        assert!(pool.swap(Vec::new()).is_err());
+        assert!(pool.swap(vec!["not a domain".to_string()]).is_err());
+        assert!(pool.swap(vec!["localhost".to_string()]).is_err());
+        assert_eq!(pool.len(), before);
+    }
+
+    #[test]
+    fn test_profiles_are_valid_pools() {
+        for profile in [SniPoolProfile::IranWhitelisted, SniPoolProfile::GlobalCdn] {
+            let domains = profile.builtin_domains().unwrap();
+            let pool =
+                SniPool::from_domains(domains.iter().map(|d| d.to_string()).collect()).unwrap();
+            assert_eq!(pool.len(), domains.len());
+        }
+    }
+
+    #[test]
+    fn test_iran_profile_excludes_blocked_domains() {
+        let pool = SniPool::for_profile(&SniPoolProfile::IranWhitelisted);
+        let domains = pool.domains();
+        for blocked in [
+            "facebook.com",
+            "twitter.com",
+            "youtube.com",
+            "instagram.com",
+        ] {
+            assert!(!domains.iter().any(|d| d == blocked));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_load_from_file() {
+        let path = std::env::temp_dir().join(format!("sni-pool-{}.txt", std::process::id()));
+        std::fs::write(&path, "alpha.example.com\nbeta.example.com\n").unwrap();
+
+        let pool = SniPool::load(&SniPoolSource::File(path.clone()))
+            .await
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(pool.len(), 2);
+    }
+
+    #[test]
+    fn test_signature_verification() {
+        let signing_key = SigningKey::from_bytes(&[7u8; 32]);
+        let public_key = BASE64.encode(signing_key.verifying_key().as_bytes());
+        let body = b"alpha.example.com\n";
+        let signature = BASE64.encode(signing_key.sign(body).to_bytes());
+
+        assert!(verify_pool_signature(body, &signature, &public_key).is_ok());
+        assert!(verify_pool_signature(b"evil.example.com\n", &signature, &public_key).is_err());
+        assert!(verify_pool_signature(body, "bm90IGEgc2ln", &public_key).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_probe_classifies_resets_and_refusals() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // Accept and immediately drop, like an injected reset after ClientHello
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                drop(stream);
+            }
+        });
+
+        let validator = SniPoolValidator {
+            port,
+            timeout: Duration::from_secs(2),
+            ..Default::default()
+        };
+        assert_eq!(
+            validator.probe("127.0.0.1").await.status,
+            DomainStatus::Blocked
+        );
+
+        let closed = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed_port = closed.local_addr().unwrap().port();
+        drop(closed);
+        let validator = SniPoolValidator {
+            port: closed_port,
+            ..validator
+        };
+        assert_eq!(
+            validator.probe("127.0.0.1").await.status,
+            DomainStatus::Unreachable
+        );
+    }
+
+    #[test]
+    fn test_prune_keeps_healthy_domains() {
+        let pool = SniPool::from_domains(vec![
+            "good.example.com".to_string(),
+            "bad.example.com".to_string(),
+        ])
+        .unwrap();
+        let report = PoolReport {
+            checked_at: SystemTime::now(),
+            results: vec![
+                DomainHealth {
+                    domain: "good.example.com".to_string(),
+                    status: DomainStatus::Healthy,
+                    handshake_time: Some(Duration::from_millis(80)),
+                },
+                DomainHealth {
+                    domain: "bad.example.com".to_string(),
+                    status: DomainStatus::Blocked,
+                    handshake_time: None,
+                },
+            ],
+        };
+
+        assert_eq!(report.blocked(), vec!["bad.example.com"]);
+        assert_eq!(pool.prune(&report), 1);
+        assert_eq!(pool.domains().as_slice(), ["good.example.com"]);
+
+        // A report with nothing healthy leaves the pool alone
+        let all_bad = PoolReport {
+            checked_at: SystemTime::now(),
+            results: vec![DomainHealth {
+                domain: "good.example.com".to_string(),
+                status: DomainStatus::Unreachable,
+                handshake_time: None,
+            }],
+        };
+        assert_eq!(pool.prune(&all_bad), 1);
+    }
+
+    #[tokio::test]
+    async fn test_remote_requires_https() {
+        let source = SniPoolSource::Remote {
+            url: "http://pool.example.com/sni.txt".to_string(),
+            public_key: String::new(),
+        };
+        let pool = SniPool::builtin();
+        assert!(matches!(
+            pool.reload(&source).await,
+            Err(Error::ConfigError(_))
+        ));
+    }
+}
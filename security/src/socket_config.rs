@@ -0,0 +1,136 @@
+//! Socket-level session parameters
+//! Applies the portable subset of `SessionParameters` (TTL or hop limit, MSS,
+//! receive buffer) to ordinary TCP sockets, for setups that can't use raw sockets
+
+use crate::dynamic_patterns::SessionParameters;
+use crate::error::Result;
+use socket2::SockRef;
+use std::net::SocketAddr;
+use tokio::net::{TcpSocket, TcpStream};
+
+/// Which parameters took effect on a socket
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AppliedParameters {
+    pub ttl: bool,
+    /// TCP_MAXSEG isn't settable on every platform
+    pub mss: bool,
+    pub recv_buffer: bool,
+}
+
+/// Sets session TCP parameters through socket options
+///
+/// TTL can be changed at any time. MSS and the receive buffer (which bounds
+/// the advertised window and window scale) only shape the SYN when set before
+/// connecting, so prefer `connect` over `apply` on an established stream.
+pub struct SocketConfigurator;
+
+impl SocketConfigurator {
+    /// Apply the parameters to an established stream
+    pub fn apply(params: &SessionParameters, stream: &TcpStream) -> Result<AppliedParameters> {
+        let ipv6 = stream.local_addr()?.is_ipv6();
+        Self::apply_to(params, SockRef::from(stream), ipv6)
+    }
+
+    /// Apply the parameters to a socket that hasn't connected yet
+    pub fn apply_unconnected(
+        params: &SessionParameters,
+        socket: &TcpSocket,
+        ipv6: bool,
+    ) -> Result<AppliedParameters> {
+        Self::apply_to(params, SockRef::from(socket), ipv6)
+    }
+
+    /// Connect to `addr` with the parameters applied from the SYN onwards
+    pub async fn connect(params: &SessionParameters, addr: SocketAddr) -> Result<TcpStream> {
+        let socket = if addr.is_ipv6() {
+            TcpSocket::new_v6()?
+        } else {
+            TcpSocket::new_v4()?
+        };
+        Self::apply_unconnected(params, &socket, addr.is_ipv6())?;
+        Ok(socket.connect(addr).await?)
+    }
+
+    fn apply_to(
+        params: &SessionParameters,
+        socket: SockRef<'_>,
+        ipv6: bool,
+    ) -> Result<AppliedParameters> {
+        let mut applied = AppliedParameters::default();
+
+        if ipv6 {
+            socket.set_unicast_hops_v6(params.ipv6.hop_limit as u32)?;
+        } else {
+            socket.set_ttl_v4(params.ttl as u32)?;
+        }
+        applied.ttl = true;
+
+        #[cfg(unix)]
+        {
+            let mss = if ipv6 {
+                params.ipv6.tcp_mss
+            } else {
+                params.tcp_mss
+            };
+            applied.mss = socket.set_tcp_mss(mss as u32).is_ok();
+        }
+
+        // The kernel derives the advertised window from the receive buffer
+        socket.set_recv_buffer_size(params.tcp_window_size as usize)?;
+        applied.recv_buffer = true;
+
+        Ok(applied)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dynamic_patterns::Ipv6Parameters;
+    use tokio::net::TcpListener;
+
+    fn params() -> SessionParameters {
+        SessionParameters {
+            tcp_window_size: 29200,
+            tcp_mss: 1360,
+            ttl: 57,
+            initial_rtt_ms: 40,
+            packet_timing_variance: 5,
+            ipv6: Ipv6Parameters::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_apply_to_established_stream() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        let applied = SocketConfigurator::apply(&params(), &stream).unwrap();
+        assert!(applied.ttl && applied.recv_buffer);
+        assert_eq!(stream.ttl().unwrap(), 57);
+    }
+
+    #[tokio::test]
+    async fn test_connect_applies_before_syn() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = SocketConfigurator::connect(&params(), listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let socket = SockRef::from(&stream);
+        assert_eq!(socket.ttl_v4().unwrap(), 57);
+        assert!(socket.recv_buffer_size().unwrap() >= 29200);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_mss_on_unconnected_socket() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let _guard = rt.enter();
+        let socket = TcpSocket::new_v4().unwrap();
+        let applied = SocketConfigurator::apply_unconnected(&params(), &socket, false).unwrap();
+        assert!(applied.mss);
+        assert_eq!(SockRef::from(&socket).tcp_mss().unwrap(), 1360);
+    }
+}
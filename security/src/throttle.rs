@@ -0,0 +1,221 @@
+//! Throttling detection
+//! Tells a throttled path (goodput pinned far below what it was) from a
+//! blocked one (stalls and resets), since each needs a different response
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Condition of the path
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PathVerdict {
+    Clean,
+    /// Data flows, but at a fraction of the rate the path showed before
+    Throttled,
+    /// Data stopped flowing or connections are being reset
+    Blocked,
+}
+
+/// What to do about a verdict
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Adaptation {
+    None,
+    /// Reconnect with a fresh handshake; throttling is usually tied to the
+    /// flow that was flagged, not to the method
+    Rehandshake,
+    /// The method itself is detected; move to another strategy
+    SwitchStrategy,
+}
+
+impl PathVerdict {
+    pub fn response(self) -> Adaptation {
+        match self {
+            PathVerdict::Clean => Adaptation::None,
+            PathVerdict::Throttled => Adaptation::Rehandshake,
+            PathVerdict::Blocked => Adaptation::SwitchStrategy,
+        }
+    }
+}
+
+/// Connection outcome reported by the host application
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NetworkEvent {
+    /// Connection reset by the network, typically an injected RST
+    ConnReset,
+    /// Connect or handshake timed out: packets are being dropped
+    Timeout,
+    /// Data flows, but far slower than the path allows
+    Throttle,
+}
+
+impl NetworkEvent {
+    pub fn verdict(self) -> PathVerdict {
+        match self {
+            NetworkEvent::ConnReset | NetworkEvent::Timeout => PathVerdict::Blocked,
+            NetworkEvent::Throttle => PathVerdict::Throttled,
+        }
+    }
+}
+
+/// Settings of the throttling detector
+#[derive(Clone, Debug)]
+pub struct ThrottleConfig {
+    /// Busy intervals the recent goodput is the median of
+    pub window: usize,
+    /// Recent goodput below this share of the best seen is throttling
+    pub throttle_ratio: f64,
+    /// Busy intervals in a row without a byte that count as blocked
+    pub stall_intervals: usize,
+}
+
+impl Default for ThrottleConfig {
+    fn default() -> Self {
+        ThrottleConfig {
+            window: 10,
+            throttle_ratio: 0.25,
+            stall_intervals: 3,
+        }
+    }
+}
+
+/// Classifies a path from goodput measured over time
+///
+/// Feed it one sample per measuring interval. Only intervals with data
+/// waiting count: an idle session moving nothing says nothing about the
+/// path.
+pub struct ThrottleDetector {
+    config: ThrottleConfig,
+    /// Goodput of recent busy intervals, bytes per second
+    recent: VecDeque<f64>,
+    /// Best median goodput seen
+    baseline: f64,
+    stalled: usize,
+    reset: bool,
+    verdict: PathVerdict,
+}
+
+impl ThrottleDetector {
+    pub fn new(config: ThrottleConfig) -> Self {
+        ThrottleDetector {
+            recent: VecDeque::with_capacity(config.window),
+            config,
+            baseline: 0.0,
+            stalled: 0,
+            reset: false,
+            verdict: PathVerdict::Clean,
+        }
+    }
+
+    pub fn verdict(&self) -> PathVerdict {
+        self.verdict
+    }
+
+    /// Best sustained goodput seen, bytes per second
+    pub fn baseline(&self) -> f64 {
+        self.baseline
+    }
+
+    /// Median goodput of the recent busy intervals, bytes per second
+    pub fn recent_goodput(&self) -> Option<f64> {
+        if self.recent.len() < self.config.window {
+            return None;
+        }
+        let mut sorted: Vec<f64> = self.recent.iter().copied().collect();
+        sorted.sort_by(f64::total_cmp);
+        Some(sorted[sorted.len() / 2])
+    }
+
+    /// Record `bytes` delivered over `interval` while data was waiting
+    pub fn record(&mut self, bytes: u64, interval: Duration) -> PathVerdict {
+        if interval.is_zero() {
+            return self.verdict;
+        }
+        if bytes == 0 {
+            self.stalled += 1;
+        } else {
+            self.stalled = 0;
+            self.reset = false;
+            if self.recent.len() == self.config.window {
+                self.recent.pop_front();
+            }
+            self.recent.push_back(bytes as f64 / interval.as_secs_f64());
+            if let Some(goodput) = self.recent_goodput() {
+                self.baseline = self.baseline.max(goodput);
+            }
+        }
+        self.classify()
+    }
+
+    /// Record a connection reset or handshake timeout
+    pub fn record_reset(&mut self) -> PathVerdict {
+        self.reset = true;
+        self.classify()
+    }
+
+    fn classify(&mut self) -> PathVerdict {
+        self.verdict = if self.reset || self.stalled >= self.config.stall_intervals {
+            PathVerdict::Blocked
+        } else {
+            match self.recent_goodput() {
+                Some(goodput) if goodput < self.baseline * self.config.throttle_ratio => {
+                    PathVerdict::Throttled
+                }
+                _ => PathVerdict::Clean,
+            }
+        };
+        self.verdict
+    }
+}
+
+impl Default for ThrottleDetector {
+    fn default() -> Self {
+        Self::new(ThrottleConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn test_throttled_after_fast_start() {
+        let mut detector = ThrottleDetector::default();
+        // A few MB at full speed, then pinned to about 20 KB/s
+        for i in 0..15 {
+            let bytes = 2_000_000 + (i % 3) * 100_000;
+            assert_eq!(detector.record(bytes, SECOND), PathVerdict::Clean);
+        }
+        let mut verdict = PathVerdict::Clean;
+        for _ in 0..10 {
+            verdict = detector.record(20_000, SECOND);
+        }
+        assert_eq!(verdict, PathVerdict::Throttled);
+        assert_eq!(verdict.response(), Adaptation::Rehandshake);
+    }
+
+    #[test]
+    fn test_stall_is_blocking() {
+        let mut detector = ThrottleDetector::default();
+        for _ in 0..10 {
+            detector.record(500_000, SECOND);
+        }
+        detector.record(0, SECOND);
+        assert_eq!(detector.record(0, SECOND), PathVerdict::Clean);
+        assert_eq!(detector.record(0, SECOND), PathVerdict::Blocked);
+        assert_eq!(detector.verdict().response(), Adaptation::SwitchStrategy);
+
+        // Flowing again at the old rate
+        assert_eq!(detector.record(500_000, SECOND), PathVerdict::Clean);
+        assert_eq!(detector.record_reset(), PathVerdict::Blocked);
+    }
+
+    #[test]
+    fn test_slow_but_steady_path_is_clean() {
+        let mut detector = ThrottleDetector::default();
+        for _ in 0..50 {
+            assert_eq!(detector.record(30_000, SECOND), PathVerdict::Clean);
+        }
+        assert!((detector.baseline() - 30_000.0).abs() < 1.0);
+    }
+}
@@ -0,0 +1,240 @@
+//! Inter-packet timing model
+//! Draws send delays from a Markov chain of timing states (bursts, active
+//! exchange, think time) with heavy-tailed delays, like organic browsing
+
+use crate::dpi_bypass::TimingStrategy;
+use crate::error::{Error, Result};
+use rand::Rng;
+use std::time::Duration;
+
+/// Gaps shorter than this belong to the same burst
+const BURST_GAP: Duration = Duration::from_millis(50);
+/// Longest burst reported in a `TimingStrategy`
+const MAX_BURST: u32 = 64;
+
+/// One state of the timing chain
+#[derive(Clone, Debug)]
+pub struct TimingState {
+    pub name: String,
+    /// Median delay in this state
+    pub median: Duration,
+    /// Spread of the log-normal delay; 0 makes every delay the median
+    pub sigma: f64,
+    /// Weights of moving to each state after a delay, indexed like the
+    /// model's states
+    pub transitions: Vec<u32>,
+}
+
+impl TimingState {
+    pub fn new(name: &str, median: Duration, sigma: f64, transitions: Vec<u32>) -> Self {
+        TimingState {
+            name: name.to_string(),
+            median,
+            sigma,
+            transitions,
+        }
+    }
+}
+
+/// Markov chain over timing states
+#[derive(Clone, Debug)]
+pub struct TimingModel {
+    states: Vec<TimingState>,
+    /// Cap on any single delay
+    max_delay: Duration,
+}
+
+impl TimingModel {
+    pub fn new(states: Vec<TimingState>, max_delay: Duration) -> Result<Self> {
+        if states.is_empty() {
+            return Err(Error::ConfigError("Timing model has no states".to_string()));
+        }
+        for state in &states {
+            if state.transitions.len() != states.len()
+                || state.transitions.iter().all(|&weight| weight == 0)
+            {
+                return Err(Error::ConfigError(format!(
+                    "Timing state {} needs one weight per state, not all zero",
+                    state.name
+                )));
+            }
+            if !state.sigma.is_finite() || state.sigma < 0.0 {
+                return Err(Error::ConfigError(format!(
+                    "Timing state {} has an invalid sigma",
+                    state.name
+                )));
+            }
+        }
+        Ok(TimingModel { states, max_delay })
+    }
+
+    /// Browsing: bursts of back-to-back packets while a page loads, slower
+    /// exchange as scripts fetch resources, and seconds of reading between
+    pub fn browsing() -> Self {
+        Self::new(
+            vec![
+                TimingState::new("burst", Duration::from_millis(2), 0.8, vec![85, 12, 3]),
+                TimingState::new("active", Duration::from_millis(40), 1.0, vec![50, 40, 10]),
+                TimingState::new("think", Duration::from_secs(3), 1.2, vec![90, 10, 0]),
+            ],
+            Duration::from_secs(30),
+        )
+        .expect("built-in timing model is valid")
+    }
+
+    /// Idle session: occasional short exchanges spaced like a person glancing
+    /// at a page, with long reading pauses between
+    pub fn idle() -> Self {
+        Self::new(
+            vec![
+                TimingState::new("glance", Duration::from_secs(4), 0.6, vec![40, 60]),
+                TimingState::new("read", Duration::from_secs(25), 0.7, vec![70, 30]),
+            ],
+            Duration::from_secs(120),
+        )
+        .expect("built-in timing model is valid")
+    }
+
+    pub fn states(&self) -> &[TimingState] {
+        &self.states
+    }
+
+    pub fn max_delay(&self) -> Duration {
+        self.max_delay
+    }
+}
+
+impl Default for TimingModel {
+    fn default() -> Self {
+        Self::browsing()
+    }
+}
+
+/// Walks a `TimingModel`, producing one delay per packet
+#[derive(Clone, Debug)]
+pub struct TimingGenerator {
+    model: TimingModel,
+    state: usize,
+}
+
+impl TimingGenerator {
+    pub fn new(model: TimingModel) -> Self {
+        TimingGenerator { model, state: 0 }
+    }
+
+    pub fn model(&self) -> &TimingModel {
+        &self.model
+    }
+
+    /// Name of the current state
+    pub fn state(&self) -> &str {
+        &self.model.states[self.state].name
+    }
+
+    /// Delay before the next packet; advances the chain
+    pub fn next_delay(&mut self) -> Duration {
+        let mut rng = rand::thread_rng();
+        let state = &self.model.states[self.state];
+
+        // Log-normal via Box-Muller
+        let u1: f64 = 1.0 - rng.gen::<f64>();
+        let u2: f64 = rng.gen();
+        let z = (-2.0 * u1.ln()).sqrt() * (std::f64::consts::TAU * u2).cos();
+        let secs = state.median.as_secs_f64() * (state.sigma * z).exp();
+        let delay = Duration::from_secs_f64(secs.min(self.model.max_delay.as_secs_f64()));
+
+        let total: u32 = state.transitions.iter().sum();
+        let mut pick = rng.gen_range(0..total);
+        for (next, &weight) in state.transitions.iter().enumerate() {
+            if pick < weight {
+                self.state = next;
+                break;
+            }
+            pick -= weight;
+        }
+        delay
+    }
+
+    /// Summarize the next stretch of the chain as a `TimingStrategy`: the
+    /// packets sent with short gaps and the pause that ends them
+    pub fn strategy(&mut self) -> TimingStrategy {
+        let mut gaps = Vec::new();
+        let burst_delay = loop {
+            let delay = self.next_delay();
+            if delay >= BURST_GAP || gaps.len() as u32 + 1 >= MAX_BURST {
+                break delay;
+            }
+            gaps.push(delay);
+        };
+
+        let mean_gap = match gaps.len() {
+            0 => burst_delay,
+            n => gaps.iter().sum::<Duration>() / n as u32,
+        };
+        TimingStrategy {
+            inter_packet_delay_ms: (mean_gap.as_micros().div_ceil(1000) as u32).max(1),
+            burst_size: gaps.len() as u32 + 1,
+            burst_delay_ms: burst_delay.as_millis() as u32,
+        }
+    }
+}
+
+impl Default for TimingGenerator {
+    fn default() -> Self {
+        Self::new(TimingModel::browsing())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_browsing_delays_are_bursty() {
+        let mut generator = TimingGenerator::default();
+        let delays: Vec<Duration> = (0..5000).map(|_| generator.next_delay()).collect();
+        let short = delays.iter().filter(|d| **d < BURST_GAP).count();
+        let long = delays
+            .iter()
+            .filter(|d| **d > Duration::from_millis(500))
+            .count();
+        // Mostly back-to-back packets, with a tail of think times
+        assert!(short > 3000, "{} short delays", short);
+        assert!(long > 50, "{} long delays", long);
+        assert!(delays.iter().all(|d| *d <= Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn test_fixed_chain() {
+        let model = TimingModel::new(
+            vec![
+                TimingState::new("a", Duration::from_millis(10), 0.0, vec![0, 1]),
+                TimingState::new("b", Duration::from_millis(200), 0.0, vec![1, 0]),
+            ],
+            Duration::from_secs(1),
+        )
+        .unwrap();
+        let mut generator = TimingGenerator::new(model);
+        assert_eq!(generator.state(), "a");
+        assert_eq!(generator.next_delay(), Duration::from_millis(10));
+        assert_eq!(generator.state(), "b");
+        assert_eq!(generator.next_delay(), Duration::from_millis(200));
+
+        let strategy = generator.strategy();
+        assert_eq!(strategy.burst_size, 2);
+        assert_eq!(strategy.inter_packet_delay_ms, 10);
+        assert_eq!(strategy.burst_delay_ms, 200);
+    }
+
+    #[test]
+    fn test_invalid_models_rejected() {
+        let max = Duration::from_secs(1);
+        assert!(TimingModel::new(Vec::new(), max).is_err());
+        let bad_row = TimingState::new("a", Duration::from_millis(1), 1.0, vec![1, 1]);
+        assert!(TimingModel::new(vec![bad_row], max).is_err());
+        let zero_row = TimingState::new("a", Duration::from_millis(1), 1.0, vec![0]);
+        assert!(TimingModel::new(vec![zero_row], max).is_err());
+        let bad_sigma = TimingState::new("a", Duration::from_millis(1), -1.0, vec![1]);
+        assert!(TimingModel::new(vec![bad_sigma], max).is_err());
+    }
+}
@@ -2,40 +2,102 @@
 // Splits TLS ClientHello into multiple packets to evade DPI inspection
 // Implements randomized fragment sizes and inter-packet delays
 
+use crate::client_hello;
+use crate::dpi_bypass::is_blocked_first_length;
 use rand::Rng;
+use serde::{Deserialize, Serialize};
 use std::cmp;
 
 const MIN_FRAGMENT_SIZE: usize = 100;
 const MAX_FRAGMENT_SIZE: usize = 500;
 const MIN_DELAY_MS: u32 = 10;
 const MAX_DELAY_MS: u32 = 100;
+const MIN_FIRST_FRAGMENT_SIZE: usize = 150;
+const MAX_FIRST_FRAGMENT_SIZE: usize = 200;
+const MIN_APP_RECORD_SIZE: usize = 64;
+const MAX_APP_RECORD_SIZE: usize = 16384;
 
 // TLS Record Layer constants
 const TLS_RECORD_TYPE_HANDSHAKE: u8 = 0x16;
+const TLS_RECORD_TYPE_APPLICATION_DATA: u8 = 0x17;
 const TLS_VERSION_MAJOR: u8 = 0x03;
-const TLS_VERSION_MINOR: u8 = 0x03;
 const TLS_HANDSHAKE_TYPE_CLIENT_HELLO: u8 = 0x01;
 
+/// Where the ClientHello is cut into fragments
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FragmentationStrategy {
+    /// Random fragment sizes within the configured bounds
+    Random,
+    /// Cut exactly in the middle of the SNI hostname so no single packet
+    /// carries the full name; falls back to `Random` when no SNI is found
+    SniSplit,
+    /// Split the handshake message across several complete TLS records,
+    /// one per packet, with a record boundary inside the SNI hostname.
+    /// Middleboxes that only inspect the first record never see the full
+    /// ClientHello, while TLS stacks reassemble it as the spec requires
+    RecordSplit,
+}
+
+/// Record-header manipulation applied to the first TCP segment only
+///
+/// Signature-based DPI often matches the exact bytes `16 03 01 LL LL 01` and
+/// stops parsing when they don't line up. Compatibility per server stack:
+/// - `UnusualVersion`: OpenSSL, BoringSSL (nginx, Cloudflare, Google) and Go
+///   only check the major version of the first record, so any `0x03xx` value
+///   works. Some load balancers that parse the record themselves reject
+///   anything but `0x0301`-`0x0303`.
+/// - `ZeroLengthPrefix`: OpenSSL and BoringSSL skip a bounded number of empty
+///   records. RFC 8446 forbids empty handshake fragments, so stricter stacks
+///   (Go's crypto/tls, some Java and embedded stacks) may abort; keep it off
+///   for destinations that haven't been tested.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FirstRecordMode {
+    /// Leave the record header untouched
+    #[default]
+    Standard,
+    /// Advertise this record-layer version in the first record
+    UnusualVersion(u16),
+    /// Send an empty handshake record ahead of the ClientHello record
+    ZeroLengthPrefix,
+}
+
 /// Configuration for TLS fragmentation behavior
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct TLSFragmentationConfig {
+    pub strategy: FragmentationStrategy,
     pub min_fragment_size: usize,
     pub max_fragment_size: usize,
     pub min_delay_ms: u32,
     pub max_delay_ms: u32,
     pub randomize_delays: bool,
     pub preserve_record_boundary: bool,
+    /// Payload size bounds for re-framed application-data records
+    pub min_app_record_size: usize,
+    pub max_app_record_size: usize,
+    /// Record-header manipulation for the first segment
+    pub first_record: FirstRecordMode,
+    /// Largest TCP payload per packet, from `mtu::PathMtu::max_segment_payload`.
+    /// Fragments never exceed it, so the IP layer doesn't fragment them again
+    pub max_segment_size: Option<usize>,
 }
 
 impl Default for TLSFragmentationConfig {
     fn default() -> Self {
         TLSFragmentationConfig {
+            strategy: FragmentationStrategy::Random,
             min_fragment_size: MIN_FRAGMENT_SIZE,
             max_fragment_size: MAX_FRAGMENT_SIZE,
             min_delay_ms: MIN_DELAY_MS,
             max_delay_ms: MAX_DELAY_MS,
             randomize_delays: true,
             preserve_record_boundary: true,
+            min_app_record_size: MIN_APP_RECORD_SIZE,
+            max_app_record_size: MAX_APP_RECORD_SIZE,
+            first_record: FirstRecordMode::Standard,
+            max_segment_size: None,
         }
     }
 }
@@ -76,8 +138,8 @@ impl TLSFragmenter {
             return false;
         }
 
-        // Check TLS version (3.3 = TLS 1.2, 3.4 = TLS 1.3)
-        if data[1] != TLS_VERSION_MAJOR || (data[2] != 0x03 && data[2] != 0x04) {
+        // Check TLS version (3.1 - 3.4; browsers advertise 3.1 in the record layer)
+        if data[1] != TLS_VERSION_MAJOR || !(0x01..=0x04).contains(&data[2]) {
             return false;
         }
 
@@ -98,6 +160,41 @@ impl TLSFragmenter {
         Some(len + 5) // Add 5-byte header
     }
 
+    /// Pick the next fragment size so the remaining tail is either empty or
+    /// at least `min_fragment_size` bytes long
+    fn next_fragment_size<R: Rng>(
+        &self,
+        rng: &mut R,
+        remaining: usize,
+        first: bool,
+        cap: usize,
+    ) -> usize {
+        let min_size = self.config.min_fragment_size.clamp(1, cap);
+        let (lo, hi) = if first && self.config.preserve_record_boundary {
+            // Send at least the TLS record header + some handshake data
+            (MIN_FIRST_FRAGMENT_SIZE, MAX_FIRST_FRAGMENT_SIZE)
+        } else {
+            (min_size, self.config.max_fragment_size.max(min_size))
+        };
+        let (lo, hi) = (lo.min(cap), hi.min(cap));
+
+        if remaining >= lo + min_size {
+            let hi = cmp::min(hi, remaining - min_size);
+            rng.gen_range(cmp::min(lo, hi)..=hi)
+        } else {
+            cmp::min(remaining, hi.max(lo))
+        }
+    }
+
+    /// Generate the inter-packet delay for a fragment
+    fn next_delay<R: Rng>(&self, rng: &mut R, first: bool) -> u32 {
+        if first && !self.config.randomize_delays {
+            0
+        } else {
+            rng.gen_range(self.config.min_delay_ms..=self.config.max_delay_ms)
+        }
+    }
+
     /// Fragment ClientHello maintaining TLS record boundaries
     pub fn fragment_client_hello(&self, handshake: &[u8]) -> Result<Vec<FragmentedPacket>, String> {
         if !Self::is_client_hello(handshake) {
@@ -108,45 +205,43 @@ impl TLSFragmenter {
             return Err("ClientHello too short".to_string());
         }
 
+        match Self::get_record_length(handshake) {
+            Some(len) if len <= handshake.len() => {}
+            _ => return Err("Truncated ClientHello record".to_string()),
+        }
+
         let mut rng = rand::thread_rng();
+        if self.config.strategy == FragmentationStrategy::RecordSplit {
+            let mut packets = self.split_records(&mut rng, handshake);
+            self.apply_first_record_mode(&mut packets);
+            return Ok(self.enforce_segment_cap(packets));
+        }
+
         let mut packets = Vec::new();
         let mut offset = 0;
 
-        // Split the TLS record into fragments
+        if self.config.strategy == FragmentationStrategy::SniSplit {
+            if let Some(split) = Self::sni_split_offset(handshake) {
+                packets.push(FragmentedPacket {
+                    data: handshake[..split].to_vec(),
+                    delay_ms: self.next_delay(&mut rng, true),
+                });
+                offset = split;
+            }
+        }
+
+        // Split the (rest of the) TLS record into fragments
+        let cap = self.segment_cap();
         while offset < handshake.len() {
-            // Generate random fragment size
-            let fragment_size = if self.config.preserve_record_boundary {
-                // For first packet, try to send some header + partial payload
-                if offset == 0 {
-                    // Send at least the TLS record header + some handshake data
-                    let min_first = cmp::min(200, handshake.len());
-                    rng.gen_range(150..=min_first)
-                } else {
-                    rng.gen_range(
-                        self.config.min_fragment_size..=cmp::min(
-                            self.config.max_fragment_size,
-                            handshake.len() - offset,
-                        ),
-                    )
-                }
-            } else {
-                rng.gen_range(
-                    self.config.min_fragment_size..=cmp::min(
-                        self.config.max_fragment_size,
-                        handshake.len() - offset,
-                    ),
-                )
-            };
+            let first = packets.is_empty();
+            let remaining = handshake.len() - offset;
+            let fragment_size = self.next_fragment_size(&mut rng, remaining, first, cap);
 
             let end = cmp::min(offset + fragment_size, handshake.len());
             let fragment_data = handshake[offset..end].to_vec();
 
             // Generate delay for this packet (except potentially first packet)
-            let delay = if offset == 0 && !self.config.randomize_delays {
-                0
-            } else {
-                rng.gen_range(self.config.min_delay_ms..=self.config.max_delay_ms)
-            };
+            let delay = self.next_delay(&mut rng, first);
 
             packets.push(FragmentedPacket {
                 data: fragment_data,
@@ -156,15 +251,169 @@ impl TLSFragmenter {
             offset = end;
         }
 
-        // Ensure we have at least 2 packets
-        if packets.len() == 1 && packets[0].data.len() > self.config.max_fragment_size {
-            // Re-fragment the single packet
-            return self.fragment_client_hello(handshake);
+        self.apply_first_record_mode(&mut packets);
+        let mut packets = self.enforce_segment_cap(packets);
+        self.avoid_blocked_first_length(&mut rng, &mut packets);
+        Ok(packets)
+    }
+
+    /// Move the last byte of the first packet on to the next one if the
+    /// first packet has a length DPI blocks outright
+    fn avoid_blocked_first_length<R: Rng>(&self, rng: &mut R, packets: &mut Vec<FragmentedPacket>) {
+        let Some(first) = packets.first_mut() else {
+            return;
+        };
+        if !is_blocked_first_length(first.data.len()) {
+            return;
+        }
+        let byte = first.data.pop().unwrap_or_default();
+        match packets.get_mut(1) {
+            Some(next) if next.data.len() < self.segment_cap() => next.data.insert(0, byte),
+            _ => packets.insert(
+                1,
+                FragmentedPacket {
+                    data: vec![byte],
+                    delay_ms: self.next_delay(rng, false),
+                },
+            ),
+        }
+    }
+
+    /// Largest fragment the configured segment size allows
+    fn segment_cap(&self) -> usize {
+        self.config.max_segment_size.unwrap_or(usize::MAX).max(1)
+    }
+
+    /// Split any packet still above the segment size, e.g. an SNI-split head
+    /// or a first record grown by `ZeroLengthPrefix`
+    fn enforce_segment_cap(&self, packets: Vec<FragmentedPacket>) -> Vec<FragmentedPacket> {
+        let cap = self.segment_cap();
+        if packets.iter().all(|p| p.data.len() <= cap) {
+            return packets;
+        }
+        let mut result = Vec::with_capacity(packets.len() + 1);
+        for packet in packets {
+            for (i, chunk) in packet.data.chunks(cap).enumerate() {
+                result.push(FragmentedPacket {
+                    data: chunk.to_vec(),
+                    delay_ms: if i == 0 { packet.delay_ms } else { 0 },
+                });
+            }
+        }
+        result
+    }
+
+    /// Rewrite the record header carried by the first packet
+    fn apply_first_record_mode(&self, packets: &mut [FragmentedPacket]) {
+        let Some(first) = packets.first_mut() else {
+            return;
+        };
+        if first.data.len() < 5 {
+            return;
+        }
+
+        match self.config.first_record {
+            FirstRecordMode::Standard => {}
+            FirstRecordMode::UnusualVersion(version) => {
+                first.data[1..3].copy_from_slice(&version.to_be_bytes());
+            }
+            FirstRecordMode::ZeroLengthPrefix => {
+                let version = [first.data[1], first.data[2]];
+                let empty = [TLS_RECORD_TYPE_HANDSHAKE, version[0], version[1], 0x00, 0x00];
+                first.data.splice(0..0, empty);
+            }
+        }
+    }
+
+    /// Re-frame the handshake message as several TLS records
+    fn split_records<R: Rng>(&self, rng: &mut R, handshake: &[u8]) -> Vec<FragmentedPacket> {
+        // Bounds were checked by the caller
+        let record_len = Self::get_record_length(handshake).unwrap_or(handshake.len());
+        let message = &handshake[5..record_len];
+
+        let mut cuts = Vec::new();
+        if let Some(split) = Self::sni_split_offset(handshake) {
+            cuts.push(split - 5);
+        }
+        // Each record carries its 5-byte header in the same segment
+        let cap = self.segment_cap().saturating_sub(5).max(1);
+        let mut offset = 0;
+        while offset < message.len() {
+            offset += self.next_fragment_size(rng, message.len() - offset, false, cap);
+            if offset < message.len() {
+                cuts.push(offset);
+            }
+        }
+        cuts.push(message.len());
+        cuts.sort_unstable();
+        cuts.dedup();
+
+        // Keep the first packet, headers included, off lengths DPI blocks
+        // outright by ending its record a byte early
+        let mut header_len = 5;
+        if self.config.first_record == FirstRecordMode::ZeroLengthPrefix {
+            header_len += 5;
+        }
+        let first = cuts[0];
+        if first > 1 && is_blocked_first_length(header_len + first) {
+            if first == message.len() {
+                cuts.insert(0, first - 1);
+            } else {
+                cuts[0] = first - 1;
+            }
+        }
+
+        let mut packets = Vec::with_capacity(cuts.len() + 1);
+        let mut start = 0;
+        for end in cuts {
+            let chunk = &message[start..end];
+            let mut record = vec![TLS_RECORD_TYPE_HANDSHAKE, handshake[1], handshake[2]];
+            record.extend_from_slice(&(chunk.len() as u16).to_be_bytes());
+            record.extend_from_slice(chunk);
+
+            let first = packets.is_empty();
+            packets.push(FragmentedPacket {
+                data: record,
+                delay_ms: self.next_delay(rng, first),
+            });
+            start = end;
+        }
+
+        // Anything after the ClientHello record goes out unchanged
+        if record_len < handshake.len() {
+            packets.push(FragmentedPacket {
+                data: handshake[record_len..].to_vec(),
+                delay_ms: self.next_delay(rng, false),
+            });
         }
 
+        packets
+    }
+
+    /// Re-frame a run of application-data records into randomly sized records
+    ///
+    /// Record payloads are merged and split again, so only records whose
+    /// payload is a continuous stream (our own tunnel framing) can be
+    /// re-framed; records sealed individually by a TLS stack cannot.
+    pub fn refragment_application_data(
+        &self,
+        data: &[u8],
+    ) -> Result<Vec<FragmentedPacket>, String> {
+        let mut refragmenter = ApplicationDataRefragmenter::new(self.config.clone());
+        let packets = refragmenter.push(data)?;
+        if refragmenter.has_pending() {
+            return Err("Truncated application-data record".to_string());
+        }
         Ok(packets)
     }
 
+    /// Locate the middle of the SNI hostname, if the ClientHello carries one
+    fn sni_split_offset(handshake: &[u8]) -> Option<usize> {
+        client_hello::parse_client_hello(handshake)
+            .ok()?
+            .sni_split_offset()
+    }
+
     /// Fragment with Inter-Packet Delay (IPD) payload hiding
     pub fn fragment_with_ipd(&self, handshake: &[u8]) -> Result<Vec<FragmentedPacket>, String> {
         let packets = self.fragment_client_hello(handshake)?;
@@ -218,6 +467,81 @@ impl TLSFragmenter {
     }
 }
 
+impl Default for TLSFragmenter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Streaming re-framer for application-data records
+///
+/// Input may arrive in arbitrary chunks; partial records are held until
+/// complete, and every complete payload byte is emitted right away so the
+/// re-framing adds no latency.
+pub struct ApplicationDataRefragmenter {
+    config: TLSFragmentationConfig,
+    pending: Vec<u8>,
+}
+
+impl ApplicationDataRefragmenter {
+    /// Create a re-framer using the record size bounds from `config`
+    pub fn new(config: TLSFragmentationConfig) -> Self {
+        ApplicationDataRefragmenter {
+            config,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Feed stream bytes, returning re-framed records
+    pub fn push(&mut self, data: &[u8]) -> Result<Vec<FragmentedPacket>, String> {
+        self.pending.extend_from_slice(data);
+
+        let mut payload = Vec::new();
+        let mut version = [TLS_VERSION_MAJOR, 0x03];
+        let mut offset = 0;
+        while offset + 5 <= self.pending.len() {
+            if self.pending[offset] != TLS_RECORD_TYPE_APPLICATION_DATA {
+                return Err("Not an application-data record".to_string());
+            }
+            let len = ((self.pending[offset + 3] as usize) << 8) | (self.pending[offset + 4] as usize);
+            if offset + 5 + len > self.pending.len() {
+                break;
+            }
+            version = [self.pending[offset + 1], self.pending[offset + 2]];
+            payload.extend_from_slice(&self.pending[offset + 5..offset + 5 + len]);
+            offset += 5 + len;
+        }
+        self.pending.drain(..offset);
+
+        let min_size = self.config.min_app_record_size.clamp(1, MAX_APP_RECORD_SIZE);
+        let max_size = self.config.max_app_record_size.clamp(min_size, MAX_APP_RECORD_SIZE);
+
+        let mut rng = rand::thread_rng();
+        let mut packets = Vec::new();
+        let mut start = 0;
+        while start < payload.len() {
+            let size = rng.gen_range(min_size..=max_size);
+            let end = cmp::min(start + size, payload.len());
+
+            let mut record = vec![TLS_RECORD_TYPE_APPLICATION_DATA, version[0], version[1]];
+            record.extend_from_slice(&((end - start) as u16).to_be_bytes());
+            record.extend_from_slice(&payload[start..end]);
+            packets.push(FragmentedPacket {
+                data: record,
+                delay_ms: 0,
+            });
+            start = end;
+        }
+
+        Ok(packets)
+    }
+
+    /// Check whether a partial record is buffered
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+}
+
 /// Statistics about fragmentation
 #[derive(Clone, Debug)]
 pub struct FragmentationStats {
@@ -239,22 +563,75 @@ pub fn reassemble_fragments(packets: &[Vec<u8>]) -> Vec<u8> {
     result
 }
 
+/// Merge consecutive handshake records produced by `RecordSplit` back into
+/// a single record
+pub fn reassemble_records(data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut message = Vec::new();
+    let mut version = [TLS_VERSION_MAJOR, 0x01];
+    let mut offset = 0;
+
+    while offset < data.len() {
+        if offset + 5 > data.len() || data[offset] != TLS_RECORD_TYPE_HANDSHAKE {
+            return Err("Not a sequence of TLS handshake records".to_string());
+        }
+        let len = ((data[offset + 3] as usize) << 8) | (data[offset + 4] as usize);
+        if offset + 5 + len > data.len() {
+            return Err("Truncated TLS record".to_string());
+        }
+        version = [data[offset + 1], data[offset + 2]];
+        message.extend_from_slice(&data[offset + 5..offset + 5 + len]);
+        offset += 5 + len;
+    }
+
+    let mut record = vec![TLS_RECORD_TYPE_HANDSHAKE, version[0], version[1]];
+    record.extend_from_slice(&(message.len() as u16).to_be_bytes());
+    record.extend_from_slice(&message);
+    Ok(record)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    fn create_sample_client_hello() -> Vec<u8> {
-        // Minimal valid ClientHello packet
+    fn create_client_hello_with_sni(sni: &str) -> Vec<u8> {
+        let name = sni.as_bytes();
+        let mut extensions = vec![0x00, 0x00]; // server_name
+        extensions.extend_from_slice(&((name.len() + 5) as u16).to_be_bytes());
+        extensions.extend_from_slice(&((name.len() + 3) as u16).to_be_bytes());
+        extensions.push(0x00); // host_name
+        extensions.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        extensions.extend_from_slice(name);
+
+        let mut body = vec![0x03, 0x03]; // Client Version: TLS 1.2
+        body.extend_from_slice(&[0x5A; 32]); // Random
+        body.push(0x00); // Session ID length
+        body.extend_from_slice(&[0x00, 0x02, 0x13, 0x01]); // Cipher suites
+        body.extend_from_slice(&[0x01, 0x00]); // Compression methods
+
+        // Pad the record to the 517 bytes Chrome sends
+        let padding_len = 517 - 5 - 4 - body.len() - 2 - extensions.len() - 4;
+        extensions.extend_from_slice(&[0x00, 0x15]); // padding
+        extensions.extend_from_slice(&(padding_len as u16).to_be_bytes());
+        extensions.resize(extensions.len() + padding_len, 0x00);
+
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+
         let mut hello = vec![
             0x16, // TLS Record Type: Handshake
-            0x03, 0x03, // TLS Version: 1.2
-            0x00, 0x50, // Record Length: 80 bytes
-            0x01, // Handshake Type: ClientHello
+            0x03, 0x01, // TLS Version: 1.0 (record layer)
         ];
-        hello.resize(85, 0x00); // Pad to full length
+        hello.extend_from_slice(&((body.len() + 4) as u16).to_be_bytes());
+        hello.push(0x01); // Handshake Type: ClientHello
+        hello.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        hello.extend_from_slice(&body);
         hello
     }
 
+    fn create_sample_client_hello() -> Vec<u8> {
+        create_client_hello_with_sni("example.com")
+    }
+
     #[test]
     fn test_detect_client_hello() {
         let hello = create_sample_client_hello();
@@ -270,7 +647,7 @@ mod tests {
         let fragmenter = TLSFragmenter::new();
         let packets = fragmenter.fragment_client_hello(&hello).unwrap();
 
-        assert!(packets.len() >= 1);
+        assert!(!packets.is_empty());
 
         // Verify all data is present
         let reassembled = reassemble_fragments(
@@ -297,7 +674,7 @@ mod tests {
         let fragmenter = TLSFragmenter::new();
         let packets = fragmenter.fragment_with_ipd(&hello).unwrap();
 
-        assert!(packets.len() >= 1);
+        assert!(!packets.is_empty());
         if packets.len() > 1 {
             // First packet should have 0 delay
             assert_eq!(packets[0].delay_ms, 0);
@@ -319,4 +696,214 @@ mod tests {
         assert_eq!(stats.num_packets, packets.len());
         assert!(stats.total_size >= hello.len() - 10); // Allow small variance
     }
+
+    #[test]
+    fn test_sni_split_cuts_hostname() {
+        let hello = create_client_hello_with_sni("blocked.example.org");
+        let config = TLSFragmentationConfig {
+            strategy: FragmentationStrategy::SniSplit,
+            ..Default::default()
+        };
+        let fragmenter = TLSFragmenter::with_config(config);
+        let packets = fragmenter.fragment_client_hello(&hello).unwrap();
+
+        assert!(packets.len() >= 2);
+        // No single packet carries the full hostname
+        for packet in &packets {
+            assert!(!packet
+                .data
+                .windows(b"blocked.example.org".len())
+                .any(|w| w == b"blocked.example.org"));
+        }
+        assert!(packets[0].data.ends_with(b"blocked.e"));
+
+        let reassembled = reassemble_fragments(
+            &packets.iter().map(|p| p.data.clone()).collect::<Vec<_>>(),
+        );
+        assert_eq!(reassembled, hello);
+    }
+
+    #[test]
+    fn test_sni_split_without_sni_falls_back() {
+        let mut hello = create_sample_client_hello();
+        // Corrupt the extensions length so no SNI can be located
+        let ext_len_offset = 5 + 4 + 2 + 32 + 1 + 4 + 2;
+        hello[ext_len_offset] = 0xFF;
+
+        let config = TLSFragmentationConfig {
+            strategy: FragmentationStrategy::SniSplit,
+            ..Default::default()
+        };
+        let fragmenter = TLSFragmenter::with_config(config);
+        let packets = fragmenter.fragment_client_hello(&hello).unwrap();
+        assert!(packets[0].data.len() >= MIN_FIRST_FRAGMENT_SIZE);
+    }
+
+    #[test]
+    fn test_record_split_produces_valid_records() {
+        let hello = create_client_hello_with_sni("blocked.example.org");
+        let config = TLSFragmentationConfig {
+            strategy: FragmentationStrategy::RecordSplit,
+            ..Default::default()
+        };
+        let fragmenter = TLSFragmenter::with_config(config);
+        let packets = fragmenter.fragment_client_hello(&hello).unwrap();
+        assert!(packets.len() >= 2);
+
+        for packet in &packets {
+            // Every packet is exactly one complete handshake record
+            assert_eq!(packet.data[0], TLS_RECORD_TYPE_HANDSHAKE);
+            assert_eq!(
+                TLSFragmenter::get_record_length(&packet.data),
+                Some(packet.data.len())
+            );
+            assert!(!packet
+                .data
+                .windows(b"blocked.example.org".len())
+                .any(|w| w == b"blocked.example.org"));
+        }
+
+        let wire = reassemble_fragments(&packets.iter().map(|p| p.data.clone()).collect::<Vec<_>>());
+        assert_eq!(reassemble_records(&wire).unwrap(), hello);
+    }
+
+    #[test]
+    fn test_first_packet_avoids_blocked_lengths() {
+        let handshake = create_client_hello_with_sni("blocked.example.com");
+        for strategy in [
+            FragmentationStrategy::Random,
+            FragmentationStrategy::SniSplit,
+            FragmentationStrategy::RecordSplit,
+        ] {
+            for first_record in [FirstRecordMode::Standard, FirstRecordMode::ZeroLengthPrefix] {
+                for size in 60..=160 {
+                    let fragmenter = TLSFragmenter::with_config(TLSFragmentationConfig {
+                        strategy,
+                        first_record,
+                        min_fragment_size: size,
+                        max_fragment_size: size,
+                        preserve_record_boundary: false,
+                        ..Default::default()
+                    });
+                    let packets = fragmenter.fragment_client_hello(&handshake).unwrap();
+                    let first = packets[0].data.len();
+                    assert!(!is_blocked_first_length(first), "{:?} {}", strategy, size);
+
+                    let stream: Vec<u8> = packets.into_iter().flat_map(|p| p.data).collect();
+                    if strategy == FragmentationStrategy::RecordSplit {
+                        assert_eq!(reassemble_records(&stream).unwrap(), handshake);
+                    } else {
+                        assert!(stream.ends_with(&handshake));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_first_record_modes() {
+        let handshake = create_client_hello_with_sni("blocked.example.com");
+
+        let fragmenter = TLSFragmenter::with_config(TLSFragmentationConfig {
+            strategy: FragmentationStrategy::SniSplit,
+            first_record: FirstRecordMode::UnusualVersion(0x0300),
+            ..Default::default()
+        });
+        let packets = fragmenter.fragment_client_hello(&handshake).unwrap();
+        assert_eq!(&packets[0].data[..3], &[0x16, 0x03, 0x00]);
+        let data: Vec<Vec<u8>> = packets.into_iter().map(|p| p.data).collect();
+        assert_eq!(reassemble_fragments(&data)[3..], handshake[3..]);
+
+        let fragmenter = TLSFragmenter::with_config(TLSFragmentationConfig {
+            strategy: FragmentationStrategy::RecordSplit,
+            first_record: FirstRecordMode::ZeroLengthPrefix,
+            ..Default::default()
+        });
+        let packets = fragmenter.fragment_client_hello(&handshake).unwrap();
+        assert_eq!(&packets[0].data[..5], &[0x16, 0x03, 0x01, 0x00, 0x00]);
+        let stream: Vec<u8> = packets.into_iter().flat_map(|p| p.data).collect();
+        assert_eq!(reassemble_records(&stream).unwrap(), handshake);
+    }
+
+    #[test]
+    fn test_fragments_fit_segment_size() {
+        let hello = create_client_hello_with_sni("blocked.example.com");
+        for strategy in [
+            FragmentationStrategy::Random,
+            FragmentationStrategy::SniSplit,
+            FragmentationStrategy::RecordSplit,
+        ] {
+            let fragmenter = TLSFragmenter::with_config(TLSFragmentationConfig {
+                strategy,
+                max_segment_size: Some(120),
+                first_record: FirstRecordMode::ZeroLengthPrefix,
+                ..Default::default()
+            });
+            let packets = fragmenter.fragment_client_hello(&hello).unwrap();
+            assert!(packets.iter().all(|p| p.data.len() <= 120));
+
+            let stream: Vec<u8> = packets.into_iter().flat_map(|p| p.data).collect();
+            assert_eq!(reassemble_records(&stream).unwrap(), hello);
+        }
+    }
+
+    #[test]
+    fn test_reassemble_records_rejects_truncation() {
+        let hello = create_sample_client_hello();
+        assert!(reassemble_records(&hello[..hello.len() - 1]).is_err());
+        assert!(reassemble_records(&[0x17, 0x03, 0x03, 0x00, 0x00]).is_err());
+    }
+
+    fn app_data_records(payload: &[u8], record_size: usize) -> Vec<u8> {
+        let mut data = Vec::new();
+        for chunk in payload.chunks(record_size) {
+            data.extend_from_slice(&[TLS_RECORD_TYPE_APPLICATION_DATA, 0x03, 0x03]);
+            data.extend_from_slice(&(chunk.len() as u16).to_be_bytes());
+            data.extend_from_slice(chunk);
+        }
+        data
+    }
+
+    fn record_payloads(packets: &[FragmentedPacket]) -> Vec<u8> {
+        packets.iter().flat_map(|p| p.data[5..].to_vec()).collect()
+    }
+
+    #[test]
+    fn test_refragment_application_data() {
+        let payload: Vec<u8> = (0..20000).map(|i| i as u8).collect();
+        let config = TLSFragmentationConfig {
+            min_app_record_size: 100,
+            max_app_record_size: 3000,
+            ..Default::default()
+        };
+        let fragmenter = TLSFragmenter::with_config(config);
+        let packets = fragmenter
+            .refragment_application_data(&app_data_records(&payload, 1400))
+            .unwrap();
+
+        for packet in &packets[..packets.len() - 1] {
+            let len = TLSFragmenter::get_record_length(&packet.data).unwrap() - 5;
+            assert_eq!(packet.data.len(), len + 5);
+            assert!((100..=3000).contains(&len));
+        }
+        assert_eq!(record_payloads(&packets), payload);
+    }
+
+    #[test]
+    fn test_refragmenter_handles_partial_records() {
+        let payload: Vec<u8> = (0..5000).map(|i| (i * 7) as u8).collect();
+        let stream = app_data_records(&payload, 700);
+        let mut refragmenter = ApplicationDataRefragmenter::new(TLSFragmentationConfig::default());
+
+        let mut packets = Vec::new();
+        for chunk in stream.chunks(333) {
+            packets.extend(refragmenter.push(chunk).unwrap());
+        }
+        assert!(!refragmenter.has_pending());
+        assert_eq!(record_payloads(&packets), payload);
+
+        let fragmenter = TLSFragmenter::new();
+        assert!(fragmenter.refragment_application_data(&stream[..stream.len() - 1]).is_err());
+        assert!(fragmenter.refragment_application_data(&create_sample_client_hello()).is_err());
+    }
 }
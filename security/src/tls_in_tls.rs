@@ -0,0 +1,335 @@
+//! TLS-in-TLS shaping
+//! Pads and re-times the first writes of a tunnel that carries an inner TLS
+//! handshake, hiding the record sizes and round trips of the nested handshake
+
+use crate::error::{Error, Result};
+use rand::Rng;
+use std::ops::Range;
+
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const CONTENT_TYPE_APPLICATION_DATA: u8 = 0x17;
+const TLS_RECORD_HEADER_LEN: usize = 5;
+
+/// Frame header: command, content length, padding length
+const FRAME_HEADER_LEN: usize = 5;
+/// More shaped frames follow
+const COMMAND_CONTINUE: u8 = 0x00;
+/// Last shaped frame; the stream is passed through unframed afterwards
+const COMMAND_END: u8 = 0x01;
+
+/// Configuration for TLS-in-TLS shaping
+#[derive(Clone, Debug)]
+pub struct TlsInTlsConfig {
+    /// Stop shaping after this many writes even if the inner handshake hasn't finished
+    pub max_shaped_writes: usize,
+    /// Writes shorter than this are padded into `padded_len`
+    pub small_write_threshold: usize,
+    /// Target size range for padded small writes
+    pub padded_len: Range<usize>,
+    /// Extra random padding for writes above the threshold
+    pub max_large_padding: usize,
+    /// Delay range applied before each shaped write
+    pub min_delay_ms: u32,
+    pub max_delay_ms: u32,
+}
+
+impl Default for TlsInTlsConfig {
+    fn default() -> Self {
+        TlsInTlsConfig {
+            max_shaped_writes: 8,
+            small_write_threshold: 900,
+            padded_len: 900..1400,
+            max_large_padding: 256,
+            min_delay_ms: 0,
+            max_delay_ms: 25,
+        }
+    }
+}
+
+/// One shaped write for the outer tunnel
+#[derive(Clone, Debug)]
+pub struct ShapedWrite {
+    pub data: Vec<u8>,
+    pub delay_ms: u32,
+}
+
+/// Check whether data starts with a TLS handshake record
+fn is_tls_handshake(data: &[u8]) -> bool {
+    data.len() >= TLS_RECORD_HEADER_LEN && data[0] == CONTENT_TYPE_HANDSHAKE && data[1] == 0x03
+}
+
+/// Check whether a write carries inner application data, i.e. the inner
+/// handshake is over in this direction
+fn carries_application_data(data: &[u8]) -> bool {
+    let mut offset = 0;
+    while offset + TLS_RECORD_HEADER_LEN <= data.len() {
+        if data[offset] == CONTENT_TYPE_APPLICATION_DATA {
+            return true;
+        }
+        let len = u16::from_be_bytes([data[offset + 3], data[offset + 4]]) as usize;
+        offset += TLS_RECORD_HEADER_LEN + len;
+    }
+    false
+}
+
+/// Sending side: frames and pads writes until the inner handshake is done
+pub struct TlsInTlsShaper {
+    config: TlsInTlsConfig,
+    writes: usize,
+    finished: bool,
+}
+
+impl TlsInTlsShaper {
+    /// Create a shaper with default settings
+    pub fn new() -> Self {
+        Self::with_config(TlsInTlsConfig::default())
+    }
+
+    /// Create a shaper with custom settings
+    pub fn with_config(config: TlsInTlsConfig) -> Self {
+        TlsInTlsShaper {
+            config,
+            writes: 0,
+            finished: false,
+        }
+    }
+
+    /// Check whether writes are still being shaped
+    pub fn is_shaping(&self) -> bool {
+        !self.finished
+    }
+
+    /// Shape one write for the tunnel
+    pub fn shape(&mut self, data: &[u8]) -> ShapedWrite {
+        if self.finished {
+            return ShapedWrite {
+                data: data.to_vec(),
+                delay_ms: 0,
+            };
+        }
+
+        let mut rng = rand::thread_rng();
+        self.writes += 1;
+
+        // Only an inner TLS handshake needs hiding; anything else ends
+        // shaping on the first write
+        let inner_handshake = self.writes > 1 || is_tls_handshake(data);
+        let last = !inner_handshake
+            || carries_application_data(data)
+            || self.writes >= self.config.max_shaped_writes;
+
+        let padding_len = if !inner_handshake {
+            0
+        } else if data.len() < self.config.small_write_threshold {
+            let target = rng.gen_range(self.config.padded_len.clone());
+            target.saturating_sub(data.len())
+        } else {
+            rng.gen_range(0..=self.config.max_large_padding)
+        };
+
+        let padding_len = padding_len.min(u16::MAX as usize);
+        let chunks: Vec<&[u8]> = if data.is_empty() {
+            vec![&[]]
+        } else {
+            data.chunks(u16::MAX as usize).collect()
+        };
+
+        // Padding and the end command travel on the final frame of the write
+        let mut framed =
+            Vec::with_capacity(FRAME_HEADER_LEN * chunks.len() + data.len() + padding_len);
+        for (i, chunk) in chunks.iter().enumerate() {
+            let final_frame = i + 1 == chunks.len();
+            let pad = if final_frame { padding_len } else { 0 };
+            framed.push(if final_frame && last {
+                COMMAND_END
+            } else {
+                COMMAND_CONTINUE
+            });
+            framed.extend_from_slice(&(chunk.len() as u16).to_be_bytes());
+            framed.extend_from_slice(&(pad as u16).to_be_bytes());
+            framed.extend_from_slice(chunk);
+            framed.extend((0..pad).map(|_| rng.gen::<u8>()));
+        }
+
+        if last {
+            self.finished = true;
+        }
+
+        let delay_ms = if inner_handshake && self.config.max_delay_ms > self.config.min_delay_ms {
+            rng.gen_range(self.config.min_delay_ms..=self.config.max_delay_ms)
+        } else {
+            self.config.min_delay_ms
+        };
+
+        ShapedWrite {
+            data: framed,
+            delay_ms,
+        }
+    }
+}
+
+impl Default for TlsInTlsShaper {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Receiving side: strips shaping frames, then passes the stream through
+pub struct TlsInTlsUnshaper {
+    buffer: Vec<u8>,
+    finished: bool,
+}
+
+impl TlsInTlsUnshaper {
+    /// Create an unshaper
+    pub fn new() -> Self {
+        TlsInTlsUnshaper {
+            buffer: Vec::new(),
+            finished: false,
+        }
+    }
+
+    /// Feed received bytes, returning any payload that is now complete
+    ///
+    /// Frames may arrive split across reads; incomplete frames are buffered.
+    pub fn unshape(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        if self.finished {
+            return Ok(data.to_vec());
+        }
+
+        self.buffer.extend_from_slice(data);
+        let mut output = Vec::new();
+        let mut offset = 0;
+
+        while !self.finished && offset + FRAME_HEADER_LEN <= self.buffer.len() {
+            let command = self.buffer[offset];
+            if command != COMMAND_CONTINUE && command != COMMAND_END {
+                return Err(Error::DataError(format!(
+                    "Invalid TLS-in-TLS shaping command {}",
+                    command
+                )));
+            }
+            let content_len =
+                u16::from_be_bytes([self.buffer[offset + 1], self.buffer[offset + 2]]) as usize;
+            let padding_len =
+                u16::from_be_bytes([self.buffer[offset + 3], self.buffer[offset + 4]]) as usize;
+            let frame_end = offset + FRAME_HEADER_LEN + content_len + padding_len;
+            if frame_end > self.buffer.len() {
+                break;
+            }
+
+            let content_start = offset + FRAME_HEADER_LEN;
+            output.extend_from_slice(&self.buffer[content_start..content_start + content_len]);
+            offset = frame_end;
+            self.finished = command == COMMAND_END;
+        }
+
+        if self.finished {
+            // Whatever follows the last frame is unframed stream data
+            output.extend_from_slice(&self.buffer[offset..]);
+            self.buffer = Vec::new();
+        } else {
+            self.buffer.drain(..offset);
+        }
+        Ok(output)
+    }
+
+    /// Check whether the shaped phase is over
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+}
+
+impl Default for TlsInTlsUnshaper {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client_hello::{ClientHelloBuilder, TLSProfileConfig};
+
+    fn app_data_record(len: usize) -> Vec<u8> {
+        let mut record = vec![CONTENT_TYPE_APPLICATION_DATA, 0x03, 0x03];
+        record.extend_from_slice(&(len as u16).to_be_bytes());
+        record.extend(std::iter::repeat_n(0xAA, len));
+        record
+    }
+
+    #[test]
+    fn test_inner_handshake_is_padded() {
+        let mut shaper = TlsInTlsShaper::new();
+        let hello = ClientHelloBuilder::from_profile(&TLSProfileConfig::default())
+            .server_name("inner.example.com")
+            .build();
+
+        let shaped = shaper.shape(&hello);
+        assert!(shaped.data.len() >= 900);
+        assert!(shaper.is_shaping());
+
+        // A short client Finished flight is padded just the same
+        let finished = shaper.shape(&[0x14, 0x03, 0x03, 0x00, 0x01, 0x01]);
+        assert!(finished.data.len() >= 900);
+    }
+
+    #[test]
+    fn test_round_trip_with_split_reads() {
+        let mut shaper = TlsInTlsShaper::new();
+        let mut unshaper = TlsInTlsUnshaper::new();
+
+        let hello = ClientHelloBuilder::from_profile(&TLSProfileConfig::default())
+            .server_name("inner.example.com")
+            .build();
+        let app = app_data_record(300);
+        let tail = b"after shaping".to_vec();
+
+        let mut wire = Vec::new();
+        for write in [&hello, &app, &tail] {
+            wire.extend_from_slice(&shaper.shape(write).data);
+        }
+        assert!(!shaper.is_shaping());
+
+        let mut received = Vec::new();
+        for chunk in wire.chunks(97) {
+            received.extend_from_slice(&unshaper.unshape(chunk).unwrap());
+        }
+        assert!(unshaper.is_finished());
+        assert_eq!(received, [hello, app, tail].concat());
+    }
+
+    #[test]
+    fn test_non_tls_stream_ends_shaping_immediately() {
+        let mut shaper = TlsInTlsShaper::new();
+        let shaped = shaper.shape(b"GET / HTTP/1.1\r\n\r\n");
+        assert_eq!(shaped.data.len(), FRAME_HEADER_LEN + 18);
+        assert!(!shaper.is_shaping());
+
+        let mut unshaper = TlsInTlsUnshaper::new();
+        assert_eq!(
+            unshaper.unshape(&shaped.data).unwrap(),
+            b"GET / HTTP/1.1\r\n\r\n"
+        );
+        assert_eq!(unshaper.unshape(b"raw").unwrap(), b"raw");
+    }
+
+    #[test]
+    fn test_shaping_stops_after_limit() {
+        let mut shaper = TlsInTlsShaper::with_config(TlsInTlsConfig {
+            max_shaped_writes: 2,
+            ..Default::default()
+        });
+        let hello = ClientHelloBuilder::from_profile(&TLSProfileConfig::default()).build();
+        shaper.shape(&hello);
+        shaper.shape(&[0x16, 0x03, 0x03, 0x00, 0x00]);
+        assert!(!shaper.is_shaping());
+        assert_eq!(shaper.shape(b"plain").data, b"plain");
+    }
+
+    #[test]
+    fn test_invalid_command_is_rejected() {
+        let mut unshaper = TlsInTlsUnshaper::new();
+        assert!(unshaper.unshape(&[0x07, 0x00, 0x00, 0x00, 0x00]).is_err());
+    }
+}
@@ -0,0 +1,250 @@
+//! Traffic profiles learned from captures
+//! Extracts packet sizes and gaps per direction from a PCAP of benign
+//! traffic, for the morphing engine to replay as its target distribution
+
+use crate::error::{Error, Result};
+use crate::morphing::{LengthDistribution, Slot, TrafficShape};
+use crate::pcap::{self, PcapReader};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::time::Duration;
+
+/// Longest gap kept; longer pauses in a recording are the user walking
+/// away, and replaying them would stall the tunnel
+const MAX_GAP: Duration = Duration::from_secs(10);
+/// Packets replayed in order before jumping to another point of the
+/// recording, so bursts keep their structure
+const RUN_LEN: usize = 32;
+
+/// One observed packet: its payload length and the wait since the previous
+/// packet in the same direction
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProfilePacket {
+    pub len: usize,
+    pub gap_us: u64,
+}
+
+impl ProfilePacket {
+    pub fn gap(&self) -> Duration {
+        Duration::from_micros(self.gap_us)
+    }
+}
+
+/// Packets of one direction in capture order
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DirectionProfile {
+    pub packets: Vec<ProfilePacket>,
+}
+
+impl DirectionProfile {
+    /// Observed payload lengths as a distribution for `Morpher`
+    pub fn length_distribution(&self) -> Result<LengthDistribution> {
+        let lengths: Vec<usize> = self.packets.iter().map(|packet| packet.len).collect();
+        LengthDistribution::from_samples(&lengths)
+    }
+
+    /// Sizes and pacing of this direction as a traffic shape for `Shaper`
+    pub fn shape(&self) -> Result<ReplayShape> {
+        ReplayShape::new(self.packets.clone())
+    }
+}
+
+/// Size and timing profile of a recorded session
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrafficProfile {
+    /// Packets the client sent
+    pub upstream: DirectionProfile,
+    /// Packets the client received
+    pub downstream: DirectionProfile,
+}
+
+impl TrafficProfile {
+    /// Extract a profile from a PCAP capture
+    ///
+    /// `client` is the recording device's address; without it the source
+    /// of the first TCP SYN is taken, or of the first packet if the capture
+    /// holds no connection setup. Packets without payload, such as bare
+    /// ACKs, are left out: the tunnel never sends them itself.
+    pub fn from_pcap(data: &[u8], client: Option<IpAddr>) -> Result<Self> {
+        let mut reader = PcapReader::new(data)?;
+        let mut packets = Vec::new();
+        while let Some(record) = reader.next_record()? {
+            if let Some(info) = pcap::decode(reader.link_type(), &record.data) {
+                packets.push((record.timestamp, info));
+            }
+        }
+
+        let client = client
+            .or_else(|| {
+                packets
+                    .iter()
+                    .find(|(_, info)| info.syn)
+                    .map(|(_, info)| info.src.ip())
+            })
+            .or_else(|| packets.first().map(|(_, info)| info.src.ip()))
+            .ok_or_else(|| Error::DataError("Capture holds no TCP or UDP packets".to_string()))?;
+
+        let mut profile = TrafficProfile::default();
+        let mut last_up = None;
+        let mut last_down = None;
+        for (at, info) in packets {
+            if info.payload_len == 0 {
+                continue;
+            }
+            let (direction, last) = if info.src.ip() == client {
+                (&mut profile.upstream, &mut last_up)
+            } else if info.dst.ip() == client {
+                (&mut profile.downstream, &mut last_down)
+            } else {
+                continue;
+            };
+            let gap = last.map_or(Duration::ZERO, |last| at.saturating_sub(last));
+            *last = Some(at);
+            direction.packets.push(ProfilePacket {
+                len: info.payload_len,
+                gap_us: gap.min(MAX_GAP).as_micros() as u64,
+            });
+        }
+
+        if profile.upstream.packets.is_empty() && profile.downstream.packets.is_empty() {
+            return Err(Error::DataError(format!(
+                "No payload to or from {} in the capture",
+                client
+            )));
+        }
+        Ok(profile)
+    }
+}
+
+/// Replays recorded packets as a traffic shape
+///
+/// Plays runs of consecutive packets from random points of the recording,
+/// so the sizes and gaps follow the recorded distribution along with the
+/// burst structure of the session.
+pub struct ReplayShape {
+    packets: Vec<ProfilePacket>,
+    next: usize,
+    remaining: usize,
+    max_len: usize,
+}
+
+impl ReplayShape {
+    pub fn new(packets: Vec<ProfilePacket>) -> Result<Self> {
+        let max_len = packets
+            .iter()
+            .map(|packet| packet.len)
+            .max()
+            .ok_or_else(|| Error::ConfigError("Replay shape needs recorded packets".to_string()))?;
+        Ok(ReplayShape {
+            packets,
+            next: 0,
+            remaining: 0,
+            max_len,
+        })
+    }
+}
+
+impl TrafficShape for ReplayShape {
+    fn next_slot(&mut self) -> Slot {
+        if self.remaining == 0 || self.next == self.packets.len() {
+            self.next = rand::thread_rng().gen_range(0..self.packets.len());
+            self.remaining = RUN_LEN;
+        }
+        let packet = self.packets[self.next];
+        self.next += 1;
+        self.remaining -= 1;
+        Slot {
+            len: packet.len,
+            delay: packet.gap(),
+        }
+    }
+
+    fn max_len(&self) -> usize {
+        self.max_len
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pcap::{capture, tcp_frame};
+    use std::net::SocketAddr;
+
+    const SYN: u8 = 0x02;
+    const ACK: u8 = 0x10;
+
+    fn session() -> Vec<u8> {
+        let client: SocketAddr = "192.168.1.5:40000".parse().unwrap();
+        let server: SocketAddr = "157.240.1.1:443".parse().unwrap();
+        let ms = Duration::from_millis;
+        capture(&[
+            (ms(0), tcp_frame(client, server, SYN, 0)),
+            (ms(30), tcp_frame(server, client, SYN | ACK, 0)),
+            (ms(31), tcp_frame(client, server, ACK, 517)),
+            (ms(62), tcp_frame(server, client, ACK, 1448)),
+            (ms(63), tcp_frame(server, client, ACK, 1448)),
+            (ms(64), tcp_frame(client, server, ACK, 0)),
+            (ms(70), tcp_frame(server, client, ACK, 300)),
+            (ms(90), tcp_frame(client, server, ACK, 80)),
+            // Another host on the capture interface
+            (
+                ms(95),
+                tcp_frame("10.9.9.9:1".parse().unwrap(), server, ACK, 999),
+            ),
+            (ms(60_000), tcp_frame(client, server, ACK, 120)),
+        ])
+    }
+
+    #[test]
+    fn test_profile_from_capture() {
+        let profile = TrafficProfile::from_pcap(&session(), None).unwrap();
+        let up: Vec<(usize, u64)> = profile
+            .upstream
+            .packets
+            .iter()
+            .map(|packet| (packet.len, packet.gap_us))
+            .collect();
+        assert_eq!(up, vec![(517, 0), (80, 59_000), (120, 10_000_000)]);
+        let down: Vec<usize> = profile.downstream.packets.iter().map(|p| p.len).collect();
+        assert_eq!(down, vec![1448, 1448, 300]);
+        assert_eq!(
+            profile.downstream.packets[1].gap(),
+            Duration::from_millis(1)
+        );
+
+        let distribution = profile.downstream.length_distribution().unwrap();
+        assert_eq!(distribution.max_len(), 1448);
+    }
+
+    #[test]
+    fn test_explicit_client_and_json_round_trip() {
+        let server: IpAddr = "157.240.1.1".parse().unwrap();
+        let profile = TrafficProfile::from_pcap(&session(), Some(server)).unwrap();
+        // Seen from the server, directions swap
+        assert_eq!(profile.upstream.packets.len(), 3);
+        assert_eq!(profile.downstream.packets.len(), 4);
+
+        let json = serde_json::to_string(&profile).unwrap();
+        assert_eq!(
+            serde_json::from_str::<TrafficProfile>(&json).unwrap(),
+            profile
+        );
+
+        let stranger: IpAddr = "8.8.8.8".parse().unwrap();
+        assert!(TrafficProfile::from_pcap(&session(), Some(stranger)).is_err());
+    }
+
+    #[test]
+    fn test_replay_follows_recording() {
+        let profile = TrafficProfile::from_pcap(&session(), None).unwrap();
+        let mut shape = profile.downstream.shape().unwrap();
+        assert_eq!(shape.max_len(), 1448);
+        for _ in 0..100 {
+            let slot = shape.next_slot();
+            assert!([1448, 300].contains(&slot.len));
+            assert!(slot.delay <= Duration::from_millis(32));
+        }
+        assert!(DirectionProfile::default().shape().is_err());
+    }
+}
@@ -0,0 +1,290 @@
+//! Transparent TCP proxy
+//! Accepts connections redirected by the router's firewall (REDIRECT or
+//! TPROXY), recovers where they were headed, and forwards them with the
+//! ClientHello fragmented and session TCP parameters applied
+//!
+//! Redirect example: `iptables -t nat -A PREROUTING -i br-lan -p tcp --dport 443
+//! -j REDIRECT --to-ports 12345`. Upstream connections carry `upstream_mark` so
+//! rules can exclude them.
+
+use crate::client_hello::parse_client_hello;
+use crate::dynamic_patterns::SessionParameters;
+use crate::error::{Error, Result};
+use crate::socket_config::SocketConfigurator;
+use crate::tls_fragmentation::{FragmentationStrategy, TLSFragmentationConfig, TLSFragmenter};
+use socket2::SockRef;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+
+/// How redirected connections reach the proxy
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RedirectMode {
+    /// NAT REDIRECT; the original destination comes from SO_ORIGINAL_DST
+    #[default]
+    Redirect,
+    /// TPROXY; the socket's local address is the original destination
+    Tproxy,
+}
+
+/// Configuration for the transparent proxy
+#[derive(Clone, Debug)]
+pub struct TransparentConfig {
+    pub listen: SocketAddr,
+    pub mode: RedirectMode,
+    /// Fragment ClientHellos with these settings; None forwards them as-is
+    pub fragmentation: Option<TLSFragmentationConfig>,
+    /// TCP parameters for upstream connections
+    pub session: Option<SessionParameters>,
+    /// fwmark for upstream connections, so they aren't redirected again
+    pub upstream_mark: Option<u32>,
+    pub connect_timeout: Duration,
+    /// How long to wait for the client to speak first; server-first
+    /// protocols are forwarded untouched after this
+    pub first_read_timeout: Duration,
+}
+
+impl Default for TransparentConfig {
+    fn default() -> Self {
+        TransparentConfig {
+            listen: SocketAddr::from(([0, 0, 0, 0], 12345)),
+            mode: RedirectMode::Redirect,
+            fragmentation: Some(TLSFragmentationConfig {
+                strategy: FragmentationStrategy::RecordSplit,
+                ..Default::default()
+            }),
+            session: None,
+            upstream_mark: None,
+            connect_timeout: Duration::from_secs(10),
+            first_read_timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Destination a redirected connection was originally headed for
+pub fn original_destination(stream: &TcpStream, mode: RedirectMode) -> Result<SocketAddr> {
+    match mode {
+        RedirectMode::Tproxy => Ok(stream.local_addr()?),
+        RedirectMode::Redirect => {
+            let socket = SockRef::from(stream);
+            let addr = if stream.local_addr()?.is_ipv6() {
+                socket.original_dst_v6()?
+            } else {
+                socket.original_dst_v4()?
+            };
+            addr.as_socket().ok_or_else(|| {
+                Error::DataError("Original destination is not an IP address".to_string())
+            })
+        }
+    }
+}
+
+/// Transparent proxy accepting redirected connections
+pub struct TransparentProxy {
+    config: Arc<TransparentConfig>,
+    listener: TcpListener,
+}
+
+impl TransparentProxy {
+    /// Bind the listener; TPROXY mode needs CAP_NET_ADMIN for IP_TRANSPARENT
+    pub async fn bind(config: TransparentConfig) -> Result<Self> {
+        let socket = if config.listen.is_ipv6() {
+            TcpSocket::new_v6()?
+        } else {
+            TcpSocket::new_v4()?
+        };
+        socket.set_reuseaddr(true)?;
+        if config.mode == RedirectMode::Tproxy {
+            let sock = SockRef::from(&socket);
+            if config.listen.is_ipv6() {
+                sock.set_ip_transparent_v6(true)?;
+            } else {
+                sock.set_ip_transparent_v4(true)?;
+            }
+        }
+        socket.bind(config.listen)?;
+        let listener = socket.listen(1024)?;
+        Ok(TransparentProxy {
+            config: Arc::new(config),
+            listener,
+        })
+    }
+
+    /// Address the proxy listens on
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Accept and forward connections until the listener fails
+    pub async fn run(&self) -> Result<()> {
+        loop {
+            let (client, peer) = self.listener.accept().await?;
+            let config = self.config.clone();
+            tokio::spawn(async move {
+                let result = match original_destination(&client, config.mode) {
+                    Ok(dst) => forward(&config, client, dst).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = result {
+                    log::debug!(
+                        "Transparent connection from {} failed: {}",
+                        crate::privacy::redact(peer),
+                        e
+                    );
+                }
+            });
+        }
+    }
+}
+
+/// Open the upstream connection with the configured mark and TCP parameters
+async fn connect_upstream(config: &TransparentConfig, dst: SocketAddr) -> Result<TcpStream> {
+    let socket = if dst.is_ipv6() {
+        TcpSocket::new_v6()?
+    } else {
+        TcpSocket::new_v4()?
+    };
+    if let Some(mark) = config.upstream_mark {
+        SockRef::from(&socket).set_mark(mark)?;
+    }
+    if let Some(params) = &config.session {
+        SocketConfigurator::apply_unconnected(params, &socket, dst.is_ipv6())?;
+    }
+    tokio::time::timeout(config.connect_timeout, socket.connect(dst))
+        .await
+        .map_err(|_| Error::IoError(std::io::ErrorKind::TimedOut.into()))?
+        .map_err(Error::from)
+}
+
+/// Forward one client connection to `dst`, fragmenting its ClientHello
+pub async fn forward(
+    config: &TransparentConfig,
+    mut client: TcpStream,
+    dst: SocketAddr,
+) -> Result<()> {
+    let mut upstream = connect_upstream(config, dst).await?;
+    upstream.set_nodelay(true)?;
+
+    let mut first = vec![0u8; 16 * 1024];
+    let len = match tokio::time::timeout(config.first_read_timeout, client.read(&mut first)).await {
+        Ok(read) => read?,
+        Err(_) => {
+            tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+            return Ok(());
+        }
+    };
+    if len == 0 {
+        return Ok(());
+    }
+    let first = &first[..len];
+
+    match &config.fragmentation {
+        Some(fragmentation) if parse_client_hello(first).is_ok() => {
+            let fragmenter = TLSFragmenter::with_config(fragmentation.clone());
+            let packets = fragmenter
+                .fragment_client_hello(first)
+                .map_err(Error::DPIBypassError)?;
+            for packet in packets {
+                if packet.delay_ms > 0 {
+                    tokio::time::sleep(Duration::from_millis(packet.delay_ms as u64)).await;
+                }
+                upstream.write_all(&packet.data).await?;
+            }
+        }
+        _ => upstream.write_all(first).await?,
+    }
+
+    tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client_hello::{ClientHelloBuilder, TLSProfileConfig};
+    use crate::tls_fragmentation::reassemble_records;
+
+    /// Upstream that reports what it received and answers "pong"
+    async fn recording_upstream() -> (SocketAddr, tokio::sync::oneshot::Receiver<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut received = Vec::new();
+            let mut buf = [0u8; 4096];
+            // The hello arrives in pieces; wait for the whole thing
+            while let Ok(Ok(n)) =
+                tokio::time::timeout(Duration::from_millis(300), stream.read(&mut buf)).await
+            {
+                if n == 0 {
+                    break;
+                }
+                received.extend_from_slice(&buf[..n]);
+            }
+            let _ = stream.write_all(b"pong").await;
+            let _ = tx.send(received);
+        });
+        (addr, rx)
+    }
+
+    #[tokio::test]
+    async fn test_tproxy_destination_is_local_addr() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _client = TcpStream::connect(addr).await.unwrap();
+        let (accepted, _) = listener.accept().await.unwrap();
+        assert_eq!(
+            original_destination(&accepted, RedirectMode::Tproxy).unwrap(),
+            addr
+        );
+    }
+
+    #[tokio::test]
+    async fn test_forward_fragments_client_hello() {
+        let (upstream, received) = recording_upstream().await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (client, _) = listener.accept().await.unwrap();
+            let _ = forward(&TransparentConfig::default(), client, upstream).await;
+        });
+
+        let hello = ClientHelloBuilder::from_profile(&TLSProfileConfig::default())
+            .server_name("blocked.example")
+            .build();
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client.write_all(&hello).await.unwrap();
+
+        let mut reply = [0u8; 4];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"pong");
+
+        let wire = received.await.unwrap();
+        assert_ne!(wire, hello);
+        assert_eq!(reassemble_records(&wire).unwrap(), hello);
+    }
+
+    #[tokio::test]
+    async fn test_server_first_protocols_pass_through() {
+        let (upstream, _received) = recording_upstream().await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        let config = TransparentConfig {
+            first_read_timeout: Duration::from_millis(50),
+            ..Default::default()
+        };
+        tokio::spawn(async move {
+            let (client, _) = listener.accept().await.unwrap();
+            let _ = forward(&config, client, upstream).await;
+        });
+
+        // Client stays silent and still gets the server's bytes
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        let mut reply = [0u8; 4];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"pong");
+    }
+}
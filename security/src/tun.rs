@@ -0,0 +1,416 @@
+//! TUN device integration
+//! Reads IP packets from a TUN interface, runs each flow through its own
+//! security pipeline and forwards the result, for full-device protection
+
+use crate::client_hello::parse_client_hello;
+use crate::desync::{parse_segment, Desync, DesyncConfig, RawSocket, TcpFlow};
+use crate::error::{Error, Result};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::time::{Duration, Instant};
+use tokio::io::unix::AsyncFd;
+
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+const MAX_PACKET_LEN: usize = 65535;
+
+/// TUN interface carrying raw IP packets (no packet-info header)
+pub struct TunDevice {
+    fd: AsyncFd<File>,
+    name: String,
+}
+
+impl TunDevice {
+    /// Create or attach to a TUN interface (Linux; needs CAP_NET_ADMIN)
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn open(name: &str) -> Result<Self> {
+        const TUNSETIFF: libc::c_ulong = 0x400454ca;
+        const IFF_TUN: libc::c_short = 0x0001;
+        const IFF_NO_PI: libc::c_short = 0x1000;
+
+        #[repr(C)]
+        struct IfReq {
+            name: [u8; libc::IFNAMSIZ],
+            flags: libc::c_short,
+            _pad: [u8; 22],
+        }
+
+        if name.len() >= libc::IFNAMSIZ {
+            return Err(Error::ConfigError(format!(
+                "Interface name too long: {}",
+                name
+            )));
+        }
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/net/tun")?;
+
+        let mut request = IfReq {
+            name: [0; libc::IFNAMSIZ],
+            flags: IFF_TUN | IFF_NO_PI,
+            _pad: [0; 22],
+        };
+        request.name[..name.len()].copy_from_slice(name.as_bytes());
+
+        // SAFETY: TUNSETIFF reads and writes one ifreq, which `request` lays out
+        let rc = unsafe { libc::ioctl(file.as_raw_fd(), TUNSETIFF as _, &mut request) };
+        if rc < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        let end = request.name.iter().position(|&b| b == 0).unwrap_or(0);
+        let name = String::from_utf8_lossy(&request.name[..end]).into_owned();
+        Self::from_file(file, name)
+    }
+
+    /// Wrap a TUN descriptor opened elsewhere, e.g. by Android's VpnService
+    ///
+    /// # Safety
+    /// `fd` must be an open TUN descriptor that nothing else will close.
+    pub unsafe fn from_raw_fd(fd: RawFd, name: &str) -> Result<Self> {
+        Self::from_file(File::from_raw_fd(fd), name.to_string())
+    }
+
+    fn from_file(file: File, name: String) -> Result<Self> {
+        let fd = file.as_raw_fd();
+        // SAFETY: plain fcntl calls on a descriptor we own
+        unsafe {
+            let flags = libc::fcntl(fd, libc::F_GETFL);
+            if flags < 0 || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+        }
+        Ok(TunDevice {
+            fd: AsyncFd::new(file)?,
+            name,
+        })
+    }
+
+    /// Interface name
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Read one packet
+    pub async fn recv(&self, buf: &mut [u8]) -> Result<usize> {
+        loop {
+            let mut guard = self.fd.readable().await?;
+            match guard.try_io(|fd| fd.get_ref().read(buf)) {
+                Ok(result) => return Ok(result?),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    /// Write one packet back towards the device's applications
+    pub async fn send(&self, packet: &[u8]) -> Result<()> {
+        loop {
+            let mut guard = self.fd.writable().await?;
+            match guard.try_io(|fd| fd.get_ref().write(packet)) {
+                Ok(result) => {
+                    result?;
+                    return Ok(());
+                }
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+/// Transport flow a packet belongs to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FlowKey {
+    pub protocol: u8,
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+}
+
+/// Identify the TCP/UDP flow of an IPv4 or IPv6 packet
+pub fn parse_flow(packet: &[u8]) -> Option<FlowKey> {
+    let (protocol, src, dst, transport) = match packet.first()? >> 4 {
+        4 => {
+            let header_len = (packet[0] & 0x0f) as usize * 4;
+            if packet.len() < header_len.max(20) {
+                return None;
+            }
+            let src = Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]);
+            let dst = Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]);
+            (
+                packet[9],
+                IpAddr::V4(src),
+                IpAddr::V4(dst),
+                &packet[header_len..],
+            )
+        }
+        6 => {
+            if packet.len() < 40 {
+                return None;
+            }
+            let src: [u8; 16] = packet[8..24].try_into().ok()?;
+            let dst: [u8; 16] = packet[24..40].try_into().ok()?;
+            (
+                packet[6],
+                IpAddr::V6(Ipv6Addr::from(src)),
+                IpAddr::V6(Ipv6Addr::from(dst)),
+                &packet[40..],
+            )
+        }
+        _ => return None,
+    };
+
+    if !matches!(protocol, IPPROTO_TCP | IPPROTO_UDP) || transport.len() < 4 {
+        return None;
+    }
+    let src_port = u16::from_be_bytes([transport[0], transport[1]]);
+    let dst_port = u16::from_be_bytes([transport[2], transport[3]]);
+    Some(FlowKey {
+        protocol,
+        src: SocketAddr::new(src, src_port),
+        dst: SocketAddr::new(dst, dst_port),
+    })
+}
+
+/// Per-flow processing of outbound packets
+pub trait FlowPipeline: Send {
+    /// Process one packet, returning the packets to forward in its place
+    fn process(&mut self, packet: Vec<u8>) -> Result<Vec<Vec<u8>>>;
+}
+
+/// Where processed packets go
+pub trait PacketSink: Send + Sync {
+    fn send_packet(&self, packet: &[u8]) -> Result<()>;
+}
+
+impl PacketSink for RawSocket {
+    fn send_packet(&self, packet: &[u8]) -> Result<()> {
+        self.send(packet)
+    }
+}
+
+/// Pipeline applying desync to the ClientHello of a TCP flow
+///
+/// Passes every other packet through untouched.
+pub struct DesyncPipeline {
+    desync: Desync,
+    done: bool,
+}
+
+impl DesyncPipeline {
+    pub fn new(config: DesyncConfig) -> Self {
+        DesyncPipeline {
+            desync: Desync::new(config),
+            done: false,
+        }
+    }
+}
+
+impl FlowPipeline for DesyncPipeline {
+    fn process(&mut self, packet: Vec<u8>) -> Result<Vec<Vec<u8>>> {
+        if self.done {
+            return Ok(vec![packet]);
+        }
+        let Some(segment) = parse_segment(&packet) else {
+            // Not IPv4/TCP; nothing to desync in this flow
+            self.done = true;
+            return Ok(vec![packet]);
+        };
+        if segment.payload.is_empty() {
+            return Ok(vec![packet]);
+        }
+        self.done = true;
+        if parse_client_hello(&segment.payload).is_err() {
+            return Ok(vec![packet]);
+        }
+
+        let flow = TcpFlow {
+            src: segment.src,
+            dst: segment.dst,
+            seq: segment.seq,
+            ack: segment.ack,
+            window: segment.window,
+            ttl: segment.ttl,
+            server_ttl: None,
+        };
+        let segments = self.desync.segments(&flow, &segment.payload)?;
+        Ok(segments.into_iter().map(|s| s.packet).collect())
+    }
+}
+
+/// Creates the pipeline for a new flow
+pub type PipelineFactory = Box<dyn Fn(&FlowKey) -> Box<dyn FlowPipeline> + Send + Sync>;
+
+/// Runs outbound TUN packets through per-flow pipelines
+pub struct TunForwarder {
+    factory: PipelineFactory,
+    flows: HashMap<FlowKey, (Box<dyn FlowPipeline>, Instant)>,
+    idle_timeout: Duration,
+    last_sweep: Instant,
+}
+
+impl TunForwarder {
+    /// Create a forwarder building pipelines with `factory`
+    pub fn new(factory: PipelineFactory) -> Self {
+        TunForwarder {
+            factory,
+            flows: HashMap::new(),
+            idle_timeout: Duration::from_secs(300),
+            last_sweep: Instant::now(),
+        }
+    }
+
+    /// Forget flows idle for longer than `timeout`
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Number of tracked flows
+    pub fn flow_count(&self) -> usize {
+        self.flows.len()
+    }
+
+    /// Process one outbound packet; packets outside any TCP/UDP flow pass through
+    pub fn handle_packet(&mut self, packet: Vec<u8>) -> Result<Vec<Vec<u8>>> {
+        let now = Instant::now();
+        if now.duration_since(self.last_sweep) >= self.idle_timeout {
+            self.expire_idle(now);
+        }
+
+        let Some(key) = parse_flow(&packet) else {
+            return Ok(vec![packet]);
+        };
+        let factory = &self.factory;
+        let (pipeline, last_seen) = self
+            .flows
+            .entry(key)
+            .or_insert_with(|| (factory(&key), now));
+        *last_seen = now;
+        pipeline.process(packet)
+    }
+
+    /// Drop flows that have been idle past the timeout
+    pub fn expire_idle(&mut self, now: Instant) {
+        let timeout = self.idle_timeout;
+        self.flows
+            .retain(|_, (_, last_seen)| now.duration_since(*last_seen) < timeout);
+        self.last_sweep = now;
+    }
+
+    /// Forward packets from `device` to `sink` until an error occurs
+    pub async fn run(&mut self, device: &TunDevice, sink: &dyn PacketSink) -> Result<()> {
+        let mut buf = vec![0u8; MAX_PACKET_LEN];
+        loop {
+            let len = device.recv(&mut buf).await?;
+            for packet in self.handle_packet(buf[..len].to_vec())? {
+                if let Err(e) = sink.send_packet(&packet) {
+                    log::warn!("Dropping packet for {}: {}", device.name(), e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client_hello::{ClientHelloBuilder, TLSProfileConfig};
+    use crate::desync::{tcp_packet, DesyncStrategy};
+    use parking_lot::Mutex;
+    use std::os::fd::IntoRawFd;
+    use std::sync::Arc;
+
+    fn flow() -> TcpFlow {
+        TcpFlow {
+            src: "10.0.0.2:40000".parse().unwrap(),
+            dst: "203.0.113.7:443".parse().unwrap(),
+            seq: 1000,
+            ack: 5000,
+            window: 64240,
+            ttl: 64,
+            server_ttl: None,
+        }
+    }
+
+    fn desync_forwarder() -> TunForwarder {
+        TunForwarder::new(Box::new(|_| {
+            Box::new(DesyncPipeline::new(DesyncConfig {
+                strategy: DesyncStrategy::OutOfOrder,
+                ..Default::default()
+            }))
+        }))
+    }
+
+    #[test]
+    fn test_parse_flow() {
+        let packet = tcp_packet(&flow(), 1000, b"data", 64);
+        let key = parse_flow(&packet).unwrap();
+        assert_eq!(key.protocol, IPPROTO_TCP);
+        assert_eq!(key.dst, "203.0.113.7:443".parse().unwrap());
+
+        let mut v6 = vec![0x60, 0, 0, 0, 0, 4, IPPROTO_UDP, 64];
+        v6.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        v6.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        v6.extend_from_slice(&[0x30, 0x39, 0x00, 0x35]);
+        let key = parse_flow(&v6).unwrap();
+        assert_eq!(key.dst, "[::1]:53".parse().unwrap());
+        assert!(parse_flow(&[0x10; 30]).is_none());
+    }
+
+    #[test]
+    fn test_client_hello_is_desynced_once_per_flow() {
+        let mut forwarder = desync_forwarder();
+        let hello = ClientHelloBuilder::from_profile(&TLSProfileConfig::default())
+            .server_name("blocked.example")
+            .build();
+
+        let out = forwarder
+            .handle_packet(tcp_packet(&flow(), 1000, &hello, 64))
+            .unwrap();
+        assert_eq!(out.len(), 2);
+        assert_eq!(forwarder.flow_count(), 1);
+
+        // Later data on the same flow passes through
+        let data = tcp_packet(&flow(), 2000, b"later", 64);
+        assert_eq!(forwarder.handle_packet(data.clone()).unwrap(), vec![data]);
+    }
+
+    #[test]
+    fn test_idle_flows_expire() {
+        let mut forwarder = desync_forwarder().with_idle_timeout(Duration::from_secs(1));
+        forwarder
+            .handle_packet(tcp_packet(&flow(), 1000, b"x", 64))
+            .unwrap();
+        forwarder.expire_idle(Instant::now() + Duration::from_secs(2));
+        assert_eq!(forwarder.flow_count(), 0);
+    }
+
+    struct Collect(Arc<Mutex<Vec<Vec<u8>>>>);
+
+    impl PacketSink for Collect {
+        fn send_packet(&self, packet: &[u8]) -> Result<()> {
+            self.0.lock().push(packet.to_vec());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_forwards_from_descriptor() {
+        // A datagram socket pair stands in for the TUN descriptor
+        let (device_end, app_end) = std::os::unix::net::UnixDatagram::pair().unwrap();
+        let device =
+            unsafe { TunDevice::from_raw_fd(device_end.into_raw_fd(), "tun-test") }.unwrap();
+        let packet = tcp_packet(&flow(), 1000, b"data", 64);
+        app_end.send(&packet).unwrap();
+
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let sink = Collect(sent.clone());
+        let mut forwarder = desync_forwarder();
+        let _ =
+            tokio::time::timeout(Duration::from_millis(200), forwarder.run(&device, &sink)).await;
+        assert_eq!(*sent.lock(), vec![packet]);
+    }
+}
@@ -0,0 +1,281 @@
+//! UDP data path
+//! Carries datagrams over plain UDP or, where UDP is throttled, over FakeTCP:
+//! each datagram rides as one segment of a raw-socket TCP connection
+
+use crate::error::{Error, Result};
+use crate::record_padding::RecordPadder;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+
+/// Largest payload a UDP datagram can carry over IPv4
+pub const MAX_DATAGRAM: usize = 65507;
+
+/// Reversible payload treatment applied to every datagram
+///
+/// Plugs the obfuscation stages into the UDP path; `decode` must undo
+/// `encode` exactly since datagrams have no other framing.
+pub trait PayloadTransform: Send + Sync {
+    fn encode(&self, payload: &[u8]) -> Result<Vec<u8>>;
+    fn decode(&self, datagram: &[u8]) -> Result<Vec<u8>>;
+}
+
+impl PayloadTransform for RecordPadder {
+    fn encode(&self, payload: &[u8]) -> Result<Vec<u8>> {
+        Ok(self.pad(payload))
+    }
+
+    fn decode(&self, datagram: &[u8]) -> Result<Vec<u8>> {
+        self.unpad(datagram)
+    }
+}
+
+fn encode(transform: &Option<Arc<dyn PayloadTransform>>, payload: &[u8]) -> Result<Vec<u8>> {
+    match transform {
+        Some(transform) => transform.encode(payload),
+        None => Ok(payload.to_vec()),
+    }
+}
+
+fn decode(transform: &Option<Arc<dyn PayloadTransform>>, datagram: &[u8]) -> Result<Vec<u8>> {
+    match transform {
+        Some(transform) => transform.decode(datagram),
+        None => Ok(datagram.to_vec()),
+    }
+}
+
+/// Plain UDP transport
+pub struct UdpTransport {
+    socket: UdpSocket,
+    transform: Option<Arc<dyn PayloadTransform>>,
+}
+
+impl UdpTransport {
+    /// Bind a transport for serving or unconnected use
+    pub async fn bind(addr: SocketAddr) -> Result<Self> {
+        Ok(UdpTransport {
+            socket: UdpSocket::bind(addr).await?,
+            transform: None,
+        })
+    }
+
+    /// Bind an ephemeral port and connect it to `peer`
+    pub async fn connect(peer: SocketAddr) -> Result<Self> {
+        let local = if peer.is_ipv6() {
+            SocketAddr::from(([0u16; 8], 0))
+        } else {
+            SocketAddr::from(([0u8; 4], 0))
+        };
+        let transport = Self::bind(local).await?;
+        transport.socket.connect(peer).await?;
+        Ok(transport)
+    }
+
+    /// Apply `transform` to every datagram sent and received
+    pub fn with_transform(mut self, transform: Arc<dyn PayloadTransform>) -> Self {
+        self.transform = Some(transform);
+        self
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    fn encode_checked(&self, payload: &[u8]) -> Result<Vec<u8>> {
+        let datagram = encode(&self.transform, payload)?;
+        if datagram.len() > MAX_DATAGRAM {
+            return Err(Error::DataError(format!(
+                "Datagram of {} bytes exceeds the UDP maximum",
+                datagram.len()
+            )));
+        }
+        Ok(datagram)
+    }
+
+    /// Send one datagram to the connected peer
+    pub async fn send(&self, payload: &[u8]) -> Result<()> {
+        let datagram = self.encode_checked(payload)?;
+        self.socket.send(&datagram).await?;
+        Ok(())
+    }
+
+    /// Send one datagram to `peer`
+    pub async fn send_to(&self, payload: &[u8], peer: SocketAddr) -> Result<()> {
+        let datagram = self.encode_checked(payload)?;
+        self.socket.send_to(&datagram, peer).await?;
+        Ok(())
+    }
+
+    /// Receive one datagram from the connected peer
+    pub async fn recv(&self) -> Result<Vec<u8>> {
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+        let len = self.socket.recv(&mut buf).await?;
+        decode(&self.transform, &buf[..len])
+    }
+
+    /// Receive one datagram and the address it came from
+    pub async fn recv_from(&self) -> Result<(Vec<u8>, SocketAddr)> {
+        let (datagram, peer) = self.recv_raw_from().await?;
+        Ok((decode(&self.transform, &datagram)?, peer))
+    }
+
+    /// Send a datagram built elsewhere (e.g. a mimicked handshake) as-is
+    pub async fn send_raw(&self, datagram: &[u8], peer: Option<SocketAddr>) -> Result<()> {
+        match peer {
+            Some(peer) => self.socket.send_to(datagram, peer).await?,
+            None => self.socket.send(datagram).await?,
+        };
+        Ok(())
+    }
+
+    /// Receive a datagram without applying the transform
+    pub async fn recv_raw_from(&self) -> Result<(Vec<u8>, SocketAddr)> {
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+        let (len, peer) = self.socket.recv_from(&mut buf).await?;
+        buf.truncate(len);
+        Ok((buf, peer))
+    }
+}
+
+#[cfg(all(feature = "raw-net", target_os = "linux"))]
+pub use fake_tcp::FakeTcpTransport;
+
+#[cfg(all(feature = "raw-net", target_os = "linux"))]
+mod fake_tcp {
+    use super::*;
+    use crate::dynamic_patterns::SessionParameters;
+    use crate::raw_net::{RawTcpConnection, TCP_FLAG_RST};
+    use std::io::ErrorKind;
+    use std::net::SocketAddrV4;
+    use std::time::{Duration, Instant};
+
+    /// Datagrams carried as segments of a handshaken raw TCP connection
+    ///
+    /// Middleboxes see an ordinary TCP flow, but there is no retransmission
+    /// or ordering: a lost segment is a lost datagram, as with UDP. Blocking;
+    /// run it on a blocking thread. The same RST rules as `RawTcpConnection`
+    /// apply on both ends.
+    pub struct FakeTcpTransport {
+        conn: RawTcpConnection,
+        transform: Option<Arc<dyn PayloadTransform>>,
+    }
+
+    impl FakeTcpTransport {
+        /// Handshake with a FakeTCP peer at `peer`
+        pub fn connect(
+            peer: SocketAddrV4,
+            params: &SessionParameters,
+            timeout: Duration,
+        ) -> Result<Self> {
+            Ok(FakeTcpTransport {
+                conn: RawTcpConnection::connect(peer, params, timeout)?,
+                transform: None,
+            })
+        }
+
+        /// Wait for a FakeTCP peer to handshake with `local`
+        pub fn accept(
+            local: SocketAddrV4,
+            params: &SessionParameters,
+            timeout: Duration,
+        ) -> Result<Self> {
+            Ok(FakeTcpTransport {
+                conn: RawTcpConnection::accept(local, params, timeout)?,
+                transform: None,
+            })
+        }
+
+        /// Apply `transform` to every datagram sent and received
+        pub fn with_transform(mut self, transform: Arc<dyn PayloadTransform>) -> Self {
+            self.transform = Some(transform);
+            self
+        }
+
+        /// Send one datagram as a single segment
+        pub fn send(&mut self, payload: &[u8]) -> Result<()> {
+            let datagram = encode(&self.transform, payload)?;
+            let mss = self.conn.params().tcp_mss as usize;
+            if datagram.len() > mss {
+                return Err(Error::DataError(format!(
+                    "Datagram of {} bytes exceeds the {} byte MSS",
+                    datagram.len(),
+                    mss
+                )));
+            }
+            self.conn.send(&datagram)
+        }
+
+        /// Receive the next datagram, skipping bare ACKs
+        pub fn recv(&mut self, timeout: Duration) -> Result<Vec<u8>> {
+            let deadline = Instant::now() + timeout;
+            loop {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                let segment = self.conn.recv(remaining)?;
+                if segment.flags & TCP_FLAG_RST != 0 {
+                    return Err(Error::IoError(ErrorKind::ConnectionReset.into()));
+                }
+                if !segment.payload.is_empty() {
+                    return decode(&self.transform, &segment.payload);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_plain_round_trip() {
+        let server = UdpTransport::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let client = UdpTransport::connect(server.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        client.send(b"datagram").await.unwrap();
+        let (payload, peer) = server.recv_from().await.unwrap();
+        assert_eq!(payload, b"datagram");
+        assert_eq!(peer, client.local_addr().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_transform_applied_on_both_ends() {
+        let padder: Arc<dyn PayloadTransform> = Arc::new(RecordPadder::new(vec![256]).unwrap());
+        let server = UdpTransport::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap()
+            .with_transform(padder.clone());
+        let raw = UdpTransport::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let client = UdpTransport::connect(server.local_addr().unwrap())
+            .await
+            .unwrap()
+            .with_transform(padder);
+
+        client.send(b"hello").await.unwrap();
+        assert_eq!(server.recv_from().await.unwrap().0, b"hello");
+
+        // On the wire the datagram is a padded record
+        client
+            .send_to(b"hello", raw.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (wire, _) = raw.recv_from().await.unwrap();
+        assert_eq!(wire.len(), 5 + 256);
+        assert_eq!(wire[0], 0x17);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_datagram_rejected() {
+        let transport = UdpTransport::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let peer = transport.local_addr().unwrap();
+        let payload = vec![0u8; MAX_DATAGRAM + 1];
+        assert!(transport.send_to(&payload, peer).await.is_err());
+    }
+}
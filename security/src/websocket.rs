@@ -0,0 +1,616 @@
+//! WebSocket transport
+//! Performs a browser-like HTTP Upgrade, then carries data in properly
+//! masked WebSocket frames, so traffic passes CDNs such as Cloudflare or
+//! ArvanCloud that proxy WebSockets
+
+use crate::error::{Error, Result};
+use crate::obfuscation::USER_AGENTS;
+use crate::udp_transport::PayloadTransform;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+const WS_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_HANDSHAKE_LEN: usize = 8192;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+/// SHA-1, needed only for the handshake's accept key
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for block in message.chunks_exact(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, &word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (state, value) in h.iter_mut().zip([a, b, c, d, e]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+
+    let mut digest = [0u8; 20];
+    for (chunk, word) in digest.chunks_exact_mut(4).zip(h) {
+        chunk.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+/// `Sec-WebSocket-Accept` value for a `Sec-WebSocket-Key`
+pub fn accept_key(key: &str) -> String {
+    BASE64.encode(sha1(format!("{}{}", key, WS_GUID).as_bytes()))
+}
+
+/// Configuration for the WebSocket client
+#[derive(Clone, Debug)]
+pub struct WebSocketConfig {
+    /// Host header, the site the CDN routes to the proxy
+    pub host: String,
+    pub path: String,
+    /// Origin header; `https://<host>` when unset
+    pub origin: Option<String>,
+    /// Drawn from common browsers when unset
+    pub user_agent: Option<String>,
+    /// Largest frame payload sent
+    pub max_frame_payload: usize,
+    /// Largest message accepted from the peer
+    pub max_message_size: usize,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        WebSocketConfig {
+            host: String::new(),
+            path: "/".to_string(),
+            origin: None,
+            user_agent: None,
+            max_frame_payload: 16384,
+            max_message_size: 16 * 1024 * 1024,
+        }
+    }
+}
+
+/// Frame payload sizes for a message of `len` bytes
+///
+/// Drawn from the size classes of browser app traffic: mostly small frames,
+/// some medium and a few large ones up to `max`.
+fn frame_sizes<R: Rng>(rng: &mut R, len: usize, max: usize) -> Vec<usize> {
+    let mut sizes = Vec::new();
+    let mut left = len;
+    while left > 0 {
+        let size = match rng.gen_range(0..100) {
+            0..=59 => rng.gen_range(64..=512),
+            60..=89 => rng.gen_range(513..=4096),
+            _ => rng.gen_range(4097..=max.max(4097)),
+        };
+        let size = size.min(max).min(left);
+        sizes.push(size);
+        left -= size;
+    }
+    if sizes.is_empty() {
+        sizes.push(0);
+    }
+    sizes
+}
+
+fn encode_frame(fin: bool, opcode: u8, payload: &[u8], mask: Option<[u8; 4]>) -> Vec<u8> {
+    let mut out = Vec::with_capacity(payload.len() + 14);
+    out.push(if fin { 0x80 } else { 0 } | opcode);
+    let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+    match payload.len() {
+        len @ 0..=125 => out.push(mask_bit | len as u8),
+        len @ 126..=0xffff => {
+            out.push(mask_bit | 126);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            out.push(mask_bit | 127);
+            out.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    match mask {
+        Some(key) => {
+            out.extend_from_slice(&key);
+            out.extend(payload.iter().enumerate().map(|(i, b)| b ^ key[i % 4]));
+        }
+        None => out.extend_from_slice(payload),
+    }
+    out
+}
+
+struct WsFrame {
+    fin: bool,
+    opcode: u8,
+    masked: bool,
+    payload: Vec<u8>,
+}
+
+/// Parse and unmask the frame at the start of `buf`; `None` if incomplete
+fn parse_frame(buf: &[u8], max_payload: usize) -> Result<Option<(WsFrame, usize)>> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    if buf[0] & 0x70 != 0 {
+        return Err(Error::DataError("WebSocket extension bits set".to_string()));
+    }
+    let masked = buf[1] & 0x80 != 0;
+    let (len, mut offset) = match buf[1] & 0x7f {
+        126 if buf.len() >= 4 => (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4),
+        127 if buf.len() >= 10 => (u64::from_be_bytes(buf[2..10].try_into().unwrap()), 10),
+        126 | 127 => return Ok(None),
+        len => (len as u64, 2),
+    };
+    if len > max_payload as u64 {
+        return Err(Error::DataError(format!(
+            "WebSocket frame of {} bytes exceeds the limit",
+            len
+        )));
+    }
+
+    let mut key = [0u8; 4];
+    if masked {
+        match buf.get(offset..offset + 4) {
+            Some(bytes) => key.copy_from_slice(bytes),
+            None => return Ok(None),
+        }
+        offset += 4;
+    }
+    let Some(payload) = buf.get(offset..offset + len as usize) else {
+        return Ok(None);
+    };
+    let payload = if masked {
+        payload
+            .iter()
+            .enumerate()
+            .map(|(i, b)| b ^ key[i % 4])
+            .collect()
+    } else {
+        payload.to_vec()
+    };
+
+    Ok(Some((
+        WsFrame {
+            fin: buf[0] & 0x80 != 0,
+            opcode: buf[0] & 0x0f,
+            masked,
+            payload,
+        },
+        offset + len as usize,
+    )))
+}
+
+/// Status line or request line and lowercased headers of an HTTP head
+fn parse_head(head: &str) -> (String, Vec<(String, String)>) {
+    let mut lines = head.split("\r\n");
+    let first = lines.next().unwrap_or_default().to_string();
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+        .collect();
+    (first, headers)
+}
+
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, v)| v.as_str())
+}
+
+/// A WebSocket connection carrying one binary message per `send`
+///
+/// Messages keep their boundaries; each is cut into frames whose sizes
+/// follow `frame_sizes`, with continuation frames after the first.
+pub struct WebSocketTransport<S> {
+    io: S,
+    client: bool,
+    max_frame_payload: usize,
+    max_message_size: usize,
+    transform: Option<Arc<dyn PayloadTransform>>,
+    read_buf: Vec<u8>,
+    closed: bool,
+}
+
+impl WebSocketTransport<TlsStream<TcpStream>> {
+    /// Open a TLS connection to `addr` and upgrade it
+    ///
+    /// `sni` is the name presented in the handshake, e.g. a CDN front;
+    /// the Host header from the config names the site.
+    pub async fn connect_tls(addr: SocketAddr, sni: &str, config: WebSocketConfig) -> Result<Self> {
+        let mut roots = RootCertStore::empty();
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        let mut tls_config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        tls_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        let server_name = ServerName::try_from(sni.to_string())
+            .map_err(|e| Error::ConfigError(format!("Invalid server name {}: {}", sni, e)))?;
+        let tcp = TcpStream::connect(addr).await?;
+        let tls = TlsConnector::from(Arc::new(tls_config))
+            .connect(server_name, tcp)
+            .await?;
+        Self::connect(tls, config).await
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> WebSocketTransport<S> {
+    fn new(io: S, client: bool, max_frame_payload: usize, max_message_size: usize) -> Self {
+        WebSocketTransport {
+            io,
+            client,
+            max_frame_payload,
+            max_message_size,
+            transform: None,
+            read_buf: Vec::new(),
+            closed: false,
+        }
+    }
+
+    /// Upgrade `io` as a client
+    pub async fn connect(io: S, config: WebSocketConfig) -> Result<Self> {
+        if config.host.is_empty() || !config.path.starts_with('/') {
+            return Err(Error::ConfigError(
+                "WebSocket needs a host and an absolute path".to_string(),
+            ));
+        }
+        let key = BASE64.encode(rand::thread_rng().gen::<[u8; 16]>());
+        let user_agent = config.user_agent.clone().unwrap_or_else(|| {
+            USER_AGENTS
+                .choose(&mut rand::thread_rng())
+                .unwrap()
+                .to_string()
+        });
+        let origin = config
+            .origin
+            .clone()
+            .unwrap_or_else(|| format!("https://{}", config.host));
+
+        // Header order as Chrome sends it
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: Upgrade\r\nPragma: no-cache\r\n\
+             Cache-Control: no-cache\r\nUser-Agent: {}\r\nUpgrade: websocket\r\nOrigin: {}\r\n\
+             Sec-WebSocket-Version: 13\r\nAccept-Encoding: gzip, deflate, br\r\n\
+             Accept-Language: en-US,en;q=0.9\r\nSec-WebSocket-Key: {}\r\n\r\n",
+            config.path, config.host, user_agent, origin, key
+        );
+
+        let mut transport = Self::new(io, true, config.max_frame_payload, config.max_message_size);
+        transport.io.write_all(request.as_bytes()).await?;
+        transport.io.flush().await?;
+
+        let head = transport.read_head().await?;
+        let (status, headers) = parse_head(&head);
+        let rejected =
+            |reason: &str| Error::DPIBypassError(format!("WebSocket upgrade rejected: {}", reason));
+        if !status.starts_with("HTTP/1.1 101") {
+            return Err(rejected(&status));
+        }
+        if !header(&headers, "upgrade").is_some_and(|v| v.eq_ignore_ascii_case("websocket")) {
+            return Err(rejected("missing Upgrade header"));
+        }
+        if header(&headers, "sec-websocket-accept") != Some(accept_key(&key).as_str()) {
+            return Err(rejected("bad Sec-WebSocket-Accept"));
+        }
+        Ok(transport)
+    }
+
+    /// Answer a client's upgrade request on `io`
+    pub async fn accept(io: S) -> Result<Self> {
+        let defaults = WebSocketConfig::default();
+        let mut transport = Self::new(
+            io,
+            false,
+            defaults.max_frame_payload,
+            defaults.max_message_size,
+        );
+
+        let head = transport.read_head().await?;
+        let (request_line, headers) = parse_head(&head);
+        let key = header(&headers, "sec-websocket-key");
+        let valid = request_line.starts_with("GET ")
+            && header(&headers, "upgrade").is_some_and(|v| v.eq_ignore_ascii_case("websocket"))
+            && header(&headers, "sec-websocket-version") == Some("13");
+        let Some(key) = key.filter(|_| valid) else {
+            transport
+                .io
+                .write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n")
+                .await?;
+            return Err(Error::DataError("Not a WebSocket upgrade".to_string()));
+        };
+
+        let response = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Accept: {}\r\n\r\n",
+            accept_key(key)
+        );
+        transport.io.write_all(response.as_bytes()).await?;
+        transport.io.flush().await?;
+        Ok(transport)
+    }
+
+    /// Apply `transform` to every message sent and received
+    pub fn with_transform(mut self, transform: Arc<dyn PayloadTransform>) -> Self {
+        self.transform = Some(transform);
+        self
+    }
+
+    /// Read up to the end of the HTTP head; bytes after it stay buffered
+    async fn read_head(&mut self) -> Result<String> {
+        loop {
+            if let Some(end) = self.read_buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                let head: Vec<u8> = self.read_buf.drain(..end + 4).collect();
+                return String::from_utf8(head[..end].to_vec())
+                    .map_err(|_| Error::DataError("Invalid HTTP head".to_string()));
+            }
+            if self.read_buf.len() > MAX_HANDSHAKE_LEN {
+                return Err(Error::DataError("HTTP head too long".to_string()));
+            }
+            self.fill().await?;
+        }
+    }
+
+    async fn fill(&mut self) -> Result<()> {
+        let mut chunk = [0u8; 16384];
+        let n = self.io.read(&mut chunk).await?;
+        if n == 0 {
+            self.closed = true;
+            return Err(Error::IoError(ErrorKind::UnexpectedEof.into()));
+        }
+        self.read_buf.extend_from_slice(&chunk[..n]);
+        Ok(())
+    }
+
+    fn frame(&self, fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = self.client.then(|| rand::thread_rng().gen());
+        encode_frame(fin, opcode, payload, mask)
+    }
+
+    /// Send `payload` as one binary message
+    pub async fn send(&mut self, payload: &[u8]) -> Result<()> {
+        if self.closed {
+            return Err(Error::IoError(ErrorKind::BrokenPipe.into()));
+        }
+        let payload = match &self.transform {
+            Some(transform) => transform.encode(payload)?,
+            None => payload.to_vec(),
+        };
+
+        let sizes = frame_sizes(
+            &mut rand::thread_rng(),
+            payload.len(),
+            self.max_frame_payload,
+        );
+        let mut out = Vec::with_capacity(payload.len() + sizes.len() * 14);
+        let mut offset = 0;
+        for (i, &size) in sizes.iter().enumerate() {
+            let opcode = if i == 0 {
+                OPCODE_BINARY
+            } else {
+                OPCODE_CONTINUATION
+            };
+            let fin = i == sizes.len() - 1;
+            out.extend(self.frame(fin, opcode, &payload[offset..offset + size]));
+            offset += size;
+        }
+        self.io.write_all(&out).await?;
+        self.io.flush().await?;
+        Ok(())
+    }
+
+    /// Receive the next data message, answering pings on the way
+    pub async fn recv(&mut self) -> Result<Vec<u8>> {
+        let mut message: Option<Vec<u8>> = None;
+        loop {
+            if self.closed {
+                return Err(Error::IoError(ErrorKind::UnexpectedEof.into()));
+            }
+            let Some((frame, len)) = parse_frame(&self.read_buf, self.max_message_size)? else {
+                self.fill().await?;
+                continue;
+            };
+            self.read_buf.drain(..len);
+            if frame.masked == self.client {
+                return Err(Error::DataError(
+                    "WebSocket frame masking is wrong for the direction".to_string(),
+                ));
+            }
+
+            match frame.opcode {
+                OPCODE_PING => {
+                    let pong = self.frame(true, OPCODE_PONG, &frame.payload);
+                    self.io.write_all(&pong).await?;
+                    self.io.flush().await?;
+                }
+                OPCODE_PONG => {}
+                OPCODE_CLOSE => {
+                    let reply = self.frame(true, OPCODE_CLOSE, &frame.payload);
+                    // The peer may already be gone
+                    let _ = self.io.write_all(&reply).await;
+                    self.closed = true;
+                }
+                OPCODE_TEXT | OPCODE_BINARY if message.is_none() => {
+                    message = Some(frame.payload);
+                }
+                OPCODE_CONTINUATION if message.is_some() => {
+                    let buf = message.as_mut().unwrap();
+                    buf.extend_from_slice(&frame.payload);
+                    if buf.len() > self.max_message_size {
+                        return Err(Error::DataError(
+                            "WebSocket message exceeds the limit".to_string(),
+                        ));
+                    }
+                }
+                _ => {
+                    return Err(Error::DataError(format!(
+                        "Unexpected WebSocket opcode {}",
+                        frame.opcode
+                    )))
+                }
+            }
+
+            if frame.fin && frame.opcode < OPCODE_CLOSE {
+                let message = message.take().unwrap_or_default();
+                return match &self.transform {
+                    Some(transform) => transform.decode(&message),
+                    None => Ok(message),
+                };
+            }
+        }
+    }
+
+    /// Send a normal-closure Close frame
+    pub async fn close(&mut self) -> Result<()> {
+        if !self.closed {
+            let frame = self.frame(true, OPCODE_CLOSE, &1000u16.to_be_bytes());
+            self.io.write_all(&frame).await?;
+            self.io.flush().await?;
+            self.closed = true;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> WebSocketConfig {
+        WebSocketConfig {
+            host: "ws.example.org".to_string(),
+            path: "/chat".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_accept_key() {
+        assert_eq!(
+            sha1(b"abc"),
+            [
+                0xa9, 0x99, 0x3e, 0x36, 0x47, 0x06, 0x81, 0x6a, 0xba, 0x3e, 0x25, 0x71, 0x78, 0x50,
+                0xc2, 0x6c, 0x9c, 0xd0, 0xd8, 0x9d
+            ]
+        );
+        // RFC 6455 section 1.3
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_and_round_trip() {
+        let (client_io, server_io) = tokio::io::duplex(1 << 20);
+        let (client, server) = tokio::join!(
+            WebSocketTransport::connect(client_io, config()),
+            WebSocketTransport::accept(server_io)
+        );
+        let (mut client, mut server) = (client.unwrap(), server.unwrap());
+
+        let big: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        client.send(&big).await.unwrap();
+        client.send(b"").await.unwrap();
+        assert_eq!(server.recv().await.unwrap(), big);
+        assert!(server.recv().await.unwrap().is_empty());
+
+        server.send(b"reply").await.unwrap();
+        assert_eq!(client.recv().await.unwrap(), b"reply");
+
+        client.close().await.unwrap();
+        assert!(server.recv().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_ping_answered_and_frames_masked() {
+        let (client_io, mut server) = tokio::io::duplex(1 << 16);
+        let connect = tokio::spawn(WebSocketTransport::connect(client_io, config()));
+
+        let mut request = vec![0u8; 4096];
+        let n = server.read(&mut request).await.unwrap();
+        let (_, headers) = parse_head(std::str::from_utf8(&request[..n]).unwrap());
+        let key = header(&headers, "sec-websocket-key").unwrap();
+        let response = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+             Connection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+            accept_key(key)
+        );
+        // A ping right behind the 101, then a text message
+        let mut reply = response.into_bytes();
+        reply.extend(encode_frame(true, OPCODE_PING, b"hb", None));
+        reply.extend(encode_frame(true, OPCODE_TEXT, b"hi", None));
+        server.write_all(&reply).await.unwrap();
+
+        let mut client = connect.await.unwrap().unwrap();
+        assert_eq!(client.recv().await.unwrap(), b"hi");
+
+        let mut pong = vec![0u8; 64];
+        let n = server.read(&mut pong).await.unwrap();
+        let (frame, _) = parse_frame(&pong[..n], 1024).unwrap().unwrap();
+        assert_eq!(frame.opcode, OPCODE_PONG);
+        assert!(frame.masked);
+        assert_eq!(frame.payload, b"hb");
+    }
+
+    #[tokio::test]
+    async fn test_bad_accept_key_rejected() {
+        let (client_io, mut server) = tokio::io::duplex(1 << 16);
+        let connect = tokio::spawn(WebSocketTransport::connect(client_io, config()));
+        let mut request = vec![0u8; 4096];
+        let n = server.read(&mut request).await.unwrap();
+        assert!(request[..n].starts_with(b"GET /chat HTTP/1.1\r\n"));
+        server
+            .write_all(
+                b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+                  Sec-WebSocket-Accept: bogus\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        assert!(connect.await.unwrap().is_err());
+    }
+}